@@ -2,13 +2,14 @@ use std::cmp::Ordering;
 use std::path::Path;
 use std::time::Instant;
 
-use bb_core::matcher::Matcher;
-use bb_core::psl::get_etld1;
-use bb_core::snapshot::Snapshot;
-use bb_core::types::{MatchDecision, RequestContext, RequestType, SchemeMask};
-use bb_core::url::{extract_host, extract_scheme};
+use bb_core::engine::{Engine, RequestInfo};
+use bb_core::matcher::{MatchScratch, Matcher, ResponseHeader};
+use bb_core::snapshot::{MappedSnapshot, Snapshot};
+use bb_core::types::{MatchDecision, RequestType};
+use bb_core::url::tokenize_url;
 use clap::ValueEnum;
 
+use crate::alloc_count;
 use crate::snapshot;
 
 #[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq)]
@@ -16,6 +17,16 @@ pub enum BenchMode {
     ShouldBlock,
     MatchRequest,
     Both,
+    /// `match_cosmetics` once per document navigation
+    Cosmetics,
+    /// `match_response_headers` on documents with synthetic header sets
+    Headers,
+}
+
+impl BenchMode {
+    fn matches_requests(self) -> bool {
+        matches!(self, Self::ShouldBlock | Self::MatchRequest | Self::Both)
+    }
 }
 
 pub struct SimpleBenchOptions {
@@ -37,6 +48,18 @@ pub struct RealisticBenchOptions {
     pub synthetic_pages: usize,
     pub synthetic_reqs_per_page: usize,
     pub seed: u32,
+    pub threads: usize,
+}
+
+pub struct CompareBenchOptions {
+    pub old_path: String,
+    pub new_path: String,
+    pub trace_path: Option<String>,
+    pub trace_limit: usize,
+    pub seed: u32,
+    pub iterations: usize,
+    /// Allowed average-latency slowdown of the new snapshot, in percent
+    pub max_regression_pct: f64,
 }
 
 struct SimpleRequest {
@@ -46,7 +69,7 @@ struct SimpleRequest {
 }
 
 #[derive(Clone)]
-struct BenchRequest {
+pub(crate) struct BenchRequest {
     url: String,
     request_type: String,
     initiator: Option<String>,
@@ -55,18 +78,19 @@ struct BenchRequest {
     request_id: String,
 }
 
-fn ensure_snapshot(inputs: &[String], snapshot_path: &Path, compile: bool) -> Result<Vec<u8>, String> {
+fn ensure_snapshot(inputs: &[String], snapshot_path: &Path, compile: bool) -> Result<MappedSnapshot, String> {
     if compile {
         let (bytes, stats) = snapshot::compile_snapshot_bytes(inputs, true)?;
         snapshot::write_snapshot(snapshot_path, &bytes)?;
         println!(
-            "Compiled {} list(s): {} -> {} rules (dedupe {}, badfilter {} incl {})",
+            "Compiled {} list(s): {} -> {} rules (dedupe {}, badfilter {} incl {}, shadowed {})",
             inputs.len(),
             stats.rules_before,
             stats.rules_after,
             stats.rules_deduped,
             stats.badfiltered_rules + stats.badfilter_rules,
-            stats.badfilter_rules
+            stats.badfilter_rules,
+            stats.shadowed_rules
         );
         println!(
             "Snapshot size: {} bytes, total time {:.1}ms",
@@ -75,45 +99,21 @@ fn ensure_snapshot(inputs: &[String], snapshot_path: &Path, compile: bool) -> Re
         );
     }
 
-    snapshot::read_snapshot(snapshot_path)
+    snapshot::map_snapshot(snapshot_path)
 }
 
-fn should_block(matcher: &Matcher, req: &BenchRequest) -> bool {
-    match_request(matcher, req).decision == MatchDecision::Block
+fn should_block(engine: &Engine, req: &BenchRequest) -> bool {
+    match_request(engine, req).decision == MatchDecision::Block
 }
 
-fn match_request(matcher: &Matcher, req: &BenchRequest) -> bb_core::types::MatchResult {
-    let req_host = extract_host(&req.url).unwrap_or("");
-    let req_etld1 = get_etld1(req_host);
-
-    let is_main_frame = req.request_type == "main_frame" || req.request_type == "document";
-    let site_url = if is_main_frame {
-        req.url.as_str()
-    } else {
-        req.initiator.as_deref().unwrap_or(req.url.as_str())
-    };
-    let site_host = extract_host(site_url).unwrap_or(req_host);
-    let site_etld1 = get_etld1(site_host);
+fn match_request(engine: &Engine, req: &BenchRequest) -> bb_core::types::MatchResult {
+    engine.check_request(&request_info(req))
+}
 
-    let scheme = extract_scheme(&req.url).unwrap_or(SchemeMask::HTTP);
-    let is_third_party = !site_etld1.is_empty() && req_etld1 != site_etld1;
+pub(crate) fn request_info(req: &BenchRequest) -> RequestInfo<'_> {
     let request_type = RequestType::from_str(&req.request_type);
-
-    let ctx = RequestContext {
-        url: &req.url,
-        req_host,
-        req_etld1: &req_etld1,
-        site_host,
-        site_etld1: &site_etld1,
-        is_third_party,
-        request_type,
-        scheme,
-        tab_id: req.tab_id,
-        frame_id: req.frame_id,
-        request_id: &req.request_id,
-    };
-
-    matcher.match_request(&ctx)
+    RequestInfo::new(&req.url, request_type, req.initiator.as_deref())
+        .with_ids(req.tab_id, req.frame_id, &req.request_id)
 }
 
 pub fn run_simple(opts: SimpleBenchOptions) -> Result<(), String> {
@@ -122,33 +122,33 @@ pub fn run_simple(opts: SimpleBenchOptions) -> Result<(), String> {
     println!("============================================================");
 
     let snapshot_path = Path::new(&opts.snapshot_path);
-    let snapshot_bytes = ensure_snapshot(&opts.input_paths, snapshot_path, opts.compile)?;
-    let snapshot = Snapshot::load(&snapshot_bytes)
+    let mapped = ensure_snapshot(&opts.input_paths, snapshot_path, opts.compile)?;
+    let snapshot = mapped.snapshot()
         .map_err(|e| format!("Invalid snapshot: {}", e))?;
-    let matcher = Matcher::new(&snapshot);
+    let engine = Engine::new(&snapshot);
 
     let realistic_mix = generate_realistic_mix();
     let random_requests = generate_test_requests(1000, DEFAULT_SEED);
 
     println!("Warmup...");
-    warmup_simple(&matcher, &realistic_mix);
+    warmup_simple(&engine, &realistic_mix);
 
     println!("------------------------------------------------------------");
     println!("Benchmark: Realistic Mix (10 requests, 10000 iterations)");
     println!("------------------------------------------------------------");
-    let realistic = run_benchmark_simple(&matcher, &realistic_mix, 10_000);
+    let realistic = run_benchmark_simple(&engine, &realistic_mix, 10_000);
     println!("{}", format_simple_result("Realistic Mix", &realistic));
 
     println!("------------------------------------------------------------");
     println!("Benchmark: Random Requests (1000 requests, 100 iterations)");
     println!("------------------------------------------------------------");
-    let random = run_benchmark_simple(&matcher, &random_requests, 100);
+    let random = run_benchmark_simple(&engine, &random_requests, 100);
     println!("{}", format_simple_result("Random Requests", &random));
 
     println!("------------------------------------------------------------");
     println!("Benchmark: Single Hot Path (1 request, 100000 iterations)");
     println!("------------------------------------------------------------");
-    let hot_path = run_benchmark_simple(&matcher, &realistic_mix[..1], 100_000);
+    let hot_path = run_benchmark_simple(&engine, &realistic_mix[..1], 100_000);
     println!("{}", format_simple_result("Hot Path", &hot_path));
 
     println!("============================================================");
@@ -172,13 +172,14 @@ pub fn run_realistic(opts: RealisticBenchOptions) -> Result<(), String> {
     println!("Iterations: {}", opts.iterations);
     println!("Warmup ops: {}", opts.warmup_ops);
     println!("Sample batch ops: {}", opts.sample_batch_ops);
+    println!("Threads: {}", opts.threads);
     println!();
 
     let snapshot_path = Path::new(&opts.snapshot_path);
-    let snapshot_bytes = ensure_snapshot(&opts.input_paths, snapshot_path, opts.compile)?;
-    let snapshot = Snapshot::load(&snapshot_bytes)
+    let mapped = ensure_snapshot(&opts.input_paths, snapshot_path, opts.compile)?;
+    let snapshot = mapped.snapshot()
         .map_err(|e| format!("Invalid snapshot: {}", e))?;
-    let matcher = Matcher::new(&snapshot);
+    let engine = Engine::new(&snapshot);
 
     let requests = if let Some(path) = &opts.trace_path {
         println!("Loading trace: {} (limit {})", path, opts.trace_limit);
@@ -194,14 +195,42 @@ pub fn run_realistic(opts: RealisticBenchOptions) -> Result<(), String> {
     };
 
     println!("Dataset size: {} requests", requests.len());
+    if opts.mode.matches_requests() {
+        println!("{}", format_token_probes(&snapshot, &requests));
+    }
     println!();
 
+    // Cosmetic and header matching only happen for documents.
+    let requests = if opts.mode.matches_requests() {
+        requests
+    } else {
+        let documents: Vec<BenchRequest> = requests
+            .into_iter()
+            .filter(|req| RequestType::from_str(&req.request_type).intersects(RequestType::DOCUMENT))
+            .collect();
+        if documents.is_empty() {
+            return Err("Dataset has no main_frame/sub_frame requests to benchmark".to_string());
+        }
+        println!("Documents: {}", documents.len());
+        documents
+    };
+
     println!("Warming up...");
     if opts.mode == BenchMode::ShouldBlock || opts.mode == BenchMode::Both {
-        warmup_realistic(&matcher, &requests, opts.warmup_ops, false);
+        warmup_realistic(&engine, &requests, opts.warmup_ops, false);
     }
     if opts.mode == BenchMode::MatchRequest || opts.mode == BenchMode::Both {
-        warmup_realistic(&matcher, &requests, opts.warmup_ops, true);
+        warmup_realistic(&engine, &requests, opts.warmup_ops, true);
+    }
+    if opts.mode == BenchMode::Cosmetics {
+        warmup_with(&requests, opts.warmup_ops, |req| {
+            let _ = engine.cosmetics_for_request(&request_info(req));
+        });
+    }
+    if opts.mode == BenchMode::Headers {
+        warmup_with(&requests, opts.warmup_ops, |req| {
+            let _ = match_headers(&engine, req, 0);
+        });
     }
     println!("Warmup done.");
     println!();
@@ -222,22 +251,67 @@ pub fn run_realistic(opts: RealisticBenchOptions) -> Result<(), String> {
             &requests,
             opts.iterations,
             opts.sample_batch_ops,
-            |req| if should_block(&matcher, req) { 1 } else { 0 },
+            |req| if should_block(&engine, req) { 1 } else { 0 },
         );
         println!("{}", format_realistic_result(&result));
         println!();
     }
 
+    let mut single_thread = None;
     if opts.mode == BenchMode::MatchRequest || opts.mode == BenchMode::Both {
         let result = run_bench_batched(
             "match_request (extension-facing API)",
             &requests,
             opts.iterations,
             opts.sample_batch_ops,
-            |req| if match_request(&matcher, req).decision != MatchDecision::Allow { 1 } else { 0 },
+            |req| if match_request(&engine, req).decision != MatchDecision::Allow { 1 } else { 0 },
         );
         println!("{}", format_realistic_result(&result));
         println!();
+        single_thread = Some(result);
+    }
+
+    if opts.mode == BenchMode::Cosmetics {
+        let result = run_bench_batched(
+            "match_cosmetics (per navigation)",
+            &requests,
+            opts.iterations,
+            opts.sample_batch_ops,
+            |req| {
+                let result = engine.cosmetics_for_request(&request_info(req));
+                let applied = !result.css.is_empty() || !result.procedural.is_empty() || !result.scriptlets.is_empty();
+                if applied { 1 } else { 0 }
+            },
+        );
+        println!("{}", format_realistic_result(&BenchResult { hit_label: "With cosmetics", ..result }));
+        println!();
+    }
+
+    if opts.mode == BenchMode::Headers {
+        let mut set = 0usize;
+        let result = run_bench_batched(
+            "match_response_headers (synthetic header sets)",
+            &requests,
+            opts.iterations,
+            opts.sample_batch_ops,
+            |req| {
+                set = set.wrapping_add(1);
+                if match_headers(&engine, req, set) { 1 } else { 0 }
+            },
+        );
+        println!("{}", format_realistic_result(&BenchResult { hit_label: "With actions", ..result }));
+        println!();
+    }
+
+    if opts.threads > 1 && opts.mode.matches_requests() {
+        let threaded = run_bench_threaded(engine.matcher(), &requests, opts.threads, opts.iterations, opts.sample_batch_ops);
+        println!("{}", format_threaded_result(&threaded, single_thread.as_ref()));
+        println!();
+    }
+
+    if opts.mode.matches_requests() {
+        println!("{}", format_match_allocations(&engine, &requests));
+        println!();
     }
 
     println!("Notes:");
@@ -247,6 +321,126 @@ pub fn run_realistic(opts: RealisticBenchOptions) -> Result<(), String> {
     Ok(())
 }
 
+/// Synthetic workload shape for `--compare` when no trace is given.
+const COMPARE_PAGES: usize = 60;
+const COMPARE_REQS_PER_PAGE: usize = 120;
+/// Timed rounds per snapshot, alternated so drift hits both alike.
+const COMPARE_ROUNDS: usize = 3;
+const MAX_PRINTED_MISMATCHES: usize = 20;
+
+pub fn run_compare(opts: CompareBenchOptions) -> Result<(), String> {
+    println!("========================================================================");
+    println!("BetterBlocker Benchmark (Compare)");
+    println!("========================================================================");
+    println!("Old: {}", opts.old_path);
+    println!("New: {}", opts.new_path);
+    println!("Iterations: {} x {} rounds", opts.iterations, COMPARE_ROUNDS);
+    println!("Max regression: {:.1}%", opts.max_regression_pct);
+    println!();
+
+    let old_mapped = snapshot::map_snapshot(Path::new(&opts.old_path))?;
+    let new_mapped = snapshot::map_snapshot(Path::new(&opts.new_path))?;
+    let old_snapshot = old_mapped.snapshot()
+        .map_err(|e| format!("Invalid snapshot '{}': {}", opts.old_path, e))?;
+    let new_snapshot = new_mapped.snapshot()
+        .map_err(|e| format!("Invalid snapshot '{}': {}", opts.new_path, e))?;
+    let old_engine = Engine::new(&old_snapshot);
+    let new_engine = Engine::new(&new_snapshot);
+
+    let requests = if let Some(path) = &opts.trace_path {
+        println!("Loading trace: {} (limit {})", path, opts.trace_limit);
+        load_trace_jsonl(path, opts.trace_limit)?
+    } else {
+        println!(
+            "Generating synthetic workload: pages={}, reqs/page={}, seed={}",
+            COMPARE_PAGES, COMPARE_REQS_PER_PAGE, opts.seed
+        );
+        generate_synthetic_workload(COMPARE_PAGES, COMPARE_REQS_PER_PAGE, opts.seed)
+    };
+    println!("Dataset size: {} requests", requests.len());
+    println!();
+
+    let mut mismatches = 0usize;
+    for req in &requests {
+        let old = match_request(&old_engine, req);
+        let new = match_request(&new_engine, req);
+        if old.decision == new.decision && old.redirect_url == new.redirect_url {
+            continue;
+        }
+        mismatches += 1;
+        if mismatches <= MAX_PRINTED_MISMATCHES {
+            println!(
+                "  MISMATCH {} {}: {:?} -> {:?}",
+                req.request_type, req.url, old.decision, new.decision
+            );
+        }
+    }
+    if mismatches > MAX_PRINTED_MISMATCHES {
+        println!("  ... and {} more", mismatches - MAX_PRINTED_MISMATCHES);
+    }
+    println!("Decision mismatches: {} / {}", mismatches, requests.len());
+    println!();
+
+    println!("Warming up...");
+    warmup_realistic(&old_engine, &requests, requests.len(), true);
+    warmup_realistic(&new_engine, &requests, requests.len(), true);
+
+    let mut old_best: Option<BenchResult> = None;
+    let mut new_best: Option<BenchResult> = None;
+    for _ in 0..COMPARE_ROUNDS {
+        for (engine, best, name) in [
+            (&old_engine, &mut old_best, "match_request (old)"),
+            (&new_engine, &mut new_best, "match_request (new)"),
+        ] {
+            let result = run_bench_batched(name, &requests, opts.iterations, 512, |req| {
+                if match_request(engine, req).decision != MatchDecision::Allow { 1 } else { 0 }
+            });
+            if best.as_ref().is_none_or(|b| result.avg_us < b.avg_us) {
+                *best = Some(result);
+            }
+        }
+    }
+    let (old, new) = match (old_best, new_best) {
+        (Some(old), Some(new)) => (old, new),
+        _ => unreachable!("COMPARE_ROUNDS is non-zero"),
+    };
+
+    println!("{}", format_realistic_result(&old));
+    println!();
+    println!("{}", format_realistic_result(&new));
+    println!();
+
+    let regression_pct = pct_change(old.avg_us, new.avg_us);
+    println!("Deltas (new vs old, best of {} rounds):", COMPARE_ROUNDS);
+    println!("  Avg: {:+.1}%", regression_pct);
+    println!("  P50: {:+.1}%", pct_change(old.p50_us, new.p50_us));
+    println!("  P99: {:+.1}%", pct_change(old.p99_us, new.p99_us));
+    println!("  Throughput: {:+.1}%", pct_change(old.ops_per_sec as f64, new.ops_per_sec as f64));
+    println!();
+
+    let mut failures = Vec::new();
+    if mismatches > 0 {
+        failures.push(format!("{} decision mismatch(es)", mismatches));
+    }
+    if regression_pct > opts.max_regression_pct {
+        failures.push(format!(
+            "avg latency up {:.1}% (limit {:.1}%)",
+            regression_pct, opts.max_regression_pct
+        ));
+    }
+    if failures.is_empty() {
+        println!("PASS");
+        Ok(())
+    } else {
+        println!("FAIL");
+        Err(format!("Compare failed: {}", failures.join(", ")))
+    }
+}
+
+fn pct_change(old: f64, new: f64) -> f64 {
+    if old > 0.0 { (new - old) / old * 100.0 } else { 0.0 }
+}
+
 struct SimpleBenchResult {
     iterations: usize,
     total_ms: f64,
@@ -257,7 +451,7 @@ struct SimpleBenchResult {
     ops_per_sec: u64,
 }
 
-fn run_benchmark_simple(matcher: &Matcher, requests: &[SimpleRequest], iterations: usize) -> SimpleBenchResult {
+fn run_benchmark_simple(engine: &Engine, requests: &[SimpleRequest], iterations: usize) -> SimpleBenchResult {
     let mut latencies = Vec::new();
     let mut total_ops = 0usize;
 
@@ -272,7 +466,7 @@ fn run_benchmark_simple(matcher: &Matcher, requests: &[SimpleRequest], iteration
                 frame_id: 0,
                 request_id: "bench".to_string(),
             };
-            let _ = should_block(matcher, &bench_req);
+            let _ = should_block(engine, &bench_req);
             let elapsed = start.elapsed().as_secs_f64() * 1_000_000.0;
             latencies.push(elapsed);
             total_ops += 1;
@@ -318,6 +512,8 @@ struct BenchResult {
     p99_us: f64,
     ops_per_sec: u64,
     blocked_pct: f64,
+    /// What a non-zero return from the measured op means
+    hit_label: &'static str,
 }
 
 fn run_bench_batched(
@@ -374,12 +570,88 @@ fn run_bench_batched(
         p99_us: percentile(&samples_us, 0.99),
         ops_per_sec: if total_ms > 0.0 { (total_ops as f64 / (total_ms / 1000.0)) as u64 } else { 0 },
         blocked_pct: if total_ops > 0 { (blocked as f64 / total_ops as f64) * 100.0 } else { 0.0 },
+        hit_label: "Blocked",
+    }
+}
+
+struct ThreadedBenchResult {
+    threads: Vec<BenchResult>,
+    total_ops: usize,
+    wall_ms: f64,
+}
+
+/// Shard `requests` across `threads` workers that all match against the same
+/// `Matcher`, each with its own `MatchScratch`.
+fn run_bench_threaded(
+    matcher: &Matcher,
+    requests: &[BenchRequest],
+    threads: usize,
+    iterations: usize,
+    sample_batch_ops: usize,
+) -> ThreadedBenchResult {
+    let shard_len = requests.len().div_ceil(threads).max(1);
+    let start = Instant::now();
+
+    let results: Vec<BenchResult> = std::thread::scope(|scope| {
+        let workers: Vec<_> = requests
+            .chunks(shard_len)
+            .enumerate()
+            .map(|(idx, shard)| {
+                scope.spawn(move || {
+                    let mut scratch = MatchScratch::new();
+                    run_bench_batched(&format!("thread {}", idx), shard, iterations, sample_batch_ops, |req| {
+                        let info = request_info(req);
+                        let result = matcher.match_request_with_scratch(&info.context(), &mut scratch);
+                        if result.decision != MatchDecision::Allow { 1 } else { 0 }
+                    })
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("bench thread panicked"))
+            .collect()
+    });
+
+    ThreadedBenchResult {
+        total_ops: results.iter().map(|result| result.op_count).sum(),
+        threads: results,
+        wall_ms: start.elapsed().as_secs_f64() * 1000.0,
+    }
+}
+
+fn format_threaded_result(result: &ThreadedBenchResult, single_thread: Option<&BenchResult>) -> String {
+    let ops_per_sec = if result.wall_ms > 0.0 {
+        (result.total_ops as f64 / (result.wall_ms / 1000.0)) as u64
+    } else {
+        0
+    };
+
+    let mut out = format!(
+        "match_request ({} threads, shared matcher):\n  Ops: {}\n  Wall: {:.2} ms\n  Throughput: {} ops/sec",
+        result.threads.len(),
+        result.total_ops,
+        result.wall_ms,
+        ops_per_sec,
+    );
+    if let Some(single) = single_thread.filter(|single| single.ops_per_sec > 0) {
+        out.push_str(&format!(
+            "\n  Scaling: {:.2}x vs single-threaded",
+            ops_per_sec as f64 / single.ops_per_sec as f64
+        ));
+    }
+    for thread in &result.threads {
+        out.push_str(&format!(
+            "\n  {}: {} ops, P50 {:.2} us, P95 {:.2} us, P99 {:.2} us, {} ops/sec",
+            thread.name, thread.op_count, thread.p50_us, thread.p95_us, thread.p99_us, thread.ops_per_sec,
+        ));
     }
+    out
 }
 
 fn format_realistic_result(result: &BenchResult) -> String {
     format!(
-        "{}:\n  Ops: {}\n  Total: {:.2} ms\n  Avg: {:.2} us\n  P50: {:.2} us\n  P95: {:.2} us\n  P99: {:.2} us\n  Throughput: {} ops/sec\n  Blocked: {:.1}%",
+        "{}:\n  Ops: {}\n  Total: {:.2} ms\n  Avg: {:.2} us\n  P50: {:.2} us\n  P95: {:.2} us\n  P99: {:.2} us\n  Throughput: {} ops/sec\n  {}: {:.1}%",
         result.name,
         result.op_count,
         result.total_ms,
@@ -388,10 +660,45 @@ fn format_realistic_result(result: &BenchResult) -> String {
         result.p95_us,
         result.p99_us,
         result.ops_per_sec,
+        result.hit_label,
         result.blocked_pct,
     )
 }
 
+/// Count token dictionary probes with and without the bloom pre-check.
+fn format_token_probes(snapshot: &Snapshot, requests: &[BenchRequest]) -> String {
+    let bloom = snapshot.token_bloom();
+    if bloom.is_empty() {
+        return "Token probes: snapshot has no token bloom filter".to_string();
+    }
+
+    let dict = snapshot.token_dict();
+    let mut tokens = 0usize;
+    let mut probes = 0usize;
+    let mut false_positives = 0usize;
+    for req in requests {
+        for hash in tokenize_url(&req.url) {
+            tokens += 1;
+            if bloom.may_contain(hash) {
+                probes += 1;
+                if dict.lookup(hash).is_none() {
+                    false_positives += 1;
+                }
+            }
+        }
+    }
+
+    let saved = if tokens > 0 {
+        (tokens - probes) as f64 / tokens as f64 * 100.0
+    } else {
+        0.0
+    };
+    format!(
+        "Token probes: {} tokens -> {} dict probes ({:.1}% skipped by bloom, {} false positives)",
+        tokens, probes, saved, false_positives
+    )
+}
+
 fn percentile(values: &[f64], p: f64) -> f64 {
     if values.is_empty() {
         return 0.0;
@@ -401,7 +708,7 @@ fn percentile(values: &[f64], p: f64) -> f64 {
     values[idx]
 }
 
-fn warmup_simple(matcher: &Matcher, requests: &[SimpleRequest]) {
+fn warmup_simple(engine: &Engine, requests: &[SimpleRequest]) {
     for _ in 0..100 {
         for req in requests {
             let bench_req = BenchRequest {
@@ -412,25 +719,118 @@ fn warmup_simple(matcher: &Matcher, requests: &[SimpleRequest]) {
                 frame_id: 0,
                 request_id: "warmup".to_string(),
             };
-            let _ = should_block(matcher, &bench_req);
+            let _ = should_block(engine, &bench_req);
         }
     }
 }
 
-fn warmup_realistic(matcher: &Matcher, requests: &[BenchRequest], warmup_ops: usize, use_match_request: bool) {
+/// Heap allocations per `Matcher::match_request` call, with fresh buffers
+/// versus a reused `MatchScratch`. Request contexts are built up front so only
+/// the matcher is counted.
+fn format_match_allocations(engine: &Engine, requests: &[BenchRequest]) -> String {
+    if requests.is_empty() {
+        return "Allocations/op: (no requests)".to_string();
+    }
+    let infos: Vec<RequestInfo<'_>> = requests.iter().map(request_info).collect();
+    let matcher = engine.matcher();
+
+    let before = alloc_count::allocations();
+    for info in &infos {
+        let _ = matcher.match_request(&info.context());
+    }
+    let fresh = alloc_count::allocations() - before;
+
+    let mut scratch = MatchScratch::new();
+    for info in &infos {
+        let _ = matcher.match_request_with_scratch(&info.context(), &mut scratch);
+    }
+    let before = alloc_count::allocations();
+    for info in &infos {
+        let _ = matcher.match_request_with_scratch(&info.context(), &mut scratch);
+    }
+    let reused = alloc_count::allocations() - before;
+
+    let ops = infos.len() as f64;
+    format!(
+        "Allocations/op: match_request {:.2}, match_request_with_scratch {:.2} ({} ops)",
+        fresh as f64 / ops,
+        reused as f64 / ops,
+        infos.len()
+    )
+}
+
+fn warmup_realistic(engine: &Engine, requests: &[BenchRequest], warmup_ops: usize, use_match_request: bool) {
     let loops = if requests.is_empty() { 0 } else { warmup_ops / requests.len() + 1 };
     for _ in 0..loops {
         for req in requests {
             if use_match_request {
-                let _ = match_request(matcher, req);
+                let _ = match_request(engine, req);
             } else {
-                let _ = should_block(matcher, req);
+                let _ = should_block(engine, req);
             }
         }
     }
 }
 
-fn load_trace_jsonl(path: &str, limit: usize) -> Result<Vec<BenchRequest>, String> {
+fn warmup_with(requests: &[BenchRequest], warmup_ops: usize, mut f: impl FnMut(&BenchRequest)) {
+    let loops = if requests.is_empty() { 0 } else { warmup_ops / requests.len() + 1 };
+    for _ in 0..loops {
+        requests.iter().for_each(&mut f);
+    }
+}
+
+/// Response header sets cycled through by `--mode headers`: a bare static
+/// file, a typical page and a header-heavy page with an existing CSP.
+const SYNTHETIC_HEADER_SETS: &[&[(&str, &str)]] = &[
+    &[("content-type", "text/html"), ("content-length", "5120")],
+    &[
+        ("content-type", "text/html; charset=utf-8"),
+        ("cache-control", "private, max-age=0"),
+        ("date", "Tue, 01 Oct 2024 12:00:00 GMT"),
+        ("server", "nginx"),
+        ("set-cookie", "session=abc123; Path=/; HttpOnly; Secure"),
+        ("strict-transport-security", "max-age=31536000; includeSubDomains"),
+        ("vary", "Accept-Encoding"),
+        ("x-frame-options", "SAMEORIGIN"),
+    ],
+    &[
+        ("content-type", "text/html; charset=utf-8"),
+        ("cache-control", "no-cache, no-store, must-revalidate"),
+        ("content-encoding", "br"),
+        (
+            "content-security-policy",
+            "default-src 'self'; script-src 'self' 'unsafe-inline' https://www.googletagmanager.com; img-src * data:",
+        ),
+        ("cross-origin-opener-policy", "same-origin-allow-popups"),
+        ("date", "Tue, 01 Oct 2024 12:00:00 GMT"),
+        ("etag", "W/\"5e1f-1a2b3c\""),
+        ("link", "<https://fonts.gstatic.com>; rel=preconnect"),
+        ("permissions-policy", "interest-cohort=()"),
+        ("refresh", "30"),
+        ("report-to", "{\"group\":\"default\",\"max_age\":10886400}"),
+        ("server", "cloudflare"),
+        ("server-timing", "cfRequestDuration;dur=12.3"),
+        ("set-cookie", "_ga=GA1.2.123.456; Path=/; Max-Age=63072000"),
+        ("set-cookie", "consent=pending; Path=/; SameSite=Lax"),
+        ("strict-transport-security", "max-age=63072000"),
+        ("vary", "Accept-Encoding, Cookie"),
+        ("x-content-type-options", "nosniff"),
+        ("x-powered-by", "Express"),
+        ("x-request-id", "4f2c0f0e-8f7d-4c4e-9a57-2b6f0f3c9d11"),
+    ],
+];
+
+/// Whether any header action (cancel, CSP, removal) applies.
+fn match_headers(engine: &Engine, req: &BenchRequest, set: usize) -> bool {
+    let headers: Vec<ResponseHeader<'_>> = SYNTHETIC_HEADER_SETS[set % SYNTHETIC_HEADER_SETS.len()]
+        .iter()
+        .map(|&(name, value)| ResponseHeader { name, value })
+        .collect();
+    let result = engine.headers_for_request(&request_info(req), &headers);
+    result.cancel || !result.csp_injections.is_empty() || !result.remove_headers.is_empty()
+}
+
+pub(crate) fn load_trace_jsonl(path: &str, limit: usize) -> Result<Vec<BenchRequest>, String> {
     let text = std::fs::read_to_string(path)
         .map_err(|e| format!("Failed to read trace '{}': {}", path, e))?;
     let mut out = Vec::new();
@@ -457,7 +857,7 @@ fn load_trace_jsonl(path: &str, limit: usize) -> Result<Vec<BenchRequest>, Strin
         let request_id = value
             .get("requestId")
             .and_then(|v| v.as_str())
-            .unwrap_or_else(|| "0");
+            .unwrap_or("0");
 
         out.push(BenchRequest {
             url: url.to_string(),
@@ -0,0 +1,192 @@
+//! `betterblocker.toml` build configuration
+//!
+//! Describes the lists a snapshot is built from, plus the compile settings
+//! that would otherwise be spelled out as flags, so `compile`, `check` and the
+//! benchmarks can share one definition:
+//!
+//! ```toml
+//! output = "dist/data/snapshot.ubx"
+//! psl = "data/public_suffix_list.dat"
+//! env = ["env_firefox"]
+//! dns_options = "skip"
+//! embed_redirects = false
+//!
+//! [optimizer]
+//! keep_shadowed = false
+//! max_rules = 300000
+//!
+//! [[lists]]
+//! source = "https://easylist.to/easylist/easylist.txt"
+//! name = "EasyList"
+//!
+//! [[lists]]
+//! source = "lists/custom.txt"
+//! id = 7
+//! tier = "user"
+//! ```
+//!
+//! Relative paths are resolved against the directory holding the config
+//! file. Flags given on the command line take precedence.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use bb_core::types::ListTier;
+use serde::Deserialize;
+
+use crate::fetch::is_url;
+use crate::DnsOptions;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BuildConfig {
+    pub lists: Vec<ListConfig>,
+    pub output: Option<String>,
+    /// Public suffix list to embed in the snapshot
+    pub psl: Option<String>,
+    /// Only checked: `none` is the one mode the snapshot format has
+    #[serde(default, rename = "compression")]
+    _compression: Compression,
+    /// Extra `!#if` flags
+    #[serde(default)]
+    pub env: Vec<String>,
+    pub dns_options: Option<DnsOptions>,
+    /// Embed redirect resources as `data:` URLs
+    #[serde(default)]
+    pub embed_redirects: bool,
+    /// Cache directory for URL sources
+    pub cache_dir: Option<String>,
+    #[serde(default)]
+    pub optimizer: OptimizerConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListConfig {
+    /// Path or http(s):// URL
+    pub source: String,
+    /// List id recorded on the list's rules; defaults to the list's position
+    pub id: Option<u16>,
+    /// Display name, used when the list has no `! Title:`
+    pub name: Option<String>,
+    /// Precedence over the other lists' rules
+    #[serde(default)]
+    pub tier: TierConfig,
+}
+
+/// [`ListTier`] as spelled in the config.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TierConfig {
+    #[default]
+    BuiltIn,
+    Custom,
+    User,
+}
+
+impl From<TierConfig> for ListTier {
+    fn from(tier: TierConfig) -> Self {
+        match tier {
+            TierConfig::BuiltIn => ListTier::BuiltIn,
+            TierConfig::Custom => ListTier::Custom,
+            TierConfig::User => ListTier::User,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OptimizerConfig {
+    /// Keep pattern rules already covered by a host-only block rule
+    #[serde(default)]
+    pub keep_shadowed: bool,
+    /// Rule budget; the least specific generic rules past it are dropped
+    #[serde(default)]
+    pub max_rules: Option<usize>,
+}
+
+/// Snapshot sections are always written uncompressed; the key exists so a
+/// config asking for anything else fails loudly instead of being ignored.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+}
+
+/// A list with its id and name settled.
+#[derive(Debug, Clone)]
+pub struct ListInput {
+    pub source: String,
+    pub id: u16,
+    pub name: Option<String>,
+    pub tier: ListTier,
+}
+
+impl ListInput {
+    /// `--input` values, numbered by position.
+    pub fn from_sources(sources: Vec<String>) -> Vec<Self> {
+        sources
+            .into_iter()
+            .enumerate()
+            .map(|(id, source)| Self {
+                source,
+                id: id as u16,
+                name: None,
+                tier: ListTier::BuiltIn,
+            })
+            .collect()
+    }
+}
+
+impl BuildConfig {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Failed to read '{}': {}", path, e))?;
+        let mut config: Self = toml::from_str(&text).map_err(|e| format!("Invalid config '{}': {}", path, e))?;
+        if config.lists.is_empty() {
+            return Err(format!("Config '{}' lists no filter lists", path));
+        }
+
+        let base = Path::new(path).parent().unwrap_or(Path::new(""));
+        let resolve = |value: &mut String| {
+            if !is_url(value) && Path::new(value.as_str()).is_relative() {
+                *value = base.join(&*value).to_string_lossy().into_owned();
+            }
+        };
+        for list in &mut config.lists {
+            resolve(&mut list.source);
+        }
+        for value in [&mut config.output, &mut config.psl, &mut config.cache_dir].into_iter().flatten() {
+            resolve(value);
+        }
+
+        config.list_inputs()?;
+        Ok(config)
+    }
+
+    /// Lists in config order. Lists without an `id` take their position.
+    pub fn list_inputs(&self) -> Result<Vec<ListInput>, String> {
+        let mut seen = HashSet::new();
+        self.lists
+            .iter()
+            .enumerate()
+            .map(|(index, list)| {
+                let id = list.id.unwrap_or(index as u16);
+                if !seen.insert(id) {
+                    return Err(format!("List id {} is used twice ('{}')", id, list.source));
+                }
+                Ok(ListInput {
+                    source: list.source.clone(),
+                    id,
+                    name: list.name.clone(),
+                    tier: list.tier.into(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn sources(&self) -> Vec<String> {
+        self.lists.iter().map(|list| list.source.clone()).collect()
+    }
+}
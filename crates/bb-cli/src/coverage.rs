@@ -0,0 +1,274 @@
+//! `coverage`: which rules a request trace ever exercises
+//!
+//! Replays a trace through [`Engine::explain_request`] and counts every rule
+//! that matched a request, not only the ones that decided it, so lists that
+//! duplicate each other show up as overlap rather than as dead weight. The
+//! report covers per-list hit rates, how many requests only one list
+//! matched, and the largest list sections (rules of one kind from one list)
+//! that never matched, with sample rules from each.
+//!
+//! Rules that only act on headers (`$csp`, `$removeheader`, `$header`,
+//! `$replace`, `##^responseheader`) can't match a request trace and are left
+//! out of the totals.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bb_core::snapshot::Snapshot;
+use bb_core::types::{MatchDecision, RuleAction};
+use bb_core::Engine;
+
+use crate::bench::{load_trace_jsonl, request_info};
+use crate::dump::{HostNames, RuleDecompiler};
+use crate::snapshot::map_snapshot;
+
+pub struct CoverageOptions {
+    pub snapshot_path: String,
+    pub trace_path: String,
+    pub trace_limit: usize,
+    /// Never-matched sections to list
+    pub top: usize,
+    /// Never-matched rules to print per section
+    pub samples: usize,
+    /// Files whose host names are used to reverse domain hashes
+    pub names: Vec<String>,
+    /// Write the full report as JSON
+    pub report: Option<String>,
+}
+
+#[derive(Default)]
+struct ListCoverage {
+    rules: usize,
+    matched_rules: usize,
+    /// Requests at least one of the list's rules matched
+    requests: usize,
+    /// Requests no other list matched
+    exclusive: usize,
+}
+
+/// Rules of one kind from one list.
+struct Section {
+    list_id: u16,
+    kind: &'static str,
+    rules: Vec<usize>,
+}
+
+pub fn run_coverage(opts: CoverageOptions) -> Result<(), String> {
+    let mapped = map_snapshot(Path::new(&opts.snapshot_path))?;
+    let snapshot = mapped.snapshot().map_err(|e| format!("Invalid snapshot: {}", e))?;
+    let mut engine = Engine::new(&snapshot);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    engine.matcher_mut().set_current_time(now);
+    let hosts = HostNames::load(&opts.names)?;
+    let decompiler = RuleDecompiler::new(&snapshot, &hosts);
+
+    let requests = load_trace_jsonl(&opts.trace_path, opts.trace_limit)?;
+    let rules = snapshot.rules();
+    let tracked: Vec<bool> = (0..rules.count)
+        .map(|rule_id| section_kind(rules.action(rule_id)).is_some() && decompiler.rule(rule_id).is_some())
+        .collect();
+
+    let mut hits = vec![0u32; rules.count];
+    let mut decisions: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut lists: BTreeMap<u16, ListCoverage> = BTreeMap::new();
+    let mut overlapping = 0;
+    for request in &requests {
+        let explanation = engine.explain_request(&request_info(request));
+        *decisions.entry(decision_name(explanation.result.decision)).or_default() += 1;
+
+        // A rule can be a candidate under several tokens; count it once.
+        let matched: BTreeSet<usize> = explanation
+            .candidates
+            .iter()
+            .filter(|candidate| candidate.matched() && tracked.get(candidate.rule_id) == Some(&true))
+            .map(|candidate| candidate.rule_id)
+            .collect();
+        let mut matched_lists = BTreeSet::new();
+        for &rule_id in &matched {
+            hits[rule_id] += 1;
+            matched_lists.insert(rules.list_id(rule_id));
+        }
+        for &list_id in &matched_lists {
+            let list = lists.entry(list_id).or_default();
+            list.requests += 1;
+            if matched_lists.len() == 1 {
+                list.exclusive += 1;
+            }
+        }
+        if matched_lists.len() > 1 {
+            overlapping += 1;
+        }
+    }
+
+    // (rule count, never-matched rules) per section
+    let mut sections: BTreeMap<(u16, &'static str), (usize, Vec<usize>)> = BTreeMap::new();
+    for rule_id in (0..rules.count).filter(|&rule_id| tracked[rule_id]) {
+        let list_id = rules.list_id(rule_id);
+        let list = lists.entry(list_id).or_default();
+        list.rules += 1;
+        let Some(kind) = section_kind(rules.action(rule_id)) else {
+            continue;
+        };
+        let section = sections.entry((list_id, kind)).or_default();
+        section.0 += 1;
+        if hits[rule_id] > 0 {
+            list.matched_rules += 1;
+        } else {
+            section.1.push(rule_id);
+        }
+    }
+    // Sections where every rule went unmatched, largest first.
+    let mut dead: Vec<Section> = sections
+        .into_iter()
+        .filter(|(_, (total, unmatched))| *total == unmatched.len())
+        .map(|((list_id, kind), (_, rules))| Section { list_id, kind, rules })
+        .collect();
+    dead.sort_by(|a, b| b.rules.len().cmp(&a.rules.len()).then(a.list_id.cmp(&b.list_id)));
+
+    let names = list_names(&snapshot);
+    let list_name = |list_id: u16| names.get(&list_id).cloned().unwrap_or_else(|| format!("list {}", list_id));
+    let total_rules: usize = lists.values().map(|list| list.rules).sum();
+    let matched_rules: usize = lists.values().map(|list| list.matched_rules).sum();
+
+    println!("Replayed {} requests from {}", requests.len(), opts.trace_path);
+    println!(
+        "Rules matched: {} of {} ({:.1}%)",
+        matched_rules,
+        total_rules,
+        percent(matched_rules, total_rules)
+    );
+    let decision_summary: Vec<String> = decisions.iter().map(|(name, count)| format!("{} {}", name, count)).collect();
+    println!("Decisions: {}", decision_summary.join(", "));
+    println!("Requests matched by more than one list: {}", overlapping);
+
+    println!();
+    println!("{:<5} {:>8} {:>8} {:>7} {:>9} {:>9}  list", "id", "rules", "matched", "hit%", "requests", "exclusive");
+    for (&list_id, list) in &lists {
+        println!(
+            "{:<5} {:>8} {:>8} {:>6.1}% {:>9} {:>9}  {}",
+            list_id,
+            list.rules,
+            list.matched_rules,
+            percent(list.matched_rules, list.rules),
+            list.requests,
+            list.exclusive,
+            list_name(list_id)
+        );
+    }
+
+    if !dead.is_empty() {
+        println!();
+        println!("Never-matched sections (top {} of {}):", opts.top.min(dead.len()), dead.len());
+        for section in dead.iter().take(opts.top) {
+            println!("  {} rules: {} {}", section.rules.len(), list_name(section.list_id), section.kind);
+            for &rule_id in section.rules.iter().take(opts.samples) {
+                println!("    #{:<6} {}", rule_id, decompiler.rule(rule_id).unwrap_or_default());
+            }
+        }
+    }
+
+    if let Some(report_path) = &opts.report {
+        let list_reports: Vec<_> = lists
+            .iter()
+            .map(|(&list_id, list)| {
+                serde_json::json!({
+                    "listId": list_id,
+                    "name": list_name(list_id),
+                    "rules": list.rules,
+                    "matchedRules": list.matched_rules,
+                    "requests": list.requests,
+                    "exclusiveRequests": list.exclusive,
+                })
+            })
+            .collect();
+        let dead_reports: Vec<_> = dead
+            .iter()
+            .map(|section| {
+                let samples: Vec<_> = section
+                    .rules
+                    .iter()
+                    .take(opts.samples)
+                    .map(|&rule_id| decompiler.rule(rule_id).unwrap_or_default())
+                    .collect();
+                serde_json::json!({
+                    "listId": section.list_id,
+                    "kind": section.kind,
+                    "rules": section.rules.len(),
+                    "samples": samples,
+                })
+            })
+            .collect();
+        let rule_hits: BTreeMap<String, u32> = hits
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(rule_id, &count)| (rule_id.to_string(), count))
+            .collect();
+        let report = serde_json::json!({
+            "requests": requests.len(),
+            "rules": total_rules,
+            "matchedRules": matched_rules,
+            "overlappingRequests": overlapping,
+            "decisions": decisions,
+            "lists": list_reports,
+            "neverMatchedSections": dead_reports,
+            "ruleHits": rule_hits,
+        });
+        let json = serde_json::to_string_pretty(&report).map_err(|e| format!("Failed to serialize report: {}", e))?;
+        fs::write(report_path, json).map_err(|e| format!("Failed to write '{}': {}", report_path, e))?;
+        println!("Wrote report to {}", report_path);
+    }
+
+    Ok(())
+}
+
+/// Section a rule action belongs to, or `None` for header-only actions.
+fn section_kind(action: u8) -> Option<&'static str> {
+    match RuleAction::try_from(action).ok()? {
+        RuleAction::Block => Some("block"),
+        RuleAction::Allow => Some("exception"),
+        RuleAction::RedirectDirective => Some("redirect-rule"),
+        RuleAction::Removeparam => Some("removeparam"),
+        RuleAction::CspInject
+        | RuleAction::RemoveHeader
+        | RuleAction::HeaderMatchBlock
+        | RuleAction::HeaderMatchAllow
+        | RuleAction::ResponseReplace
+        | RuleAction::ResponseCancel => None,
+    }
+}
+
+fn decision_name(decision: MatchDecision) -> &'static str {
+    match decision {
+        MatchDecision::Allow => "allow",
+        MatchDecision::Block => "block",
+        MatchDecision::Redirect => "redirect",
+        MatchDecision::Removeparam => "removeparam",
+    }
+}
+
+/// List titles, falling back to the source the list was compiled from.
+fn list_names(snapshot: &Snapshot<'_>) -> BTreeMap<u16, String> {
+    let mut names = BTreeMap::new();
+    if let Some(build) = snapshot.build_info() {
+        for list in &build.lists {
+            names.insert(list.list_id, list.source.to_string());
+        }
+    }
+    for meta in snapshot.all_list_metadata() {
+        if let Some(title) = meta.title {
+            names.insert(meta.list_id, title.to_string());
+        }
+    }
+    names
+}
+
+fn percent(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
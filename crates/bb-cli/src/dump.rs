@@ -0,0 +1,753 @@
+//! Snapshot decompiler
+//!
+//! Turns a UBX snapshot back into approximate filter-list text. Host names are
+//! only stored as hashes, so host-only rules and `domain=` constraints print as
+//! `{hash:...}` placeholders unless a names file supplies the original host.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bb_core::hash::{hash_domain, Hash64};
+use bb_core::snapshot::{
+    cosmetic_style_entry, decode_posting_list_with_count, header_spec_flags, read_u16_le, read_u32_le,
+    regex_pattern_entry, removeheader_flags, removeheader_spec_entry, replace_flags, PatternOp, SectionId, Snapshot,
+    COSMETIC_STYLE_ENTRY_SIZE, HEADER_SPEC_ENTRY_SIZE, NO_CONSTRAINT, NO_PATTERN, REGEX_PATTERN_ENTRY_SIZE, REMOVEHEADER_SPEC_ENTRY_SIZE,
+    REPLACE_SPEC_ENTRY_SIZE, UBX_MIN_VERSION, UBX_VERSION,
+};
+use bb_core::types::{PartyMask, RequestType, RuleAction, RuleFlags, SchemeMask};
+
+use crate::snapshot::read_snapshot;
+
+const NO_OPTION_ID: u32 = 0xFFFF_FFFF;
+
+/// Cosmetic, procedural, scriptlet and response-header entry size
+const SPECIAL_ENTRY_SIZE: usize = 16;
+
+const TYPE_OPTION_NAMES: &[(RequestType, &str)] = &[
+    (RequestType::OTHER, "other"),
+    (RequestType::SCRIPT, "script"),
+    (RequestType::IMAGE, "image"),
+    (RequestType::STYLESHEET, "stylesheet"),
+    (RequestType::OBJECT, "object"),
+    (RequestType::SUBDOCUMENT, "subdocument"),
+    (RequestType::MAIN_FRAME, "document"),
+    (RequestType::XMLHTTPREQUEST, "xmlhttprequest"),
+    (RequestType::WEBSOCKET, "websocket"),
+    (RequestType::FONT, "font"),
+    (RequestType::MEDIA, "media"),
+    (RequestType::PING, "ping"),
+    (RequestType::CSP_REPORT, "csp_report"),
+    (RequestType::BEACON, "beacon"),
+    (RequestType::FETCH, "fetch"),
+    (RequestType::SPECULATIVE, "speculative"),
+    (RequestType::AUDIOWORKLET, "audioworklet"),
+    (RequestType::PAINTWORKLET, "paintworklet"),
+    (RequestType::MANIFEST, "manifest"),
+    (RequestType::XSLT, "xslt"),
+];
+
+const SCHEME_OPTION_NAMES: &[(SchemeMask, &str)] = &[
+    (SchemeMask::HTTP, "http"),
+    (SchemeMask::HTTPS, "https"),
+    (SchemeMask::WS, "ws"),
+    (SchemeMask::WSS, "wss"),
+    (SchemeMask::DATA, "data"),
+    (SchemeMask::FTP, "ftp"),
+    (SchemeMask::EXTENSION, "extension"),
+    (SchemeMask::INTERNAL, "internal"),
+];
+
+pub struct DumpOptions {
+    pub input: String,
+    /// Output file, or directory when `split` is set; stdout if `None`
+    pub output: Option<String>,
+    pub split: bool,
+    /// Files whose host names are used to reverse domain hashes
+    pub names: Vec<String>,
+}
+
+pub fn run_dump(opts: DumpOptions) -> Result<(), String> {
+    // Dump is how snapshots from other builds get inspected, so it reads
+    // any version rather than only the ones the matcher accepts.
+    let bytes = read_snapshot(Path::new(&opts.input))?;
+    let snapshot = Snapshot::load_any_version(&bytes).map_err(|e| format!("Invalid snapshot: {}", e))?;
+    if !(UBX_MIN_VERSION..=UBX_VERSION).contains(&snapshot.version) {
+        eprintln!(
+            "warning: snapshot version {} is not one this build reads (v{}-v{}); output is best effort",
+            snapshot.version, UBX_MIN_VERSION, UBX_VERSION
+        );
+    }
+
+    let hosts = HostNames::load(&opts.names)?;
+    let decompiler = Decompiler { snapshot: &snapshot, hosts: &hosts };
+    let lists = decompiler.decompile();
+
+    let mut stats = DumpStats::default();
+    for lines in lists.values() {
+        stats.rules += lines.len();
+    }
+    stats.unresolved_hosts = decompiler.count_unresolved(&lists);
+
+    match (&opts.output, opts.split) {
+        (Some(dir), true) => {
+            let dir = PathBuf::from(dir);
+            fs::create_dir_all(&dir).map_err(|e| format!("Failed to create '{}': {}", dir.display(), e))?;
+            for (&list_id, lines) in &lists {
+                let path = dir.join(format!("list-{}.txt", list_id));
+                let mut text = String::new();
+                decompiler.write_list(&mut text, list_id, lines);
+                fs::write(&path, text).map_err(|e| format!("Failed to write '{}': {}", path.display(), e))?;
+            }
+        }
+        (None, true) => return Err("--split needs --output <dir>".to_string()),
+        (output, false) => {
+            let text = decompiler.combined_text(&lists);
+            match output {
+                Some(path) => {
+                    fs::write(path, text).map_err(|e| format!("Failed to write '{}': {}", path, e))?
+                }
+                None => {
+                    print!("{}", text);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    println!("Dumped {} rules from {} list(s)", stats.rules, lists.len());
+    if stats.unresolved_hosts > 0 {
+        println!(
+            "  {} rules reference hashed hosts; pass --names <list> to resolve them",
+            stats.unresolved_hosts
+        );
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct DumpStats {
+    rules: usize,
+    unresolved_hosts: usize,
+}
+
+/// Reverse lookup from domain hash to a host name seen in a names file.
+#[derive(Default)]
+pub struct HostNames {
+    by_hash: HashMap<Hash64, String>,
+}
+
+impl HostNames {
+    pub fn load(paths: &[String]) -> Result<Self, String> {
+        let mut hosts = HostNames::default();
+        for path in paths {
+            let text = fs::read_to_string(path).map_err(|e| format!("Failed to read '{}': {}", path, e))?;
+            hosts.add_text(&text);
+        }
+        Ok(hosts)
+    }
+
+    /// Collect every host-like token in `text`, plus its parent domains.
+    fn add_text(&mut self, text: &str) {
+        let is_host_char = |ch: char| ch.is_ascii_alphanumeric() || ch == '.' || ch == '-' || ch == '_';
+        for token in text.split(|ch: char| !is_host_char(ch)) {
+            let mut host = token.trim_matches('.');
+            while host.contains('.') {
+                let lower = host.to_ascii_lowercase();
+                self.by_hash.entry(hash_domain(&lower)).or_insert(lower);
+                host = match host.split_once('.') {
+                    Some((_, parent)) => parent,
+                    None => break,
+                };
+            }
+        }
+    }
+
+    pub fn get(&self, hash: Hash64) -> Option<&str> {
+        self.by_hash.get(&hash).map(String::as_str)
+    }
+
+    fn name(&self, hash: Hash64) -> String {
+        match self.by_hash.get(&hash) {
+            Some(name) => name.clone(),
+            None => format!("{{hash:{:08x}{:08x}}}", hash.hi, hash.lo),
+        }
+    }
+}
+
+struct Decompiler<'s, 'a> {
+    snapshot: &'s Snapshot<'a>,
+    hosts: &'s HostNames,
+}
+
+/// Approximate filter text for single network rules, e.g. the candidates
+/// `query --explain` lists.
+pub struct RuleDecompiler<'s, 'a> {
+    decompiler: Decompiler<'s, 'a>,
+    rule_hosts: HashMap<u32, Hash64>,
+    regex_sources: HashMap<u32, &'a str>,
+}
+
+impl<'s, 'a> RuleDecompiler<'s, 'a> {
+    pub fn new(snapshot: &'s Snapshot<'a>, hosts: &'s HostNames) -> Self {
+        let decompiler = Decompiler { snapshot, hosts };
+        Self {
+            rule_hosts: decompiler.rule_hosts(),
+            regex_sources: decompiler.regex_sources(),
+            decompiler,
+        }
+    }
+
+    pub fn rule(&self, rule_id: usize) -> Option<String> {
+        self.decompiler.rule_line(rule_id, &self.rule_hosts, &self.regex_sources)
+    }
+}
+
+impl<'a> Decompiler<'_, 'a> {
+    /// Decompile every rule, grouped by list id.
+    fn decompile(&self) -> BTreeMap<u16, Vec<String>> {
+        let mut lists: BTreeMap<u16, Vec<String>> = BTreeMap::new();
+        let rule_hosts = self.rule_hosts();
+        let regex_sources = self.regex_sources();
+        let rules = self.snapshot.rules();
+
+        for rule_id in 0..rules.count {
+            if let Some(line) = self.rule_line(rule_id, &rule_hosts, &regex_sources) {
+                lists.entry(rules.list_id(rule_id)).or_default().push(line);
+            }
+        }
+
+        self.special_rules(&mut lists);
+        lists
+    }
+
+    fn rule_line(
+        &self,
+        rule_id: usize,
+        rule_hosts: &HashMap<u32, Hash64>,
+        regex_sources: &HashMap<u32, &str>,
+    ) -> Option<String> {
+        let rules = self.snapshot.rules();
+        let action = RuleAction::try_from(rules.action(rule_id)).unwrap_or(RuleAction::Block);
+        let pattern_id = rules.pattern_id(rule_id);
+        let body = if pattern_id != NO_PATTERN {
+            self.decode_pattern(pattern_id as usize)?
+        } else if let Some(source) = regex_sources.get(&(rule_id as u32)) {
+            format!("/{}/", source)
+        } else if let Some(hash) = rule_hosts.get(&(rule_id as u32)) {
+            format!("||{}^", self.hosts.name(*hash))
+        } else if action == RuleAction::ResponseCancel {
+            // Placeholder row for a cosmetic/scriptlet rule; those come
+            // from their own sections.
+            return None;
+        } else {
+            // Host-only rules outside the domain sets keep no host at all.
+            "||{host}^".to_string()
+        };
+        self.network_rule(rule_id, action, body)
+    }
+
+    /// Map rule ids of host-only rules to the host hash they are filed under.
+    fn rule_hosts(&self) -> HashMap<u32, Hash64> {
+        let postings = self.snapshot.domain_postings();
+        let mut rule_hosts = HashMap::new();
+        for set in [self.snapshot.domain_block_set(), self.snapshot.domain_allow_set()] {
+            for (hash, value) in set.entries() {
+                match postings {
+                    Some(postings) => {
+                        for rule_id in decode_posting_list_with_count(postings, value as usize) {
+                            rule_hosts.insert(rule_id, hash);
+                        }
+                    }
+                    None => {
+                        rule_hosts.insert(value, hash);
+                    }
+                }
+            }
+        }
+        rule_hosts
+    }
+
+    /// Map rule ids of `/.../` filters to their regex source.
+    fn regex_sources(&self) -> HashMap<u32, &'a str> {
+        let section = self.snapshot.regex_patterns();
+        let mut sources = HashMap::new();
+        for_each_entry(section, REGEX_PATTERN_ENTRY_SIZE, |base| {
+            let source = self.snapshot.get_string(
+                read_u32_le(section, base + regex_pattern_entry::SOURCE_OFF) as usize,
+                read_u32_le(section, base + regex_pattern_entry::SOURCE_LEN) as usize,
+            );
+            if let Some(source) = source {
+                sources.insert(read_u32_le(section, base + regex_pattern_entry::RULE_ID), source);
+            }
+        });
+        sources
+    }
+
+    fn decode_pattern(&self, pattern_id: usize) -> Option<String> {
+        let pool = self.snapshot.pattern_pool();
+        let entry = pool.get_pattern(pattern_id)?;
+        let program = pool.get_program(&entry);
+
+        let mut out = String::new();
+        let mut pc = 0;
+        while pc < program.len() {
+            match PatternOp::try_from(program[pc]).ok()? {
+                PatternOp::FindLit => {
+                    if pc + 7 > program.len() {
+                        return None;
+                    }
+                    let off = read_u32_le(program, pc + 1) as usize;
+                    let len = read_u16_le(program, pc + 5) as usize;
+                    out.push_str(self.snapshot.get_string(off, len)?);
+                    pc += 7;
+                    continue;
+                }
+                PatternOp::HostAnchor => out.push_str("||"),
+                PatternOp::AssertStart | PatternOp::AssertEnd => out.push('|'),
+                PatternOp::AssertBoundary => out.push('^'),
+                PatternOp::SkipAny => out.push('*'),
+                PatternOp::Done => break,
+            }
+            pc += 1;
+        }
+        Some(out)
+    }
+
+    fn network_rule(&self, rule_id: usize, action: RuleAction, body: String) -> Option<String> {
+        let rules = self.snapshot.rules();
+        let flags = RuleFlags::from_bits_truncate(rules.flags(rule_id));
+        let option_id = rules.option_id(rule_id);
+        let mut options = Vec::new();
+        let mut exception = false;
+
+        push_mask_options(
+            &mut options,
+            rules.type_mask(rule_id),
+            RequestType::ALL.bits(),
+            TYPE_OPTION_NAMES.iter().map(|(ty, name)| (ty.bits(), *name)),
+        );
+        if rules.type_mask(rule_id) & RequestType::POPUP.bits() != 0 {
+            options.push("popup".to_string());
+        }
+        match PartyMask::from_bits_truncate(rules.party_mask(rule_id)) {
+            PartyMask::THIRD_PARTY => options.push("third-party".to_string()),
+            PartyMask::FIRST_PARTY => options.push("first-party".to_string()),
+            _ => {}
+        }
+        let scheme_mask = SchemeMask::from_bits_truncate(rules.scheme_mask(rule_id));
+        if SchemeMask::WEB.contains(scheme_mask) {
+            push_mask_options(
+                &mut options,
+                scheme_mask.bits() as u32,
+                SchemeMask::WEB.bits() as u32,
+                SCHEME_OPTION_NAMES
+                    .iter()
+                    .filter(|(scheme, _)| SchemeMask::WEB.contains(*scheme))
+                    .map(|(scheme, name)| (scheme.bits() as u32, *name)),
+            );
+        } else {
+            // Negations never reach extension or internal schemes, so name each one.
+            let named = SCHEME_OPTION_NAMES.iter().filter(|(scheme, _)| scheme_mask.contains(*scheme));
+            options.extend(named.map(|(_, name)| name.to_string()));
+        }
+
+        for (flag, name) in [
+            (RuleFlags::IMPORTANT, "important"),
+            (RuleFlags::MATCH_CASE, "match-case"),
+            (RuleFlags::ELEMHIDE, "elemhide"),
+            (RuleFlags::GENERICHIDE, "generichide"),
+            (RuleFlags::SPECIFICHIDE, "specifichide"),
+            (RuleFlags::GENERICBLOCK, "genericblock"),
+        ] {
+            if flags.contains(flag) {
+                options.push(name.to_string());
+            }
+        }
+
+        match action {
+            RuleAction::Allow => {
+                exception = true;
+                if option_id != NO_OPTION_ID {
+                    if flags.contains(RuleFlags::REDIRECT_RULE_EXCEPTION) {
+                        options.push(format!("redirect-rule={}", self.redirect_name(option_id)?));
+                    } else {
+                        options.push(format!("removeparam={}", self.spec_string(self.snapshot.removeparam_specs(), option_id)?));
+                    }
+                }
+            }
+            RuleAction::Block => {
+                if flags.contains(RuleFlags::FROM_REDIRECT_EQ) && option_id != NO_OPTION_ID {
+                    options.push(format!("redirect={}", self.redirect_name(option_id)?));
+                }
+            }
+            RuleAction::RedirectDirective => {
+                options.push(format!("redirect-rule={}", self.redirect_name(option_id)?));
+            }
+            RuleAction::Removeparam => {
+                options.push(format!("removeparam={}", self.spec_string(self.snapshot.removeparam_specs(), option_id)?));
+            }
+            RuleAction::CspInject => {
+                exception = flags.contains(RuleFlags::CSP_EXCEPTION);
+                match self.spec_string(self.snapshot.csp_specs(), option_id) {
+                    Some(csp) if !csp.is_empty() => options.push(format!("csp={}", csp)),
+                    _ => options.push("csp".to_string()),
+                }
+            }
+            RuleAction::HeaderMatchBlock | RuleAction::HeaderMatchAllow => {
+                exception = action == RuleAction::HeaderMatchAllow;
+                options.push(format!("header={}", self.header_spec(option_id)?));
+            }
+            RuleAction::ResponseReplace => {
+                let (replace, is_exception) = self.replace_spec(option_id)?;
+                exception = is_exception;
+                options.push(replace);
+            }
+            RuleAction::RemoveHeader => {
+                exception = flags.contains(RuleFlags::REMOVEHEADER_EXCEPTION);
+                options.push(self.removeheader_spec(option_id)?);
+            }
+            RuleAction::ResponseCancel => {}
+        }
+
+        if let Some(domains) = self.constraint_domains(rules.domain_constraint_offset(rule_id), "|") {
+            options.push(format!("domain={}", domains));
+        }
+
+        let mut line = String::new();
+        if exception {
+            line.push_str("@@");
+        }
+        line.push_str(&body);
+        if !options.is_empty() {
+            line.push('$');
+            line.push_str(&options.join(","));
+        }
+        Some(line)
+    }
+
+    /// Cosmetic, style, procedural, scriptlet and response-header rules.
+    fn special_rules(&self, lists: &mut BTreeMap<u16, Vec<String>>) {
+        let sections = [
+            (self.snapshot.cosmetic_rules(), ""),
+            (self.snapshot.procedural_rules(), ""),
+            (self.snapshot.scriptlet_rules(), "+js"),
+            (self.snapshot.responseheader_rules(), "^responseheader"),
+        ];
+        for (section, wrapper) in sections {
+            for_each_entry(section, SPECIAL_ENTRY_SIZE, |base| {
+                let constraint_off = read_u32_le(section, base);
+                let text_off = read_u32_le(section, base + 4) as usize;
+                let text_len = read_u32_le(section, base + 8) as usize;
+                let flags = read_u16_le(section, base + 12);
+                let list_id = read_u16_le(section, base + 14);
+                let Some(text) = self.snapshot.get_string(text_off, text_len) else {
+                    return;
+                };
+                let body = if wrapper.is_empty() {
+                    text.to_string()
+                } else {
+                    format!("{}({})", wrapper, text)
+                };
+                // Response-header entries only use the exception bit.
+                let line = self.cosmetic_line(constraint_off, flags & 1 != 0, &body);
+                lists.entry(list_id).or_default().push(line);
+            });
+        }
+
+        let section = self.snapshot.cosmetic_style_rules();
+        for_each_entry(section, COSMETIC_STYLE_ENTRY_SIZE, |base| {
+            let string_at = |off: usize, len: usize| {
+                self.snapshot.get_string(
+                    read_u32_le(section, base + off) as usize,
+                    read_u32_le(section, base + len) as usize,
+                )
+            };
+            let (Some(selector), Some(style)) = (
+                string_at(cosmetic_style_entry::SELECTOR_OFF, cosmetic_style_entry::SELECTOR_LEN),
+                string_at(cosmetic_style_entry::STYLE_OFF, cosmetic_style_entry::STYLE_LEN),
+            ) else {
+                return;
+            };
+            let constraint_off = read_u32_le(section, base + cosmetic_style_entry::CONSTRAINT_OFFSET);
+            let flags = read_u16_le(section, base + cosmetic_style_entry::FLAGS);
+            let list_id = read_u16_le(section, base + cosmetic_style_entry::LIST_ID);
+            let line = self.cosmetic_line(constraint_off, flags & 1 != 0, &format!("{}:style({})", selector, style));
+            lists.entry(list_id).or_default().push(line);
+        });
+    }
+
+    fn cosmetic_line(&self, constraint_off: u32, is_exception: bool, body: &str) -> String {
+        let domains = self.constraint_domains(constraint_off, ",").unwrap_or_default();
+        let marker = if is_exception { "#@#" } else { "##" };
+        format!("{}{}{}", domains, marker, body)
+    }
+
+    /// Render a domain constraint as `a.com<sep>~b.com`.
+    fn constraint_domains(&self, constraint_off: u32, separator: &str) -> Option<String> {
+        if constraint_off == NO_CONSTRAINT {
+            return None;
+        }
+        let pool = self.snapshot.domain_constraints();
+        let offset = constraint_off as usize;
+        if offset + 4 > pool.len() {
+            return None;
+        }
+        let include_count = read_u16_le(pool, offset) as usize;
+        let exclude_count = read_u16_le(pool, offset + 2) as usize;
+        if offset + 4 + (include_count + exclude_count) * 8 > pool.len() {
+            return None;
+        }
+
+        let domains: Vec<String> = (0..include_count + exclude_count)
+            .map(|idx| {
+                let pos = offset + 4 + idx * 8;
+                let name = self.hosts.name(Hash64::new(read_u32_le(pool, pos), read_u32_le(pool, pos + 4)));
+                if idx < include_count {
+                    name
+                } else {
+                    format!("~{}", name)
+                }
+            })
+            .collect();
+        (!domains.is_empty()).then(|| domains.join(separator))
+    }
+
+    fn redirect_name(&self, option_id: u32) -> Option<&str> {
+        let section = self.snapshot.get_section(SectionId::RedirectResources)?;
+        self.spec_string_sized(section, option_id, 20)
+    }
+
+    /// String of a 12-byte (off, len, reserved) spec entry.
+    fn spec_string(&self, section: &[u8], option_id: u32) -> Option<&str> {
+        self.spec_string_sized(section, option_id, 12)
+    }
+
+    fn spec_string_sized(&self, section: &[u8], option_id: u32, entry_size: usize) -> Option<&str> {
+        let base = spec_entry(section, option_id, entry_size)?;
+        self.snapshot.get_string(
+            read_u32_le(section, base) as usize,
+            read_u32_le(section, base + 4) as usize,
+        )
+    }
+
+    fn header_spec(&self, option_id: u32) -> Option<String> {
+        let section = self.snapshot.header_specs();
+        let base = spec_entry(section, option_id, HEADER_SPEC_ENTRY_SIZE)?;
+        let name = self.spec_string_sized(section, option_id, HEADER_SPEC_ENTRY_SIZE)?;
+        let flags = read_u32_le(section, base + 16);
+        let direction = if flags & header_spec_flags::REQUEST != 0 { "request:" } else { "" };
+        let value_len = read_u32_le(section, base + 12) as usize;
+        if value_len == 0 {
+            return Some(format!("{}{}", direction, name));
+        }
+        let value = self.snapshot.get_string(read_u32_le(section, base + 8) as usize, value_len)?;
+        let negate = if flags & header_spec_flags::NEGATE != 0 { "~" } else { "" };
+        Some(format!("{}{}:{}{}", direction, name, negate, value))
+    }
+
+    /// `removeheader=[request:]name`, or bare `removeheader` for a disable-all exception.
+    fn removeheader_spec(&self, option_id: u32) -> Option<String> {
+        let section = self.snapshot.removeheader_specs();
+        let base = spec_entry(section, option_id, REMOVEHEADER_SPEC_ENTRY_SIZE)?;
+        let name = self.spec_string_sized(section, option_id, REMOVEHEADER_SPEC_ENTRY_SIZE)?;
+        if name.is_empty() {
+            return Some("removeheader".to_string());
+        }
+        let flags = read_u32_le(section, base + removeheader_spec_entry::FLAGS);
+        let direction = if flags & removeheader_flags::REQUEST != 0 { "request:" } else { "" };
+        Some(format!("removeheader={}{}", direction, name))
+    }
+
+    /// `replace=/regex/replacement/flags` and whether it is an exception.
+    fn replace_spec(&self, option_id: u32) -> Option<(String, bool)> {
+        let section = self.snapshot.replace_specs();
+        let base = spec_entry(section, option_id, REPLACE_SPEC_ENTRY_SIZE)?;
+        let pattern = self.spec_string_sized(section, option_id, REPLACE_SPEC_ENTRY_SIZE)?;
+        let replacement = self.snapshot.get_string(
+            read_u32_le(section, base + 8) as usize,
+            read_u32_le(section, base + 12) as usize,
+        )?;
+        let flags = read_u32_le(section, base + 16);
+        let is_exception = flags & replace_flags::EXCEPTION != 0;
+        if pattern.is_empty() {
+            return Some(("replace".to_string(), is_exception));
+        }
+
+        let mut regex_flags = String::new();
+        for (bit, ch) in [
+            (replace_flags::IGNORE_CASE, 'i'),
+            (replace_flags::GLOBAL, 'g'),
+            (replace_flags::MULTILINE, 'm'),
+            (replace_flags::DOT_ALL, 's'),
+        ] {
+            if flags & bit != 0 {
+                regex_flags.push(ch);
+            }
+        }
+        let escape = |text: &str| text.replace(',', "\\,");
+        let replacement = replacement.replace('/', "\\/");
+        Some((
+            format!("replace=/{}/{}/{}", escape(pattern), escape(&replacement), regex_flags),
+            is_exception,
+        ))
+    }
+
+    fn write_list(&self, out: &mut String, list_id: u16, lines: &[String]) {
+        let meta = self.snapshot.list_metadata(list_id);
+        let _ = writeln!(out, "! List {} (decompiled from snapshot)", list_id);
+        if let Some(meta) = meta {
+            if let Some(title) = meta.title {
+                let _ = writeln!(out, "! Title: {}", title);
+            }
+            if let Some(version) = meta.version {
+                let _ = writeln!(out, "! Version: {}", version);
+            }
+            if let Some(homepage) = meta.homepage {
+                let _ = writeln!(out, "! Homepage: {}", homepage);
+            }
+            if let Some(secs) = meta.expires_secs {
+                let _ = writeln!(out, "! Expires: {} hours", secs / 3600);
+            }
+        }
+        for line in lines {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+
+    /// Every list in one file, separated by blank lines.
+    fn combined_text(&self, lists: &BTreeMap<u16, Vec<String>>) -> String {
+        let mut text = String::new();
+        for (&list_id, lines) in lists {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            self.write_list(&mut text, list_id, lines);
+        }
+        text
+    }
+
+    fn count_unresolved(&self, lists: &BTreeMap<u16, Vec<String>>) -> usize {
+        lists
+            .values()
+            .flatten()
+            .filter(|line| line.contains("{hash:") || line.contains("{host}"))
+            .count()
+    }
+}
+
+/// Emit `name` for every bit in `mask`, or `~name` for every bit missing from
+/// it when that is shorter. A zero mask means "all" and emits nothing.
+fn push_mask_options<'n>(
+    options: &mut Vec<String>,
+    mask: u32,
+    all: u32,
+    names: impl Iterator<Item = (u32, &'n str)> + Clone,
+) {
+    if mask == 0 || mask & all == all {
+        return;
+    }
+    let included = names.clone().filter(|(bit, _)| mask & bit != 0).count();
+    let excluded = names.clone().filter(|(bit, _)| mask & bit == 0).count();
+    for (bit, name) in names {
+        if excluded < included {
+            if mask & bit == 0 {
+                options.push(format!("~{}", name));
+            }
+        } else if mask & bit != 0 {
+            options.push(name.to_string());
+        }
+    }
+}
+
+/// Byte offset of `option_id` in a `count` + fixed-size entry section.
+fn spec_entry(section: &[u8], option_id: u32, entry_size: usize) -> Option<usize> {
+    if option_id == NO_OPTION_ID || section.len() < 4 {
+        return None;
+    }
+    if option_id as usize >= read_u32_le(section, 0) as usize {
+        return None;
+    }
+    let base = 4 + option_id as usize * entry_size;
+    (base + entry_size <= section.len()).then_some(base)
+}
+
+fn for_each_entry(section: &[u8], entry_size: usize, mut f: impl FnMut(usize)) {
+    if section.len() < 4 {
+        return;
+    }
+    let count = read_u32_le(section, 0) as usize;
+    for idx in 0..count {
+        let base = 4 + idx * entry_size;
+        if base + entry_size > section.len() {
+            break;
+        }
+        f(base);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bb_compiler::{build_snapshot, parse_filter_list};
+    use bb_core::snapshot::SnapshotError;
+
+    use super::*;
+
+    const LIST: &str = "\
+||ads.example.com^
+||tracker.example^$third-party,image,script
+||cdn.example/ads/*$script,domain=news.example|~sports.news.example
+||img.example/banner/*$image,important
+@@||ads.example.com/allowed/*$~third-party
+||media.example^$redirect=noop.js,script
+||site.example^$removeparam=utm_source
+||site.example^$csp=script-src 'self'
+||api.example^$removeheader=location
+/ad-[0-9]+\\.js/$script
+example.com##.ad-banner
+example.com#@#.ad-banner-ok
+##.sponsored
+example.com##.box:style(color: red)
+example.com##.post:has-text(Sponsored)
+example.com##+js(set-constant, ads, false)
+";
+
+    fn dump(bytes: &[u8], hosts: &HostNames) -> String {
+        let snapshot = Snapshot::load_any_version(bytes).expect("snapshot should load");
+        let decompiler = Decompiler { snapshot: &snapshot, hosts };
+        decompiler.combined_text(&decompiler.decompile())
+    }
+
+    #[test]
+    fn dump_recompiles_to_the_same_rules() {
+        let mut hosts = HostNames::default();
+        hosts.add_text(LIST);
+
+        let text = dump(&build_snapshot(&parse_filter_list(LIST)), &hosts);
+        assert!(!text.contains("{hash:"), "{}", text);
+        for line in ["||cdn.example/ads/*$script,domain=news.example|~sports.news.example", "example.com#@#.ad-banner-ok"] {
+            assert!(text.lines().any(|dumped| dumped == line), "missing {:?} in\n{}", line, text);
+        }
+
+        let recompiled = dump(&build_snapshot(&parse_filter_list(&text)), &hosts);
+        assert_eq!(recompiled, text);
+    }
+
+    #[test]
+    fn dump_reads_snapshots_from_older_and_newer_builds() {
+        let mut bytes = build_snapshot(&parse_filter_list(LIST));
+        bytes[4..6].copy_from_slice(&(UBX_VERSION + 1).to_le_bytes());
+        assert!(matches!(Snapshot::load(&bytes), Err(SnapshotError::UnsupportedVersion(_))));
+        assert!(dump(&bytes, &HostNames::default()).contains("##.sponsored"));
+
+        let filters = include_str!("../../../testdata/compat/filters.txt");
+        let mut hosts = HostNames::default();
+        hosts.add_text(filters);
+        let current = dump(&build_snapshot(&parse_filter_list(filters)), &hosts);
+        let v1 = dump(include_bytes!("../../../testdata/compat/v1.ubx"), &hosts);
+        assert_eq!(v1, current);
+    }
+}
@@ -0,0 +1,145 @@
+//! `export`: unconditional host blocks as a hosts file or domain list
+//!
+//! Only `||host^` block rules without options end up in the domain block set
+//! unconditionally, and those are exactly what DNS-level blockers such as
+//! Pi-hole or dnsmasq can enforce. The snapshot stores hosts as hashes, so
+//! the names come from the filter lists it was compiled from.
+
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::path::Path;
+
+use clap::ValueEnum;
+
+use bb_core::hash::hash_domain;
+use bb_core::psl::walk_host_suffixes;
+use bb_core::snapshot::{decode_posting_list_with_count, Snapshot, NO_CONSTRAINT, NO_PATTERN};
+use bb_core::types::RuleAction;
+
+use crate::dump::HostNames;
+use crate::snapshot::map_snapshot;
+
+const NO_OPTION_ID: u32 = 0xFFFF_FFFF;
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// `0.0.0.0 <host>` lines
+    Hosts,
+    /// One host per line; consumers such as dnsmasq block subdomains too
+    Domains,
+}
+
+pub struct ExportOptions {
+    pub input: String,
+    /// Output file; stdout if `None`
+    pub output: Option<String>,
+    pub format: ExportFormat,
+    /// Files whose host names are used to reverse domain hashes
+    pub names: Vec<String>,
+}
+
+pub fn run_export(opts: ExportOptions) -> Result<(), String> {
+    let mapped = map_snapshot(Path::new(&opts.input))?;
+    let snapshot = mapped.snapshot().map_err(|e| format!("Invalid snapshot: {}", e))?;
+    let names = HostNames::load(&opts.names)?;
+
+    let export = blocked_hosts(&snapshot, &names, opts.format == ExportFormat::Domains);
+
+    let mut text = String::new();
+    if opts.format == ExportFormat::Hosts {
+        text.push_str("# Unconditional host blocks exported by bb-cli\n");
+    }
+    for host in &export.hosts {
+        if opts.format == ExportFormat::Hosts {
+            text.push_str("0.0.0.0 ");
+        }
+        text.push_str(host);
+        text.push('\n');
+    }
+
+    match &opts.output {
+        Some(path) => {
+            fs::write(path, text).map_err(|e| format!("Failed to write '{}': {}", path, e))?;
+            println!("Exported {} hosts to {}", export.hosts.len(), path);
+        }
+        None => print!("{}", text),
+    }
+
+    // Keep stdout clean for piping; the summary goes to stderr.
+    if export.excepted > 0 {
+        eprintln!("  {} hosts skipped: an exception applies to the host", export.excepted);
+    }
+    if export.unresolved > 0 {
+        eprintln!(
+            "  {} hosts skipped: hash not found in --names; pass the lists the snapshot was compiled from",
+            export.unresolved
+        );
+    }
+    Ok(())
+}
+
+struct HostExport {
+    hosts: BTreeSet<String>,
+    /// Blocked hosts left out because an exception applies to them
+    excepted: usize,
+    /// Blocked hosts whose name the names files don't contain
+    unresolved: usize,
+}
+
+/// With `subdomains`, hosts are skipped when an exception covers one of their
+/// subdomains as well, since blocking the domain would override it.
+fn blocked_hosts(snapshot: &Snapshot<'_>, names: &HostNames, subdomains: bool) -> HostExport {
+    let rules = snapshot.rules();
+    let postings = snapshot.domain_postings();
+    let allow_set = snapshot.domain_allow_set();
+    // A hosts file can't retire a rule on its `$expires=` date.
+    let expiring: HashSet<usize> = snapshot.rule_expiry().map(|(rule_id, _)| rule_id as usize).collect();
+    let is_unconditional = |rule_id: usize| {
+        !expiring.contains(&rule_id)
+            && RuleAction::try_from(rules.action(rule_id)) == Ok(RuleAction::Block)
+            && rules.type_mask(rule_id) == 0
+            && rules.party_mask(rule_id) == 0
+            && rules.scheme_mask(rule_id) == 0
+            && rules.pattern_id(rule_id) == NO_PATTERN
+            && rules.domain_constraint_offset(rule_id) == NO_CONSTRAINT
+            && rules.option_id(rule_id) == NO_OPTION_ID
+    };
+
+    let mut excepted_parents = HashSet::new();
+    if subdomains {
+        for (hash, _) in allow_set.entries() {
+            if let Some(host) = names.get(hash) {
+                excepted_parents.extend(walk_host_suffixes(host).skip(1).map(hash_domain));
+            }
+        }
+    }
+
+    let mut export = HostExport {
+        hosts: BTreeSet::new(),
+        excepted: 0,
+        unresolved: 0,
+    };
+    for (hash, value) in snapshot.domain_block_set().entries() {
+        let unconditional = match postings {
+            Some(postings) => decode_posting_list_with_count(postings, value as usize)
+                .into_iter()
+                .any(|rule_id| is_unconditional(rule_id as usize)),
+            None => is_unconditional(value as usize),
+        };
+        if !unconditional {
+            continue;
+        }
+        let Some(host) = names.get(hash) else {
+            export.unresolved += 1;
+            continue;
+        };
+        if excepted_parents.contains(&hash)
+            || walk_host_suffixes(host).any(|suffix| allow_set.lookup(hash_domain(suffix)).is_some())
+        {
+            export.excepted += 1;
+            continue;
+        }
+        export.hosts.insert(host.to_string());
+    }
+    export
+}
@@ -0,0 +1,160 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bb_core::hash::hash64;
+
+/// Where downloaded lists are cached and whether to ignore the cache.
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    pub cache_dir: PathBuf,
+    pub refresh: bool,
+}
+
+impl FetchOptions {
+    /// `$XDG_CACHE_HOME/bb-cli/lists`, falling back to `~/.cache`.
+    pub fn default_cache_dir() -> PathBuf {
+        std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .unwrap_or_else(|| PathBuf::from(".cache"))
+            .join("bb-cli")
+            .join("lists")
+    }
+}
+
+/// Validators from the last successful download of a URL.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheMeta {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+#[cfg_attr(not(feature = "fetch"), allow(dead_code))]
+enum Fetched {
+    NotModified,
+    Body {
+        text: String,
+        etag: Option<String>,
+        last_modified: Option<String>,
+    },
+}
+
+pub fn is_url(input: &str) -> bool {
+    input.starts_with("https://") || input.starts_with("http://")
+}
+
+/// Map each input to a local file, downloading `http(s)://` inputs into the
+/// cache. Cached copies are revalidated with ETag / Last-Modified unless
+/// `refresh` is set, and reused with a warning if the server is unreachable.
+pub fn resolve_inputs(inputs: &[String], opts: &FetchOptions) -> Result<Vec<String>, String> {
+    inputs
+        .iter()
+        .map(|input| {
+            if is_url(input) {
+                fetch_cached(input, opts).map(|path| path.to_string_lossy().into_owned())
+            } else {
+                Ok(input.clone())
+            }
+        })
+        .collect()
+}
+
+fn fetch_cached(url: &str, opts: &FetchOptions) -> Result<PathBuf, String> {
+    fs::create_dir_all(&opts.cache_dir)
+        .map_err(|e| format!("Failed to create '{}': {}", opts.cache_dir.display(), e))?;
+
+    let key = hash64(url.as_bytes());
+    let stem = format!("{:08x}{:08x}", key.hi, key.lo);
+    let body_path = opts.cache_dir.join(format!("{}.txt", stem));
+    let meta_path = opts.cache_dir.join(format!("{}.json", stem));
+
+    let cached = if body_path.is_file() {
+        fs::read_to_string(&meta_path)
+            .ok()
+            .and_then(|json| serde_json::from_str::<CacheMeta>(&json).ok())
+            .filter(|meta| meta.url == url)
+    } else {
+        None
+    };
+    let validators = cached.as_ref().filter(|_| !opts.refresh);
+
+    match download(url, validators) {
+        Ok(Fetched::NotModified) => {
+            println!("  cached     {}", url);
+        }
+        Ok(Fetched::Body { text, etag, last_modified }) => {
+            fs::write(&body_path, &text)
+                .map_err(|e| format!("Failed to write '{}': {}", body_path.display(), e))?;
+            let meta = CacheMeta {
+                url: url.to_string(),
+                etag,
+                last_modified,
+            };
+            let json = serde_json::to_string_pretty(&meta)
+                .map_err(|e| format!("Failed to serialize cache metadata: {}", e))?;
+            fs::write(&meta_path, json)
+                .map_err(|e| format!("Failed to write '{}': {}", meta_path.display(), e))?;
+            println!("  fetched    {} ({} bytes)", url, text.len());
+        }
+        Err(e) if cached.is_some() => {
+            eprintln!("warning: {}; using cached copy", e);
+        }
+        Err(e) => return Err(e),
+    }
+
+    Ok(body_path)
+}
+
+#[cfg(feature = "fetch")]
+fn download(url: &str, validators: Option<&CacheMeta>) -> Result<Fetched, String> {
+    use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+    use reqwest::StatusCode;
+
+    let client = reqwest::blocking::Client::builder()
+        .user_agent(concat!("bb-cli/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+
+    let mut request = client.get(url);
+    if let Some(meta) = validators {
+        if let Some(etag) = &meta.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &meta.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = request.send().map_err(|e| format!("Failed to fetch '{}': {}", url, e))?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(Fetched::NotModified);
+    }
+    if !response.status().is_success() {
+        return Err(format!("Failed to fetch '{}': HTTP {}", url, response.status()));
+    }
+
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let etag = header(ETAG);
+    let last_modified = header(LAST_MODIFIED);
+    let text = response
+        .text()
+        .map_err(|e| format!("Failed to read '{}': {}", url, e))?;
+
+    Ok(Fetched::Body { text, etag, last_modified })
+}
+
+#[cfg(not(feature = "fetch"))]
+fn download(url: &str, _validators: Option<&CacheMeta>) -> Result<Fetched, String> {
+    Err(format!(
+        "cannot fetch '{}': bb-cli built without fetch support; rebuild with --features fetch",
+        url
+    ))
+}
@@ -0,0 +1,287 @@
+//! Filter list linter
+//!
+//! Reports problems per source line so list maintainers can fix them before
+//! users ever compile the list: options or syntax the compiler drops,
+//! malformed domains, redundant rules, regex filters with nothing to
+//! prefilter on and cosmetic selectors a browser would reject. Lines are
+//! linted as written, so both branches of `!#if` blocks are checked.
+
+use std::fmt;
+use std::fs;
+
+use clap::ValueEnum;
+
+use bb_compiler::parser::AnchorType;
+use bb_compiler::{
+    optimize_rules, parse_filter_list_with_config, selector_issue, DropReason, DuplicateRule, ParserConfig,
+};
+use bb_core::types::RuleFlags;
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Info => "info",
+            Self::Warning => "warning",
+            Self::Error => "error",
+        })
+    }
+}
+
+pub struct LintOptions {
+    pub input_paths: Vec<String>,
+    pub config: ParserConfig,
+    /// Exit non-zero when any finding is at least this severe
+    pub fail_on: Severity,
+}
+
+struct Finding {
+    list_id: u16,
+    line: u32,
+    severity: Severity,
+    message: String,
+}
+
+pub fn run_lint(opts: LintOptions) -> Result<(), String> {
+    if opts.input_paths.is_empty() {
+        return Err("No input files specified".to_string());
+    }
+
+    let mut sources = Vec::with_capacity(opts.input_paths.len());
+    for path in &opts.input_paths {
+        sources.push(fs::read_to_string(path).map_err(|e| format!("Failed to read '{}': {}", path, e))?);
+    }
+    let lines: Vec<Vec<&str>> = sources.iter().map(|content| content.lines().collect()).collect();
+    let line_text = |list_id: u16, line: u32| -> &str {
+        lines[list_id as usize]
+            .get(line.saturating_sub(1) as usize)
+            .map_or("", |text| text.trim())
+    };
+
+    let mut findings = Vec::new();
+    let mut all_rules = Vec::new();
+
+    for (list_id, content) in sources.iter().enumerate() {
+        let list_id = list_id as u16;
+        let (mut rules, diagnostics) = parse_filter_list_with_config(content, &opts.config);
+
+        for dropped in &diagnostics.dropped_lines {
+            findings.push(Finding {
+                list_id,
+                line: dropped.line,
+                severity: drop_severity(&dropped.reason),
+                message: dropped.reason.to_string(),
+            });
+        }
+
+        for rule in &mut rules {
+            rule.list_id = list_id;
+            let line = line_text(list_id, rule.line);
+            let mut report = |severity, message: String| {
+                findings.push(Finding {
+                    list_id,
+                    line: rule.line,
+                    severity,
+                    message,
+                })
+            };
+
+            for domain in listed_domains(line) {
+                if !is_valid_domain_entry(domain) {
+                    report(Severity::Error, format!("malformed domain '{}'", domain));
+                }
+            }
+
+            if let (AnchorType::Hostname, Some(pattern)) = (rule.anchor_type, rule.pattern.as_deref()) {
+                let end = pattern.find(['/', '^', '*', '?', '#', '|', ':']).unwrap_or(pattern.len());
+                let host = &pattern[..end];
+                if !rule.flags.contains(RuleFlags::IS_REGEX) && !host.is_empty() && !is_valid_hostname(host) {
+                    report(Severity::Error, format!("malformed domain '{}'", host));
+                }
+            }
+
+            if rule.flags.contains(RuleFlags::IS_REGEX) {
+                if let Some(source) = rule.pattern.as_deref().filter(|source| !has_literal_run(source)) {
+                    report(
+                        Severity::Warning,
+                        format!("regex /{}/ has no literal text to prefilter on; it runs on every request", source),
+                    );
+                }
+            }
+
+            if let Some(cosmetic) = &rule.cosmetic {
+                if let Some(issue) = selector_issue(&cosmetic.selector) {
+                    report(Severity::Error, format!("invalid selector: {}", issue));
+                }
+            }
+        }
+
+        all_rules.extend(rules);
+    }
+
+    let optimize_stats = optimize_rules(&mut all_rules);
+    let mut redundant = |rules: &[DuplicateRule], what: &str| {
+        for rule in rules {
+            findings.push(Finding {
+                list_id: rule.list_id,
+                line: rule.line,
+                severity: Severity::Warning,
+                message: format!(
+                    "redundant: {} {}:{}",
+                    what, opts.input_paths[rule.original_list_id as usize], rule.original_line
+                ),
+            });
+        }
+    };
+    redundant(&optimize_stats.duplicates, "duplicate of");
+    redundant(&optimize_stats.shadowed_rules, "already blocked by");
+
+    findings.sort_by_key(|finding| (finding.list_id, finding.line, std::cmp::Reverse(finding.severity)));
+
+    let mut counts = [0usize; 3];
+    for finding in &findings {
+        counts[finding.severity as usize] += 1;
+        let path = &opts.input_paths[finding.list_id as usize];
+        println!("{}:{}: {}: {}", path, finding.line, finding.severity, finding.message);
+        println!("    {}", line_text(finding.list_id, finding.line));
+    }
+
+    println!(
+        "\n{} error(s), {} warning(s), {} info in {} list(s)",
+        counts[Severity::Error as usize],
+        counts[Severity::Warning as usize],
+        counts[Severity::Info as usize],
+        opts.input_paths.len()
+    );
+
+    let failing = findings.iter().filter(|finding| finding.severity >= opts.fail_on).count();
+    if failing > 0 {
+        return Err(format!("{} finding(s) at or above '{}'", failing, opts.fail_on));
+    }
+    Ok(())
+}
+
+fn drop_severity(reason: &DropReason) -> Severity {
+    match reason {
+        DropReason::DnsOption(_) => Severity::Info,
+        // Usually syntax from another blocker rather than a mistake.
+        DropReason::UnsupportedOption(_) | DropReason::UnsupportedCosmetic => Severity::Warning,
+        DropReason::InvalidOption(_)
+        | DropReason::InvalidDomain(_)
+        | DropReason::ConflictingOptions
+        | DropReason::EmptyMask
+        | DropReason::MisplacedCosmeticOption
+        | DropReason::UnsupportedPattern
+        | DropReason::InvalidRegex
+        | DropReason::InvalidSelector(_)
+        | DropReason::InvalidProcedural(_)
+        | DropReason::UnknownRedirect(_) => Severity::Error,
+    }
+}
+
+/// Domains listed on a filter line: the `domain=` option of a network rule,
+/// or the prefix of a cosmetic rule. Negations keep their `~`.
+fn listed_domains(line: &str) -> Vec<&str> {
+    if let Some(marker) = cosmetic_marker(line) {
+        return line[..marker].split(',').map(str::trim).filter(|d| !d.is_empty()).collect();
+    }
+    let Some((_, options)) = line.rsplit_once('$') else {
+        return Vec::new();
+    };
+    options
+        .split(',')
+        .filter_map(|option| option.trim().strip_prefix("domain="))
+        .flat_map(|value| value.split('|'))
+        .collect()
+}
+
+fn cosmetic_marker(line: &str) -> Option<usize> {
+    let bytes = line.as_bytes();
+    (0..bytes.len().saturating_sub(1))
+        .find(|&i| bytes[i] == b'#' && matches!(bytes[i + 1], b'#' | b'@' | b'?' | b'$' | b'%'))
+}
+
+/// A `domain=` / cosmetic entry: optionally negated, `*` TLD wildcards
+/// allowed, and `/regex/` entries accepted as-is.
+fn is_valid_domain_entry(entry: &str) -> bool {
+    let entry = entry.strip_prefix('~').unwrap_or(entry);
+    if entry.len() > 2 && entry.starts_with('/') && entry.ends_with('/') {
+        return true;
+    }
+    match entry.strip_suffix(".*") {
+        Some(base) => is_valid_hostname(base),
+        None => is_valid_hostname(entry),
+    }
+}
+
+fn is_valid_hostname(host: &str) -> bool {
+    let host = host.strip_prefix('.').unwrap_or(host);
+    let host = host.strip_suffix('.').unwrap_or(host);
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+/// Whether a regex requires some literal run of 3+ alphanumerics, which a
+/// token index could key on. Top-level alternation, optional characters and
+/// anything inside groups or classes do not count.
+fn has_literal_run(source: &str) -> bool {
+    let bytes = source.as_bytes();
+    let mut depth = 0usize;
+    let mut run = 0usize;
+    let mut best = 0usize;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let byte = bytes[i];
+        match byte {
+            b'\\' => {
+                best = best.max(run);
+                run = 0;
+                i += 1;
+            }
+            b'[' => {
+                best = best.max(run);
+                run = 0;
+                i += 1;
+                while i < bytes.len() && bytes[i] != b']' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+            }
+            b'(' => {
+                best = best.max(run);
+                run = 0;
+                depth += 1;
+            }
+            b')' => {
+                run = 0;
+                depth = depth.saturating_sub(1);
+            }
+            b'|' if depth == 0 => return false,
+            b'?' | b'*' | b'{' => {
+                best = best.max(run.saturating_sub(1));
+                run = 0;
+            }
+            _ if depth == 0 && byte.is_ascii_alphanumeric() => run += 1,
+            _ => {
+                best = best.max(run);
+                run = 0;
+            }
+        }
+        i += 1;
+    }
+
+    best.max(run) >= 3
+}
@@ -7,12 +7,26 @@ use std::io::Write;
 use std::path::Path;
 use std::time::Instant;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
-use bb_compiler::{build_snapshot, optimize_rules, parse_filter_list};
-use bb_core::snapshot::Snapshot;
+use bb_compiler::{
+    build_snapshot, optimize_rules, optimize_rules_with_options, parse_filter_list_into, parse_filter_list_with_config,
+    parse_list_metadata, parse_psl, try_build_snapshot_with_options, BuildInfo, CompileDiagnostics, DnsOptionPolicy,
+    DropReason, Error, OptimizeOptions, ParserConfig, PreprocessEnv, SnapshotOptions,
+};
+use bb_core::snapshot::{Snapshot, SnapshotLayout};
 
+use config::{BuildConfig, ListInput};
+
+mod alloc_count;
 mod bench;
+mod config;
+mod coverage;
+mod dnr;
+mod dump;
+mod export;
+mod fetch;
+mod lint;
 
 #[cfg(feature = "e2e")]
 mod e2e;
@@ -33,9 +47,16 @@ mod e2e {
 }
 
 mod perf_budget;
+mod query;
+mod safari;
+mod serve;
 mod snapshot;
 mod stress_hosts;
 mod ts_types;
+mod watch;
+
+#[global_allocator]
+static GLOBAL: alloc_count::CountingAllocator = alloc_count::CountingAllocator;
 
 const DEFAULT_FILTER_LIST: &str = "testdata/test-filters.txt";
 
@@ -51,13 +72,57 @@ struct Cli {
 enum Commands {
     /// Compile filter lists into a UBX snapshot
     Compile {
-        /// Input filter list files
-        #[arg(short, long, required = true)]
+        /// Input filter list files or http(s):// URLs
+        #[arg(short, long, required_unless_present = "config", conflicts_with = "config")]
         input: Vec<String>,
 
-        /// Output snapshot file
-        #[arg(short, long, default_value = "snapshot.ubx")]
-        output: String,
+        /// Build config (betterblocker.toml) listing the inputs and compile settings
+        #[arg(long)]
+        config: Option<String>,
+
+        /// Output snapshot file [default: snapshot.ubx]
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Public suffix list (public_suffix_list.dat) to embed in the snapshot
+        #[arg(long)]
+        psl: Option<String>,
+
+        /// Extra `!#if` flags, e.g. env_firefox or env_mobile
+        #[arg(long = "env")]
+        env_flags: Vec<String>,
+
+        /// Handling of rules with DNS-only options such as $dnsrewrite [default: skip]
+        #[arg(long, value_enum)]
+        dns_options: Option<DnsOptions>,
+
+        /// Keep pattern rules already covered by a host-only block rule
+        #[arg(long)]
+        keep_shadowed: bool,
+
+        /// Drop the least specific generic rules past this many
+        #[arg(long)]
+        max_rules: Option<usize>,
+
+        /// Embed redirect resources as data: URLs for callers that can't serve /redirects/*
+        #[arg(long)]
+        embed_redirects: bool,
+
+        /// Write the UBX1 layout (u32 section offsets) for readers that predate UBX2
+        #[arg(long)]
+        v1_layout: bool,
+
+        /// Re-download URL inputs instead of revalidating the cached copies
+        #[arg(long)]
+        refresh: bool,
+
+        /// Cache directory for URL inputs (default: ~/.cache/bb-cli/lists)
+        #[arg(long)]
+        cache_dir: Option<String>,
+
+        /// Write the lines the compiler dropped as JSON errors
+        #[arg(long)]
+        report: Option<String>,
 
         /// Verbose output
         #[arg(short, long)]
@@ -78,32 +143,285 @@ enum Commands {
         input: String,
     },
 
+    /// Decompile a UBX snapshot back into approximate filter-list text
+    Dump {
+        /// Snapshot file to decompile
+        #[arg(short, long)]
+        input: String,
+
+        /// Output file (stdout if omitted), or directory with --split
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Write one `list-<id>.txt` per list into the output directory
+        #[arg(long)]
+        split: bool,
+
+        /// Filter lists or hosts files used to turn domain hashes back into names
+        #[arg(long)]
+        names: Vec<String>,
+    },
+
+    /// Export unconditional host blocks as a hosts file or plain domain list
+    Export {
+        /// Snapshot file to export from
+        #[arg(short, long)]
+        input: String,
+
+        /// Output file (stdout if omitted)
+        #[arg(short, long)]
+        output: Option<String>,
+
+        #[arg(long, value_enum, default_value = "hosts")]
+        format: export::ExportFormat,
+
+        /// Filter lists the snapshot was compiled from; hosts are stored as hashes
+        #[arg(long, required = true)]
+        names: Vec<String>,
+    },
+
+    /// Match one request against a snapshot
+    Query {
+        /// Request URL
+        url: String,
+
+        /// Snapshot file
+        #[arg(short, long, default_value = "dist/data/snapshot.ubx")]
+        snapshot: String,
+
+        /// Request type, e.g. script, image or sub_frame
+        #[arg(short = 't', long = "type", default_value = "other")]
+        request_type: String,
+
+        /// URL of the page or frame making the request
+        #[arg(long)]
+        initiator: Option<String>,
+
+        /// List every candidate rule and why it did or didn't apply
+        #[arg(long)]
+        explain: bool,
+
+        /// Filter lists or hosts files used to turn domain hashes back into names
+        #[arg(long)]
+        names: Vec<String>,
+    },
+
+    /// Replay a request trace and report which rules and lists it ever matched
+    Coverage {
+        /// Snapshot file
+        #[arg(short, long, default_value = "dist/data/snapshot.ubx")]
+        snapshot: String,
+
+        /// Request trace (jsonl with url, type and initiator)
+        #[arg(long)]
+        trace: String,
+
+        #[arg(long, default_value = "50000")]
+        trace_limit: usize,
+
+        /// Never-matched list sections to show
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+
+        /// Never-matched rules to print per section
+        #[arg(long, default_value_t = 3)]
+        samples: usize,
+
+        /// Filter lists or hosts files used to turn domain hashes back into names
+        #[arg(long)]
+        names: Vec<String>,
+
+        /// Write the full report, including per-rule hit counts, as JSON
+        #[arg(long)]
+        report: Option<String>,
+    },
+
+    /// Serve /match, /cosmetics and /headers as a localhost HTTP/JSON API
+    Serve {
+        /// Snapshot file
+        #[arg(short, long, default_value = "dist/data/snapshot.ubx")]
+        snapshot: String,
+
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8787")]
+        listen: String,
+
+        /// Worker threads, each with its own engine
+        #[arg(long, default_value_t = 4)]
+        threads: usize,
+    },
+
+    /// Recompile on every filter list change and diff test URL decisions
+    Watch {
+        /// Input filter list files
+        #[arg(short, long, required = true)]
+        input: Vec<String>,
+
+        /// Output snapshot file
+        #[arg(short, long, default_value = "snapshot.ubx")]
+        snapshot: String,
+
+        /// Requests to re-check after each build, one `<url> [type] [initiator]` per line
+        #[arg(long)]
+        test_urls: Option<String>,
+    },
+
     /// Check bundled lists compile without errors (CI gate)
     Check {
         /// Input filter list files
-        #[arg(short, long, required = true)]
+        #[arg(short, long, required_unless_present = "config", conflicts_with = "config")]
         input: Vec<String>,
 
+        /// Take the inputs, `!#if` flags and DNS option handling from a build config
+        #[arg(long)]
+        config: Option<String>,
+
         /// Fail if parse ratio drops below threshold (0.0-1.0)
         #[arg(long, default_value = "0.95")]
         min_parse_ratio: f64,
+
+        /// Extra `!#if` flags, e.g. env_firefox or env_mobile
+        #[arg(long = "env")]
+        env_flags: Vec<String>,
+
+        /// Write dropped lines, unsupported options and duplicates as JSON
+        #[arg(long)]
+        report: Option<String>,
+
+        /// Handling of rules with DNS-only options such as $dnsrewrite [default: skip]
+        #[arg(long, value_enum)]
+        dns_options: Option<DnsOptions>,
     },
 
-    Bench {
+    /// Report per-line problems in filter lists (for list maintainers)
+    Lint {
+        /// Input filter list files
+        #[arg(short, long, required = true)]
+        input: Vec<String>,
+
+        /// Exit with an error when a finding is at least this severe
+        #[arg(long, value_enum, default_value = "error")]
+        fail_on: lint::Severity,
+
+        /// Handling of rules with DNS-only options such as $dnsrewrite
+        #[arg(long, value_enum, default_value = "skip")]
+        dns_options: DnsOptions,
+    },
+
+    /// Export the network rules as a Chrome declarativeNetRequest ruleset
+    ExportDnr {
+        /// Input filter list files or http(s):// URLs
+        #[arg(short, long, required_unless_present = "config", conflicts_with = "config")]
+        input: Vec<String>,
+
+        /// Take the inputs and compile settings from a build config
+        #[arg(long)]
+        config: Option<String>,
+
+        /// Output ruleset file
+        #[arg(short, long, default_value = "rules.json")]
+        output: String,
+
+        /// Extra `!#if` flags, e.g. env_firefox or env_mobile
+        #[arg(long = "env")]
+        env_flags: Vec<String>,
+
+        /// Handling of rules with DNS-only options such as $dnsrewrite [default: skip]
+        #[arg(long, value_enum)]
+        dns_options: Option<DnsOptions>,
+
+        /// Id of the first rule, for rulesets that share an id space
+        #[arg(long, default_value_t = 1)]
+        first_id: u32,
+
+        /// Redirect to data: URLs instead of the extension's /resources/redirects/* files
+        #[arg(long)]
+        embed_redirects: bool,
+
+        /// Write the rules that could not be converted as JSON
+        #[arg(long)]
+        report: Option<String>,
+
+        /// List every skipped rule
+        #[arg(short, long)]
+        verbose: bool,
+    },
+
+    /// Export rules as a Safari content blocker list
+    ExportSafari {
+        /// Input filter list files or http(s):// URLs
+        #[arg(short, long, required_unless_present = "config", conflicts_with = "config")]
+        input: Vec<String>,
+
+        /// Take the inputs and compile settings from a build config
+        #[arg(long)]
+        config: Option<String>,
+
+        /// Output content blocker file
+        #[arg(short, long, default_value = "blockerList.json")]
+        output: String,
+
+        /// Extra `!#if` flags, e.g. env_safari or env_mobile
+        #[arg(long = "env")]
+        env_flags: Vec<String>,
+
+        /// Handling of rules with DNS-only options such as $dnsrewrite [default: skip]
+        #[arg(long, value_enum)]
+        dns_options: Option<DnsOptions>,
+
+        /// Write the rules that could not be converted as JSON
+        #[arg(long)]
+        report: Option<String>,
+
+        /// List every skipped rule
         #[arg(short, long)]
+        verbose: bool,
+    },
+
+    Bench {
+        #[arg(short, long, conflicts_with = "config")]
         input: Vec<String>,
 
+        /// Take the inputs from a build config
+        #[arg(long)]
+        config: Option<String>,
+
         #[arg(short, long, default_value = "dist/data/snapshot.ubx")]
         snapshot: String,
 
         #[arg(long)]
         no_compile: bool,
+
+        /// Run the same workload against two snapshots and compare them
+        #[arg(long, num_args = 2, value_names = ["OLD", "NEW"], conflicts_with_all = ["input", "config"])]
+        compare: Vec<String>,
+
+        /// Workload for --compare; a seeded synthetic one when omitted
+        #[arg(long, requires = "compare")]
+        trace: Option<String>,
+
+        #[arg(long, default_value = "50000", requires = "compare")]
+        trace_limit: usize,
+
+        #[arg(long, default_value = "12648430", requires = "compare")]
+        seed: u32,
+
+        #[arg(long, default_value = "50", requires = "compare")]
+        iterations: usize,
+
+        /// Fail --compare when NEW's average latency is more than this many percent above OLD's
+        #[arg(long, default_value = "10", requires = "compare")]
+        max_regression: f64,
     },
 
     BenchRealistic {
-        #[arg(short, long)]
+        #[arg(short, long, conflicts_with = "config")]
         input: Vec<String>,
 
+        /// Take the inputs from a build config
+        #[arg(long)]
+        config: Option<String>,
+
         #[arg(short, long, default_value = "dist/data/snapshot.ubx")]
         snapshot: String,
 
@@ -136,12 +454,20 @@ enum Commands {
 
         #[arg(long, default_value = "12648430")]
         seed: u32,
+
+        /// Also run match_request on N threads sharing one matcher
+        #[arg(long, default_value = "1")]
+        threads: usize,
     },
 
     PerfBudget {
-        #[arg(short, long)]
+        #[arg(short, long, conflicts_with = "config")]
         input: Vec<String>,
 
+        /// Take the inputs from a build config
+        #[arg(long)]
+        config: Option<String>,
+
         #[arg(short, long, default_value = "dist/data/snapshot.ubx")]
         snapshot: String,
 
@@ -180,23 +506,253 @@ fn main() {
     let result = match cli.command {
         Commands::Compile {
             input,
+            config,
             output,
+            psl,
+            env_flags,
+            dns_options,
+            keep_shadowed,
+            max_rules,
+            embed_redirects,
+            v1_layout,
+            refresh,
+            cache_dir,
+            report,
             verbose,
-        } => cmd_compile(&input, &output, verbose),
+        } => load_config(config.as_deref()).and_then(|config| {
+            let config = config.as_ref();
+            let lists = match config {
+                Some(config) => config.list_inputs()?,
+                None => ListInput::from_sources(input),
+            };
+            let sources: Vec<String> = lists.iter().map(|list| list.source.clone()).collect();
+            let fetch_options = fetch::FetchOptions {
+                cache_dir: cache_dir
+                    .or_else(|| config.and_then(|config| config.cache_dir.clone()))
+                    .map(Into::into)
+                    .unwrap_or_else(fetch::FetchOptions::default_cache_dir),
+                refresh,
+            };
+            let paths = fetch::resolve_inputs(&sources, &fetch_options)?;
+            let output = output
+                .or_else(|| config.and_then(|config| config.output.clone()))
+                .unwrap_or_else(|| "snapshot.ubx".to_string());
+            let psl = psl.or_else(|| config.and_then(|config| config.psl.clone()));
+            cmd_compile(
+                &lists,
+                &paths,
+                &output,
+                psl.as_deref(),
+                &config_env(&env_flags, config),
+                &config_dns_options(dns_options, config).parser_config(),
+                &OptimizeOptions {
+                    eliminate_shadowed: !keep_shadowed && !config.is_some_and(|config| config.optimizer.keep_shadowed),
+                    max_rules: max_rules.or_else(|| config.and_then(|config| config.optimizer.max_rules)),
+                },
+                embed_redirects || config.is_some_and(|config| config.embed_redirects),
+                if v1_layout { SnapshotLayout::V1 } else { SnapshotLayout::V2 },
+                report.as_deref(),
+                verbose,
+            )
+        }),
         Commands::Validate { input } => cmd_validate(&input),
         Commands::Info { input } => cmd_info(&input),
-        Commands::Check { input, min_parse_ratio } => cmd_check(&input, min_parse_ratio),
+        Commands::Dump {
+            input,
+            output,
+            split,
+            names,
+        } => dump::run_dump(dump::DumpOptions {
+            input,
+            output,
+            split,
+            names,
+        }),
+        Commands::Export {
+            input,
+            output,
+            format,
+            names,
+        } => export::run_export(export::ExportOptions {
+            input,
+            output,
+            format,
+            names,
+        }),
+        Commands::Query {
+            url,
+            snapshot,
+            request_type,
+            initiator,
+            explain,
+            names,
+        } => query::run_query(query::QueryOptions {
+            snapshot_path: snapshot,
+            url,
+            request_type,
+            initiator,
+            explain,
+            names,
+        }),
+        Commands::Coverage {
+            snapshot,
+            trace,
+            trace_limit,
+            top,
+            samples,
+            names,
+            report,
+        } => coverage::run_coverage(coverage::CoverageOptions {
+            snapshot_path: snapshot,
+            trace_path: trace,
+            trace_limit,
+            top,
+            samples,
+            names,
+            report,
+        }),
+        Commands::Serve {
+            snapshot,
+            listen,
+            threads,
+        } => serve::run_serve(serve::ServeOptions {
+            snapshot_path: snapshot,
+            listen,
+            threads,
+        }),
+        Commands::Watch {
+            input,
+            snapshot,
+            test_urls,
+        } => watch::run_watch(watch::WatchOptions {
+            input_paths: input,
+            snapshot_path: snapshot,
+            test_urls_path: test_urls,
+        }),
+        Commands::Check {
+            input,
+            config,
+            min_parse_ratio,
+            env_flags,
+            report,
+            dns_options,
+        } => load_config(config.as_deref()).and_then(|config| {
+            let config = config.as_ref();
+            cmd_check(
+                &config_input_paths(input, config)?,
+                min_parse_ratio,
+                &config_env(&env_flags, config),
+                &config_dns_options(dns_options, config).parser_config(),
+                report.as_deref(),
+            )
+        }),
+        Commands::Lint {
+            input,
+            fail_on,
+            dns_options,
+        } => lint::run_lint(lint::LintOptions {
+            input_paths: input,
+            config: dns_options.parser_config(),
+            fail_on,
+        }),
+        Commands::ExportDnr {
+            input,
+            config,
+            output,
+            env_flags,
+            dns_options,
+            first_id,
+            embed_redirects,
+            report,
+            verbose,
+        } => load_config(config.as_deref()).and_then(|config| {
+            let config = config.as_ref();
+            let lists = match config {
+                Some(config) => config.list_inputs()?,
+                None => ListInput::from_sources(input.clone()),
+            };
+            dnr::run_export_dnr(dnr::ExportDnrOptions {
+                lists,
+                paths: config_input_paths(input, config)?,
+                output,
+                env: config_env(&env_flags, config),
+                config: config_dns_options(dns_options, config).parser_config(),
+                optimize: OptimizeOptions {
+                    eliminate_shadowed: !config.is_some_and(|config| config.optimizer.keep_shadowed),
+                    max_rules: config.and_then(|config| config.optimizer.max_rules),
+                },
+                dnr: bb_compiler::dnr::DnrOptions {
+                    first_id,
+                    redirect_data_urls: embed_redirects || config.is_some_and(|config| config.embed_redirects),
+                },
+                report,
+                verbose,
+            })
+        }),
+        Commands::ExportSafari {
+            input,
+            config,
+            output,
+            env_flags,
+            dns_options,
+            report,
+            verbose,
+        } => load_config(config.as_deref()).and_then(|config| {
+            let config = config.as_ref();
+            let lists = match config {
+                Some(config) => config.list_inputs()?,
+                None => ListInput::from_sources(input.clone()),
+            };
+            safari::run_export_safari(safari::ExportSafariOptions {
+                lists,
+                paths: config_input_paths(input, config)?,
+                output,
+                env: config_env(&env_flags, config),
+                config: config_dns_options(dns_options, config).parser_config(),
+                optimize: OptimizeOptions {
+                    eliminate_shadowed: !config.is_some_and(|config| config.optimizer.keep_shadowed),
+                    max_rules: config.and_then(|config| config.optimizer.max_rules),
+                },
+                report,
+                verbose,
+            })
+        }),
+        Commands::Bench {
+            compare,
+            trace,
+            trace_limit,
+            seed,
+            iterations,
+            max_regression,
+            ..
+        } if !compare.is_empty() => {
+            let [old_path, new_path]: [String; 2] = compare.try_into().expect("clap takes two values");
+            bench::run_compare(bench::CompareBenchOptions {
+                old_path,
+                new_path,
+                trace_path: trace,
+                trace_limit,
+                seed,
+                iterations,
+                max_regression_pct: max_regression,
+            })
+        }
         Commands::Bench {
             input,
+            config,
             snapshot,
             no_compile,
-        } => bench::run_simple(bench::SimpleBenchOptions {
-            input_paths: with_default_input(input),
-            snapshot_path: snapshot,
-            compile: !no_compile,
+            ..
+        } => bench_input_paths(input, config.as_deref()).and_then(|input_paths| {
+            bench::run_simple(bench::SimpleBenchOptions {
+                input_paths,
+                snapshot_path: snapshot,
+                compile: !no_compile,
+            })
         }),
         Commands::BenchRealistic {
             input,
+            config,
             snapshot,
             no_compile,
             mode,
@@ -208,28 +764,35 @@ fn main() {
             pages,
             reqs_per_page,
             seed,
-        } => bench::run_realistic(bench::RealisticBenchOptions {
-            input_paths: with_default_input(input),
-            snapshot_path: snapshot,
-            compile: !no_compile,
-            mode,
-            iterations,
-            warmup_ops,
-            sample_batch_ops,
-            trace_path: trace,
-            trace_limit,
-            synthetic_pages: pages,
-            synthetic_reqs_per_page: reqs_per_page,
-            seed,
+            threads,
+        } => bench_input_paths(input, config.as_deref()).and_then(|input_paths| {
+            bench::run_realistic(bench::RealisticBenchOptions {
+                input_paths,
+                snapshot_path: snapshot,
+                compile: !no_compile,
+                mode,
+                iterations,
+                warmup_ops,
+                sample_batch_ops,
+                trace_path: trace,
+                trace_limit,
+                synthetic_pages: pages,
+                synthetic_reqs_per_page: reqs_per_page,
+                seed,
+                threads,
+            })
         }),
         Commands::PerfBudget {
             input,
+            config,
             snapshot,
             no_compile,
-        } => perf_budget::run_perf_budget(perf_budget::PerfBudgetOptions {
-            input_paths: with_default_input(input),
-            snapshot_path: snapshot,
-            compile: !no_compile,
+        } => bench_input_paths(input, config.as_deref()).and_then(|input_paths| {
+            perf_budget::run_perf_budget(perf_budget::PerfBudgetOptions {
+                input_paths,
+                snapshot_path: snapshot,
+                compile: !no_compile,
+            })
         }),
         Commands::GenerateHosts { input, output } => stress_hosts::run_generate_hosts(
             stress_hosts::StressHostsOptions {
@@ -262,51 +825,157 @@ fn with_default_input(mut input: Vec<String>) -> Vec<String> {
     input
 }
 
-fn cmd_compile(inputs: &[String], output: &str, verbose: bool) -> Result<(), String> {
-    if inputs.is_empty() {
+fn load_config(path: Option<&str>) -> Result<Option<BuildConfig>, String> {
+    path.map(BuildConfig::load).transpose()
+}
+
+/// Local paths for the config's lists (downloading URL sources into the
+/// default cache), or `input` as given.
+fn config_input_paths(input: Vec<String>, config: Option<&BuildConfig>) -> Result<Vec<String>, String> {
+    let Some(config) = config else {
+        return Ok(input);
+    };
+    let fetch_options = fetch::FetchOptions {
+        cache_dir: config
+            .cache_dir
+            .clone()
+            .map(Into::into)
+            .unwrap_or_else(fetch::FetchOptions::default_cache_dir),
+        refresh: false,
+    };
+    fetch::resolve_inputs(&config.sources(), &fetch_options)
+}
+
+fn bench_input_paths(input: Vec<String>, config: Option<&str>) -> Result<Vec<String>, String> {
+    let config = load_config(config)?;
+    config_input_paths(with_default_input(input), config.as_ref())
+}
+
+/// `--env` flags plus the config's `env`.
+fn config_env(flags: &[String], config: Option<&BuildConfig>) -> PreprocessEnv {
+    let config_flags = config.map_or(&[][..], |config| &config.env);
+    preprocess_env(&[flags, config_flags].concat())
+}
+
+fn config_dns_options(flag: Option<DnsOptions>, config: Option<&BuildConfig>) -> DnsOptions {
+    flag.or_else(|| config.and_then(|config| config.dns_options))
+        .unwrap_or(DnsOptions::Skip)
+}
+
+/// `--dns-options`: what to do with rules a DNS filter would evaluate.
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum DnsOptions {
+    /// Drop them, reported separately from unsupported options
+    Skip,
+    /// Keep `||host^` block rules as plain host blocks
+    BlockHost,
+}
+
+impl DnsOptions {
+    fn parser_config(self) -> ParserConfig {
+        let dns_options = match self {
+            Self::Skip => DnsOptionPolicy::Skip,
+            Self::BlockHost => DnsOptionPolicy::BlockHost,
+        };
+        ParserConfig { dns_options }
+    }
+}
+
+fn preprocess_env(flags: &[String]) -> PreprocessEnv {
+    flags
+        .iter()
+        .fold(PreprocessEnv::default(), |env, flag| env.with_flag(flag))
+}
+
+/// `paths` are the local copies of `lists`, whose sources (the URL for
+/// downloaded lists, otherwise the path) go into the BuildInfo section.
+#[allow(clippy::too_many_arguments)]
+fn cmd_compile(
+    lists: &[ListInput],
+    paths: &[String],
+    output: &str,
+    psl: Option<&str>,
+    env: &PreprocessEnv,
+    config: &ParserConfig,
+    optimize: &OptimizeOptions,
+    embed_redirects: bool,
+    layout: SnapshotLayout,
+    report_path: Option<&str>,
+    verbose: bool,
+) -> Result<(), String> {
+    if lists.is_empty() {
         return Err("No input files specified".to_string());
     }
 
     let start = Instant::now();
     let mut all_rules = Vec::new();
+    let mut list_metadata = Vec::new();
+    let mut build_info = BuildInfo::new(snapshot::build_timestamp());
     let mut total_lines = 0usize;
+    let mut diagnostics = CompileDiagnostics::default();
 
-    for (list_id, path) in inputs.iter().enumerate() {
-        let content = fs::read_to_string(path)
-            .map_err(|e| format!("Failed to read '{}': {}", path, e))?;
+    for (list, path) in lists.iter().zip(paths) {
+        let list_id = list.id;
+        let raw = fs::read_to_string(path).map_err(|e| Error::io(path, e).to_string())?;
+        build_info.add_list(list_id, &list.source, &raw);
+        let content = snapshot::preprocess_list(path, &raw, env);
 
         let line_count = content.lines().count();
         total_lines += line_count;
 
-        let mut rules = parse_filter_list(&content);
+        let first_rule = all_rules.len();
+        let list_diagnostics = parse_filter_list_into(&content, config, list_id, &mut all_rules);
+        diagnostics.merge(list_id, list_diagnostics);
 
-        for rule in &mut rules {
-            rule.list_id = list_id as u16;
+        let mut metadata = parse_list_metadata(&content);
+        metadata.list_id = list_id;
+        metadata.tier = list.tier;
+        if metadata.title.is_none() {
+            metadata.title = list.name.clone();
         }
 
         if verbose {
             println!(
                 "  [{}] {} - {} lines, {} rules",
                 list_id,
-                Path::new(path).file_name().unwrap_or_default().to_string_lossy(),
+                metadata.title.as_deref().unwrap_or(
+                    &Path::new(path).file_name().unwrap_or_default().to_string_lossy()
+                ),
                 line_count,
-                rules.len()
+                all_rules.len() - first_rule
             );
         }
 
-        all_rules.extend(rules);
+        if !metadata.is_empty() {
+            list_metadata.push(metadata);
+        }
     }
 
     let parse_time = start.elapsed();
 
     let opt_start = Instant::now();
-    let optimize_stats = optimize_rules(&mut all_rules);
+    let optimize_stats = optimize_rules_with_options(&mut all_rules, optimize);
     let opt_time = opt_start.elapsed();
     let rules_before = optimize_stats.before;
     let rules_after = optimize_stats.after;
 
+    let psl_rules = match psl {
+        Some(path) => Some(parse_psl(&fs::read_to_string(path).map_err(|e| Error::io(path, e).to_string())?)),
+        None => None,
+    };
+    let psl_rule_count = psl_rules.as_ref().map(|rules| rules.len());
+    let options = SnapshotOptions {
+        psl: psl_rules,
+        list_metadata,
+        build_info: Some(build_info),
+        embed_redirect_data: embed_redirects,
+        layout,
+        custom_sections: Vec::new(),
+    };
+
     let build_start = Instant::now();
-    let snapshot_bytes = build_snapshot(&all_rules);
+    let snapshot_bytes = try_build_snapshot_with_options(&all_rules, &options).map_err(|e| e.to_string())?;
     let build_time = build_start.elapsed();
 
     Snapshot::load(&snapshot_bytes)
@@ -317,9 +986,30 @@ fn cmd_compile(inputs: &[String], output: &str, verbose: bool) -> Result<(), Str
     file.write_all(&snapshot_bytes)
         .map_err(|e| format!("Failed to write '{}': {}", output, e))?;
 
+    let errors = diagnostics.errors();
+    if let Some(report_path) = report_path {
+        let list_name = |list_id: u16| {
+            lists
+                .iter()
+                .find(|list| list.id == list_id)
+                .map_or("", |list| list.source.as_str())
+        };
+        let truncated: Vec<_> = optimize_stats
+            .truncated
+            .iter()
+            .map(|rule| serde_json::json!({ "list": list_name(rule.list_id), "line": rule.line }))
+            .collect();
+        let report = serde_json::json!({
+            "lists": lists.iter().map(|list| list.source.as_str()).collect::<Vec<_>>(),
+            "errors": errors_json(&diagnostics, list_name),
+            "truncated": truncated,
+        });
+        write_json_report(report_path, &report)?;
+    }
+
     let total_time = start.elapsed();
 
-    println!("Compiled {} filter lists to '{}'", inputs.len(), output);
+    println!("Compiled {} filter lists to '{}'", lists.len(), output);
     println!("  Lines:    {}", total_lines);
     println!(
         "  Rules:    {} -> {} (dedupe removed {}, badfilter removed {} incl {} directives)",
@@ -329,6 +1019,35 @@ fn cmd_compile(inputs: &[String], output: &str, verbose: bool) -> Result<(), Str
         optimize_stats.badfiltered_rules + optimize_stats.badfilter_rules,
         optimize_stats.badfilter_rules
     );
+    if optimize_stats.shadowed > 0 {
+        println!("  Shadowed: {} pattern rules covered by host rules", optimize_stats.shadowed);
+    }
+    if !optimize_stats.truncated.is_empty() {
+        println!(
+            "  Truncated: {} generic rules to fit the {} rule budget",
+            optimize_stats.truncated.len(),
+            optimize.max_rules.unwrap_or_default()
+        );
+    }
+    if optimize_stats.invalid_scriptlets > 0 {
+        println!("  Scriptlets: {} invalid injections dropped", optimize_stats.invalid_scriptlets);
+    }
+    if !errors.is_empty() {
+        println!("  Errors:   {} lines dropped", errors.len());
+    }
+    if verbose {
+        for error in &errors {
+            if let Some((list_id, line)) = error.location() {
+                println!("    [{}] line {}: {} ({})", list_id, line, error, error.code());
+            }
+        }
+        for warning in &optimize_stats.warnings {
+            println!("    [{}] {}: {}", warning.list_id, warning.rule, warning.message);
+        }
+    }
+    if let Some(count) = psl_rule_count {
+        println!("  PSL:      {} rules", count);
+    }
     println!("  Size:     {} bytes ({:.1} KB)", snapshot_bytes.len(), snapshot_bytes.len() as f64 / 1024.0);
     println!("  Time:     {:.1}ms (parse: {:.1}ms, opt: {:.1}ms, build: {:.1}ms)",
         total_time.as_secs_f64() * 1000.0,
@@ -341,10 +1060,11 @@ fn cmd_compile(inputs: &[String], output: &str, verbose: bool) -> Result<(), Str
 }
 
 fn cmd_validate(input: &str) -> Result<(), String> {
-    let bytes = fs::read(input)
-        .map_err(|e| format!("Failed to read '{}': {}", input, e))?;
+    let mapped = Snapshot::load_mmap(input)
+        .map_err(|e| format!("Invalid snapshot '{}': {}", input, e))?;
+    let bytes = mapped.bytes();
 
-    let snapshot = Snapshot::load(&bytes)
+    let snapshot = mapped.snapshot()
         .map_err(|e| format!("Invalid snapshot: {}", e))?;
 
     println!("Snapshot '{}' is valid", input);
@@ -356,16 +1076,21 @@ fn cmd_validate(input: &str) -> Result<(), String> {
 }
 
 fn cmd_info(input: &str) -> Result<(), String> {
-    let bytes = fs::read(input)
-        .map_err(|e| format!("Failed to read '{}': {}", input, e))?;
+    let mapped = Snapshot::load_mmap(input)
+        .map_err(|e| format!("Invalid snapshot '{}': {}", input, e))?;
+    let bytes = mapped.bytes();
 
-    let snapshot = Snapshot::load(&bytes)
+    let snapshot = mapped.snapshot()
         .map_err(|e| format!("Invalid snapshot: {}", e))?;
 
     println!("Snapshot: {}", input);
-    println!("  Magic:       UBX1");
+    println!("  Magic:       {}", String::from_utf8_lossy(&snapshot.layout.magic()));
     println!("  Version:     {}", snapshot.version);
     println!("  Sections:    {}", snapshot.section_count());
+    for id in snapshot.custom_section_ids() {
+        let len = snapshot.get_custom_section(id).map_or(0, <[u8]>::len);
+        println!("  Custom:      {:#06x} ({} bytes)", id, len);
+    }
     println!("  Total size:  {} bytes ({:.1} KB)", bytes.len(), bytes.len() as f64 / 1024.0);
     println!();
 
@@ -380,10 +1105,43 @@ fn cmd_info(input: &str) -> Result<(), String> {
     println!("Rules:");
     println!("  Count:       {}", rules.count);
 
+    let lists = snapshot.all_list_metadata();
+    if !lists.is_empty() {
+        println!();
+        println!("Lists:");
+        for meta in lists {
+            println!(
+                "  [{}] {}{}{}",
+                meta.list_id,
+                meta.title.unwrap_or("(untitled)"),
+                meta.version.map(|v| format!(" v{}", v)).unwrap_or_default(),
+                meta.expires_secs
+                    .map(|secs| format!(", expires every {}h", secs / 3600))
+                    .unwrap_or_default(),
+            );
+        }
+    }
+
+    if let Some(info) = snapshot.build_info() {
+        println!();
+        println!("Build:");
+        println!("  Compiled at: {} (unix)", info.compiled_at);
+        println!("  Compiler:    bb-compiler {}", info.compiler_version);
+        for list in &info.lists {
+            println!("  [{}] {} ({})", list.list_id, list.source, list.content_hash_hex());
+        }
+    }
+
     Ok(())
 }
 
-fn cmd_check(inputs: &[String], min_parse_ratio: f64) -> Result<(), String> {
+fn cmd_check(
+    inputs: &[String],
+    min_parse_ratio: f64,
+    env: &PreprocessEnv,
+    config: &ParserConfig,
+    report_path: Option<&str>,
+) -> Result<(), String> {
     if inputs.is_empty() {
         return Err("No input files specified".to_string());
     }
@@ -392,12 +1150,13 @@ fn cmd_check(inputs: &[String], min_parse_ratio: f64) -> Result<(), String> {
     let mut all_rules = Vec::new();
     let mut total_lines = 0usize;
     let mut total_content_lines = 0usize;
+    let mut total_dns_only = 0usize;
+    let mut diagnostics = CompileDiagnostics::default();
 
     println!("Checking {} filter list(s)...\n", inputs.len());
 
     for (list_id, path) in inputs.iter().enumerate() {
-        let content = fs::read_to_string(path)
-            .map_err(|e| format!("Failed to read '{}': {}", path, e))?;
+        let content = snapshot::read_filter_list(path, env)?;
 
         let line_count = content.lines().count();
         let content_lines = content
@@ -408,11 +1167,17 @@ fn cmd_check(inputs: &[String], min_parse_ratio: f64) -> Result<(), String> {
             })
             .count();
 
+        let (mut rules, list_diagnostics) = parse_filter_list_with_config(&content, config);
+        let rule_count = rules.len();
+        // DNS-only rules are out of scope, not parse failures
+        let dns_only = list_diagnostics.dns_only_lines();
+        let content_lines = content_lines - dns_only;
+        total_dns_only += dns_only;
+
         total_lines += line_count;
         total_content_lines += content_lines;
 
-        let mut rules = parse_filter_list(&content);
-        let rule_count = rules.len();
+        diagnostics.merge(list_id as u16, list_diagnostics);
 
         for rule in &mut rules {
             rule.list_id = list_id as u16;
@@ -443,6 +1208,7 @@ fn cmd_check(inputs: &[String], min_parse_ratio: f64) -> Result<(), String> {
     let opt_start = Instant::now();
     let optimize_stats = optimize_rules(&mut all_rules);
     let opt_time = opt_start.elapsed();
+    diagnostics.record_optimize(&optimize_stats);
 
     let build_start = Instant::now();
     let snapshot_bytes = build_snapshot(&all_rules);
@@ -451,6 +1217,10 @@ fn cmd_check(inputs: &[String], min_parse_ratio: f64) -> Result<(), String> {
     Snapshot::load(&snapshot_bytes)
         .map_err(|e| format!("Generated snapshot failed validation: {}", e))?;
 
+    if let Some(report_path) = report_path {
+        write_check_report(report_path, inputs, &diagnostics)?;
+    }
+
     let total_time = start.elapsed();
     let overall_ratio = if total_content_lines > 0 {
         optimize_stats.before as f64 / total_content_lines as f64
@@ -463,6 +1233,26 @@ fn cmd_check(inputs: &[String], min_parse_ratio: f64) -> Result<(), String> {
     println!("Content lines:   {}", total_content_lines);
     println!("Rules parsed:    {}", optimize_stats.before);
     println!("Rules after opt: {}", optimize_stats.after);
+    println!("Bad scriptlets:  {}", optimize_stats.invalid_scriptlets);
+    for warning in &optimize_stats.warnings {
+        println!("  [{}] {}: {}", warning.list_id, warning.rule, warning.message);
+    }
+    println!("Dropped lines:   {}", diagnostics.dropped_lines.len());
+    if total_dns_only > 0 {
+        println!("DNS-only rules:  {} (not counted as content)", total_dns_only);
+    }
+    println!("Duplicates:      {}", diagnostics.duplicates.len());
+    if !diagnostics.unsupported_options.is_empty() {
+        let mut top: Vec<_> = diagnostics.unsupported_options.iter().collect();
+        top.sort_by(|a, b| b.1.cmp(a.1));
+        let top: Vec<String> = top.iter().take(5).map(|(name, count)| format!("{} ({})", name, count)).collect();
+        println!("Unsupported opts: {}", top.join(", "));
+    }
+    if !diagnostics.legacy_options.is_empty() {
+        let legacy: Vec<String> =
+            diagnostics.legacy_options.iter().map(|(name, count)| format!("{} ({})", name, count)).collect();
+        println!("Legacy opts:     {}", legacy.join(", "));
+    }
     println!("Parse ratio:     {:.2}%", overall_ratio * 100.0);
     println!("Snapshot size:   {} bytes ({:.1} KB)", snapshot_bytes.len(), snapshot_bytes.len() as f64 / 1024.0);
     println!("Time:            {:.1}ms (parse: {:.1}ms, opt: {:.1}ms, build: {:.1}ms)",
@@ -483,3 +1273,62 @@ fn cmd_check(inputs: &[String], min_parse_ratio: f64) -> Result<(), String> {
     println!("\n✓ All checks passed");
     Ok(())
 }
+
+fn write_check_report(path: &str, inputs: &[String], diagnostics: &CompileDiagnostics) -> Result<(), String> {
+    let list_name = |list_id: u16| inputs.get(list_id as usize).map(String::as_str).unwrap_or("");
+
+    let report = serde_json::json!({
+        "lists": inputs,
+        "unsupportedOptions": diagnostics.unsupported_options,
+        "legacyOptions": diagnostics.legacy_options,
+        "dnsOnlyLines": diagnostics.dns_only_lines(),
+        "droppedLines": diagnostics.dropped_lines.iter().map(|dropped| serde_json::json!({
+            "list": list_name(dropped.list_id),
+            "line": dropped.line,
+            "text": dropped.text,
+            "reason": dropped.reason.to_string(),
+        })).collect::<Vec<_>>(),
+        "duplicates": diagnostics.duplicates.iter().map(|dup| serde_json::json!({
+            "list": list_name(dup.list_id),
+            "line": dup.line,
+            "originalList": list_name(dup.original_list_id),
+            "originalLine": dup.original_line,
+        })).collect::<Vec<_>>(),
+        "warnings": diagnostics.warnings.iter().map(|warning| serde_json::json!({
+            "list": list_name(warning.list_id),
+            "rule": warning.rule,
+            "message": warning.message,
+        })).collect::<Vec<_>>(),
+        "errors": errors_json(diagnostics, list_name),
+    });
+
+    write_json_report(path, &report)
+}
+
+/// Dropped lines as structured errors: a stable `code` plus the list, line
+/// and source text they point at. DNS-only lines are not errors.
+fn errors_json<'a>(diagnostics: &CompileDiagnostics, list_name: impl Fn(u16) -> &'a str) -> Vec<serde_json::Value> {
+    diagnostics
+        .dropped_lines
+        .iter()
+        .filter(|dropped| !matches!(dropped.reason, DropReason::DnsOption(_)))
+        .map(|dropped| {
+            let error = Error::from(dropped);
+            serde_json::json!({
+                "code": error.code(),
+                "list": list_name(dropped.list_id),
+                "line": dropped.line,
+                "text": dropped.text,
+                "message": error.to_string(),
+            })
+        })
+        .collect()
+}
+
+fn write_json_report(path: &str, report: &serde_json::Value) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(report)
+        .map_err(|e| format!("Failed to serialize report: {}", e))?;
+    fs::write(path, json).map_err(|e| format!("Failed to write '{}': {}", path, e))?;
+    println!("Wrote report to {}", path);
+    Ok(())
+}
@@ -1,11 +1,9 @@
 use std::path::Path;
 use std::time::Instant;
 
-use bb_core::matcher::Matcher;
-use bb_core::psl::get_etld1;
+use bb_core::engine::{Engine, RequestInfo};
 use bb_core::snapshot::Snapshot;
-use bb_core::types::{MatchDecision, RequestContext, RequestType, SchemeMask};
-use bb_core::url::{extract_host, extract_scheme};
+use bb_core::types::{MatchDecision, RequestType};
 
 use crate::snapshot;
 
@@ -51,7 +49,7 @@ pub fn run_perf_budget(opts: PerfBudgetOptions) -> Result<(), String> {
     let cold_start_begin = Instant::now();
     let snapshot = Snapshot::load(&snapshot_bytes)
         .map_err(|e| format!("Invalid snapshot: {}", e))?;
-    let matcher = Matcher::new(&snapshot);
+    let engine = Engine::new(&snapshot);
     let cold_start_ms = cold_start_begin.elapsed().as_secs_f64() * 1000.0;
 
     println!("Warming up...");
@@ -61,11 +59,11 @@ pub fn run_perf_budget(opts: PerfBudgetOptions) -> Result<(), String> {
         initiator: Some("https://example.com".to_string()),
     };
     for _ in 0..1000 {
-        let _ = should_block(&matcher, &warm_req);
+        let _ = should_block(&engine, &warm_req);
     }
 
     println!("Measuring match latency...");
-    let latencies = measure_match_latency(&matcher, 2000);
+    let latencies = measure_match_latency(&engine, 2000);
     let p99_us = percentile(&latencies, 0.99);
 
     let wasm_peak_mb = snapshot_size_mb;
@@ -101,7 +99,7 @@ fn report_budget(name: &str, actual: f64, limit: f64, unit: &str) -> bool {
     passed
 }
 
-fn measure_match_latency(matcher: &Matcher, iterations: usize) -> Vec<f64> {
+fn measure_match_latency(engine: &Engine, iterations: usize) -> Vec<f64> {
     let test_urls = [
         BudgetRequest {
             url: "https://pagead2.googlesyndication.com/pagead/js/adsbygoogle.js".to_string(),
@@ -135,7 +133,7 @@ fn measure_match_latency(matcher: &Matcher, iterations: usize) -> Vec<f64> {
     for _ in 0..iterations {
         for req in &test_urls {
             let start = Instant::now();
-            let _ = should_block(matcher, req);
+            let _ = should_block(engine, req);
             let elapsed = start.elapsed().as_secs_f64() * 1_000_000.0;
             latencies.push(elapsed);
         }
@@ -154,40 +152,13 @@ fn percentile(sorted: &[f64], p: f64) -> f64 {
     sorted[idx]
 }
 
-fn should_block(matcher: &Matcher, req: &BudgetRequest) -> bool {
-    match_request(matcher, req).decision == MatchDecision::Block
+fn should_block(engine: &Engine, req: &BudgetRequest) -> bool {
+    match_request(engine, req).decision == MatchDecision::Block
 }
 
-fn match_request(matcher: &Matcher, req: &BudgetRequest) -> bb_core::types::MatchResult {
-    let req_host = extract_host(&req.url).unwrap_or("");
-    let req_etld1 = get_etld1(req_host);
-
-    let is_main_frame = req.request_type == "main_frame" || req.request_type == "document";
-    let site_url = if is_main_frame {
-        req.url.as_str()
-    } else {
-        req.initiator.as_deref().unwrap_or(req.url.as_str())
-    };
-    let site_host = extract_host(site_url).unwrap_or(req_host);
-    let site_etld1 = get_etld1(site_host);
-
-    let scheme = extract_scheme(&req.url).unwrap_or(SchemeMask::HTTP);
-    let is_third_party = !site_etld1.is_empty() && req_etld1 != site_etld1;
+fn match_request(engine: &Engine, req: &BudgetRequest) -> bb_core::types::MatchResult {
     let request_type = RequestType::from_str(&req.request_type);
-
-    let ctx = RequestContext {
-        url: &req.url,
-        req_host,
-        req_etld1: &req_etld1,
-        site_host,
-        site_etld1: &site_etld1,
-        is_third_party,
-        request_type,
-        scheme,
-        tab_id: 1,
-        frame_id: 0,
-        request_id: "perf",
-    };
-
-    matcher.match_request(&ctx)
+    let request = RequestInfo::new(&req.url, request_type, req.initiator.as_deref())
+        .with_ids(1, 0, "perf");
+    engine.check_request(&request)
 }
@@ -0,0 +1,82 @@
+//! `query`: match one request against a snapshot
+//!
+//! Prints the decision and the rule behind it; `--explain` adds every
+//! candidate rule the matcher looked at and why it did or didn't apply.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bb_core::types::{MatchResult, RequestType};
+use bb_core::{Engine, RequestInfo};
+
+use crate::dump::{HostNames, RuleDecompiler};
+use crate::snapshot::map_snapshot;
+
+pub struct QueryOptions {
+    pub snapshot_path: String,
+    pub url: String,
+    pub request_type: String,
+    pub initiator: Option<String>,
+    pub explain: bool,
+    /// Files whose host names are used to reverse domain hashes
+    pub names: Vec<String>,
+}
+
+pub fn run_query(opts: QueryOptions) -> Result<(), String> {
+    let mapped = map_snapshot(Path::new(&opts.snapshot_path))?;
+    let snapshot = mapped.snapshot().map_err(|e| format!("Invalid snapshot: {}", e))?;
+    let mut engine = Engine::new(&snapshot);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    engine.matcher_mut().set_current_time(now);
+    let hosts = HostNames::load(&opts.names)?;
+    let rules = RuleDecompiler::new(&snapshot, &hosts);
+
+    let request = RequestInfo::new(&opts.url, RequestType::from_str(&opts.request_type), opts.initiator.as_deref());
+    let rule_text = |rule_id: usize| rules.rule(rule_id).unwrap_or_else(|| "?".to_string());
+
+    if !opts.explain {
+        let result = engine.check_request(&request);
+        println!("{}", describe(&result));
+        if result.rule_id >= 0 {
+            println!("  rule {} (list {}): {}", result.rule_id, result.list_id, rule_text(result.rule_id as usize));
+        }
+        return Ok(());
+    }
+
+    let explanation = engine.explain_request(&request);
+    let result = &explanation.result;
+    println!("{}: {}", describe(result), explanation.reason);
+    if result.rule_id >= 0 {
+        println!("  rule {} (list {}): {}", result.rule_id, result.list_id, rule_text(result.rule_id as usize));
+    }
+    if explanation.genericblock {
+        println!("  $genericblock exception applies to the page");
+    }
+    println!("Tokens with postings: {}", explanation.tokens.join(" "));
+
+    let matched = explanation.candidates.iter().filter(|c| c.matched()).count();
+    println!("Candidates: {} ({} matched)", explanation.candidates.len(), matched);
+    for candidate in &explanation.candidates {
+        let status = match candidate.rejected {
+            None => "match".to_string(),
+            Some(rejection) => format!("no: {}", rejection),
+        };
+        println!(
+            "  #{:<6} list {:<3} {:<40} via {}; {}",
+            candidate.rule_id,
+            candidate.list_id,
+            rule_text(candidate.rule_id),
+            candidate.source,
+            status
+        );
+    }
+    Ok(())
+}
+
+fn describe(result: &MatchResult) -> String {
+    let decision = format!("{:?}", result.decision).to_lowercase();
+    match &result.redirect_url {
+        Some(url) => format!("{} -> {}", decision, url),
+        None => decision,
+    }
+}
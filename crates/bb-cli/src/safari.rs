@@ -0,0 +1,105 @@
+//! `export-safari`: filter lists to a Safari content blocker
+//!
+//! Compiles the lists the same way `compile` does, then hands the optimized
+//! rules to `bb_compiler::safari` and writes the `blockerList.json` a Safari
+//! (macOS / iOS) content blocker extension bundles.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use bb_compiler::safari::{convert_rules, MAX_RULES};
+use bb_compiler::{optimize_rules_with_options, parse_filter_list_with_config, OptimizeOptions, ParserConfig, PreprocessEnv};
+
+use crate::config::ListInput;
+use crate::snapshot;
+
+pub struct ExportSafariOptions {
+    pub lists: Vec<ListInput>,
+    /// Local copies of `lists`, in the same order
+    pub paths: Vec<String>,
+    pub output: String,
+    pub env: PreprocessEnv,
+    pub config: ParserConfig,
+    pub optimize: OptimizeOptions,
+    /// Write the rules that could not be converted as JSON
+    pub report: Option<String>,
+    pub verbose: bool,
+}
+
+pub fn run_export_safari(opts: ExportSafariOptions) -> Result<(), String> {
+    if opts.lists.is_empty() {
+        return Err("No input files specified".to_string());
+    }
+
+    let mut all_rules = Vec::new();
+    for (list, path) in opts.lists.iter().zip(&opts.paths) {
+        let content = snapshot::read_filter_list(path, &opts.env)?;
+        let (mut rules, _) = parse_filter_list_with_config(&content, &opts.config);
+        for rule in &mut rules {
+            rule.list_id = list.id;
+        }
+        all_rules.extend(rules);
+    }
+    optimize_rules_with_options(&mut all_rules, &opts.optimize);
+
+    let export = convert_rules(&all_rules);
+    let json = serde_json::to_string_pretty(&export.rules)
+        .map_err(|e| format!("Failed to serialize content blocker: {}", e))?;
+    fs::write(&opts.output, json).map_err(|e| format!("Failed to write '{}': {}", opts.output, e))?;
+
+    let list_source = |list_id: u16| {
+        opts.lists
+            .iter()
+            .find(|list| list.id == list_id)
+            .map_or("", |list| list.source.as_str())
+    };
+
+    let mut by_reason: BTreeMap<&str, usize> = BTreeMap::new();
+    for skipped in &export.skipped {
+        *by_reason.entry(skipped.reason.code()).or_default() += 1;
+    }
+
+    println!("Wrote {} content blocker rules to {}", export.rules.len(), opts.output);
+    if export.rules.len() > MAX_RULES {
+        eprintln!(
+            "  warning: Safari rejects content blockers with more than {} rules; split the lists across extensions",
+            MAX_RULES
+        );
+    }
+    println!("  Skipped rules: {}", export.skipped.len());
+    for (code, count) in &by_reason {
+        println!("    {:<22} {}", code, count);
+    }
+    if opts.verbose {
+        for skipped in &export.skipped {
+            let source = list_source(skipped.list_id);
+            let name = Path::new(source).file_name().map_or(source.into(), |name| name.to_string_lossy());
+            println!("  {}:{}: {}", name, skipped.line, skipped.reason);
+        }
+    }
+
+    if let Some(report_path) = &opts.report {
+        let skipped: Vec<_> = export
+            .skipped
+            .iter()
+            .map(|skipped| {
+                serde_json::json!({
+                    "list": list_source(skipped.list_id),
+                    "line": skipped.line,
+                    "code": skipped.reason.code(),
+                    "message": skipped.reason.to_string(),
+                })
+            })
+            .collect();
+        let report = serde_json::json!({
+            "rules": export.rules.len(),
+            "skipped": skipped,
+        });
+        let json = serde_json::to_string_pretty(&report).map_err(|e| format!("Failed to serialize report: {}", e))?;
+        fs::write(report_path, json).map_err(|e| format!("Failed to write '{}': {}", report_path, e))?;
+        println!("Wrote report to {}", report_path);
+    }
+
+    Ok(())
+}
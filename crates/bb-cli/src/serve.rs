@@ -0,0 +1,243 @@
+//! `serve`: a localhost HTTP/JSON API over a snapshot
+//!
+//! Lets consumers that aren't Rust (scripts, proxy prototypes, QA tooling)
+//! query the engine. Every endpoint is a `POST` with a JSON body:
+//!
+//! - `/match` `{"url", "type"?, "initiator"?}`: the network decision
+//! - `/cosmetics` `{"url"}`: hide CSS, scriptlets and procedural selectors
+//! - `/headers` `{"url", "type"?, "initiator"?, "headers": [{"name", "value"}], "stage"?}`:
+//!   response-header actions, or outgoing-header ones with `"stage": "request"`
+//!
+//! Each worker thread keeps its own engine over the shared snapshot.
+
+use std::io::Read;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use bb_core::matcher::ResponseHeader;
+use bb_core::snapshot::Snapshot;
+use bb_core::types::{RequestContext, RequestType};
+use bb_core::{Engine, RequestInfo};
+
+use crate::snapshot::map_snapshot;
+
+/// Largest request body read; the JSON bodies are a URL or two and a few headers.
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+pub struct ServeOptions {
+    pub snapshot_path: String,
+    /// Address to listen on, e.g. `127.0.0.1:8787`
+    pub listen: String,
+    /// Worker threads, each with its own engine
+    pub threads: usize,
+}
+
+#[derive(Deserialize)]
+struct MatchBody {
+    url: String,
+    #[serde(rename = "type", default = "default_request_type")]
+    request_type: String,
+    initiator: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CosmeticsBody {
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct HeadersBody {
+    #[serde(flatten)]
+    request: MatchBody,
+    #[serde(default)]
+    headers: Vec<HeaderBody>,
+    #[serde(default)]
+    stage: HeaderStage,
+}
+
+#[derive(Deserialize)]
+struct HeaderBody {
+    name: String,
+    #[serde(default)]
+    value: String,
+}
+
+#[derive(Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum HeaderStage {
+    #[default]
+    Response,
+    Request,
+}
+
+fn default_request_type() -> String {
+    "other".to_string()
+}
+
+pub fn run_serve(opts: ServeOptions) -> Result<(), String> {
+    let mapped = map_snapshot(Path::new(&opts.snapshot_path))?;
+    let snapshot = mapped.snapshot().map_err(|e| format!("Invalid snapshot: {}", e))?;
+    let server = Server::http(&opts.listen).map_err(|e| format!("Failed to listen on '{}': {}", opts.listen, e))?;
+    let threads = opts.threads.max(1);
+
+    println!("Serving {} on http://{} ({} threads)", opts.snapshot_path, server.server_addr(), threads);
+    println!("  POST /match, /cosmetics, /headers");
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| serve_requests(&server, &snapshot));
+        }
+    });
+    Ok(())
+}
+
+fn serve_requests(server: &Server, snapshot: &Snapshot<'_>) {
+    let mut engine = Engine::new(snapshot);
+    while let Ok(mut request) = server.recv() {
+        let start = Instant::now();
+        let (status, body) = handle(&mut engine, &mut request);
+        eprintln!(
+            "{} {} {} {:.2}ms",
+            request.method(),
+            request.url(),
+            status,
+            start.elapsed().as_secs_f64() * 1000.0
+        );
+
+        let content_type = Header::from_bytes("Content-Type", "application/json").expect("static header is valid");
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(content_type);
+        // The client may have gone away; nothing to do about it.
+        let _ = request.respond(response);
+    }
+}
+
+fn handle(engine: &mut Engine<'_>, request: &mut Request) -> (u16, Value) {
+    if *request.method() != Method::Post {
+        return error(405, "use POST with a JSON body");
+    }
+    let mut body = String::new();
+    if request.as_reader().take(MAX_BODY_BYTES + 1).read_to_string(&mut body).is_err() {
+        return error(400, "body is not UTF-8");
+    }
+    if body.len() as u64 > MAX_BODY_BYTES {
+        return error(413, "body is over 1 MiB");
+    }
+
+    retire_expired_rules(engine);
+
+    let path = request.url().split('?').next().unwrap_or_default();
+    let result = match path {
+        "/match" => parse_body(&body).and_then(|body| match_json(engine, &body)),
+        "/cosmetics" => parse_body(&body).and_then(|body| cosmetics_json(engine, &body)),
+        "/headers" => parse_body(&body).and_then(|body| headers_json(engine, &body)),
+        _ => return error(404, "unknown endpoint; use /match, /cosmetics or /headers"),
+    };
+    match result {
+        Ok(value) => (200, value),
+        Err(message) => error(400, &message),
+    }
+}
+
+/// `$expires` rules retire on the wall clock, which keeps moving while we
+/// serve. The clock is only read while a rule is still due to expire.
+fn retire_expired_rules(engine: &mut Engine<'_>) {
+    let Some(next_expiry) = engine.matcher().next_rule_expiry() else {
+        return;
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    if now >= next_expiry {
+        engine.matcher_mut().set_current_time(now);
+    }
+}
+
+fn parse_body<T: DeserializeOwned>(body: &str) -> Result<T, String> {
+    serde_json::from_str(body).map_err(|e| format!("invalid request body: {}", e))
+}
+
+fn request_info<'u>(url: &'u str, request_type: &str, initiator: Option<&'u str>) -> Result<RequestInfo<'u>, String> {
+    let mut builder = RequestContext::builder(url, RequestType::from_str(request_type));
+    if let Some(initiator) = initiator {
+        builder = builder.initiator(initiator);
+    }
+    builder.build().map_err(|e| format!("invalid url '{}': {}", url, e))
+}
+
+fn match_json(engine: &Engine<'_>, body: &MatchBody) -> Result<Value, String> {
+    let request = request_info(&body.url, &body.request_type, body.initiator.as_deref())?;
+    let result = engine.check_request(&request);
+    Ok(json!({
+        "decision": format!("{:?}", result.decision).to_lowercase(),
+        "ruleId": result.rule_id,
+        "listId": result.list_id,
+        "redirectUrl": result.redirect_url,
+    }))
+}
+
+fn cosmetics_json(engine: &Engine<'_>, body: &CosmeticsBody) -> Result<Value, String> {
+    let request = request_info(&body.url, "main_frame", None)?;
+    let result = engine.cosmetics_for_request(&request);
+    let scriptlets: Vec<Value> = result
+        .scriptlets
+        .iter()
+        .map(|call| json!({ "name": call.name, "args": call.args }))
+        .collect();
+    let procedural: Vec<Value> = result
+        .procedural
+        .iter()
+        .map(|program| {
+            let ops: Vec<Value> =
+                program.ops.iter().map(|op| json!({ "type": op.kind.name(), "args": op.arg })).collect();
+            json!({ "base": program.base, "ops": ops })
+        })
+        .collect();
+    Ok(json!({
+        "css": result.css,
+        "styleCss": result.style_css,
+        "enableGeneric": result.enable_generic,
+        "scriptlets": scriptlets,
+        "procedural": procedural,
+    }))
+}
+
+fn headers_json(engine: &Engine<'_>, body: &HeadersBody) -> Result<Value, String> {
+    let request = request_info(&body.request.url, &body.request.request_type, body.request.initiator.as_deref())?;
+    let headers: Vec<ResponseHeader<'_>> = body
+        .headers
+        .iter()
+        .map(|header| ResponseHeader {
+            name: &header.name,
+            value: &header.value,
+        })
+        .collect();
+
+    if body.stage == HeaderStage::Request {
+        let result = engine.request_headers_for_request(&request, &headers);
+        return Ok(json!({
+            "cancel": result.cancel,
+            "ruleId": result.rule_id,
+            "listId": result.list_id,
+            "removeHeaders": result.remove_headers,
+        }));
+    }
+
+    let result = engine.headers_for_request(&request, &headers);
+    Ok(json!({
+        "cancel": result.cancel,
+        "ruleId": result.rule_id,
+        "listId": result.list_id,
+        "csp": result.csp_injections,
+        "cspReportOnly": result.csp_report_only,
+        "removeHeaders": result.remove_headers,
+    }))
+}
+
+fn error(status: u16, message: &str) -> (u16, Value) {
+    (status, json!({ "error": message }))
+}
@@ -1,9 +1,12 @@
 use std::fs;
-use std::path::Path;
-use std::time::Instant;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use bb_compiler::{build_snapshot, optimize_rules, parse_filter_list};
-use bb_core::snapshot::Snapshot;
+use bb_compiler::{
+    build_snapshot_with_options, optimize_rules, parse_filter_list_into, parse_list_metadata, preprocess_filter_list,
+    BuildInfo, IncludeResolver, ParserConfig, PreprocessEnv, SnapshotOptions,
+};
+use bb_core::snapshot::{MappedSnapshot, Snapshot};
 
 #[derive(Debug, Clone)]
 pub struct CompileStats {
@@ -12,9 +15,64 @@ pub struct CompileStats {
     pub rules_deduped: usize,
     pub badfilter_rules: usize,
     pub badfiltered_rules: usize,
+    pub shadowed_rules: usize,
     pub total_ms: f64,
 }
 
+/// Resolves `!#include` targets relative to the including list's directory.
+/// Absolute paths, `..` and URLs are refused, as uBO only allows same-origin includes.
+pub struct FileIncludeResolver {
+    base_dir: PathBuf,
+}
+
+impl FileIncludeResolver {
+    pub fn for_list(path: &Path) -> Self {
+        Self {
+            base_dir: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+        }
+    }
+}
+
+impl IncludeResolver for FileIncludeResolver {
+    fn resolve(&self, name: &str) -> Option<String> {
+        let relative = Path::new(name);
+        if name.contains("://") || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return None;
+        }
+        fs::read_to_string(self.base_dir.join(relative)).ok()
+    }
+}
+
+/// Read a filter list and apply its `!#if`/`!#include` directives.
+pub fn read_filter_list(path: &str, env: &PreprocessEnv) -> Result<String, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read '{}': {}", path, e))?;
+    Ok(preprocess_list(path, &content, env))
+}
+
+/// Apply `!#if`/`!#include` directives to list text already read from `path`.
+pub fn preprocess_list(path: &str, content: &str, env: &PreprocessEnv) -> String {
+    let preprocessed = preprocess_filter_list(content, env, &FileIncludeResolver::for_list(Path::new(path)));
+    for name in &preprocessed.unresolved {
+        eprintln!("warning: {}: could not include '{}'", path, name);
+    }
+    preprocessed.text
+}
+
+/// Compile time for BuildInfo: `$SOURCE_DATE_EPOCH` if set, so reproducible
+/// builds stay byte-identical, otherwise the current time.
+pub fn build_timestamp() -> u64 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0)
+        })
+}
+
 pub fn compile_snapshot_bytes(inputs: &[String], verbose: bool) -> Result<(Vec<u8>, CompileStats), String> {
     if inputs.is_empty() {
         return Err("No input files specified".to_string());
@@ -22,17 +80,24 @@ pub fn compile_snapshot_bytes(inputs: &[String], verbose: bool) -> Result<(Vec<u
 
     let start = Instant::now();
     let mut all_rules = Vec::new();
+    let mut options = SnapshotOptions::default();
+    let mut build_info = BuildInfo::new(build_timestamp());
 
     for (list_id, path) in inputs.iter().enumerate() {
-        let content = fs::read_to_string(path)
+        let raw = fs::read_to_string(path)
             .map_err(|e| format!("Failed to read '{}': {}", path, e))?;
+        build_info.add_list(list_id as u16, path, &raw);
+        let content = preprocess_list(path, &raw, &PreprocessEnv::default());
 
         let line_count = content.lines().count();
 
-        let mut rules = parse_filter_list(&content);
+        let first_rule = all_rules.len();
+        parse_filter_list_into(&content, &ParserConfig::default(), list_id as u16, &mut all_rules);
 
-        for rule in &mut rules {
-            rule.list_id = list_id as u16;
+        let mut metadata = parse_list_metadata(&content);
+        metadata.list_id = list_id as u16;
+        if !metadata.is_empty() {
+            options.list_metadata.push(metadata);
         }
 
         if verbose {
@@ -41,15 +106,14 @@ pub fn compile_snapshot_bytes(inputs: &[String], verbose: bool) -> Result<(Vec<u
                 list_id,
                 Path::new(path).file_name().unwrap_or_default().to_string_lossy(),
                 line_count,
-                rules.len()
+                all_rules.len() - first_rule
             );
         }
-
-        all_rules.extend(rules);
     }
 
+    options.build_info = Some(build_info);
     let optimize_stats = optimize_rules(&mut all_rules);
-    let snapshot_bytes = build_snapshot(&all_rules);
+    let snapshot_bytes = build_snapshot_with_options(&all_rules, &options);
 
     Snapshot::load(&snapshot_bytes)
         .map_err(|e| format!("Generated snapshot failed validation: {}", e))?;
@@ -62,6 +126,7 @@ pub fn compile_snapshot_bytes(inputs: &[String], verbose: bool) -> Result<(Vec<u
         rules_deduped: optimize_stats.deduped,
         badfilter_rules: optimize_stats.badfilter_rules,
         badfiltered_rules: optimize_stats.badfiltered_rules,
+        shadowed_rules: optimize_stats.shadowed,
         total_ms: total_time.as_secs_f64() * 1000.0,
     };
 
@@ -82,3 +147,8 @@ pub fn read_snapshot(path: &Path) -> Result<Vec<u8>, String> {
     fs::read(path)
         .map_err(|e| format!("Failed to read '{}': {}", path.display(), e))
 }
+
+pub fn map_snapshot(path: &Path) -> Result<MappedSnapshot, String> {
+    Snapshot::load_mmap(path)
+        .map_err(|e| format!("Failed to load '{}': {}", path.display(), e))
+}
@@ -31,7 +31,8 @@ pub struct ScriptletCall {
 #[derive(TS, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CosmeticPayload {
-    pub css: String,
+    pub css: Vec<String>,
+    pub style_css: String,
     pub enable_generic: bool,
     pub procedural: Vec<ProceduralRule>,
     pub scriptlets: Vec<ScriptletCall>,
@@ -46,6 +47,8 @@ pub struct UserSettings {
     pub dynamic_filtering_enabled: bool,
     pub removeparam_enabled: bool,
     pub csp_enabled: bool,
+    pub csp_report_only: bool,
+    pub infer_request_types: bool,
     pub response_header_enabled: bool,
     pub disabled_sites: Vec<String>,
 }
@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use notify::{RecursiveMode, Watcher};
+
+use bb_core::snapshot::Snapshot;
+use bb_core::types::{MatchResult, RequestType};
+use bb_core::{Engine, RequestInfo};
+
+use crate::snapshot::{compile_snapshot_bytes, write_snapshot};
+
+/// Editors often write a file as several events (truncate, write, rename);
+/// wait this long for the burst to settle before recompiling.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    pub input_paths: Vec<String>,
+    pub snapshot_path: String,
+    pub test_urls_path: Option<String>,
+}
+
+/// One line of the `--test-urls` file: `<url> [type] [initiator]`.
+#[derive(Debug, Clone)]
+struct TestRequest {
+    url: String,
+    request_type: String,
+    initiator: Option<String>,
+}
+
+pub fn run_watch(opts: WatchOptions) -> Result<(), String> {
+    if opts.input_paths.is_empty() {
+        return Err("No input files specified".to_string());
+    }
+
+    let watched: HashSet<PathBuf> = opts.input_paths.iter().map(|path| absolute(Path::new(path))).collect();
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)
+        .map_err(|e| format!("Failed to start file watcher: {}", e))?;
+    // Watch the parent directories so atomic saves (write + rename) are seen.
+    let dirs: HashSet<PathBuf> = watched
+        .iter()
+        .map(|path| path.parent().map(Path::to_path_buf).unwrap_or_default())
+        .collect();
+    for dir in &dirs {
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .map_err(|e| format!("Failed to watch '{}': {}", dir.display(), e))?;
+    }
+
+    let mut previous = rebuild(&opts, None);
+    println!("Watching {} file(s) for changes (Ctrl+C to stop)", opts.input_paths.len());
+
+    loop {
+        let event = rx.recv().map_err(|_| "File watcher stopped".to_string())?;
+        let mut changed = touches(&event, &watched);
+        while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+            changed |= touches(&event, &watched);
+        }
+        if changed {
+            println!();
+            previous = rebuild(&opts, previous.as_deref()).or(previous);
+        }
+    }
+}
+
+fn touches(event: &notify::Result<notify::Event>, watched: &HashSet<PathBuf>) -> bool {
+    match event {
+        Ok(event) => !event.kind.is_access() && event.paths.iter().any(|path| watched.contains(&absolute(path))),
+        Err(e) => {
+            eprintln!("warning: watch error: {}", e);
+            false
+        }
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Compile, write the snapshot and diff test URLs against the previous build.
+/// Errors are reported and return `None` so the watch loop keeps going.
+fn rebuild(opts: &WatchOptions, previous: Option<&[u8]>) -> Option<Vec<u8>> {
+    let (bytes, stats) = match compile_snapshot_bytes(&opts.input_paths, false) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("Compile failed: {}", e);
+            return None;
+        }
+    };
+    if let Err(e) = write_snapshot(Path::new(&opts.snapshot_path), &bytes) {
+        eprintln!("{}", e);
+        return None;
+    }
+    println!(
+        "Compiled {} rules to '{}' in {:.1}ms",
+        stats.rules_after, opts.snapshot_path, stats.total_ms
+    );
+
+    if let Some(path) = &opts.test_urls_path {
+        if let Err(e) = report_decisions(path, &bytes, previous) {
+            eprintln!("{}", e);
+        }
+    }
+    Some(bytes)
+}
+
+fn report_decisions(path: &str, current: &[u8], previous: Option<&[u8]>) -> Result<(), String> {
+    let requests = load_test_urls(path)?;
+    let current = Snapshot::load(current).map_err(|e| format!("Invalid snapshot: {}", e))?;
+    let engine = Engine::new(&current);
+
+    let Some(previous) = previous else {
+        for req in &requests {
+            println!("  {:<10} {}", describe(&engine.check_request(&request_info(req))), req.url);
+        }
+        return Ok(());
+    };
+
+    let previous = Snapshot::load(previous).map_err(|e| format!("Invalid snapshot: {}", e))?;
+    let previous_engine = Engine::new(&previous);
+    let mut changed = 0usize;
+    for req in &requests {
+        let delta = engine.diff_decision(&previous_engine, &request_info(req));
+        if delta.changed() {
+            changed += 1;
+            println!(
+                "  {} -> {}  {} ({})",
+                describe(&delta.previous),
+                describe(&delta.current),
+                req.url,
+                req.request_type
+            );
+        }
+    }
+    println!("  {} of {} test URL decisions changed", changed, requests.len());
+    Ok(())
+}
+
+fn request_info(req: &TestRequest) -> RequestInfo<'_> {
+    RequestInfo::new(&req.url, RequestType::from_str(&req.request_type), req.initiator.as_deref())
+}
+
+fn describe(result: &MatchResult) -> String {
+    let decision = format!("{:?}", result.decision).to_lowercase();
+    match &result.redirect_url {
+        Some(url) => format!("{}[{}]", decision, url),
+        None => decision,
+    }
+}
+
+fn load_test_urls(path: &str) -> Result<Vec<TestRequest>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read test URLs '{}': {}", path, e))?;
+    let requests = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            TestRequest {
+                url: fields.next().unwrap_or_default().to_string(),
+                request_type: fields.next().unwrap_or("other").to_string(),
+                initiator: fields.next().map(str::to_string),
+            }
+        })
+        .collect();
+    Ok(requests)
+}
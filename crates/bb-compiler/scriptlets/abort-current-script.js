@@ -0,0 +1,26 @@
+const needle = toRegExp({{2}});
+const context = toRegExp({{3}});
+onPropertyChain({{1}}, (owner, prop) => {
+  const descriptor = Object.getOwnPropertyDescriptor(owner, prop) || { value: owner[prop], writable: true };
+  let value = descriptor.value;
+  const check = () => {
+    const script = document.currentScript;
+    if (script instanceof HTMLScriptElement && script.src === '' && context.test(script.textContent) && needle.test(script.textContent)) {
+      throw abortError();
+    }
+  };
+  Object.defineProperty(owner, prop, {
+    get() {
+      check();
+      return descriptor.get ? descriptor.get.call(this) : value;
+    },
+    set(next) {
+      check();
+      if (descriptor.set) {
+        descriptor.set.call(this, next);
+      } else {
+        value = next;
+      }
+    },
+  });
+});
@@ -0,0 +1,8 @@
+onPropertyChain({{1}}, (owner, prop) => {
+  Object.defineProperty(owner, prop, {
+    get: () => {
+      throw abortError();
+    },
+    set: () => {},
+  });
+});
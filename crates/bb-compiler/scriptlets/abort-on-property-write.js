@@ -0,0 +1,7 @@
+onPropertyChain({{1}}, (owner, prop) => {
+  Object.defineProperty(owner, prop, {
+    set: () => {
+      throw abortError();
+    },
+  });
+});
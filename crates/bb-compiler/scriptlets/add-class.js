@@ -0,0 +1,9 @@
+const classes = {{1}}.split('|').map((name) => name.trim()).filter((name) => name.length > 0);
+const selector = {{2}};
+const run = () => forEachNode(selector, (node) => node.classList.add(...classes));
+if (selector) {
+  run();
+  if (document.readyState === 'loading') {
+    document.addEventListener('DOMContentLoaded', run, { once: true });
+  }
+}
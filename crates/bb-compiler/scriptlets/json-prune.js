@@ -0,0 +1,31 @@
+const prune = {{1}}.split(/\s+/).filter((path) => path.length > 0);
+const required = {{2}}.split(/\s+/).filter((path) => path.length > 0);
+const has = (obj, path) => path.split('.').every((key) => {
+  if (obj === null || typeof obj !== 'object' || !(key in obj)) {
+    return false;
+  }
+  obj = obj[key];
+  return true;
+});
+const remove = (obj, path) => {
+  const keys = path.split('.');
+  const last = keys.pop();
+  for (const key of keys) {
+    if (obj === null || typeof obj !== 'object') {
+      return;
+    }
+    obj = obj[key];
+  }
+  if (obj !== null && typeof obj === 'object') {
+    delete obj[last];
+  }
+};
+JSON.parse = new Proxy(JSON.parse, {
+  apply(target, thisArg, args) {
+    const result = Reflect.apply(target, thisArg, args);
+    if (required.every((path) => has(result, path))) {
+      prune.forEach((path) => remove(result, path));
+    }
+    return result;
+  },
+});
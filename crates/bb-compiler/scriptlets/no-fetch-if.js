@@ -0,0 +1,10 @@
+const needle = toRegExp({{1}});
+window.fetch = new Proxy(window.fetch, {
+  apply(target, thisArg, args) {
+    const resource = args[0] instanceof Request ? args[0].url : String(args[0]);
+    if (needle.test(resource)) {
+      return Promise.resolve(new Response(''));
+    }
+    return Reflect.apply(target, thisArg, args);
+  },
+});
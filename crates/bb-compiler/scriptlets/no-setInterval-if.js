@@ -0,0 +1,13 @@
+const raw = {{1}};
+const negate = raw.startsWith('!');
+const needle = toRegExp(negate ? raw.slice(1) : raw);
+const delay = {{2}} === '' ? null : parseInt({{2}}, 10);
+window.setInterval = new Proxy(window.setInterval, {
+  apply(target, thisArg, args) {
+    const matched = needle.test(String(args[0])) !== negate && (delay === null || delay === args[1]);
+    if (matched) {
+      args[0] = function () {};
+    }
+    return Reflect.apply(target, thisArg, args);
+  },
+});
@@ -0,0 +1,11 @@
+const raw = {{1}};
+const negate = raw.startsWith('!');
+const needle = toRegExp(negate ? raw.slice(1) : raw);
+window.open = new Proxy(window.open, {
+  apply(target, thisArg, args) {
+    if (needle.test(String(args[0] || '')) !== negate) {
+      return null;
+    }
+    return Reflect.apply(target, thisArg, args);
+  },
+});
@@ -0,0 +1,10 @@
+const signatures = [['blockadblock'], ['babasbm'], [/getItem\('babn'\)/], ['getElementById', 'String.fromCharCode', 'ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789', 'charAt', 'DOMContentLoaded', 'AdBlock', 'addEventListener', 'doScroll', 'fromCharCode', '<<2|r>>4', 'sessionStorage', 'clientWidth', 'localStorage', 'Math', 'random']];
+const isBab = (source) => signatures.some((parts) => parts.every((part) => (part instanceof RegExp ? part.test(source) : source.includes(part))));
+window.eval = new Proxy(window.eval, {
+  apply(target, thisArg, args) {
+    if (typeof args[0] === 'string' && isBab(args[0])) {
+      return undefined;
+    }
+    return Reflect.apply(target, thisArg, args);
+  },
+});
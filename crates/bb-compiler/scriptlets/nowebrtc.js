@@ -0,0 +1,14 @@
+const names = ['RTCPeerConnection', 'webkitRTCPeerConnection', 'mozRTCPeerConnection'];
+const Fake = function () {
+  this.close = function () {};
+  this.createDataChannel = function () {};
+  this.createOffer = function () {
+    return Promise.reject(new DOMException('', 'NotAllowedError'));
+  };
+  this.setRemoteDescription = this.createOffer;
+};
+names.forEach((name) => {
+  if (typeof window[name] === 'function') {
+    window[name] = Fake;
+  }
+});
@@ -0,0 +1,47 @@
+const toRegExp = (pattern) => {
+  if (pattern === '') {
+    return /^/;
+  }
+  const match = /^\/(.+)\/([gimsu]*)$/.exec(pattern);
+  if (match) {
+    try {
+      return new RegExp(match[1], match[2]);
+    } catch (e) {
+      return /^\b$/;
+    }
+  }
+  return new RegExp(pattern.replace(/[.*+?^${}()|[\]\\]/g, '\\$&'));
+};
+const forEachNode = (selector, fn) => {
+  let nodes = [];
+  try {
+    nodes = Array.from(document.querySelectorAll(selector));
+  } catch (e) {
+    return;
+  }
+  nodes.slice(0, 200).forEach(fn);
+};
+const parseValue = (raw) => {
+  const known = { null: null, true: true, false: false, undefined: undefined, '': '', noopFunc: function () {}, trueFunc: () => true, falseFunc: () => false, emptyArr: [], emptyObj: {} };
+  if (Object.prototype.hasOwnProperty.call(known, raw)) {
+    return known[raw];
+  }
+  const numeric = Number(raw);
+  return raw.trim() !== '' && String(numeric) === raw ? numeric : raw;
+};
+const abortError = () => new ReferenceError('bb-' + Math.random().toString(36).slice(2));
+const onPropertyChain = (chain, install) => {
+  const parts = chain.split('.').filter((part) => part.length > 0);
+  if (parts.length === 0) {
+    return;
+  }
+  let owner = window;
+  for (let i = 0; i < parts.length - 1; i++) {
+    const next = owner[parts[i]];
+    if ((typeof next !== 'object' && typeof next !== 'function') || next === null) {
+      return;
+    }
+    owner = next;
+  }
+  install(owner, parts[parts.length - 1]);
+};
@@ -0,0 +1,10 @@
+const type = toRegExp({{1}});
+const needle = toRegExp({{2}});
+EventTarget.prototype.addEventListener = new Proxy(EventTarget.prototype.addEventListener, {
+  apply(target, thisArg, args) {
+    if (type.test(String(args[0])) && needle.test(String(args[1]))) {
+      return undefined;
+    }
+    return Reflect.apply(target, thisArg, args);
+  },
+});
@@ -0,0 +1,7 @@
+const attrs = {{1}}.split('|').map((attr) => attr.trim()).filter((attr) => attr.length > 0);
+const selector = {{2}} || attrs.map((attr) => '[' + attr + ']').join(',');
+const run = () => forEachNode(selector, (node) => attrs.forEach((attr) => node.removeAttribute(attr)));
+run();
+if (document.readyState === 'loading') {
+  document.addEventListener('DOMContentLoaded', run, { once: true });
+}
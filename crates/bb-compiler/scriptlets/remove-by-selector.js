@@ -0,0 +1,5 @@
+const run = () => forEachNode({{1}}, (node) => node.remove());
+run();
+if (document.readyState === 'loading') {
+  document.addEventListener('DOMContentLoaded', run, { once: true });
+}
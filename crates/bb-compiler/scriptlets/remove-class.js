@@ -0,0 +1,7 @@
+const classes = {{1}}.split('|').map((name) => name.trim()).filter((name) => name.length > 0);
+const selector = {{2}} || classes.map((name) => '.' + CSS.escape(name)).join(',');
+const run = () => forEachNode(selector, (node) => node.classList.remove(...classes));
+run();
+if (document.readyState === 'loading') {
+  document.addEventListener('DOMContentLoaded', run, { once: true });
+}
@@ -0,0 +1,8 @@
+const value = parseValue({{2}});
+onPropertyChain({{1}}, (owner, prop) => {
+  try {
+    Object.defineProperty(owner, prop, { get: () => value, set: () => {}, configurable: false });
+  } catch (e) {
+    owner[prop] = value;
+  }
+});
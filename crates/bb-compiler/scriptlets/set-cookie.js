@@ -0,0 +1,7 @@
+const allowed = ['true', 'false', 'yes', 'no', 'ok', 'on', 'off', 'accept', 'reject', 'allow', 'deny', '0', '1', ''];
+const name = {{1}};
+const value = {{2}};
+if (name && (allowed.includes(value.toLowerCase()) || /^\d{1,8}$/.test(value))) {
+  const path = {{3}} === 'none' ? '' : '; path=' + ({{3}} || '/');
+  document.cookie = encodeURIComponent(name) + '=' + encodeURIComponent(value) + path;
+}
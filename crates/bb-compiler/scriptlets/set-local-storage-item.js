@@ -0,0 +1,15 @@
+const key = {{1}};
+const raw = {{2}};
+const known = { undefined: undefined, null: null, true: 'true', false: 'false', emptyObj: '{}', emptyArr: '[]', '': '' };
+if (key) {
+  if (raw === '$remove$') {
+    window.localStorage.removeItem(key);
+  } else if (Object.prototype.hasOwnProperty.call(known, raw) || /^\d{1,8}$/.test(raw)) {
+    const value = Object.prototype.hasOwnProperty.call(known, raw) ? known[raw] : raw;
+    if (value === undefined || value === null) {
+      window.localStorage.removeItem(key);
+    } else {
+      window.localStorage.setItem(key, value);
+    }
+  }
+}
@@ -1,78 +1,223 @@
 use std::collections::HashMap;
 
-use bb_core::hash::{hash_domain, murmur3_32, Hash64};
+use bb_core::hash::{hash_domain, hash_token, murmur3_32, Hash64};
 use bb_core::snapshot::{
-    align_offset, header, section_entry, SectionId, HEADER_SIZE, SECTION_ENTRY_SIZE, UBX_MAGIC,
+    align_offset, header, section_entry, section_entry_v1, SectionId, SnapshotLayout, HEADER_SIZE, SECTION_ALIGNMENT,
     UBX_VERSION, HASHMAP64_ENTRY_SIZE, HASHMAP64_HEADER_SIZE, NO_CONSTRAINT, NO_PATTERN,
-    TOKEN_DICT_HEADER_SIZE, TOKEN_DICT_ENTRY_SIZE, PatternOp,
+    TOKEN_DICT_HEADER_SIZE, TOKEN_DICT_ENTRY_SIZE, PatternOp, HASHSET64_ENTRY_SIZE, replace_flags, pattern_flags,
+    LIST_META_ENTRY_SIZE, COSMETIC_STYLE_ENTRY_SIZE, TOKEN_BLOOM_HEADER_SIZE, TOKEN_BLOOM_BITS_PER_TOKEN,
+    TOKEN_BLOOM_HASH_COUNT, token_bloom_probe, REGEX_PATTERN_ENTRY_SIZE, header_spec_flags, removeheader_flags,
+    GENERIC_COSMETIC_BUCKET_ENTRY_SIZE, REDIRECT_REGISTRY_ENTRY_SIZE, redirect_registry_flags, redirect_resource_flags,
+    COSMETIC_SELECTOR_HASH_ENTRY_SIZE, COSMETIC_RULE_LINE_ENTRY_SIZE, BUILD_INFO_HEADER_SIZE, BUILD_INFO_ENTRY_SIZE,
+    posting_bucket_for, POSTING_BUCKET_COUNT, PROCEDURAL_OP_ENTRY_SIZE, PROCEDURAL_PROGRAM_ENTRY_SIZE,
+    PROCEDURAL_RULE_ENTRY_SIZE, RULE_EXPIRY_ENTRY_SIZE, RULE_SIGNATURE_ENTRY_SIZE, CUSTOM_SECTION_IDS,
 };
-use bb_core::types::RuleAction;
+use bb_core::types::{RuleAction, RuleFlags};
+use bb_core::url::is_scheme_token;
 
-use crate::parser::{AnchorType, CompiledRule};
+use crate::error::{Error, MAX_STRING_BYTES};
+use crate::metadata::{BuildInfo, ListMetadata};
+use crate::optimizer::rule_signature;
+use crate::parser::{parse_procedural_selector, AnchorType, CompiledRule};
+use crate::psl::{parse_psl, PslRules};
+use crate::redirects::{lookup_redirect, REDIRECT_REGISTRY};
 
 const HASH_SEED_LO: u32 = 0x9e3779b9;
 const HASH_SEED_HI: u32 = 0x85ebca6b;
 const NO_OPTION_ID: u32 = 0xFFFF_FFFF;
 
+/// Optional snapshot contents beyond the rules themselves.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotOptions {
+    /// Embedded as a PslSets section when present
+    pub psl: Option<PslRules>,
+    /// Embedded as a ListMeta section, keyed by `list_id`
+    pub list_metadata: Vec<ListMetadata>,
+    /// Embedded as a BuildInfo section when present
+    pub build_info: Option<BuildInfo>,
+    /// Store each redirect target as a `data:` URL next to its path
+    pub embed_redirect_data: bool,
+    /// Directory layout; `V1` for readers that predate `UBX2`
+    pub layout: SnapshotLayout,
+    /// Embedders' own sections as (id, bytes), ids in [`CUSTOM_SECTION_IDS`].
+    /// Readers get them back from `Snapshot::get_custom_section`.
+    pub custom_sections: Vec<(u16, Vec<u8>)>,
+}
+
 pub fn build_snapshot(rules: &[CompiledRule]) -> Vec<u8> {
+    build_snapshot_with_options(rules, &SnapshotOptions::default())
+}
+
+/// Build a snapshot with an embedded PslSets section parsed from
+/// `public_suffix_list.dat` text.
+pub fn build_snapshot_with_psl(rules: &[CompiledRule], psl_dat: &str) -> Vec<u8> {
+    let options = SnapshotOptions {
+        psl: Some(parse_psl(psl_dat)),
+        ..SnapshotOptions::default()
+    };
+    build_snapshot_with_options(rules, &options)
+}
+
+/// Build a snapshot carrying `extra` custom sections next to the rules;
+/// see [`SnapshotOptions::custom_sections`].
+pub fn build_snapshot_with_extra(rules: &[CompiledRule], extra: &[(u16, Vec<u8>)]) -> Result<Vec<u8>, Error> {
+    let options = SnapshotOptions {
+        custom_sections: extra.to_vec(),
+        ..SnapshotOptions::default()
+    };
+    try_build_snapshot_with_options(rules, &options)
+}
+
+/// `build_snapshot_with_options`, failing with `Error::SnapshotTooLarge`
+/// when a section, or in the v1 layout the whole snapshot, is past what
+/// offsets can address, with `Error::MaxRulesExceeded` or
+/// `Error::StrPoolOverflow` when rule ids or string lengths would wrap, and
+/// with `Error::CustomSection` for a custom section id outside
+/// [`CUSTOM_SECTION_IDS`] or given twice.
+pub fn try_build_snapshot_with_options(rules: &[CompiledRule], options: &SnapshotOptions) -> Result<Vec<u8>, Error> {
+    let sections = build_sections(rules, options)?;
+    let bytes = write_snapshot(sections, options.layout);
+    if options.layout == SnapshotLayout::V1 {
+        Error::check_snapshot_size(bytes.len())?;
+    }
+    Ok(bytes)
+}
+
+/// # Panics
+///
+/// On any error [`try_build_snapshot_with_options`] returns; untrusted input
+/// should go through that instead.
+pub fn build_snapshot_with_options(rules: &[CompiledRule], options: &SnapshotOptions) -> Vec<u8> {
+    try_build_snapshot_with_options(rules, options).unwrap_or_else(|e| panic!("cannot build snapshot: {}", e))
+}
+
+fn build_sections(rules: &[CompiledRule], options: &SnapshotOptions) -> Result<Vec<SectionData>, Error> {
+    Error::check_rule_count(rules.len())?;
+    for (index, &(id, _)) in options.custom_sections.iter().enumerate() {
+        if !CUSTOM_SECTION_IDS.contains(&id) {
+            return Err(Error::CustomSection { id, reason: "outside the custom range 0x8000..=0xFFFF" });
+        }
+        if options.custom_sections[..index].iter().any(|&(earlier, _)| earlier == id) {
+            return Err(Error::CustomSection { id, reason: "given twice" });
+        }
+    }
     let mut str_pool = StringPool::new();
     let domain_sets = build_domain_sets_section(rules);
     let (constraint_pool, constraint_offsets) = build_domain_constraint_pool(rules);
 
     let (pattern_pool, pattern_ids) = build_pattern_pool(rules, &mut str_pool);
-    let (token_dict, token_postings) = build_token_sections(rules, &pattern_ids);
-    let (redirect_resources, redirect_option_ids) = build_redirect_resources_section(rules, &mut str_pool);
+    let host_anchors = host_anchor_hashes(rules, &pattern_ids);
+    let (token_dict, token_postings, token_bloom) = build_token_sections(rules, &pattern_ids, &host_anchors);
+    let host_anchored_rules = build_host_anchored_rules_section(rules, &host_anchors);
+    let regex_patterns = build_regex_patterns_section(rules, &mut str_pool);
+    let (redirect_resources, redirect_option_ids) = build_redirect_resources_section(rules, &mut str_pool, options.embed_redirect_data);
+    let redirect_registry = build_redirect_registry_section(&mut str_pool);
     let (removeparam_specs, removeparam_option_ids) =
         build_removeparam_specs_section(rules, &mut str_pool);
     let (csp_specs, csp_option_ids) = build_csp_specs_section(rules, &mut str_pool);
     let (header_specs, header_option_ids) = build_header_specs_section(rules, &mut str_pool);
+    let (replace_specs, replace_option_ids) = build_replace_specs_section(rules, &mut str_pool);
+    let (removeheader_specs, removeheader_option_ids) = build_removeheader_specs_section(rules, &mut str_pool);
     let responseheader_rules = build_responseheader_rules_section(rules, &constraint_offsets, &mut str_pool);
-    let cosmetic_rules = build_cosmetic_rules_section(rules, &constraint_offsets, &mut str_pool);
+    let (cosmetic_rules, generic_cosmetic_buckets, cosmetic_selector_hashes, cosmetic_rule_lines) =
+        build_cosmetic_rules_section(rules, &constraint_offsets, &mut str_pool);
+    let cosmetic_style_rules = build_cosmetic_style_rules_section(rules, &constraint_offsets, &mut str_pool);
     let procedural_rules = build_procedural_rules_section(rules, &constraint_offsets, &mut str_pool);
     let scriptlet_rules = build_scriptlet_rules_section(rules, &constraint_offsets, &mut str_pool);
+    let list_meta = build_list_meta_section(&options.list_metadata, &mut str_pool);
+    let build_info = options
+        .build_info
+        .as_ref()
+        .map(|info| build_build_info_section(info, &mut str_pool));
     let option_ids = build_option_ids(
         rules,
         &redirect_option_ids,
         &removeparam_option_ids,
         &csp_option_ids,
         &header_option_ids,
+        &replace_option_ids,
+        &removeheader_option_ids,
     );
 
     let rules_section = build_rules_section(rules, &constraint_offsets, &pattern_ids, &option_ids);
-    let str_pool_section = str_pool.build();
+    let rule_expiry = build_rule_expiry_section(rules);
+    let rule_signatures = build_rule_signatures_section(rules);
+    let str_pool_section = str_pool.build()?;
 
     let mut sections = vec![
         SectionData::new(SectionId::StrPool, str_pool_section),
         SectionData::new(SectionId::DomainSets, domain_sets),
         SectionData::new(SectionId::TokenDict, token_dict),
         SectionData::new(SectionId::TokenPostings, token_postings),
+        SectionData::new(SectionId::TokenBloom, token_bloom),
         SectionData::new(SectionId::PatternPool, pattern_pool),
+        SectionData::new(SectionId::RegexPatterns, regex_patterns),
         SectionData::new(SectionId::DomainConstraintPool, constraint_pool),
         SectionData::new(SectionId::RedirectResources, redirect_resources),
+        SectionData::new(SectionId::RedirectRegistry, redirect_registry),
         SectionData::new(SectionId::RemoveparamSpecs, removeparam_specs),
         SectionData::new(SectionId::CspSpecs, csp_specs),
         SectionData::new(SectionId::HeaderSpecs, header_specs),
+        SectionData::new(SectionId::ReplaceSpecs, replace_specs),
+        SectionData::new(SectionId::RemoveheaderSpecs, removeheader_specs),
         SectionData::new(SectionId::ResponseHeaderRules, responseheader_rules),
         SectionData::new(SectionId::CosmeticRules, cosmetic_rules),
+        SectionData::new(SectionId::GenericCosmeticBuckets, generic_cosmetic_buckets),
+        SectionData::new(SectionId::CosmeticSelectorHashes, cosmetic_selector_hashes),
+        SectionData::new(SectionId::CosmeticRuleLines, cosmetic_rule_lines),
+        SectionData::new(SectionId::CosmeticStyleRules, cosmetic_style_rules),
         SectionData::new(SectionId::ProceduralRules, procedural_rules),
         SectionData::new(SectionId::ScriptletRules, scriptlet_rules),
         SectionData::new(SectionId::Rules, rules_section),
     ];
 
+    if let Some(psl) = &options.psl {
+        sections.push(SectionData::new(SectionId::PslSets, build_psl_section(psl)));
+    }
+    if !options.list_metadata.is_empty() {
+        sections.push(SectionData::new(SectionId::ListMeta, list_meta));
+    }
+    if let Some(build_info) = build_info {
+        sections.push(SectionData::new(SectionId::BuildInfo, build_info));
+    }
+    if let Some(rule_expiry) = rule_expiry {
+        sections.push(SectionData::new(SectionId::RuleExpiry, rule_expiry));
+    }
+    if let Some(rule_signatures) = rule_signatures {
+        sections.push(SectionData::new(SectionId::RuleSignatures, rule_signatures));
+    }
+    if let Some(host_anchored_rules) = host_anchored_rules {
+        sections.push(SectionData::new(SectionId::HostAnchoredRules, host_anchored_rules));
+    }
+    for (id, data) in &options.custom_sections {
+        sections.push(SectionData { id: *id, data: data.clone(), offset: 0 });
+    }
+    for section in &sections {
+        Error::check_snapshot_size(section.data.len())?;
+    }
+    Ok(sections)
+}
+
+/// Lay out the header, section directory and sections.
+fn write_snapshot(mut sections: Vec<SectionData>, layout: SnapshotLayout) -> Vec<u8> {
+    let alignment = match layout {
+        SnapshotLayout::V1 => 4,
+        SnapshotLayout::V2 => SECTION_ALIGNMENT,
+    };
     let section_count = sections.len();
     let section_dir_offset = HEADER_SIZE;
-    let section_dir_bytes = section_count * SECTION_ENTRY_SIZE;
-    let mut data_offset = align_offset(section_dir_offset + section_dir_bytes, 4);
+    let section_dir_bytes = section_count * layout.section_entry_size();
+    let mut data_offset = align_offset(section_dir_offset + section_dir_bytes, alignment);
 
     for section in &mut sections {
         section.offset = data_offset;
-        data_offset = align_offset(data_offset + section.data.len(), 4);
+        data_offset = align_offset(data_offset + section.data.len(), alignment);
     }
 
     let total_size = data_offset;
     let mut buffer = vec![0u8; total_size];
 
-    buffer[0..4].copy_from_slice(&UBX_MAGIC);
+    buffer[0..4].copy_from_slice(&layout.magic());
     write_u16_le(&mut buffer, header::VERSION, UBX_VERSION);
     write_u16_le(&mut buffer, header::FLAGS, 0);
     write_u32_le(&mut buffer, header::HEADER_BYTES, HEADER_SIZE as u32);
@@ -82,13 +227,26 @@ pub fn build_snapshot(rules: &[CompiledRule]) -> Vec<u8> {
     write_u32_le(&mut buffer, header::BUILD_ID, 0);
 
     for (index, section) in sections.iter().enumerate() {
-        let entry_offset = section_dir_offset + index * SECTION_ENTRY_SIZE;
-        write_u16_le(&mut buffer, entry_offset + section_entry::ID, section.id as u16);
-        write_u16_le(&mut buffer, entry_offset + section_entry::FLAGS, 0);
-        write_u32_le(&mut buffer, entry_offset + section_entry::OFFSET, section.offset as u32);
-        write_u32_le(&mut buffer, entry_offset + section_entry::LENGTH, section.data.len() as u32);
-        write_u32_le(&mut buffer, entry_offset + section_entry::UNCOMPRESSED_LENGTH, 0);
-        write_u32_le(&mut buffer, entry_offset + section_entry::CRC32, 0);
+        let entry_offset = section_dir_offset + index * layout.section_entry_size();
+        match layout {
+            SnapshotLayout::V1 => {
+                write_u16_le(&mut buffer, entry_offset + section_entry_v1::ID, section.id);
+                write_u16_le(&mut buffer, entry_offset + section_entry_v1::FLAGS, 0);
+                write_u32_le(&mut buffer, entry_offset + section_entry_v1::OFFSET, section.offset as u32);
+                write_u32_le(&mut buffer, entry_offset + section_entry_v1::LENGTH, section.data.len() as u32);
+                write_u32_le(&mut buffer, entry_offset + section_entry_v1::UNCOMPRESSED_LENGTH, 0);
+                write_u32_le(&mut buffer, entry_offset + section_entry_v1::CRC32, 0);
+            }
+            SnapshotLayout::V2 => {
+                write_u16_le(&mut buffer, entry_offset + section_entry::ID, section.id);
+                write_u16_le(&mut buffer, entry_offset + section_entry::FLAGS, 0);
+                write_u32_le(&mut buffer, entry_offset + section_entry::ALIGNMENT, alignment as u32);
+                write_u64_le(&mut buffer, entry_offset + section_entry::OFFSET, section.offset as u64);
+                write_u64_le(&mut buffer, entry_offset + section_entry::LENGTH, section.data.len() as u64);
+                write_u32_le(&mut buffer, entry_offset + section_entry::UNCOMPRESSED_LENGTH, 0);
+                write_u32_le(&mut buffer, entry_offset + section_entry::CRC32, 0);
+            }
+        }
 
         let end = section.offset + section.data.len();
         buffer[section.offset..end].copy_from_slice(&section.data);
@@ -98,20 +256,23 @@ pub fn build_snapshot(rules: &[CompiledRule]) -> Vec<u8> {
 }
 
 struct SectionData {
-    id: SectionId,
+    /// A [`SectionId`], or a custom id
+    id: u16,
     data: Vec<u8>,
     offset: usize,
 }
 
 impl SectionData {
     fn new(id: SectionId, data: Vec<u8>) -> Self {
-        Self { id, data, offset: 0 }
+        Self { id: id as u16, data, offset: 0 }
     }
 }
 
 struct StringPool {
     data: Vec<u8>,
     index: HashMap<String, u32>,
+    /// Length of the first string too long for a `u16` length
+    overflow: Option<usize>,
 }
 
 impl StringPool {
@@ -119,10 +280,14 @@ impl StringPool {
         Self {
             data: Vec::new(),
             index: HashMap::new(),
+            overflow: None,
         }
     }
 
     fn intern(&mut self, s: &str) -> (u32, u16) {
+        if s.len() > MAX_STRING_BYTES && self.overflow.is_none() {
+            self.overflow = Some(s.len());
+        }
         if let Some(&offset) = self.index.get(s) {
             return (offset, s.len() as u16);
         }
@@ -132,11 +297,17 @@ impl StringPool {
         (offset, s.len() as u16)
     }
 
-    fn build(self) -> Vec<u8> {
+    fn build(self) -> Result<Vec<u8>, Error> {
+        if let Some(len) = self.overflow {
+            return Err(Error::StrPoolOverflow {
+                len,
+                limit: MAX_STRING_BYTES,
+            });
+        }
         let mut buf = Vec::with_capacity(4 + self.data.len());
         buf.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
         buf.extend_from_slice(&self.data);
-        buf
+        Ok(buf)
     }
 }
 
@@ -178,11 +349,16 @@ fn build_domain_sets_section(rules: &[CompiledRule]) -> Vec<u8> {
     section
 }
 
+/// Entries are emitted in hash order rather than `HashMap` iteration order,
+/// so identical rules always produce identical postings and table layout.
 fn map_to_posting_entries(
     map: &HashMap<Hash64, Vec<u32>>,
     postings_data: &mut Vec<u8>,
 ) -> Vec<(Hash64, u32)> {
-    map.iter()
+    let mut sorted: Vec<_> = map.iter().collect();
+    sorted.sort_unstable_by_key(|(hash, _)| (hash.hi, hash.lo));
+    sorted
+        .into_iter()
         .map(|(hash, rule_ids)| {
             let offset = postings_data.len() as u32;
             encode_domain_posting_list(postings_data, rule_ids);
@@ -239,8 +415,9 @@ fn build_pattern_pool(rules: &[CompiledRule], str_pool: &mut StringPool) -> (Vec
     let mut prog_bytes: Vec<u8> = Vec::new();
 
     for rule in rules {
-        if let Some(pattern) = &rule.pattern {
-            let (bytecode, host_hash) = compile_pattern(pattern, rule.anchor_type, str_pool);
+        if let Some(pattern) = rule.pattern.as_ref().filter(|_| !rule.flags.contains(RuleFlags::IS_REGEX)) {
+            let match_case = rule.flags.contains(RuleFlags::MATCH_CASE);
+            let (bytecode, host_hash) = compile_pattern(pattern, rule.anchor_type, match_case, str_pool);
             
             let prog_offset = prog_bytes.len() as u32;
             prog_bytes.extend_from_slice(&bytecode);
@@ -254,7 +431,7 @@ fn build_pattern_pool(rules: &[CompiledRule], str_pool: &mut StringPool) -> (Vec
                     AnchorType::Left => 1,
                     AnchorType::Hostname => 2,
                 },
-                flags: 0,
+                flags: if match_case { pattern_flags::MATCH_CASE } else { 0 },
                 host_hash_lo: host_hash.lo,
                 host_hash_hi: host_hash.hi,
             });
@@ -292,41 +469,49 @@ struct PatternEntry {
     host_hash_hi: u32,
 }
 
-fn compile_pattern(pattern: &str, anchor_type: AnchorType, str_pool: &mut StringPool) -> (Vec<u8>, Hash64) {
+/// Hash of the host a `||host...` pattern is anchored to: everything before
+/// the first `/`, `^` or `*`. `None` for other anchors or an empty host.
+fn pattern_host_hash(pattern: &str, anchor_type: AnchorType) -> Option<Hash64> {
+    if anchor_type != AnchorType::Hostname {
+        return None;
+    }
+    let host = &pattern[..pattern.find(['/', '^', '*']).unwrap_or(pattern.len())];
+    (!host.is_empty()).then(|| hash_domain(&host.to_lowercase()))
+}
+
+fn compile_pattern(
+    pattern: &str,
+    anchor_type: AnchorType,
+    match_case: bool,
+    str_pool: &mut StringPool,
+) -> (Vec<u8>, Hash64) {
     let mut bytecode = Vec::new();
-    let mut host_hash = Hash64 { lo: 0, hi: 0 };
+    let host_hash = pattern_host_hash(pattern, anchor_type).unwrap_or(Hash64 { lo: 0, hi: 0 });
     let pattern_lower = pattern.to_lowercase();
+    // $match-case rules keep the original-case literals for verification.
+    let literal_source = if match_case { pattern } else { pattern_lower.as_str() };
     
     if anchor_type == AnchorType::Hostname {
         bytecode.push(PatternOp::HostAnchor as u8);
-        
-        if let Some(end) = pattern_lower.find(|c| c == '/' || c == '^' || c == '*') {
-            let host = &pattern_lower[..end];
-            if !host.is_empty() {
-                host_hash = hash_domain(host);
-            }
-        } else {
-            host_hash = hash_domain(&pattern_lower);
-        }
     } else if anchor_type == AnchorType::Left {
         bytecode.push(PatternOp::AssertStart as u8);
     }
 
-    let mut chars = pattern_lower.chars().peekable();
+    let chars = literal_source.chars().peekable();
     let mut literal_start = None;
     let mut pos = 0;
 
-    while let Some(ch) = chars.next() {
+    for ch in chars {
         match ch {
             '*' => {
                 if let Some(start) = literal_start.take() {
-                    emit_literal(&mut bytecode, &pattern_lower[start..pos], str_pool);
+                    emit_literal(&mut bytecode, &literal_source[start..pos], str_pool);
                 }
                 bytecode.push(PatternOp::SkipAny as u8);
             }
             '^' => {
                 if let Some(start) = literal_start.take() {
-                    emit_literal(&mut bytecode, &pattern_lower[start..pos], str_pool);
+                    emit_literal(&mut bytecode, &literal_source[start..pos], str_pool);
                 }
                 bytecode.push(PatternOp::AssertBoundary as u8);
             }
@@ -340,7 +525,7 @@ fn compile_pattern(pattern: &str, anchor_type: AnchorType, str_pool: &mut String
     }
 
     if let Some(start) = literal_start {
-        emit_literal(&mut bytecode, &pattern_lower[start..], str_pool);
+        emit_literal(&mut bytecode, &literal_source[start..], str_pool);
     }
 
     bytecode.push(PatternOp::Done as u8);
@@ -357,47 +542,181 @@ fn emit_literal(bytecode: &mut Vec<u8>, literal: &str, str_pool: &mut StringPool
     bytecode.extend_from_slice(&len.to_le_bytes());
 }
 
-fn build_token_sections(rules: &[CompiledRule], pattern_ids: &[u32]) -> (Vec<u8>, Vec<u8>) {
-    let mut token_to_rules: HashMap<u32, Vec<u32>> = HashMap::new();
+/// Regex filters keep their source; matchers compile them on load.
+fn build_regex_patterns_section(rules: &[CompiledRule], str_pool: &mut StringPool) -> Vec<u8> {
+    let mut entries = Vec::new();
 
     for (rule_id, rule) in rules.iter().enumerate() {
-        if pattern_ids[rule_id] == NO_PATTERN {
+        let Some(source) = rule.pattern.as_ref().filter(|_| rule.flags.contains(RuleFlags::IS_REGEX)) else {
             continue;
+        };
+        let (source_off, source_len) = str_pool.intern(source);
+        let flags = if rule.flags.contains(RuleFlags::MATCH_CASE) {
+            pattern_flags::MATCH_CASE as u32
+        } else {
+            0
+        };
+        entries.push((rule_id as u32, source_off, source_len as u32, flags));
+    }
+
+    let mut section = Vec::with_capacity(4 + entries.len() * REGEX_PATTERN_ENTRY_SIZE);
+    section.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    for (rule_id, source_off, source_len, flags) in entries {
+        section.extend_from_slice(&rule_id.to_le_bytes());
+        section.extend_from_slice(&source_off.to_le_bytes());
+        section.extend_from_slice(&source_len.to_le_bytes());
+        section.extend_from_slice(&flags.to_le_bytes());
+    }
+
+    section
+}
+
+/// Host hash of every pattern rule the HostAnchoredRules section files
+/// under its host; `None` for the rest.
+fn host_anchor_hashes(rules: &[CompiledRule], pattern_ids: &[u32]) -> Vec<Option<Hash64>> {
+    rules
+        .iter()
+        .zip(pattern_ids)
+        .map(|(rule, &pattern_id)| match &rule.pattern {
+            Some(pattern) if pattern_id != NO_PATTERN => pattern_host_hash(pattern, rule.anchor_type),
+            _ => None,
+        })
+        .collect()
+}
+
+/// `||host/path` rules only match when the request host ends in `host`, so
+/// the matcher finds them on the same suffix walk as the domain sets instead
+/// of through a URL token. Postings are bucketed by request type like token
+/// postings. `None` if no rule is host-anchored.
+fn build_host_anchored_rules_section(rules: &[CompiledRule], host_anchors: &[Option<Hash64>]) -> Option<Vec<u8>> {
+    let mut host_to_rules: HashMap<Hash64, Vec<u32>> = HashMap::new();
+    for (rule_id, host_hash) in host_anchors.iter().enumerate() {
+        if let Some(host_hash) = host_hash {
+            host_to_rules.entry(*host_hash).or_default().push(rule_id as u32);
         }
-        
-        if let Some(pattern) = &rule.pattern {
-            let tokens = extract_pattern_tokens(pattern);
-            for token_hash in tokens {
-                token_to_rules.entry(token_hash).or_default().push(rule_id as u32);
+    }
+    if host_to_rules.is_empty() {
+        return None;
+    }
+
+    let mut hosts: Vec<_> = host_to_rules.into_iter().collect();
+    hosts.sort_unstable_by_key(|(hash, _)| (hash.hi, hash.lo));
+    let mut postings_data = Vec::new();
+    let mut entries = Vec::with_capacity(hosts.len());
+    for (hash, rule_ids) in &hosts {
+        entries.push((*hash, postings_data.len() as u32));
+        encode_bucketed_postings(&mut postings_data, rules, rule_ids);
+    }
+
+    let mut section = build_hashmap64(&entries);
+    section.extend_from_slice(&(postings_data.len() as u32).to_le_bytes());
+    section.extend_from_slice(&postings_data);
+    Some(section)
+}
+
+fn build_token_sections(
+    rules: &[CompiledRule],
+    pattern_ids: &[u32],
+    host_anchors: &[Option<Hash64>],
+) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let mut token_to_rules: HashMap<u32, Vec<u32>> = HashMap::new();
+
+    // Host-anchored rules live in their own section.
+    let rule_tokens: Vec<Vec<PatternToken>> = rules
+        .iter()
+        .enumerate()
+        .map(|(rule_id, rule)| match &rule.pattern {
+            Some(pattern) if pattern_ids[rule_id] != NO_PATTERN && host_anchors[rule_id].is_none() => {
+                extract_pattern_tokens(pattern, rule.anchor_type)
             }
+            _ => Vec::new(),
+        })
+        .collect();
+
+    let mut token_counts: HashMap<u32, usize> = HashMap::new();
+    for tokens in &rule_tokens {
+        for token in tokens {
+            *token_counts.entry(token.hash).or_default() += 1;
+        }
+    }
+
+    // The matcher looks up every URL token, so each rule is posted under a
+    // single token: the rarest one the URL is guaranteed to contain.
+    for (rule_id, tokens) in rule_tokens.iter().enumerate() {
+        let pick = |exact_only: bool| {
+            tokens
+                .iter()
+                .filter(|token| token.exact || !exact_only)
+                .min_by_key(|token| (token_counts[&token.hash], std::cmp::Reverse(token.len)))
+        };
+        if let Some(token) = pick(true).or_else(|| pick(false)) {
+            token_to_rules.entry(token.hash).or_default().push(rule_id as u32);
         }
     }
 
     if token_to_rules.is_empty() {
         let empty_dict = build_token_dict(&[]);
         let empty_postings = vec![0u8; 4];
-        return (empty_dict, empty_postings);
+        return (empty_dict, empty_postings, build_token_bloom(&[]));
     }
 
     let mut postings_data = Vec::new();
     let mut dict_entries: Vec<(u32, u32, u32)> = Vec::new();
 
-    for (token_hash, rule_ids) in &token_to_rules {
+    // Token order decides postings offsets and dictionary probe placement
+    let mut tokens: Vec<_> = token_to_rules.iter().collect();
+    tokens.sort_unstable_by_key(|(token_hash, _)| **token_hash);
+
+    for (token_hash, rule_ids) in tokens {
         let postings_offset = postings_data.len() as u32;
-        encode_posting_list(&mut postings_data, rule_ids);
+        encode_bucketed_postings(&mut postings_data, rules, rule_ids);
         dict_entries.push((*token_hash, postings_offset, rule_ids.len() as u32));
     }
 
     let token_dict = build_token_dict(&dict_entries);
-    
+    let token_hashes: Vec<u32> = dict_entries.iter().map(|&(hash, _, _)| hash).collect();
+    let token_bloom = build_token_bloom(&token_hashes);
+
     let mut postings_section = Vec::new();
     postings_section.extend_from_slice(&(postings_data.len() as u32).to_le_bytes());
     postings_section.extend_from_slice(&postings_data);
 
-    (token_dict, postings_section)
+    (token_dict, postings_section, token_bloom)
+}
+
+/// Blocked bloom filter over token hashes: each token sets
+/// `TOKEN_BLOOM_HASH_COUNT` bits within a single 64-bit word, so the matcher
+/// pays one cache miss per probe.
+fn build_token_bloom(token_hashes: &[u32]) -> Vec<u8> {
+    let word_count = (token_hashes.len() * TOKEN_BLOOM_BITS_PER_TOKEN)
+        .div_ceil(64)
+        .max(1)
+        .next_power_of_two();
+    let mut words = vec![0u64; word_count];
+    for &hash in token_hashes {
+        let (word, bits) = token_bloom_probe(hash, word_count - 1, TOKEN_BLOOM_HASH_COUNT);
+        words[word] |= bits;
+    }
+
+    let mut section = Vec::with_capacity(TOKEN_BLOOM_HEADER_SIZE + word_count * 8);
+    section.extend_from_slice(&(word_count as u32).to_le_bytes());
+    section.extend_from_slice(&TOKEN_BLOOM_HASH_COUNT.to_le_bytes());
+    for word in words {
+        section.extend_from_slice(&word.to_le_bytes());
+    }
+    section
+}
+
+struct PatternToken {
+    hash: u32,
+    len: usize,
+    /// The URL is certain to contain this exact token wherever the pattern
+    /// matches; false when a `*`, or an unanchored pattern edge, could
+    /// extend it into a longer URL token.
+    exact: bool,
 }
 
-fn extract_pattern_tokens(pattern: &str) -> Vec<u32> {
+fn extract_pattern_tokens(pattern: &str, anchor_type: AnchorType) -> Vec<PatternToken> {
     let mut tokens = Vec::new();
     let bytes = pattern.as_bytes();
 
@@ -412,9 +731,17 @@ fn extract_pattern_tokens(pattern: &str) -> Vec<u32> {
             }
         } else if let Some(start) = token_start.take() {
             let len = i - start;
-            if len >= 3 {
-                let token = &bytes[start..i];
-                tokens.push(hash_token_bytes_lower(token));
+            // `|ws://` is indexed on the scheme, which URL tokenization keeps
+            // whatever its length.
+            let is_scheme = anchor_type == AnchorType::Left && is_scheme_token(bytes, start, i);
+            if len >= 3 || is_scheme {
+                let left_exact = if start == 0 { anchor_type != AnchorType::None } else { bytes[start - 1] != b'*' };
+                let right_exact = i < bytes.len() && bytes[i] != b'*';
+                tokens.push(PatternToken {
+                    hash: hash_token_bytes_lower(&bytes[start..i]),
+                    len,
+                    exact: left_exact && right_exact,
+                });
             }
         }
     }
@@ -457,6 +784,32 @@ fn encode_posting_list(buf: &mut Vec<u8>, rule_ids: &[u32]) {
     }
 }
 
+/// Split a token's rules into request-type buckets (see
+/// `bb_core::snapshot::posting_bucket`) and append them behind a bucket mask.
+fn encode_bucketed_postings(buf: &mut Vec<u8>, rules: &[CompiledRule], rule_ids: &[u32]) {
+    let mut buckets: Vec<Vec<u32>> = vec![Vec::new(); POSTING_BUCKET_COUNT as usize];
+    for &rule_id in rule_ids {
+        let bucket = posting_bucket_for(rules[rule_id as usize].type_mask.bits());
+        buckets[bucket as usize].push(rule_id);
+    }
+
+    let mask = buckets
+        .iter()
+        .enumerate()
+        .filter(|(_, ids)| !ids.is_empty())
+        .fold(0u8, |mask, (bucket, _)| mask | 1 << bucket);
+    buf.push(mask);
+
+    let mut encoded = Vec::new();
+    for ids in buckets.iter().filter(|ids| !ids.is_empty()) {
+        encoded.clear();
+        encode_posting_list(&mut encoded, ids);
+        encode_varint(buf, ids.len() as u32);
+        encode_varint(buf, encoded.len() as u32);
+        buf.extend_from_slice(&encoded);
+    }
+}
+
 fn encode_varint(buf: &mut Vec<u8>, mut value: u32) {
     loop {
         let byte = (value & 0x7F) as u8;
@@ -508,6 +861,7 @@ fn build_token_dict(entries: &[(u32, u32, u32)]) -> Vec<u8> {
 fn build_redirect_resources_section(
     rules: &[CompiledRule],
     str_pool: &mut StringPool,
+    embed_data: bool,
 ) -> (Vec<u8>, Vec<u32>) {
     let mut option_ids = Vec::with_capacity(rules.len());
     let mut resources = Vec::new();
@@ -521,12 +875,21 @@ fn build_redirect_resources_section(
                 let path = redirect_resource_path(redirect_name);
                 let (name_off, name_len) = str_pool.intern(redirect_name);
                 let (path_off, path_len) = str_pool.intern(&path);
+                let data_url = if embed_data {
+                    lookup_redirect(redirect_name).map(|spec| {
+                        let (off, len) = str_pool.intern(&spec.data_url());
+                        (off, len as u32)
+                    })
+                } else {
+                    None
+                };
                 let index = resources.len() as u32;
                 resources.push(RedirectResource {
                     name_off,
                     name_len: name_len as u32,
                     path_off,
                     path_len: path_len as u32,
+                    data_url,
                 });
                 resource_index.insert(redirect_name.clone(), index);
                 index
@@ -540,11 +903,19 @@ fn build_redirect_resources_section(
     let mut section = Vec::new();
     section.extend_from_slice(&(resources.len() as u32).to_le_bytes());
     for resource in &resources {
+        let flags = if resource.data_url.is_some() { redirect_resource_flags::HAS_DATA_URL } else { 0 };
         section.extend_from_slice(&resource.name_off.to_le_bytes());
         section.extend_from_slice(&resource.name_len.to_le_bytes());
         section.extend_from_slice(&resource.path_off.to_le_bytes());
         section.extend_from_slice(&resource.path_len.to_le_bytes());
-        section.extend_from_slice(&0u32.to_le_bytes());
+        section.extend_from_slice(&flags.to_le_bytes());
+    }
+    if resources.iter().any(|resource| resource.data_url.is_some()) {
+        for resource in &resources {
+            let (off, len) = resource.data_url.unwrap_or((0, 0));
+            section.extend_from_slice(&off.to_le_bytes());
+            section.extend_from_slice(&len.to_le_bytes());
+        }
     }
 
     (section, option_ids)
@@ -555,19 +926,33 @@ struct RedirectResource {
     name_len: u32,
     path_off: u32,
     path_len: u32,
+    /// (off, len) of the `data:` URL when embedded
+    data_url: Option<(u32, u32)>,
 }
 
+/// Path for a redirect target. The parser only accepts registry names, so
+/// the fallback only serves rules built by hand.
 fn redirect_resource_path(name: &str) -> String {
-    if name.starts_with('/') || name.starts_with("data:") || name.contains("://") {
-        return name.to_string();
+    match lookup_redirect(name) {
+        Some(spec) => spec.path.to_string(),
+        None => format!("/redirects/{}", name),
     }
-    if name == "noopjs" {
-        return "/redirects/noop.js".to_string();
-    }
-    if name.starts_with("redirects/") {
-        return format!("/{}", name);
+}
+
+fn build_redirect_registry_section(str_pool: &mut StringPool) -> Vec<u8> {
+    let mut section = Vec::with_capacity(4 + REDIRECT_REGISTRY.len() * REDIRECT_REGISTRY_ENTRY_SIZE);
+    section.extend_from_slice(&(REDIRECT_REGISTRY.len() as u32).to_le_bytes());
+    for spec in REDIRECT_REGISTRY {
+        for value in [spec.name, spec.path, spec.mime] {
+            let (off, len) = str_pool.intern(value);
+            section.extend_from_slice(&off.to_le_bytes());
+            section.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+        let flags = if spec.checksum.is_some() { redirect_registry_flags::HAS_CHECKSUM } else { 0 };
+        section.extend_from_slice(&spec.checksum.unwrap_or(0).to_le_bytes());
+        section.extend_from_slice(&flags.to_le_bytes());
     }
-    format!("/redirects/{}", name)
+    section
 }
 
 fn build_removeparam_specs_section(
@@ -675,9 +1060,10 @@ fn build_header_specs_section(
                     name_len: name_len as u32,
                     value_off,
                     value_len,
-                    flags: if spec.negate { 1 } else { 0 },
+                    flags: if spec.negate { header_spec_flags::NEGATE } else { 0 }
+                        | if spec.request { header_spec_flags::REQUEST } else { 0 },
                 });
-                spec_index.insert(spec.clone(), index);
+                spec_index.insert(spec.as_ref().clone(), index);
                 index
             };
             option_ids.push(index);
@@ -699,6 +1085,105 @@ fn build_header_specs_section(
     (section, option_ids)
 }
 
+fn build_replace_specs_section(
+    rules: &[CompiledRule],
+    str_pool: &mut StringPool,
+) -> (Vec<u8>, Vec<u32>) {
+    let mut option_ids = Vec::with_capacity(rules.len());
+    let mut specs = Vec::new();
+    let mut spec_index: HashMap<crate::parser::ReplaceSpec, u32> = HashMap::new();
+
+    for rule in rules {
+        if let Some(spec) = &rule.replace {
+            let index = if let Some(&existing) = spec_index.get(spec) {
+                existing
+            } else {
+                let (pattern_off, pattern_len) = str_pool.intern(&spec.pattern);
+                let (replacement_off, replacement_len) = str_pool.intern(&spec.replacement);
+                let mut flags = 0u32;
+                if spec.is_exception {
+                    flags |= replace_flags::EXCEPTION;
+                }
+                for ch in spec.flags.chars() {
+                    flags |= match ch {
+                        'i' => replace_flags::IGNORE_CASE,
+                        'g' => replace_flags::GLOBAL,
+                        'm' => replace_flags::MULTILINE,
+                        's' => replace_flags::DOT_ALL,
+                        _ => 0,
+                    };
+                }
+                let index = specs.len() as u32;
+                specs.push(ReplaceSpecEntry {
+                    pattern_off,
+                    pattern_len: pattern_len as u32,
+                    replacement_off,
+                    replacement_len: replacement_len as u32,
+                    flags,
+                });
+                spec_index.insert(spec.as_ref().clone(), index);
+                index
+            };
+            option_ids.push(index);
+        } else {
+            option_ids.push(NO_OPTION_ID);
+        }
+    }
+
+    let mut section = Vec::new();
+    section.extend_from_slice(&(specs.len() as u32).to_le_bytes());
+    for spec in &specs {
+        section.extend_from_slice(&spec.pattern_off.to_le_bytes());
+        section.extend_from_slice(&spec.pattern_len.to_le_bytes());
+        section.extend_from_slice(&spec.replacement_off.to_le_bytes());
+        section.extend_from_slice(&spec.replacement_len.to_le_bytes());
+        section.extend_from_slice(&spec.flags.to_le_bytes());
+    }
+
+    (section, option_ids)
+}
+
+fn build_removeheader_specs_section(
+    rules: &[CompiledRule],
+    str_pool: &mut StringPool,
+) -> (Vec<u8>, Vec<u32>) {
+    let mut option_ids = Vec::with_capacity(rules.len());
+    let mut specs = Vec::new();
+    let mut spec_index: HashMap<(String, bool), u32> = HashMap::new();
+
+    for rule in rules {
+        if let Some(spec) = &rule.removeheader {
+            let key = (spec.name.clone(), spec.request);
+            let index = if let Some(&existing) = spec_index.get(&key) {
+                existing
+            } else {
+                let (name_off, name_len) = str_pool.intern(&spec.name);
+                let index = specs.len() as u32;
+                specs.push(RemoveheaderSpecEntry {
+                    name_off,
+                    name_len: name_len as u32,
+                    flags: if spec.request { removeheader_flags::REQUEST } else { 0 },
+                });
+                spec_index.insert(key, index);
+                index
+            };
+            option_ids.push(index);
+        } else {
+            option_ids.push(NO_OPTION_ID);
+        }
+    }
+
+    let mut section = Vec::new();
+    section.extend_from_slice(&(specs.len() as u32).to_le_bytes());
+    for spec in &specs {
+        section.extend_from_slice(&spec.name_off.to_le_bytes());
+        section.extend_from_slice(&spec.name_len.to_le_bytes());
+        section.extend_from_slice(&spec.flags.to_le_bytes());
+    }
+
+    (section, option_ids)
+}
+
 fn build_responseheader_rules_section(
     rules: &[CompiledRule],
     constraint_offsets: &[u32],
@@ -733,12 +1218,17 @@ fn build_responseheader_rules_section(
     section
 }
 
+/// Returns the cosmetic rules section plus the generic bucket, selector
+/// hash and source line sections that index into it.
 fn build_cosmetic_rules_section(
     rules: &[CompiledRule],
     constraint_offsets: &[u32],
     str_pool: &mut StringPool,
-) -> Vec<u8> {
+) -> (Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>) {
     let mut entries = Vec::new();
+    let mut buckets: Vec<(u32, u32)> = Vec::new();
+    let mut selector_hashes: Vec<u32> = Vec::new();
+    let mut lines: Vec<u32> = Vec::new();
 
     for (idx, rule) in rules.iter().enumerate() {
         let cosmetic = match &rule.cosmetic {
@@ -754,10 +1244,18 @@ fn build_cosmetic_rules_section(
         if cosmetic.is_generic {
             flags |= 1 << 1;
         }
+        if cosmetic.is_generic && !cosmetic.is_exception {
+            if let Some(key) = generic_cosmetic_key(&cosmetic.selector) {
+                flags |= 1 << 2;
+                buckets.push((hash_token(key), entries.len() as u32));
+            }
+        }
         let list_id = rule.list_id;
         let constraint_offset = constraint_offsets.get(idx).copied().unwrap_or(NO_CONSTRAINT);
 
         entries.push((constraint_offset, selector_off, selector_len as u32, flags, list_id));
+        selector_hashes.push(hash_token(&cosmetic.selector));
+        lines.push(rule.line);
     }
 
     let mut section = Vec::new();
@@ -770,22 +1268,103 @@ fn build_cosmetic_rules_section(
         section.extend_from_slice(&list_id.to_le_bytes());
     }
 
-    section
+    buckets.sort_unstable();
+    let mut bucket_section = Vec::with_capacity(4 + buckets.len() * GENERIC_COSMETIC_BUCKET_ENTRY_SIZE);
+    bucket_section.extend_from_slice(&(buckets.len() as u32).to_le_bytes());
+    for (key_hash, rule_index) in buckets {
+        bucket_section.extend_from_slice(&key_hash.to_le_bytes());
+        bucket_section.extend_from_slice(&rule_index.to_le_bytes());
+    }
+
+    let mut hash_section = Vec::with_capacity(4 + selector_hashes.len() * COSMETIC_SELECTOR_HASH_ENTRY_SIZE);
+    hash_section.extend_from_slice(&(selector_hashes.len() as u32).to_le_bytes());
+    for hash in selector_hashes {
+        hash_section.extend_from_slice(&hash.to_le_bytes());
+    }
+
+    let mut line_section = Vec::with_capacity(4 + lines.len() * COSMETIC_RULE_LINE_ENTRY_SIZE);
+    line_section.extend_from_slice(&(lines.len() as u32).to_le_bytes());
+    for line in lines {
+        line_section.extend_from_slice(&line.to_le_bytes());
+    }
+
+    (section, bucket_section, hash_section, line_section)
 }
 
-fn build_procedural_rules_section(
+/// The leading `#id` / `.class` of a generic selector: no page without it
+/// can match, so the rule only needs to be sent once the page uses it.
+/// Selector lists and idents needing escapes stay highly generic.
+fn generic_cosmetic_key(selector: &str) -> Option<&str> {
+    if !(selector.starts_with('#') || selector.starts_with('.')) || selector.contains(',') {
+        return None;
+    }
+    let end = selector[1..]
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        .map_or(selector.len(), |pos| pos + 1);
+    if end == 1 {
+        return None;
+    }
+    match selector[end..].chars().next() {
+        None | Some(' ' | '>' | '+' | '~' | '.' | '#' | '[' | ':') => Some(&selector[..end]),
+        _ => None,
+    }
+}
+
+fn build_cosmetic_style_rules_section(
     rules: &[CompiledRule],
     constraint_offsets: &[u32],
     str_pool: &mut StringPool,
 ) -> Vec<u8> {
-    let mut entries = Vec::new();
+    let styled: Vec<_> = rules
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, rule)| rule.cosmetic_style.as_ref().map(|style| (idx, rule, style)))
+        .collect();
+
+    let mut section = Vec::with_capacity(4 + styled.len() * COSMETIC_STYLE_ENTRY_SIZE);
+    section.extend_from_slice(&(styled.len() as u32).to_le_bytes());
+    for (idx, rule, style) in styled {
+        let (selector_off, selector_len) = str_pool.intern(&style.selector);
+        let (style_off, style_len) = str_pool.intern(&style.style);
+        let mut flags: u16 = 0;
+        if style.is_exception {
+            flags |= 1;
+        }
+        if style.is_generic {
+            flags |= 1 << 1;
+        }
+        let constraint_offset = constraint_offsets.get(idx).copied().unwrap_or(NO_CONSTRAINT);
 
-    for (idx, rule) in rules.iter().enumerate() {
-        let procedural = match &rule.procedural {
-            Some(rule) => rule,
-            None => continue,
-        };
+        section.extend_from_slice(&constraint_offset.to_le_bytes());
+        section.extend_from_slice(&selector_off.to_le_bytes());
+        section.extend_from_slice(&(selector_len as u32).to_le_bytes());
+        section.extend_from_slice(&style_off.to_le_bytes());
+        section.extend_from_slice(&(style_len as u32).to_le_bytes());
+        section.extend_from_slice(&flags.to_le_bytes());
+        section.extend_from_slice(&rule.list_id.to_le_bytes());
+    }
+
+    section
+}
 
+/// Entries keep the raw selector for `#@#` exceptions; the trailing programs
+/// and op table hold the selector pre-split by [`parse_procedural_selector`],
+/// so the extension gets structured ops without parsing at match time.
+fn build_procedural_rules_section(
+    rules: &[CompiledRule],
+    constraint_offsets: &[u32],
+    str_pool: &mut StringPool,
+) -> Vec<u8> {
+    let procedural: Vec<_> = rules
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, rule)| rule.procedural.as_ref().map(|procedural| (idx, rule, procedural)))
+        .collect();
+
+    let mut section =
+        Vec::with_capacity(4 + procedural.len() * (PROCEDURAL_RULE_ENTRY_SIZE + PROCEDURAL_PROGRAM_ENTRY_SIZE));
+    section.extend_from_slice(&(procedural.len() as u32).to_le_bytes());
+    for &(idx, rule, procedural) in &procedural {
         let (selector_off, selector_len) = str_pool.intern(&procedural.selector);
         let mut flags: u16 = 0;
         if procedural.is_exception {
@@ -794,21 +1373,39 @@ fn build_procedural_rules_section(
         if procedural.is_generic {
             flags |= 1 << 1;
         }
-        let list_id = rule.list_id;
         let constraint_offset = constraint_offsets.get(idx).copied().unwrap_or(NO_CONSTRAINT);
 
-        entries.push((constraint_offset, selector_off, selector_len as u32, flags, list_id));
-    }
-
-    let mut section = Vec::new();
-    section.extend_from_slice(&(entries.len() as u32).to_le_bytes());
-    for (constraint_offset, selector_off, selector_len, flags, list_id) in entries {
         section.extend_from_slice(&constraint_offset.to_le_bytes());
         section.extend_from_slice(&selector_off.to_le_bytes());
-        section.extend_from_slice(&selector_len.to_le_bytes());
+        section.extend_from_slice(&(selector_len as u32).to_le_bytes());
         section.extend_from_slice(&flags.to_le_bytes());
-        section.extend_from_slice(&list_id.to_le_bytes());
+        section.extend_from_slice(&rule.list_id.to_le_bytes());
+    }
+
+    // Exceptions only need the selector, and a selector that doesn't parse
+    // can only come from rules built without the parser: both get an empty
+    // program, which is never applied. Selectors too long for u16 counts
+    // fail the build in the string pool.
+    let mut ops = Vec::new();
+    for &(_, _, procedural) in &procedural {
+        let (base, program_ops) = match parse_procedural_selector(&procedural.selector) {
+            Ok(program) if !procedural.is_exception => program,
+            _ => ("", Vec::new()),
+        };
+        let (base_off, base_len) = str_pool.intern(base);
+        section.extend_from_slice(&base_off.to_le_bytes());
+        section.extend_from_slice(&base_len.to_le_bytes());
+        section.extend_from_slice(&(program_ops.len() as u16).to_le_bytes());
+        section.extend_from_slice(&((ops.len() / PROCEDURAL_OP_ENTRY_SIZE) as u32).to_le_bytes());
+        for (kind, arg) in program_ops {
+            let (arg_off, arg_len) = str_pool.intern(arg);
+            ops.extend_from_slice(&arg_off.to_le_bytes());
+            ops.extend_from_slice(&arg_len.to_le_bytes());
+            ops.push(kind as u8);
+            ops.push(0);
+        }
     }
+    section.extend_from_slice(&ops);
 
     section
 }
@@ -834,6 +1431,9 @@ fn build_scriptlet_rules_section(
         if scriptlet.is_generic {
             flags |= 1 << 1;
         }
+        if scriptlet.name_only {
+            flags |= 1 << 2;
+        }
         let list_id = rule.list_id;
         let constraint_offset = constraint_offsets.get(idx).copied().unwrap_or(NO_CONSTRAINT);
 
@@ -853,12 +1453,66 @@ fn build_scriptlet_rules_section(
     section
 }
 
+fn build_list_meta_section(lists: &[ListMetadata], str_pool: &mut StringPool) -> Vec<u8> {
+    let mut section = Vec::with_capacity(4 + lists.len() * LIST_META_ENTRY_SIZE);
+    section.extend_from_slice(&(lists.len() as u32).to_le_bytes());
+
+    let mut intern = |value: &Option<String>| match value {
+        Some(value) if !value.is_empty() => {
+            let (off, len) = str_pool.intern(value);
+            (off, len as u32)
+        }
+        _ => (0, 0),
+    };
+
+    for meta in lists {
+        let (title_off, title_len) = intern(&meta.title);
+        let (version_off, version_len) = intern(&meta.version);
+        let (homepage_off, homepage_len) = intern(&meta.homepage);
+
+        section.extend_from_slice(&meta.list_id.to_le_bytes());
+        section.extend_from_slice(&(meta.tier as u16).to_le_bytes());
+        section.extend_from_slice(&meta.expires_secs.unwrap_or(0).to_le_bytes());
+        section.extend_from_slice(&title_off.to_le_bytes());
+        section.extend_from_slice(&title_len.to_le_bytes());
+        section.extend_from_slice(&version_off.to_le_bytes());
+        section.extend_from_slice(&version_len.to_le_bytes());
+        section.extend_from_slice(&homepage_off.to_le_bytes());
+        section.extend_from_slice(&homepage_len.to_le_bytes());
+    }
+
+    section
+}
+
+fn build_build_info_section(info: &BuildInfo, str_pool: &mut StringPool) -> Vec<u8> {
+    let mut section = Vec::with_capacity(BUILD_INFO_HEADER_SIZE + info.lists.len() * BUILD_INFO_ENTRY_SIZE);
+    let (version_off, version_len) = str_pool.intern(&info.compiler_version);
+    section.extend_from_slice(&info.compiled_at.to_le_bytes());
+    section.extend_from_slice(&version_off.to_le_bytes());
+    section.extend_from_slice(&(version_len as u32).to_le_bytes());
+    section.extend_from_slice(&(info.lists.len() as u32).to_le_bytes());
+
+    for list in &info.lists {
+        let (source_off, source_len) = str_pool.intern(&list.source);
+        section.extend_from_slice(&list.list_id.to_le_bytes());
+        section.extend_from_slice(&0u16.to_le_bytes());
+        section.extend_from_slice(&source_off.to_le_bytes());
+        section.extend_from_slice(&(source_len as u32).to_le_bytes());
+        section.extend_from_slice(&list.content_hash.lo.to_le_bytes());
+        section.extend_from_slice(&list.content_hash.hi.to_le_bytes());
+    }
+
+    section
+}
+
 fn build_option_ids(
     rules: &[CompiledRule],
     redirect_option_ids: &[u32],
     removeparam_option_ids: &[u32],
     csp_option_ids: &[u32],
     header_option_ids: &[u32],
+    replace_option_ids: &[u32],
+    removeheader_option_ids: &[u32],
 ) -> Vec<u32> {
     let mut merged = Vec::with_capacity(rules.len());
     for (idx, rule) in rules.iter().enumerate() {
@@ -868,6 +1522,10 @@ fn build_option_ids(
             csp_option_ids.get(idx).copied().unwrap_or(NO_OPTION_ID)
         } else if rule.header.is_some() {
             header_option_ids.get(idx).copied().unwrap_or(NO_OPTION_ID)
+        } else if rule.replace.is_some() {
+            replace_option_ids.get(idx).copied().unwrap_or(NO_OPTION_ID)
+        } else if rule.removeheader.is_some() {
+            removeheader_option_ids.get(idx).copied().unwrap_or(NO_OPTION_ID)
         } else if rule.redirect.is_some() {
             redirect_option_ids.get(idx).copied().unwrap_or(NO_OPTION_ID)
         } else {
@@ -889,6 +1547,12 @@ struct CspSpec {
     spec_len: u32,
 }
 
+struct RemoveheaderSpecEntry {
+    name_off: u32,
+    name_len: u32,
+    flags: u32,
+}
+
 struct HeaderSpecEntry {
     name_off: u32,
     name_len: u32,
@@ -897,30 +1561,92 @@ struct HeaderSpecEntry {
     flags: u32,
 }
 
-fn build_rules_section(rules: &[CompiledRule], constraint_offsets: &[u32], pattern_ids: &[u32], option_ids: &[u32]) -> Vec<u8> {
-    let count = rules.len();
-    let mut buf = Vec::new();
-    buf.extend_from_slice(&(count as u32).to_le_bytes());
+struct ReplaceSpecEntry {
+    pattern_off: u32,
+    pattern_len: u32,
+    replacement_off: u32,
+    replacement_len: u32,
+    flags: u32,
+}
 
-    if count == 0 {
-        return buf;
+/// Flags as stored in the Rules section: the parsed flags plus the derived
+/// `IS_GENERIC` bit `$genericblock` filters on.
+fn snapshot_flags(rule: &CompiledRule) -> RuleFlags {
+    let is_generic = rule.action == RuleAction::Block
+        && rule.domain_constraints.as_ref().is_none_or(|constraints| constraints.include.is_empty());
+    if is_generic {
+        rule.flags | RuleFlags::IS_GENERIC
+    } else {
+        rule.flags
     }
+}
 
-    let mut pos = 4;
+/// `(rule_id, expires_at)` for rules with `$expires=`, or `None` if there
+/// are none.
+fn build_rule_expiry_section(rules: &[CompiledRule]) -> Option<Vec<u8>> {
+    let expiring: Vec<(u32, u32)> = rules
+        .iter()
+        .enumerate()
+        .filter_map(|(rule_id, rule)| Some((rule_id as u32, rule.expires_at?)))
+        .collect();
+    if expiring.is_empty() {
+        return None;
+    }
+
+    let mut section = Vec::with_capacity(4 + expiring.len() * RULE_EXPIRY_ENTRY_SIZE);
+    section.extend_from_slice(&(expiring.len() as u32).to_le_bytes());
+    for (rule_id, expires_at) in expiring {
+        section.extend_from_slice(&rule_id.to_le_bytes());
+        section.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    Some(section)
+}
+
+fn build_rule_signatures_section(rules: &[CompiledRule]) -> Option<Vec<u8>> {
+    let mut signatures: Vec<(u64, u32)> = rules
+        .iter()
+        .enumerate()
+        .filter_map(|(rule_id, rule)| Some((rule_signature(rule)?.to_u64(), rule_id as u32)))
+        .collect();
+    if signatures.is_empty() {
+        return None;
+    }
+    signatures.sort_unstable();
+
+    let mut section = Vec::with_capacity(4 + signatures.len() * RULE_SIGNATURE_ENTRY_SIZE);
+    section.extend_from_slice(&(signatures.len() as u32).to_le_bytes());
+    for (signature, rule_id) in signatures {
+        let signature = Hash64::from_u64(signature);
+        section.extend_from_slice(&signature.lo.to_le_bytes());
+        section.extend_from_slice(&signature.hi.to_le_bytes());
+        section.extend_from_slice(&rule_id.to_le_bytes());
+    }
+    Some(section)
+}
+
+fn build_rules_section(rules: &[CompiledRule], constraint_offsets: &[u32], pattern_ids: &[u32], option_ids: &[u32]) -> Vec<u8> {
+    let count = rules.len();
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(count as u32).to_le_bytes());
+
+    if count == 0 {
+        return buf;
+    }
+
+    let mut pos = 4;
 
     pad_to(&mut buf, pos);
     for rule in rules {
         buf.push(rule.action as u8);
     }
     pos += count;
-    pos = align_offset(pos, 2);
+    pos = align_offset(pos, 4);
     pad_to(&mut buf, pos);
 
     for rule in rules {
-        buf.extend_from_slice(&rule.flags.bits().to_le_bytes());
+        buf.extend_from_slice(&snapshot_flags(rule).bits().to_le_bytes());
     }
-    pos += count * 2;
-    pos = align_offset(pos, 4);
+    pos += count * 4;
     pad_to(&mut buf, pos);
 
     for rule in rules {
@@ -961,8 +1687,8 @@ fn build_rules_section(rules: &[CompiledRule], constraint_offsets: &[u32], patte
     pos += count * 4;
     pad_to(&mut buf, pos);
 
-    for _ in rules {
-        buf.extend_from_slice(&0i16.to_le_bytes());
+    for rule in rules {
+        buf.extend_from_slice(&rule_priority(rule).to_le_bytes());
     }
     pos += count * 2;
     pos = align_offset(pos, 2);
@@ -975,6 +1701,89 @@ fn build_rules_section(rules: &[CompiledRule], constraint_offsets: &[u32], patte
     buf
 }
 
+/// Specificity score used to break ties between rules of the same class
+/// (e.g. two blocks): longer literal host/pattern text and narrower options
+/// win over broader rules.
+pub(crate) fn rule_priority(rule: &CompiledRule) -> i16 {
+    let pattern_literals = rule
+        .pattern
+        .as_deref()
+        .map(|pattern| pattern.bytes().filter(|b| !matches!(b, b'*' | b'^' | b'|')).count())
+        .unwrap_or(0);
+    let mut score = (rule.domain.len() + pattern_literals).min(1024) as i32;
+
+    if rule.anchor_type != AnchorType::None {
+        score += 8;
+    }
+    if let Some(constraints) = &rule.domain_constraints {
+        if !constraints.include.is_empty() {
+            score += 256;
+        }
+        if !constraints.exclude.is_empty() {
+            score += 16;
+        }
+    }
+    if !rule.type_mask.is_empty() {
+        score += 64;
+    }
+    if !rule.party_mask.is_empty() {
+        score += 32;
+    }
+    if !rule.scheme_mask.is_empty() {
+        score += 16;
+    }
+
+    score.min(i16::MAX as i32) as i16
+}
+
+fn build_psl_section(psl: &PslRules) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for set in [&psl.exact, &psl.wildcard, &psl.exception] {
+        let hashes: Vec<Hash64> = set.iter().map(|suffix| hash_domain(suffix)).collect();
+        buf.extend_from_slice(&build_hashset64(&hashes));
+    }
+    buf
+}
+
+fn build_hashset64(hashes: &[Hash64]) -> Vec<u8> {
+    let count = hashes.len();
+    let capacity = if count == 0 { 0 } else { compute_capacity(count) };
+
+    let mut buf = vec![0u8; HASHMAP64_HEADER_SIZE + capacity * HASHSET64_ENTRY_SIZE];
+    write_u32_le(&mut buf, 0, capacity as u32);
+    write_u32_le(&mut buf, 4, count as u32);
+    write_u32_le(&mut buf, 8, HASH_SEED_LO);
+    write_u32_le(&mut buf, 12, HASH_SEED_HI);
+    write_u32_le(&mut buf, 16, 0);
+
+    if capacity == 0 {
+        return buf;
+    }
+
+    let entries_offset = HASHMAP64_HEADER_SIZE;
+    let mask = capacity - 1;
+
+    for hash in hashes {
+        let mut idx = (hash.lo as usize) & mask;
+        for _ in 0..capacity {
+            let entry_offset = entries_offset + idx * HASHSET64_ENTRY_SIZE;
+            let lo = read_u32_le(&buf, entry_offset);
+            let hi = read_u32_le(&buf, entry_offset + 4);
+            if lo == hash.lo && hi == hash.hi {
+                break;
+            }
+            if lo == 0 && hi == 0 {
+                write_u32_le(&mut buf, entry_offset, hash.lo);
+                write_u32_le(&mut buf, entry_offset + 4, hash.hi);
+                break;
+            }
+            idx = (idx + 1) & mask;
+        }
+    }
+
+    buf
+}
+
 fn build_hashmap64(entries: &[(Hash64, u32)]) -> Vec<u8> {
     let count = entries.len();
     let capacity = if count == 0 { 0 } else { compute_capacity(count) };
@@ -1046,17 +1855,58 @@ fn write_u32_le(data: &mut [u8], offset: usize, value: u32) {
     data[offset..offset + 4].copy_from_slice(&bytes);
 }
 
+fn write_u64_le(data: &mut [u8], offset: usize, value: u64) {
+    let bytes = value.to_le_bytes();
+    data[offset..offset + 8].copy_from_slice(&bytes);
+}
+
 #[cfg(test)]
 mod tests {
-    use bb_core::hash::hash_domain;
-    use bb_core::matcher::{Matcher, ResponseHeader};
-    use bb_core::snapshot::Snapshot;
-    use bb_core::types::{MatchDecision, RequestContext, RequestType, SchemeMask};
+    use std::collections::HashMap;
+
+    use bb_core::hash::{hash64, hash_domain, hash_token};
+    use bb_core::engine::{Engine, RequestInfo};
+    use bb_core::matcher::{
+        CandidateSource, CosmeticHideSwitches, DecisionReason, MatchBudget, MatchScratch, Matcher, ProceduralOp,
+        ProceduralProgram, Rejection, RequestHeader, ResponseHeader, DEFAULT_LARGE_MEDIA_SIZE, NO_SCRIPTING_CSP,
+    };
+    use bb_core::psl::load_psl_from_bytes;
+    use bb_core::snapshot::{
+        append_bucketed_postings, decode_varint, posting_bucket_for, section_entry, PostingBuf, SectionId, Snapshot,
+        SnapshotError, SnapshotLayout, HEADER_SIZE, UBX_VERSION,
+    };
+    use bb_core::types::{
+        MatchDecision, ProceduralOpKind, RequestContext, RequestType, RuleFlags, SchemeMask, SiteSwitches,
+    };
+
+    use crate::optimizer::{optimize_rules, optimize_rules_with_options, OptimizeOptions};
+    use crate::diagnostics::{CompileDiagnostics, DropReason};
+    use crate::error::{Error, MAX_RULES, MAX_SNAPSHOT_BYTES, MAX_STRING_BYTES};
+    use crate::parser::{
+        normalize_selector, parse_filter_list, parse_filter_list_into, parse_filter_list_with_config,
+        parse_filter_list_with_diagnostics, DnsOptionPolicy, ParserConfig,
+    };
 
-    use crate::optimizer::optimize_rules;
-    use crate::parser::parse_filter_list;
+    use super::{
+        build_hashmap64, build_sections, build_snapshot, build_snapshot_with_extra, build_snapshot_with_options,
+        build_snapshot_with_psl, generic_cosmetic_key, map_to_posting_entries, try_build_snapshot_with_options,
+        write_snapshot, SnapshotOptions,
+    };
+    use crate::metadata::{parse_list_metadata, BuildInfo, ListMetadata};
+    use crate::preprocess::{preprocess_filter_list, NoIncludes, PreprocessEnv};
 
-    use super::build_snapshot;
+    #[test]
+    fn identical_input_builds_identical_snapshots() {
+        let list: String = (0..200)
+            .map(|i| format!("||ads{i}.example^\n@@||ok{i}.example^\n/banner{i}/*$script\nsite{i}.example##.ad{i}\n"))
+            .collect();
+        let build = || {
+            let mut rules = parse_filter_list(&list);
+            optimize_rules(&mut rules);
+            build_snapshot(&rules)
+        };
+        assert_eq!(build(), build());
+    }
 
     #[test]
     fn builds_domain_sets_and_rules() {
@@ -1380,7 +2230,7 @@ mod tests {
         }];
 
         let result = matcher.match_response_headers(&ctx, &headers);
-        assert_eq!(result.cancel, false);
+        assert!(!result.cancel);
         assert_eq!(result.csp_injections, vec!["script-src 'none'".to_string()]);
 
         let rules = parse_filter_list(
@@ -1480,6 +2330,103 @@ mod tests {
         assert!(result.remove_headers.is_empty());
     }
 
+    #[test]
+    fn rewrite_headers_merges_csp_and_drops_removed() {
+        let rules = parse_filter_list(
+            "||example.com^$csp=script-src 'none'\n||example.com^$csp=frame-src 'self'\nexample.com##^responseheader(refresh)",
+        );
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+
+        let ctx = RequestContext {
+            url: "https://example.com/index.html",
+            req_host: "example.com",
+            req_etld1: "example.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: false,
+            request_type: RequestType::MAIN_FRAME,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        let headers = [
+            ResponseHeader {
+                name: "Content-Security-Policy",
+                value: "default-src 'self'",
+            },
+            ResponseHeader {
+                name: "Refresh",
+                value: "0; url=https://ads.example/",
+            },
+            ResponseHeader {
+                name: "Content-Type",
+                value: "text/html",
+            },
+        ];
+
+        let rewritten = matcher.rewrite_headers(&ctx, &headers);
+        assert_eq!(
+            rewritten,
+            vec![
+                ("Content-Security-Policy".to_string(), None),
+                ("Content-Type".to_string(), None),
+                (
+                    "Content-Security-Policy".to_string(),
+                    Some("frame-src 'self', script-src 'none'".to_string())
+                ),
+            ]
+        );
+
+        let ctx = RequestContext {
+            request_type: RequestType::SCRIPT,
+            ..ctx
+        };
+        let rewritten = matcher.rewrite_headers(&ctx, &headers);
+        assert_eq!(rewritten.len(), headers.len());
+        assert!(rewritten.iter().all(|(_, value)| value.is_none()));
+    }
+
+    #[test]
+    fn csp_exceptions_normalize_and_report_only() {
+        let rules = parse_filter_list(
+            "||example.com^$csp=Script-Src  'none';\n||example.com^$csp=frame-src 'self'\n@@||example.com^$csp=script-src 'none'",
+        );
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let mut matcher = Matcher::new(&snapshot);
+
+        let ctx = RequestContext {
+            url: "https://example.com/index.html",
+            req_host: "example.com",
+            req_etld1: "example.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: false,
+            request_type: RequestType::MAIN_FRAME,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        let result = matcher.match_response_headers(&ctx, &[]);
+        assert_eq!(result.csp_injections, vec!["frame-src 'self'".to_string()]);
+        assert!(!result.csp_report_only);
+
+        matcher.set_csp_report_only(true);
+        assert_eq!(
+            matcher.rewrite_headers(&ctx, &[]),
+            vec![(
+                "Content-Security-Policy-Report-Only".to_string(),
+                Some("frame-src 'self'".to_string())
+            )]
+        );
+    }
+
     #[test]
     fn cosmetic_rules_and_generichide() {
         let rules = parse_filter_list("example.com##.ad\nexample.com#@#.ad");
@@ -1511,7 +2458,7 @@ mod tests {
 
         let result = matcher.match_cosmetics(&ctx);
         assert!(result.css.is_empty());
-        assert_eq!(result.enable_generic, false);
+        assert!(!result.enable_generic);
     }
 
     #[test]
@@ -1678,71 +2625,193 @@ mod tests {
     }
 
     #[test]
-    fn badfilter_cancels_block_rule() {
-        // Block rule with matching badfilter should be cancelled
-        let mut rules = parse_filter_list("||ads.com^\n||ads.com^$badfilter");
-        optimize_rules(&mut rules);
+    fn cosmetic_hide_switches_key_cosmetics_by_path() {
+        let rules = parse_filter_list("example.com##.ad\n##div[data-banner]\n@@||example.com/forum/$generichide");
         let bytes = build_snapshot(&rules);
         let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
-        let matcher = Matcher::new(&snapshot);
+        let engine = Engine::new(&snapshot);
+
+        let home = RequestInfo::new("https://example.com/", RequestType::MAIN_FRAME, None);
+        let forum = RequestInfo::new("https://example.com/forum/1", RequestType::MAIN_FRAME, None);
+        let home_switches = engine.cosmetic_hide_switches_for_request(&home);
+        let forum_switches = engine.cosmetic_hide_switches_for_request(&forum);
+        assert!(!home_switches.generichide);
+        assert!(forum_switches.generichide);
+
+        // Same site, different exceptions: the switches keep per-site caches apart.
+        for (request, switches) in [(&home, home_switches), (&forum, forum_switches)] {
+            let direct = engine.cosmetics_for_request(request);
+            let cached = engine.cosmetics_with_switches_for_request(request, switches);
+            assert_eq!(direct.css, cached.css);
+            assert_eq!(direct.enable_generic, cached.enable_generic);
+        }
+        assert!(engine.cosmetics_for_request(&home).css.concat().contains("div[data-banner]"));
+        assert!(!engine.cosmetics_for_request(&forum).css.concat().contains("div[data-banner]"));
+    }
 
-        let ctx = RequestContext {
-            url: "https://ads.com/script.js",
-            req_host: "ads.com",
-            req_etld1: "ads.com",
-            site_host: "example.com",
-            site_etld1: "example.com",
-            is_third_party: true,
-            request_type: RequestType::SCRIPT,
-            scheme: SchemeMask::HTTPS,
-            tab_id: 0,
-            frame_id: 0,
-            request_id: "0",
+    #[test]
+    fn procedural_operator_arguments_are_validated() {
+        let valid = [
+            "example.com##.ad:upward(2)",
+            "example.com##.ad:upward(.wrapper > div)",
+            "example.com##.ad:matches-css(display: block)",
+            "example.com##.ad:matches-css(--banner-height, /^\\d+px$/)",
+            "example.com##.ad:matches-path(/shop)",
+            "example.com##.ad:matches-path(/^\\/news\\//)",
+            "example.com##p:min-text-length(200):remove()",
+            "example.com##.ad:has-text((sponsored))",
+        ];
+        let (rules, diagnostics) = parse_filter_list_with_diagnostics(&valid.join("\n"));
+        assert!(diagnostics.dropped_lines.is_empty(), "{:?}", diagnostics.dropped_lines);
+        assert_eq!(rules.iter().filter(|rule| rule.procedural.is_some()).count(), valid.len());
+
+        let invalid = [
+            ("example.com##.ad:upward(0)", "upward"),
+            ("example.com##.ad:upward(300)", "upward"),
+            ("example.com##.ad:upward(1.5)", "upward"),
+            ("example.com##.ad:upward(> div)", "upward"),
+            ("example.com##.ad:matches-css(display)", "matches-css"),
+            ("example.com##.ad:matches-css(2px: block)", "matches-css"),
+            ("example.com##.ad:matches-path(/(/)", "matches-path"),
+            ("example.com##p:min-text-length(many)", "min-text-length"),
+            ("example.com##.ad:remove(now)", "remove"),
+            ("example.com##.ad:has-text(foo", "has-text"),
+        ];
+        for (line, op) in invalid {
+            let (rules, diagnostics) = parse_filter_list_with_diagnostics(line);
+            assert!(rules.is_empty(), "{} should be dropped", line);
+            assert_eq!(diagnostics.dropped_lines[0].reason, DropReason::InvalidProcedural(op.to_string()), "{}", line);
+        }
+    }
+
+    #[test]
+    fn procedural_selectors_compile_to_ops() {
+        let (rules, diagnostics) = parse_filter_list_with_diagnostics(
+            "example.com##.ad:has-text( Sponsored ):upward(2)\n\
+             example.com##:xpath(//div[@id=\"x\"])\n\
+             example.com##.skip:remove()\n\
+             example.com#@#.skip:remove()\n\
+             example.com##.ad:HAS-TEXT(x)",
+        );
+        assert_eq!(diagnostics.dropped_lines.len(), 1);
+        assert_eq!(diagnostics.dropped_lines[0].reason, DropReason::UnsupportedCosmetic);
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let engine = Engine::new(&snapshot);
+        let request = RequestInfo::new("https://example.com/", RequestType::MAIN_FRAME, None);
+        let mut procedural = engine.cosmetics_for_request(&request).procedural;
+        procedural.sort_by(|a, b| a.base.cmp(&b.base));
+
+        let op = |kind, arg: &str| ProceduralOp {
+            kind,
+            arg: arg.to_string(),
         };
+        assert_eq!(
+            procedural,
+            [
+                ProceduralProgram {
+                    base: "*".to_string(),
+                    ops: vec![op(ProceduralOpKind::Xpath, "//div[@id=\"x\"]")],
+                },
+                ProceduralProgram {
+                    base: ".ad".to_string(),
+                    ops: vec![op(ProceduralOpKind::HasText, "Sponsored"), op(ProceduralOpKind::Upward, "2")],
+                },
+            ]
+        );
+    }
 
-        let result = matcher.match_request(&ctx);
-        assert_eq!(result.decision, MatchDecision::Allow);
+    #[test]
+    fn invalid_css_selectors_are_dropped() {
+        let valid = [
+            "##.ad-banner",
+            "example.com##div[id^=\"ad-\"] > a:not([href*=\"example\"])",
+            "example.com##li:nth-child(2n+1 of .promo)",
+            "example.com##.card:has(> .sponsored)",
+            "example.com##:is(.ad, .promo)::before",
+            "example.com##input:-webkit-autofill",
+            "example.com##.nag:style(opacity: 0)",
+            "example.com#@#.ad:-abp-has(.x)",
+        ];
+        let (rules, diagnostics) = parse_filter_list_with_diagnostics(&valid.join("\n"));
+        assert!(diagnostics.dropped_lines.is_empty(), "{:?}", diagnostics.dropped_lines);
+        assert_eq!(rules.len(), valid.len());
+
+        for line in [
+            "##.ad:-abp-has(.x)",
+            "example.com##div >",
+            "example.com##.ad,,.promo",
+            "example.com##a[href=",
+            "example.com##.ad:hovered",
+            "example.com##.nag:bogus:style(opacity: 0)",
+        ] {
+            let (rules, diagnostics) = parse_filter_list_with_diagnostics(line);
+            assert!(rules.is_empty(), "{} should be dropped", line);
+            assert!(
+                matches!(diagnostics.dropped_lines[0].reason, DropReason::InvalidSelector(_)),
+                "{}: {:?}",
+                line,
+                diagnostics.dropped_lines[0].reason
+            );
+        }
     }
 
     #[test]
-    fn badfilter_cancels_exception_rule() {
-        // Exception rule with matching badfilter should be cancelled, allowing block
-        let mut rules = parse_filter_list("||ads.com^\n@@||ads.com^\n@@||ads.com^$badfilter");
-        optimize_rules(&mut rules);
+    fn specifichide_keeps_generic_cosmetics() {
+        let rules = parse_filter_list(
+            "##div.sponsored\n\
+             example.com##.promo-box\n\
+             example.com#?#.ad:has-text(foo)\n\
+             example.com##.nag:style(opacity: 0)\n\
+             @@||example.com^$shide",
+        );
         let bytes = build_snapshot(&rules);
         let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
         let matcher = Matcher::new(&snapshot);
 
         let ctx = RequestContext {
-            url: "https://ads.com/script.js",
-            req_host: "ads.com",
-            req_etld1: "ads.com",
+            url: "https://example.com/index.html",
+            req_host: "example.com",
+            req_etld1: "example.com",
             site_host: "example.com",
             site_etld1: "example.com",
-            is_third_party: true,
-            request_type: RequestType::SCRIPT,
+            is_third_party: false,
+            request_type: RequestType::MAIN_FRAME,
             scheme: SchemeMask::HTTPS,
             tab_id: 0,
             frame_id: 0,
             request_id: "0",
         };
 
-        let result = matcher.match_request(&ctx);
-        assert_eq!(result.decision, MatchDecision::Block);
+        let result = matcher.match_cosmetics(&ctx);
+        assert_eq!(result.css, ["div.sponsored{display:none !important;}"]);
+        assert!(result.procedural.is_empty());
+        assert!(result.style_css.is_empty());
+        assert!(result.enable_generic);
+        // The exception itself never allows the request.
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Allow);
+
+        let rules = parse_filter_list("||example.com^$specifichide");
+        assert!(rules.is_empty());
     }
 
     #[test]
-    fn important_exception_beats_important_block() {
-        // @@$important should beat $important block
-        let rules = parse_filter_list("||ads.com^$important\n@@||ads.com^$important");
+    fn disabled_lists_are_skipped_at_match_time() {
+        let mut rules = parse_filter_list("||ads.example^\nexample.com##.banner\nexample.com##+js(set-constant, foo, bar)");
+        let mut user = parse_filter_list("@@||ads.example^$script\nexample.com##.nag");
+        for rule in &mut user {
+            rule.list_id = 1;
+        }
+        rules.extend(user);
         let bytes = build_snapshot(&rules);
         let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
-        let matcher = Matcher::new(&snapshot);
+        let mut matcher = Matcher::new(&snapshot);
 
-        let ctx = RequestContext {
-            url: "https://ads.com/script.js",
-            req_host: "ads.com",
-            req_etld1: "ads.com",
+        let script = RequestContext {
+            url: "https://ads.example/a.js",
+            req_host: "ads.example",
+            req_etld1: "ads.example",
             site_host: "example.com",
             site_etld1: "example.com",
             is_third_party: true,
@@ -1752,42 +2821,392 @@ mod tests {
             frame_id: 0,
             request_id: "0",
         };
-
-        let result = matcher.match_request(&ctx);
-        assert_eq!(result.decision, MatchDecision::Allow);
+        let page = RequestContext {
+            url: "https://example.com/",
+            req_host: "example.com",
+            req_etld1: "example.com",
+            is_third_party: false,
+            request_type: RequestType::MAIN_FRAME,
+            ..script.clone()
+        };
+        assert_eq!(matcher.match_request(&script).decision, MatchDecision::Allow);
+
+        matcher.set_disabled_lists(&[1]);
+        assert!(!matcher.is_list_enabled(1));
+        assert_eq!(matcher.match_request(&script).decision, MatchDecision::Block);
+        let cosmetics = matcher.match_cosmetics(&page);
+        assert!(cosmetics.css.concat().contains(".banner") && !cosmetics.css.concat().contains(".nag"));
+
+        matcher.set_disabled_lists(&[0]);
+        assert_eq!(matcher.match_request(&script).decision, MatchDecision::Allow);
+        let cosmetics = matcher.match_cosmetics(&page);
+        assert!(!cosmetics.css.concat().contains(".banner") && cosmetics.css.concat().contains(".nag"));
+        assert!(cosmetics.scriptlets.is_empty());
+
+        matcher.set_disabled_lists(&[]);
+        assert_eq!(matcher.match_cosmetics(&page).scriptlets.len(), 1);
     }
 
     #[test]
-    fn redirect_with_important_beats_exception() {
-        // $redirect,important should redirect even with exception
-        let rules = parse_filter_list("||ads.com^$redirect=noop.js,important\n@@||ads.com^");
+    fn disabled_rules_are_skipped_at_match_time() {
+        let list = "||ads.example^\n||cdn.example/ads/*$script\n@@||ads.example/ok.js\n";
+        let rules = parse_filter_list(list);
         let bytes = build_snapshot(&rules);
         let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
-        let matcher = Matcher::new(&snapshot);
+        let mut engine = Engine::new(&snapshot);
+        let check = |engine: &Engine<'_>, url: &str| engine.check(url, RequestType::SCRIPT, Some("https://site.com/"));
 
-        let ctx = RequestContext {
-            url: "https://ads.com/script.js",
-            req_host: "ads.com",
-            req_etld1: "ads.com",
-            site_host: "example.com",
-            site_etld1: "example.com",
-            is_third_party: true,
-            request_type: RequestType::SCRIPT,
-            scheme: SchemeMask::HTTPS,
-            tab_id: 0,
-            frame_id: 0,
-            request_id: "0",
-        };
+        let host = check(&engine, "https://ads.example/a.js");
+        let pattern = check(&engine, "https://cdn.example/ads/a.js");
+        let exception = check(&engine, "https://ads.example/ok.js");
+        assert_eq!(
+            (host.decision, pattern.decision, exception.decision),
+            (MatchDecision::Block, MatchDecision::Block, MatchDecision::Allow)
+        );
 
-        let result = matcher.match_request(&ctx);
-        assert_eq!(result.decision, MatchDecision::Redirect);
-        assert!(result.redirect_url.is_some());
+        let disabled = [pattern.rule_id as u32, exception.rule_id as u32, 200];
+        engine.matcher_mut().set_disabled_rules(&disabled);
+        assert_eq!(engine.matcher().disabled_rules(), disabled);
+        assert!(!engine.matcher().is_rule_enabled(pattern.rule_id as usize));
+        assert_eq!(check(&engine, "https://cdn.example/ads/a.js").decision, MatchDecision::Allow);
+        let unexcepted = check(&engine, "https://ads.example/ok.js");
+        assert_eq!((unexcepted.decision, unexcepted.rule_id), (MatchDecision::Block, host.rule_id));
+
+        engine.matcher_mut().set_disabled_rules(&[]);
+        assert!(engine.matcher().disabled_rules().is_empty());
+        assert_eq!(check(&engine, "https://cdn.example/ads/a.js").decision, MatchDecision::Block);
     }
 
     #[test]
-    fn specific_exception_beats_generic_block() {
-        // Exception with domain constraint beats generic block for that domain
-        let rules = parse_filter_list("||ads.com^\n@@||ads.com^$domain=safe.com");
+    fn genericblock_exception_skips_generic_block_rules() {
+        let (rules, diagnostics) = parse_filter_list_with_diagnostics(
+            "||ads.example^\n\
+             ||cdn.example/ad.js$script,redirect=noop.js\n\
+             ||tracker.example^$domain=news.example\n\
+             @@||news.example^$genericblock\n\
+             @@||blog.example/$genericblock\n\
+             @@||shop.example/cart$genericblock\n\
+             ||other.example^$genericblock",
+        );
+        assert_eq!(diagnostics.dropped_lines.len(), 1);
+        assert_eq!(diagnostics.dropped_lines[0].reason, DropReason::MisplacedCosmeticOption);
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let rules_view = snapshot.rules();
+        let generic = (0..rules_view.count)
+            .filter(|&id| RuleFlags::from_bits_truncate(rules_view.flags(id)).contains(RuleFlags::IS_GENERIC))
+            .count();
+        assert_eq!(generic, 2, "the two block rules without $domain");
+
+        let matcher = Matcher::new(&snapshot);
+        let decide = |url: &str, request_type, initiator| {
+            let request = RequestContext::builder(url, request_type)
+                .initiator(initiator)
+                .build()
+                .expect("valid request");
+            matcher.match_request(&request.context()).decision
+        };
+
+        assert_eq!(decide("https://ads.example/x.js", RequestType::SCRIPT, "https://news.example/"), MatchDecision::Allow);
+        assert_eq!(decide("https://cdn.example/ad.js", RequestType::SCRIPT, "https://www.news.example/"), MatchDecision::Allow);
+        assert_eq!(decide("https://tracker.example/t.gif", RequestType::IMAGE, "https://news.example/"), MatchDecision::Block);
+
+        assert_eq!(decide("https://ads.example/x.js", RequestType::SCRIPT, "https://other.example/"), MatchDecision::Block);
+        assert_eq!(decide("https://cdn.example/ad.js", RequestType::SCRIPT, "https://other.example/"), MatchDecision::Redirect);
+        assert_eq!(decide("https://other.example/x.js", RequestType::SCRIPT, "https://other.example/"), MatchDecision::Allow);
+
+        // Host-anchored exceptions match the page as `https://<site>/`, so
+        // only ones without a deeper path apply.
+        assert_eq!(decide("https://ads.example/x.js", RequestType::SCRIPT, "https://blog.example/"), MatchDecision::Allow);
+        assert_eq!(decide("https://ads.example/x.js", RequestType::SCRIPT, "https://shop.example/"), MatchDecision::Block);
+    }
+
+    #[test]
+    fn match_budget_caps_work_per_request() {
+        let mut list: String = (0..60).map(|i| format!("&p{}=1|\n", i)).collect();
+        list.push_str("||ads.example^");
+        let rules = parse_filter_list(&list);
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let mut matcher = Matcher::new(&snapshot);
+
+        let query: Vec<String> = (0..60).map(|i| format!("p{}=0", i)).collect();
+        let worst = format!("https://site.example/?{}", query.join("&"));
+        let check = |matcher: &Matcher<'_>, url: &str| {
+            let request = RequestContext::builder(url, RequestType::IMAGE).build().expect("valid request");
+            matcher.match_request(&request.context())
+        };
+
+        let result = check(&matcher, &worst);
+        assert_eq!(result.decision, MatchDecision::Allow);
+        assert!(!result.budget_exceeded);
+
+        // A budget of exactly the rules the worst URL has to check is enough:
+        // the `$removeparam` pass and static filtering share that work.
+        let request = RequestContext::builder(&worst, RequestType::IMAGE).build().expect("valid request");
+        let checked = matcher
+            .explain_request(&request.context())
+            .candidates
+            .iter()
+            .filter(|c| !matches!(c.source, CandidateSource::DomainSet { .. }))
+            .count() as u32;
+        assert!(checked > 8);
+        matcher.set_match_budget(MatchBudget { max_candidates: checked, max_pattern_ops: 0 });
+        assert!(!check(&matcher, &worst).budget_exceeded);
+        matcher.set_match_budget(MatchBudget { max_candidates: checked - 1, max_pattern_ops: 0 });
+        assert!(check(&matcher, &worst).budget_exceeded);
+
+        matcher.set_match_budget(MatchBudget { max_candidates: 8, max_pattern_ops: 0 });
+        assert!(check(&matcher, &worst).budget_exceeded);
+        let result = check(&matcher, "https://ads.example/x.png");
+        assert_eq!(result.decision, MatchDecision::Block);
+        assert!(!result.budget_exceeded);
+
+        matcher.set_match_budget(MatchBudget { max_candidates: 0, max_pattern_ops: 16 });
+        assert!(check(&matcher, &worst).budget_exceeded);
+
+        matcher.set_match_budget(MatchBudget::UNLIMITED);
+        assert!(!check(&matcher, &worst).budget_exceeded);
+
+        // Running out of budget fails open: the cut candidate may be the
+        // exception that covers the request.
+        // `/banner/` is a regex filter, so it is checked before the exception.
+        let rules = parse_filter_list("/banner/\n@@||cdn.example/banner/ok.png");
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let mut matcher = Matcher::new(&snapshot);
+        let url = "https://cdn.example/banner/ok.png";
+        let result = check(&matcher, url);
+        assert_eq!(result.decision, MatchDecision::Allow);
+        assert!(!result.budget_exceeded);
+        matcher.set_match_budget(MatchBudget { max_candidates: 1, max_pattern_ops: 0 });
+        let result = check(&matcher, url);
+        assert_eq!(result.decision, MatchDecision::Allow);
+        assert!(result.budget_exceeded);
+    }
+
+    #[test]
+    fn expires_option_retires_rules() {
+        let (rules, diagnostics) = parse_filter_list_with_diagnostics(
+            "||ads.example^$expires=2025-12-31\n\
+             ||track.example/pixel$image,expires=2024-02-29\n\
+             ||cdn.example^\n\
+             ||bad.example^$expires=2025-02-30\n\
+             ||bad.example^$expires=tomorrow",
+        );
+        assert_eq!(rules.len(), 3);
+        assert_eq!(diagnostics.dropped_lines.len(), 2);
+        assert!(diagnostics
+            .dropped_lines
+            .iter()
+            .all(|dropped| dropped.reason == DropReason::InvalidOption("expires".to_string())));
+        // Through the end of the day, UTC.
+        assert_eq!(rules[0].expires_at, Some(1_767_225_600));
+        assert_eq!(rules[1].expires_at, Some(1_709_251_200));
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let mut matcher = Matcher::new(&snapshot);
+        let check = |matcher: &Matcher<'_>, url: &str| {
+            let request = RequestContext::builder(url, RequestType::IMAGE).build().expect("valid request");
+            matcher.match_request(&request.context()).decision
+        };
+
+        // Without a clock nothing expires.
+        assert_eq!(check(&matcher, "https://track.example/pixel.gif"), MatchDecision::Block);
+        assert_eq!(matcher.next_rule_expiry(), Some(1_709_251_200));
+
+        matcher.set_current_time(1_767_225_599);
+        assert_eq!(check(&matcher, "https://track.example/pixel.gif"), MatchDecision::Allow);
+        assert_eq!(check(&matcher, "https://ads.example/x.png"), MatchDecision::Block);
+        assert_eq!(matcher.next_rule_expiry(), Some(1_767_225_600));
+
+        matcher.set_current_time(1_767_225_600);
+        assert_eq!(check(&matcher, "https://ads.example/x.png"), MatchDecision::Allow);
+        assert_eq!(check(&matcher, "https://cdn.example/x.png"), MatchDecision::Block);
+        assert_eq!(matcher.next_rule_expiry(), None);
+    }
+
+    #[test]
+    fn explain_request_lists_candidates_and_rejections() {
+        let rules = parse_filter_list(
+            "||ads.example^\n\
+             @@||ads.example/ok/\n\
+             ||ads.example/banner$image\n\
+             ||tracker.example^$domain=news.example\n\
+             ||ads.example^$important,third-party",
+        );
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let explain = |url: &str, request_type, initiator| {
+            let request = RequestContext::builder(url, request_type)
+                .initiator(initiator)
+                .build()
+                .expect("valid request");
+            matcher.explain_request(&request.context())
+        };
+
+        let explanation = explain("https://ads.example/ok/x.js", RequestType::SCRIPT, "https://ads.example/");
+        assert_eq!(explanation.result.decision, MatchDecision::Allow);
+        assert_eq!(explanation.reason, DecisionReason::Exception);
+        let rejection = |id: usize| explanation.candidates.iter().find(|c| c.rule_id == id).map(|c| c.rejected);
+        assert_eq!(rejection(0), Some(None));
+        assert_eq!(rejection(1), Some(None));
+        assert_eq!(rejection(4), Some(Some(Rejection::Party)));
+        let block = explanation.candidates.iter().find(|c| c.rule_id == 0).unwrap();
+        assert_eq!(block.source, CandidateSource::DomainSet { suffix: "ads.example".to_string() });
+        let exception = explanation.candidates.iter().find(|c| c.rule_id == 1).unwrap();
+        assert_eq!(exception.source, CandidateSource::HostAnchor { suffix: "ads.example".to_string() });
+
+        let explanation = explain("https://ads.example/x.js", RequestType::SCRIPT, "https://news.example/");
+        assert_eq!(explanation.reason, DecisionReason::ImportantBlock);
+        assert_eq!(explanation.result.rule_id, 4);
+
+        let explanation = explain("https://tracker.example/x.js", RequestType::SCRIPT, "https://other.example/");
+        assert_eq!(explanation.reason, DecisionReason::NoMatch);
+        assert!(explanation
+            .candidates
+            .iter()
+            .any(|c| c.rule_id == 3 && c.rejected == Some(Rejection::DomainConstraint)));
+    }
+
+    #[test]
+    fn badfilter_cancels_block_rule() {
+        // Block rule with matching badfilter should be cancelled
+        let mut rules = parse_filter_list("||ads.com^\n||ads.com^$badfilter");
+        optimize_rules(&mut rules);
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+
+        let ctx = RequestContext {
+            url: "https://ads.com/script.js",
+            req_host: "ads.com",
+            req_etld1: "ads.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: true,
+            request_type: RequestType::SCRIPT,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        let result = matcher.match_request(&ctx);
+        assert_eq!(result.decision, MatchDecision::Allow);
+    }
+
+    #[test]
+    fn rule_signatures_find_badfiltered_rules_at_runtime() {
+        let mut rules = parse_filter_list("||ads.com^\n||ads.com^$third-party\nexample.com##.ad\n||ads.com^\n||other.com^");
+        for (index, rule) in rules.iter_mut().enumerate() {
+            rule.list_id = index as u16;
+        }
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        assert!(snapshot.has_rule_signatures());
+
+        let badfilter = &parse_filter_list("||ads.com^$badfilter")[0];
+        let signature = crate::rule_signature(badfilter).expect("network filters have a signature");
+        // Both lists' copies, but not the `$third-party` variant.
+        assert_eq!(snapshot.rules_with_signature(signature), vec![0, 3]);
+        assert_eq!(crate::rule_signature(&parse_filter_list("example.com##.ad")[0]), None);
+
+        let mut engine = Engine::new(&snapshot);
+        engine.matcher_mut().set_disabled_rules(&snapshot.rules_with_signature(signature));
+        let request = RequestInfo::new("https://ads.com/x.js", RequestType::SCRIPT, Some("https://ads.com/"));
+        assert_eq!(engine.check_request(&request).decision, MatchDecision::Allow);
+        let request = RequestInfo::new("https://ads.com/x.js", RequestType::SCRIPT, Some("https://example.com/"));
+        assert_eq!(engine.check_request(&request).rule_id, 1);
+    }
+
+    #[test]
+    fn badfilter_cancels_exception_rule() {
+        // Exception rule with matching badfilter should be cancelled, allowing block
+        let mut rules = parse_filter_list("||ads.com^\n@@||ads.com^\n@@||ads.com^$badfilter");
+        optimize_rules(&mut rules);
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+
+        let ctx = RequestContext {
+            url: "https://ads.com/script.js",
+            req_host: "ads.com",
+            req_etld1: "ads.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: true,
+            request_type: RequestType::SCRIPT,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        let result = matcher.match_request(&ctx);
+        assert_eq!(result.decision, MatchDecision::Block);
+    }
+
+    #[test]
+    fn important_exception_beats_important_block() {
+        // @@$important should beat $important block
+        let rules = parse_filter_list("||ads.com^$important\n@@||ads.com^$important");
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+
+        let ctx = RequestContext {
+            url: "https://ads.com/script.js",
+            req_host: "ads.com",
+            req_etld1: "ads.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: true,
+            request_type: RequestType::SCRIPT,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        let result = matcher.match_request(&ctx);
+        assert_eq!(result.decision, MatchDecision::Allow);
+    }
+
+    #[test]
+    fn redirect_with_important_beats_exception() {
+        // $redirect,important should redirect even with exception
+        let rules = parse_filter_list("||ads.com^$redirect=noop.js,important\n@@||ads.com^");
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+
+        let ctx = RequestContext {
+            url: "https://ads.com/script.js",
+            req_host: "ads.com",
+            req_etld1: "ads.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: true,
+            request_type: RequestType::SCRIPT,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        let result = matcher.match_request(&ctx);
+        assert_eq!(result.decision, MatchDecision::Redirect);
+        assert!(result.redirect_url.is_some());
+    }
+
+    #[test]
+    fn specific_exception_beats_generic_block() {
+        // Exception with domain constraint beats generic block for that domain
+        let rules = parse_filter_list("||ads.com^\n@@||ads.com^$domain=safe.com");
         let bytes = build_snapshot(&rules);
         let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
         let matcher = Matcher::new(&snapshot);
@@ -1863,4 +3282,2379 @@ mod tests {
         assert!(result.csp_injections.contains(&"script-src 'none'".to_string()));
         assert!(result.csp_injections.contains(&"frame-src 'self'".to_string()));
     }
+
+    #[test]
+    fn embeds_psl_section() {
+        let psl_dat = "// ===BEGIN ICANN DOMAINS===\n\
+            com\n\
+            co.uk\n\
+            *.ck\n\
+            !www.ck\n\
+            // ===BEGIN PRIVATE DOMAINS===\n\
+            github.io\n";
+        let rules = parse_filter_list("||example.com^");
+
+        let plain = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&plain).expect("snapshot should load");
+        assert!(snapshot.get_section_info(SectionId::PslSets).is_none());
+
+        let bytes = build_snapshot_with_psl(&rules, psl_dat);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let info = snapshot
+            .get_section_info(SectionId::PslSets)
+            .expect("psl section should exist");
+
+        let sets = load_psl_from_bytes(&bytes, info.offset);
+        assert!(sets.is_exact("com"));
+        assert!(sets.is_exact("co.uk"));
+        assert!(sets.is_exact("github.io"));
+        assert!(sets.is_wildcard("ck"));
+        assert!(!sets.is_exact("ck"));
+        assert!(sets.is_exception("www.ck"));
+        assert_eq!(sets.exact.len(), 3);
+    }
+
+    #[test]
+    fn diff_decision_reports_both_sides() {
+        let old_rules = parse_filter_list("||ads.example.com^");
+        let new_rules = parse_filter_list("||ads.example.com^\n@@||ads.example.com/ok.js");
+        let old_bytes = build_snapshot(&old_rules);
+        let new_bytes = build_snapshot(&new_rules);
+        let old_snapshot = Snapshot::load(&old_bytes).expect("snapshot should load");
+        let new_snapshot = Snapshot::load(&new_bytes).expect("snapshot should load");
+        let old_matcher = Matcher::new(&old_snapshot);
+        let new_matcher = Matcher::new(&new_snapshot);
+
+        let mut ctx = RequestContext {
+            url: "https://ads.example.com/ok.js",
+            req_host: "ads.example.com",
+            req_etld1: "example.com",
+            site_host: "site.com",
+            site_etld1: "site.com",
+            is_third_party: true,
+            request_type: RequestType::SCRIPT,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        let delta = new_matcher.diff_decision(&old_matcher, &ctx);
+        assert!(delta.changed());
+        assert_eq!(delta.previous.decision, MatchDecision::Block);
+        assert_eq!(delta.current.decision, MatchDecision::Allow);
+        assert!(delta.current.rule_id >= 0);
+
+        ctx.url = "https://ads.example.com/ad.js";
+        let delta = new_matcher.diff_decision(&old_matcher, &ctx);
+        assert!(!delta.changed());
+        assert_eq!(delta.current.decision, MatchDecision::Block);
+    }
+
+    #[test]
+    fn replace_rules_and_exceptions() {
+        let rules = parse_filter_list(
+            "||example.com^$replace=/ads\\/banner/nothing\\, really/gi\n\
+             ||example.com^$replace=/\"ad\":true/\"ad\":false/\n\
+             @@||example.com^$replace=/\"ad\":true/\"ad\":false/\n\
+             @@||example.org^$replace\n\
+             ||example.org^$replace=/foo/bar/",
+        );
+        assert_eq!(rules.len(), 5);
+        let spec = rules[0].replace.as_ref().expect("replace spec");
+        assert_eq!(spec.pattern, "ads\\/banner");
+        assert_eq!(spec.replacement, "nothing, really");
+        assert_eq!(spec.flags, "gi");
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+
+        let mut ctx = RequestContext {
+            url: "https://example.com/api/feed",
+            req_host: "example.com",
+            req_etld1: "example.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: false,
+            request_type: RequestType::XMLHTTPREQUEST,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        let result = matcher.match_replace(&ctx);
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].pattern, "ads\\/banner");
+        assert_eq!(result[0].flags, "gi");
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Allow);
+
+        ctx.url = "https://example.org/page";
+        ctx.req_host = "example.org";
+        ctx.req_etld1 = "example.org";
+        assert!(matcher.match_replace(&ctx).is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_replace() {
+        assert!(parse_filter_list("||example.com^$replace=foo/bar/").is_empty());
+        assert!(parse_filter_list("||example.com^$replace=/foo/bar").is_empty());
+        assert!(parse_filter_list("||example.com^$replace=/foo/bar/x").is_empty());
+    }
+
+    #[test]
+    fn match_case_rules_are_case_sensitive() {
+        let rules = parse_filter_list("/Banner/Ad.js$match-case\n/promo/Track.js");
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+
+        let mut ctx = RequestContext {
+            url: "https://example.com/Banner/Ad.js",
+            req_host: "example.com",
+            req_etld1: "example.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: false,
+            request_type: RequestType::SCRIPT,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Block);
+
+        ctx.url = "https://example.com/banner/ad.js";
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Allow);
+
+        // Rules without $match-case stay case-insensitive.
+        ctx.url = "https://example.com/PROMO/track.js";
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Block);
+    }
+
+    #[test]
+    fn fetch_destination_types_are_matchable() {
+        let rules = parse_filter_list(
+            "||example.com^$manifest\n||example.org^$~script\n||example.net^$audioworklet,xslt",
+        );
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+
+        let mut ctx = RequestContext {
+            url: "https://example.com/app.webmanifest",
+            req_host: "example.com",
+            req_etld1: "example.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: false,
+            request_type: RequestType::from_str("manifest"),
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Block);
+
+        ctx.request_type = RequestType::SCRIPT;
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Allow);
+
+        // Negated masks must still cover the new destinations.
+        ctx.url = "https://example.org/worklet.js";
+        ctx.req_host = "example.org";
+        ctx.req_etld1 = "example.org";
+        ctx.request_type = RequestType::PAINTWORKLET;
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Block);
+
+        ctx.url = "https://example.net/style.xsl";
+        ctx.req_host = "example.net";
+        ctx.req_etld1 = "example.net";
+        ctx.request_type = RequestType::from_str("xslt");
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Block);
+        ctx.request_type = RequestType::OTHER;
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Allow);
+    }
+
+    #[test]
+    fn mmap_snapshot_matches_like_in_memory() {
+        let rules = parse_filter_list("||ads.example.com^");
+        let bytes = build_snapshot(&rules);
+        let path = std::env::temp_dir().join(format!("bb-mmap-test-{}.ubx", std::process::id()));
+        std::fs::write(&path, &bytes).expect("write snapshot");
+
+        let mapped = Snapshot::load_mmap(&path).expect("snapshot should map");
+        assert_eq!(mapped.bytes(), &bytes[..]);
+        let snapshot = mapped.snapshot().expect("mapped view should parse");
+        let matcher = Matcher::new(&snapshot);
+
+        let ctx = RequestContext {
+            url: "https://ads.example.com/pixel.gif",
+            req_host: "ads.example.com",
+            req_etld1: "example.com",
+            site_host: "news.test",
+            site_etld1: "news.test",
+            is_third_party: true,
+            request_type: RequestType::IMAGE,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Block);
+
+        drop(matcher);
+        drop(snapshot);
+        drop(mapped);
+        let _ = std::fs::remove_file(&path);
+
+        let mut corrupt = bytes.clone();
+        corrupt[0] = b'X';
+        std::fs::write(&path, &corrupt).expect("write snapshot");
+        assert!(Snapshot::load_mmap(&path).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn embeds_list_metadata() {
+        let text = "[Adblock Plus 2.0]\n! Title: Example List\n! Version: 202601010000\n\
+                    ! Homepage: https://example.com/list\n! Expires: 4 days (update frequency)\n\
+                    ||ads.example.com^\n! Title: Not A Header";
+        let mut meta = parse_list_metadata(text);
+        assert_eq!(meta.title.as_deref(), Some("Example List"));
+        assert_eq!(meta.expires_secs, Some(4 * 86_400));
+        meta.list_id = 1;
+
+        let hosts = parse_list_metadata("# Title: Hosts\n# Expires: 12 hours\n0.0.0.0 ads.test");
+        assert_eq!(hosts.title.as_deref(), Some("Hosts"));
+        assert_eq!(hosts.expires_secs, Some(12 * 3_600));
+        assert_eq!(parse_list_metadata("! Expires: soon").expires_secs, None);
+
+        let rules = parse_filter_list(text);
+        let options = SnapshotOptions {
+            list_metadata: vec![meta],
+            ..SnapshotOptions::default()
+        };
+        let bytes = build_snapshot_with_options(&rules, &options);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+
+        let loaded = snapshot.list_metadata(1).expect("list 1 metadata");
+        assert_eq!(loaded.title, Some("Example List"));
+        assert_eq!(loaded.version, Some("202601010000"));
+        assert_eq!(loaded.homepage, Some("https://example.com/list"));
+        assert_eq!(loaded.expires_secs, Some(4 * 86_400));
+        assert!(snapshot.list_metadata(0).is_none());
+
+        let plain = build_snapshot(&rules);
+        let plain = Snapshot::load(&plain).expect("snapshot should load");
+        assert!(plain.get_section(SectionId::ListMeta).is_none());
+    }
+
+    #[test]
+    fn higher_list_tiers_decide_first() {
+        use bb_core::types::ListTier;
+
+        let lists = [
+            "||ads.example^$important\n@@||cdn.example^\n||track.example^",
+            "@@||ads.example/ok/*",
+            "||cdn.example/ads/*",
+        ];
+        let mut rules = Vec::new();
+        for (list_id, text) in lists.iter().enumerate() {
+            let mut list = parse_filter_list(text);
+            list.iter_mut().for_each(|rule| rule.list_id = list_id as u16);
+            rules.extend(list);
+        }
+        let tiered = |tiers: [ListTier; 3]| {
+            let list_metadata = tiers
+                .iter()
+                .enumerate()
+                .map(|(list_id, &tier)| ListMetadata {
+                    list_id: list_id as u16,
+                    tier,
+                    ..ListMetadata::default()
+                })
+                .collect();
+            build_snapshot_with_options(&rules, &SnapshotOptions { list_metadata, ..SnapshotOptions::default() })
+        };
+
+        let bytes = tiered([ListTier::BuiltIn, ListTier::User, ListTier::Custom]);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        assert_eq!(snapshot.list_metadata(1).expect("list 1 metadata").tier, ListTier::User);
+        let engine = Engine::new(&snapshot);
+        assert_eq!(engine.matcher().list_tier(2), ListTier::Custom);
+        assert_eq!(engine.matcher().list_tier(9), ListTier::BuiltIn);
+        let check = |url: &str| engine.check(url, RequestType::SCRIPT, Some("https://site.example/"));
+
+        // A user exception beats a built-in $important block.
+        let ok = check("https://ads.example/ok/x.js");
+        assert_eq!((ok.decision, ok.list_id), (MatchDecision::Allow, 1));
+        let request = RequestInfo::new("https://ads.example/ok/x.js", RequestType::SCRIPT, Some("https://site.example/"));
+        assert_eq!(engine.explain_request(&request).reason, DecisionReason::ExceptionWithoutBlock);
+        assert_eq!(check("https://ads.example/x.js").decision, MatchDecision::Block);
+        // A custom list's block beats a built-in exception.
+        let ads = check("https://cdn.example/ads/x.js");
+        assert_eq!((ads.decision, ads.list_id), (MatchDecision::Block, 2));
+        assert_eq!(check("https://cdn.example/lib.js").decision, MatchDecision::Allow);
+        assert_eq!(check("https://track.example/x.js").decision, MatchDecision::Block);
+
+        // With equal tiers the usual precedence applies.
+        let bytes = tiered([ListTier::BuiltIn; 3]);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let engine = Engine::new(&snapshot);
+        let check = |url: &str| engine.check(url, RequestType::SCRIPT, Some("https://site.example/")).decision;
+        assert_eq!(check("https://ads.example/ok/x.js"), MatchDecision::Block);
+        assert_eq!(check("https://cdn.example/ads/x.js"), MatchDecision::Allow);
+    }
+
+    #[test]
+    fn embeds_build_info() {
+        let text = "! Title: Example List\n||ads.example^";
+        let rules = parse_filter_list(text);
+        let mut info = BuildInfo::new(1_767_225_600);
+        info.add_list(0, "https://example.com/list.txt", text);
+        let options = SnapshotOptions {
+            build_info: Some(info),
+            ..SnapshotOptions::default()
+        };
+        let bytes = build_snapshot_with_options(&rules, &options);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+
+        let loaded = snapshot.build_info().expect("build info");
+        assert_eq!(loaded.compiled_at, 1_767_225_600);
+        assert_eq!(loaded.compiler_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(loaded.lists.len(), 1);
+        assert_eq!(loaded.lists[0].list_id, 0);
+        assert_eq!(loaded.lists[0].source, "https://example.com/list.txt");
+        assert_eq!(loaded.lists[0].content_hash, hash64(text.as_bytes()));
+
+        let plain = build_snapshot(&rules);
+        let plain = Snapshot::load(&plain).expect("snapshot should load");
+        assert!(plain.build_info().is_none());
+    }
+
+    #[test]
+    fn scriptlet_aliases_are_normalized() {
+        let mut rules = parse_filter_list(
+            "example.com##+js(acis.js, setTimeout, ads)\n\
+             example.com##+js(aopr, adblock)\n\
+             example.com#@#+js(abort-on-property-read, adblock)\n\
+             example.com##+js(set, onlyOneArg)\n\
+             example.com##+js(made-up-scriptlet, x)",
+        );
+        let stats = optimize_rules(&mut rules);
+        assert_eq!(stats.invalid_scriptlets, 2);
+        assert_eq!(stats.warnings.len(), 2);
+        assert!(stats.warnings[0].message.contains("set-constant"));
+        assert!(stats.warnings[1].message.contains("made-up-scriptlet"));
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let ctx = RequestContext {
+            url: "https://example.com/",
+            req_host: "example.com",
+            req_etld1: "example.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: false,
+            request_type: RequestType::DOCUMENT,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        // The aopr alias is cancelled by the canonical-name exception.
+        let result = matcher.match_cosmetics(&ctx);
+        assert_eq!(result.scriptlets.len(), 1);
+        assert_eq!(result.scriptlets[0].name, "abort-current-script");
+        assert_eq!(result.scriptlets[0].args, vec!["setTimeout", "ads"]);
+    }
+
+    #[test]
+    fn scriptlet_sources_render_with_escaped_args() {
+        use crate::scriptlets::{render_scriptlet, ScriptletIssue, SCRIPTLET_REGISTRY};
+
+        for sig in SCRIPTLET_REGISTRY {
+            let args = vec!["x"; sig.min_args];
+            let source = render_scriptlet(sig.name, &args).expect("registered scriptlet should render");
+            assert!(!source.contains("{{"), "{} left a placeholder", sig.name);
+        }
+
+        let source = render_scriptlet("set", &["ads.enabled", "x';alert(1)</script>\\"]).unwrap();
+        assert!(source.contains("parseValue('x\\';alert(1)\\x3c/script\\x3e\\\\')"));
+        assert!(source.contains("onPropertyChain('ads.enabled',"));
+
+        // Missing optional arguments become empty strings.
+        assert!(render_scriptlet("remove-attr", &["onclick"]).unwrap().contains("const selector = '' ||"));
+
+        assert_eq!(
+            render_scriptlet("made-up", &[]),
+            Err(ScriptletIssue::UnknownName("made-up".to_string()))
+        );
+        assert!(matches!(render_scriptlet("aopr", &[]), Err(ScriptletIssue::ArgCount { .. })));
+    }
+
+    #[test]
+    fn picker_rules_are_generalized_and_previewed() {
+        use crate::picker::{generate_cosmetic_rule, PickerIssue, PickerOptions, PickerRuleKind};
+
+        let exact = PickerOptions::default();
+        let rule = generate_cosmetic_rule("WWW.Example.com", "div#main >  .ad:nth-child(3)", exact).unwrap();
+        assert_eq!(rule.filter, "www.example.com##div#main > .ad:nth-child(3)");
+        assert_eq!(rule.kind, PickerRuleKind::Hide);
+
+        let loose = PickerOptions { strip_nth: true, strip_ids: true };
+        let rule = generate_cosmetic_rule("example.com", "#page > div#main .ad:nth-of-type(2n+1)[href='#x']", loose)
+            .unwrap();
+        assert_eq!(rule.selector, "* > div .ad[href=\"#x\"]");
+        assert_eq!(rule.compiled_selector, rule.selector);
+
+        let rule = generate_cosmetic_rule("example.com", "div.promo:has-text(Sponsored)", loose).unwrap();
+        assert_eq!(rule.kind, PickerRuleKind::Procedural);
+
+        let snapshot_rules = parse_filter_list(&rule.filter);
+        assert_eq!(snapshot_rules.len(), 1);
+
+        assert_eq!(generate_cosmetic_rule("example.com", "#only", loose), Err(PickerIssue::TooBroad));
+        assert_eq!(
+            generate_cosmetic_rule("example.com", "div > ", exact),
+            Err(PickerIssue::InvalidSelector("dangling combinator"))
+        );
+        assert!(matches!(
+            generate_cosmetic_rule("bad host", ".ad", exact),
+            Err(PickerIssue::InvalidHostname(_))
+        ));
+    }
+
+    #[test]
+    fn logger_network_rules_cover_url_to_domain() {
+        use crate::picker::{generate_network_rule, NetworkRuleChoices, NetworkRuleScope};
+
+        let url = "https://cdn.tracker.example:8443/js/v1/tag.js?id=1$x|y";
+        let candidates = generate_network_rule(url, RequestType::SCRIPT, None, NetworkRuleChoices::default()).unwrap();
+        let filters: Vec<(NetworkRuleScope, &str)> =
+            candidates.iter().map(|c| (c.scope, c.filter.as_str())).collect();
+        assert_eq!(
+            filters,
+            vec![
+                (NetworkRuleScope::Url, "|https://cdn.tracker.example:8443/js/v1/tag.js?id=1*x*y|"),
+                (NetworkRuleScope::PathPrefix, "|https://cdn.tracker.example:8443/js/v1/"),
+                (NetworkRuleScope::Hostname, "||cdn.tracker.example^"),
+                (NetworkRuleScope::Domain, "||tracker.example^"),
+            ]
+        );
+
+        let choices = NetworkRuleChoices { allow: true, third_party: true, match_type: true, site_only: true };
+        let candidates =
+            generate_network_rule("https://tracker.example/", RequestType::IMAGE, Some("news.example"), choices).unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].filter, "@@||tracker.example^$image,third-party,domain=news.example");
+
+        // Every candidate compiles to a rule that matches the request.
+        let plain = "https://cdn.tracker.example/js/v1/tag.js?id=1$x";
+        for url in [url, plain] {
+            let candidates = generate_network_rule(url, RequestType::SCRIPT, None, NetworkRuleChoices::default()).unwrap();
+            assert_eq!(candidates.len(), 4);
+            for candidate in &candidates {
+                let bytes = build_snapshot(&parse_filter_list(&candidate.filter));
+                let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+                let result = Engine::new(&snapshot).check(url, RequestType::SCRIPT, Some("https://site.example/"));
+                assert_eq!(result.decision, MatchDecision::Block, "{}", candidate.filter);
+            }
+        }
+
+        assert!(generate_network_rule("not a url", RequestType::OTHER, None, NetworkRuleChoices::default()).is_err());
+    }
+
+    #[test]
+    fn scriptlet_name_only_exceptions_ignore_args() {
+        let mut rules = parse_filter_list(
+            "example.com##+js(set-constant, foo, bar)\n\
+             example.com##+js(set, baz, true)\n\
+             example.com##+js(aopr, adblock)\n\
+             example.com#@#+js(set)",
+        );
+        optimize_rules(&mut rules);
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let ctx = RequestContext {
+            url: "https://example.com/",
+            req_host: "example.com",
+            req_etld1: "example.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: false,
+            request_type: RequestType::DOCUMENT,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        let result = matcher.match_cosmetics(&ctx);
+        assert_eq!(result.scriptlets.len(), 1);
+        assert_eq!(result.scriptlets[0].name, "abort-on-property-read");
+
+        // A domainless name-only exception applies everywhere.
+        let rules = parse_filter_list(
+            "example.com##+js(abort-on-property-read, adblock)\n#@#+js(abort-on-property-read)",
+        );
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        assert!(matcher.match_cosmetics(&ctx).scriptlets.is_empty());
+    }
+
+    #[test]
+    fn optimizer_drops_rules_shadowed_by_host_blocks() {
+        let list = "||ads.com^\n\
+                    ||ads.com/banner.js\n\
+                    ||cdn.ads.com/img/pixel.gif$image\n\
+                    ||ads.community/x.js\n\
+                    ||ads.com*foo\n\
+                    ||ads.com/top.js$important\n\
+                    ||ads.com/stub.js$redirect=noop.js\n\
+                    ||tracker.net^$script\n\
+                    ||tracker.net/beacon.gif$image";
+        let mut rules = parse_filter_list(list);
+        let mut other = parse_filter_list("||ads.com/other-list.js");
+        other[0].list_id = 1;
+        rules.extend(other);
+
+        let mut kept = rules.clone();
+        let stats = optimize_rules_with_options(&mut kept, &OptimizeOptions { eliminate_shadowed: false, ..OptimizeOptions::default() });
+        assert_eq!(stats.shadowed, 0);
+
+        let stats = optimize_rules(&mut rules);
+        assert_eq!(stats.shadowed, 2);
+        assert_eq!(stats.after, kept.len() - 2);
+        let patterns: Vec<&str> = rules.iter().filter_map(|rule| rule.pattern.as_deref()).collect();
+        assert!(!patterns.contains(&"ads.com/banner.js"));
+        assert!(!patterns.contains(&"cdn.ads.com/img/pixel.gif"));
+        for survivor in ["ads.community/x.js", "ads.com*foo", "ads.com/top.js", "tracker.net/beacon.gif", "ads.com/other-list.js"] {
+            assert!(patterns.contains(&survivor), "{survivor} should be kept");
+        }
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let ctx = RequestContext {
+            url: "https://cdn.ads.com/img/pixel.gif",
+            req_host: "cdn.ads.com",
+            req_etld1: "ads.com",
+            site_host: "news.test",
+            site_etld1: "news.test",
+            is_third_party: true,
+            request_type: RequestType::IMAGE,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Block);
+    }
+
+    #[test]
+    fn more_specific_rules_win_ties() {
+        let mut rules = parse_filter_list("||example.com^\n||example.com^$redirect-rule=noop.js");
+        let mut specific = parse_filter_list(
+            "/ads/banner.js$script,domain=news.test\n/ads/banner.js$script,redirect-rule=1x1.gif",
+        );
+        for rule in &mut specific {
+            rule.list_id = 1;
+        }
+        rules.extend(specific);
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+
+        let ctx = RequestContext {
+            url: "https://example.com/ads/banner.js",
+            req_host: "example.com",
+            req_etld1: "example.com",
+            site_host: "news.test",
+            site_etld1: "news.test",
+            is_third_party: true,
+            request_type: RequestType::SCRIPT,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        let result = matcher.match_request(&ctx);
+        assert_eq!(result.decision, MatchDecision::Redirect);
+        assert_eq!(result.list_id, 1);
+        assert_eq!(result.redirect_url.as_deref(), Some("/redirects/1x1.gif"));
+    }
+
+    #[test]
+    fn reports_dropped_lines_and_duplicate_origins() {
+        let (first, first_diag) = parse_filter_list_with_diagnostics(
+            "! Title: first\n||ads.example^\n||tracker.example^$ping2\n/banner/$domain=\n",
+        );
+        let (mut second, second_diag) =
+            parse_filter_list_with_diagnostics("||other.example^\n\n||ads.example^\n||x.example^$frobnicate\n||other.example^\n");
+        for rule in &mut second {
+            rule.list_id = 1;
+        }
+
+        let mut diagnostics = CompileDiagnostics::default();
+        diagnostics.merge(0, first_diag);
+        diagnostics.merge(1, second_diag);
+
+        assert_eq!(diagnostics.dropped_lines.len(), 3);
+        let ping = &diagnostics.dropped_lines[0];
+        assert_eq!((ping.list_id, ping.line), (0, 3));
+        assert_eq!(ping.reason, DropReason::UnsupportedOption("ping2".to_string()));
+        assert_eq!(
+            diagnostics.dropped_lines[1].reason,
+            DropReason::InvalidOption("domain".to_string())
+        );
+        let frob = &diagnostics.dropped_lines[2];
+        assert_eq!((frob.list_id, frob.line), (1, 4));
+        assert_eq!(diagnostics.unsupported_options.get("frobnicate"), Some(&1));
+
+        let mut rules = first;
+        rules.extend(second);
+        let stats = optimize_rules(&mut rules);
+        diagnostics.record_optimize(&stats);
+
+        // Dedupe is per list, so `||ads.example^` in both lists survives twice.
+        assert_eq!(diagnostics.duplicates.len(), 1);
+        let dup = diagnostics.duplicates[0];
+        assert_eq!((dup.list_id, dup.line), (1, 5));
+        assert_eq!((dup.original_list_id, dup.original_line), (1, 1));
+
+        let bytes = build_snapshot(&rules);
+        Snapshot::load(&bytes).expect("snapshot should load");
+    }
+
+    #[test]
+    fn legacy_options_map_onto_modern_types() {
+        let (rules, diagnostics) = parse_filter_list_with_diagnostics(
+            "||ads.example/player.swf$object-subrequest\n\
+             ||rtc.example^$webrtc,third-party\n\
+             ||ads.example/bg.png$background,collapse\n\
+             ||ads.example^$~object-subrequest\n\
+             ||cdn.example^$collapse\n\
+             example.com##div[data-x=\"$webrtc\"]\n",
+        );
+        assert!(diagnostics.dropped_lines.is_empty());
+        assert_eq!(rules.len(), 6);
+        assert_eq!(rules[0].type_mask, RequestType::OBJECT);
+        assert_eq!(rules[1].type_mask, RequestType::OTHER);
+        assert_eq!(rules[2].type_mask, RequestType::IMAGE);
+        assert_eq!(rules[3].type_mask, RequestType::ALL & !RequestType::OBJECT);
+        assert!(rules[4].type_mask.is_empty());
+        let legacy: Vec<_> = diagnostics.legacy_options.iter().map(|(name, &count)| (name.as_str(), count)).collect();
+        assert_eq!(legacy, [("background", 1), ("collapse", 2), ("object-subrequest", 2), ("webrtc", 1)]);
+    }
+
+    #[test]
+    fn diagnostics_become_structured_errors() {
+        let (_, list_diagnostics) = parse_filter_list_with_diagnostics(
+            "||a.example^$ping2\n||b.example^$domain=bad_host\n||c.example^$domain=\n\
+             ||d.example^$dnsrewrite=1.2.3.4\n||e.example^$image,~image\n",
+        );
+        let mut diagnostics = CompileDiagnostics::default();
+        diagnostics.merge(3, list_diagnostics);
+
+        let errors = diagnostics.errors();
+        let codes: Vec<&str> = errors.iter().map(Error::code).collect();
+        assert_eq!(codes, ["unsupported-option", "invalid-domain", "invalid-option", "empty-mask"]);
+        assert!(matches!(
+            &errors[0],
+            Error::UnsupportedOption { list_id: 3, line: 1, option } if option == "ping2"
+        ));
+        assert!(matches!(&errors[1], Error::InvalidDomain { domain, .. } if domain == "bad_host"));
+        assert_eq!(errors[1].to_string(), "invalid domain 'bad_host'");
+        assert_eq!(errors[3].location(), Some((3, 5)));
+
+        let too_large = Error::check_snapshot_size(MAX_SNAPSHOT_BYTES + 1).unwrap_err();
+        assert_eq!(too_large.code(), "snapshot-too-large");
+        assert_eq!(too_large.location(), None);
+        assert!(Error::check_snapshot_size(1024).is_ok());
+    }
+
+    #[test]
+    fn simple_style_rules_become_static_css() {
+        let rules = parse_filter_list(
+            "example.com##.banner:style(position: absolute !important)\n\
+             example.com##.promo:style(position: absolute !important)\n\
+             example.com##.sticky:style(top: 0 !important)\n\
+             example.com#@#.sticky:style(top: 0 !important)\n\
+             ##.nag:style(opacity: 0)\n\
+             example.com##.bg:style(background: url(https://x.example/a.png))\n\
+             example.com##div:has-text(Ad):style(color: red)",
+        );
+        assert_eq!(rules.iter().filter(|rule| rule.cosmetic_style.is_some()).count(), 5);
+        assert_eq!(rules.iter().filter(|rule| rule.procedural.is_some()).count(), 2);
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let ctx = RequestContext {
+            url: "https://example.com/",
+            req_host: "example.com",
+            req_etld1: "example.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: false,
+            request_type: RequestType::DOCUMENT,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        let result = matcher.match_cosmetics(&ctx);
+        assert!(result.css.is_empty());
+        // One block per declaration list, ordered by declaration.
+        assert_eq!(
+            result.style_css,
+            ".nag{opacity: 0}\n.banner,\n.promo{position: absolute !important}"
+        );
+        assert_eq!(result.procedural.len(), 2);
+
+        let other = RequestContext {
+            url: "https://other.example/",
+            req_host: "other.example",
+            req_etld1: "other.example",
+            site_host: "other.example",
+            site_etld1: "other.example",
+            ..ctx
+        };
+        assert_eq!(matcher.match_cosmetics(&other).style_css, ".nag{opacity: 0}");
+    }
+
+    #[test]
+    fn adguard_css_injection_becomes_style_css() {
+        let (rules, diagnostics) = parse_filter_list_with_diagnostics(
+            "example.com#$#body { overflow: auto !important; }\n\
+             example.com##.banner:style(overflow: auto !important)\n\
+             #$#.nag { opacity: 0 }\n\
+             example.com#@$#.nag { opacity: 0; }\n\
+             example.com##.ad\n\
+             example.com#$#.promo { remove: true; }\n\
+             example.com#$#.bg { background: url(https://x.example/a.png) }\n\
+             example.com#$?#div:has-text(Ad) { display: none }",
+        );
+        assert_eq!(rules.iter().filter(|rule| rule.cosmetic_style.is_some()).count(), 4);
+        let dropped: Vec<u32> = diagnostics.dropped_lines.iter().map(|line| line.line).collect();
+        assert_eq!(dropped, vec![6, 7, 8]);
+        assert!(diagnostics.dropped_lines.iter().all(|line| line.reason == DropReason::UnsupportedCosmetic));
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let ctx = RequestContext {
+            url: "https://example.com/",
+            req_host: "example.com",
+            req_etld1: "example.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: false,
+            request_type: RequestType::DOCUMENT,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        // Declarations stay out of the display:none chunks, and the
+        // `#@$#` exception cancels the generic injection.
+        let result = matcher.match_cosmetics(&ctx);
+        assert_eq!(result.css.len(), 1);
+        assert!(!result.css[0].contains("body"));
+        assert_eq!(result.style_css, "body,\n.banner{overflow: auto !important}");
+
+        let other = RequestContext {
+            url: "https://other.example/",
+            req_host: "other.example",
+            req_etld1: "other.example",
+            site_host: "other.example",
+            site_etld1: "other.example",
+            ..ctx
+        };
+        assert_eq!(matcher.match_cosmetics(&other).style_css, ".nag{opacity: 0}");
+    }
+
+    #[test]
+    fn corrupt_snapshots_are_rejected_without_panicking() {
+        let rules = parse_filter_list("||ads.example^\n/adserve/*/banner.\nexample.com##.ad\n");
+        let bytes = build_snapshot(&rules);
+        let rules_offset = Snapshot::load(&bytes)
+            .expect("snapshot should load")
+            .get_section_info(SectionId::Rules)
+            .expect("rules section")
+            .offset;
+
+        // A rule count whose SoA arrays run past the section.
+        let mut corrupt = bytes.clone();
+        corrupt[rules_offset..rules_offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(Snapshot::load(&corrupt), Err(SnapshotError::InvalidSection(_))));
+
+        for len in (0..bytes.len()).step_by(7) {
+            if let Ok(snapshot) = Snapshot::load_without_psl(&bytes[..len]) {
+                let engine = Engine::new(&snapshot);
+                engine.check("https://ads.example/adserve/x/banner.gif", RequestType::IMAGE, None);
+                engine.cosmetics_for("https://example.com/");
+            }
+        }
+
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        assert_eq!(snapshot.get_string(usize::MAX, 2), None);
+        // Ten continuation bytes: stops after the five a u32 can use.
+        assert_eq!(decode_varint(&[0xff; 10], 0).1, 5);
+    }
+
+    #[test]
+    fn custom_sections_round_trip_next_to_the_rules() {
+        let rules = parse_filter_list("||ads.example^\n");
+        let weights = (0..=255u8).collect::<Vec<_>>();
+        let bytes = build_snapshot_with_extra(&rules, &[(0x8001, weights.clone()), (0xFFFF, Vec::new())])
+            .expect("custom ids are valid");
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        assert_eq!(snapshot.get_custom_section(0x8001), Some(&weights[..]));
+        assert_eq!(snapshot.get_custom_section(0xFFFF), Some(&[][..]));
+        assert_eq!(snapshot.get_custom_section(0x8002), None);
+        assert_eq!(snapshot.custom_section_ids(), vec![0x8001, 0xFFFF]);
+        assert_eq!(snapshot.section_count(), Snapshot::load(&build_snapshot(&rules)).unwrap().section_count());
+        let engine = Engine::new(&snapshot);
+        assert_eq!(engine.check("https://ads.example/x", RequestType::SCRIPT, None).decision, MatchDecision::Block);
+
+        let v1 = try_build_snapshot_with_options(
+            &rules,
+            &SnapshotOptions {
+                layout: SnapshotLayout::V1,
+                custom_sections: vec![(0x9000, b"model".to_vec())],
+                ..SnapshotOptions::default()
+            },
+        )
+        .expect("custom ids are valid");
+        assert_eq!(Snapshot::load(&v1).unwrap().get_custom_section(0x9000), Some(&b"model"[..]));
+
+        let error = build_snapshot_with_extra(&rules, &[(SectionId::Rules as u16, Vec::new())]).unwrap_err();
+        assert_eq!(error.code(), "custom-section");
+        assert_eq!(error.to_string(), "custom section 0x0007 is outside the custom range 0x8000..=0xFFFF");
+        let error = build_snapshot_with_extra(&rules, &[(0x8001, Vec::new()), (0x8001, Vec::new())]).unwrap_err();
+        assert_eq!(error.to_string(), "custom section 0x8001 is given twice");
+    }
+
+    /// The panicking builder runs the same checks rather than writing a
+    /// section that would shadow a core one.
+    #[test]
+    #[should_panic(expected = "custom section 0x0007 is outside the custom range")]
+    fn build_snapshot_with_options_rejects_core_section_ids() {
+        let options = SnapshotOptions {
+            custom_sections: vec![(SectionId::Rules as u16, Vec::new())],
+            ..SnapshotOptions::default()
+        };
+        build_snapshot_with_options(&parse_filter_list("||ads.example^\n"), &options);
+    }
+
+    #[test]
+    fn v1_and_v2_layouts_load_the_same_rules() {
+        let rules = parse_filter_list("||ads.example^\n/adserve/*/banner.\nexample.com##.ad\n");
+        let v2 = build_snapshot(&rules);
+        let v1 = build_snapshot_with_options(
+            &rules,
+            &SnapshotOptions {
+                layout: SnapshotLayout::V1,
+                ..SnapshotOptions::default()
+            },
+        );
+        assert_eq!(&v2[..4], b"UBX2");
+        assert_eq!(&v1[..4], b"UBX1");
+
+        let current = Snapshot::load(&v2).expect("v2 snapshot should load");
+        let legacy = Snapshot::load(&v1).expect("v1 snapshot should load");
+        assert_eq!(current.layout, SnapshotLayout::V2);
+        assert_eq!(legacy.layout, SnapshotLayout::V1);
+        assert_eq!(current.section_count(), legacy.section_count());
+        let rules_info = current.get_section_info(SectionId::Rules).expect("rules section");
+        assert_eq!(rules_info.alignment, 8);
+        assert_eq!(rules_info.offset % 8, 0);
+        assert_eq!(current.get_section(SectionId::Rules), legacy.get_section(SectionId::Rules));
+
+        for snapshot in [&current, &legacy] {
+            let engine = Engine::new(snapshot);
+            let result = engine.check("https://ads.example/x.gif", RequestType::IMAGE, None);
+            assert_eq!(result.decision, MatchDecision::Block);
+        }
+
+        // An offset that breaks the recorded alignment is rejected.
+        let mut misaligned = v2.clone();
+        let entry = HEADER_SIZE + section_entry::OFFSET;
+        let offset = u64::from_le_bytes(misaligned[entry..entry + 8].try_into().unwrap());
+        misaligned[entry..entry + 8].copy_from_slice(&(offset + 4).to_le_bytes());
+        assert!(matches!(Snapshot::load(&misaligned), Err(SnapshotError::InvalidSection(_))));
+
+        let mut unknown = v2;
+        unknown[3] = b'9';
+        assert!(matches!(Snapshot::load(&unknown), Err(SnapshotError::InvalidMagic)));
+    }
+
+    #[test]
+    fn token_bloom_skips_unknown_tokens() {
+        let list: String = (0..500).map(|i| format!("/adserve{}/banner.\n", i)).collect();
+        let rules = parse_filter_list(&list);
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let bloom = snapshot.token_bloom();
+        assert!(!bloom.is_empty());
+
+        for i in 0..500 {
+            assert!(bloom.may_contain(hash_token(&format!("adserve{}", i))));
+        }
+        // Every rule is posted under its rarest token only.
+        assert!(snapshot.token_dict().lookup(hash_token("banner")).is_none());
+
+        let false_positives = (0..10_000)
+            .filter(|i| bloom.may_contain(hash_token(&format!("unrelated{}", i))))
+            .count();
+        assert!(false_positives < 500, "too many false positives: {}", false_positives);
+
+        let matcher = Matcher::new(&snapshot);
+        let ctx = RequestContext {
+            url: "https://cdn.example/adserve42/banner.gif",
+            req_host: "cdn.example",
+            req_etld1: "cdn.example",
+            site_host: "site.example",
+            site_etld1: "site.example",
+            is_third_party: true,
+            request_type: RequestType::IMAGE,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Block);
+    }
+
+    #[test]
+    fn host_anchored_rules_are_found_by_host_suffix() {
+        let rules = parse_filter_list(
+            "||adserver.example/banners/\n\
+             ||cdn.example/*/track.js$script\n\
+             @@||adserver.example/banners/ok.gif$image\n\
+             /trackpixel.\n",
+        );
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let index = snapshot.host_anchored_rules();
+        assert_eq!(index.entry_count(), 2);
+        assert!(index.lookup(hash_domain("adserver.example")).is_some());
+        // Only the unanchored rule goes through URL tokens.
+        assert!(snapshot.token_dict().lookup(hash_token("banners")).is_none());
+        assert!(snapshot.token_dict().lookup(hash_token("trackpixel")).is_some());
+
+        let matcher = Matcher::new(&snapshot);
+        let decide = |url: &str, request_type| {
+            let request = RequestContext::builder(url, request_type)
+                .initiator("https://news.example/")
+                .build()
+                .expect("valid request");
+            matcher.match_request(&request.context())
+        };
+        let long_url = format!("https://img.adserver.example/banners/{}/x.png", "a1b2c3/".repeat(40));
+        assert_eq!(decide(&long_url, RequestType::IMAGE).rule_id, 0);
+        assert_eq!(decide("https://adserver.example/banners/ok.gif", RequestType::IMAGE).decision, MatchDecision::Allow);
+        assert_eq!(decide("https://cdn.example/v2/track.js", RequestType::SCRIPT).rule_id, 1);
+        assert_eq!(decide("https://cdn.example/v2/track.js", RequestType::IMAGE).decision, MatchDecision::Allow);
+        assert_eq!(decide("https://other.example/banners/x.png", RequestType::IMAGE).decision, MatchDecision::Allow);
+        assert_eq!(decide("https://notadserver.example/banners/x.png", RequestType::IMAGE).decision, MatchDecision::Allow);
+        assert_eq!(decide("https://other.example/trackpixel.gif", RequestType::IMAGE).rule_id, 3);
+    }
+
+    #[test]
+    fn token_postings_are_bucketed_by_request_type() {
+        let rules = parse_filter_list(
+            "/adserve.$script\n/adserve.$image\n/adserve.$subdocument\n/adserve.$image,media\n/adserve.$font\n",
+        );
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let entry = snapshot.token_dict().lookup(hash_token("adserve")).expect("adserve is indexed");
+        assert_eq!(entry.rule_count, 5);
+
+        let decode = |request_type: RequestType| {
+            let mut out = PostingBuf::new();
+            append_bucketed_postings(
+                snapshot.token_postings(),
+                entry.postings_offset,
+                posting_bucket_for(request_type.bits()),
+                &mut out,
+            );
+            out.sort_unstable();
+            out.into_vec()
+        };
+        // `$image,media` spans two classes, so it is decoded for every type.
+        assert_eq!(decode(RequestType::IMAGE), [1, 3]);
+        assert_eq!(decode(RequestType::SCRIPT), [0, 3]);
+        assert_eq!(decode(RequestType::SUBDOCUMENT), [2, 3]);
+        assert_eq!(decode(RequestType::FONT), [3, 4]);
+        assert_eq!(decode(RequestType::SCRIPT | RequestType::IMAGE), [0, 1, 2, 3, 4]);
+
+        let engine = Engine::new(&snapshot);
+        let check = |request_type| engine.check("https://cdn.example/adserve.x", request_type, None).decision;
+        for request_type in [
+            RequestType::SCRIPT,
+            RequestType::IMAGE,
+            RequestType::SUBDOCUMENT,
+            RequestType::MEDIA,
+            RequestType::FONT,
+        ] {
+            assert_eq!(check(request_type), MatchDecision::Block, "{:?}", request_type);
+        }
+        assert_eq!(check(RequestType::STYLESHEET), MatchDecision::Allow);
+    }
+
+    #[test]
+    fn regex_filters_match_with_options() {
+        let list = "/ads[0-9]+\\.js$/$script\n\
+                    @@/ads7\\.js/$script,domain=trusted.example\n\
+                    /tracker\\/v[0-9]/$match-case\n\
+                    /(unclosed/\n";
+        let (rules, diagnostics) = parse_filter_list_with_diagnostics(list);
+        assert_eq!(rules.len(), 3);
+        assert_eq!(diagnostics.dropped_lines.len(), 1);
+        assert_eq!(diagnostics.dropped_lines[0].reason, DropReason::InvalidRegex);
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let ctx = RequestContext {
+            url: "https://cdn.example/ads42.js",
+            req_host: "cdn.example",
+            req_etld1: "cdn.example",
+            site_host: "site.example",
+            site_etld1: "site.example",
+            is_third_party: true,
+            request_type: RequestType::SCRIPT,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Block);
+
+        let image = RequestContext { request_type: RequestType::IMAGE, ..ctx.clone() };
+        assert_eq!(matcher.match_request(&image).decision, MatchDecision::Allow);
+
+        let query = RequestContext { url: "https://cdn.example/ads42.js?v=1", ..ctx.clone() };
+        assert_eq!(matcher.match_request(&query).decision, MatchDecision::Allow);
+
+        let trusted = RequestContext {
+            url: "https://cdn.example/ads7.js",
+            site_host: "trusted.example",
+            site_etld1: "trusted.example",
+            ..ctx.clone()
+        };
+        let result = matcher.match_request(&trusted);
+        assert_eq!(result.decision, MatchDecision::Allow);
+        assert!(result.rule_id >= 0);
+
+        let tracker = RequestContext { url: "https://cdn.example/tracker/v2/p.gif", ..ctx.clone() };
+        assert_eq!(matcher.match_request(&tracker).decision, MatchDecision::Block);
+        let upper = RequestContext { url: "https://cdn.example/Tracker/V2/p.gif", ..ctx };
+        assert_eq!(matcher.match_request(&upper).decision, MatchDecision::Allow);
+    }
+
+    #[test]
+    fn engine_derives_context_from_urls() {
+        let list = "||tracker.net^$third-party\n\
+                    ||cdn.site.com/ads/$script\n\
+                    site.com##.banner\n";
+        let rules = parse_filter_list(list);
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let engine = Engine::new(&snapshot);
+
+        let third_party = engine.check("https://tracker.net/p.js", RequestType::SCRIPT, Some("https://www.site.com/"));
+        assert_eq!(third_party.decision, MatchDecision::Block);
+        let first_party = engine.check("https://tracker.net/p.js", RequestType::SCRIPT, Some("https://tracker.net/"));
+        assert_eq!(first_party.decision, MatchDecision::Allow);
+        let document = engine.check("https://tracker.net/", RequestType::MAIN_FRAME, Some("https://www.site.com/"));
+        assert_eq!(document.decision, MatchDecision::Allow);
+
+        let info = RequestInfo::new("https://cdn.site.com/ads/x.js", RequestType::SCRIPT, Some("https://www.site.com/"))
+            .with_ids(3, 1, "42");
+        assert_eq!(info.req_etld1, "site.com");
+        assert!(!info.is_third_party);
+        assert_eq!(info.context().request_id, "42");
+        assert_eq!(engine.check_request(&info).decision, MatchDecision::Block);
+
+        assert!(engine.cosmetics_for("https://www.site.com/page").css.concat().contains(".banner"));
+        assert!(!engine.cosmetics_for("https://other.org/").css.concat().contains(".banner"));
+    }
+
+    #[test]
+    fn websocket_rules_match_ws_urls() {
+        let list = "||socket.example.com/live$websocket\n\
+                    @@||socket.example.com/live/ok$websocket\n\
+                    ||chat.example.net^$websocket\n\
+                    |wss://$websocket,third-party\n\
+                    |ws://insecure.example/feed\n";
+        let rules = parse_filter_list(list);
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let engine = Engine::new(&snapshot);
+        let check = |url: &str, request_type, site: &str| engine.check(url, request_type, Some(site)).decision;
+
+        let ws = RequestType::WEBSOCKET;
+        assert_eq!(check("wss://socket.example.com/live/feed", ws, "https://socket.example.com/"), MatchDecision::Block);
+        assert_eq!(
+            check("wss://socket.example.com/live/abcde/fghij?room=klmno", ws, "https://socket.example.com/"),
+            MatchDecision::Block
+        );
+        assert_eq!(check("wss://socket.example.com/live/ok", ws, "https://socket.example.com/"), MatchDecision::Allow);
+        assert_eq!(
+            check("https://socket.example.com/live/feed", RequestType::XMLHTTPREQUEST, "https://socket.example.com/"),
+            MatchDecision::Allow
+        );
+        assert_eq!(check("wss://chat.example.net/socket", ws, "https://chat.example.net/"), MatchDecision::Block);
+
+        // Scheme-only patterns are indexed on the scheme token.
+        assert_eq!(check("wss://push.other.org/", ws, "https://site.com/"), MatchDecision::Block);
+        assert_eq!(check("wss://push.site.com/", ws, "https://site.com/"), MatchDecision::Allow);
+        assert_eq!(check("ws://insecure.example/feed", ws, "http://insecure.example/"), MatchDecision::Block);
+        assert_eq!(check("wss://insecure.example/feed", ws, "https://insecure.example/"), MatchDecision::Allow);
+    }
+
+    #[test]
+    fn pattern_rules_match_when_url_has_rarer_tokens() {
+        let list = "/tracker/live.js\n/abcde/x.js\n";
+        let rules = parse_filter_list(list);
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let engine = Engine::new(&snapshot);
+
+        let result = engine.check("https://a.com/abcde/tracker/live.js", RequestType::SCRIPT, None);
+        assert_eq!(result.decision, MatchDecision::Block);
+        assert_eq!(result.rule_id, 0);
+    }
+
+    #[test]
+    fn request_context_builder_derives_and_validates() {
+        use bb_core::types::RequestContextError;
+
+        let request = RequestContext::builder("https://cdn.site.com/ads/x.js", RequestType::SCRIPT)
+            .initiator("https://www.site.com/")
+            .tab_id(3)
+            .request_id("42")
+            .build()
+            .unwrap();
+        let ctx = request.context();
+        assert_eq!((ctx.req_etld1, ctx.site_host), ("site.com", "www.site.com"));
+        assert!(!ctx.is_third_party);
+        assert_eq!(ctx.scheme, SchemeMask::HTTPS);
+        assert_eq!((ctx.tab_id, ctx.frame_id, ctx.request_id), (3, -1, "42"));
+
+        let tracker = RequestContext::builder("wss://tracker.net/live", RequestType::WEBSOCKET)
+            .site_host("news.example.co.uk")
+            .build()
+            .unwrap();
+        assert_eq!(tracker.site_etld1, "example.co.uk");
+        assert!(tracker.is_third_party);
+
+        let opaque = RequestContext::builder("https://a.com/x", RequestType::IMAGE).initiator("null").build().unwrap();
+        assert_eq!(opaque.site_host, "a.com");
+        let page = RequestContext::builder("https://a.com/", RequestType::MAIN_FRAME).initiator("https://b.com/").build().unwrap();
+        assert!(!page.is_third_party);
+
+        assert!(RequestContext::builder("data:image/png;base64,AAAA", RequestType::IMAGE).build().is_ok());
+        assert_eq!(
+            RequestContext::builder("chrome-extension://abc/x.js", RequestType::SCRIPT).build().unwrap_err(),
+            RequestContextError::UnsupportedScheme
+        );
+        assert_eq!(
+            RequestContext::builder("https:///x.js", RequestType::SCRIPT).build().unwrap_err(),
+            RequestContextError::MissingHost
+        );
+    }
+
+    #[test]
+    fn match_scratch_reuse_matches_fresh_buffers() {
+        let list = "||ads.example^\n\
+                    /banner/*$image\n\
+                    @@/banner/ok.png\n\
+                    ||cdn.example/p$removeparam=utm_source\n";
+        let rules = parse_filter_list(list);
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let ctx = RequestContext {
+            url: "https://ads.example/x.js",
+            req_host: "ads.example",
+            req_etld1: "ads.example",
+            site_host: "site.example",
+            site_etld1: "site.example",
+            is_third_party: true,
+            request_type: RequestType::IMAGE,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+        let requests = [
+            ctx.clone(),
+            RequestContext { url: "https://cdn.example/banner/a.png", req_host: "cdn.example", req_etld1: "cdn.example", ..ctx.clone() },
+            RequestContext { url: "https://cdn.example/banner/ok.png", req_host: "cdn.example", req_etld1: "cdn.example", ..ctx.clone() },
+            RequestContext { url: "https://cdn.example/p?utm_source=x&id=1", req_host: "cdn.example", req_etld1: "cdn.example", ..ctx.clone() },
+            RequestContext { url: "https://cdn.example/plain.png", req_host: "cdn.example", req_etld1: "cdn.example", ..ctx },
+        ];
+
+        let mut scratch = MatchScratch::new();
+        for _ in 0..2 {
+            for request in &requests {
+                let fresh = matcher.match_request(request);
+                let reused = matcher.match_request_with_scratch(request, &mut scratch);
+                assert_eq!(fresh.decision, reused.decision, "{}", request.url);
+                assert_eq!(fresh.rule_id, reused.rule_id, "{}", request.url);
+                assert_eq!(fresh.redirect_url, reused.redirect_url, "{}", request.url);
+            }
+        }
+        assert_eq!(matcher.match_request(&requests[0]).decision, MatchDecision::Block);
+        assert_eq!(matcher.match_request(&requests[2]).decision, MatchDecision::Allow);
+        assert_eq!(matcher.match_request(&requests[3]).decision, MatchDecision::Removeparam);
+    }
+
+    #[test]
+    fn one_matcher_serves_many_threads() {
+        let list: String = (0..100)
+            .map(|i| format!("||ads{i}.example^\n@@||ads{i}.example/ok/*\n/banner{i}/*$image\n"))
+            .collect();
+        let rules = parse_filter_list(&list);
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+
+        let urls: Vec<String> = (0..100)
+            .flat_map(|i| {
+                [
+                    format!("https://ads{i}.example/x.png"),
+                    format!("https://ads{i}.example/ok/x.png"),
+                    format!("https://cdn.example/banner{i}/x.png"),
+                    format!("https://cdn.example/plain{i}.png"),
+                ]
+            })
+            .collect();
+        let requests: Vec<RequestInfo<'_>> = urls
+            .iter()
+            .map(|url| {
+                RequestContext::builder(url, RequestType::IMAGE)
+                    .initiator("https://site.example/")
+                    .build()
+                    .expect("valid url")
+            })
+            .collect();
+        let expected: Vec<_> = requests
+            .iter()
+            .map(|request| {
+                let result = matcher.match_request(&request.context());
+                (result.decision, result.rule_id)
+            })
+            .collect();
+
+        std::thread::scope(|scope| {
+            // Trusted sites change under the readers; none of their requests come from it.
+            scope.spawn(|| {
+                for _ in 0..200 {
+                    matcher.add_trusted_site("trusted.example");
+                    matcher.remove_trusted_site("trusted.example");
+                }
+            });
+            for worker in 0..8 {
+                let (matcher, requests, expected) = (&matcher, &requests, &expected);
+                scope.spawn(move || {
+                    let mut scratch = MatchScratch::new();
+                    for round in 0..10 {
+                        for (index, request) in requests.iter().enumerate().skip((worker + round) % 4) {
+                            let result = matcher.match_request_with_scratch(&request.context(), &mut scratch);
+                            assert_eq!((result.decision, result.rule_id), expected[index], "{}", request.url);
+                        }
+                    }
+                });
+            }
+        });
+
+        let trusted = RequestContext::builder("https://ads1.example/x.png", RequestType::IMAGE)
+            .initiator("https://trusted.example/")
+            .build()
+            .expect("valid url");
+        assert_eq!(matcher.match_request(&trusted.context()).decision, MatchDecision::Block);
+        matcher.add_trusted_site("trusted.example");
+        assert_eq!(matcher.match_request(&trusted.context()).decision, MatchDecision::Allow);
+    }
+
+    #[test]
+    fn preprocessor_applies_conditions_and_includes() {
+        let list = "! Title: main\n\
+                    ||always.example^\n\
+                    !#if env_firefox\n\
+                    ||firefox.example^\n\
+                    !#else\n\
+                    ||chromium.example^\n\
+                    !#endif\n\
+                    !#if !env_mobile && (env_firefox || env_chromium)\n\
+                    !#include extra.txt\n\
+                    !#endif\n\
+                    !#include missing.txt\n\
+                    ||ads.example^$ping2\n";
+        let resolver = |name: &str| match name {
+            "extra.txt" => Some("||extra.example^\n!#include extra.txt\n".to_string()),
+            _ => None,
+        };
+
+        let env = PreprocessEnv::default().with_flag("env_firefox");
+        let preprocessed = preprocess_filter_list(list, &env, &resolver);
+        assert_eq!(preprocessed.included, vec!["extra.txt"]);
+        assert_eq!(preprocessed.unresolved, vec!["extra.txt", "missing.txt"]);
+
+        let (rules, diagnostics) = parse_filter_list_with_diagnostics(&preprocessed.text);
+        let domains: Vec<&str> = rules.iter().map(|rule| rule.domain.as_str()).collect();
+        assert_eq!(domains, vec!["always.example", "firefox.example", "extra.example"]);
+        // Line numbers still point into the original list.
+        assert_eq!(diagnostics.dropped_lines[0].line, 12);
+
+        let chromium = PreprocessEnv::default().with_flag("env_chromium").with_flag("env_mobile");
+        let rules = parse_filter_list(&preprocess_filter_list(list, &chromium, &NoIncludes).text);
+        let domains: Vec<&str> = rules.iter().map(|rule| rule.domain.as_str()).collect();
+        assert_eq!(domains, vec!["always.example", "chromium.example"]);
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        assert!(snapshot.domain_block_set().contains(hash_domain("chromium.example")));
+        assert!(!snapshot.domain_block_set().contains(hash_domain("firefox.example")));
+    }
+
+    #[test]
+    fn removeheader_strips_request_and_response_headers() {
+        let list = "||tracker.example^$removeheader=refresh\n\
+                    ||tracker.example^$removeheader=request:Cookie\n\
+                    ||tracker.example^$removeheader=x-frame-options\n\
+                    @@||tracker.example^$removeheader=refresh,domain=keep.example\n\
+                    @@||tracker.example^$removeheader,domain=all.example\n\
+                    ||other.example^$removeheader=request:\n";
+        let (rules, diagnostics) = parse_filter_list_with_diagnostics(list);
+        assert_eq!(rules.len(), 5);
+        assert_eq!(diagnostics.dropped_lines.len(), 1);
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let ctx = RequestContext {
+            url: "https://tracker.example/pixel",
+            req_host: "tracker.example",
+            req_etld1: "tracker.example",
+            site_host: "site.example",
+            site_etld1: "site.example",
+            is_third_party: true,
+            request_type: RequestType::IMAGE,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+        let headers = [ResponseHeader { name: "Refresh", value: "0" }];
+        let request_headers = [RequestHeader { name: "Cookie", value: "id=1" }];
+
+        // x-frame-options is restricted, so only refresh is stripped.
+        let result = matcher.match_response_headers(&ctx, &headers);
+        assert_eq!(result.remove_headers, vec!["refresh".to_string()]);
+        assert_eq!(matcher.match_request_headers(&ctx, &request_headers).remove_headers, vec!["cookie".to_string()]);
+        // Absent headers aren't listed.
+        assert!(matcher.match_request_headers(&ctx, &[]).remove_headers.is_empty());
+        assert_eq!(matcher.match_request(&ctx).decision, MatchDecision::Allow);
+
+        let keep = RequestContext { site_host: "keep.example", site_etld1: "keep.example", ..ctx.clone() };
+        assert!(matcher.match_response_headers(&keep, &headers).remove_headers.is_empty());
+        assert_eq!(matcher.match_request_headers(&keep, &request_headers).remove_headers, vec!["cookie".to_string()]);
+
+        let all = RequestContext { site_host: "all.example", site_etld1: "all.example", ..ctx };
+        assert!(matcher.match_response_headers(&all, &headers).remove_headers.is_empty());
+        assert!(matcher.match_request_headers(&all, &request_headers).remove_headers.is_empty());
+    }
+
+    #[test]
+    fn header_request_rules_match_outgoing_headers() {
+        let list = "||tracker.example^$header=request:referer:leaky.example\n\
+                    ||tracker.example^$header=request:x-client-data,important,domain=strict.example\n\
+                    @@||tracker.example^$header=request:referer:leaky.example,domain=ok.example\n\
+                    ||tracker.example^$header=referer:leaky.example\n";
+        let rules = parse_filter_list(list);
+        assert_eq!(rules.len(), 4);
+        assert!(rules[0].header.as_ref().is_some_and(|spec| spec.request && spec.name == "referer"));
+        assert!(!rules[3].header.as_ref().is_some_and(|spec| spec.request));
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let request = |site: &'static str| {
+            RequestContext::builder("https://tracker.example/pixel", RequestType::IMAGE)
+                .initiator(site)
+                .build()
+                .expect("valid request")
+        };
+        let referer = [RequestHeader { name: "Referer", value: "https://leaky.example/page" }];
+        let client_data = [RequestHeader { name: "X-Client-Data", value: "abc" }];
+
+        let site = request("https://site.example/");
+        let result = matcher.match_request_headers(&site.context(), &referer);
+        assert!(result.cancel);
+        assert_eq!(result.rule_id, 0);
+        assert!(!matcher.match_request_headers(&site.context(), &client_data).cancel);
+        // Each stage only sees the rules for its own direction.
+        assert_eq!(matcher.match_response_headers(&site.context(), &referer).rule_id, 3);
+        assert!(!matcher.match_response_headers(&site.context(), &client_data).cancel);
+
+        let ok = request("https://ok.example/");
+        assert!(!matcher.match_request_headers(&ok.context(), &referer).cancel);
+
+        let strict = request("https://strict.example/");
+        assert!(matcher.match_request_headers(&strict.context(), &client_data).cancel);
+    }
+
+    #[test]
+    fn generic_cosmetics_are_bucketed_by_id_and_class() {
+        assert_eq!(generic_cosmetic_key(".ad-banner"), Some(".ad-banner"));
+        assert_eq!(generic_cosmetic_key("#promo > .x"), Some("#promo"));
+        assert_eq!(generic_cosmetic_key(".ad[data-x]"), Some(".ad"));
+        assert_eq!(generic_cosmetic_key("div.sponsored"), None);
+        assert_eq!(generic_cosmetic_key(".a, .b"), None);
+        assert_eq!(generic_cosmetic_key(".\\31 ad"), None);
+
+        let rules = parse_filter_list(
+            "##.ad-banner\n\
+             ###promo > .x\n\
+             ##div.sponsored\n\
+             example.com#@#.ad-banner\n\
+             @@||quiet.example^$generichide",
+        );
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let ctx = RequestContext {
+            url: "https://news.example/",
+            req_host: "news.example",
+            req_etld1: "news.example",
+            site_host: "news.example",
+            site_etld1: "news.example",
+            is_third_party: false,
+            request_type: RequestType::MAIN_FRAME,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        // Only the highly generic selector is sent up front.
+        let result = matcher.match_cosmetics(&ctx);
+        assert!(result.css.concat().contains("div.sponsored"));
+        assert!(!result.css.concat().contains(".ad-banner"));
+        assert!(!result.css.concat().contains("#promo"));
+
+        let keys = [hash_token(".ad-banner"), hash_token("#promo"), hash_token(".unused")];
+        let css = matcher.match_cosmetics_generic(&ctx, &keys);
+        assert!(css.contains(".ad-banner"));
+        assert!(css.contains("#promo > .x"));
+        assert!(!css.contains("div.sponsored"));
+        assert!(matcher.match_cosmetics_generic(&ctx, &[hash_token(".unused")]).is_empty());
+
+        let excepted = RequestContext {
+            url: "https://example.com/",
+            req_host: "example.com",
+            req_etld1: "example.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            ..ctx.clone()
+        };
+        assert_eq!(matcher.match_cosmetics_generic(&excepted, &keys), "#promo > .x{display:none !important;}");
+
+        let quiet = RequestContext {
+            url: "https://quiet.example/",
+            req_host: "quiet.example",
+            req_etld1: "quiet.example",
+            site_host: "quiet.example",
+            site_etld1: "quiet.example",
+            ..ctx
+        };
+        assert!(matcher.match_cosmetics_generic(&quiet, &keys).is_empty());
+
+        // Callers holding the page's switches pass them in instead.
+        let switches = matcher.cosmetic_hide_switches(&quiet);
+        assert!(switches.generichide);
+        assert!(matcher.match_cosmetics_generic_with_switches(&quiet, &keys, switches).is_empty());
+        let css = matcher.match_cosmetics_generic_with_switches(&quiet, &keys, CosmeticHideSwitches::default());
+        assert!(css.contains(".ad-banner"));
+    }
+
+    #[test]
+    fn cosmetics_stats_count_selectors_without_building_css() {
+        let rules = parse_filter_list(
+            "news.example##.sidebar-ad\n\
+             news.example###top-banner\n\
+             ##.ad-banner\n\
+             ##div.sponsored\n\
+             news.example##.promo:has-text(Sponsored)\n\
+             news.example#@#.ad-banner\n\
+             @@||quiet.example^$elemhide",
+        );
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let ctx = RequestContext {
+            url: "https://news.example/",
+            req_host: "news.example",
+            req_etld1: "news.example",
+            site_host: "news.example",
+            site_etld1: "news.example",
+            is_third_party: false,
+            request_type: RequestType::MAIN_FRAME,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        let stats = matcher.cosmetics_stats(&ctx);
+        assert_eq!(stats.specific.count, 2);
+        assert_eq!(stats.specific.bytes, (".sidebar-ad".len() + "#top-banner".len()) as u32);
+        assert_eq!(stats.generic.count, 1);
+        assert_eq!(stats.generic.bytes, "div.sponsored".len() as u32);
+        // `.ad-banner` is excepted on this site.
+        assert_eq!(stats.generic_bucketed.count, 0);
+        assert_eq!(stats.procedural.count, 1);
+        assert!(stats.enable_generic);
+
+        let other = RequestContext {
+            url: "https://other.example/",
+            req_host: "other.example",
+            req_etld1: "other.example",
+            site_host: "other.example",
+            site_etld1: "other.example",
+            ..ctx.clone()
+        };
+        let stats = matcher.cosmetics_stats(&other);
+        assert_eq!((stats.specific.count, stats.generic.count), (0, 1));
+        assert_eq!(stats.generic_bucketed.count, 1);
+        assert_eq!(stats.generic_bucketed.bytes, ".ad-banner".len() as u32);
+        assert_eq!(stats.procedural.count, 0);
+
+        let quiet = RequestContext {
+            url: "https://quiet.example/",
+            req_host: "quiet.example",
+            req_etld1: "quiet.example",
+            site_host: "quiet.example",
+            site_etld1: "quiet.example",
+            ..ctx
+        };
+        let stats = matcher.cosmetics_stats(&quiet);
+        assert_eq!(stats.generic.count + stats.generic_bucketed.count, 0);
+    }
+
+    #[test]
+    fn cosmetic_selectors_are_normalized() {
+        assert_eq!(normalize_selector("div>a"), "div > a");
+        assert_eq!(normalize_selector("  div   >   a.ad  "), "div > a.ad");
+        assert_eq!(normalize_selector("h2+p ~span,.x"), "h2 + p ~ span, .x");
+        assert_eq!(normalize_selector("a[href^='https://x/']"), "a[href^=\"https://x/\"]");
+        assert_eq!(normalize_selector("a[ href ^= \"x\" ]"), "a[href^=\"x\"]");
+        assert_eq!(normalize_selector("a[title='say \"hi\"']"), "a[title='say \"hi\"']");
+        assert_eq!(normalize_selector("li:nth-child( 2n + 1 )"), "li:nth-child(2n+1)");
+        assert_eq!(normalize_selector("div [data-ad]"), "div [data-ad]");
+        assert_eq!(normalize_selector(".a\\ b"), ".a\\ b");
+    }
+
+    #[test]
+    fn cosmetic_exceptions_match_normalized_selectors_on_subdomains() {
+        // Shapes from EasyList Germany, with exceptions formatted differently
+        // from the hides they cancel.
+        let rules = parse_filter_list(
+            "spiegel.de##div[id='spWerbung'] > a\n\
+             www.spiegel.de#@#div[id=\"spWerbung\"]>a\n\
+             heise.de,chip.de##a[href^=\"https://www.amazon.de/gp/\"]\n\
+             m.heise.de#@#a[href^='https://www.amazon.de/gp/']\n\
+             ###Ad_Win2day >  iframe\n\
+             t-online.de#@##Ad_Win2day>iframe",
+        );
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let page = |host: &'static str, etld1: &'static str| RequestContext {
+            url: "https://page.example/",
+            req_host: host,
+            req_etld1: etld1,
+            site_host: host,
+            site_etld1: etld1,
+            is_third_party: false,
+            request_type: RequestType::MAIN_FRAME,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        // The exception only covers the subdomain it names, not its parent.
+        assert!(matcher.match_cosmetics(&page("spiegel.de", "spiegel.de")).css.concat().contains("div[id=\"spWerbung\"] > a"));
+        assert!(matcher.match_cosmetics(&page("www.spiegel.de", "spiegel.de")).css.is_empty());
+
+        assert!(matcher.match_cosmetics(&page("www.heise.de", "heise.de")).css.concat().contains("amazon.de/gp/"));
+        assert!(matcher.match_cosmetics(&page("m.heise.de", "heise.de")).css.is_empty());
+        assert!(matcher.match_cosmetics(&page("chip.de", "chip.de")).css.concat().contains("amazon.de/gp/"));
+
+        // Bucketed generic hides honor the same hashes.
+        let keys = [hash_token("#Ad_Win2day")];
+        assert_eq!(
+            matcher.match_cosmetics_generic(&page("www.chip.de", "chip.de"), &keys),
+            "#Ad_Win2day > iframe{display:none !important;}"
+        );
+        assert!(matcher.match_cosmetics_generic(&page("www.t-online.de", "t-online.de"), &keys).is_empty());
+    }
+
+    #[test]
+    fn wildcard_tld_domains_match_any_public_suffix() {
+        use crate::dnr::{convert_rules, DnrOptions, DnrSkipReason};
+
+        let rules = parse_filter_list(
+            "||ads.example^$domain=google.*|~mail.google.*\n\
+             google.*,~maps.google.*##.sponsored\n",
+        );
+        assert_eq!(rules.len(), 2);
+        let constraints = rules[0].domain_constraints.as_ref().expect("domain constraint");
+        assert_eq!(constraints.include_names, vec!["google.*"]);
+        assert_eq!(constraints.exclude_names, vec!["mail.google.*"]);
+        assert_ne!(constraints.include[0], hash_domain("google"));
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let engine = Engine::new(&snapshot);
+        let decide = |site: &str| {
+            let request = RequestInfo::new("https://ads.example/x.js", RequestType::SCRIPT, Some(site));
+            engine.check_request(&request).decision
+        };
+        assert_eq!(decide("https://google.com/"), MatchDecision::Block);
+        assert_eq!(decide("https://www.google.co.uk/"), MatchDecision::Block);
+        assert_eq!(decide("https://mail.google.de/"), MatchDecision::Allow);
+        assert_eq!(decide("https://notgoogle.com/"), MatchDecision::Allow);
+        assert_eq!(decide("https://google.example.org/"), MatchDecision::Allow);
+
+        let css = |url: &str| engine.cosmetics_for(url).css.concat();
+        assert!(css("https://www.google.fr/").contains(".sponsored"));
+        assert!(css("https://maps.google.fr/").is_empty());
+        assert!(css("https://google.example.org/").is_empty());
+
+        let export = convert_rules(&rules, &DnrOptions::default());
+        assert_eq!(export.skipped[0].reason, DnrSkipReason::WildcardDomain);
+    }
+
+    #[test]
+    fn popup_rules_only_apply_to_popups() {
+        use crate::dnr::{convert_rules, DnrOptions, DnrSkipReason};
+
+        let mut rules = parse_filter_list(
+            "||ads.example^\n\
+             ||ads.example^$popup\n\
+             ||pop.example^$popup,third-party\n\
+             ||pop.example^$~script\n",
+        );
+        assert_eq!(rules[1].type_mask, RequestType::POPUP);
+        assert_eq!(rules[3].type_mask, RequestType::ALL & !RequestType::SCRIPT);
+        // The plain rule doesn't cover the popup one.
+        optimize_rules(&mut rules);
+        assert_eq!(rules.len(), 4);
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let engine = Engine::new(&snapshot);
+        let popup = |url: &str, opener: &str| engine.check_popup(url, opener).decision;
+        assert_eq!(popup("https://ads.example/landing", "https://news.example/"), MatchDecision::Block);
+        assert_eq!(popup("https://pop.example/", "https://news.example/"), MatchDecision::Block);
+        assert_eq!(popup("https://pop.example/", "https://www.pop.example/"), MatchDecision::Allow);
+        assert_eq!(popup("https://other.example/", "https://news.example/"), MatchDecision::Allow);
+        let frame = engine.check("https://pop.example/", RequestType::MAIN_FRAME, Some("https://news.example/"));
+        assert_eq!(frame.decision, MatchDecision::Block);
+        assert_eq!(frame.rule_id, 3);
+
+        let export = convert_rules(&rules, &DnrOptions::default());
+        let skipped: Vec<_> = export.skipped.iter().map(|skip| &skip.reason).collect();
+        assert_eq!(skipped, [&DnrSkipReason::Popup, &DnrSkipReason::Popup]);
+    }
+
+    #[test]
+    fn extension_and_internal_urls_skip_unless_targeted() {
+        let mut rules = parse_filter_list(
+            "/ads/banner.js\n\
+             /ads/banner.js$~http\n\
+             ||abcdef^$extension\n",
+        );
+        assert_eq!(rules[1].scheme_mask, SchemeMask::WEB - SchemeMask::HTTP);
+        assert_eq!(rules[2].scheme_mask, SchemeMask::EXTENSION);
+        optimize_rules(&mut rules);
+        assert_eq!(rules.len(), 3);
+
+        let extension_url = "chrome-extension://abcdef/ads/banner.js";
+        let bytes = build_snapshot(&rules[..2]);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let engine = Engine::new(&snapshot);
+        assert!(!engine.matcher().is_filterable(extension_url));
+        assert!(!engine.matcher().is_filterable("about:blank"));
+        assert!(engine.matcher().is_filterable("https://news.example/ads/banner.js"));
+        assert_eq!(engine.check(extension_url, RequestType::SCRIPT, None).decision, MatchDecision::Allow);
+        assert_eq!(
+            engine.check("https://news.example/ads/banner.js", RequestType::SCRIPT, None).decision,
+            MatchDecision::Block
+        );
+        let request = RequestInfo::new(extension_url, RequestType::SCRIPT, None);
+        assert_eq!(engine.explain_request(&request).reason, DecisionReason::UnfilterableScheme);
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let engine = Engine::new(&snapshot);
+        assert!(engine.matcher().is_filterable(extension_url));
+        assert!(!engine.matcher().is_filterable("about:blank"));
+        let result = engine.check(extension_url, RequestType::SCRIPT, None);
+        assert_eq!((result.decision, result.rule_id), (MatchDecision::Block, 2));
+    }
+
+    #[test]
+    fn site_diagnostics_count_active_exceptions() {
+        use bb_core::matcher::{ExceptionCount, ExceptionKind};
+
+        let lists = [
+            "@@||cdn.example/ads.js$domain=site.example\n\
+             @@||site.example^$generichide\n\
+             site.example#@#.ad\n\
+             #@#.banner\n",
+            "site.example#@#+js(set-constant, x, 1)\n\
+             site.example#@#div:has-text(Sponsored)\n\
+             @@||cdn.example/ads.js$domain=elsewhere.example\n",
+        ];
+        let mut rules = Vec::new();
+        for (list_id, text) in lists.iter().enumerate() {
+            let mut list = parse_filter_list(text);
+            list.iter_mut().for_each(|rule| rule.list_id = list_id as u16);
+            rules.extend(list);
+        }
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let mut engine = Engine::new(&snapshot);
+
+        let site = engine.site_diagnostics("https://www.site.example/article");
+        let entry = |kind, list_id, count| ExceptionCount { kind, list_id, count };
+        assert_eq!(
+            site.exceptions,
+            [
+                entry(ExceptionKind::Network, 0, 1),
+                entry(ExceptionKind::HideSwitch, 0, 1),
+                entry(ExceptionKind::Cosmetic, 0, 2),
+                entry(ExceptionKind::Procedural, 1, 1),
+                entry(ExceptionKind::Scriptlet, 1, 1),
+            ]
+        );
+        assert!(site.switches.generichide && !site.trusted);
+
+        let other = engine.site_diagnostics("https://other.example/");
+        assert_eq!(other.exceptions, [entry(ExceptionKind::Cosmetic, 0, 1)]);
+
+        engine.matcher_mut().set_disabled_lists(&[0]);
+        let site = engine.site_diagnostics("https://www.site.example/article");
+        assert_eq!(site.count(ExceptionKind::Network) + site.count(ExceptionKind::Cosmetic), 0);
+        assert_eq!(site.count(ExceptionKind::Scriptlet), 1);
+    }
+
+    #[test]
+    fn hide_css_comes_in_chunks() {
+        let list: String = (0..1000).map(|i| format!("example.com##.ad-{i}\n")).collect();
+        let rules = parse_filter_list(&list);
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let mut engine = Engine::new(&snapshot);
+
+        let css = engine.cosmetics_for("https://example.com/").css;
+        assert_eq!(css.len(), 4);
+        assert_eq!(css[0].matches(",\n").count(), 255);
+        assert!(css.iter().all(|rule| rule.ends_with("{display:none !important;}")));
+
+        engine.matcher_mut().set_hide_css_chunk(300);
+        let css = engine.cosmetics_for("https://example.com/").css;
+        assert_eq!(css.iter().map(|rule| rule.matches(",\n").count() + 1).collect::<Vec<_>>(), [300, 300, 300, 100]);
+        engine.matcher_mut().set_hide_css_chunk(0);
+        assert_eq!(engine.cosmetics_for("https://example.com/").css.len(), 1000);
+    }
+
+    #[test]
+    fn unexcepted_selectors_honor_page_exceptions() {
+        let rules = parse_filter_list("example.com#@#.promo\n#@#div[id=\"sticky\"]\n");
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let engine = Engine::new(&snapshot);
+        let user = [".promo", "div[id=\"sticky\"]", ".cookie-bar"];
+
+        let page = RequestInfo::new("https://www.example.com/", RequestType::MAIN_FRAME, None);
+        assert_eq!(engine.unexcepted_selectors_for_request(&page, &user), vec![".cookie-bar"]);
+        let page = RequestInfo::new("https://other.example/", RequestType::MAIN_FRAME, None);
+        assert_eq!(engine.unexcepted_selectors_for_request(&page, &user), vec![".promo", ".cookie-bar"]);
+    }
+
+    #[test]
+    fn cosmetic_matches_report_list_and_line() {
+        let mut rules = parse_filter_list("! Title: base\nexample.com##.banner\n##.ad-slot\n");
+        let mut extra = parse_filter_list("example.com#@#.ad-slot\n\nexample.com##.promo\nother.com##.promo\n");
+        for rule in &mut extra {
+            rule.list_id = 1;
+        }
+        rules.extend(extra);
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let ctx = RequestContext {
+            url: "https://example.com/",
+            req_host: "example.com",
+            req_etld1: "example.com",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: false,
+            request_type: RequestType::MAIN_FRAME,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+
+        let matches = matcher.match_cosmetics_detailed(&ctx);
+        let summary: Vec<(&str, u16, u32)> = matches
+            .iter()
+            .map(|m| (m.selector.as_str(), m.list_id, m.line))
+            .collect();
+        assert_eq!(summary, vec![(".banner", 0, 2), (".promo", 1, 3)]);
+
+        let css = matcher.match_cosmetics(&ctx).css.concat();
+        assert_eq!(css, ".banner,\n.promo{display:none !important;}");
+
+        // Indices point back into the CosmeticRules section.
+        let section = snapshot.cosmetic_rules();
+        for m in &matches {
+            let entry = 4 + m.rule_index as usize * 16;
+            let off = u32::from_le_bytes(section[entry + 4..entry + 8].try_into().unwrap()) as usize;
+            let len = u32::from_le_bytes(section[entry + 8..entry + 12].try_into().unwrap()) as usize;
+            assert_eq!(snapshot.get_string(off, len), Some(m.selector.as_str()));
+        }
+    }
+
+    #[test]
+    fn dns_only_options_are_skipped_or_mapped_to_host_blocks() {
+        let list = "||ads.example^$dnstype=AAAA\n\
+                    ||tracker.example^$dnsrewrite=REFUSED,important\n\
+                    ||cdn.example^$dnsrewrite=NOERROR;A;1.2.3.4\n\
+                    @@||ok.example^$client=127.0.0.1\n\
+                    /ads/*$ctag=device_pc\n\
+                    ||x.example^$ping2\n";
+
+        let (rules, diagnostics) = parse_filter_list_with_diagnostics(list);
+        assert!(rules.is_empty());
+        assert_eq!(diagnostics.dns_only_lines(), 5);
+        assert_eq!(diagnostics.dropped_lines[0].reason, DropReason::DnsOption("dnstype".to_string()));
+        // Only the genuinely unknown option counts as unsupported.
+        assert_eq!(diagnostics.unsupported_options.keys().collect::<Vec<_>>(), vec!["ping2"]);
+
+        let config = ParserConfig { dns_options: DnsOptionPolicy::BlockHost };
+        let (rules, diagnostics) = parse_filter_list_with_config(list, &config);
+        let domains: Vec<&str> = rules.iter().map(|rule| rule.domain.as_str()).collect();
+        assert_eq!(domains, vec!["ads.example", "tracker.example"]);
+        assert!(rules[1].flags.contains(RuleFlags::IMPORTANT));
+        assert_eq!(diagnostics.dns_only_lines(), 3);
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        assert!(snapshot.domain_block_set().contains(hash_domain("ads.example")));
+    }
+
+    #[test]
+    fn parse_into_appends_tagged_rules() {
+        let mut rules = Vec::new();
+        let config = ParserConfig::default();
+        parse_filter_list_into("||ads.example^\nexample.com##.banner\n", &config, 1, &mut rules);
+        let diagnostics = parse_filter_list_into("!\n||ads.example^$bogus\n@@||ok.example^\n", &config, 2, &mut rules);
+
+        let tagged: Vec<(u16, u32)> = rules.iter().map(|rule| (rule.list_id, rule.line)).collect();
+        assert_eq!(tagged, vec![(1, 1), (1, 2), (2, 3)]);
+        assert_eq!(diagnostics.dropped_lines.len(), 1);
+        assert_eq!(diagnostics.dropped_lines[0].line, 2);
+        assert_eq!(rules[2].domain, "ok.example");
+    }
+
+    #[test]
+    fn redirect_targets_are_checked_against_the_registry() {
+        let (rules, diagnostics) = parse_filter_list_with_diagnostics(
+            "||ads.example^$script,redirect=noopjs\n||img.example^$image,redirect=missing.gif\n",
+        );
+        assert_eq!(rules.len(), 1);
+        assert_eq!(diagnostics.dropped_lines.len(), 1);
+        assert_eq!(
+            diagnostics.dropped_lines[0].reason,
+            DropReason::UnknownRedirect("missing.gif".to_string())
+        );
+
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let engine = Engine::new(&snapshot);
+        let result = engine.check("https://ads.example/a.js", RequestType::SCRIPT, Some("https://site.example/"));
+        assert_eq!(result.decision, MatchDecision::Redirect);
+        assert_eq!(result.redirect_url.as_deref(), Some("/redirects/noop.js"));
+
+        let resources: Vec<_> = snapshot.redirect_resources().collect();
+        assert_eq!(resources.len(), crate::redirects::REDIRECT_REGISTRY.len());
+        for resource in resources {
+            let spec = crate::redirects::lookup_redirect(resource.name).expect("registered resource");
+            assert_eq!(resource.path, spec.path);
+            assert_eq!(resource.mime, spec.mime);
+            assert_eq!(resource.checksum, spec.checksum);
+        }
+
+        for spec in crate::redirects::REDIRECT_REGISTRY {
+            assert_eq!(spec.checksum, Some(bb_core::hash::crc32(spec.data)), "{} changed on disk", spec.name);
+        }
+    }
+
+    #[test]
+    fn redirect_resources_can_be_embedded_as_data_urls() {
+        let gif = crate::redirects::lookup_redirect("1x1.gif").expect("registered resource");
+        assert_eq!(
+            gif.data_url(),
+            "data:image/gif;base64,R0lGODlhAQABAIAAAP///wAAACH5BAEAAAAALAAAAAABAAEAAAICRAEAOw=="
+        );
+        let txt = crate::redirects::lookup_redirect("noop.txt").expect("registered resource");
+        assert_eq!(txt.data_url(), "data:text/plain;base64,");
+
+        let rules = parse_filter_list("||ads.example^$script,redirect=noopjs\n||img.example^$image,redirect=1x1.gif\n");
+        let plain = build_snapshot(&rules);
+        let embedded = build_snapshot_with_options(
+            &rules,
+            &SnapshotOptions {
+                embed_redirect_data: true,
+                ..SnapshotOptions::default()
+            },
+        );
+
+        let snapshot = Snapshot::load(&embedded).expect("snapshot should load");
+        let mut matcher = Matcher::new(&snapshot);
+        let request = RequestContext::builder("https://img.example/a.gif", RequestType::IMAGE)
+            .build()
+            .expect("valid url");
+        let ctx = request.context();
+        // Paths stay the default; data URLs are opt-in per matcher.
+        assert_eq!(matcher.match_request(&ctx).redirect_url.as_deref(), Some("/redirects/1x1.gif"));
+        matcher.set_redirect_data_urls(true);
+        assert_eq!(matcher.match_request(&ctx).redirect_url, Some(gif.data_url()));
+
+        // Without embedded data the path is the only form.
+        let snapshot = Snapshot::load(&plain).expect("snapshot should load");
+        let mut matcher = Matcher::new(&snapshot);
+        matcher.set_redirect_data_urls(true);
+        assert_eq!(matcher.match_request(&ctx).redirect_url.as_deref(), Some("/redirects/1x1.gif"));
+    }
+
+    #[test]
+    fn site_switches_block_fonts_scripts_and_cosmetics() {
+        let rules = parse_filter_list("example.com##.ad\n##.banner");
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let mut matcher = Matcher::new(&snapshot);
+
+        let page = RequestContext {
+            url: "https://www.example.com/index.html",
+            req_host: "www.example.com",
+            req_etld1: "example.com",
+            site_host: "www.example.com",
+            site_etld1: "example.com",
+            is_third_party: false,
+            request_type: RequestType::MAIN_FRAME,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+        let script = RequestContext {
+            url: "https://cdn.other.net/app.js",
+            req_host: "cdn.other.net",
+            req_etld1: "other.net",
+            is_third_party: true,
+            request_type: RequestType::SCRIPT,
+            ..page
+        };
+        let font = RequestContext {
+            url: "https://fonts.other.net/a.woff2",
+            req_host: "fonts.other.net",
+            request_type: RequestType::FONT,
+            ..script
+        };
+        let inline_font = RequestContext {
+            url: "data:font/woff2;base64,AAAA",
+            scheme: SchemeMask::DATA,
+            ..font
+        };
+
+        assert!(!matcher.match_cosmetics(&page).css.is_empty());
+        assert_eq!(matcher.match_request(&script).decision, MatchDecision::Allow);
+
+        matcher.set_site_switches("Example.com", SiteSwitches::all());
+        assert_eq!(matcher.site_switches("www.example.com"), SiteSwitches::all());
+        assert!(matcher.site_switches("example.org").is_empty());
+
+        let result = matcher.match_request(&script);
+        assert_eq!(result.decision, MatchDecision::Block);
+        assert_eq!(result.site_switch, SiteSwitches::NO_SCRIPTING);
+        assert_eq!(matcher.match_request(&font).site_switch, SiteSwitches::NO_REMOTE_FONTS);
+        assert_eq!(matcher.match_request(&inline_font).decision, MatchDecision::Allow);
+        assert_eq!(matcher.match_request(&page).decision, MatchDecision::Allow);
+
+        let headers = matcher.match_response_headers(&page, &[]);
+        assert_eq!(headers.csp_injections, vec![NO_SCRIPTING_CSP.to_string()]);
+
+        let cosmetics = matcher.match_cosmetics(&page);
+        assert!(cosmetics.css.is_empty());
+        assert!(!cosmetics.enable_generic);
+        assert_eq!(cosmetics.site_switch, SiteSwitches::NO_COSMETIC_FILTERING);
+
+        matcher.set_site_switches("example.com", SiteSwitches::empty());
+        assert_eq!(matcher.all_site_switches().count(), 0);
+        assert_eq!(matcher.match_request(&script).decision, MatchDecision::Allow);
+        assert!(matcher.match_response_headers(&page, &[]).csp_injections.is_empty());
+    }
+
+    #[test]
+    fn large_media_switch_cancels_by_content_length() {
+        let rules = parse_filter_list("||ads.example.net^");
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let mut matcher = Matcher::new(&snapshot);
+
+        let video = RequestContext {
+            url: "https://media.other.net/clip.mp4",
+            req_host: "media.other.net",
+            req_etld1: "other.net",
+            site_host: "example.com",
+            site_etld1: "example.com",
+            is_third_party: true,
+            request_type: RequestType::MEDIA,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+        let script = RequestContext {
+            request_type: RequestType::SCRIPT,
+            ..video
+        };
+        let length = (DEFAULT_LARGE_MEDIA_SIZE + 1).to_string();
+        let large = [ResponseHeader {
+            name: "Content-Length",
+            value: &length,
+        }];
+        let small = [ResponseHeader {
+            name: "content-length",
+            value: "1024",
+        }];
+
+        assert!(!matcher.match_response_headers(&video, &large).cancel);
+
+        matcher.set_site_switches("example.com", SiteSwitches::NO_LARGE_MEDIA);
+        let result = matcher.match_response_headers(&video, &large);
+        assert!(result.cancel);
+        assert_eq!(result.rule_id, -1);
+        assert_eq!(result.site_switch, SiteSwitches::NO_LARGE_MEDIA);
+        assert!(!matcher.match_response_headers(&video, &small).cancel);
+        assert!(!matcher.match_response_headers(&video, &[]).cancel);
+        assert!(!matcher.match_response_headers(&script, &large).cancel);
+
+        matcher.set_large_media_size(512);
+        assert!(matcher.match_response_headers(&video, &small).cancel);
+        assert_eq!(matcher.match_request(&video).decision, MatchDecision::Allow);
+    }
+
+    #[test]
+    fn equal_priority_ties_go_to_the_lowest_rule_id() {
+        let ctx = RequestContext {
+            url: "https://ads.example.com/banner/0123456789abcdef.gif",
+            req_host: "ads.example.com",
+            req_etld1: "example.com",
+            site_host: "news.example.org",
+            site_etld1: "example.org",
+            is_third_party: true,
+            request_type: RequestType::IMAGE,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+        // Equally specific rules, one from the domain sets and one from the
+        // token index; whichever stage runs first, the earlier rule reports.
+        for list in ["banner/0123456789abcdef\n||ads.example.com^", "||ads.example.com^\nbanner/0123456789abcdef"] {
+            let rules = parse_filter_list(list);
+            let bytes = build_snapshot(&rules);
+            let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+            let result = Matcher::new(&snapshot).match_request(&ctx);
+            assert_eq!(result.decision, MatchDecision::Block);
+            assert_eq!(result.rule_id, 0, "{}", list);
+        }
+    }
+
+    #[test]
+    fn duplicate_candidates_do_not_skew_decisions() {
+        let rules = parse_filter_list("||ads.example.com^\n@@||ads.example.com^$script");
+        let mut sections = build_sections(&rules, &SnapshotOptions::default()).expect("sections should build");
+
+        // Post each rule several times under the host, as a snapshot with
+        // overlapping postings would.
+        let mut block_map = HashMap::new();
+        block_map.insert(hash_domain("ads.example.com"), vec![0, 0, 0]);
+        let mut allow_map = HashMap::new();
+        allow_map.insert(hash_domain("ads.example.com"), vec![1, 1]);
+        let mut postings = Vec::new();
+        let block_entries = map_to_posting_entries(&block_map, &mut postings);
+        let allow_entries = map_to_posting_entries(&allow_map, &mut postings);
+        let mut domain_sets = build_hashmap64(&block_entries);
+        domain_sets.extend_from_slice(&build_hashmap64(&allow_entries));
+        domain_sets.extend_from_slice(&(postings.len() as u32).to_le_bytes());
+        domain_sets.extend_from_slice(&postings);
+        let section = sections
+            .iter_mut()
+            .find(|section| section.id == SectionId::DomainSets as u16)
+            .expect("domain sets section");
+        section.data = domain_sets;
+
+        let bytes = write_snapshot(sections, SnapshotLayout::default());
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+
+        let image = RequestContext {
+            url: "https://ads.example.com/pixel.gif",
+            req_host: "ads.example.com",
+            req_etld1: "example.com",
+            site_host: "news.example.org",
+            site_etld1: "example.org",
+            is_third_party: true,
+            request_type: RequestType::IMAGE,
+            scheme: SchemeMask::HTTPS,
+            tab_id: 0,
+            frame_id: 0,
+            request_id: "0",
+        };
+        let script = RequestContext {
+            url: "https://ads.example.com/ads.js",
+            request_type: RequestType::SCRIPT,
+            ..image
+        };
+
+        let result = matcher.match_request(&image);
+        assert_eq!(result.decision, MatchDecision::Block);
+        assert_eq!(result.rule_id, 0);
+        let result = matcher.match_request(&script);
+        assert_eq!(result.decision, MatchDecision::Allow);
+        assert_eq!(result.rule_id, 1);
+    }
+
+    #[test]
+    fn builder_rejects_counts_and_strings_that_would_wrap() {
+        let mut rules = parse_filter_list("##.ad\n||ads.example.com^");
+        rules[0].cosmetic.as_mut().expect("hide rule").selector = format!(".{}", "a".repeat(MAX_STRING_BYTES));
+        let error = try_build_snapshot_with_options(&rules, &SnapshotOptions::default()).unwrap_err();
+        assert_eq!(error.code(), "str-pool-overflow");
+        assert!(matches!(error, Error::StrPoolOverflow { len, .. } if len == MAX_STRING_BYTES + 1));
+
+        rules[0].cosmetic.as_mut().expect("hide rule").selector = ".ad".to_string();
+        assert!(try_build_snapshot_with_options(&rules, &SnapshotOptions::default()).is_ok());
+
+        let too_many = Error::check_rule_count(MAX_RULES + 1).unwrap_err();
+        assert_eq!(too_many.code(), "max-rules-exceeded");
+        assert_eq!(too_many.location(), None);
+        assert!(Error::check_rule_count(MAX_RULES).is_ok());
+    }
+
+    #[test]
+    fn rule_budget_truncates_least_specific_generic_rules() {
+        let text = "##.ad\n||ads.example.com^\n/banner.\nexample.com##.promo\n@@||cdn.example.com^\n/pixel.$important";
+        let mut rules = parse_filter_list(text);
+        let options = OptimizeOptions {
+            max_rules: Some(4),
+            ..OptimizeOptions::default()
+        };
+        let stats = optimize_rules_with_options(&mut rules, &options);
+
+        let truncated: Vec<u32> = stats.truncated.iter().map(|rule| rule.line).collect();
+        assert_eq!(truncated, [1, 3]);
+        assert_eq!(stats.after, 4);
+        let lines: Vec<u32> = rules.iter().map(|rule| rule.line).collect();
+        assert_eq!(lines, [2, 4, 5, 6]);
+
+        let mut diagnostics = CompileDiagnostics::default();
+        diagnostics.record_optimize(&stats);
+        assert_eq!(diagnostics.truncated, stats.truncated);
+        assert!(!diagnostics.is_empty());
+
+        // Nothing generic is left to cut, so the budget is overrun.
+        let stats = optimize_rules_with_options(&mut rules, &OptimizeOptions { max_rules: Some(1), ..options });
+        assert_eq!(stats.truncated.len(), 1);
+        assert_eq!(rules.len(), 3);
+
+        let mut rules = parse_filter_list(text);
+        assert!(optimize_rules(&mut rules).truncated.is_empty());
+        assert_eq!(rules.len(), 6);
+    }
+
+    #[test]
+    #[cfg(feature = "audit")]
+    fn audit_log_keeps_the_last_decisions() {
+        let rules = parse_filter_list("||ads.example^\n@@||ads.example/ok/");
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let mut matcher = Matcher::new(&snapshot);
+        matcher.set_audit_capacity(2);
+
+        let urls = ["https://ads.example/a.png", "https://ads.example/ok/b.png", "https://site.example/c.png"];
+        let results: Vec<_> = urls
+            .iter()
+            .map(|url| {
+                let request = RequestContext::builder(url, RequestType::IMAGE).build().expect("valid request");
+                matcher.match_request(&request.context())
+            })
+            .collect();
+
+        let recent = matcher.recent_decisions();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].url, urls[1]);
+        assert_eq!(recent[0].decision, MatchDecision::Allow);
+        assert_eq!(recent[0].rule_id, results[1].rule_id);
+        assert!(recent[0].rule_id >= 0);
+        assert_eq!(recent[1].url, urls[2]);
+        assert_eq!(recent[1].rule_id, -1);
+        assert_eq!(recent[1].request_type, RequestType::IMAGE);
+        assert!(recent[0].timestamp_ms > 0 && recent[0].timestamp_ms <= recent[1].timestamp_ms);
+
+        matcher.clear_recent_decisions();
+        assert!(matcher.recent_decisions().is_empty());
+
+        // Firmware without a system clock supplies its own; resizing keeps it.
+        matcher.set_audit_clock(|| 42);
+        matcher.set_audit_capacity(4);
+        let request = RequestContext::builder(urls[2], RequestType::IMAGE).build().expect("valid request");
+        matcher.match_request(&request.context());
+        assert_eq!(matcher.recent_decisions()[0].timestamp_ms, 42);
+
+        matcher.set_audit_capacity(0);
+        let request = RequestContext::builder(urls[0], RequestType::IMAGE).build().expect("valid request");
+        assert_eq!(matcher.match_request(&request.context()).decision, MatchDecision::Block);
+        assert!(matcher.recent_decisions().is_empty());
+    }
+
+    #[test]
+    fn cosmetic_domains_use_the_most_specific_entry() {
+        let rules = parse_filter_list(
+            "example.com,~sub.example.com,deep.sub.example.com##.ad\n\
+             example.*,~example.co.uk##.promo\n\
+             shop.example.com,~shop.example.com##.banner",
+        );
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let engine = Engine::new(&snapshot);
+        let hides = |url: &str, selector: &str| {
+            let request = RequestInfo::new(url, RequestType::MAIN_FRAME, None);
+            engine.cosmetics_for_request(&request).css.concat().contains(selector)
+        };
+
+        assert!(hides("https://example.com/", ".ad"));
+        assert!(hides("https://www.example.com/", ".ad"));
+        assert!(!hides("https://sub.example.com/", ".ad"));
+        assert!(!hides("https://a.sub.example.com/", ".ad"));
+        assert!(hides("https://deep.sub.example.com/", ".ad"));
+        assert!(hides("https://x.deep.sub.example.com/", ".ad"));
+
+        assert!(hides("https://example.de/", ".promo"));
+        assert!(!hides("https://www.example.co.uk/", ".promo"));
+
+        // Excluding and including the same name: the exclusion wins.
+        assert!(!hides("https://shop.example.com/", ".banner"));
+    }
+
+    #[test]
+    fn snapshots_from_older_builds_still_load() {
+        let filters = include_str!("../../../testdata/compat/filters.txt");
+        let current = build_snapshot(&parse_filter_list(filters));
+        let fixtures: [(u16, &[u8]); 4] = [
+            (1, include_bytes!("../../../testdata/compat/v1.ubx")),
+            (2, include_bytes!("../../../testdata/compat/v2.ubx")),
+            (3, include_bytes!("../../../testdata/compat/v3.ubx")),
+            (4, include_bytes!("../../../testdata/compat/v4.ubx")),
+        ];
+        let requests = [
+            ("https://ads.example.com/x.js", RequestType::SCRIPT, "https://site.example/"),
+            ("https://ads.example.com/allowed/a.js", RequestType::SCRIPT, "https://site.example/"),
+            ("https://tracker.example/t.gif", RequestType::IMAGE, "https://site.example/"),
+            ("https://tracker.example/t.gif", RequestType::IMAGE, "https://tracker.example/"),
+            ("https://cdn.example/ads/x.js", RequestType::SCRIPT, "https://site.example/"),
+            ("https://cdn.example/ads/x.png", RequestType::IMAGE, "https://site.example/"),
+            ("https://cdn.example/ads/ok.js", RequestType::SCRIPT, "https://site.example/"),
+            ("https://img.example/banner/a.png", RequestType::IMAGE, "https://img.example/"),
+            ("https://img.example/pixel.gif", RequestType::IMAGE, "https://news.example/"),
+            ("https://img.example/pixel.gif", RequestType::IMAGE, "https://sports.news.example/"),
+            ("https://img.example/pixel.gif", RequestType::IMAGE, "https://other.example/"),
+            ("https://media.example/a.js", RequestType::SCRIPT, "https://site.example/"),
+            ("https://site.example/?utm_source=x&a=1", RequestType::SUBDOCUMENT, "https://site.example/"),
+        ];
+        let pages = ["https://example.com/", "https://www.example.com/a", "https://other.example/"];
+
+        let observe = |bytes: &[u8]| {
+            let snapshot = Snapshot::load(bytes).expect("snapshot should load");
+            let matcher = Matcher::new(&snapshot);
+            let decisions: Vec<_> = requests
+                .iter()
+                .map(|&(url, request_type, initiator)| {
+                    let request = RequestContext::builder(url, request_type)
+                        .initiator(initiator)
+                        .build()
+                        .expect("valid request");
+                    let result = matcher.match_request(&request.context());
+                    (result.decision, result.redirect_url)
+                })
+                .collect();
+            // Older snapshots have no generic buckets, so their generic
+            // selectors come back with the page's own; compare what is hidden.
+            let keys = [hash_token(".sponsored"), hash_token(".ad-banner")];
+            let hidden: Vec<Vec<String>> = pages
+                .iter()
+                .map(|&page| {
+                    let request = RequestContext::builder(page, RequestType::MAIN_FRAME).build().expect("valid request");
+                    let mut css = matcher.match_cosmetics(&request.context()).css;
+                    css.push(matcher.match_cosmetics_generic(&request.context(), &keys));
+                    let mut selectors: Vec<String> = css
+                        .iter()
+                        .filter_map(|block| block.split('{').next())
+                        .flat_map(|selectors| selectors.split(','))
+                        .map(|selector| selector.trim().to_string())
+                        .filter(|selector| !selector.is_empty())
+                        .collect();
+                    selectors.sort();
+                    selectors
+                })
+                .collect();
+            (snapshot.version, decisions, hidden)
+        };
+
+        let (version, expected_decisions, expected_hidden) = observe(&current);
+        assert_eq!(version, UBX_VERSION);
+        assert!(expected_hidden[0].contains(&".sponsored".to_string()));
+        for (fixture_version, bytes) in fixtures {
+            let (version, decisions, hidden) = observe(bytes);
+            assert_eq!(version, fixture_version);
+            assert_eq!(decisions, expected_decisions, "v{} decisions", version);
+            assert_eq!(hidden, expected_hidden, "v{} cosmetics", version);
+        }
+    }
 }
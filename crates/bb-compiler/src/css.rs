@@ -0,0 +1,261 @@
+//! CSS selector validation for cosmetic filters
+//!
+//! Hide selectors are injected as `sel, sel, ... {display:none !important;}`
+//! and browsers drop a whole rule when one of its selectors fails to parse.
+//! Selectors are run through Servo's `selectors` parser at compile time so
+//! the ones no browser could apply are dropped with a diagnostic instead of
+//! taking their neighbours down on the page.
+//!
+//! Standard pseudo-classes and pseudo-elements are accepted, as are
+//! vendor-prefixed ones (`:-webkit-autofill`, `::-moz-selection`) since lists
+//! target them per engine. Anything else, including the ABP extended syntax
+//! (`:-abp-has()`, `:-abp-contains()`), is rejected.
+
+use std::borrow::Borrow;
+use std::fmt;
+
+use cssparser::{BasicParseErrorKind, CowRcStr, ParseError, ParseErrorKind, ParserInput, SourceLocation, ToCss};
+use precomputed_hash::PrecomputedHash;
+use selectors::parser::{NonTSPseudoClass, ParseRelative, PseudoElement, SelectorParseErrorKind};
+use selectors::{Parser, SelectorImpl, SelectorList};
+
+/// Why `selector` would not parse in a browser, or `None` if it would.
+pub fn selector_parse_error(selector: &str) -> Option<String> {
+    let mut input = ParserInput::new(selector);
+    let mut parser = cssparser::Parser::new(&mut input);
+    match SelectorList::<HideSelectors>::parse(&HideSelectorParser, &mut parser, ParseRelative::No) {
+        Ok(_) => parser.expect_exhausted().err().map(|err| describe_basic(&err.kind)),
+        Err(err) => Some(describe(&err)),
+    }
+}
+
+fn describe(err: &ParseError<'_, SelectorParseErrorKind<'_>>) -> String {
+    match &err.kind {
+        ParseErrorKind::Basic(kind) => describe_basic(kind),
+        ParseErrorKind::Custom(kind) => match kind {
+            SelectorParseErrorKind::EmptySelector => "empty selector".to_string(),
+            SelectorParseErrorKind::DanglingCombinator => "dangling combinator".to_string(),
+            SelectorParseErrorKind::UnsupportedPseudoClassOrElement(name) => {
+                format!("unsupported pseudo-class or pseudo-element '{}'", name)
+            }
+            SelectorParseErrorKind::ExpectedNamespace(prefix) => format!("undeclared namespace '{}'", prefix),
+            SelectorParseErrorKind::ClassNeedsIdent(_) => "class name expected after '.'".to_string(),
+            SelectorParseErrorKind::NoIdentForPseudo(_) | SelectorParseErrorKind::PseudoElementExpectedIdent(_) => {
+                "name expected after ':'".to_string()
+            }
+            SelectorParseErrorKind::NoQualifiedNameInAttributeSelector(_)
+            | SelectorParseErrorKind::UnexpectedTokenInAttributeSelector(_)
+            | SelectorParseErrorKind::ExpectedBarInAttr(_)
+            | SelectorParseErrorKind::BadValueInAttr(_)
+            | SelectorParseErrorKind::InvalidQualNameInAttr(_) => "malformed attribute selector".to_string(),
+            _ => "invalid selector".to_string(),
+        },
+    }
+}
+
+fn describe_basic(kind: &BasicParseErrorKind<'_>) -> String {
+    match kind {
+        BasicParseErrorKind::EndOfInput => "unexpected end of selector".to_string(),
+        BasicParseErrorKind::UnexpectedToken(token) => {
+            let mut text = String::new();
+            let _ = token.to_css(&mut text);
+            format!("unexpected '{}'", text)
+        }
+        _ => "invalid selector".to_string(),
+    }
+}
+
+/// Pseudo-classes the `selectors` parser leaves to the embedder.
+const PSEUDO_CLASSES: &[&str] = &[
+    "active",
+    "any-link",
+    "autofill",
+    "blank",
+    "checked",
+    "default",
+    "defined",
+    "disabled",
+    "enabled",
+    "focus",
+    "focus-visible",
+    "focus-within",
+    "fullscreen",
+    "hover",
+    "in-range",
+    "indeterminate",
+    "invalid",
+    "link",
+    "modal",
+    "open",
+    "optional",
+    "out-of-range",
+    "paused",
+    "picture-in-picture",
+    "placeholder-shown",
+    "playing",
+    "popover-open",
+    "read-only",
+    "read-write",
+    "required",
+    "target",
+    "user-invalid",
+    "user-valid",
+    "valid",
+    "visited",
+];
+
+const FUNCTIONAL_PSEUDO_CLASSES: &[&str] = &["dir", "lang", "state"];
+
+const PSEUDO_ELEMENTS: &[&str] = &[
+    "after",
+    "backdrop",
+    "before",
+    "cue",
+    "file-selector-button",
+    "first-letter",
+    "first-line",
+    "marker",
+    "placeholder",
+    "selection",
+];
+
+fn is_vendor_prefixed(name: &str) -> bool {
+    name.starts_with("-webkit-") || name.starts_with("-moz-") || name.starts_with("-ms-")
+}
+
+/// CSS-1 pseudo-elements may also be written with a single colon.
+fn is_legacy_pseudo_element(name: &str) -> bool {
+    matches!(name, "before" | "after" | "first-letter" | "first-line")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HideSelectors;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct CssString(String);
+
+impl From<&str> for CssString {
+    fn from(value: &str) -> Self {
+        Self(value.to_string())
+    }
+}
+
+impl Borrow<str> for CssString {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl ToCss for CssString {
+    fn to_css<W: fmt::Write>(&self, dest: &mut W) -> fmt::Result {
+        dest.write_str(&self.0)
+    }
+}
+
+impl PrecomputedHash for CssString {
+    fn precomputed_hash(&self) -> u32 {
+        0
+    }
+}
+
+/// A pseudo-class or pseudo-element, kept by name only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pseudo(String);
+
+impl ToCss for Pseudo {
+    fn to_css<W: fmt::Write>(&self, dest: &mut W) -> fmt::Result {
+        dest.write_str(&self.0)
+    }
+}
+
+impl NonTSPseudoClass for Pseudo {
+    type Impl = HideSelectors;
+
+    fn is_active_or_hover(&self) -> bool {
+        self.0 == ":active" || self.0 == ":hover"
+    }
+
+    fn is_user_action_state(&self) -> bool {
+        self.is_active_or_hover() || self.0 == ":focus"
+    }
+}
+
+impl PseudoElement for Pseudo {
+    type Impl = HideSelectors;
+}
+
+impl SelectorImpl for HideSelectors {
+    type ExtraMatchingData<'a> = ();
+    type AttrValue = CssString;
+    type Identifier = CssString;
+    type LocalName = CssString;
+    type NamespaceUrl = CssString;
+    type NamespacePrefix = CssString;
+    type BorrowedNamespaceUrl = str;
+    type BorrowedLocalName = str;
+    type NonTSPseudoClass = Pseudo;
+    type PseudoElement = Pseudo;
+}
+
+struct HideSelectorParser;
+
+impl<'i> Parser<'i> for HideSelectorParser {
+    type Impl = HideSelectors;
+    type Error = SelectorParseErrorKind<'i>;
+
+    fn parse_nth_child_of(&self) -> bool {
+        true
+    }
+
+    fn parse_is_and_where(&self) -> bool {
+        true
+    }
+
+    fn parse_has(&self) -> bool {
+        true
+    }
+
+    fn parse_host(&self) -> bool {
+        true
+    }
+
+    fn parse_non_ts_pseudo_class(
+        &self,
+        location: SourceLocation,
+        name: CowRcStr<'i>,
+    ) -> Result<Pseudo, ParseError<'i, Self::Error>> {
+        let lower = name.to_ascii_lowercase();
+        if PSEUDO_CLASSES.contains(&lower.as_str()) || is_vendor_prefixed(&lower) {
+            return Ok(Pseudo(format!(":{}", lower)));
+        }
+        Err(location.new_custom_error(SelectorParseErrorKind::UnsupportedPseudoClassOrElement(name)))
+    }
+
+    fn parse_non_ts_functional_pseudo_class<'t>(
+        &self,
+        name: CowRcStr<'i>,
+        parser: &mut cssparser::Parser<'i, 't>,
+        _after_part: bool,
+    ) -> Result<Pseudo, ParseError<'i, Self::Error>> {
+        let lower = name.to_ascii_lowercase();
+        if !FUNCTIONAL_PSEUDO_CLASSES.contains(&lower.as_str()) && !is_vendor_prefixed(&lower) {
+            return Err(parser.new_custom_error(SelectorParseErrorKind::UnsupportedPseudoClassOrElement(name)));
+        }
+        // Arguments aren't checked beyond being present.
+        parser.next()?;
+        while parser.next().is_ok() {}
+        Ok(Pseudo(format!(":{}()", lower)))
+    }
+
+    fn parse_pseudo_element(
+        &self,
+        location: SourceLocation,
+        name: CowRcStr<'i>,
+    ) -> Result<Pseudo, ParseError<'i, Self::Error>> {
+        let lower = name.to_ascii_lowercase();
+        if PSEUDO_ELEMENTS.contains(&lower.as_str()) || is_legacy_pseudo_element(&lower) || is_vendor_prefixed(&lower) {
+            return Ok(Pseudo(format!("::{}", lower)));
+        }
+        Err(location.new_custom_error(SelectorParseErrorKind::UnsupportedPseudoClassOrElement(name)))
+    }
+}
@@ -0,0 +1,165 @@
+//! Compile diagnostics
+//!
+//! Collects what the parser and optimizer threw away and why, so list
+//! maintainers and `bb-cli check` can see more than aggregate counts.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::optimizer::{OptimizeStats, OptimizeWarning};
+
+/// Why a filter line produced no rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DropReason {
+    /// Option name the parser does not understand (e.g. `$ping2`)
+    UnsupportedOption(String),
+    /// Known option with a malformed value (e.g. `domain=` with no hosts)
+    InvalidOption(String),
+    /// `domain=` entry that is not a hostname
+    InvalidDomain(String),
+    /// More than one of `$csp`, `$header`, `$removeparam`, `$replace`
+    ConflictingOptions,
+    /// Type/party/scheme options that exclude every request
+    EmptyMask,
+    /// `$elemhide`/`$generichide`/`$specifichide`/`$genericblock` outside of a
+    /// plain exception
+    MisplacedCosmeticOption,
+    /// Cosmetic syntax that none of the cosmetic parsers accepted
+    UnsupportedCosmetic,
+    /// Hide or `:style()` selector a browser's CSS parser would reject; the
+    /// message says why
+    InvalidSelector(String),
+    /// Procedural operator with a malformed argument (e.g. `:upward(0)`)
+    InvalidProcedural(String),
+    /// Network pattern the pattern parser rejected
+    UnsupportedPattern,
+    /// `/.../` filter the regex engine cannot compile
+    InvalidRegex,
+    /// `$redirect=` / `$redirect-rule=` target that is not a bundled resource
+    UnknownRedirect(String),
+    /// Option only a DNS filter can evaluate (e.g. `$dnsrewrite`); skipped
+    /// by policy rather than unsupported
+    DnsOption(String),
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedOption(name) => write!(f, "unsupported option '{}'", name),
+            Self::InvalidOption(name) => write!(f, "invalid value for option '{}'", name),
+            Self::InvalidDomain(domain) => write!(f, "invalid domain '{}'", domain),
+            Self::ConflictingOptions => f.write_str("conflicting csp/header/removeparam/replace options"),
+            Self::EmptyMask => f.write_str("options exclude every request"),
+            Self::MisplacedCosmeticOption => {
+                f.write_str("elemhide/generichide/specifichide/genericblock are only valid on plain exceptions")
+            }
+            Self::UnsupportedCosmetic => f.write_str("unsupported cosmetic filter"),
+            Self::InvalidSelector(issue) => write!(f, "invalid CSS selector: {}", issue),
+            Self::InvalidProcedural(op) => write!(f, "invalid argument to procedural operator ':{}()'", op),
+            Self::UnsupportedPattern => f.write_str("unsupported pattern"),
+            Self::InvalidRegex => f.write_str("invalid regex"),
+            Self::UnknownRedirect(name) => write!(f, "unknown redirect resource '{}'", name),
+            Self::DnsOption(name) => write!(f, "DNS-only option '{}'", name),
+        }
+    }
+}
+
+/// A source line that was dropped during parsing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DroppedLine {
+    pub list_id: u16,
+    /// 1-based line number within the list
+    pub line: u32,
+    pub text: String,
+    pub reason: DropReason,
+}
+
+/// A rule removed by dedupe, with where its surviving twin came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DuplicateRule {
+    pub list_id: u16,
+    pub line: u32,
+    pub original_list_id: u16,
+    pub original_line: u32,
+}
+
+/// A generic rule dropped to fit `OptimizeOptions::max_rules`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TruncatedRule {
+    pub list_id: u16,
+    pub line: u32,
+}
+
+/// Everything the compiler dropped or flagged for a set of lists.
+#[derive(Debug, Clone, Default)]
+pub struct CompileDiagnostics {
+    /// Unsupported option name -> number of lines it appeared on
+    pub unsupported_options: BTreeMap<String, usize>,
+    /// Legacy option (e.g. `object-subrequest`) mapped onto a modern type or
+    /// ignored -> number of rules it appeared on
+    pub legacy_options: BTreeMap<String, usize>,
+    pub dropped_lines: Vec<DroppedLine>,
+    pub duplicates: Vec<DuplicateRule>,
+    pub warnings: Vec<OptimizeWarning>,
+    /// Rules cut to fit the rule budget, least specific first
+    pub truncated: Vec<TruncatedRule>,
+}
+
+impl CompileDiagnostics {
+    pub fn is_empty(&self) -> bool {
+        self.dropped_lines.is_empty()
+            && self.duplicates.is_empty()
+            && self.warnings.is_empty()
+            && self.truncated.is_empty()
+    }
+
+    /// Lines skipped for DNS-only options. These are out of scope for a
+    /// browser blocker, so coverage figures should leave them out.
+    pub fn dns_only_lines(&self) -> usize {
+        self.dropped_lines
+            .iter()
+            .filter(|dropped| matches!(dropped.reason, DropReason::DnsOption(_)))
+            .count()
+    }
+
+    pub(crate) fn note_legacy_option(&mut self, name: &str) {
+        *self.legacy_options.entry(name.to_string()).or_insert(0) += 1;
+    }
+
+    pub(crate) fn drop_line(&mut self, line: u32, text: &str, reason: DropReason) {
+        if let DropReason::UnsupportedOption(name) = &reason {
+            *self.unsupported_options.entry(name.clone()).or_insert(0) += 1;
+        }
+        self.dropped_lines.push(DroppedLine {
+            list_id: 0,
+            line,
+            text: text.to_string(),
+            reason,
+        });
+    }
+
+    /// Fold in the diagnostics of one list parsed with
+    /// `parse_filter_list_with_diagnostics`, tagging them with `list_id`.
+    pub fn merge(&mut self, list_id: u16, other: CompileDiagnostics) {
+        for (name, count) in other.unsupported_options {
+            *self.unsupported_options.entry(name).or_insert(0) += count;
+        }
+        for (name, count) in other.legacy_options {
+            *self.legacy_options.entry(name).or_insert(0) += count;
+        }
+        self.dropped_lines.extend(other.dropped_lines.into_iter().map(|mut dropped| {
+            dropped.list_id = list_id;
+            dropped
+        }));
+        self.duplicates.extend(other.duplicates);
+        self.warnings.extend(other.warnings);
+        self.truncated.extend(other.truncated);
+    }
+
+    /// Record what `optimize_rules` removed.
+    pub fn record_optimize(&mut self, stats: &OptimizeStats) {
+        self.duplicates.extend_from_slice(&stats.duplicates);
+        self.warnings.extend_from_slice(&stats.warnings);
+        self.truncated.extend_from_slice(&stats.truncated);
+    }
+}
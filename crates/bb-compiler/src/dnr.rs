@@ -0,0 +1,781 @@
+//! Declarative Net Request export
+//!
+//! Converts compiled network rules into a Chrome `declarativeNetRequest`
+//! static ruleset, so the lists behind a snapshot can also drive an MV3
+//! ruleset. Cosmetic rules have no DNR form and are only counted.
+//!
+//! DNR breaks ties between actions differently from the matcher (at equal
+//! priority `allow` beats `block`, which beats `redirect`), so uBO's
+//! precedence is rebuilt from priorities:
+//!
+//! | rule                                   | priority |
+//! |----------------------------------------|----------|
+//! | block, `$csp`, `$removeheader`         | 1        |
+//! | `$redirect=`, `$removeparam`           | 2        |
+//! | `@@` exception                         | 3        |
+//! | `$important` block                     | 4        |
+//! | `$important` redirect                  | 5        |
+//! | `@@...$important`                      | 6        |
+//!
+//! Patterns become `urlFilter`s. Those DNR can't take as a `urlFilter`
+//! (non-ASCII, or a `|` that is not an anchor) fall back to an equivalent
+//! `regexFilter`. Anything else that can't be expressed is reported with a
+//! reason instead of being approximated.
+
+use std::fmt;
+
+use bb_core::types::{PartyMask, RequestType, RuleAction, RuleFlags, SchemeMask};
+use serde::Serialize;
+
+use crate::parser::{AnchorType, CompiledRule};
+use crate::redirects::lookup_redirect;
+
+pub const PRIORITY_BLOCK: u32 = 1;
+pub const PRIORITY_REDIRECT: u32 = 2;
+pub const PRIORITY_ALLOW: u32 = 3;
+pub const PRIORITY_IMPORTANT_BLOCK: u32 = 4;
+pub const PRIORITY_IMPORTANT_REDIRECT: u32 = 5;
+pub const PRIORITY_IMPORTANT_ALLOW: u32 = 6;
+
+/// Chrome's cap on `regexFilter` rules per extension.
+pub const MAX_REGEX_RULES: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct DnrOptions {
+    /// Id of the first rule; later rules count up from it
+    pub first_id: u32,
+    /// Redirect to the resource's `data:` URL instead of its extension path
+    pub redirect_data_urls: bool,
+}
+
+impl Default for DnrOptions {
+    fn default() -> Self {
+        Self {
+            first_id: 1,
+            redirect_data_urls: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DnrRule {
+    pub id: u32,
+    pub priority: u32,
+    pub action: DnrAction,
+    pub condition: DnrCondition,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnrAction {
+    #[serde(rename = "type")]
+    pub action_type: DnrActionType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<DnrRedirect>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub request_headers: Vec<DnrHeaderInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub response_headers: Vec<DnrHeaderInfo>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DnrActionType {
+    Block,
+    Allow,
+    AllowAllRequests,
+    Redirect,
+    ModifyHeaders,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnrRedirect {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extension_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transform: Option<DnrTransform>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnrTransform {
+    pub query_transform: DnrQueryTransform,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnrQueryTransform {
+    pub remove_params: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DnrHeaderInfo {
+    pub header: String,
+    pub operation: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnrCondition {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url_filter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub regex_filter: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub is_url_filter_case_sensitive: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub request_domains: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub initiator_domains: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub excluded_initiator_domains: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub resource_types: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain_type: Option<&'static str>,
+}
+
+/// Why a network rule has no DNR equivalent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnrSkipReason {
+    /// `$redirect-rule=` only redirects what something else blocked
+    RedirectRule,
+    /// `$header=` matches on request or response headers
+    HeaderMatch,
+    /// `$replace=` rewrites response bodies
+    Replace,
+    /// `$removeparam` with a regex or negated name
+    RemoveparamPattern,
+    /// `@@...$csp`, `@@...$removeparam` and `@@...$removeheader` cancel
+    /// specific modifications, which DNR can't target
+    ModifierException,
+    /// `@@...$genericblock`; DNR has no notion of generic rules
+    GenericBlock,
+    /// `$csp` with no policy
+    EmptyCsp,
+    /// `$http`, `$https` and the other scheme options
+    Scheme,
+    /// Regex using syntax RE2 lacks (lookaround, backreferences)
+    UnsupportedRegex,
+    /// Past [`MAX_REGEX_RULES`]
+    RegexLimit,
+    /// Action with no DNR counterpart
+    UnsupportedAction,
+    /// `$expires=`; a static ruleset can't retire the rule on time
+    Expires,
+    /// `$domain=example.*`; DNR domain lists take exact hosts only
+    WildcardDomain,
+    /// `$popup`; DNR never sees the tab a page opens
+    Popup,
+}
+
+impl DnrSkipReason {
+    /// Stable identifier for reports.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::RedirectRule => "redirect-rule",
+            Self::HeaderMatch => "header-match",
+            Self::Replace => "replace",
+            Self::RemoveparamPattern => "removeparam-pattern",
+            Self::ModifierException => "modifier-exception",
+            Self::GenericBlock => "genericblock",
+            Self::EmptyCsp => "empty-csp",
+            Self::Scheme => "scheme",
+            Self::UnsupportedRegex => "unsupported-regex",
+            Self::RegexLimit => "regex-limit",
+            Self::UnsupportedAction => "unsupported-action",
+            Self::Expires => "expires",
+            Self::WildcardDomain => "wildcard-domain",
+            Self::Popup => "popup",
+        }
+    }
+}
+
+impl fmt::Display for DnrSkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::RedirectRule => "$redirect-rule depends on another rule's block",
+            Self::HeaderMatch => "$header matches HTTP headers",
+            Self::Replace => "$replace rewrites response bodies",
+            Self::RemoveparamPattern => "$removeparam with a regex or negation",
+            Self::ModifierException => "exception for a single modifier",
+            Self::GenericBlock => "$genericblock exception",
+            Self::EmptyCsp => "$csp without a policy",
+            Self::Scheme => "scheme options",
+            Self::UnsupportedRegex => "regex uses lookaround or backreferences",
+            Self::RegexLimit => "over the regexFilter rule limit",
+            Self::UnsupportedAction => "action has no DNR equivalent",
+            Self::Expires => "$expires rules can't be retired by a static ruleset",
+            Self::WildcardDomain => "$domain entry with a wildcard TLD",
+            Self::Popup => "$popup needs the tabs API",
+        })
+    }
+}
+
+/// A network rule left out of the ruleset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnrSkippedRule {
+    pub list_id: u16,
+    pub line: u32,
+    pub reason: DnrSkipReason,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DnrExport {
+    pub rules: Vec<DnrRule>,
+    pub skipped: Vec<DnrSkippedRule>,
+    /// Rules that fell back to a `regexFilter` because their pattern is not
+    /// a valid `urlFilter`
+    pub regex_fallbacks: usize,
+    /// Cosmetic, scriptlet and `$elemhide`-style rules, which DNR can't carry
+    pub non_network: usize,
+}
+
+/// Convert compiled rules into a DNR ruleset, in rule order. Expects
+/// optimized rules; `$badfilter` rules left in are ignored.
+pub fn convert_rules(rules: &[CompiledRule], options: &DnrOptions) -> DnrExport {
+    let mut export = DnrExport::default();
+    let mut regex_rules = 0usize;
+
+    for rule in rules {
+        if rule.is_badfilter {
+            continue;
+        }
+        if is_non_network(rule) {
+            export.non_network += 1;
+            continue;
+        }
+        let skip = |reason| DnrSkippedRule {
+            list_id: rule.list_id,
+            line: rule.line,
+            reason,
+        };
+
+        let (condition, fell_back) = match rule_condition(rule) {
+            Ok(condition) => condition,
+            Err(reason) => {
+                export.skipped.push(skip(reason));
+                continue;
+            }
+        };
+        let (action, priority) = match rule_action(rule, options) {
+            Ok(action) => action,
+            Err(reason) => {
+                export.skipped.push(skip(reason));
+                continue;
+            }
+        };
+        if condition.regex_filter.is_some() {
+            if regex_rules == MAX_REGEX_RULES {
+                export.skipped.push(skip(DnrSkipReason::RegexLimit));
+                continue;
+            }
+            regex_rules += 1;
+        }
+        if fell_back {
+            export.regex_fallbacks += 1;
+        }
+
+        let condition = match action.action_type {
+            DnrActionType::AllowAllRequests => DnrCondition {
+                resource_types: document_types(rule.type_mask),
+                ..condition
+            },
+            // Policies only take effect on documents.
+            DnrActionType::ModifyHeaders if rule.csp.is_some() && condition.resource_types.is_empty() => DnrCondition {
+                resource_types: vec!["main_frame", "sub_frame"],
+                ..condition
+            },
+            _ => condition,
+        };
+
+        export.rules.push(DnrRule {
+            id: options.first_id + export.rules.len() as u32,
+            priority,
+            action,
+            condition,
+        });
+    }
+
+    export
+}
+
+fn is_non_network(rule: &CompiledRule) -> bool {
+    rule.cosmetic.is_some()
+        || rule.cosmetic_style.is_some()
+        || rule.procedural.is_some()
+        || rule.scriptlet.is_some()
+        || rule.responseheader.is_some()
+        || rule.flags.intersects(RuleFlags::ELEMHIDE | RuleFlags::GENERICHIDE | RuleFlags::SPECIFICHIDE)
+}
+
+/// The rule's condition, and whether its pattern needed the regex fallback.
+fn rule_condition(rule: &CompiledRule) -> Result<(DnrCondition, bool), DnrSkipReason> {
+    if rule.expires_at.is_some() {
+        return Err(DnrSkipReason::Expires);
+    }
+    if !rule.scheme_mask.is_empty() && rule.scheme_mask != SchemeMask::ALL {
+        return Err(DnrSkipReason::Scheme);
+    }
+    if rule.type_mask == RequestType::POPUP {
+        return Err(DnrSkipReason::Popup);
+    }
+
+    let mut condition = DnrCondition {
+        resource_types: resource_types(rule.type_mask),
+        domain_type: if rule.party_mask == PartyMask::FIRST_PARTY {
+            Some("firstParty")
+        } else if rule.party_mask == PartyMask::THIRD_PARTY {
+            Some("thirdParty")
+        } else {
+            None
+        },
+        ..DnrCondition::default()
+    };
+    if let Some(constraints) = &rule.domain_constraints {
+        if constraints.include_names.iter().chain(&constraints.exclude_names).any(|name| name.ends_with(".*")) {
+            return Err(DnrSkipReason::WildcardDomain);
+        }
+        condition.initiator_domains = constraints.include_names.clone();
+        condition.excluded_initiator_domains = constraints.exclude_names.clone();
+    }
+
+    let match_case = rule.flags.contains(RuleFlags::MATCH_CASE);
+    let mut fell_back = false;
+    match &rule.pattern {
+        None => condition.request_domains = vec![rule.domain.clone()],
+        Some(pattern) if rule.flags.contains(RuleFlags::IS_REGEX) => {
+            if !is_re2_compatible(pattern) {
+                return Err(DnrSkipReason::UnsupportedRegex);
+            }
+            condition.regex_filter = Some(pattern.clone());
+            condition.is_url_filter_case_sensitive = match_case;
+        }
+        Some(pattern) => {
+            if pattern.is_ascii() && !pattern.contains('|') {
+                condition.url_filter = Some(url_filter(pattern, rule.anchor_type));
+            } else {
+                condition.regex_filter = Some(pattern_to_regex(pattern, rule.anchor_type));
+                fell_back = true;
+            }
+            condition.is_url_filter_case_sensitive = match_case;
+        }
+    }
+
+    Ok((condition, fell_back))
+}
+
+fn rule_action(rule: &CompiledRule, options: &DnrOptions) -> Result<(DnrAction, u32), DnrSkipReason> {
+    let important = rule.flags.contains(RuleFlags::IMPORTANT);
+    let action = |action_type| DnrAction {
+        action_type,
+        redirect: None,
+        request_headers: Vec::new(),
+        response_headers: Vec::new(),
+    };
+
+    match rule.action {
+        RuleAction::Block => {
+            if let Some(spec) = rule.redirect.as_deref().and_then(lookup_redirect) {
+                let redirect = if options.redirect_data_urls {
+                    DnrRedirect {
+                        extension_path: None,
+                        url: Some(spec.data_url()),
+                        transform: None,
+                    }
+                } else {
+                    DnrRedirect {
+                        extension_path: Some(format!("/resources{}", spec.path)),
+                        url: None,
+                        transform: None,
+                    }
+                };
+                let priority = if important { PRIORITY_IMPORTANT_REDIRECT } else { PRIORITY_REDIRECT };
+                return Ok((
+                    DnrAction {
+                        redirect: Some(redirect),
+                        ..action(DnrActionType::Redirect)
+                    },
+                    priority,
+                ));
+            }
+            let priority = if important { PRIORITY_IMPORTANT_BLOCK } else { PRIORITY_BLOCK };
+            Ok((action(DnrActionType::Block), priority))
+        }
+        RuleAction::Allow => {
+            if rule.flags.contains(RuleFlags::GENERICBLOCK) {
+                return Err(DnrSkipReason::GenericBlock);
+            }
+            if rule.removeparam.is_some() {
+                return Err(DnrSkipReason::ModifierException);
+            }
+            let priority = if important { PRIORITY_IMPORTANT_ALLOW } else { PRIORITY_ALLOW };
+            let action_type = if !rule.type_mask.is_empty() && RequestType::DOCUMENT.contains(rule.type_mask) {
+                DnrActionType::AllowAllRequests
+            } else {
+                DnrActionType::Allow
+            };
+            Ok((action(action_type), priority))
+        }
+        RuleAction::Removeparam => {
+            let name = rule.removeparam.as_deref().unwrap_or_default();
+            if name.starts_with('/') || name.starts_with('~') {
+                return Err(DnrSkipReason::RemoveparamPattern);
+            }
+            let redirect = DnrRedirect {
+                extension_path: None,
+                url: None,
+                transform: Some(DnrTransform {
+                    query_transform: DnrQueryTransform {
+                        remove_params: vec![name.to_string()],
+                    },
+                }),
+            };
+            Ok((
+                DnrAction {
+                    redirect: Some(redirect),
+                    ..action(DnrActionType::Redirect)
+                },
+                PRIORITY_REDIRECT,
+            ))
+        }
+        RuleAction::CspInject => {
+            if rule.flags.contains(RuleFlags::CSP_EXCEPTION) {
+                return Err(DnrSkipReason::ModifierException);
+            }
+            let policy = rule.csp.as_deref().unwrap_or_default();
+            if policy.is_empty() {
+                return Err(DnrSkipReason::EmptyCsp);
+            }
+            Ok((
+                DnrAction {
+                    response_headers: vec![DnrHeaderInfo {
+                        header: "content-security-policy".to_string(),
+                        operation: "append",
+                        value: Some(policy.to_string()),
+                    }],
+                    ..action(DnrActionType::ModifyHeaders)
+                },
+                PRIORITY_BLOCK,
+            ))
+        }
+        RuleAction::RemoveHeader => {
+            let spec = match &rule.removeheader {
+                Some(spec) if !rule.flags.contains(RuleFlags::REMOVEHEADER_EXCEPTION) => spec,
+                _ => return Err(DnrSkipReason::ModifierException),
+            };
+            let header = DnrHeaderInfo {
+                header: spec.name.clone(),
+                operation: "remove",
+                value: None,
+            };
+            let mut modify = action(DnrActionType::ModifyHeaders);
+            if spec.request {
+                modify.request_headers.push(header);
+            } else {
+                modify.response_headers.push(header);
+            }
+            Ok((modify, PRIORITY_BLOCK))
+        }
+        RuleAction::RedirectDirective => Err(DnrSkipReason::RedirectRule),
+        RuleAction::HeaderMatchBlock | RuleAction::HeaderMatchAllow => Err(DnrSkipReason::HeaderMatch),
+        RuleAction::ResponseReplace => Err(DnrSkipReason::Replace),
+        RuleAction::ResponseCancel => Err(DnrSkipReason::UnsupportedAction),
+    }
+}
+
+fn url_filter(pattern: &str, anchor_type: AnchorType) -> String {
+    match anchor_type {
+        AnchorType::Hostname => format!("||{}", pattern),
+        AnchorType::Left => format!("|{}", pattern),
+        AnchorType::None => pattern.to_string(),
+    }
+}
+
+/// `urlFilter` semantics as an RE2 regex: `*` is any run of characters and
+/// `^` a separator or the end of the URL.
+fn pattern_to_regex(pattern: &str, anchor_type: AnchorType) -> String {
+    let mut regex = String::from(match anchor_type {
+        AnchorType::Hostname => r"^[a-z][a-z0-9+.-]*://(?:[^/?#]*\.)?",
+        AnchorType::Left => "^",
+        AnchorType::None => "",
+    });
+    for ch in pattern.chars() {
+        match ch {
+            '*' => regex.push_str(".*"),
+            '^' => regex.push_str(r"(?:[^\w.%-]|$)"),
+            '\\' | '.' | '+' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '|' | '$' => {
+                regex.push('\\');
+                regex.push(ch);
+            }
+            _ => regex.push(ch),
+        }
+    }
+    regex
+}
+
+fn is_re2_compatible(regex: &str) -> bool {
+    let bytes = regex.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => {
+                if bytes.get(i + 1).is_some_and(|b| (b'1'..=b'9').contains(b)) {
+                    return false;
+                }
+                i += 2;
+                continue;
+            }
+            b'(' if regex[i..].starts_with("(?=")
+                || regex[i..].starts_with("(?!")
+                || regex[i..].starts_with("(?<=")
+                || regex[i..].starts_with("(?<!") =>
+            {
+                return false;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    true
+}
+
+/// DNR resource types for a type mask; empty (every type but `main_frame`,
+/// DNR's default) when the rule has no type options.
+fn resource_types(mask: RequestType) -> Vec<&'static str> {
+    if mask.is_empty() {
+        return Vec::new();
+    }
+    const TYPES: &[(RequestType, &str)] = &[
+        (RequestType::MAIN_FRAME, "main_frame"),
+        (RequestType::SUBDOCUMENT, "sub_frame"),
+        (RequestType::STYLESHEET, "stylesheet"),
+        (RequestType::SCRIPT, "script"),
+        (RequestType::IMAGE, "image"),
+        (RequestType::FONT, "font"),
+        (RequestType::OBJECT, "object"),
+        (RequestType::XMLHTTPREQUEST, "xmlhttprequest"),
+        (RequestType::FETCH, "xmlhttprequest"),
+        (RequestType::PING, "ping"),
+        (RequestType::BEACON, "ping"),
+        (RequestType::CSP_REPORT, "csp_report"),
+        (RequestType::MEDIA, "media"),
+        (RequestType::WEBSOCKET, "websocket"),
+        (RequestType::OTHER, "other"),
+        (RequestType::SPECULATIVE, "other"),
+        (RequestType::AUDIOWORKLET, "other"),
+        (RequestType::PAINTWORKLET, "other"),
+        (RequestType::MANIFEST, "other"),
+        (RequestType::XSLT, "other"),
+    ];
+    let mut types = Vec::new();
+    for &(bit, name) in TYPES {
+        if mask.intersects(bit) && !types.contains(&name) {
+            types.push(name);
+        }
+    }
+    types
+}
+
+/// `allowAllRequests` only accepts document types.
+fn document_types(mask: RequestType) -> Vec<&'static str> {
+    let mut types = Vec::new();
+    if mask.intersects(RequestType::MAIN_FRAME) {
+        types.push("main_frame");
+    }
+    if mask.intersects(RequestType::SUBDOCUMENT) {
+        types.push("sub_frame");
+    }
+    types
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_filter_list;
+
+    #[test]
+    fn network_rules_export_to_declarative_net_request() {
+        let rules = parse_filter_list(
+            "||ads.example^$third-party,domain=news.example|~shop.news.example\n\
+             /banner/*.gif$image,match-case\n\
+             @@||ads.example/ok.js$script\n\
+             @@||trusted.example^$document\n\
+             ||cdn.example/lib.js$script,redirect=noop.js\n\
+             ||cdn.example/x.js$script,redirect-rule=noop.js\n\
+             ||track.example^$removeparam=utm_source\n\
+             /^https?:\\/\\/[a-z]+\\.example\\/ad/\n\
+             ||bücher.example/ad|x\n\
+             example.com##.ad\n",
+        );
+        let mut lookahead = rules[7].clone();
+        lookahead.pattern = Some("^https?://(?!good)[a-z]+\\.example/".to_string());
+        let mut with_lookahead = rules.clone();
+        with_lookahead.push(lookahead);
+        let export = convert_rules(&with_lookahead, &DnrOptions::default());
+        assert_eq!(export.non_network, 1);
+        assert_eq!(export.regex_fallbacks, 1);
+        let skipped: Vec<_> = export.skipped.iter().map(|skip| (skip.line, skip.reason.clone())).collect();
+        assert_eq!(
+            skipped,
+            vec![(6, DnrSkipReason::RedirectRule), (8, DnrSkipReason::UnsupportedRegex)]
+        );
+        assert_eq!(export.rules.len(), 8);
+        assert!(export.rules.iter().enumerate().all(|(i, rule)| rule.id == i as u32 + 1));
+
+        let json = serde_json::to_value(&export.rules).expect("serializable");
+        assert_eq!(
+            json[0],
+            serde_json::json!({
+                "id": 1,
+                "priority": 1,
+                "action": { "type": "block" },
+                "condition": {
+                    "requestDomains": ["ads.example"],
+                    "initiatorDomains": ["news.example"],
+                    "excludedInitiatorDomains": ["shop.news.example"],
+                    "domainType": "thirdParty",
+                },
+            })
+        );
+        assert_eq!(
+            json[1]["condition"],
+            serde_json::json!({
+                "urlFilter": "/banner/*.gif",
+                "isUrlFilterCaseSensitive": true,
+                "resourceTypes": ["image"],
+            })
+        );
+
+        let allow = &export.rules[2];
+        assert_eq!((allow.action.action_type, allow.priority), (DnrActionType::Allow, PRIORITY_ALLOW));
+        assert_eq!(allow.condition.url_filter.as_deref(), Some("||ads.example/ok.js"));
+        let document = &export.rules[3];
+        assert_eq!(document.action.action_type, DnrActionType::AllowAllRequests);
+        assert_eq!(document.condition.resource_types, vec!["main_frame"]);
+
+        let redirect = &export.rules[4];
+        assert_eq!(redirect.priority, PRIORITY_REDIRECT);
+        assert_eq!(json[4]["action"]["redirect"], serde_json::json!({ "extensionPath": "/resources/redirects/noop.js" }));
+        assert_eq!(
+            json[5]["action"]["redirect"],
+            serde_json::json!({ "transform": { "queryTransform": { "removeParams": ["utm_source"] } } })
+        );
+
+        assert_eq!(
+            export.rules[6].condition.regex_filter.as_deref(),
+            Some("^https?:\\/\\/[a-z]+\\.example\\/ad")
+        );
+        let fallback = &export.rules[7];
+        assert_eq!(fallback.condition.url_filter, None);
+        let regex = fallback.condition.regex_filter.as_deref().expect("regex fallback");
+        let compiled = bb_core::regex::compile_filter_regex(regex, false).expect("valid regex");
+        assert!(compiled.is_match("https://www.bücher.example/ad|x"));
+        assert!(!compiled.is_match("https://other.example/bücher.example/ad|x"));
+
+        let embedded = convert_rules(
+            &rules,
+            &DnrOptions {
+                first_id: 100,
+                redirect_data_urls: true,
+            },
+        );
+        assert_eq!(embedded.rules[0].id, 100);
+        let data_url = embedded.rules[4].action.redirect.as_ref().and_then(|redirect| redirect.url.as_deref());
+        assert!(data_url.is_some_and(|url| url.starts_with("data:application/javascript;base64,")));
+    }
+
+    #[test]
+    fn patterns_that_are_not_url_filters_fall_back_to_regex() {
+        let rules = parse_filter_list(
+            "||ads.example/a|b^\n\
+             |https://shop.example/ärger*.js\n\
+             ||plain.example/ad.js\n",
+        );
+        let export = convert_rules(&rules, &DnrOptions::default());
+        assert_eq!(export.regex_fallbacks, 2);
+        assert!(export.skipped.is_empty());
+
+        let regex = |index: usize| {
+            let rule: &DnrRule = &export.rules[index];
+            assert_eq!(rule.condition.url_filter, None);
+            let source = rule.condition.regex_filter.as_deref().expect("regex fallback");
+            bb_core::regex::compile_filter_regex(source, false).expect("valid regex")
+        };
+        let pipe = regex(0);
+        assert!(pipe.is_match("https://ads.example/a|b"));
+        assert!(pipe.is_match("https://cdn.ads.example/a|b?x=1"));
+        assert!(!pipe.is_match("https://ads.example/a|bc"));
+        assert!(!pipe.is_match("https://bads.example/a|b"));
+        let non_ascii = regex(1);
+        assert!(non_ascii.is_match("https://shop.example/ärger/main.js"));
+        assert!(!non_ascii.is_match("http://shop.example/ärger.js"));
+        assert!(!non_ascii.is_match("https://cdn.example/?u=https://shop.example/ärger.js"));
+
+        assert_eq!(export.rules[2].condition.url_filter.as_deref(), Some("||plain.example/ad.js"));
+        assert_eq!(export.rules[2].condition.regex_filter, None);
+    }
+
+    #[test]
+    fn regex_rules_stop_at_the_limit() {
+        let mut list: String = (0..=MAX_REGEX_RULES).map(|i| format!("/ad{}[0-9]+\\.js/\n", i)).collect();
+        list.push_str("||bücher.example^\n||ads.example^\n");
+        let rules = parse_filter_list(&list);
+        let export = convert_rules(&rules, &DnrOptions::default());
+
+        let regex_rules = export.rules.iter().filter(|rule| rule.condition.regex_filter.is_some()).count();
+        assert_eq!(regex_rules, MAX_REGEX_RULES);
+        // The fallback counts toward the limit too, and is only counted as
+        // a fallback when it fits.
+        let skipped: Vec<_> = export.skipped.iter().map(|skip| skip.reason.clone()).collect();
+        assert_eq!(skipped, vec![DnrSkipReason::RegexLimit, DnrSkipReason::RegexLimit]);
+        assert_eq!(export.regex_fallbacks, 0);
+        let last = export.rules.last().expect("plain rule");
+        assert_eq!(last.condition.request_domains, vec!["ads.example"]);
+    }
+
+    #[test]
+    fn allow_all_requests_keeps_only_document_types() {
+        let rules = parse_filter_list(
+            "@@||top.example^$document\n\
+             @@||frame.example^$subdocument\n\
+             @@||both.example^$document,subdocument\n\
+             @@||mixed.example^$document,script\n",
+        );
+        let export = convert_rules(&rules, &DnrOptions::default());
+        let actions: Vec<_> = export
+            .rules
+            .iter()
+            .map(|rule| (rule.action.action_type, rule.condition.resource_types.clone()))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                (DnrActionType::AllowAllRequests, vec!["main_frame"]),
+                (DnrActionType::AllowAllRequests, vec!["sub_frame"]),
+                (DnrActionType::AllowAllRequests, vec!["main_frame", "sub_frame"]),
+                // Not only documents, so a plain exception for each type
+                (DnrActionType::Allow, vec!["main_frame", "script"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn wildcard_domains_are_skipped() {
+        let rules = parse_filter_list(
+            "||ads.example^$domain=news.*\n\
+             ||ads.example^$domain=shop.example|~blog.*\n\
+             ||ads.example^$domain=shop.example\n",
+        );
+        let export = convert_rules(&rules, &DnrOptions::default());
+        let skipped: Vec<_> = export.skipped.iter().map(|skip| (skip.line, skip.reason.clone())).collect();
+        assert_eq!(skipped, vec![(1, DnrSkipReason::WildcardDomain), (2, DnrSkipReason::WildcardDomain)]);
+        assert_eq!(export.rules.len(), 1);
+        assert_eq!(export.rules[0].condition.initiator_domains, vec!["shop.example"]);
+    }
+}
@@ -0,0 +1,174 @@
+//! Structured compile errors
+//!
+//! `Error` is what callers surface to people: every variant carries a stable
+//! `code()` so UIs can pick a message or a fix-it without parsing text, and
+//! line-level variants keep the list and line they came from.
+
+use std::path::Path;
+
+use crate::diagnostics::{CompileDiagnostics, DropReason, DroppedLine};
+
+/// Largest section the format can address, since offsets inside sections
+/// are `u32`; also the largest v1 snapshot, whose directory stores `u32`
+/// section offsets and lengths.
+pub const MAX_SNAPSHOT_BYTES: usize = u32::MAX as usize;
+
+/// Most rules a snapshot can hold, since matchers report rule ids as `i32`
+/// with -1 for "no rule".
+pub const MAX_RULES: usize = i32::MAX as usize;
+
+/// Longest string the string pool can hold, since entries store `u16`
+/// lengths.
+pub const MAX_STRING_BYTES: usize = u16::MAX as usize;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read '{path}': {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("unsupported option '{option}'")]
+    UnsupportedOption { list_id: u16, line: u32, option: String },
+    #[error("invalid value for option '{option}'")]
+    InvalidOption { list_id: u16, line: u32, option: String },
+    #[error("invalid domain '{domain}'")]
+    InvalidDomain { list_id: u16, line: u32, domain: String },
+    /// Any other reason a line produced no rule
+    #[error("{reason}")]
+    DroppedLine { list_id: u16, line: u32, reason: DropReason },
+    #[error("snapshot is {size} bytes, over the {limit} byte limit")]
+    SnapshotTooLarge { size: usize, limit: usize },
+    #[error("{count} rules, over the {limit} rule limit")]
+    MaxRulesExceeded { count: usize, limit: usize },
+    /// A selector, spec or other string too long for a string pool entry
+    #[error("string of {len} bytes, over the {limit} byte string pool limit")]
+    StrPoolOverflow { len: usize, limit: usize },
+    /// Malformed document passed to `rules_from_json`
+    #[error("invalid rules JSON: {message}")]
+    RulesJson { message: String },
+    #[error("rules JSON version {found} is not supported (newest is {supported})")]
+    RulesJsonVersion { found: u32, supported: u32 },
+    #[error("custom section {id:#06x} is {reason}")]
+    CustomSection { id: u16, reason: &'static str },
+}
+
+impl Error {
+    pub fn io(path: impl AsRef<Path>, source: std::io::Error) -> Self {
+        Self::Io {
+            path: path.as_ref().display().to_string(),
+            source,
+        }
+    }
+
+    /// Stable identifier for this kind of error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Io { .. } => "io",
+            Self::UnsupportedOption { .. } => "unsupported-option",
+            Self::InvalidOption { .. } => "invalid-option",
+            Self::InvalidDomain { .. } => "invalid-domain",
+            Self::DroppedLine { reason, .. } => match reason {
+                DropReason::ConflictingOptions => "conflicting-options",
+                DropReason::EmptyMask => "empty-mask",
+                DropReason::MisplacedCosmeticOption => "misplaced-cosmetic-option",
+                DropReason::UnsupportedCosmetic => "unsupported-cosmetic",
+                DropReason::InvalidSelector(_) => "invalid-selector",
+                DropReason::InvalidProcedural(_) => "invalid-procedural",
+                DropReason::UnsupportedPattern => "unsupported-pattern",
+                DropReason::InvalidRegex => "invalid-regex",
+                DropReason::UnknownRedirect(_) => "unknown-redirect",
+                DropReason::DnsOption(_) => "dns-option",
+                DropReason::UnsupportedOption(_) => "unsupported-option",
+                DropReason::InvalidOption(_) => "invalid-option",
+                DropReason::InvalidDomain(_) => "invalid-domain",
+            },
+            Self::SnapshotTooLarge { .. } => "snapshot-too-large",
+            Self::MaxRulesExceeded { .. } => "max-rules-exceeded",
+            Self::StrPoolOverflow { .. } => "str-pool-overflow",
+            Self::RulesJson { .. } => "rules-json",
+            Self::RulesJsonVersion { .. } => "rules-json-version",
+            Self::CustomSection { .. } => "custom-section",
+        }
+    }
+
+    /// `(list_id, line)` for errors tied to a filter line.
+    pub fn location(&self) -> Option<(u16, u32)> {
+        match *self {
+            Self::UnsupportedOption { list_id, line, .. }
+            | Self::InvalidOption { list_id, line, .. }
+            | Self::InvalidDomain { list_id, line, .. }
+            | Self::DroppedLine { list_id, line, .. } => Some((list_id, line)),
+            Self::Io { .. }
+            | Self::SnapshotTooLarge { .. }
+            | Self::MaxRulesExceeded { .. }
+            | Self::StrPoolOverflow { .. }
+            | Self::RulesJson { .. }
+            | Self::RulesJsonVersion { .. }
+            | Self::CustomSection { .. } => None,
+        }
+    }
+
+    /// Fails with `SnapshotTooLarge` when `size` cannot be addressed.
+    pub fn check_snapshot_size(size: usize) -> Result<(), Self> {
+        if size > MAX_SNAPSHOT_BYTES {
+            return Err(Self::SnapshotTooLarge {
+                size,
+                limit: MAX_SNAPSHOT_BYTES,
+            });
+        }
+        Ok(())
+    }
+
+    /// Fails with `MaxRulesExceeded` when `count` rules can't get ids.
+    pub fn check_rule_count(count: usize) -> Result<(), Self> {
+        if count > MAX_RULES {
+            return Err(Self::MaxRulesExceeded {
+                count,
+                limit: MAX_RULES,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl From<&DroppedLine> for Error {
+    fn from(dropped: &DroppedLine) -> Self {
+        let (list_id, line) = (dropped.list_id, dropped.line);
+        match &dropped.reason {
+            DropReason::UnsupportedOption(option) => Self::UnsupportedOption {
+                list_id,
+                line,
+                option: option.clone(),
+            },
+            DropReason::InvalidOption(option) => Self::InvalidOption {
+                list_id,
+                line,
+                option: option.clone(),
+            },
+            DropReason::InvalidDomain(domain) => Self::InvalidDomain {
+                list_id,
+                line,
+                domain: domain.clone(),
+            },
+            reason => Self::DroppedLine {
+                list_id,
+                line,
+                reason: reason.clone(),
+            },
+        }
+    }
+}
+
+impl CompileDiagnostics {
+    /// Dropped lines as structured errors, in list/line order. Lines skipped
+    /// for DNS-only options are policy rather than mistakes and are left out.
+    pub fn errors(&self) -> Vec<Error> {
+        self.dropped_lines
+            .iter()
+            .filter(|dropped| !matches!(dropped.reason, DropReason::DnsOption(_)))
+            .map(Error::from)
+            .collect()
+    }
+}
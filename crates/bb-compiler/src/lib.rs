@@ -5,7 +5,40 @@
 pub mod parser;
 pub mod optimizer;
 pub mod builder;
+pub mod css;
+pub mod diagnostics;
+pub mod dnr;
+pub mod error;
+pub mod metadata;
+pub mod picker;
+pub mod preprocess;
+pub mod psl;
+pub mod redirects;
+pub mod rules_json;
+pub mod safari;
+pub mod scriptlets;
 
-pub use builder::build_snapshot;
-pub use optimizer::optimize_rules;
-pub use parser::{parse_filter_list, CompiledRule, DomainConstraint};
+pub use builder::{
+    build_snapshot, build_snapshot_with_extra, build_snapshot_with_options, build_snapshot_with_psl,
+    try_build_snapshot_with_options, SnapshotOptions,
+};
+pub use css::selector_parse_error;
+pub use metadata::{parse_list_metadata, BuildInfo, ListMetadata, ListSource};
+pub use picker::{
+    generate_cosmetic_rule, generate_network_rule, NetworkRuleCandidate, NetworkRuleChoices, NetworkRuleScope,
+    PickerIssue, PickerOptions, PickerRule, PickerRuleKind,
+};
+pub use preprocess::{preprocess_filter_list, IncludeResolver, NoIncludes, PreprocessEnv, Preprocessed};
+pub use psl::{parse_psl, PslRules};
+pub use redirects::{lookup_redirect, RedirectResourceSpec, REDIRECT_REGISTRY};
+pub use rules_json::{rules_from_json, rules_to_json, RuleJson, RuleSetJson, RULES_JSON_VERSION};
+pub use optimizer::{
+    optimize_rules, optimize_rules_with_options, rule_signature, OptimizeOptions, OptimizeStats, OptimizeWarning,
+};
+pub use scriptlets::{lookup_scriptlet, normalize_scriptlet, render_scriptlet, ScriptletIssue, ScriptletSignature};
+pub use diagnostics::{CompileDiagnostics, DropReason, DroppedLine, DuplicateRule, TruncatedRule};
+pub use error::{Error, MAX_RULES, MAX_SNAPSHOT_BYTES, MAX_STRING_BYTES};
+pub use parser::{
+    parse_filter_list, parse_filter_list_into, parse_filter_list_with_config, parse_filter_list_with_diagnostics,
+    CompiledRule, DnsOptionPolicy, DomainConstraint, ParserConfig, normalize_selector, selector_issue,
+};
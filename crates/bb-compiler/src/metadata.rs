@@ -0,0 +1,131 @@
+//! Filter list header metadata and build provenance
+//!
+//! Extracts the `! Title:`, `! Version:`, `! Homepage:` and `! Expires:`
+//! comment headers that ABP/uBO lists (and `#`-commented hosts files) carry
+//! before their first rule, and records where each list came from.
+
+use bb_core::hash::{hash64, Hash64};
+use bb_core::types::ListTier;
+
+/// Header metadata for a single filter list.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ListMetadata {
+    /// List this metadata belongs to (matches `CompiledRule::list_id`)
+    pub list_id: u16,
+    pub title: Option<String>,
+    pub version: Option<String>,
+    pub homepage: Option<String>,
+    /// Refresh interval from `! Expires:`, in seconds
+    pub expires_secs: Option<u32>,
+    /// Precedence over other lists' rules; never read from the list text
+    pub tier: ListTier,
+}
+
+impl ListMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.version.is_none()
+            && self.homepage.is_none()
+            && self.expires_secs.is_none()
+            && self.tier == ListTier::BuiltIn
+    }
+}
+
+/// Compile provenance embedded as a BuildInfo section, so a snapshot can be
+/// traced back to the exact list versions it was built from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// Compile time, unix seconds
+    pub compiled_at: u64,
+    pub compiler_version: String,
+    pub lists: Vec<ListSource>,
+}
+
+/// Where one list was read from and a hash of its text.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ListSource {
+    pub list_id: u16,
+    /// Path or URL the list was read from
+    pub source: String,
+    pub content_hash: Hash64,
+}
+
+impl BuildInfo {
+    /// Build info stamped with this compiler's crate version.
+    pub fn new(compiled_at: u64) -> Self {
+        Self {
+            compiled_at,
+            compiler_version: env!("CARGO_PKG_VERSION").to_string(),
+            lists: Vec::new(),
+        }
+    }
+
+    /// Record a list's source and hash its text as read, before preprocessing.
+    pub fn add_list(&mut self, list_id: u16, source: &str, text: &str) {
+        self.lists.push(ListSource {
+            list_id,
+            source: source.to_string(),
+            content_hash: hash64(text.as_bytes()),
+        });
+    }
+}
+
+/// Parse the metadata header block of a filter list.
+///
+/// Only the leading comment block is scanned; headers after the first rule
+/// are ignored, as in ABP and uBO. `list_id` is left at 0 for the caller to set.
+pub fn parse_list_metadata(text: &str) -> ListMetadata {
+    let mut meta = ListMetadata::default();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('[') {
+            continue;
+        }
+
+        let comment = match line.strip_prefix('!').or_else(|| line.strip_prefix('#')) {
+            // `##` / `#@#` etc. are cosmetic rules, not comments.
+            Some(rest) if !line.starts_with("##") && !line.starts_with("#@") => rest,
+            _ => break,
+        };
+
+        let Some((key, value)) = comment.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+
+        match key.trim().to_ascii_lowercase().as_str() {
+            "title" if meta.title.is_none() => meta.title = Some(value.to_string()),
+            "version" if meta.version.is_none() => meta.version = Some(value.to_string()),
+            "homepage" if meta.homepage.is_none() => meta.homepage = Some(value.to_string()),
+            "expires" if meta.expires_secs.is_none() => meta.expires_secs = parse_expires(value),
+            _ => {}
+        }
+    }
+
+    meta
+}
+
+/// Parse an `Expires` value such as `4 days (update frequency)` or `12 hours`.
+///
+/// A bare number is read as days, matching ABP.
+fn parse_expires(value: &str) -> Option<u32> {
+    let mut parts = value.split_whitespace();
+    let amount: u32 = parts.next()?.parse().ok()?;
+    if amount == 0 {
+        return None;
+    }
+
+    let unit_secs = match parts.next().map(|unit| unit.to_ascii_lowercase()) {
+        None => 86_400,
+        Some(unit) if unit.starts_with('(') => 86_400,
+        Some(unit) if unit.starts_with("day") => 86_400,
+        Some(unit) if unit.starts_with("hour") => 3_600,
+        Some(_) => return None,
+    };
+
+    Some(amount.saturating_mul(unit_secs))
+}
@@ -1,6 +1,34 @@
-use std::collections::HashSet;
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 
-use crate::parser::CompiledRule;
+use bb_core::hash::{hash64, Hash64};
+use bb_core::types::{RequestType, RuleAction, RuleFlags, SchemeMask};
+
+use crate::builder::rule_priority;
+use crate::diagnostics::{DuplicateRule, TruncatedRule};
+use crate::parser::{AnchorType, CompiledRule};
+use crate::scriptlets::{lookup_scriptlet, normalize_scriptlet};
+
+/// Which optional passes `optimize_rules_with_options` runs.
+#[derive(Debug, Clone, Copy)]
+pub struct OptimizeOptions {
+    /// Drop pattern block rules already covered by a host-only block rule
+    /// from the same list, e.g. `||ads.com/banner.js` under `||ads.com^`
+    pub eliminate_shadowed: bool,
+    /// Keep at most this many rules, dropping the least specific generic
+    /// ones first; see [`OptimizeStats::truncated`]
+    pub max_rules: Option<usize>,
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> Self {
+        Self {
+            eliminate_shadowed: true,
+            max_rules: None,
+        }
+    }
+}
 
 pub struct OptimizeStats {
     pub before: usize,
@@ -8,48 +36,96 @@ pub struct OptimizeStats {
     pub deduped: usize,
     pub badfilter_rules: usize,
     pub badfiltered_rules: usize,
+    pub invalid_scriptlets: usize,
+    /// Pattern rules removed because a broader host rule already blocks them
+    pub shadowed: usize,
+    pub duplicates: Vec<DuplicateRule>,
+    /// Shadowed rules, with the host rule that covers each as the original
+    pub shadowed_rules: Vec<DuplicateRule>,
+    /// Generic rules dropped to fit `OptimizeOptions::max_rules`; the
+    /// budget can still be exceeded once every generic rule is gone
+    pub truncated: Vec<TruncatedRule>,
+    pub warnings: Vec<OptimizeWarning>,
+}
+
+/// A rule the optimizer dropped, with the reason.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptimizeWarning {
+    pub list_id: u16,
+    pub rule: String,
+    pub message: String,
 }
 
 pub fn optimize_rules(rules: &mut Vec<CompiledRule>) -> OptimizeStats {
+    optimize_rules_with_options(rules, &OptimizeOptions::default())
+}
+
+pub fn optimize_rules_with_options(rules: &mut Vec<CompiledRule>, options: &OptimizeOptions) -> OptimizeStats {
     let before = rules.len();
-    let mut badfilter_keys: HashSet<BadfilterKey> = HashSet::new();
-    let mut badfilter_rules = 0usize;
+    let mut warnings = Vec::new();
+    let invalid_scriptlets = normalize_scriptlets(rules, &mut warnings);
 
-    for rule in rules.iter() {
-        if rule.is_badfilter {
-            badfilter_rules += 1;
-            badfilter_keys.insert(BadfilterKey::from(rule));
-        }
-    }
+    // Keys borrow from the rules, so each pass decides what to keep first
+    // and removes afterwards; cloning every rule's strings into a key would
+    // double peak memory on large lists.
+    let badfilter_keys: HashSet<BadfilterKey<'_>> =
+        rules.iter().filter(|rule| rule.is_badfilter).map(BadfilterKey::from).collect();
+    let badfilter_rules = rules.iter().filter(|rule| rule.is_badfilter).count();
 
     let mut badfiltered_rules = 0usize;
-    if !badfilter_keys.is_empty() {
-        rules.retain(|rule| {
+    let keep: Vec<bool> = rules
+        .iter()
+        .map(|rule| {
             if rule.is_badfilter {
                 return false;
             }
-            if badfilter_keys.contains(&BadfilterKey::from(rule)) {
+            if !badfilter_keys.is_empty() && badfilter_keys.contains(&BadfilterKey::from(rule)) {
                 badfiltered_rules += 1;
                 return false;
             }
             true
-        });
+        })
+        .collect();
+    drop(badfilter_keys);
+    retain_marked(rules, keep);
+
+    let mut seen: HashMap<RuleKey<'_>, usize> = HashMap::with_capacity(rules.len());
+    let mut duplicates = Vec::new();
+    let keep: Vec<bool> = rules
+        .iter()
+        .enumerate()
+        .map(|(idx, rule)| match seen.entry(RuleKey::from(rule)) {
+            Entry::Occupied(original) => {
+                let original = &rules[*original.get()];
+                duplicates.push(DuplicateRule {
+                    list_id: rule.list_id,
+                    line: rule.line,
+                    original_list_id: original.list_id,
+                    original_line: original.line,
+                });
+                false
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(idx);
+                true
+            }
+        })
+        .collect();
+    drop(seen);
+    retain_marked(rules, keep);
+    let deduped = duplicates.len();
+
+    let shadowed_rules = if options.eliminate_shadowed {
+        eliminate_shadowed(rules)
     } else {
-        rules.retain(|rule| !rule.is_badfilter);
-    }
+        Vec::new()
+    };
+    let shadowed = shadowed_rules.len();
 
-    let mut seen: HashSet<RuleKey> = HashSet::new();
-    let mut deduped = 0usize;
-    rules.retain(|rule| {
-        let key = RuleKey::from(rule);
-        if seen.contains(&key) {
-            deduped += 1;
-            false
-        } else {
-            seen.insert(key);
-            true
-        }
-    });
+    let truncated = match options.max_rules {
+        Some(max_rules) => truncate_generic(rules, max_rules),
+        None => Vec::new(),
+    };
 
     let after = rules.len();
 
@@ -59,89 +135,285 @@ pub fn optimize_rules(rules: &mut Vec<CompiledRule>) -> OptimizeStats {
         deduped,
         badfilter_rules,
         badfiltered_rules,
+        invalid_scriptlets,
+        shadowed,
+        duplicates,
+        shadowed_rules,
+        truncated,
+        warnings,
+    }
+}
+
+/// Keep the rules whose entry in `keep` is true.
+fn retain_marked(rules: &mut Vec<CompiledRule>, keep: Vec<bool>) {
+    let mut keep = keep.into_iter();
+    rules.retain(|_| keep.next().unwrap_or(true));
+}
+
+/// Signature shared by a network rule and its `$badfilter` version, or
+/// `None` for cosmetic, scriptlet and response-header rules, which the
+/// RuleSignatures section doesn't index.
+pub fn rule_signature(rule: &CompiledRule) -> Option<Hash64> {
+    let cosmetic = rule.cosmetic.is_some()
+        || rule.cosmetic_style.is_some()
+        || rule.procedural.is_some()
+        || rule.scriptlet.is_some()
+        || rule.responseheader.is_some();
+    if cosmetic {
+        return None;
+    }
+    Some(hash64(format!("{:?}", BadfilterKey::from(rule)).as_bytes()))
+}
+
+/// Rewrite scriptlet names to their canonical form and drop injections that
+/// the runtime could never execute. Exceptions are normalized when the name
+/// is known but never dropped, since a stale exception is harmless.
+fn normalize_scriptlets(rules: &mut Vec<CompiledRule>, warnings: &mut Vec<OptimizeWarning>) -> usize {
+    let before = rules.len();
+
+    rules.retain_mut(|rule| {
+        let Some(scriptlet) = rule.scriptlet.as_mut() else {
+            return true;
+        };
+        if scriptlet.is_exception && scriptlet.scriptlet.is_empty() {
+            return true;
+        }
+        if scriptlet.name_only {
+            if let Some(sig) = lookup_scriptlet(&scriptlet.scriptlet) {
+                scriptlet.scriptlet = sig.name.to_string();
+            }
+            return true;
+        }
+
+        match normalize_scriptlet(&scriptlet.scriptlet) {
+            Ok(normalized) => {
+                scriptlet.scriptlet = normalized;
+                true
+            }
+            Err(_) if scriptlet.is_exception => true,
+            Err(issue) => {
+                warnings.push(OptimizeWarning {
+                    list_id: rule.list_id,
+                    rule: format!("+js({})", scriptlet.scriptlet),
+                    message: issue.to_string(),
+                });
+                false
+            }
+        }
+    });
+
+    before - rules.len()
+}
+
+/// Remove `||host/path` style block rules that a plain `||host^` block rule
+/// (or one for a parent domain) already covers.
+///
+/// Only rules from the same list are compared, so toggling a list off at
+/// runtime never exposes requests a shadowed rule in another list would have
+/// blocked. The broad rule must apply at least as widely: no domain
+/// constraints, a superset of the type, party and scheme masks, and
+/// `$important` if the shadowed rule is.
+fn eliminate_shadowed(rules: &mut Vec<CompiledRule>) -> Vec<DuplicateRule> {
+    let mut broad: HashMap<(u16, &str), Vec<usize>> = HashMap::new();
+    for (idx, rule) in rules.iter().enumerate() {
+        // A host rule that expires can't stand in for rules that don't.
+        if is_plain_block(rule)
+            && rule.pattern.is_none()
+            && !rule.domain.is_empty()
+            && rule.domain_constraints.is_none()
+            && rule.expires_at.is_none()
+        {
+            broad.entry((rule.list_id, rule.domain.as_str())).or_default().push(idx);
+        }
+    }
+    if broad.is_empty() {
+        return Vec::new();
+    }
+
+    let covering: Vec<Option<usize>> = rules
+        .iter()
+        .map(|rule| {
+            if !is_plain_block(rule) || !has_host_prefix(rule) {
+                return None;
+            }
+            let mut host = rule.domain.as_str();
+            loop {
+                if let Some(candidates) = broad.get(&(rule.list_id, host)) {
+                    if let Some(&idx) = candidates.iter().find(|&&idx| covers(&rules[idx], rule)) {
+                        return Some(idx);
+                    }
+                }
+                host = host.split_once('.')?.1;
+            }
+        })
+        .collect();
+
+    let shadowed: Vec<DuplicateRule> = rules
+        .iter()
+        .zip(&covering)
+        .filter_map(|(rule, covered_by)| {
+            let original = &rules[(*covered_by)?];
+            Some(DuplicateRule {
+                list_id: rule.list_id,
+                line: rule.line,
+                original_list_id: original.list_id,
+                original_line: original.line,
+            })
+        })
+        .collect();
+
+    let mut covering = covering.into_iter();
+    rules.retain(|_| covering.next().flatten().is_none());
+    shadowed
+}
+
+/// Drop generic rules until at most `max_rules` are left, least specific
+/// (by snapshot priority) first and, among equals, later rules first.
+fn truncate_generic(rules: &mut Vec<CompiledRule>, max_rules: usize) -> Vec<TruncatedRule> {
+    let excess = rules.len().saturating_sub(max_rules);
+    if excess == 0 {
+        return Vec::new();
+    }
+
+    let mut candidates: Vec<(i16, usize)> = rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| is_truncatable(rule))
+        .map(|(idx, rule)| (rule_priority(rule), idx))
+        .collect();
+    candidates.sort_unstable_by_key(|&(priority, idx)| (priority, Reverse(idx)));
+    candidates.truncate(excess);
+
+    let mut keep = vec![true; rules.len()];
+    for &(_, idx) in &candidates {
+        keep[idx] = false;
+    }
+    let truncated = rules
+        .iter()
+        .zip(&keep)
+        .filter(|(_, &keep)| !keep)
+        .map(|(rule, _)| TruncatedRule {
+            list_id: rule.list_id,
+            line: rule.line,
+        })
+        .collect();
+    retain_marked(rules, keep);
+    truncated
+}
+
+/// Rules a rule budget may cut: hide rules that apply everywhere and block
+/// rules without `$domain=` sites. Exceptions, `$important` blocks,
+/// scriptlets and anything site-specific stay, since dropping them breaks
+/// pages rather than letting an ad through.
+fn is_truncatable(rule: &CompiledRule) -> bool {
+    if let Some(cosmetic) = &rule.cosmetic {
+        return cosmetic.is_generic && !cosmetic.is_exception;
+    }
+    rule.cosmetic_style.is_none()
+        && rule.procedural.is_none()
+        && rule.scriptlet.is_none()
+        && rule.responseheader.is_none()
+        && is_plain_block(rule)
+        && !rule.flags.contains(RuleFlags::IMPORTANT)
+        && rule.domain_constraints.as_ref().is_none_or(|constraints| constraints.include.is_empty())
+}
+
+/// A block rule with no redirect or response-phase behaviour attached.
+fn is_plain_block(rule: &CompiledRule) -> bool {
+    rule.action == RuleAction::Block
+        && rule.redirect.is_none()
+        && !rule.flags.intersects(RuleFlags::IS_REGEX | RuleFlags::FROM_REDIRECT_EQ)
+}
+
+/// `||host/...` or `||host^...`: the pattern can only match `host` and its
+/// subdomains. `||host*...` or a bare `||host` could also match `hostname.tld`.
+fn has_host_prefix(rule: &CompiledRule) -> bool {
+    let Some(pattern) = rule.pattern.as_deref() else {
+        return false;
+    };
+    if rule.anchor_type != AnchorType::Hostname || rule.domain.is_empty() {
+        return false;
+    }
+    let boundary = pattern.find(['/', '^', '*', '?', '#']);
+    boundary.is_some_and(|end| matches!(pattern.as_bytes()[end], b'/' | b'^'))
+}
+
+fn covers(broad: &CompiledRule, narrow: &CompiledRule) -> bool {
+    mask_covers(type_bits(broad.type_mask), type_bits(narrow.type_mask))
+        && mask_covers(broad.party_mask.bits().into(), narrow.party_mask.bits().into())
+        && mask_covers(scheme_bits(broad.scheme_mask), scheme_bits(narrow.scheme_mask))
+        && (broad.flags.contains(RuleFlags::IMPORTANT) || !narrow.flags.contains(RuleFlags::IMPORTANT))
+}
+
+/// An empty option mask means "unrestricted".
+fn mask_covers(broad: u32, narrow: u32) -> bool {
+    broad == 0 || (narrow != 0 && broad & narrow == narrow)
+}
+
+/// Type options spelled out: no options means every type but `$popup`.
+fn type_bits(mask: RequestType) -> u32 {
+    if mask.is_empty() {
+        RequestType::ALL.bits()
+    } else {
+        mask.bits()
+    }
+}
+
+/// Scheme options spelled out: no options means the web schemes.
+fn scheme_bits(mask: SchemeMask) -> u32 {
+    if mask.is_empty() {
+        SchemeMask::WEB.bits().into()
+    } else {
+        mask.bits().into()
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-struct RuleKey {
-    action: u8,
-    flags: u16,
-    type_mask: u32,
-    party_mask: u8,
-    scheme_mask: u8,
+struct RuleKey<'r> {
     list_id: u16,
-    domain: String,
-    pattern: Option<String>,
-    anchor_type: u8,
-    constraint_include: Vec<u64>,
-    constraint_exclude: Vec<u64>,
-    redirect: Option<String>,
-    removeparam: Option<String>,
-    csp: Option<String>,
-    header: Option<crate::parser::HeaderSpec>,
-    cosmetic: Option<crate::parser::CosmeticRule>,
-    procedural: Option<crate::parser::ProceduralRule>,
-    scriptlet: Option<crate::parser::ScriptletRule>,
-    responseheader: Option<crate::parser::ResponseHeaderRule>,
+    /// Everything a `$badfilter` version of the rule also has
+    rule: BadfilterKey<'r>,
 }
 
+// The Debug output feeds `rule_signature`, so field names and order are
+// part of the snapshot format.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-struct BadfilterKey {
+struct BadfilterKey<'r> {
     action: u8,
-    flags: u16,
+    flags: u32,
     type_mask: u32,
     party_mask: u8,
     scheme_mask: u8,
-    domain: String,
-    pattern: Option<String>,
+    domain: &'r str,
+    pattern: Option<&'r str>,
     anchor_type: u8,
     constraint_include: Vec<u64>,
     constraint_exclude: Vec<u64>,
-    redirect: Option<String>,
-    removeparam: Option<String>,
-    csp: Option<String>,
-    header: Option<crate::parser::HeaderSpec>,
-    cosmetic: Option<crate::parser::CosmeticRule>,
-    procedural: Option<crate::parser::ProceduralRule>,
-    scriptlet: Option<crate::parser::ScriptletRule>,
-    responseheader: Option<crate::parser::ResponseHeaderRule>,
-}
-
-impl From<&CompiledRule> for RuleKey {
-    fn from(rule: &CompiledRule) -> Self {
-        let (include, exclude) = match &rule.domain_constraints {
-            Some(c) => (
-                c.include.iter().map(|h| h.to_u64()).collect(),
-                c.exclude.iter().map(|h| h.to_u64()).collect(),
-            ),
-            None => (Vec::new(), Vec::new()),
-        };
+    redirect: Option<&'r str>,
+    removeparam: Option<&'r str>,
+    csp: Option<&'r str>,
+    header: Option<&'r crate::parser::HeaderSpec>,
+    replace: Option<&'r crate::parser::ReplaceSpec>,
+    removeheader: Option<&'r crate::parser::RemoveHeaderSpec>,
+    cosmetic: Option<&'r crate::parser::CosmeticRule>,
+    cosmetic_style: Option<&'r crate::parser::CosmeticStyleRule>,
+    procedural: Option<&'r crate::parser::ProceduralRule>,
+    scriptlet: Option<&'r crate::parser::ScriptletRule>,
+    responseheader: Option<&'r crate::parser::ResponseHeaderRule>,
+    expires_at: Option<u32>,
+}
+
+impl<'r> From<&'r CompiledRule> for RuleKey<'r> {
+    fn from(rule: &'r CompiledRule) -> Self {
         Self {
-            action: rule.action as u8,
-            flags: rule.flags.bits(),
-            type_mask: rule.type_mask.bits(),
-            party_mask: rule.party_mask.bits(),
-            scheme_mask: rule.scheme_mask.bits(),
             list_id: rule.list_id,
-            domain: rule.domain.clone(),
-            pattern: rule.pattern.clone(),
-            anchor_type: rule.anchor_type as u8,
-            constraint_include: include,
-            constraint_exclude: exclude,
-            redirect: rule.redirect.clone(),
-            removeparam: rule.removeparam.clone(),
-            csp: rule.csp.clone(),
-            header: rule.header.clone(),
-            cosmetic: rule.cosmetic.clone(),
-            procedural: rule.procedural.clone(),
-            scriptlet: rule.scriptlet.clone(),
-            responseheader: rule.responseheader.clone(),
+            rule: BadfilterKey::from(rule),
         }
     }
 }
 
-impl From<&CompiledRule> for BadfilterKey {
-    fn from(rule: &CompiledRule) -> Self {
+impl<'r> From<&'r CompiledRule> for BadfilterKey<'r> {
+    fn from(rule: &'r CompiledRule) -> Self {
         let (include, exclude) = match &rule.domain_constraints {
             Some(c) => (
                 c.include.iter().map(|h| h.to_u64()).collect(),
@@ -155,19 +427,23 @@ impl From<&CompiledRule> for BadfilterKey {
             type_mask: rule.type_mask.bits(),
             party_mask: rule.party_mask.bits(),
             scheme_mask: rule.scheme_mask.bits(),
-            domain: rule.domain.clone(),
-            pattern: rule.pattern.clone(),
+            domain: &rule.domain,
+            pattern: rule.pattern.as_deref(),
             anchor_type: rule.anchor_type as u8,
             constraint_include: include,
             constraint_exclude: exclude,
-            redirect: rule.redirect.clone(),
-            removeparam: rule.removeparam.clone(),
-            csp: rule.csp.clone(),
-            header: rule.header.clone(),
-            cosmetic: rule.cosmetic.clone(),
-            procedural: rule.procedural.clone(),
-            scriptlet: rule.scriptlet.clone(),
-            responseheader: rule.responseheader.clone(),
+            redirect: rule.redirect.as_deref(),
+            removeparam: rule.removeparam.as_deref(),
+            csp: rule.csp.as_deref(),
+            header: rule.header.as_deref(),
+            replace: rule.replace.as_deref(),
+            removeheader: rule.removeheader.as_deref(),
+            cosmetic: rule.cosmetic.as_ref(),
+            cosmetic_style: rule.cosmetic_style.as_deref(),
+            procedural: rule.procedural.as_deref(),
+            scriptlet: rule.scriptlet.as_deref(),
+            responseheader: rule.responseheader.as_deref(),
+            expires_at: rule.expires_at,
         }
     }
 }
@@ -1,19 +1,47 @@
 use std::net::IpAddr;
 
-use bb_core::hash::{hash_domain, Hash64};
-use bb_core::types::{PartyMask, RequestType, RuleAction, RuleFlags, SchemeMask};
+use bb_core::hash::{hash_domain, hash_wildcard_domain, Hash64};
+use bb_core::types::{PartyMask, ProceduralOpKind, RequestType, RuleAction, RuleFlags, SchemeMask};
+
+use crate::css::selector_parse_error;
+use crate::diagnostics::{CompileDiagnostics, DropReason};
+use crate::redirects::lookup_redirect;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DomainConstraint {
     pub include: Vec<Hash64>,
     pub exclude: Vec<Hash64>,
+    /// Normalized names behind `include`, for exporters that need the text
+    pub include_names: Vec<String>,
+    /// Normalized names behind `exclude`
+    pub exclude_names: Vec<String>,
 }
 
+/// `$header=[request:]name[:[~]value]`
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct HeaderSpec {
     pub name: String,
     pub value: Option<String>,
     pub negate: bool,
+    /// Match the outgoing request headers instead of the response headers
+    pub request: bool,
+}
+
+/// `$removeheader=[request:]name`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RemoveHeaderSpec {
+    /// Lowercased header name; empty on an `@@...$removeheader` that disables every removal
+    pub name: String,
+    /// Strip from the request headers instead of the response headers
+    pub request: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ReplaceSpec {
+    pub pattern: String,
+    pub replacement: String,
+    pub flags: String,
+    pub is_exception: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -23,11 +51,23 @@ pub struct CosmeticRule {
     pub is_generic: bool,
 }
 
+/// `selector:style(declarations)` that needs no procedural evaluation, or
+/// AdGuard's `#$#selector { declarations }`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CosmeticStyleRule {
+    pub selector: String,
+    pub style: String,
+    pub is_exception: bool,
+    pub is_generic: bool,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ScriptletRule {
     pub scriptlet: String,
     pub is_exception: bool,
     pub is_generic: bool,
+    /// `#@#+js(name)`: cancels every injection of `name`, whatever its args
+    pub name_only: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -51,18 +91,28 @@ pub struct CompiledRule {
     pub pattern: Option<String>,
     pub anchor_type: AnchorType,
     pub list_id: u16,
+    /// 1-based source line within its list (0 if synthesized)
+    pub line: u32,
     pub type_mask: RequestType,
     pub party_mask: PartyMask,
     pub scheme_mask: SchemeMask,
-    pub domain_constraints: Option<DomainConstraint>,
+    // Payloads most rules don't have are boxed: default-bundle compiles hold
+    // a few hundred thousand rules at once, so every inline byte counts.
+    pub domain_constraints: Option<Box<DomainConstraint>>,
     pub redirect: Option<String>,
     pub removeparam: Option<String>,
     pub csp: Option<String>,
-    pub header: Option<HeaderSpec>,
+    pub header: Option<Box<HeaderSpec>>,
+    pub replace: Option<Box<ReplaceSpec>>,
+    pub removeheader: Option<Box<RemoveHeaderSpec>>,
     pub cosmetic: Option<CosmeticRule>,
-    pub procedural: Option<ProceduralRule>,
-    pub scriptlet: Option<ScriptletRule>,
-    pub responseheader: Option<ResponseHeaderRule>,
+    pub cosmetic_style: Option<Box<CosmeticStyleRule>>,
+    pub procedural: Option<Box<ProceduralRule>>,
+    pub scriptlet: Option<Box<ScriptletRule>>,
+    pub responseheader: Option<Box<ResponseHeaderRule>>,
+    /// Unix time (seconds) from which the rule no longer matches, from
+    /// `$expires=`
+    pub expires_at: Option<u32>,
     pub is_badfilter: bool,
 }
 
@@ -74,168 +124,323 @@ pub enum AnchorType {
     Hostname,
 }
 
+/// What to do with rules using options only a DNS filter can evaluate:
+/// AdGuard's `$dnsrewrite`, `$dnstype`, `$client`, `$ctag` and uBO's
+/// `$ipaddress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DnsOptionPolicy {
+    /// Drop the rule with a `DropReason::DnsOption` diagnostic
+    #[default]
+    Skip,
+    /// Turn blocking `||host^` rules into plain host blocks without the DNS
+    /// options; client/tag restrictions are lost. Everything else is skipped.
+    BlockHost,
+}
+
+/// Parser settings.
+#[derive(Debug, Clone, Default)]
+pub struct ParserConfig {
+    pub dns_options: DnsOptionPolicy,
+}
+
+/// Option names (before any `=`) that only make sense to a DNS filter.
+const DNS_ONLY_OPTIONS: &[&str] = &["dnsrewrite", "dnstype", "client", "ctag", "ipaddress"];
+
+/// Options older lists still use, with the closest type each stands for.
+/// `None` means the option is accepted and ignored.
+const LEGACY_OPTIONS: &[(&str, Option<RequestType>)] = &[
+    ("object-subrequest", Some(RequestType::OBJECT)),
+    ("webrtc", Some(RequestType::OTHER)),
+    ("background", Some(RequestType::IMAGE)),
+    ("xbl", Some(RequestType::OTHER)),
+    ("dtd", Some(RequestType::OTHER)),
+    ("collapse", None),
+];
+
 pub fn parse_filter_list(text: &str) -> Vec<CompiledRule> {
+    parse_filter_list_with_diagnostics(text).0
+}
+
+/// Parse a filter list, also reporting every line that was dropped and why.
+///
+/// Diagnostics carry list_id 0; use `CompileDiagnostics::merge` to tag them.
+pub fn parse_filter_list_with_diagnostics(text: &str) -> (Vec<CompiledRule>, CompileDiagnostics) {
+    parse_filter_list_with_config(text, &ParserConfig::default())
+}
+
+/// [`parse_filter_list_with_diagnostics`] with non-default parser settings.
+pub fn parse_filter_list_with_config(text: &str, config: &ParserConfig) -> (Vec<CompiledRule>, CompileDiagnostics) {
     let mut rules = Vec::new();
+    let diagnostics = parse_filter_list_into(text, config, 0, &mut rules);
+    (rules, diagnostics)
+}
 
-    for raw_line in text.lines() {
-        let mut line = raw_line.trim();
-        if line.is_empty() || is_comment_line(line) {
-            continue;
+/// Parse a filter list straight onto the end of `rules`, tagging each rule
+/// with `list_id`.
+///
+/// Compiling several lists this way keeps one growing vector instead of a
+/// per-list vector plus the copy that merges it.
+pub fn parse_filter_list_into(
+    text: &str,
+    config: &ParserConfig,
+    list_id: u16,
+    rules: &mut Vec<CompiledRule>,
+) -> CompileDiagnostics {
+    let mut diagnostics = CompileDiagnostics::default();
+
+    for (index, raw_line) in text.lines().enumerate() {
+        let line_no = (index + 1) as u32;
+        match parse_line(raw_line, config) {
+            Ok(Some(mut rule)) => {
+                rule.list_id = list_id;
+                rule.line = line_no;
+                if raw_line.contains('$') && is_network_rule(&rule) {
+                    legacy_options_in(raw_line).for_each(|name| diagnostics.note_legacy_option(name));
+                }
+                rules.push(rule);
+            }
+            Ok(None) => {}
+            Err(reason) => diagnostics.drop_line(line_no, raw_line.trim(), reason),
         }
+    }
 
-        if let Some(rule) = parse_responseheader_line(line) {
-            rules.push(rule);
-            continue;
-        }
+    diagnostics
+}
 
-        if let Some(rule) = parse_scriptlet_line(line) {
-            rules.push(rule);
-            continue;
-        }
+fn is_network_rule(rule: &CompiledRule) -> bool {
+    rule.cosmetic.is_none()
+        && rule.cosmetic_style.is_none()
+        && rule.procedural.is_none()
+        && rule.scriptlet.is_none()
+        && rule.responseheader.is_none()
+}
 
-        if let Some(rule) = parse_procedural_line(line) {
-            rules.push(rule);
-            continue;
-        }
+/// The [`LEGACY_OPTIONS`] a network filter line uses.
+fn legacy_options_in(line: &str) -> impl Iterator<Item = &'static str> {
+    let line = line.trim();
+    let (_, options_text) = split_rule_options(line.strip_prefix("@@").unwrap_or(line));
+    let names: Vec<String> = split_options(options_text.unwrap_or_default())
+        .into_iter()
+        .map(|raw| raw.trim().trim_start_matches('~').to_ascii_lowercase())
+        .collect();
+    LEGACY_OPTIONS
+        .iter()
+        .map(|&(legacy, _)| legacy)
+        .filter(move |legacy| names.iter().any(|name| name == legacy))
+}
 
-        if let Some(rule) = parse_cosmetic_line(line) {
-            rules.push(rule);
-            continue;
-        }
+/// Parse one line. `Ok(None)` means there was nothing to parse (blank or comment).
+fn parse_line(raw_line: &str, config: &ParserConfig) -> Result<Option<CompiledRule>, DropReason> {
+    let mut line = raw_line.trim();
+    if line.is_empty() || is_comment_line(line) {
+        return Ok(None);
+    }
 
-        if line.contains("##") || line.contains("#@#") || line.contains("#?#") {
-            continue;
-        }
+    if let Some(rule) = parse_responseheader_line(line) {
+        return Ok(Some(rule));
+    }
 
-        let mut action = RuleAction::Block;
-        if let Some(rest) = line.strip_prefix("@@") {
-            action = RuleAction::Allow;
-            line = rest.trim_start();
-        }
+    if let Some(rule) = parse_scriptlet_line(line) {
+        return Ok(Some(rule));
+    }
 
-        let (pattern_part, options_text) = split_rule_options(line);
-        let mut options = match options_text {
-            Some(options_text) => match parse_options(options_text) {
-                Some(options) => options,
-                None => continue,
-            },
-            None => ParsedOptions::default(),
-        };
+    if let Some(rule) = parse_css_injection_line(line)? {
+        return Ok(Some(rule));
+    }
 
-        let pattern_str = pattern_part.trim();
-        let is_badfilter = options.is_badfilter;
-        let removeparam = options.removeparam.clone();
-        let csp = options.csp.clone();
-        let header = options.header.clone();
+    if let Some(rule) = parse_style_line(line)? {
+        return Ok(Some(rule));
+    }
 
-        if csp.is_some() {
-            if action == RuleAction::Allow {
-                options.flags |= RuleFlags::CSP_EXCEPTION;
-            }
-            action = RuleAction::CspInject;
-        } else if header.is_some() {
-            action = if action == RuleAction::Allow {
-                RuleAction::HeaderMatchAllow
-            } else {
-                RuleAction::HeaderMatchBlock
-            };
-        } else if removeparam.is_some() && action == RuleAction::Block {
-            action = RuleAction::Removeparam;
-        }
+    if let Some(rule) = parse_procedural_line(line)? {
+        return Ok(Some(rule));
+    }
 
-        let cosmetic_override = options.flags.intersects(RuleFlags::ELEMHIDE | RuleFlags::GENERICHIDE);
-        if cosmetic_override {
-            if action != RuleAction::Allow
-                || removeparam.is_some()
-                || csp.is_some()
-                || header.is_some()
-                || options.redirect.is_some()
+    if let Some(rule) = parse_cosmetic_line(line)? {
+        return Ok(Some(rule));
+    }
+
+    if line.contains("##") || line.contains("#@#") || line.contains("#?#") || line.contains("#$?#") {
+        return Err(DropReason::UnsupportedCosmetic);
+    }
+
+    let mut action = RuleAction::Block;
+    if let Some(rest) = line.strip_prefix("@@") {
+        action = RuleAction::Allow;
+        line = rest.trim_start();
+    }
+
+    let (pattern_part, options_text) = split_rule_options(line);
+    let dns_stripped;
+    let options_text = match options_text.and_then(find_dns_option) {
+        Some(name) => {
+            if config.dns_options != DnsOptionPolicy::BlockHost
+                || action != RuleAction::Block
+                || !is_plain_host_pattern(pattern_part.trim())
             {
-                continue;
+                return Err(DropReason::DnsOption(name));
             }
+            dns_stripped = strip_dns_options(options_text.unwrap_or_default()).ok_or(DropReason::DnsOption(name))?;
+            Some(dns_stripped.as_str()).filter(|text| !text.is_empty())
         }
+        None => options_text,
+    };
+    let mut options = match options_text {
+        Some(options_text) => parse_options(options_text)?,
+        None => ParsedOptions::default(),
+    };
 
-        if options.removeparam.is_none() && options.csp.is_none() && options.header.is_none() {
-            if let Some(domain) = parse_host_anchor_rule(pattern_str) {
-                let (final_action, final_flags, redirect) = finalize_rule(action, &options);
-                rules.push(CompiledRule {
-                    action: final_action,
-                    flags: final_flags,
-                    domain,
-                    pattern: None,
-                    anchor_type: AnchorType::Hostname,
-                    list_id: 0,
-                    type_mask: options.type_mask,
-                    party_mask: options.party_mask,
-                    scheme_mask: options.scheme_mask,
-                    domain_constraints: options.domain_constraints.clone(),
-                    redirect,
-                    removeparam: removeparam.clone(),
-                    csp: csp.clone(),
-                    header: header.clone(),
-                    cosmetic: None,
-                    procedural: None,
-                    scriptlet: None,
-                    responseheader: None,
-                    is_badfilter,
-                });
-                continue;
-            }
+    let pattern_str = pattern_part.trim();
+    let is_badfilter = options.is_badfilter;
+    let removeparam = options.removeparam.clone();
+    let csp = options.csp.clone();
+    let header = options.header.clone();
+    let removeheader = options.removeheader.clone();
+    let replace = options.replace.clone().map(|mut spec| {
+        spec.is_exception = action == RuleAction::Allow;
+        spec
+    });
 
-            if let Some(domain) = parse_hosts_file_domain(pattern_str) {
-                let (final_action, final_flags, redirect) = finalize_rule(action, &options);
-                rules.push(CompiledRule {
-                    action: final_action,
-                    flags: final_flags,
-                    domain,
-                    pattern: None,
-                    anchor_type: AnchorType::Hostname,
-                    list_id: 0,
-                    type_mask: options.type_mask,
-                    party_mask: options.party_mask,
-                    scheme_mask: options.scheme_mask,
-                    domain_constraints: options.domain_constraints.clone(),
-                    redirect,
-                    removeparam: removeparam.clone(),
-                    csp: csp.clone(),
-                    header: header.clone(),
-                    cosmetic: None,
-                    procedural: None,
-                    scriptlet: None,
-                    responseheader: None,
-                    is_badfilter,
-                });
-                continue;
-            }
+    if replace.is_some() {
+        action = RuleAction::ResponseReplace;
+    } else if csp.is_some() {
+        if action == RuleAction::Allow {
+            options.flags |= RuleFlags::CSP_EXCEPTION;
+        }
+        action = RuleAction::CspInject;
+    } else if header.is_some() {
+        action = if action == RuleAction::Allow {
+            RuleAction::HeaderMatchAllow
+        } else {
+            RuleAction::HeaderMatchBlock
+        };
+    } else if let Some(spec) = &removeheader {
+        if action == RuleAction::Allow {
+            options.flags |= RuleFlags::REMOVEHEADER_EXCEPTION;
+        } else if spec.name.is_empty() {
+            return Err(DropReason::InvalidOption("removeheader".to_string()));
         }
+        action = RuleAction::RemoveHeader;
+    } else if removeparam.is_some() && action == RuleAction::Block {
+        action = RuleAction::Removeparam;
+    }
 
-        if let Some(parsed) = parse_pattern_rule(pattern_str) {
+    let cosmetic_override = options.flags.intersects(
+        RuleFlags::ELEMHIDE | RuleFlags::GENERICHIDE | RuleFlags::SPECIFICHIDE | RuleFlags::GENERICBLOCK,
+    );
+    if cosmetic_override
+        && (action != RuleAction::Allow
+            || removeparam.is_some()
+            || csp.is_some()
+            || header.is_some()
+            || replace.is_some()
+            || removeheader.is_some()
+            || options.redirect.is_some())
+    {
+        return Err(DropReason::MisplacedCosmeticOption);
+    }
+
+    if options.removeparam.is_none()
+        && options.csp.is_none()
+        && options.header.is_none()
+        && options.replace.is_none()
+        && options.removeheader.is_none()
+    {
+        if let Some(domain) = parse_host_anchor_rule(pattern_str) {
             let (final_action, final_flags, redirect) = finalize_rule(action, &options);
-            rules.push(CompiledRule {
+            return Ok(Some(CompiledRule {
                 action: final_action,
                 flags: final_flags,
-                domain: parsed.domain,
-                pattern: Some(parsed.pattern),
-                anchor_type: parsed.anchor_type,
+                domain,
+                pattern: None,
+                anchor_type: AnchorType::Hostname,
                 list_id: 0,
+                line: 0,
                 type_mask: options.type_mask,
                 party_mask: options.party_mask,
                 scheme_mask: options.scheme_mask,
-                domain_constraints: options.domain_constraints,
+                domain_constraints: options.domain_constraints.clone().map(Box::new),
                 redirect,
-                removeparam,
-                csp,
-                header,
+                removeparam: removeparam.clone(),
+                csp: csp.clone(),
+                header: header.clone().map(Box::new),
+                replace: None,
+                removeheader: None,
                 cosmetic: None,
+                cosmetic_style: None,
                 procedural: None,
                 scriptlet: None,
                 responseheader: None,
+                expires_at: options.expires_at,
                 is_badfilter,
-            });
+            }));
+        }
+
+        if let Some(domain) = parse_hosts_file_domain(pattern_str) {
+            let (final_action, final_flags, redirect) = finalize_rule(action, &options);
+            return Ok(Some(CompiledRule {
+                action: final_action,
+                flags: final_flags,
+                domain,
+                pattern: None,
+                anchor_type: AnchorType::Hostname,
+                list_id: 0,
+                line: 0,
+                type_mask: options.type_mask,
+                party_mask: options.party_mask,
+                scheme_mask: options.scheme_mask,
+                domain_constraints: options.domain_constraints.clone().map(Box::new),
+                redirect,
+                removeparam: removeparam.clone(),
+                csp: csp.clone(),
+                header: header.clone().map(Box::new),
+                replace: None,
+                removeheader: None,
+                cosmetic: None,
+                cosmetic_style: None,
+                procedural: None,
+                scriptlet: None,
+                responseheader: None,
+                expires_at: options.expires_at,
+                is_badfilter,
+            }));
         }
     }
 
-    rules
+    let parsed = parse_pattern_rule(pattern_str).ok_or(DropReason::UnsupportedPattern)?;
+    if parsed.is_regex {
+        let match_case = options.flags.contains(RuleFlags::MATCH_CASE);
+        bb_core::regex::compile_filter_regex(&parsed.pattern, match_case).ok_or(DropReason::InvalidRegex)?;
+        options.flags |= RuleFlags::IS_REGEX;
+    }
+
+    let (final_action, final_flags, redirect) = finalize_rule(action, &options);
+    Ok(Some(CompiledRule {
+        action: final_action,
+        flags: final_flags,
+        domain: parsed.domain,
+        pattern: Some(parsed.pattern),
+        anchor_type: parsed.anchor_type,
+        list_id: 0,
+        line: 0,
+        type_mask: options.type_mask,
+        party_mask: options.party_mask,
+        scheme_mask: options.scheme_mask,
+        domain_constraints: options.domain_constraints.map(Box::new),
+        redirect,
+        removeparam,
+        csp,
+        header: header.map(Box::new),
+        replace: replace.map(Box::new),
+        removeheader: removeheader.map(Box::new),
+        cosmetic: None,
+        cosmetic_style: None,
+        procedural: None,
+        scriptlet: None,
+        responseheader: None,
+        expires_at: options.expires_at,
+        is_badfilter,
+    }))
 }
 
 fn finalize_rule(action: RuleAction, options: &ParsedOptions) -> (RuleAction, RuleFlags, Option<String>) {
@@ -245,7 +450,12 @@ fn finalize_rule(action: RuleAction, options: &ParsedOptions) -> (RuleAction, Ru
 
     if matches!(
         action,
-        RuleAction::Removeparam | RuleAction::CspInject | RuleAction::HeaderMatchBlock | RuleAction::HeaderMatchAllow
+        RuleAction::Removeparam
+            | RuleAction::CspInject
+            | RuleAction::HeaderMatchBlock
+            | RuleAction::HeaderMatchAllow
+            | RuleAction::ResponseReplace
+            | RuleAction::RemoveHeader
     ) {
         return (final_action, final_flags, None);
     }
@@ -279,6 +489,9 @@ struct ParsedOptions {
     removeparam: Option<String>,
     csp: Option<String>,
     header: Option<HeaderSpec>,
+    replace: Option<ReplaceSpec>,
+    removeheader: Option<RemoveHeaderSpec>,
+    expires_at: Option<u32>,
     is_badfilter: bool,
 }
 
@@ -295,19 +508,33 @@ impl Default for ParsedOptions {
             removeparam: None,
             csp: None,
             header: None,
+            replace: None,
+            removeheader: None,
+            expires_at: None,
             is_badfilter: false,
         }
     }
 }
 
 fn split_rule_options(line: &str) -> (&str, Option<&str>) {
+    // A regex filter may contain `$` itself, so its options start after the
+    // closing slash.
+    if line.len() > 2 && line.starts_with('/') {
+        if let Some(pos) = line.rfind("/$").filter(|&pos| pos > 0) {
+            return (&line[..pos + 1], Some(&line[pos + 2..]));
+        }
+        if line.ends_with('/') {
+            return (line, None);
+        }
+    }
+
     match line.find('$') {
         Some(pos) => (&line[..pos], Some(&line[pos + 1..])),
         None => (line, None),
     }
 }
 
-fn parse_options(text: &str) -> Option<ParsedOptions> {
+fn parse_options(text: &str) -> Result<ParsedOptions, DropReason> {
     let mut flags = RuleFlags::empty();
     let mut type_include = 0u32;
     let mut type_exclude = 0u32;
@@ -321,14 +548,17 @@ fn parse_options(text: &str) -> Option<ParsedOptions> {
     let mut removeparam: Option<String> = None;
     let mut csp: Option<String> = None;
     let mut header: Option<HeaderSpec> = None;
+    let mut replace: Option<ReplaceSpec> = None;
+    let mut removeheader: Option<RemoveHeaderSpec> = None;
+    let mut expires_at: Option<u32> = None;
     let mut is_badfilter = false;
 
     let trimmed = text.trim();
     if trimmed.is_empty() {
-        return Some(ParsedOptions::default());
+        return Ok(ParsedOptions::default());
     }
 
-    for raw in trimmed.split(',') {
+    for raw in split_options(trimmed) {
         let raw = raw.trim();
         if raw.is_empty() {
             continue;
@@ -362,15 +592,30 @@ fn parse_options(text: &str) -> Option<ParsedOptions> {
             continue;
         }
 
+        if raw_lower == "specifichide" || raw_lower == "shide" {
+            flags |= RuleFlags::SPECIFICHIDE;
+            continue;
+        }
+
+        if raw_lower == "genericblock" {
+            flags |= RuleFlags::GENERICBLOCK;
+            continue;
+        }
+
         if let Some(domain_value) = raw_lower.strip_prefix("domain=") {
             let parsed = parse_domain_option(domain_value)?;
             domain_constraints = Some(merge_constraints(domain_constraints, parsed));
             continue;
         }
 
+        if let Some(date) = raw_lower.strip_prefix("expires=") {
+            expires_at = Some(parse_expiry_date(date).ok_or_else(|| DropReason::InvalidOption("expires".to_string()))?);
+            continue;
+        }
+
         if let Some(redirect_value) = raw_lower.strip_prefix("redirect=") {
             if !redirect_value.is_empty() {
-                redirect = Some(redirect_value.to_string());
+                redirect = Some(canonical_redirect(redirect_value)?);
                 redirect_is_rule = false;
             }
             continue;
@@ -378,52 +623,107 @@ fn parse_options(text: &str) -> Option<ParsedOptions> {
 
         if let Some(redirect_value) = raw_lower.strip_prefix("redirect-rule=") {
             if !redirect_value.is_empty() {
-                redirect = Some(redirect_value.to_string());
+                redirect = Some(canonical_redirect(redirect_value)?);
                 redirect_is_rule = true;
             }
             continue;
         }
 
+        if raw_lower == "replace" {
+            if replace.is_some() || csp.is_some() || header.is_some() || removeparam.is_some() || removeheader.is_some() {
+                return Err(DropReason::ConflictingOptions);
+            }
+            replace = Some(ReplaceSpec {
+                pattern: String::new(),
+                replacement: String::new(),
+                flags: String::new(),
+                is_exception: false,
+            });
+            continue;
+        }
+
+        if raw_lower.starts_with("replace=") {
+            if replace.is_some() || csp.is_some() || header.is_some() || removeparam.is_some() || removeheader.is_some() {
+                return Err(DropReason::ConflictingOptions);
+            }
+            replace = Some(
+                parse_replace_option(&raw[8..])
+                    .ok_or_else(|| DropReason::InvalidOption("replace".to_string()))?,
+            );
+            continue;
+        }
+
         if raw_lower == "csp" {
-            if csp.is_some() || header.is_some() || removeparam.is_some() {
-                return None;
+            if replace.is_some() || csp.is_some() || header.is_some() || removeparam.is_some() || removeheader.is_some() {
+                return Err(DropReason::ConflictingOptions);
             }
             csp = Some(String::new());
             continue;
         }
 
         if let Some(_csp_value) = raw_lower.strip_prefix("csp=") {
-            if csp.is_some() || header.is_some() || removeparam.is_some() {
-                return None;
+            if replace.is_some() || csp.is_some() || header.is_some() || removeparam.is_some() || removeheader.is_some() {
+                return Err(DropReason::ConflictingOptions);
             }
-            csp = Some(raw[4..].trim().to_string());
+            csp = Some(normalize_csp(&raw[4..]));
             continue;
         }
 
         if let Some(_header_value) = raw_lower.strip_prefix("header=") {
-            if csp.is_some() || header.is_some() || removeparam.is_some() {
-                return None;
+            if replace.is_some() || csp.is_some() || header.is_some() || removeparam.is_some() || removeheader.is_some() {
+                return Err(DropReason::ConflictingOptions);
             }
-            let spec = parse_header_option(raw[7..].trim())?;
+            let spec = parse_header_option(raw[7..].trim())
+                .ok_or_else(|| DropReason::InvalidOption("header".to_string()))?;
             header = Some(spec);
             continue;
         }
 
         if let Some(removeparam_value) = raw_lower.strip_prefix("removeparam=") {
-            if removeparam_value.is_empty() || csp.is_some() || header.is_some() {
-                return None;
+            if removeparam_value.is_empty() {
+                return Err(DropReason::InvalidOption("removeparam".to_string()));
+            }
+            if replace.is_some() || csp.is_some() || header.is_some() || removeheader.is_some() {
+                return Err(DropReason::ConflictingOptions);
             }
             removeparam = Some(removeparam_value.to_string());
             continue;
         }
 
+        let removeheader_value = if raw_lower == "removeheader" {
+            Some("")
+        } else {
+            raw_lower.strip_prefix("removeheader=")
+        };
+        if let Some(removeheader_value) = removeheader_value {
+            if replace.is_some() || csp.is_some() || header.is_some() || removeparam.is_some() || removeheader.is_some() {
+                return Err(DropReason::ConflictingOptions);
+            }
+            removeheader = Some(
+                parse_removeheader_option(removeheader_value)
+                    .ok_or_else(|| DropReason::InvalidOption("removeheader".to_string()))?,
+            );
+            continue;
+        }
+
         let (negated, name) = match raw_lower.strip_prefix('~') {
             Some(rest) => (true, rest),
             None => (false, raw_lower),
         };
 
         if name.is_empty() || name.contains('=') {
-            return None;
+            let name = name.split('=').next().unwrap_or(name);
+            return Err(DropReason::UnsupportedOption(name.to_string()));
+        }
+
+        if let Some(&(_, mapped)) = LEGACY_OPTIONS.iter().find(|&&(legacy, _)| legacy == name) {
+            let mask = mapped.map_or(0, |request_type| request_type.bits());
+            if negated {
+                type_exclude |= mask;
+            } else {
+                type_include |= mask;
+            }
+            continue;
         }
 
         if let Some(mask) = request_type_mask(name) {
@@ -453,14 +753,15 @@ fn parse_options(text: &str) -> Option<ParsedOptions> {
             continue;
         }
 
-        return None;
+        return Err(DropReason::UnsupportedOption(name.to_string()));
     }
 
-    let type_bits = finalize_mask_u32(type_include, type_exclude, RequestType::ALL.bits())?;
-    let party_bits = finalize_mask_u8(party_include, party_exclude, PartyMask::ALL.bits())?;
-    let scheme_bits = finalize_mask_u8(scheme_include, scheme_exclude, SchemeMask::ALL.bits())?;
+    let type_bits = finalize_type_mask(type_include, type_exclude).ok_or(DropReason::EmptyMask)?;
+    let party_bits = finalize_mask_u8(party_include, party_exclude, PartyMask::ALL.bits())
+        .ok_or(DropReason::EmptyMask)?;
+    let scheme_bits = finalize_scheme_mask(scheme_include, scheme_exclude).ok_or(DropReason::EmptyMask)?;
 
-    Some(ParsedOptions {
+    Ok(ParsedOptions {
         flags,
         type_mask: RequestType::from_bits_truncate(type_bits),
         party_mask: PartyMask::from_bits_truncate(party_bits),
@@ -471,24 +772,200 @@ fn parse_options(text: &str) -> Option<ParsedOptions> {
         removeparam,
         csp,
         header,
+        replace,
+        removeheader,
+        expires_at,
         is_badfilter,
     })
 }
 
+/// `$expires=YYYY-MM-DD`: the rule stays in effect through that day (UTC)
+/// and stops matching at the following midnight. Returns unix seconds.
+fn parse_expiry_date(value: &str) -> Option<u32> {
+    let mut parts = value.trim().splitn(3, '-');
+    let mut field = |len: usize| {
+        let part = parts.next().filter(|part| part.len() == len && part.bytes().all(|b| b.is_ascii_digit()))?;
+        part.parse::<i64>().ok()
+    };
+    let (year, month, day) = (field(4)?, field(2)?, field(2)?);
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return None,
+    };
+    if !(1970..=2105).contains(&year) || day < 1 || day > days_in_month {
+        return None;
+    }
+
+    // Days since 1970-01-01 (Howard Hinnant's days_from_civil).
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    u32::try_from((days + 1) * 86_400).ok()
+}
+
+/// Canonical name of a bundled redirect resource.
+fn canonical_redirect(name: &str) -> Result<String, DropReason> {
+    lookup_redirect(name)
+        .map(|spec| spec.name.to_string())
+        .ok_or_else(|| DropReason::UnknownRedirect(name.to_string()))
+}
+
+/// The first DNS-only option in an options string, if any.
+fn find_dns_option(text: &str) -> Option<String> {
+    split_options(text).into_iter().find_map(|raw| {
+        let name = raw.trim().split('=').next().unwrap_or("").to_ascii_lowercase();
+        DNS_ONLY_OPTIONS.contains(&name.as_str()).then_some(name)
+    })
+}
+
+/// Options with the DNS-only ones removed, or `None` if one of them does
+/// something other than block (e.g. `$dnsrewrite` to a real address).
+fn strip_dns_options(text: &str) -> Option<String> {
+    let mut kept = Vec::new();
+    for raw in split_options(text) {
+        let raw = raw.trim();
+        let (name, value) = raw.split_once('=').unwrap_or((raw, ""));
+        let name = name.to_ascii_lowercase();
+        if !DNS_ONLY_OPTIONS.contains(&name.as_str()) {
+            kept.push(raw);
+        } else if name == "dnsrewrite" && !is_blocking_dnsrewrite(value) {
+            return None;
+        }
+    }
+    Some(kept.join(","))
+}
+
+/// Whether a `$dnsrewrite` value answers with an error or a null address.
+fn is_blocking_dnsrewrite(value: &str) -> bool {
+    let value = value.trim().to_ascii_uppercase();
+    let rcode = value.split(';').next().unwrap_or("");
+    let answer = value.rsplit(';').next().unwrap_or("");
+    matches!(rcode, "REFUSED" | "NXDOMAIN" | "SERVFAIL") || matches!(answer, "0.0.0.0" | "::")
+}
+
+/// `||host^` (or `||host`) with nothing but a hostname in between.
+fn is_plain_host_pattern(pattern: &str) -> bool {
+    let Some(host) = pattern.strip_prefix("||") else {
+        return false;
+    };
+    let host = host.strip_suffix('^').unwrap_or(host);
+    !host.is_empty() && host.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_'))
+}
+
+/// Split an options string on commas, keeping `\,` escapes intact.
+fn split_options(text: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let bytes = text.as_bytes();
+    let mut start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            b',' => {
+                parts.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Parse `name` or `request:name` from a lowercased `$removeheader=` value.
+/// An empty name is accepted here; only exceptions may use it.
+fn parse_removeheader_option(value: &str) -> Option<RemoveHeaderSpec> {
+    let value = value.trim();
+    let (request, name) = match value.strip_prefix("request:") {
+        Some(name) => (true, name),
+        None => (false, value),
+    };
+    if request && name.is_empty() {
+        return None;
+    }
+    if !name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.')) {
+        return None;
+    }
+    Some(RemoveHeaderSpec {
+        name: name.to_string(),
+        request,
+    })
+}
+
+/// Parse `/regex/replacement/flags` from a `$replace=` option.
+fn parse_replace_option(raw: &str) -> Option<ReplaceSpec> {
+    let body = raw.trim().strip_prefix('/')?;
+
+    let mut fields: Vec<String> = vec![String::new()];
+    let mut chars = body.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                let next = chars.next()?;
+                let in_regex = fields.len() == 1;
+                let field = fields.last_mut()?;
+                match next {
+                    ',' => field.push(','),
+                    // Keep `\/` inside the regex, unescape it in the replacement.
+                    '/' if in_regex => field.push_str("\\/"),
+                    '/' => field.push('/'),
+                    other => {
+                        field.push('\\');
+                        field.push(other);
+                    }
+                }
+            }
+            '/' if fields.len() < 3 => fields.push(String::new()),
+            _ => fields.last_mut()?.push(ch),
+        }
+    }
+
+    if fields.len() != 3 || fields[0].is_empty() {
+        return None;
+    }
+
+    let flags = fields.pop()?;
+    if !flags.chars().all(|ch| matches!(ch, 'i' | 'g' | 'm' | 's')) {
+        return None;
+    }
+    let replacement = fields.pop()?;
+    let pattern = fields.pop()?;
+
+    Some(ReplaceSpec {
+        pattern,
+        replacement,
+        flags,
+        is_exception: false,
+    })
+}
+
 fn merge_constraints(existing: Option<DomainConstraint>, incoming: DomainConstraint) -> DomainConstraint {
     match existing {
         Some(mut current) => {
             current.include.extend(incoming.include);
             current.exclude.extend(incoming.exclude);
+            current.include_names.extend(incoming.include_names);
+            current.exclude_names.extend(incoming.exclude_names);
             current
         }
         None => incoming,
     }
 }
 
-fn parse_domain_option(value: &str) -> Option<DomainConstraint> {
+fn parse_domain_option(value: &str) -> Result<DomainConstraint, DropReason> {
     let mut include = Vec::new();
     let mut exclude = Vec::new();
+    let mut include_names = Vec::new();
+    let mut exclude_names = Vec::new();
 
     for raw in value.split('|') {
         let raw = raw.trim();
@@ -501,26 +978,35 @@ fn parse_domain_option(value: &str) -> Option<DomainConstraint> {
             None => (false, raw),
         };
 
-        let domain = normalize_domain(domain_raw)?;
-        let hash = hash_domain(&domain);
+        let (domain, hash) =
+            constraint_domain(domain_raw).ok_or_else(|| DropReason::InvalidDomain(domain_raw.to_string()))?;
 
         if is_exclude {
             exclude.push(hash);
+            exclude_names.push(domain);
         } else {
             include.push(hash);
+            include_names.push(domain);
         }
     }
 
     if include.is_empty() && exclude.is_empty() {
-        return None;
+        return Err(DropReason::InvalidOption("domain".to_string()));
     }
 
-    Some(DomainConstraint { include, exclude })
+    Ok(DomainConstraint {
+        include,
+        exclude,
+        include_names,
+        exclude_names,
+    })
 }
 
-fn parse_cosmetic_domains(value: &str) -> Option<DomainConstraint> {
+fn parse_cosmetic_domains(value: &str) -> Option<Box<DomainConstraint>> {
     let mut include = Vec::new();
     let mut exclude = Vec::new();
+    let mut include_names = Vec::new();
+    let mut exclude_names = Vec::new();
 
     let trimmed = value.trim();
     if trimmed.is_empty() {
@@ -538,25 +1024,52 @@ fn parse_cosmetic_domains(value: &str) -> Option<DomainConstraint> {
             None => (false, raw),
         };
 
-        let domain = normalize_domain(domain_raw)?;
-        let hash = hash_domain(&domain);
+        let (domain, hash) = constraint_domain(domain_raw)?;
 
         if is_exclude {
             exclude.push(hash);
+            exclude_names.push(domain);
         } else {
             include.push(hash);
+            include_names.push(domain);
         }
     }
 
     if include.is_empty() && exclude.is_empty() {
         None
     } else {
-        Some(DomainConstraint { include, exclude })
+        Some(Box::new(DomainConstraint {
+            include,
+            exclude,
+            include_names,
+            exclude_names,
+        }))
     }
 }
 
+/// Canonical form of a `$csp=` policy so exceptions match injections that
+/// differ only in spacing, directive-name case or stray `;` separators.
+fn normalize_csp(raw: &str) -> String {
+    raw.split(';')
+        .filter_map(|directive| {
+            let mut parts = directive.split_whitespace();
+            let name = parts.next()?.to_ascii_lowercase();
+            Some(parts.fold(name, |mut acc, value| {
+                acc.push(' ');
+                acc.push_str(value);
+                acc
+            }))
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
 fn parse_header_option(raw: &str) -> Option<HeaderSpec> {
     let raw = raw.trim();
+    let (request, raw) = match raw.strip_prefix("request:") {
+        Some(rest) => (true, rest.trim()),
+        None => (false, raw),
+    };
     if raw.is_empty() {
         return None;
     }
@@ -592,9 +1105,26 @@ fn parse_header_option(raw: &str) -> Option<HeaderSpec> {
         name: name.to_ascii_lowercase(),
         value,
         negate,
+        request,
     })
 }
 
+/// Like [`finalize_mask_u32`] over [`RequestType::ALL`], with `$popup`
+/// added only when named: negations alone never reach popups.
+fn finalize_type_mask(include: u32, exclude: u32) -> Option<u32> {
+    let popup = RequestType::POPUP.bits();
+    if include & popup == 0 || exclude & popup != 0 {
+        return finalize_mask_u32(include, exclude, RequestType::ALL.bits());
+    }
+    if include & RequestType::ALL.bits() == 0 {
+        return Some(popup);
+    }
+    match finalize_mask_u32(include, exclude, RequestType::ALL.bits())? {
+        0 => Some(RequestType::ALL.bits() | popup),
+        mask => Some(mask | popup),
+    }
+}
+
 fn finalize_mask_u32(include: u32, exclude: u32, all: u32) -> Option<u32> {
     let include = include & all;
     let exclude = exclude & all;
@@ -608,6 +1138,23 @@ fn finalize_mask_u32(include: u32, exclude: u32, all: u32) -> Option<u32> {
     Some(mask)
 }
 
+/// Like [`finalize_mask_u8`] over [`SchemeMask::WEB`], with `$extension`
+/// and `$internal` added only when named: negations alone never reach them.
+fn finalize_scheme_mask(include: u8, exclude: u8) -> Option<u8> {
+    let web = SchemeMask::WEB.bits();
+    let named = include & !web & !exclude;
+    if named == 0 {
+        return finalize_mask_u8(include, exclude, web);
+    }
+    if include & web == 0 {
+        return Some(named);
+    }
+    match finalize_mask_u8(include, exclude, web)? {
+        0 => Some(web | named),
+        mask => Some(mask | named),
+    }
+}
+
 fn finalize_mask_u8(include: u8, exclude: u8, all: u8) -> Option<u8> {
     let include = include & all;
     let exclude = exclude & all;
@@ -637,7 +1184,12 @@ fn request_type_mask(name: &str) -> Option<u32> {
         "beacon" => Some(RequestType::BEACON.bits()),
         "fetch" => Some(RequestType::FETCH.bits()),
         "csp" | "csp_report" => Some(RequestType::CSP_REPORT.bits()),
+        "audioworklet" => Some(RequestType::AUDIOWORKLET.bits()),
+        "paintworklet" => Some(RequestType::PAINTWORKLET.bits()),
+        "manifest" | "web_manifest" => Some(RequestType::MANIFEST.bits()),
+        "xslt" => Some(RequestType::XSLT.bits()),
         "other" => Some(RequestType::OTHER.bits()),
+        "popup" => Some(RequestType::POPUP.bits()),
         _ => None,
     }
 }
@@ -658,6 +1210,8 @@ fn scheme_mask(name: &str) -> Option<u8> {
         "wss" => Some(SchemeMask::WSS.bits()),
         "data" => Some(SchemeMask::DATA.bits()),
         "ftp" => Some(SchemeMask::FTP.bits()),
+        "extension" => Some(SchemeMask::EXTENSION.bits()),
+        "internal" => Some(SchemeMask::INTERNAL.bits()),
         _ => None,
     }
 }
@@ -667,6 +1221,8 @@ fn is_cosmetic_marker(line: &str) -> bool {
         || line.starts_with("#@#")
         || line.starts_with("#?#")
         || line.starts_with("#@?#")
+        || line.starts_with("#$#")
+        || line.starts_with("#@$#")
         || line.starts_with("##+js(")
         || line.starts_with("#@#+js(")
 }
@@ -732,6 +1288,24 @@ fn normalize_domain(host: &str) -> Option<String> {
     Some(trimmed.to_ascii_lowercase())
 }
 
+/// Normalized name and hash of a `$domain=` or cosmetic domain entry.
+/// `example.*` entries keep their `.*` and match `example` under any public
+/// suffix; see [`hash_wildcard_domain`].
+pub(crate) fn constraint_domain(raw: &str) -> Option<(String, Hash64)> {
+    match raw.trim().strip_suffix(".*") {
+        Some(name) => {
+            let name = normalize_domain(name)?;
+            let hash = hash_wildcard_domain(&name);
+            Some((format!("{}.*", name), hash))
+        }
+        None => {
+            let name = normalize_domain(raw)?;
+            let hash = hash_domain(&name);
+            Some((name, hash))
+        }
+    }
+}
+
 fn make_special_rule() -> CompiledRule {
     CompiledRule {
         action: RuleAction::ResponseCancel,
@@ -740,6 +1314,7 @@ fn make_special_rule() -> CompiledRule {
         pattern: None,
         anchor_type: AnchorType::None,
         list_id: 0,
+        line: 0,
         type_mask: RequestType::from_bits_truncate(0),
         party_mask: PartyMask::from_bits_truncate(0),
         scheme_mask: SchemeMask::from_bits_truncate(0),
@@ -748,10 +1323,14 @@ fn make_special_rule() -> CompiledRule {
         removeparam: None,
         csp: None,
         header: None,
+        replace: None,
+        removeheader: None,
         cosmetic: None,
+        cosmetic_style: None,
         procedural: None,
         scriptlet: None,
         responseheader: None,
+        expires_at: None,
         is_badfilter: false,
     }
 }
@@ -789,10 +1368,10 @@ fn parse_responseheader_line(line: &str) -> Option<CompiledRule> {
 
     let mut rule = make_special_rule();
     rule.domain_constraints = parse_cosmetic_domains(domain_part);
-    rule.responseheader = Some(ResponseHeaderRule {
+    rule.responseheader = Some(Box::new(ResponseHeaderRule {
         header: header_raw.to_ascii_lowercase(),
         is_exception,
-    });
+    }));
     Some(rule)
 }
 
@@ -822,25 +1401,266 @@ fn parse_scriptlet_line(line: &str) -> Option<CompiledRule> {
 
     let mut rule = make_special_rule();
     rule.domain_constraints = parse_cosmetic_domains(domain_part);
-    rule.scriptlet = Some(ScriptletRule {
+    rule.scriptlet = Some(Box::new(ScriptletRule {
         scriptlet: scriptlet_raw.to_string(),
         is_exception,
         is_generic: domain_part.is_empty(),
-    });
+        name_only: is_exception && !scriptlet_raw.is_empty() && !scriptlet_raw.contains(','),
+    }));
     Some(rule)
 }
 
 fn is_procedural_selector(selector: &str) -> bool {
     let lower = selector.to_ascii_lowercase();
-    lower.contains(":has-text(")
-        || lower.contains(":matches-css(")
-        || lower.contains(":xpath(")
-        || lower.contains(":upward(")
-        || lower.contains(":remove(")
-        || lower.contains(":style(")
+    ProceduralOpKind::ALL.iter().any(|op| find_procedural_op(&lower, op.name()).is_some())
+}
+
+/// Byte offset of `:<op>(` in `text`.
+fn find_procedural_op(text: &str, op: &str) -> Option<usize> {
+    let mut start = 0;
+    while let Some(found) = text[start..].find(op) {
+        let index = start + found;
+        if index > 0 && text.as_bytes()[index - 1] == b':' && text.as_bytes().get(index + op.len()) == Some(&b'(') {
+            return Some(index - 1);
+        }
+        start = index + op.len();
+    }
+    None
+}
+
+/// Base selector and (operator, argument) pairs of a procedural selector.
+pub(crate) type ProceduralParts<'a> = (&'a str, Vec<(ProceduralOpKind, &'a str)>);
+
+/// Split a procedural selector into its base selector (`*` when there is
+/// none) and its operators with their trimmed arguments, which is what the
+/// snapshot stores. The earliest `:<op>(` wins and its argument runs to the
+/// matching `)`; text between operators is ignored. Every argument is
+/// checked the way the content script will read it, so malformed rules are
+/// dropped here instead of silently doing nothing (or the wrong thing) on
+/// the page.
+pub(crate) fn parse_procedural_selector(selector: &str) -> Result<ProceduralParts<'_>, DropReason> {
+    let mut base = "*";
+    let mut ops = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let next = ProceduralOpKind::ALL
+            .iter()
+            .filter_map(|&op| find_procedural_op(&selector[cursor..], op.name()).map(|index| (cursor + index, op)))
+            .min_by_key(|(index, _)| *index);
+        let Some((index, op)) = next else {
+            break;
+        };
+        if ops.is_empty() && !selector[..index].trim().is_empty() {
+            base = selector[..index].trim();
+        }
+        let invalid = || DropReason::InvalidProcedural(op.name().to_string());
+        let open = index + op.name().len() + 1;
+        let close = matching_paren(selector, open).ok_or_else(invalid)?;
+        let arg = selector[open + 1..close].trim();
+        if !procedural_arg_is_valid(op, arg) {
+            return Err(invalid());
+        }
+        ops.push((op, arg));
+        cursor = close + 1;
+    }
+    // Operators only matched case-insensitively, which the content script
+    // doesn't accept either.
+    if ops.is_empty() {
+        return Err(DropReason::UnsupportedCosmetic);
+    }
+    Ok((base, ops))
+}
+
+/// Index of the `)` closing the `(` at `open`.
+fn matching_paren(text: &str, open: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    for (index, byte) in text.bytes().enumerate().skip(open) {
+        match byte {
+            b'(' => depth += 1,
+            b')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn procedural_arg_is_valid(op: ProceduralOpKind, arg: &str) -> bool {
+    match op {
+        // A distance of 1 to 256 ancestors, or a selector for `closest()`
+        ProceduralOpKind::Upward => {
+            if arg.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '+') {
+                arg.parse::<u16>().is_ok_and(|steps| (1..=256).contains(&steps))
+            } else {
+                is_plausible_selector(arg)
+            }
+        }
+        // `property: value` (or the older `property, value`)
+        ProceduralOpKind::MatchesCss => {
+            let Some(split) = arg.find([':', ',']) else {
+                return false;
+            };
+            let (property, value) = (arg[..split].trim(), arg[split + 1..].trim());
+            is_css_property_name(property) && !value.is_empty() && regex_arg_is_valid(value)
+        }
+        ProceduralOpKind::MatchesPath => !arg.is_empty() && regex_arg_is_valid(arg),
+        ProceduralOpKind::MinTextLength => arg.parse::<u32>().is_ok(),
+        ProceduralOpKind::Remove => arg.is_empty(),
+        _ => !arg.is_empty(),
+    }
+}
+
+/// `/.../` arguments are regexes and have to compile; anything else is a
+/// plain substring.
+fn regex_arg_is_valid(arg: &str) -> bool {
+    match arg.strip_prefix('/').and_then(|rest| rest.strip_suffix('/')) {
+        Some(source) if arg.len() > 2 => bb_core::regex::compile_filter_regex(source, false).is_some(),
+        _ => true,
+    }
+}
+
+/// `display`, `-webkit-box-orient` or a `--custom` property.
+fn is_css_property_name(name: &str) -> bool {
+    let (body, custom) = match name.strip_prefix("--") {
+        Some(body) => (body, true),
+        None => (name.strip_prefix('-').unwrap_or(name), false),
+    };
+    (custom || body.starts_with(|c: char| c.is_ascii_alphabetic()))
+        && !body.is_empty()
+        && body.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Cheap shape check for a selector argument: brackets balance, no block
+/// syntax, and it doesn't start or end with a combinator.
+fn is_plausible_selector(selector: &str) -> bool {
+    if selector.is_empty()
+        || selector.contains(['{', '}', ';'])
+        || selector.starts_with(['>', '+', '~', ','])
+        || selector.ends_with(['>', '+', '~', ','])
+    {
+        return false;
+    }
+    let mut parens = 0i32;
+    let mut brackets = 0i32;
+    for c in selector.chars() {
+        match c {
+            '(' => parens += 1,
+            ')' => parens -= 1,
+            '[' => brackets += 1,
+            ']' => brackets -= 1,
+            _ => {}
+        }
+        if parens < 0 || brackets < 0 {
+            return false;
+        }
+    }
+    parens == 0 && brackets == 0
+}
+
+/// Split `selector:style(declarations)` into its parts when the selector is
+/// plain CSS and the declarations are safe to inject as a static stylesheet.
+fn split_style_selector(selector: &str) -> Option<(&str, &str)> {
+    let inner = selector.strip_suffix(')')?;
+    let pos = inner.rfind(":style(")?;
+    let base = inner[..pos].trim();
+    let style = inner[pos + ":style(".len()..].trim();
+    if base.is_empty() || is_procedural_selector(base) || !is_injectable_style(style) {
+        return None;
+    }
+
+    Some((base, style))
+}
+
+/// Declarations that can go into a static stylesheet as they are: nothing
+/// that loads resources, runs code or escapes the declaration block.
+fn is_injectable_style(style: &str) -> bool {
+    let style_lower = style.to_ascii_lowercase();
+    !(style.is_empty()
+        || style.contains(['{', '}', '\\', '<'])
+        || style.contains("/*")
+        || style_lower.contains("url(")
+        || style_lower.contains("expression(")
+        || style_lower.contains("image-set("))
+}
+
+/// AdGuard CSS injection, `domains#$#selector { declarations }` and its
+/// `#@$#` exception. Stored as a [`CosmeticStyleRule`], so it shares the
+/// `:style()` section and exceptions work across both syntaxes. AdGuard's
+/// `remove: true` pseudo-declaration and `#$?#` extended CSS are not
+/// supported.
+fn parse_css_injection_line(line: &str) -> Result<Option<CompiledRule>, DropReason> {
+    let (marker, is_exception, marker_pos) = if let Some(pos) = line.find("#@$#") {
+        ("#@$#", true, pos)
+    } else if let Some(pos) = line.find("#$#") {
+        ("#$#", false, pos)
+    } else {
+        return Ok(None);
+    };
+
+    let domain_part = line[..marker_pos].trim();
+    let body = line[marker_pos + marker.len()..].trim();
+    let (selector, style) = body
+        .strip_suffix('}')
+        .and_then(|inner| inner.split_once('{'))
+        .ok_or(DropReason::UnsupportedCosmetic)?;
+    let selector = selector.trim();
+    // Trailing semicolons dropped so `{ a: b; }` and `:style(a: b)` agree.
+    let style = style.trim_end_matches(|c: char| c == ';' || c.is_whitespace()).trim_start();
+    let removes = style.split(';').any(|declaration| {
+        let name = declaration.split(':').next().unwrap_or_default();
+        name.trim().eq_ignore_ascii_case("remove")
+    });
+    if selector.is_empty() || is_procedural_selector(selector) || removes || !is_injectable_style(style) {
+        return Err(DropReason::UnsupportedCosmetic);
+    }
+    if !is_exception {
+        check_injected_selector(selector)?;
+    }
+
+    let mut rule = make_special_rule();
+    rule.domain_constraints = parse_cosmetic_domains(domain_part);
+    rule.cosmetic_style = Some(Box::new(CosmeticStyleRule {
+        selector: selector.to_string(),
+        style: style.to_string(),
+        is_exception,
+        is_generic: domain_part.is_empty(),
+    }));
+    Ok(Some(rule))
+}
+
+fn parse_style_line(line: &str) -> Result<Option<CompiledRule>, DropReason> {
+    let (marker, is_exception, marker_pos) = if let Some(pos) = line.find("#@#") {
+        ("#@#", true, pos)
+    } else if let Some(pos) = line.find("##") {
+        ("##", false, pos)
+    } else {
+        return Ok(None);
+    };
+
+    let domain_part = line[..marker_pos].trim();
+    let Some((selector, style)) = split_style_selector(line[marker_pos + marker.len()..].trim()) else {
+        return Ok(None);
+    };
+    if !is_exception {
+        check_injected_selector(selector)?;
+    }
+
+    let mut rule = make_special_rule();
+    rule.domain_constraints = parse_cosmetic_domains(domain_part);
+    rule.cosmetic_style = Some(Box::new(CosmeticStyleRule {
+        selector: selector.to_string(),
+        style: style.to_string(),
+        is_exception,
+        is_generic: domain_part.is_empty(),
+    }));
+    Ok(Some(rule))
 }
 
-fn parse_procedural_line(line: &str) -> Option<CompiledRule> {
+fn parse_procedural_line(line: &str) -> Result<Option<CompiledRule>, DropReason> {
     let exception_marker = "#@?#";
     let normal_marker = "#?#";
 
@@ -853,39 +1673,41 @@ fn parse_procedural_line(line: &str) -> Option<CompiledRule> {
         if is_procedural_selector(selector) {
             ("#@#", true, pos)
         } else {
-            return None;
+            return Ok(None);
         }
     } else if let Some(pos) = line.find("##") {
         let selector = line[pos + 2..].trim();
         if is_procedural_selector(selector) {
             ("##", false, pos)
         } else {
-            return None;
+            return Ok(None);
         }
     } else {
-        return None;
+        return Ok(None);
     };
 
     let domain_part = line[..marker_pos].trim();
     let selector = line[marker_pos + marker.len()..].trim();
     if selector.is_empty() || selector.starts_with("+js(") {
-        return None;
+        return Ok(None);
     }
     if !is_procedural_selector(selector) {
-        return None;
+        return Ok(None);
     }
 
+    parse_procedural_selector(selector)?;
+
     let mut rule = make_special_rule();
     rule.domain_constraints = parse_cosmetic_domains(domain_part);
-    rule.procedural = Some(ProceduralRule {
+    rule.procedural = Some(Box::new(ProceduralRule {
         selector: selector.to_string(),
         is_exception,
         is_generic: domain_part.is_empty(),
-    });
-    Some(rule)
+    }));
+    Ok(Some(rule))
 }
 
-fn parse_cosmetic_line(line: &str) -> Option<CompiledRule> {
+fn parse_cosmetic_line(line: &str) -> Result<Option<CompiledRule>, DropReason> {
     let exception_marker = "#@#";
     let normal_marker = "##";
 
@@ -894,21 +1716,21 @@ fn parse_cosmetic_line(line: &str) -> Option<CompiledRule> {
     } else if let Some(pos) = line.find(normal_marker) {
         (normal_marker, false, pos)
     } else {
-        return None;
+        return Ok(None);
     };
 
     let domain_part = line[..marker_pos].trim();
     let selector = line[marker_pos + marker.len()..].trim();
     if selector.is_empty() {
-        return None;
+        return Ok(None);
     }
 
     if selector.starts_with('^') {
-        return None;
+        return Ok(None);
     }
 
     if selector.starts_with("+js(") {
-        return None;
+        return Ok(None);
     }
 
     if selector.contains(":has-text(")
@@ -918,23 +1740,188 @@ fn parse_cosmetic_line(line: &str) -> Option<CompiledRule> {
         || selector.contains(":remove(")
         || selector.contains(":style(")
     {
-        return None;
+        return Ok(None);
+    }
+
+    if !is_exception {
+        check_injected_selector(selector)?;
     }
 
     let mut rule = make_special_rule();
     rule.domain_constraints = parse_cosmetic_domains(domain_part);
     rule.cosmetic = Some(CosmeticRule {
-        selector: selector.to_string(),
+        selector: normalize_selector(selector),
         is_exception,
         is_generic: domain_part.is_empty(),
     });
-    Some(rule)
+    Ok(Some(rule))
+}
+
+/// Selectors end up in injected stylesheets, where one that fails to parse
+/// takes the rest of its CSS rule with it. Exceptions are never injected and
+/// are kept as written.
+fn check_injected_selector(selector: &str) -> Result<(), DropReason> {
+    match selector_parse_error(selector) {
+        Some(issue) => Err(DropReason::InvalidSelector(issue)),
+        None => Ok(()),
+    }
+}
+
+/// Structural checks a CSS parser would also fail on: unbalanced brackets
+/// or quotes, empty selector list items and dangling combinators.
+pub fn selector_issue(selector: &str) -> Option<&'static str> {
+    if selector.trim().is_empty() {
+        return Some("empty selector");
+    }
+    if selector.contains(['{', '}']) {
+        return Some("braces are not allowed in selectors");
+    }
+
+    let mut stack = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    // Last significant top-level character: None at the start of a list item.
+    let mut last: Option<char> = None;
+
+    for ch in selector.chars() {
+        if escaped {
+            escaped = false;
+            last = Some('a');
+            continue;
+        }
+        if ch == '\\' {
+            escaped = true;
+            continue;
+        }
+        if let Some(q) = quote {
+            if ch == q {
+                quote = None;
+            }
+            continue;
+        }
+        match ch {
+            '"' | '\'' => quote = Some(ch),
+            '(' | '[' => stack.push(ch),
+            ')' | ']' => {
+                let open = if ch == ')' { '(' } else { '[' };
+                if stack.pop() != Some(open) {
+                    return Some("unbalanced brackets");
+                }
+                if stack.is_empty() {
+                    last = Some(ch);
+                }
+            }
+            _ if !stack.is_empty() || ch.is_whitespace() => {}
+            '>' | '+' | '~' | ',' => {
+                if matches!(last, None | Some('>' | '+' | '~' | ',')) {
+                    return Some(if ch == ',' { "empty selector in list" } else { "dangling combinator" });
+                }
+                last = Some(ch);
+            }
+            _ => last = Some(ch),
+        }
+    }
+
+    if quote.is_some() {
+        return Some("unterminated string");
+    }
+    if !stack.is_empty() {
+        return Some("unbalanced brackets");
+    }
+    match last {
+        Some(',') => Some("empty selector in list"),
+        Some('>' | '+' | '~') => Some("dangling combinator"),
+        _ => None,
+    }
+}
+
+/// Canonical form of a plain CSS selector, so `#@#` exceptions cancel their
+/// targets even when lists format them differently. Top-level combinators
+/// are written as ` > `, ` + `, ` ~ ` and `, `; inside brackets and
+/// parentheses spaces around operators are dropped; other whitespace runs
+/// collapse to one space and single-quoted strings become double-quoted.
+/// Escapes and string contents are kept as written.
+pub fn normalize_selector(selector: &str) -> String {
+    fn is_operator(ch: char) -> bool {
+        matches!(ch, '>' | '+' | '~' | ',' | '=' | '|' | '^' | '$' | '*' | '(' | ')' | '[' | ']')
+    }
+
+    let mut out = String::with_capacity(selector.len() + 4);
+    let mut chars = selector.trim().chars().peekable();
+    let mut depth = 0usize;
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                out.push(ch);
+                if let Some(next) = chars.next() {
+                    out.push(next);
+                }
+            }
+            '"' | '\'' => {
+                let mut body = String::new();
+                while let Some(next) = chars.next() {
+                    if next == ch {
+                        break;
+                    }
+                    body.push(next);
+                    if next == '\\' {
+                        if let Some(escaped) = chars.next() {
+                            body.push(escaped);
+                        }
+                    }
+                }
+                let quote = if ch == '\'' && body.contains('"') { '\'' } else { '"' };
+                out.push(quote);
+                out.push_str(&body);
+                out.push(quote);
+            }
+            c if c.is_whitespace() => {
+                while chars.peek().is_some_and(|next| next.is_whitespace()) {
+                    chars.next();
+                }
+                let keep = if depth == 0 {
+                    !out.is_empty() && !chars.peek().is_some_and(|next| matches!(next, '>' | '+' | '~' | ','))
+                } else {
+                    !out.ends_with(is_operator) && !chars.peek().is_some_and(|&next| is_operator(next))
+                };
+                if keep {
+                    out.push(' ');
+                }
+            }
+            '>' | '+' | '~' | ',' if depth == 0 => {
+                let trimmed = out.trim_end().len();
+                out.truncate(trimmed);
+                if ch != ',' && !out.is_empty() {
+                    out.push(' ');
+                }
+                out.push(ch);
+                out.push(' ');
+                while chars.peek().is_some_and(|next| next.is_whitespace()) {
+                    chars.next();
+                }
+            }
+            '(' | '[' => {
+                depth += 1;
+                out.push(ch);
+            }
+            ')' | ']' => {
+                depth = depth.saturating_sub(1);
+                out.push(ch);
+            }
+            _ => out.push(ch),
+        }
+    }
+    out.truncate(out.trim_end().len());
+    out
 }
 
 struct ParsedPattern {
     domain: String,
     pattern: String,
     anchor_type: AnchorType,
+    /// `/.../` filter; `pattern` holds the regex source without slashes
+    is_regex: bool,
 }
 
 fn parse_pattern_rule(line: &str) -> Option<ParsedPattern> {
@@ -943,10 +1930,19 @@ fn parse_pattern_rule(line: &str) -> Option<ParsedPattern> {
         return None;
     }
 
-    let (anchor_type, rest) = if line.starts_with("||") {
-        (AnchorType::Hostname, &line[2..])
-    } else if line.starts_with('|') {
-        (AnchorType::Left, &line[1..])
+    if line.len() > 2 && line.starts_with('/') && line.ends_with('/') {
+        return Some(ParsedPattern {
+            domain: String::new(),
+            pattern: line[1..line.len() - 1].to_string(),
+            anchor_type: AnchorType::None,
+            is_regex: true,
+        });
+    }
+
+    let (anchor_type, rest) = if let Some(rest) = line.strip_prefix("||") {
+        (AnchorType::Hostname, rest)
+    } else if let Some(rest) = line.strip_prefix('|') {
+        (AnchorType::Left, rest)
     } else {
         (AnchorType::None, line)
     };
@@ -963,6 +1959,7 @@ fn parse_pattern_rule(line: &str) -> Option<ParsedPattern> {
         domain,
         pattern: rest.to_string(),
         anchor_type,
+        is_regex: false,
     })
 }
 
@@ -0,0 +1,427 @@
+//! Element picker and logger rule generation
+//!
+//! The extension's picker hands over the page hostname and a selector built
+//! from the clicked element. This turns them into the filter line the user
+//! would otherwise type (`host##selector`), optionally loosened so it keeps
+//! matching after the page reshuffles, and compiles it so the UI can show
+//! what will actually be stored before the rule is saved. The logger does
+//! the same for requests, offering network filters from the exact URL up to
+//! the whole site.
+
+use bb_core::psl::get_etld1;
+use bb_core::types::RequestType;
+use bb_core::url::{extract_host, get_host_position};
+
+use crate::diagnostics::DropReason;
+use crate::parser::{normalize_selector, parse_filter_list_with_diagnostics, selector_issue};
+
+/// How far to generalize a picked selector.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PickerOptions {
+    /// Drop `:nth-child()` / `:nth-of-type()` and their `-last-` variants
+    pub strip_nth: bool,
+    /// Drop `#id` parts, which are often generated per page load
+    pub strip_ids: bool,
+}
+
+/// What the generated line compiles to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PickerRuleKind {
+    /// Plain CSS, injected as a stylesheet
+    Hide,
+    /// Needs the content script's procedural engine
+    Procedural,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PickerRule {
+    /// The filter line to add to the user's list
+    pub filter: String,
+    /// Selector after generalization, as written into `filter`
+    pub selector: String,
+    pub kind: PickerRuleKind,
+    /// Selector as stored in the snapshot (normalized for plain CSS)
+    pub compiled_selector: String,
+}
+
+/// Why no rule could be generated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PickerIssue {
+    InvalidHostname(String),
+    InvalidUrl(String),
+    InvalidSelector(&'static str),
+    /// Nothing but `*` and combinators left after generalizing
+    TooBroad,
+    /// The compiler rejected the generated line
+    Dropped(DropReason),
+}
+
+impl std::fmt::Display for PickerIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidHostname(host) => write!(f, "invalid hostname '{}'", host),
+            Self::InvalidUrl(url) => write!(f, "cannot build a filter for '{}'", url),
+            Self::InvalidSelector(issue) => write!(f, "invalid selector: {}", issue),
+            Self::TooBroad => f.write_str("selector would match every element"),
+            Self::Dropped(reason) => write!(f, "rule would be dropped: {}", reason),
+        }
+    }
+}
+
+/// Build `hostname##selector` from a picked element.
+pub fn generate_cosmetic_rule(
+    hostname: &str,
+    selector: &str,
+    options: PickerOptions,
+) -> Result<PickerRule, PickerIssue> {
+    let host = hostname.trim().trim_end_matches('.').to_ascii_lowercase();
+    if !is_picker_hostname(&host) {
+        return Err(PickerIssue::InvalidHostname(hostname.to_string()));
+    }
+
+    let selector = selector.trim();
+    if let Some(issue) = selector_issue(selector) {
+        return Err(PickerIssue::InvalidSelector(issue));
+    }
+    let selector = generalize_selector(selector, options);
+    if selector
+        .chars()
+        .all(|c| c == '*' || c.is_whitespace() || matches!(c, '>' | '+' | '~' | ','))
+    {
+        return Err(PickerIssue::TooBroad);
+    }
+
+    let filter = format!("{}##{}", host, selector);
+    let (rules, diagnostics) = parse_filter_list_with_diagnostics(&filter);
+    if let Some(dropped) = diagnostics.dropped_lines.into_iter().next() {
+        return Err(PickerIssue::Dropped(dropped.reason));
+    }
+    let rule = rules.into_iter().next().ok_or(PickerIssue::Dropped(DropReason::UnsupportedCosmetic))?;
+    let (kind, compiled_selector) = match (rule.cosmetic, rule.procedural, rule.cosmetic_style) {
+        (Some(cosmetic), _, _) => (PickerRuleKind::Hide, cosmetic.selector),
+        (_, Some(procedural), _) => (PickerRuleKind::Procedural, procedural.selector),
+        (_, _, Some(style)) => (PickerRuleKind::Procedural, format!("{}:style({})", style.selector, style.style)),
+        _ => return Err(PickerIssue::Dropped(DropReason::UnsupportedCosmetic)),
+    };
+
+    Ok(PickerRule {
+        filter,
+        selector,
+        kind,
+        compiled_selector,
+    })
+}
+
+/// What a generated network filter covers, narrowest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NetworkRuleScope {
+    /// This URL only, query string included
+    Url,
+    /// Everything under the URL's directory
+    PathPrefix,
+    /// Every request to the hostname
+    Hostname,
+    /// Every request to the registrable domain and its subdomains
+    Domain,
+}
+
+impl NetworkRuleScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Url => "url",
+            Self::PathPrefix => "path",
+            Self::Hostname => "hostname",
+            Self::Domain => "domain",
+        }
+    }
+}
+
+/// Options the logger lets the user tick for generated network filters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetworkRuleChoices {
+    /// `@@` exception instead of a block
+    pub allow: bool,
+    /// Add `$third-party` when the request is third-party to `site`
+    pub third_party: bool,
+    /// Restrict to the request's type (`$script`, `$image`, ...)
+    pub match_type: bool,
+    /// Restrict to `site` with `$domain=`
+    pub site_only: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkRuleCandidate {
+    pub scope: NetworkRuleScope,
+    pub filter: String,
+}
+
+/// Candidate filters for a logged request, from the exact URL to its
+/// eTLD+1. Scopes that would repeat a broader one (no path below `/`, a
+/// hostname that already is its eTLD+1) are left out. `site` is the page
+/// hostname and only matters for `third_party` / `site_only`.
+pub fn generate_network_rule(
+    url: &str,
+    request_type: RequestType,
+    site: Option<&str>,
+    choices: NetworkRuleChoices,
+) -> Result<Vec<NetworkRuleCandidate>, PickerIssue> {
+    let invalid = || PickerIssue::InvalidUrl(url.to_string());
+    let host = extract_host(url)
+        .map(str::to_ascii_lowercase)
+        .filter(|host| is_picker_hostname(host))
+        .ok_or_else(invalid)?;
+    let (_, host_end) = get_host_position(url).ok_or_else(invalid)?;
+    let after_host = url[host_end..].split('#').next().unwrap_or("");
+    let (port, rest) = after_host.split_at(after_host.find(['/', '?']).unwrap_or(after_host.len()));
+    let (path, _) = rest.split_once('?').unwrap_or((rest, ""));
+    // `||host:port/` is not understood by the parser, so URLs with a port
+    // get a scheme-anchored pattern instead.
+    let prefix = if port.is_empty() {
+        format!("||{}", host)
+    } else {
+        let scheme_end = url.find("://").ok_or_else(invalid)?;
+        format!("|{}://{}{}", url[..scheme_end].to_ascii_lowercase(), host, port)
+    };
+
+    let mut options = Vec::new();
+    if choices.match_type {
+        if let Some(name) = type_option(request_type) {
+            options.push(name.to_string());
+        }
+    }
+    let site = site.map(str::trim).filter(|site| !site.is_empty()).map(str::to_ascii_lowercase);
+    if let Some(site) = &site {
+        if choices.third_party && get_etld1(site) != get_etld1(&host) {
+            options.push("third-party".to_string());
+        }
+        if choices.site_only && is_picker_hostname(site) {
+            options.push(format!("domain={}", site));
+        }
+    }
+
+    let mut candidates = Vec::new();
+    let mut push = |scope, pattern: String| {
+        candidates.push(NetworkRuleCandidate {
+            scope,
+            filter: network_filter(&pattern, &options, choices.allow),
+        });
+    };
+
+    if rest.len() > 1 {
+        push(NetworkRuleScope::Url, format!("{}{}|", prefix, escape_pattern(rest)));
+    }
+    let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+    if dir.len() > 1 && dir.len() < rest.len() {
+        push(NetworkRuleScope::PathPrefix, format!("{}{}", prefix, escape_pattern(dir)));
+    }
+    push(NetworkRuleScope::Hostname, format!("||{}^", host));
+    let domain = get_etld1(&host);
+    if !domain.is_empty() && domain != host {
+        push(NetworkRuleScope::Domain, format!("||{}^", domain));
+    }
+
+    for candidate in &candidates {
+        let (_, diagnostics) = parse_filter_list_with_diagnostics(&candidate.filter);
+        if let Some(dropped) = diagnostics.dropped_lines.into_iter().next() {
+            return Err(PickerIssue::Dropped(dropped.reason));
+        }
+    }
+    Ok(candidates)
+}
+
+fn network_filter(pattern: &str, options: &[String], allow: bool) -> String {
+    let mut filter = String::with_capacity(pattern.len() + 32);
+    if allow {
+        filter.push_str("@@");
+    }
+    filter.push_str(pattern);
+    if !options.is_empty() {
+        filter.push('$');
+        filter.push_str(&options.join(","));
+    }
+    filter
+}
+
+/// Filter syntax has no escapes: `*`, `^` and `|` would be read as
+/// wildcards or anchors and `$` would start the options, so each becomes a
+/// `*`. The filter then matches a superset of the URL, never less.
+fn escape_pattern(raw: &str) -> String {
+    let mut out = String::with_capacity(raw.len());
+    for ch in raw.chars() {
+        match ch {
+            '*' | '^' | '|' | '$' => {
+                if !out.ends_with('*') {
+                    out.push('*');
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The filter option naming a single request type.
+fn type_option(request_type: RequestType) -> Option<&'static str> {
+    Some(match request_type {
+        RequestType::SCRIPT => "script",
+        RequestType::IMAGE => "image",
+        RequestType::STYLESHEET => "stylesheet",
+        RequestType::OBJECT => "object",
+        RequestType::SUBDOCUMENT => "subdocument",
+        RequestType::MAIN_FRAME => "document",
+        RequestType::XMLHTTPREQUEST => "xmlhttprequest",
+        RequestType::MEDIA => "media",
+        RequestType::FONT => "font",
+        RequestType::PING => "ping",
+        RequestType::WEBSOCKET => "websocket",
+        RequestType::BEACON => "beacon",
+        RequestType::FETCH => "fetch",
+        RequestType::CSP_REPORT => "csp_report",
+        RequestType::MANIFEST => "manifest",
+        RequestType::XSLT => "xslt",
+        _ => return None,
+    })
+}
+
+fn is_picker_hostname(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host
+            .split('.')
+            .all(|label| !label.is_empty() && label.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_'))
+}
+
+const NTH_PSEUDOS: &[&str] = &[":nth-child(", ":nth-last-child(", ":nth-of-type(", ":nth-last-of-type("];
+
+/// Remove the parts `options` asks for. A compound left empty becomes `*`
+/// so the combinators around it keep their meaning.
+fn generalize_selector(selector: &str, options: PickerOptions) -> String {
+    if !options.strip_nth && !options.strip_ids {
+        return normalize_selector(selector);
+    }
+
+    let bytes = selector.as_bytes();
+    let mut out = String::with_capacity(selector.len());
+    // Whether nothing has been written for the current compound yet.
+    let mut compound_empty = true;
+    let mut removed = false;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let rest = &selector[i..];
+        let skip = if options.strip_ids && bytes[i] == b'#' {
+            Some(1 + ident_len(&rest[1..]))
+        } else if options.strip_nth {
+            NTH_PSEUDOS
+                .iter()
+                .find(|pseudo| rest.len() >= pseudo.len() && rest[..pseudo.len()].eq_ignore_ascii_case(pseudo))
+                .map(|pseudo| pseudo.len() + paren_body_len(&rest[pseudo.len()..]))
+        } else {
+            None
+        };
+        if let Some(len) = skip {
+            i += len;
+            removed = true;
+            continue;
+        }
+
+        let ch = rest.chars().next().unwrap_or_default();
+        let len = match ch {
+            '[' | '(' => balanced_len(rest),
+            '"' | '\'' => quoted_len(rest),
+            '\\' => 1 + rest[1..].chars().next().map_or(0, char::len_utf8),
+            _ => ch.len_utf8(),
+        };
+        let boundary = ch.is_whitespace() || matches!(ch, '>' | '+' | '~' | ',');
+        if boundary {
+            if compound_empty && removed {
+                out.push('*');
+            }
+            compound_empty = true;
+            removed = false;
+        } else {
+            compound_empty = false;
+        }
+        out.push_str(&rest[..len]);
+        i += len;
+    }
+    if compound_empty && removed {
+        out.push('*');
+    }
+
+    normalize_selector(&out)
+}
+
+/// Length of a CSS identifier at the start of `s`, escapes included.
+fn ident_len(s: &str) -> usize {
+    let mut chars = s.char_indices();
+    while let Some((pos, ch)) = chars.next() {
+        if ch == '\\' {
+            chars.next();
+        } else if !(ch.is_alphanumeric() || ch == '-' || ch == '_' || !ch.is_ascii()) {
+            return pos;
+        }
+    }
+    s.len()
+}
+
+/// Length up to and including the `)` closing an already-opened paren.
+fn paren_body_len(s: &str) -> usize {
+    let mut depth = 1usize;
+    for (pos, ch) in s.char_indices() {
+        match ch {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return pos + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+/// Length of a `[...]` / `(...)` group at the start of `s`, skipping quotes.
+fn balanced_len(s: &str) -> usize {
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut escaped = false;
+    for (pos, ch) in s.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, ch) {
+            (_, '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(ch),
+            (None, '[' | '(') => depth += 1,
+            (None, ']' | ')') => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    return pos + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+fn quoted_len(s: &str) -> usize {
+    let quote = s.chars().next().unwrap_or('"');
+    let mut escaped = false;
+    for (pos, ch) in s.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if ch == '\\' {
+            escaped = true;
+        } else if ch == quote {
+            return pos + 1;
+        }
+    }
+    s.len()
+}
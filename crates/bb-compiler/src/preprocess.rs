@@ -0,0 +1,263 @@
+//! Filter list preprocessor
+//!
+//! Handles the uBO directives `!#include <file>` and
+//! `!#if <condition>` / `!#else` / `!#endif` before a list is parsed.
+//! Where included files come from is up to the caller via [`IncludeResolver`].
+
+use std::collections::HashSet;
+
+/// Nested includes deeper than this are ignored.
+pub const MAX_INCLUDE_DEPTH: usize = 4;
+
+/// Flags that are always true, since the compiled rules target a uBO-compatible engine.
+pub const DEFAULT_ENV_FLAGS: &[&str] = &["ext_ublock"];
+
+/// Supplies the text of `!#include` targets.
+pub trait IncludeResolver {
+    /// Return the contents of `name`, or `None` if it can't be included.
+    fn resolve(&self, name: &str) -> Option<String>;
+}
+
+impl<F> IncludeResolver for F
+where
+    F: Fn(&str) -> Option<String>,
+{
+    fn resolve(&self, name: &str) -> Option<String> {
+        self(name)
+    }
+}
+
+/// Resolver that rejects every include.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoIncludes;
+
+impl IncludeResolver for NoIncludes {
+    fn resolve(&self, _name: &str) -> Option<String> {
+        None
+    }
+}
+
+/// Condition environment for `!#if` blocks (e.g. `env_chromium`, `env_mobile`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreprocessEnv {
+    flags: HashSet<String>,
+}
+
+impl Default for PreprocessEnv {
+    fn default() -> Self {
+        Self::new(DEFAULT_ENV_FLAGS.iter().copied())
+    }
+}
+
+impl PreprocessEnv {
+    pub fn new<'a>(flags: impl IntoIterator<Item = &'a str>) -> Self {
+        Self {
+            flags: flags.into_iter().map(str::to_string).collect(),
+        }
+    }
+
+    /// Add a flag, e.g. `env_firefox`.
+    pub fn with_flag(mut self, flag: &str) -> Self {
+        self.flags.insert(flag.to_string());
+        self
+    }
+
+    pub fn is_set(&self, flag: &str) -> bool {
+        self.flags.contains(flag)
+    }
+
+    /// Evaluate a `!#if` condition. Unknown flags are false.
+    pub fn evaluate(&self, condition: &str) -> bool {
+        let Some(tokens) = tokenize_condition(condition) else {
+            return false;
+        };
+        let mut pos = 0;
+        let value = self.eval_or(&tokens, &mut pos);
+        value && pos == tokens.len()
+    }
+
+    fn eval_or(&self, tokens: &[CondToken<'_>], pos: &mut usize) -> bool {
+        let mut value = self.eval_and(tokens, pos);
+        while tokens.get(*pos) == Some(&CondToken::Or) {
+            *pos += 1;
+            let rhs = self.eval_and(tokens, pos);
+            value = value || rhs;
+        }
+        value
+    }
+
+    fn eval_and(&self, tokens: &[CondToken<'_>], pos: &mut usize) -> bool {
+        let mut value = self.eval_unary(tokens, pos);
+        while tokens.get(*pos) == Some(&CondToken::And) {
+            *pos += 1;
+            let rhs = self.eval_unary(tokens, pos);
+            value = value && rhs;
+        }
+        value
+    }
+
+    fn eval_unary(&self, tokens: &[CondToken<'_>], pos: &mut usize) -> bool {
+        match tokens.get(*pos) {
+            Some(CondToken::Not) => {
+                *pos += 1;
+                !self.eval_unary(tokens, pos)
+            }
+            Some(CondToken::Open) => {
+                *pos += 1;
+                let value = self.eval_or(tokens, pos);
+                if tokens.get(*pos) == Some(&CondToken::Close) {
+                    *pos += 1;
+                }
+                value
+            }
+            Some(CondToken::Flag(flag)) => {
+                *pos += 1;
+                self.is_set(flag)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CondToken<'a> {
+    Flag(&'a str),
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+/// Split a condition into tokens; `None` on characters outside the grammar.
+fn tokenize_condition(condition: &str) -> Option<Vec<CondToken<'_>>> {
+    let mut tokens = Vec::new();
+    let bytes = condition.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b' ' | b'\t' => i += 1,
+            b'!' => {
+                tokens.push(CondToken::Not);
+                i += 1;
+            }
+            b'(' => {
+                tokens.push(CondToken::Open);
+                i += 1;
+            }
+            b')' => {
+                tokens.push(CondToken::Close);
+                i += 1;
+            }
+            b'&' if bytes.get(i + 1) == Some(&b'&') => {
+                tokens.push(CondToken::And);
+                i += 2;
+            }
+            b'|' if bytes.get(i + 1) == Some(&b'|') => {
+                tokens.push(CondToken::Or);
+                i += 2;
+            }
+            b if b.is_ascii_alphanumeric() || b == b'_' => {
+                let start = i;
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                tokens.push(CondToken::Flag(&condition[start..i]));
+            }
+            _ => return None,
+        }
+    }
+    Some(tokens)
+}
+
+/// Result of preprocessing one list.
+#[derive(Debug, Clone, Default)]
+pub struct Preprocessed {
+    pub text: String,
+    /// Includes that were resolved and spliced in
+    pub included: Vec<String>,
+    /// Includes the resolver could not provide, or that were skipped as cycles
+    pub unresolved: Vec<String>,
+}
+
+/// Apply `!#if` and `!#include` directives to a list.
+///
+/// Directive lines and lines in inactive `!#if` branches become blank, and
+/// included text is appended after the list, so line numbers in compile
+/// diagnostics still refer to the original file.
+pub fn preprocess_filter_list(
+    text: &str,
+    env: &PreprocessEnv,
+    resolver: &dyn IncludeResolver,
+) -> Preprocessed {
+    let mut preprocessor = Preprocessor {
+        env,
+        resolver,
+        seen: HashSet::new(),
+        result: Preprocessed::default(),
+    };
+    let text = preprocessor.run(text, 0);
+    Preprocessed {
+        text,
+        ..preprocessor.result
+    }
+}
+
+struct Preprocessor<'a> {
+    env: &'a PreprocessEnv,
+    resolver: &'a dyn IncludeResolver,
+    seen: HashSet<String>,
+    result: Preprocessed,
+}
+
+impl Preprocessor<'_> {
+    fn run(&mut self, text: &str, depth: usize) -> String {
+        let mut output = String::with_capacity(text.len());
+        let mut appended = String::new();
+        // One entry per open `!#if`: (branch taken, parent active)
+        let mut conditions: Vec<(bool, bool)> = Vec::new();
+
+        for line in text.lines() {
+            let trimmed = line.trim();
+            let active = conditions.last().is_none_or(|&(taken, parent)| taken && parent);
+
+            if let Some(condition) = trimmed.strip_prefix("!#if ") {
+                conditions.push((self.env.evaluate(condition.trim()), active));
+            } else if trimmed == "!#else" {
+                if let Some(top) = conditions.last_mut() {
+                    top.0 = !top.0;
+                }
+            } else if trimmed == "!#endif" {
+                conditions.pop();
+            } else if let Some(name) = trimmed.strip_prefix("!#include ") {
+                let name = name.trim();
+                if active && !name.is_empty() {
+                    appended.push_str(&self.include(name, depth));
+                }
+            } else if active {
+                output.push_str(line);
+            }
+            output.push('\n');
+        }
+
+        output.push_str(&appended);
+        output
+    }
+
+    fn include(&mut self, name: &str, depth: usize) -> String {
+        if depth >= MAX_INCLUDE_DEPTH || !self.seen.insert(name.to_string()) {
+            self.result.unresolved.push(name.to_string());
+            return String::new();
+        }
+        match self.resolver.resolve(name) {
+            Some(body) => {
+                self.result.included.push(name.to_string());
+                self.run(&body, depth + 1)
+            }
+            None => {
+                self.result.unresolved.push(name.to_string());
+                String::new()
+            }
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Public Suffix List parser
+//!
+//! Parses the `public_suffix_list.dat` format into the three rule sets
+//! consumed by `bb_core::psl` (exact, wildcard, exception).
+
+/// Parsed PSL rules, stored as normalized suffix strings.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PslRules {
+    /// Exact rules (e.g., "com", "co.uk")
+    pub exact: Vec<String>,
+    /// Wildcard rules (e.g., "*.ck" stored as "ck")
+    pub wildcard: Vec<String>,
+    /// Exception rules (e.g., "!www.ck" stored as "www.ck")
+    pub exception: Vec<String>,
+}
+
+impl PslRules {
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcard.is_empty() && self.exception.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.exact.len() + self.wildcard.len() + self.exception.len()
+    }
+}
+
+/// Parse `public_suffix_list.dat` text.
+///
+/// Both the ICANN and PRIVATE sections are included. Rules containing
+/// non-ASCII labels are skipped since hosts reach the matcher in punycode.
+pub fn parse_psl(text: &str) -> PslRules {
+    let mut rules = PslRules::default();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+
+        // Only the first whitespace-delimited token is significant.
+        let rule = match line.split_whitespace().next() {
+            Some(rule) => rule.trim_end_matches('.'),
+            None => continue,
+        };
+        if rule.is_empty() || !rule.is_ascii() {
+            continue;
+        }
+
+        let rule = rule.to_ascii_lowercase();
+        if let Some(rest) = rule.strip_prefix('!') {
+            if !rest.is_empty() {
+                rules.exception.push(rest.to_string());
+            }
+        } else if let Some(rest) = rule.strip_prefix("*.") {
+            if !rest.is_empty() {
+                rules.wildcard.push(rest.to_string());
+            }
+        } else if !rule.contains('*') {
+            rules.exact.push(rule);
+        }
+    }
+
+    rules
+}
@@ -0,0 +1,110 @@
+//! Redirect resource registry
+//!
+//! `$redirect=` / `$redirect-rule=` targets must name a resource the
+//! extension actually ships under `resources/redirects/`. Lists use uBO and
+//! ABP names interchangeably (`noopjs`, `noop.js`, `abp-resource:blank-js`),
+//! so names are normalized to the canonical one here, which also lets
+//! redirect exceptions match the rules they cancel.
+//!
+//! The resources are small enough to carry in the compiler as well, so a
+//! snapshot can embed them as `data:` URLs for callers that can't serve the
+//! extension's files.
+
+/// A redirect resource bundled with the extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectResourceSpec {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    /// Path under the extension's `resources/` directory
+    pub path: &'static str,
+    pub mime: &'static str,
+    /// CRC32 of the bundled file, for checking the build shipped what the
+    /// snapshot expects
+    pub checksum: Option<u32>,
+    /// Contents of the bundled file
+    pub data: &'static [u8],
+}
+
+impl RedirectResourceSpec {
+    /// The resource as a base64 `data:` URL.
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.mime, base64_encode(self.data))
+    }
+}
+
+pub const REDIRECT_REGISTRY: &[RedirectResourceSpec] = &[
+    res(
+        "1x1.gif",
+        &["1x1-transparent.gif", "abp-resource:1x1-transparent-gif"],
+        "/redirects/1x1.gif",
+        "image/gif",
+        Some(0x9acc_eab1),
+        include_bytes!("../../../extension/resources/redirects/1x1.gif"),
+    ),
+    res(
+        "noop.html",
+        &["noopframe", "abp-resource:blank-html"],
+        "/redirects/noop.html",
+        "text/html",
+        Some(0xda33_02af),
+        include_bytes!("../../../extension/resources/redirects/noop.html"),
+    ),
+    res(
+        "noop.js",
+        &["noopjs", "abp-resource:blank-js"],
+        "/redirects/noop.js",
+        "application/javascript",
+        Some(0x6004_9fc2),
+        include_bytes!("../../../extension/resources/redirects/noop.js"),
+    ),
+    res(
+        "noop.txt",
+        &["nooptext", "abp-resource:blank-text"],
+        "/redirects/noop.txt",
+        "text/plain",
+        Some(0x0000_0000),
+        include_bytes!("../../../extension/resources/redirects/noop.txt"),
+    ),
+];
+
+const fn res(
+    name: &'static str,
+    aliases: &'static [&'static str],
+    path: &'static str,
+    mime: &'static str,
+    checksum: Option<u32>,
+    data: &'static [u8],
+) -> RedirectResourceSpec {
+    RedirectResourceSpec {
+        name,
+        aliases,
+        path,
+        mime,
+        checksum,
+        data,
+    }
+}
+
+/// Look up a redirect resource by canonical name or alias.
+pub fn lookup_redirect(name: &str) -> Option<&'static RedirectResourceSpec> {
+    REDIRECT_REGISTRY
+        .iter()
+        .find(|spec| spec.name == name || spec.aliases.contains(&name))
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+        let bits = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,488 @@
+//! Parsed rules as JSON
+//!
+//! [`rules_to_json`] and [`rules_from_json`] round-trip [`CompiledRule`]s
+//! through a versioned JSON document, so tools can generate or prune rule
+//! sets and hand them to [`build_snapshot`](crate::build_snapshot) without
+//! writing filter syntax:
+//!
+//! ```json
+//! { "version": 1, "rules": [
+//!   { "action": "block", "pattern": "ads.example/banner", "anchor": "hostname",
+//!     "domain": "ads.example", "types": ["image"], "domains": { "include": ["news.example"] } }
+//! ] }
+//! ```
+//!
+//! Flags and masks are lists of lowercase names (`"important"`,
+//! `"third_party"`, `"xmlhttprequest"`); domains are names, hashed on load.
+//! Readers reject documents from a newer [`RULES_JSON_VERSION`].
+
+use bb_core::types::RuleAction;
+use bitflags::Flags;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::parser::{
+    constraint_domain, parse_procedural_selector, AnchorType, CompiledRule, CosmeticRule, CosmeticStyleRule,
+    DomainConstraint, HeaderSpec, ProceduralRule, RemoveHeaderSpec, ReplaceSpec, ResponseHeaderRule, ScriptletRule,
+};
+
+/// Schema version written by [`rules_to_json`].
+pub const RULES_JSON_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleSetJson {
+    pub version: u32,
+    pub rules: Vec<RuleJson>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RuleJson {
+    pub action: ActionJson,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<String>,
+    /// Host of a `||host^` rule, or the host part of a `||host/path` pattern
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub domain: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    #[serde(skip_serializing_if = "is_default")]
+    pub anchor: AnchorJson,
+    #[serde(skip_serializing_if = "is_default")]
+    pub list_id: u16,
+    #[serde(skip_serializing_if = "is_default")]
+    pub line: u32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub types: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub party: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub schemes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domains: Option<DomainsJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub removeparam: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub csp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<HeaderJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replace: Option<ReplaceJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub removeheader: Option<RemoveHeaderJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cosmetic: Option<SelectorJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cosmetic_style: Option<StyleJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub procedural: Option<SelectorJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scriptlet: Option<ScriptletJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub responseheader: Option<ResponseHeaderJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u32>,
+    #[serde(skip_serializing_if = "is_default")]
+    pub badfilter: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ActionJson {
+    Allow,
+    #[default]
+    Block,
+    RedirectRule,
+    Removeparam,
+    Csp,
+    HeaderBlock,
+    HeaderAllow,
+    /// Placeholder action of cosmetic, scriptlet and `##^` rules
+    Special,
+    Replace,
+    Removeheader,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnchorJson {
+    #[default]
+    None,
+    Left,
+    Hostname,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DomainsJson {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeaderJson {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub negate: bool,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub request: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoveHeaderJson {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub request: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplaceJson {
+    pub pattern: String,
+    pub replacement: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub flags: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub exception: bool,
+}
+
+/// A `##` or procedural selector.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SelectorJson {
+    pub selector: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub exception: bool,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub generic: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StyleJson {
+    pub selector: String,
+    pub style: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub exception: bool,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub generic: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptletJson {
+    pub scriptlet: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub exception: bool,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub generic: bool,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub name_only: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResponseHeaderJson {
+    pub header: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub exception: bool,
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// Serialize rules as a [`RULES_JSON_VERSION`] document.
+pub fn rules_to_json(rules: &[CompiledRule]) -> String {
+    let document = RuleSetJson {
+        version: RULES_JSON_VERSION,
+        rules: rules.iter().map(RuleJson::from).collect(),
+    };
+    serde_json::to_string(&document).expect("rule JSON types always serialize")
+}
+
+/// Parse a document written by [`rules_to_json`] or by external tooling.
+pub fn rules_from_json(json: &str) -> Result<Vec<CompiledRule>, Error> {
+    let document: RuleSetJson =
+        serde_json::from_str(json).map_err(|e| Error::RulesJson { message: e.to_string() })?;
+    if document.version == 0 || document.version > RULES_JSON_VERSION {
+        return Err(Error::RulesJsonVersion {
+            found: document.version,
+            supported: RULES_JSON_VERSION,
+        });
+    }
+    document
+        .rules
+        .iter()
+        .enumerate()
+        .map(|(index, rule)| {
+            CompiledRule::try_from(rule).map_err(|message| Error::RulesJson {
+                message: format!("rule {}: {}", index, message),
+            })
+        })
+        .collect()
+}
+
+impl From<&CompiledRule> for RuleJson {
+    fn from(rule: &CompiledRule) -> Self {
+        Self {
+            action: match rule.action {
+                RuleAction::Allow => ActionJson::Allow,
+                RuleAction::Block => ActionJson::Block,
+                RuleAction::RedirectDirective => ActionJson::RedirectRule,
+                RuleAction::Removeparam => ActionJson::Removeparam,
+                RuleAction::CspInject => ActionJson::Csp,
+                RuleAction::HeaderMatchBlock => ActionJson::HeaderBlock,
+                RuleAction::HeaderMatchAllow => ActionJson::HeaderAllow,
+                RuleAction::ResponseCancel => ActionJson::Special,
+                RuleAction::ResponseReplace => ActionJson::Replace,
+                RuleAction::RemoveHeader => ActionJson::Removeheader,
+            },
+            flags: flag_names(rule.flags),
+            domain: rule.domain.clone(),
+            pattern: rule.pattern.clone(),
+            anchor: match rule.anchor_type {
+                AnchorType::None => AnchorJson::None,
+                AnchorType::Left => AnchorJson::Left,
+                AnchorType::Hostname => AnchorJson::Hostname,
+            },
+            list_id: rule.list_id,
+            line: rule.line,
+            types: flag_names(rule.type_mask),
+            party: flag_names(rule.party_mask),
+            schemes: flag_names(rule.scheme_mask),
+            domains: rule.domain_constraints.as_ref().map(|constraints| DomainsJson {
+                include: constraints.include_names.clone(),
+                exclude: constraints.exclude_names.clone(),
+            }),
+            redirect: rule.redirect.clone(),
+            removeparam: rule.removeparam.clone(),
+            csp: rule.csp.clone(),
+            header: rule.header.as_ref().map(|header| HeaderJson {
+                name: header.name.clone(),
+                value: header.value.clone(),
+                negate: header.negate,
+                request: header.request,
+            }),
+            replace: rule.replace.as_ref().map(|replace| ReplaceJson {
+                pattern: replace.pattern.clone(),
+                replacement: replace.replacement.clone(),
+                flags: replace.flags.clone(),
+                exception: replace.is_exception,
+            }),
+            removeheader: rule.removeheader.as_ref().map(|removeheader| RemoveHeaderJson {
+                name: removeheader.name.clone(),
+                request: removeheader.request,
+            }),
+            cosmetic: rule.cosmetic.as_ref().map(|cosmetic| SelectorJson {
+                selector: cosmetic.selector.clone(),
+                exception: cosmetic.is_exception,
+                generic: cosmetic.is_generic,
+            }),
+            cosmetic_style: rule.cosmetic_style.as_ref().map(|style| StyleJson {
+                selector: style.selector.clone(),
+                style: style.style.clone(),
+                exception: style.is_exception,
+                generic: style.is_generic,
+            }),
+            procedural: rule.procedural.as_ref().map(|procedural| SelectorJson {
+                selector: procedural.selector.clone(),
+                exception: procedural.is_exception,
+                generic: procedural.is_generic,
+            }),
+            scriptlet: rule.scriptlet.as_ref().map(|scriptlet| ScriptletJson {
+                scriptlet: scriptlet.scriptlet.clone(),
+                exception: scriptlet.is_exception,
+                generic: scriptlet.is_generic,
+                name_only: scriptlet.name_only,
+            }),
+            responseheader: rule.responseheader.as_ref().map(|responseheader| ResponseHeaderJson {
+                header: responseheader.header.clone(),
+                exception: responseheader.is_exception,
+            }),
+            expires_at: rule.expires_at,
+            badfilter: rule.is_badfilter,
+        }
+    }
+}
+
+impl TryFrom<&RuleJson> for CompiledRule {
+    type Error = String;
+
+    fn try_from(rule: &RuleJson) -> Result<Self, Self::Error> {
+        let domain_constraints = match &rule.domains {
+            Some(domains) => {
+                let normalize = |names: &[String]| {
+                    names
+                        .iter()
+                        .map(|name| constraint_domain(name).ok_or_else(|| format!("invalid domain '{}'", name)))
+                        .collect::<Result<(Vec<_>, Vec<_>), _>>()
+                };
+                let (include_names, include) = normalize(&domains.include)?;
+                let (exclude_names, exclude) = normalize(&domains.exclude)?;
+                Some(Box::new(DomainConstraint {
+                    include,
+                    exclude,
+                    include_names,
+                    exclude_names,
+                }))
+            }
+            None => None,
+        };
+        if let Some(procedural) = &rule.procedural {
+            parse_procedural_selector(&procedural.selector)
+                .map_err(|reason| format!("procedural selector '{}': {}", procedural.selector, reason))?;
+        }
+
+        Ok(Self {
+            action: match rule.action {
+                ActionJson::Allow => RuleAction::Allow,
+                ActionJson::Block => RuleAction::Block,
+                ActionJson::RedirectRule => RuleAction::RedirectDirective,
+                ActionJson::Removeparam => RuleAction::Removeparam,
+                ActionJson::Csp => RuleAction::CspInject,
+                ActionJson::HeaderBlock => RuleAction::HeaderMatchBlock,
+                ActionJson::HeaderAllow => RuleAction::HeaderMatchAllow,
+                ActionJson::Special => RuleAction::ResponseCancel,
+                ActionJson::Replace => RuleAction::ResponseReplace,
+                ActionJson::Removeheader => RuleAction::RemoveHeader,
+            },
+            flags: parse_flags(&rule.flags)?,
+            domain: rule.domain.to_ascii_lowercase(),
+            pattern: rule.pattern.clone(),
+            anchor_type: match rule.anchor {
+                AnchorJson::None => AnchorType::None,
+                AnchorJson::Left => AnchorType::Left,
+                AnchorJson::Hostname => AnchorType::Hostname,
+            },
+            list_id: rule.list_id,
+            line: rule.line,
+            type_mask: parse_flags(&rule.types)?,
+            party_mask: parse_flags(&rule.party)?,
+            scheme_mask: parse_flags(&rule.schemes)?,
+            domain_constraints,
+            redirect: rule.redirect.clone(),
+            removeparam: rule.removeparam.clone(),
+            csp: rule.csp.clone(),
+            header: rule.header.as_ref().map(|header| Box::new(HeaderSpec {
+                name: header.name.clone(),
+                value: header.value.clone(),
+                negate: header.negate,
+                request: header.request,
+            })),
+            replace: rule.replace.as_ref().map(|replace| Box::new(ReplaceSpec {
+                pattern: replace.pattern.clone(),
+                replacement: replace.replacement.clone(),
+                flags: replace.flags.clone(),
+                is_exception: replace.exception,
+            })),
+            removeheader: rule.removeheader.as_ref().map(|removeheader| Box::new(RemoveHeaderSpec {
+                name: removeheader.name.clone(),
+                request: removeheader.request,
+            })),
+            cosmetic: rule.cosmetic.as_ref().map(|cosmetic| CosmeticRule {
+                selector: cosmetic.selector.clone(),
+                is_exception: cosmetic.exception,
+                is_generic: cosmetic.generic,
+            }),
+            cosmetic_style: rule.cosmetic_style.as_ref().map(|style| Box::new(CosmeticStyleRule {
+                selector: style.selector.clone(),
+                style: style.style.clone(),
+                is_exception: style.exception,
+                is_generic: style.generic,
+            })),
+            procedural: rule.procedural.as_ref().map(|procedural| Box::new(ProceduralRule {
+                selector: procedural.selector.clone(),
+                is_exception: procedural.exception,
+                is_generic: procedural.generic,
+            })),
+            scriptlet: rule.scriptlet.as_ref().map(|scriptlet| Box::new(ScriptletRule {
+                scriptlet: scriptlet.scriptlet.clone(),
+                is_exception: scriptlet.exception,
+                is_generic: scriptlet.generic,
+                name_only: scriptlet.name_only,
+            })),
+            responseheader: rule.responseheader.as_ref().map(|responseheader| Box::new(ResponseHeaderRule {
+                header: responseheader.header.clone(),
+                is_exception: responseheader.exception,
+            })),
+            expires_at: rule.expires_at,
+            is_badfilter: rule.badfilter,
+        })
+    }
+}
+
+fn flag_names<F: Flags>(flags: F) -> Vec<String> {
+    flags.iter_names().map(|(name, _)| name.to_ascii_lowercase()).collect()
+}
+
+fn parse_flags<F: Flags>(names: &[String]) -> Result<F, String> {
+    names.iter().try_fold(F::empty(), |flags, name| {
+        let flag = F::from_name(&name.to_ascii_uppercase()).ok_or_else(|| format!("unknown flag '{}'", name))?;
+        Ok(flags.union(flag))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bb_core::hash::hash_domain;
+    use bb_core::types::{MatchDecision, RequestContext, RequestType};
+    use bb_core::{Matcher, Snapshot};
+
+    use super::*;
+    use crate::builder::build_snapshot;
+    use crate::parser::parse_filter_list;
+
+    #[test]
+    fn rules_round_trip_through_json() {
+        let rules = parse_filter_list(
+            "||ads.example^$third-party,domain=news.example|~shop.news.example\n\
+             /banner/*.gif$image,match-case,important\n\
+             @@||ads.example/ok.js$script,elemhide\n\
+             ||cdn.example/lib.js$script,redirect=noop.js\n\
+             ||track.example^$removeparam=utm_source\n\
+             ||example.com^$csp=script-src 'none'\n\
+             ||example.com^$removeheader=request:x-client\n\
+             ||example.com^$expires=1700000000\n\
+             example.com##.ad\n\
+             example.com#@#.ad\n\
+             ##div:has(> .sponsor)\n\
+             example.com##+js(set-constant, ads, false)\n\
+             example.com##^responseheader(refresh)\n\
+             ||ads.example^$badfilter\n",
+        );
+        let json = rules_to_json(&rules);
+        assert_eq!(rules_from_json(&json).expect("round trip"), rules);
+        assert_eq!(build_snapshot(&rules_from_json(&json).unwrap()), build_snapshot(&rules));
+
+        // Hand-written rules need only the fields they use.
+        let external = r#"{"version": 1, "rules": [
+            {"action": "block", "domain": "ads.example", "anchor": "hostname", "party": ["third_party"]},
+            {"action": "allow", "pattern": "ads.example/ok/", "anchor": "hostname", "types": ["script"],
+             "domains": {"include": ["News.Example"]}}
+        ]}"#;
+        let rules = rules_from_json(external).expect("valid document");
+        assert_eq!(rules[0].party_mask, bb_core::types::PartyMask::THIRD_PARTY);
+        assert_eq!(rules[1].domain_constraints.as_ref().unwrap().include, vec![hash_domain("news.example")]);
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+        let matcher = Matcher::new(&snapshot);
+        let decide = |url: &str, initiator: &str| {
+            let request = RequestContext::builder(url, RequestType::SCRIPT)
+                .initiator(initiator)
+                .build()
+                .expect("valid request");
+            matcher.match_request(&request.context()).decision
+        };
+        assert_eq!(decide("https://ads.example/x.js", "https://news.example/"), MatchDecision::Block);
+        assert_eq!(decide("https://ads.example/ok/x.js", "https://news.example/"), MatchDecision::Allow);
+
+        let error = rules_from_json(r#"{"version": 2, "rules": []}"#).unwrap_err();
+        assert_eq!(error.code(), "rules-json-version");
+        let error = rules_from_json(r#"{"version": 1, "rules": [{"types": ["gopher"]}]}"#).unwrap_err();
+        assert_eq!(error.to_string(), "invalid rules JSON: rule 0: unknown flag 'gopher'");
+    }
+}
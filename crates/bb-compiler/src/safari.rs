@@ -0,0 +1,590 @@
+//! Safari content blocker export
+//!
+//! Converts compiled rules into the JSON a Safari (macOS / iOS 15+) content
+//! blocker loads. Safari runs triggers in order, and `ignore-previous-rules`
+//! cancels every earlier rule that matched the same load, so uBO's
+//! precedence comes from where each rule lands:
+//!
+//! | stage | rules                                   |
+//! |-------|-----------------------------------------|
+//! | 1     | generic `##` hide rules                 |
+//! | 2     | `@@...$generichide`                     |
+//! | 3     | domain-specific `##` hide rules         |
+//! | 4     | `@@...$elemhide`                        |
+//! | 5     | blocks                                  |
+//! | 6     | `@@` exceptions, including `$document`  |
+//! | 7     | `$important` blocks                     |
+//! | 8     | `@@...$important`                       |
+//!
+//! Hide rules apply to the document load, so exceptions without a type skip
+//! `document`: `@@||cdn.example^` must not cancel the hiding on cdn.example.
+//!
+//! `url-filter` is a restricted regex (ASCII, no alternation, no counted
+//! repetition or classes like `\d`). Anything that can't be expressed is
+//! reported with a reason instead of being approximated.
+
+use std::fmt;
+
+use bb_core::types::{PartyMask, RequestType, RuleAction, RuleFlags, SchemeMask};
+use serde::Serialize;
+
+use crate::parser::{AnchorType, CompiledRule};
+
+/// Rules per content blocker Safari accepts.
+pub const MAX_RULES: usize = 150_000;
+
+/// Matches the scheme and any subdomains in front of a `||` anchor.
+const HOST_ANCHOR: &str = "^[a-z][a-z0-9.+-]*://([^/?#]*\\.)?";
+/// A `^` in the middle of a pattern.
+const SEPARATOR: &str = "[^a-zA-Z0-9_.%-]";
+/// A trailing `^`: a separator or the end of the URL.
+const TRAILING_SEPARATOR: &str = "([^a-zA-Z0-9_.%-].*)?$";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SafariRule {
+    pub trigger: SafariTrigger,
+    pub action: SafariAction,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SafariTrigger {
+    pub url_filter: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub url_filter_is_case_sensitive: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub if_domain: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unless_domain: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub if_top_url: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub resource_type: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub load_type: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub load_context: Vec<&'static str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SafariAction {
+    #[serde(rename = "type")]
+    pub action_type: SafariActionType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selector: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SafariActionType {
+    Block,
+    IgnorePreviousRules,
+    CssDisplayNone,
+}
+
+/// Why a rule has no content blocker equivalent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SafariSkipReason {
+    /// `$redirect=`; a content blocker can't serve resources
+    Redirect,
+    /// `$redirect-rule=` only redirects what something else blocked
+    RedirectRule,
+    /// `$removeparam`, `$csp`, `$removeheader`, `$header` and `$replace`
+    /// change or inspect requests rather than block them
+    Modifier,
+    /// `@@...$csp`, `@@...$removeparam` and the like
+    ModifierException,
+    /// `@@...$genericblock`; Safari has no notion of generic rules
+    GenericBlock,
+    /// `@@...$specifichide` would have to cancel the specific hide rules
+    /// but not the generic ones before them
+    SpecificHide,
+    /// `$http`, `$https` and the other scheme options
+    Scheme,
+    /// `$expires=`; a static list can't retire the rule on time
+    Expires,
+    /// Pattern or regex outside Safari's regex subset
+    UnsupportedPattern,
+    /// Entity (`example.*`) or non-ASCII domains, or both including and
+    /// excluding domains
+    DomainConditions,
+    /// `$subdocument` combined with other types
+    FrameTypes,
+    /// `#@#` cancels single selectors, which Safari can't target
+    CosmeticException,
+    /// Procedural, `:style()`, scriptlet and `##^` rules need a content script
+    NeedsScript,
+    /// Action with no content blocker counterpart
+    UnsupportedAction,
+    /// `$popup`; a content blocker never sees the tab a page opens
+    Popup,
+}
+
+impl SafariSkipReason {
+    /// Stable identifier for reports.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Redirect => "redirect",
+            Self::RedirectRule => "redirect-rule",
+            Self::Modifier => "modifier",
+            Self::ModifierException => "modifier-exception",
+            Self::GenericBlock => "genericblock",
+            Self::SpecificHide => "specifichide",
+            Self::Scheme => "scheme",
+            Self::Expires => "expires",
+            Self::UnsupportedPattern => "unsupported-pattern",
+            Self::DomainConditions => "domain-conditions",
+            Self::FrameTypes => "frame-types",
+            Self::CosmeticException => "cosmetic-exception",
+            Self::NeedsScript => "needs-script",
+            Self::UnsupportedAction => "unsupported-action",
+            Self::Popup => "popup",
+        }
+    }
+}
+
+impl fmt::Display for SafariSkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Redirect => "$redirect needs resources a content blocker can't serve",
+            Self::RedirectRule => "$redirect-rule depends on another rule's block",
+            Self::Modifier => "option modifies the request instead of blocking it",
+            Self::ModifierException => "exception for a single modifier",
+            Self::GenericBlock => "$genericblock exception",
+            Self::SpecificHide => "$specifichide exception",
+            Self::Scheme => "scheme options",
+            Self::Expires => "$expires rules can't be retired by a static list",
+            Self::UnsupportedPattern => "pattern is outside Safari's regex subset",
+            Self::DomainConditions => "domain conditions a trigger can't hold",
+            Self::FrameTypes => "$subdocument combined with other types",
+            Self::CosmeticException => "#@# exception for a single selector",
+            Self::NeedsScript => "rule needs a content script",
+            Self::UnsupportedAction => "action has no content blocker equivalent",
+            Self::Popup => "$popup needs the tabs API",
+        })
+    }
+}
+
+/// A rule left out of the content blocker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafariSkippedRule {
+    pub list_id: u16,
+    pub line: u32,
+    pub reason: SafariSkipReason,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SafariExport {
+    pub rules: Vec<SafariRule>,
+    pub skipped: Vec<SafariSkippedRule>,
+}
+
+/// Where a rule goes in the list; see the module docs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Stage {
+    GenericHide,
+    GenerichideException,
+    SpecificHide,
+    ElemhideException,
+    Block,
+    Exception,
+    ImportantBlock,
+    ImportantException,
+}
+
+/// Convert compiled rules into a content blocker list. Expects optimized
+/// rules; `$badfilter` rules left in are ignored.
+pub fn convert_rules(rules: &[CompiledRule]) -> SafariExport {
+    let mut export = SafariExport::default();
+    let mut staged = Vec::new();
+
+    for rule in rules {
+        if rule.is_badfilter {
+            continue;
+        }
+        let converted = if is_cosmetic(rule) {
+            cosmetic_rule(rule)
+        } else {
+            network_rule(rule)
+        };
+        match converted {
+            Ok(converted) => staged.push(converted),
+            Err(reason) => export.skipped.push(SafariSkippedRule {
+                list_id: rule.list_id,
+                line: rule.line,
+                reason,
+            }),
+        }
+    }
+
+    // Stable, so rules keep their list order within a stage.
+    staged.sort_by_key(|(stage, _)| *stage);
+    export.rules = staged.into_iter().map(|(_, rule)| rule).collect();
+    export
+}
+
+fn is_cosmetic(rule: &CompiledRule) -> bool {
+    rule.cosmetic.is_some()
+        || rule.cosmetic_style.is_some()
+        || rule.procedural.is_some()
+        || rule.scriptlet.is_some()
+        || rule.responseheader.is_some()
+}
+
+fn cosmetic_rule(rule: &CompiledRule) -> Result<(Stage, SafariRule), SafariSkipReason> {
+    let Some(cosmetic) = &rule.cosmetic else {
+        return Err(SafariSkipReason::NeedsScript);
+    };
+    if cosmetic.is_exception {
+        return Err(SafariSkipReason::CosmeticException);
+    }
+
+    let mut trigger = SafariTrigger {
+        url_filter: ".*".to_string(),
+        ..SafariTrigger::default()
+    };
+    apply_domain_conditions(rule, &mut trigger)?;
+    let stage = if trigger.if_domain.is_empty() {
+        Stage::GenericHide
+    } else {
+        Stage::SpecificHide
+    };
+    let action = SafariAction {
+        action_type: SafariActionType::CssDisplayNone,
+        selector: Some(cosmetic.selector.clone()),
+    };
+    Ok((stage, SafariRule { trigger, action }))
+}
+
+fn network_rule(rule: &CompiledRule) -> Result<(Stage, SafariRule), SafariSkipReason> {
+    if rule.expires_at.is_some() {
+        return Err(SafariSkipReason::Expires);
+    }
+    if !rule.scheme_mask.is_empty() && rule.scheme_mask != SchemeMask::ALL {
+        return Err(SafariSkipReason::Scheme);
+    }
+    let important = rule.flags.contains(RuleFlags::IMPORTANT);
+
+    let (stage, action_type) = match rule.action {
+        RuleAction::Block if rule.redirect.is_some() => return Err(SafariSkipReason::Redirect),
+        RuleAction::Block if important => (Stage::ImportantBlock, SafariActionType::Block),
+        RuleAction::Block => (Stage::Block, SafariActionType::Block),
+        RuleAction::Allow => (exception_stage(rule)?, SafariActionType::IgnorePreviousRules),
+        RuleAction::RedirectDirective => return Err(SafariSkipReason::RedirectRule),
+        RuleAction::Removeparam
+        | RuleAction::CspInject
+        | RuleAction::RemoveHeader
+        | RuleAction::HeaderMatchBlock
+        | RuleAction::HeaderMatchAllow
+        | RuleAction::ResponseReplace => {
+            let exception = rule.flags.intersects(RuleFlags::CSP_EXCEPTION | RuleFlags::REMOVEHEADER_EXCEPTION);
+            return Err(if exception { SafariSkipReason::ModifierException } else { SafariSkipReason::Modifier });
+        }
+        RuleAction::ResponseCancel => return Err(SafariSkipReason::UnsupportedAction),
+    };
+
+    let page_exception = matches!(stage, Stage::GenerichideException | Stage::ElemhideException);
+    let document_exception = rule.action == RuleAction::Allow
+        && !page_exception
+        && !rule.type_mask.is_empty()
+        && RequestType::DOCUMENT.contains(rule.type_mask);
+
+    let mut trigger = SafariTrigger::default();
+    if document_exception {
+        // Everything loaded by the page, which `if-top-url` names.
+        if rule.domain_constraints.is_some() {
+            return Err(SafariSkipReason::DomainConditions);
+        }
+        trigger.url_filter = ".*".to_string();
+        trigger.if_top_url = vec![url_filter(rule)?];
+    } else {
+        trigger.url_filter = url_filter(rule)?;
+        apply_domain_conditions(rule, &mut trigger)?;
+        if page_exception {
+            trigger.resource_type = vec!["document"];
+        } else {
+            apply_request_types(rule, &mut trigger)?;
+        }
+    }
+    trigger.url_filter_is_case_sensitive = rule.flags.contains(RuleFlags::MATCH_CASE);
+    trigger.load_type = if rule.party_mask == PartyMask::FIRST_PARTY {
+        vec!["first-party"]
+    } else if rule.party_mask == PartyMask::THIRD_PARTY {
+        vec!["third-party"]
+    } else {
+        Vec::new()
+    };
+
+    let action = SafariAction {
+        action_type,
+        selector: None,
+    };
+    Ok((stage, SafariRule { trigger, action }))
+}
+
+fn exception_stage(rule: &CompiledRule) -> Result<Stage, SafariSkipReason> {
+    if rule.flags.contains(RuleFlags::GENERICBLOCK) {
+        return Err(SafariSkipReason::GenericBlock);
+    }
+    if rule.flags.contains(RuleFlags::ELEMHIDE) {
+        return Ok(Stage::ElemhideException);
+    }
+    if rule.flags.contains(RuleFlags::GENERICHIDE) {
+        return Ok(Stage::GenerichideException);
+    }
+    if rule.flags.contains(RuleFlags::SPECIFICHIDE) {
+        return Err(SafariSkipReason::SpecificHide);
+    }
+    if rule.flags.contains(RuleFlags::REDIRECT_RULE_EXCEPTION) || rule.removeparam.is_some() {
+        return Err(SafariSkipReason::ModifierException);
+    }
+    Ok(if rule.flags.contains(RuleFlags::IMPORTANT) {
+        Stage::ImportantException
+    } else {
+        Stage::Exception
+    })
+}
+
+/// `$domain=` as `if-domain` or `unless-domain`; a trigger can't have both.
+fn apply_domain_conditions(rule: &CompiledRule, trigger: &mut SafariTrigger) -> Result<(), SafariSkipReason> {
+    let Some(constraints) = &rule.domain_constraints else {
+        return Ok(());
+    };
+    if !constraints.include_names.is_empty() && !constraints.exclude_names.is_empty() {
+        return Err(SafariSkipReason::DomainConditions);
+    }
+    let domains = |names: &[String]| {
+        names
+            .iter()
+            .map(|name| {
+                if name.ends_with(".*") || !name.is_ascii() {
+                    Err(SafariSkipReason::DomainConditions)
+                } else {
+                    Ok(format!("*{}", name))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+    trigger.if_domain = domains(&constraints.include_names)?;
+    trigger.unless_domain = domains(&constraints.exclude_names)?;
+    Ok(())
+}
+
+fn apply_request_types(rule: &CompiledRule, trigger: &mut SafariTrigger) -> Result<(), SafariSkipReason> {
+    let mask = rule.type_mask;
+    if mask == RequestType::POPUP {
+        return Err(SafariSkipReason::Popup);
+    }
+    if rule.action == RuleAction::Allow && mask.is_empty() {
+        // Every type but `document`; see the module docs.
+        trigger.resource_type = resource_types(RequestType::ALL & !RequestType::DOCUMENT);
+        return Ok(());
+    }
+    if mask.contains(RequestType::SUBDOCUMENT) && !mask.contains(RequestType::MAIN_FRAME) {
+        if mask != RequestType::SUBDOCUMENT {
+            return Err(SafariSkipReason::FrameTypes);
+        }
+        trigger.load_context = vec!["child-frame"];
+    }
+    trigger.resource_type = resource_types(mask);
+    Ok(())
+}
+
+/// Safari resource types for a type mask; empty (every type) when the rule
+/// has no type options.
+fn resource_types(mask: RequestType) -> Vec<&'static str> {
+    const TYPES: &[(RequestType, &str)] = &[
+        (RequestType::DOCUMENT, "document"),
+        (RequestType::IMAGE, "image"),
+        (RequestType::STYLESHEET, "style-sheet"),
+        (RequestType::SCRIPT, "script"),
+        (RequestType::FONT, "font"),
+        (RequestType::MEDIA, "media"),
+        (RequestType::XMLHTTPREQUEST, "fetch"),
+        (RequestType::FETCH, "fetch"),
+        (RequestType::WEBSOCKET, "websocket"),
+        (RequestType::PING, "ping"),
+        (RequestType::BEACON, "ping"),
+    ];
+    let mut types = Vec::new();
+    for &(bit, name) in TYPES {
+        if mask.intersects(bit) && !types.contains(&name) {
+            types.push(name);
+        }
+    }
+    if mask.intersects(RequestType::ALL & !TYPES.iter().fold(RequestType::empty(), |all, &(bit, _)| all | bit)) {
+        types.push("other");
+    }
+    types
+}
+
+/// The rule's URL condition as a Safari `url-filter` regex.
+fn url_filter(rule: &CompiledRule) -> Result<String, SafariSkipReason> {
+    let Some(pattern) = &rule.pattern else {
+        if !rule.domain.is_ascii() {
+            return Err(SafariSkipReason::UnsupportedPattern);
+        }
+        return Ok(format!("{}{}{}", HOST_ANCHOR, escape(&rule.domain), TRAILING_SEPARATOR));
+    };
+    if !pattern.is_ascii() {
+        return Err(SafariSkipReason::UnsupportedPattern);
+    }
+    if rule.flags.contains(RuleFlags::IS_REGEX) {
+        return if is_safari_regex(pattern) {
+            Ok(pattern.clone())
+        } else {
+            Err(SafariSkipReason::UnsupportedPattern)
+        };
+    }
+    if pattern.contains('|') {
+        return Err(SafariSkipReason::UnsupportedPattern);
+    }
+
+    let mut regex = String::from(match rule.anchor_type {
+        AnchorType::Hostname => HOST_ANCHOR,
+        AnchorType::Left => "^",
+        AnchorType::None => "",
+    });
+    let mut chars = pattern.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '*' => regex.push_str(".*"),
+            '^' if chars.peek().is_none() => regex.push_str(TRAILING_SEPARATOR),
+            '^' => regex.push_str(SEPARATOR),
+            _ => push_escaped(&mut regex, ch),
+        }
+    }
+    Ok(regex)
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        push_escaped(&mut escaped, ch);
+    }
+    escaped
+}
+
+fn push_escaped(regex: &mut String, ch: char) {
+    if matches!(ch, '\\' | '.' | '+' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '$' | '^' | '*') {
+        regex.push('\\');
+    }
+    regex.push(ch);
+}
+
+/// Whether a `/regex/` filter stays within what `url-filter` accepts.
+fn is_safari_regex(regex: &str) -> bool {
+    let bytes = regex.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => {
+                // Only escaped literals; no `\d`, `\w`, `\b`, backreferences.
+                if bytes.get(i + 1).is_some_and(|b| b.is_ascii_alphanumeric()) {
+                    return false;
+                }
+                i += 2;
+                continue;
+            }
+            b'|' | b'{' | b'}' => return false,
+            b'(' if bytes.get(i + 1) == Some(&b'?') => return false,
+            _ => {}
+        }
+        i += 1;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_filter_list;
+
+    #[test]
+    fn rules_export_to_safari_content_blocker() {
+        let rules = parse_filter_list(
+            "||ads.example^$third-party,domain=news.example\n\
+             ##.ad-banner\n\
+             @@||trusted.example^$document\n\
+             example.com##.sidebar-ad\n\
+             @@||ads.example/ok.js$script\n\
+             ||cdn.example/lib.js$script,redirect=noop.js\n\
+             ||frames.example^$subdocument,script\n\
+             /^https?:\\/\\/\\d+\\.example\\//\n\
+             example.com#@#.ad-banner\n\
+             ||track.example^$important\n",
+        );
+        let export = convert_rules(&rules);
+        let skipped: Vec<_> = export.skipped.iter().map(|skip| (skip.line, skip.reason.clone())).collect();
+        assert_eq!(
+            skipped,
+            vec![
+                (6, SafariSkipReason::Redirect),
+                (7, SafariSkipReason::FrameTypes),
+                (8, SafariSkipReason::UnsupportedPattern),
+                (9, SafariSkipReason::CosmeticException),
+            ]
+        );
+
+        // Generic hides, specific hides, blocks, exceptions, then $important.
+        let kinds: Vec<_> = export
+            .rules
+            .iter()
+            .map(|rule| (rule.action.action_type, rule.action.selector.as_deref()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (SafariActionType::CssDisplayNone, Some(".ad-banner")),
+                (SafariActionType::CssDisplayNone, Some(".sidebar-ad")),
+                (SafariActionType::Block, None),
+                (SafariActionType::IgnorePreviousRules, None),
+                (SafariActionType::IgnorePreviousRules, None),
+                (SafariActionType::Block, None),
+            ]
+        );
+
+        let json = serde_json::to_value(&export.rules).expect("serializable");
+        assert_eq!(
+            json[1],
+            serde_json::json!({
+                "trigger": { "url-filter": ".*", "if-domain": ["*example.com"] },
+                "action": { "type": "css-display-none", "selector": ".sidebar-ad" },
+            })
+        );
+        assert_eq!(
+            json[2]["trigger"],
+            serde_json::json!({
+                "url-filter": "^[a-z][a-z0-9.+-]*://([^/?#]*\\.)?ads\\.example([^a-zA-Z0-9_.%-].*)?$",
+                "if-domain": ["*news.example"],
+                "load-type": ["third-party"],
+            })
+        );
+
+        let document = &export.rules[3].trigger;
+        assert_eq!(document.url_filter, ".*");
+        assert_eq!(document.if_top_url.len(), 1);
+        let exception = &export.rules[4].trigger;
+        assert!(exception.url_filter.ends_with("ads\\.example/ok\\.js"));
+        assert_eq!(exception.resource_type, vec!["script"]);
+
+        let regex = bb_core::regex::compile_filter_regex(&export.rules[2].trigger.url_filter, false).expect("valid regex");
+        assert!(regex.is_match("https://cdn.ads.example/x.js"));
+        assert!(!regex.is_match("https://ads.example.org/x.js"));
+    }
+
+    #[test]
+    fn wildcard_domains_are_skipped() {
+        let rules = parse_filter_list(
+            "||ads.example^$domain=news.*\n\
+             ||ads.example^$domain=shop.example|~blog.shop.example\n\
+             ||ads.example^$domain=~blog.example\n",
+        );
+        let export = convert_rules(&rules);
+        let skipped: Vec<_> = export.skipped.iter().map(|skip| (skip.line, skip.reason.clone())).collect();
+        // The second rule would need both `if-domain` and `unless-domain`.
+        assert_eq!(skipped, vec![(1, SafariSkipReason::DomainConditions), (2, SafariSkipReason::DomainConditions)]);
+        assert_eq!(export.rules.len(), 1);
+        assert_eq!(export.rules[0].trigger.unless_domain, vec!["*blog.example"]);
+    }
+}
@@ -0,0 +1,191 @@
+//! Scriptlet name aliasing, signature checks and sources
+//!
+//! Lists refer to the same scriptlet by several names (`acis`,
+//! `abort-current-inline-script.js`, ...). Names are normalized to the
+//! canonical form used by the extension's scriptlet runtime so that exceptions
+//! and dedupe compare like with like.
+//!
+//! Each entry also carries the JS template the extension injects, from
+//! `scriptlets/*.js`, so the runtime can only run what the compiler accepts.
+//! Templates refer to arguments as `{{1}}`, `{{2}}`, ...; [`render_scriptlet`]
+//! substitutes them as escaped string literals.
+
+/// Known scriptlet with its accepted argument count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScriptletSignature {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub min_args: usize,
+    pub max_args: usize,
+    /// JS body template, run after [`SCRIPTLET_PRELUDE`]
+    pub source: &'static str,
+}
+
+/// Helpers shared by every template (`toRegExp`, `forEachNode`, ...).
+pub const SCRIPTLET_PRELUDE: &str = include_str!("../scriptlets/prelude.js");
+
+/// Registry entry whose source is `scriptlets/<name>.js`.
+macro_rules! sig {
+    ($name:literal, $aliases:expr, $min:expr, $max:expr) => {
+        sig($name, $aliases, $min, $max, include_str!(concat!("../scriptlets/", $name, ".js")))
+    };
+}
+
+pub const SCRIPTLET_REGISTRY: &[ScriptletSignature] = &[
+    sig!("set-constant", &["set"], 2, 4),
+    sig!("remove-attr", &["ra"], 1, 3),
+    sig!("remove-class", &["rc"], 1, 3),
+    sig!("add-class", &[], 1, 2),
+    sig!("hide-by-selector", &[], 1, 1),
+    sig!("remove-by-selector", &[], 1, 1),
+    sig!("abort-on-property-read", &["aopr"], 1, 1),
+    sig!("abort-on-property-write", &["aopw"], 1, 1),
+    sig!("abort-current-script", &["acs", "abort-current-inline-script", "acis"], 1, 3),
+    sig!("no-setTimeout-if", &["nostif", "setTimeout-defuser", "std"], 0, 2),
+    sig!("no-setInterval-if", &["nosiif", "setInterval-defuser", "sid"], 0, 2),
+    sig!("no-fetch-if", &[], 1, 2),
+    sig!("no-window-open-if", &["nowoif", "window.open-defuser"], 0, 3),
+    sig!("prevent-addEventListener", &["aeld", "addEventListener-defuser"], 0, 2),
+    sig!("json-prune", &[], 1, 4),
+    sig!("set-cookie", &[], 2, 3),
+    sig!("set-local-storage-item", &[], 2, 2),
+    sig!("nobab", &["bab-defuser"], 0, 0),
+    sig!("nowebrtc", &[], 0, 0),
+];
+
+const fn sig(
+    name: &'static str,
+    aliases: &'static [&'static str],
+    min_args: usize,
+    max_args: usize,
+    source: &'static str,
+) -> ScriptletSignature {
+    ScriptletSignature {
+        name,
+        aliases,
+        min_args,
+        max_args,
+        source,
+    }
+}
+
+/// Why a scriptlet injection was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptletIssue {
+    UnknownName(String),
+    ArgCount {
+        name: &'static str,
+        got: usize,
+        min: usize,
+        max: usize,
+    },
+}
+
+impl std::fmt::Display for ScriptletIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownName(name) => write!(f, "unknown scriptlet '{}'", name),
+            Self::ArgCount { name, got, min, max } if min == max => {
+                write!(f, "{} expects {} argument(s), got {}", name, min, got)
+            }
+            Self::ArgCount { name, got, min, max } => {
+                write!(f, "{} expects {}-{} arguments, got {}", name, min, max, got)
+            }
+        }
+    }
+}
+
+/// Look up a scriptlet by canonical name or alias, with or without `.js`.
+pub fn lookup_scriptlet(name: &str) -> Option<&'static ScriptletSignature> {
+    let name = name.strip_suffix(".js").unwrap_or(name);
+    SCRIPTLET_REGISTRY
+        .iter()
+        .find(|sig| sig.name == name || sig.aliases.contains(&name))
+}
+
+/// Normalize a `+js(...)` body to `canonical-name, arg, ...` and validate
+/// its argument count.
+pub fn normalize_scriptlet(raw: &str) -> Result<String, ScriptletIssue> {
+    let mut parts = raw.split(',').map(str::trim).filter(|part| !part.is_empty());
+    let name = parts.next().unwrap_or("");
+    let args: Vec<&str> = parts.collect();
+
+    let sig = lookup_scriptlet(name).ok_or_else(|| ScriptletIssue::UnknownName(name.to_string()))?;
+    if args.len() < sig.min_args || args.len() > sig.max_args {
+        return Err(ScriptletIssue::ArgCount {
+            name: sig.name,
+            got: args.len(),
+            min: sig.min_args,
+            max: sig.max_args,
+        });
+    }
+
+    let mut normalized = String::from(sig.name);
+    for arg in args {
+        normalized.push_str(", ");
+        normalized.push_str(arg);
+    }
+    Ok(normalized)
+}
+
+/// Self-contained JS for one `+js(...)` call: the prelude and the template
+/// wrapped in an IIFE, with every `{{n}}` replaced by the n-th argument as a
+/// string literal (`''` when absent). Arguments never reach the output
+/// unescaped, so they cannot break out of their literal.
+pub fn render_scriptlet(name: &str, args: &[&str]) -> Result<String, ScriptletIssue> {
+    let sig = lookup_scriptlet(name).ok_or_else(|| ScriptletIssue::UnknownName(name.to_string()))?;
+    if args.len() < sig.min_args || args.len() > sig.max_args {
+        return Err(ScriptletIssue::ArgCount {
+            name: sig.name,
+            got: args.len(),
+            min: sig.min_args,
+            max: sig.max_args,
+        });
+    }
+
+    let mut out = String::with_capacity(SCRIPTLET_PRELUDE.len() + sig.source.len() + 64);
+    out.push_str("(function () {\n'use strict';\ntry {\n");
+    out.push_str(SCRIPTLET_PRELUDE);
+
+    let mut rest = sig.source;
+    while let Some(start) = rest.find("{{") {
+        let digits = rest[start + 2..].bytes().take_while(u8::is_ascii_digit).count();
+        let close = start + 2 + digits;
+        if digits == 0 || !rest[close..].starts_with("}}") {
+            out.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        let index: usize = rest[start + 2..close].parse().unwrap_or(0);
+        let arg = index.checked_sub(1).and_then(|i| args.get(i)).copied().unwrap_or("");
+        push_js_string(&mut out, arg);
+        rest = &rest[close + 2..];
+    }
+    out.push_str(rest);
+
+    out.push_str("} catch (e) {}\n})();\n");
+    Ok(out)
+}
+
+/// Append `value` as a single-quoted JS string literal. `<`, `>` and the
+/// line separators are escaped too, so the result is also safe inside an
+/// inline `<script>`.
+fn push_js_string(out: &mut String, value: &str) {
+    out.push('\'');
+    for ch in value.chars() {
+        match ch {
+            '\'' => out.push_str("\\'"),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '<' => out.push_str("\\x3c"),
+            '>' => out.push_str("\\x3e"),
+            c if (c as u32) < 0x20 || c == '\u{2028}' || c == '\u{2029}' => {
+                out.push_str(&format!("\\u{:04x}", c as u32));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('\'');
+}
@@ -0,0 +1,300 @@
+//! Filter syntax conformance harness
+//!
+//! Runs `(filters, url, type, expected)` vectors through the same
+//! parse → optimize → build → match pipeline the extension uses and tallies
+//! the results per feature area. The vectors in `corpus/` follow the cases
+//! in the Adblock Plus and uBlock Origin test suites.
+//!
+//! Corpus format, one case per block:
+//!
+//! ```text
+//! # comment
+//! [third-party]
+//! ||ads.example^$third-party
+//! expect block https://ads.example/a.js script https://news.example/
+//! expect allow https://ads.example/a.js script https://ads.example/
+//! gap block https://ads.example/a.js popup https://news.example/
+//! ```
+//!
+//! `[area]` starts a feature area. Other lines up to the first `expect` or
+//! `gap` are the case's filters; a blank line or a filter after the checks
+//! starts the next case. A check is `<block|allow|redirect|removeparam> <url>
+//! [type] [initiator]`, with the type defaulting to `other` and no initiator
+//! meaning a top-level, first-party request. `gap` marks a check the engine is
+//! known to fail; the harness reports it when it starts passing so the gap
+//! list stays honest.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use bb_compiler::{build_snapshot, optimize_rules, parse_filter_list_with_diagnostics};
+use bb_core::types::{MatchDecision, RequestContext, RequestType};
+use bb_core::{Matcher, Snapshot};
+
+/// The bundled corpus.
+pub const CORPUS: &str = include_str!("../corpus/network.txt");
+
+/// Filters plus the requests checked against them.
+#[derive(Debug, Clone)]
+pub struct Case {
+    pub area: String,
+    /// 1-based corpus line of the first filter
+    pub line: usize,
+    pub filters: Vec<String>,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub line: usize,
+    pub expected: MatchDecision,
+    pub url: String,
+    pub request_type: String,
+    pub initiator: Option<String>,
+    /// Known to fail today
+    pub known_gap: bool,
+}
+
+/// Parse corpus text into cases.
+pub fn parse_corpus(text: &str) -> Result<Vec<Case>, String> {
+    let mut cases = Vec::new();
+    let mut area = String::from("general");
+    let mut current: Option<Case> = None;
+
+    for (index, raw) in text.lines().enumerate() {
+        let line_no = index + 1;
+        let line = raw.trim();
+
+        if line.is_empty() || line.starts_with("# ") || line == "#" {
+            if line.is_empty() {
+                cases.extend(current.take());
+            }
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+            cases.extend(current.take());
+            area = name.trim().to_string();
+            continue;
+        }
+
+        let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        if keyword == "expect" || keyword == "gap" {
+            let Some(case) = current.as_mut() else {
+                return Err(format!("line {}: check without filters", line_no));
+            };
+            let mut check = parse_check(rest).map_err(|e| format!("line {}: {}", line_no, e))?;
+            check.line = line_no;
+            check.known_gap = keyword == "gap";
+            case.checks.push(check);
+            continue;
+        }
+
+        if current.as_ref().is_some_and(|case| !case.checks.is_empty()) {
+            cases.extend(current.take());
+        }
+        current
+            .get_or_insert_with(|| Case {
+                area: area.clone(),
+                line: line_no,
+                filters: Vec::new(),
+                checks: Vec::new(),
+            })
+            .filters
+            .push(line.to_string());
+    }
+    cases.extend(current);
+
+    if let Some(case) = cases.iter().find(|case| case.checks.is_empty()) {
+        return Err(format!("line {}: filters without checks", case.line));
+    }
+    Ok(cases)
+}
+
+fn parse_check(text: &str) -> Result<Check, String> {
+    let mut fields = text.split_whitespace();
+    let expected = match fields.next() {
+        Some("block") => MatchDecision::Block,
+        Some("allow") => MatchDecision::Allow,
+        Some("redirect") => MatchDecision::Redirect,
+        Some("removeparam") => MatchDecision::Removeparam,
+        Some(other) => return Err(format!("unknown decision '{}'", other)),
+        None => return Err("missing decision".to_string()),
+    };
+    let url = fields.next().ok_or("missing url")?.to_string();
+    let request_type = fields.next().unwrap_or("other").to_string();
+    let initiator = fields.next().map(str::to_string);
+    if fields.next().is_some() {
+        return Err("too many fields".to_string());
+    }
+    Ok(Check {
+        line: 0,
+        expected,
+        url,
+        request_type,
+        initiator,
+        known_gap: false,
+    })
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AreaStats {
+    pub passed: usize,
+    pub failed: usize,
+    /// Known gaps that still fail
+    pub gaps: usize,
+}
+
+/// A check whose outcome did not match its marking.
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub area: String,
+    pub line: usize,
+    pub filters: Vec<String>,
+    pub url: String,
+    pub expected: MatchDecision,
+    /// `None` when the request could not be built
+    pub actual: Option<MatchDecision>,
+    /// Why the compiler dropped any of the case's filters
+    pub dropped: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub areas: BTreeMap<String, AreaStats>,
+    /// Checks expected to pass that failed
+    pub failures: Vec<Failure>,
+    /// `gap` checks that now pass
+    pub fixed_gaps: Vec<Failure>,
+}
+
+impl Report {
+    pub fn totals(&self) -> AreaStats {
+        self.areas.values().fold(AreaStats::default(), |total, area| AreaStats {
+            passed: total.passed + area.passed,
+            failed: total.failed + area.failed,
+            gaps: total.gaps + area.gaps,
+        })
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.failures.is_empty() && self.fixed_gaps.is_empty()
+    }
+}
+
+/// Run every case through parse, optimize, build and match.
+pub fn run_cases(cases: &[Case]) -> Report {
+    let mut report = Report::default();
+
+    for case in cases {
+        let (mut rules, diagnostics) = parse_filter_list_with_diagnostics(&case.filters.join("\n"));
+        optimize_rules(&mut rules);
+        let bytes = build_snapshot(&rules);
+        let snapshot = Snapshot::load(&bytes).expect("built snapshot loads");
+        let matcher = Matcher::new(&snapshot);
+        let dropped: Vec<String> = diagnostics
+            .dropped_lines
+            .iter()
+            .map(|dropped| format!("{}: {}", dropped.text, dropped.reason))
+            .collect();
+
+        for check in &case.checks {
+            let actual = decide(&matcher, check);
+            let passed = actual == Some(check.expected);
+            let stats = report.areas.entry(case.area.clone()).or_default();
+            match (passed, check.known_gap) {
+                (true, false) => stats.passed += 1,
+                (false, true) => stats.gaps += 1,
+                (false, false) => stats.failed += 1,
+                (true, true) => stats.passed += 1,
+            }
+            if passed == check.known_gap {
+                let failure = Failure {
+                    area: case.area.clone(),
+                    line: check.line,
+                    filters: case.filters.clone(),
+                    url: check.url.clone(),
+                    expected: check.expected,
+                    actual,
+                    dropped: dropped.clone(),
+                };
+                if passed {
+                    report.fixed_gaps.push(failure);
+                } else {
+                    report.failures.push(failure);
+                }
+            }
+        }
+    }
+    report
+}
+
+fn decide(matcher: &Matcher<'_>, check: &Check) -> Option<MatchDecision> {
+    let mut builder = RequestContext::builder(&check.url, RequestType::from_str(&check.request_type));
+    if let Some(initiator) = &check.initiator {
+        builder = builder.initiator(initiator);
+    }
+    let info = builder.build().ok()?;
+    Some(matcher.match_request(&info.context()).decision)
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<24} {:>6} {:>6} {:>6}", "area", "pass", "fail", "gap")?;
+        for (area, stats) in &self.areas {
+            writeln!(f, "{:<24} {:>6} {:>6} {:>6}", area, stats.passed, stats.failed, stats.gaps)?;
+        }
+        let totals = self.totals();
+        writeln!(f, "{:<24} {:>6} {:>6} {:>6}", "total", totals.passed, totals.failed, totals.gaps)?;
+
+        for (label, list) in [("FAIL", &self.failures), ("FIXED GAP", &self.fixed_gaps)] {
+            for failure in list {
+                writeln!(f)?;
+                writeln!(
+                    f,
+                    "{} [{}] line {}: {} expected {:?}, got {}",
+                    label,
+                    failure.area,
+                    failure.line,
+                    failure.url,
+                    failure.expected,
+                    failure.actual.map_or("invalid request".to_string(), |actual| format!("{:?}", actual))
+                )?;
+                for filter in &failure.filters {
+                    writeln!(f, "    {}", filter)?;
+                }
+                for dropped in &failure.dropped {
+                    writeln!(f, "    dropped: {}", dropped)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corpus_parses_into_cases() {
+        let cases = parse_corpus("[a]\n||x.example^\nexpect block https://x.example/\n\n@@||y.example^\n||y.example^\nexpect allow https://y.example/ script https://z.example/\ngap block https://y.example/\n||z.example^\nexpect block https://z.example/").expect("valid corpus");
+        assert_eq!(cases.len(), 3);
+        assert_eq!(cases[1].filters, vec!["@@||y.example^", "||y.example^"]);
+        assert_eq!(cases[1].checks[0].initiator.as_deref(), Some("https://z.example/"));
+        assert!(cases[1].checks[1].known_gap);
+        assert_eq!(cases[2].line, 9);
+
+        assert!(parse_corpus("expect block https://x.example/").is_err());
+        assert!(parse_corpus("||x.example^\n").is_err());
+        assert!(parse_corpus("||x.example^\nexpect maybe https://x.example/").is_err());
+    }
+
+    /// Every check passes or is a known gap, and no known gap passes.
+    #[test]
+    fn bundled_corpus_conforms() {
+        let cases = parse_corpus(CORPUS).expect("bundled corpus parses");
+        let report = run_cases(&cases);
+        println!("{}", report);
+        assert!(report.is_clean(), "conformance regressions:\n{}", report);
+    }
+}
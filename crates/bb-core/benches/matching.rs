@@ -0,0 +1,115 @@
+//! Per-function benchmarks on a small fixed snapshot compiled from
+//! `testdata/test-filters.txt`, so a regression in one stage of the match
+//! path shows up on its own rather than averaged into `bb-cli bench`.
+
+use std::hint::black_box;
+
+use bb_core::matcher::MatchScratch;
+use bb_core::snapshot::{Snapshot, NO_PATTERN};
+use bb_core::types::{RequestContext, RequestInfo, RequestType};
+use bb_core::url::tokenize_url_into;
+use bb_core::{hash_domain, Matcher};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+const FILTERS: &str = include_str!("../../../testdata/test-filters.txt");
+
+const URLS: [&str; 4] = [
+    "https://www.example.org/index.html",
+    "https://ads.example.com/banner/ad.js?slot=top&size=300x250",
+    "https://cdn.site.example/assets/ads/pixel.gif?cb=1699999999",
+    "https://www.google-analytics.com/g/collect?v=2&tid=G-XXXX&cid=123.456&en=page_view",
+];
+
+const HOSTS: [&str; 4] = ["www.example.org", "ads.example.com", "pagead2.googlesyndication.com", "a.b.c.d.example.net"];
+
+fn snapshot_bytes() -> Vec<u8> {
+    let mut rules = bb_compiler::parse_filter_list(FILTERS);
+    bb_compiler::optimize_rules(&mut rules);
+    bb_compiler::build_snapshot(&rules)
+}
+
+fn requests() -> Vec<RequestInfo<'static>> {
+    let types = [RequestType::MAIN_FRAME, RequestType::SCRIPT, RequestType::IMAGE, RequestType::PING];
+    URLS.iter()
+        .zip(types)
+        .map(|(url, request_type)| {
+            RequestContext::builder(url, request_type)
+                .initiator("https://www.example.org/")
+                .build()
+                .expect("valid request")
+        })
+        .collect()
+}
+
+fn bench_tokenize_url(c: &mut Criterion) {
+    let mut tokens = Vec::new();
+    c.bench_function("tokenize_url", |b| {
+        b.iter(|| {
+            for url in URLS {
+                tokenize_url_into(black_box(url), &mut tokens);
+            }
+            tokens.len()
+        })
+    });
+}
+
+fn bench_hash_domain(c: &mut Criterion) {
+    c.bench_function("hash_domain", |b| {
+        b.iter(|| HOSTS.iter().map(|host| hash_domain(black_box(host)).lo).fold(0, u32::wrapping_add))
+    });
+}
+
+fn bench_domain_set_lookup(c: &mut Criterion) {
+    let bytes = snapshot_bytes();
+    let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+    let block_set = snapshot.domain_block_set();
+    let hashes: Vec<_> = HOSTS.iter().map(|host| hash_domain(host)).collect();
+    c.bench_function("domain_hash_set_lookup", |b| {
+        b.iter(|| hashes.iter().filter(|&&hash| block_set.lookup(black_box(hash)).is_some()).count())
+    });
+}
+
+fn bench_verify_pattern(c: &mut Criterion) {
+    let bytes = snapshot_bytes();
+    let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+    let matcher = Matcher::new(&snapshot);
+    let rules = snapshot.rules();
+    let patterned: Vec<usize> = (0..rules.count).filter(|&rule_id| rules.pattern_id(rule_id) != NO_PATTERN).collect();
+    assert!(!patterned.is_empty(), "test list should have pattern rules");
+    c.bench_function("verify_pattern", |b| {
+        b.iter(|| {
+            let mut matched = 0;
+            for &rule_id in &patterned {
+                for url in URLS {
+                    matched += usize::from(matcher.pattern_matches(rule_id, black_box(url)));
+                }
+            }
+            matched
+        })
+    });
+}
+
+fn bench_match_request(c: &mut Criterion) {
+    let bytes = snapshot_bytes();
+    let snapshot = Snapshot::load(&bytes).expect("snapshot should load");
+    let matcher = Matcher::new(&snapshot);
+    let requests = requests();
+    let mut scratch = MatchScratch::new();
+    c.bench_function("match_request", |b| {
+        b.iter(|| {
+            for request in &requests {
+                black_box(matcher.match_request_with_scratch(&request.context(), &mut scratch));
+            }
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_tokenize_url,
+    bench_hash_domain,
+    bench_domain_set_lookup,
+    bench_verify_pattern,
+    bench_match_request
+);
+criterion_main!(benches);
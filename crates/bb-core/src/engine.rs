@@ -0,0 +1,193 @@
+//! High-level matching API
+//!
+//! [`Engine`] wraps a snapshot and its [`Matcher`] and works from plain URLs:
+//! request/site hosts, eTLD+1, scheme and party are derived internally instead
+//! of every caller assembling a [`RequestContext`] by hand.
+
+use std::cell::RefCell;
+
+use crate::matcher::{
+    CosmeticHideSwitches, CosmeticMatchResult, CosmeticRuleMatch, CosmeticStats, DecisionDelta, Explanation, MatchScratch,
+    Matcher, ReplaceRule, RequestHeader, RequestHeaderMatchResult, ResponseHeader, ResponseMatchResult, SiteDiagnostics,
+};
+use crate::snapshot::Snapshot;
+use crate::types::{MatchResult, RequestType};
+pub use crate::types::RequestInfo;
+
+/// Snapshot plus matcher, queried by URL.
+///
+/// Request matching reuses the engine's own [`MatchScratch`], so an engine is
+/// `Send` but not `Sync`: give each thread its own, or share one
+/// [`Matcher`] and pass per-thread scratch to
+/// `match_request_with_scratch`.
+pub struct Engine<'a> {
+    snapshot: &'a Snapshot<'a>,
+    matcher: Matcher<'a>,
+    scratch: RefCell<MatchScratch>,
+}
+
+impl<'a> Engine<'a> {
+    pub fn new(snapshot: &'a Snapshot<'a>) -> Self {
+        Self {
+            snapshot,
+            matcher: Matcher::new(snapshot),
+            scratch: RefCell::new(MatchScratch::new()),
+        }
+    }
+
+    pub fn snapshot(&self) -> &'a Snapshot<'a> {
+        self.snapshot
+    }
+
+    pub fn matcher(&self) -> &Matcher<'a> {
+        &self.matcher
+    }
+
+    /// Mutable access for matcher state such as trusted sites.
+    pub fn matcher_mut(&mut self) -> &mut Matcher<'a> {
+        &mut self.matcher
+    }
+
+    /// Decide a network request.
+    pub fn check(&self, url: &str, request_type: RequestType, initiator: Option<&str>) -> MatchResult {
+        self.check_request(&RequestInfo::new(url, request_type, initiator))
+    }
+
+    /// Decide a window or tab `opener_url` opened for `url`. Only `$popup`
+    /// rules apply; a block means the new tab should be closed.
+    pub fn check_popup(&self, url: &str, opener_url: &str) -> MatchResult {
+        self.check(url, RequestType::POPUP, Some(opener_url))
+    }
+
+    pub fn check_request(&self, request: &RequestInfo<'_>) -> MatchResult {
+        let mut scratch = self.scratch.borrow_mut();
+        self.matcher.match_request_with_scratch(&request.context(), &mut scratch)
+    }
+
+    /// Every rule considered for a request and why it did or didn't apply.
+    pub fn explain_request(&self, request: &RequestInfo<'_>) -> Explanation {
+        self.matcher.explain_request(&request.context())
+    }
+
+    /// Cosmetic filtering for a top-level document.
+    pub fn cosmetics_for(&self, url: &str) -> CosmeticMatchResult {
+        self.cosmetics_for_request(&RequestInfo::new(url, RequestType::MAIN_FRAME, None))
+    }
+
+    pub fn cosmetics_for_request(&self, request: &RequestInfo<'_>) -> CosmeticMatchResult {
+        self.matcher.match_cosmetics(&request.context())
+    }
+
+    /// Exception rules active on the page at `url`, by kind and list.
+    pub fn site_diagnostics(&self, url: &str) -> SiteDiagnostics {
+        self.matcher.site_diagnostics(&RequestInfo::new(url, RequestType::MAIN_FRAME, None).context())
+    }
+
+    /// The cosmetic-disabling exceptions that apply to the page.
+    pub fn cosmetic_hide_switches_for_request(&self, request: &RequestInfo<'_>) -> CosmeticHideSwitches {
+        self.matcher.cosmetic_hide_switches(&request.context())
+    }
+
+    /// [`Engine::cosmetics_for_request`] with the page's switches already
+    /// known; see [`Matcher::match_cosmetics_with_switches`].
+    pub fn cosmetics_with_switches_for_request(
+        &self,
+        request: &RequestInfo<'_>,
+        switches: CosmeticHideSwitches,
+    ) -> CosmeticMatchResult {
+        self.matcher.match_cosmetics_with_switches(&request.context(), switches)
+    }
+
+    /// Hide CSS for bucketed generic selectors keyed by the page's
+    /// `#id` / `.class` hashes.
+    pub fn generic_cosmetics_for_request(&self, request: &RequestInfo<'_>, keys: &[u32]) -> String {
+        self.matcher.match_cosmetics_generic(&request.context(), keys)
+    }
+
+    /// [`Engine::generic_cosmetics_for_request`] with the page's switches
+    /// already known; see [`Matcher::match_cosmetics_generic_with_switches`].
+    pub fn generic_cosmetics_with_switches_for_request(
+        &self,
+        request: &RequestInfo<'_>,
+        keys: &[u32],
+        switches: CosmeticHideSwitches,
+    ) -> String {
+        self.matcher.match_cosmetics_generic_with_switches(&request.context(), keys, switches)
+    }
+
+    /// The hide rules behind [`Engine::cosmetics_for_request`] and, when
+    /// `keys` is non-empty, [`Engine::generic_cosmetics_for_request`].
+    pub fn cosmetic_rules_for_request(&self, request: &RequestInfo<'_>, keys: &[u32]) -> Vec<CosmeticRuleMatch> {
+        let switches = self.cosmetic_hide_switches_for_request(request);
+        self.cosmetic_rules_with_switches_for_request(request, keys, switches)
+    }
+
+    /// [`Engine::cosmetic_rules_for_request`] with the page's switches
+    /// already known.
+    pub fn cosmetic_rules_with_switches_for_request(
+        &self,
+        request: &RequestInfo<'_>,
+        keys: &[u32],
+        switches: CosmeticHideSwitches,
+    ) -> Vec<CosmeticRuleMatch> {
+        let ctx = request.context();
+        let mut rules = self.matcher.match_cosmetics_detailed_with_switches(&ctx, switches);
+        rules.extend(self.matcher.match_cosmetics_generic_detailed(&ctx, keys, switches));
+        rules
+    }
+
+    /// `selectors` minus the ones the page's `#@#` exceptions cover; see
+    /// [`Matcher::unexcepted_selectors`].
+    pub fn unexcepted_selectors_for_request<'s>(&self, request: &RequestInfo<'_>, selectors: &[&'s str]) -> Vec<&'s str> {
+        self.matcher.unexcepted_selectors(&request.context(), selectors)
+    }
+
+    /// Selector counts and sizes behind [`Engine::cosmetics_for_request`].
+    pub fn cosmetics_stats_for_request(&self, request: &RequestInfo<'_>) -> CosmeticStats {
+        self.matcher.cosmetics_stats(&request.context())
+    }
+
+    /// Response-header actions (CSP injection, header removal, cancel) for a
+    /// top-level document.
+    pub fn headers_for(&self, url: &str, headers: &[ResponseHeader<'_>]) -> ResponseMatchResult {
+        self.headers_for_request(&RequestInfo::new(url, RequestType::MAIN_FRAME, None), headers)
+    }
+
+    pub fn headers_for_request(
+        &self,
+        request: &RequestInfo<'_>,
+        headers: &[ResponseHeader<'_>],
+    ) -> ResponseMatchResult {
+        self.matcher.match_response_headers(&request.context(), headers)
+    }
+
+    /// The response's header list with CSP injected and removed headers
+    /// omitted; see [`ResponseMatchResult::rewrite`].
+    pub fn rewrite_headers_for_request(
+        &self,
+        request: &RequestInfo<'_>,
+        headers: &[ResponseHeader<'_>],
+    ) -> Vec<(String, Option<String>)> {
+        self.matcher.rewrite_headers(&request.context(), headers)
+    }
+
+    /// Outgoing-header actions: `$header=request:` cancels and
+    /// `$removeheader=request:` removals.
+    pub fn request_headers_for_request(
+        &self,
+        request: &RequestInfo<'_>,
+        headers: &[RequestHeader<'_>],
+    ) -> RequestHeaderMatchResult {
+        self.matcher.match_request_headers(&request.context(), headers)
+    }
+
+    /// `$replace=` body rewrites for a request.
+    pub fn replace_for_request(&self, request: &RequestInfo<'_>) -> Vec<ReplaceRule> {
+        self.matcher.match_replace(&request.context())
+    }
+
+    /// Decide a request under this engine and a previous one.
+    pub fn diff_decision(&self, previous: &Engine<'_>, request: &RequestInfo<'_>) -> DecisionDelta {
+        self.matcher.diff_decision(&previous.matcher, &request.context())
+    }
+}
@@ -141,6 +141,18 @@ pub fn hash_domain(domain: &str) -> Hash64 {
     hash64(&buf[..len])
 }
 
+/// Bit of `hi` that sets wildcard-TLD domain hashes apart from plain ones.
+pub const WILDCARD_TLD_FLAG: u32 = 0x8000_0000;
+
+/// Hash of a wildcard-TLD domain entry such as `example.*`, given the part
+/// before the TLD: [`hash_domain`] of `name` with [`WILDCARD_TLD_FLAG`]
+/// flipped, so it never equals the hash of the plain `name`.
+#[inline]
+pub fn hash_wildcard_domain(name: &str) -> Hash64 {
+    let hash = hash_domain(name);
+    Hash64::new(hash.lo, hash.hi ^ WILDCARD_TLD_FLAG)
+}
+
 /// Compute a 32-bit hash for tokens.
 /// Uses a single Murmur3 pass with a different seed.
 /// Ensures result is never 0 (sentinel value).
@@ -156,6 +168,13 @@ pub fn hash_token(token: &str) -> u32 {
 /// Compute CRC32 for snapshot integrity checking.
 /// Uses the standard CRC32 polynomial (IEEE 802.3).
 pub fn crc32(data: &[u8]) -> u32 {
+    crc32_parts(&[data])
+}
+
+/// Compute CRC32 over several slices as if they were concatenated.
+///
+/// Lets the loader skip the header CRC field without copying the snapshot.
+pub fn crc32_parts(parts: &[&[u8]]) -> u32 {
     static CRC32_TABLE: [u32; 256] = {
         let mut table = [0u32; 256];
         let mut i = 0;
@@ -177,8 +196,10 @@ pub fn crc32(data: &[u8]) -> u32 {
     };
 
     let mut crc = 0xffffffff_u32;
-    for &byte in data {
-        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    for part in parts {
+        for &byte in *part {
+            crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
     }
     crc ^ 0xffffffff
 }
@@ -216,9 +237,9 @@ mod tests {
 
     #[test]
     fn test_murmur3_various_lengths() {
+        let s = [b'a'; 20];
         for len in 1..=20 {
-            let s = vec![b'a'; len];
-            let h = murmur3_32(&s, 0);
+            let h = murmur3_32(&s[..len], 0);
             assert_eq!(h, h);
         }
     }
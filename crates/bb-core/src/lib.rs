@@ -1,7 +1,10 @@
 //! BetterBlocker Core Library
 //!
 //! This crate provides the core matching engine for the BetterBlocker content blocker.
-//! It is designed to be `no_std` compatible (with `alloc`) for maximum portability.
+//! With default features off it is `no_std` + `alloc`: snapshot loading, the
+//! PSL and [`Matcher`] are available, collections come from `hashbrown`, and
+//! the PSL is uncached. `cargo no-std-check` builds it for
+//! `thumbv7em-none-eabihf`.
 //!
 //! # Architecture
 //!
@@ -16,6 +19,8 @@
 //! - `snapshot`: UBX snapshot format and zero-copy loader
 //! - `url`: Fast URL parsing without allocations
 //! - `matcher`: Core request matching engine
+//! - `engine`: URL-level API over a snapshot and matcher (`std` feature)
+//! - `regex`: Regex filter compilation (`regex` feature)
 //! - `types`: Shared type definitions
 
 #![cfg_attr(not(feature = "std"), no_std)]
@@ -29,10 +34,20 @@ pub mod snapshot;
 pub mod types;
 pub mod url;
 pub mod matcher;
+mod sync;
+#[cfg(feature = "std")]
+pub mod engine;
+#[cfg(feature = "regex")]
+pub mod regex;
 
 // Re-export commonly used types
 pub use hash::{Hash64, hash64, hash_domain, hash_token};
-pub use psl::{get_etld1, is_third_party};
+pub use psl::{get_etld1, get_etld1_ref, is_third_party, walk_host_suffixes, HostSuffixIter};
 pub use snapshot::Snapshot;
 pub use matcher::Matcher;
-pub use types::{RequestContext, RuleAction, RequestType, MatchResult, MatchDecision};
+#[cfg(feature = "std")]
+pub use engine::Engine;
+pub use types::{
+    RequestContext, RequestContextBuilder, RequestContextError, RequestInfo, RuleAction, RequestType, MatchResult,
+    MatchDecision,
+};
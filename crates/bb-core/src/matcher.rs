@@ -3,27 +3,194 @@
 //! This is the hot path - every request goes through here.
 //! Performance is critical: minimize allocations, use zero-copy views.
 
-use std::collections::HashSet;
+#[cfg(not(feature = "std"))]
+use alloc::{collections::BTreeMap, string::{String, ToString}, vec::Vec};
 
-use crate::hash::hash_domain;
+#[cfg(feature = "std")]
+use std::collections::{BTreeMap, HashSet};
+#[cfg(not(feature = "std"))]
+use hashbrown::HashSet;
+
+use crate::hash::{hash_domain, hash_token, hash_wildcard_domain};
 use crate::psl::walk_host_suffixes;
+use crate::sync::RwLock;
 use crate::snapshot::{
-    Snapshot, decode_posting_list, decode_posting_list_with_count, PatternOp, NO_PATTERN, NO_CONSTRAINT,
-    read_u32_le, read_u16_le,
+    Snapshot, append_bucketed_postings, decode_posting_list_with_count_into, posting_bucket_for, PatternOp, PostingBuf, NO_PATTERN,
+    NO_CONSTRAINT,
+    read_u32_le, read_u16_le, replace_flags, pattern_flags, REPLACE_SPEC_ENTRY_SIZE,
+    cosmetic_style_entry, COSMETIC_STYLE_ENTRY_SIZE, removeheader_spec_entry, removeheader_flags,
+    header_spec_flags, HEADER_SPEC_ENTRY_SIZE,
+    REMOVEHEADER_SPEC_ENTRY_SIZE, generic_cosmetic_bucket_entry, GENERIC_COSMETIC_BUCKET_ENTRY_SIZE,
+    COSMETIC_SELECTOR_HASH_ENTRY_SIZE, COSMETIC_RULE_LINE_ENTRY_SIZE, redirect_resource_entry, redirect_resource_flags,
+    REDIRECT_DATA_ENTRY_SIZE, REDIRECT_RESOURCE_ENTRY_SIZE, procedural_op_entry, procedural_program_entry,
+    procedural_rule_entry, PROCEDURAL_OP_ENTRY_SIZE, PROCEDURAL_PROGRAM_ENTRY_SIZE, PROCEDURAL_RULE_ENTRY_SIZE,
 };
+#[cfg(feature = "regex")]
+use crate::snapshot::{bounded_count, regex_pattern_entry, REGEX_PATTERN_ENTRY_SIZE};
 use crate::types::{
-    MatchDecision, MatchResult, PartyMask, RequestContext, RequestType, RuleAction, RuleFlags,
+    ListTier, MatchDecision, MatchResult, PartyMask, ProceduralOpKind, RequestContext, RequestType, RuleAction,
+    RuleFlags, SchemeMask, SiteSwitches,
 };
-use crate::url::{extract_host, is_at_boundary, get_host_position, tokenize_url};
+use crate::url::{extract_host, extract_scheme, is_at_boundary, get_host_position, tokenize_url_into};
+
+#[cfg(feature = "audit")]
+mod audit;
+mod diagnostics;
+mod explain;
+
+#[cfg(feature = "audit")]
+pub use audit::{AuditClock, AuditEntry, DEFAULT_AUDIT_CAPACITY};
+pub use diagnostics::{ExceptionCount, ExceptionKind, SiteDiagnostics};
+pub use explain::{CandidateSource, DecisionReason, ExplainedCandidate, Explanation, Rejection};
 
 // =============================================================================
 // Matcher
 // =============================================================================
 
 /// The core matching engine.
+///
+/// `Matcher` is `Send + Sync`: one instance can serve any number of threads,
+/// each passing its own [`MatchScratch`] to the `_with_scratch` methods.
+/// Trusted sites can change while it is shared; the other settings take
+/// `&mut self`.
 pub struct Matcher<'a> {
     snapshot: &'a Snapshot<'a>,
-    trusted_sites: HashSet<String>,
+    trusted_sites: TrustedSites,
+    /// Bitset of list ids whose rules are skipped, indexed by `list_id / 64`
+    disabled_lists: Vec<u64>,
+    /// Bitset of network rule ids that never match, indexed by `rule_id / 64`
+    disabled_rules: Vec<u64>,
+    /// Bitset of rules whose `$expires=` time has passed, as of the last
+    /// [`Matcher::set_current_time`]
+    expired_rules: Vec<u64>,
+    /// Earliest `$expires=` time not yet reached, unix seconds
+    next_rule_expiry: Option<u64>,
+    /// Emit `$csp` injections as `Content-Security-Policy-Report-Only`
+    csp_report_only: bool,
+    /// Redirect to embedded `data:` URLs rather than extension paths
+    redirect_data_urls: bool,
+    /// Selectors per hide CSS rule
+    hide_css_chunk: usize,
+    /// Bytes above which [`SiteSwitches::NO_LARGE_MEDIA`] cancels a response
+    large_media_size: u64,
+    /// Tier of each list by `list_id`; empty when every list is built-in
+    list_tiers: Vec<ListTier>,
+    /// The snapshot has `$genericblock` exceptions, so generic block rules
+    /// need the page checked
+    has_genericblock: bool,
+    /// Extension and internal schemes some rule names explicitly
+    targeted_schemes: SchemeMask,
+    /// Runtime switches by site host; see [`Matcher::set_site_switches`]
+    site_switches: BTreeMap<String, SiteSwitches>,
+    budget: MatchBudget,
+    /// `/.../` filters, compiled once per snapshot
+    #[cfg(feature = "regex")]
+    regex_rules: Vec<RegexRule>,
+    /// Recent decisions; see [`Matcher::recent_decisions`]
+    #[cfg(feature = "audit")]
+    audit: audit::AuditLog,
+}
+
+/// Behind a lock, so the list can change while the matcher is shared
+/// between threads.
+type TrustedSites = RwLock<HashSet<String>>;
+
+// A matcher and the snapshot it borrows can be shared across threads.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Matcher<'static>>();
+    assert_send_sync::<Snapshot<'static>>();
+    assert_send_sync::<crate::snapshot::RulesView<'static>>();
+    assert_send_sync::<crate::snapshot::DomainHashSet<'static>>();
+    assert_send_sync::<crate::snapshot::TokenDict<'static>>();
+    assert_send_sync::<crate::snapshot::PatternPool<'static>>();
+};
+
+#[cfg(feature = "regex")]
+struct RegexRule {
+    rule_id: usize,
+    regex: crate::regex::Regex,
+}
+
+/// Reusable buffers for the request match path.
+///
+/// Keep one per caller (or per thread) and pass it to
+/// [`Matcher::match_request_with_scratch`]: once the buffers have grown to fit
+/// the workload, matching a request no longer allocates.
+#[derive(Default)]
+pub struct MatchScratch {
+    candidates: Vec<MatchCandidate>,
+    tokens: Vec<u32>,
+    postings: PostingBuf,
+    work: MatchWork,
+    /// `https://<site_host>/` for the `$genericblock` lookup
+    page_url: String,
+}
+
+impl MatchScratch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drop candidates for rules already found by another stage or posting
+    /// and order the rest by rule id, so precedence ties don't depend on
+    /// which path found a rule first.
+    fn dedup_candidates(&mut self) {
+        self.candidates.sort_unstable_by_key(|candidate| candidate.rule_id);
+        self.candidates.dedup_by_key(|candidate| candidate.rule_id);
+    }
+}
+
+/// Per-request work limits, so a pathological URL (say, a query string with
+/// dozens of parameters hitting thousands of postings) can't stall the
+/// request handler. Zero means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MatchBudget {
+    /// Candidate rules checked (options, domains, pattern or regex)
+    pub max_candidates: u32,
+    /// Pattern bytecode ops executed across all candidates
+    pub max_pattern_ops: u32,
+}
+
+impl MatchBudget {
+    pub const UNLIMITED: Self = Self {
+        max_candidates: 0,
+        max_pattern_ops: 0,
+    };
+}
+
+/// Work done so far for the current request.
+#[derive(Debug, Default)]
+struct MatchWork {
+    budget: MatchBudget,
+    candidates: u32,
+    pattern_ops: u32,
+    exceeded: bool,
+}
+
+impl MatchWork {
+    fn new(budget: MatchBudget) -> Self {
+        Self { budget, ..Self::default() }
+    }
+
+    /// Count one candidate; false once the budget is spent.
+    #[inline]
+    fn charge_candidate(&mut self) -> bool {
+        self.candidates += 1;
+        if self.budget.max_candidates != 0 && self.candidates > self.budget.max_candidates {
+            self.exceeded = true;
+        }
+        !self.exceeded
+    }
+
+    #[inline]
+    fn charge_pattern_op(&mut self) -> bool {
+        self.pattern_ops += 1;
+        if self.budget.max_pattern_ops != 0 && self.pattern_ops > self.budget.max_pattern_ops {
+            self.exceeded = true;
+        }
+        !self.exceeded
+    }
 }
 
 pub struct ResponseHeader<'a> {
@@ -31,11 +198,30 @@ pub struct ResponseHeader<'a> {
     pub value: &'a str,
 }
 
+/// An outgoing header, as seen by `onBeforeSendHeaders`.
+pub type RequestHeader<'a> = ResponseHeader<'a>;
+
 pub struct ResponseMatchResult {
     pub cancel: bool,
     pub rule_id: i32,
     pub list_id: u16,
     pub csp_injections: Vec<String>,
+    /// Whether `csp_injections` should be reported rather than enforced
+    pub csp_report_only: bool,
+    pub remove_headers: Vec<String>,
+    /// The site switch that cancelled the response instead of a rule (with
+    /// `rule_id` -1); empty otherwise
+    pub site_switch: SiteSwitches,
+}
+
+/// Actions for a request's outgoing headers; see [`Matcher::match_request_headers`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHeaderMatchResult {
+    /// A `$header=request:` block rule matched the headers
+    pub cancel: bool,
+    pub rule_id: i32,
+    pub list_id: u16,
+    /// Headers to strip under `$removeheader=request:` rules
     pub remove_headers: Vec<String>,
 }
 
@@ -44,15 +230,128 @@ pub struct ScriptletCall {
     pub args: Vec<String>,
 }
 
+/// A procedural selector compiled into a base CSS selector and the
+/// operators the content script applies to its matches, in order.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProceduralProgram {
+    pub base: String,
+    pub ops: Vec<ProceduralOp>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProceduralOp {
+    pub kind: ProceduralOpKind,
+    /// Argument between the parentheses, trimmed
+    pub arg: String,
+}
+
 pub struct CosmeticMatchResult {
-    pub css: String,
+    /// Hide rules, one `{display:none}` rule per chunk of
+    /// [`Matcher::hide_css_chunk`] selectors
+    pub css: Vec<String>,
+    /// Rules for `selector:style(...)` filters, with their own declarations
+    pub style_css: String,
     pub enable_generic: bool,
     pub scriptlets: Vec<ScriptletCall>,
-    pub procedural: Vec<String>,
+    pub procedural: Vec<ProceduralProgram>,
+    /// [`SiteSwitches::NO_COSMETIC_FILTERING`] when that switch left the
+    /// result empty
+    pub site_switch: SiteSwitches,
+}
+
+/// Number and total length in bytes of a group of selectors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SelectorStats {
+    pub count: u32,
+    pub bytes: u32,
+}
+
+impl SelectorStats {
+    fn add(&mut self, selector: &str) {
+        self.count += 1;
+        self.bytes = self.bytes.saturating_add(selector.len() as u32);
+    }
+}
+
+/// How much cosmetic filtering applies to a page, measured without building
+/// any CSS. See [`Matcher::cosmetics_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CosmeticStats {
+    /// Site-specific `##` hides
+    pub specific: SelectorStats,
+    /// Generic `##` hides injected on every page
+    pub generic: SelectorStats,
+    /// Bucketed generic hides, only injected for ids and classes the page
+    /// has, via [`Matcher::match_cosmetics_generic`]
+    pub generic_bucketed: SelectorStats,
+    pub procedural: SelectorStats,
+    pub enable_generic: bool,
+}
+
+/// A plain `##` hide rule applied to a page, so hidden elements can be
+/// attributed to the filter responsible.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CosmeticRuleMatch {
+    pub selector: String,
+    pub list_id: u16,
+    /// Index of the rule in the snapshot's CosmeticRules section
+    pub rule_index: u32,
+    /// 1-based source line within the list; 0 if the snapshot predates
+    /// the CosmeticRuleLines section
+    pub line: u32,
+}
+
+/// A `$replace=` body rewrite applicable to a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplaceRule {
+    pub pattern: String,
+    pub replacement: String,
+    /// Regex flags in JS form (e.g. "gi")
+    pub flags: String,
+    pub rule_id: i32,
+    pub list_id: u16,
+}
+
+/// Decisions for the same request under two snapshots.
+#[derive(Debug, Clone)]
+pub struct DecisionDelta {
+    pub previous: MatchResult,
+    pub current: MatchResult,
+}
+
+impl DecisionDelta {
+    /// Whether the decision (or redirect target) differs between snapshots.
+    pub fn changed(&self) -> bool {
+        self.previous.decision != self.current.decision
+            || self.previous.redirect_url != self.current.redirect_url
+    }
 }
 
 const NO_OPTION_ID: u32 = 0xFFFF_FFFF;
 
+/// Selectors per `{display:none}` rule in hide CSS unless
+/// [`Matcher::set_hide_css_chunk`] says otherwise. A selector the browser
+/// can't parse invalidates the whole rule, so chunking bounds the damage to
+/// its own group.
+pub const DEFAULT_HIDE_CSS_CHUNK: usize = 256;
+
+/// Response size in bytes above which [`SiteSwitches::NO_LARGE_MEDIA`]
+/// cancels images and media unless [`Matcher::set_large_media_size`] says
+/// otherwise.
+pub const DEFAULT_LARGE_MEDIA_SIZE: u64 = 50 * 1024;
+
+/// Policy injected into documents on sites with
+/// [`SiteSwitches::NO_SCRIPTING`].
+pub const NO_SCRIPTING_CSP: &str = "script-src 'none'";
+
+/// Cosmetic-disabling exceptions that matched a page.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CosmeticHideSwitches {
+    pub elemhide: bool,
+    pub generichide: bool,
+    pub specifichide: bool,
+}
+
 impl Default for ResponseMatchResult {
     fn default() -> Self {
         Self {
@@ -60,45 +359,370 @@ impl Default for ResponseMatchResult {
             rule_id: -1,
             list_id: 0,
             csp_injections: Vec::new(),
+            csp_report_only: false,
+            remove_headers: Vec::new(),
+            site_switch: SiteSwitches::empty(),
+        }
+    }
+}
+
+impl Default for RequestHeaderMatchResult {
+    fn default() -> Self {
+        Self {
+            cancel: false,
+            rule_id: -1,
+            list_id: 0,
             remove_headers: Vec::new(),
         }
     }
 }
 
+impl ResponseMatchResult {
+    /// Apply this result to a response's headers.
+    ///
+    /// Removed headers are omitted and the rest keep their order. Headers
+    /// passed through untouched come back as `(name, None)` so callers can
+    /// keep the original value (including non-UTF-8 ones); headers produced
+    /// here carry `Some(value)`.
+    ///
+    /// CSP injections are appended as one extra `Content-Security-Policy`
+    /// header. A comma-separated header value is a list of independent
+    /// policies that are all enforced, so the page's own policy is never
+    /// loosened by the merge. In report-only mode the header is
+    /// `Content-Security-Policy-Report-Only` instead, which leaves the page
+    /// untouched but surfaces would-be violations in the console.
+    pub fn rewrite(&self, headers: &[ResponseHeader<'_>]) -> Vec<(String, Option<String>)> {
+        let mut rewritten: Vec<(String, Option<String>)> = headers
+            .iter()
+            .filter(|header| {
+                !self
+                    .remove_headers
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(header.name))
+            })
+            .map(|header| (header.name.to_string(), None))
+            .collect();
+
+        if !self.csp_injections.is_empty() {
+            let mut policies: Vec<&str> = self.csp_injections.iter().map(String::as_str).collect();
+            policies.sort_unstable();
+            let name = if self.csp_report_only {
+                "Content-Security-Policy-Report-Only"
+            } else {
+                "Content-Security-Policy"
+            };
+            rewritten.push((name.to_string(), Some(policies.join(", "))));
+        }
+
+        rewritten
+    }
+}
+
 impl<'a> Matcher<'a> {
     /// Create a new matcher with the given snapshot.
     pub fn new(snapshot: &'a Snapshot<'a>) -> Self {
         Self {
             snapshot,
-            trusted_sites: HashSet::new(),
+            trusted_sites: TrustedSites::default(),
+            disabled_lists: Vec::new(),
+            disabled_rules: Vec::new(),
+            expired_rules: Vec::new(),
+            next_rule_expiry: snapshot.rule_expiry().map(|(_, expires_at)| expires_at as u64).min(),
+            csp_report_only: false,
+            redirect_data_urls: false,
+            hide_css_chunk: DEFAULT_HIDE_CSS_CHUNK,
+            large_media_size: DEFAULT_LARGE_MEDIA_SIZE,
+            list_tiers: list_tiers(snapshot),
+            has_genericblock: has_genericblock(snapshot),
+            targeted_schemes: targeted_schemes(snapshot),
+            site_switches: BTreeMap::new(),
+            budget: MatchBudget::UNLIMITED,
+            #[cfg(feature = "regex")]
+            regex_rules: compile_regex_rules(snapshot),
+            #[cfg(feature = "audit")]
+            audit: audit::AuditLog::new(DEFAULT_AUDIT_CAPACITY),
         }
     }
 
     /// Add a site to the trusted list (bypass all blocking).
-    pub fn add_trusted_site(&mut self, site: &str) {
-        self.trusted_sites.insert(site.to_lowercase());
+    pub fn add_trusted_site(&self, site: &str) {
+        self.trusted_sites.write().insert(site.to_lowercase());
     }
 
     /// Remove a site from the trusted list.
-    pub fn remove_trusted_site(&mut self, site: &str) {
-        self.trusted_sites.remove(&site.to_lowercase());
+    pub fn remove_trusted_site(&self, site: &str) {
+        self.trusted_sites.write().remove(&site.to_lowercase());
+    }
+
+    pub(crate) fn is_trusted_site(&self, site_etld1: &str) -> bool {
+        let trusted_sites = self.trusted_sites.read();
+        !trusted_sites.is_empty() && trusted_sites.contains(site_etld1)
+    }
+
+    /// Set the switches for pages on `site` (a host; its subdomains are
+    /// covered too), replacing any previous ones. Empty switches remove the
+    /// site.
+    pub fn set_site_switches(&mut self, site: &str, switches: SiteSwitches) {
+        let site = site.trim_end_matches('.').to_lowercase();
+        if switches.is_empty() {
+            self.site_switches.remove(&site);
+        } else {
+            self.site_switches.insert(site, switches);
+        }
+    }
+
+    /// Remove the switches from every site.
+    pub fn clear_site_switches(&mut self) {
+        self.site_switches.clear();
+    }
+
+    /// Every site with switches set.
+    pub fn all_site_switches(&self) -> impl Iterator<Item = (&str, SiteSwitches)> + '_ {
+        self.site_switches.iter().map(|(site, &switches)| (site.as_str(), switches))
+    }
+
+    /// Switches in effect for pages on `site_host`: those of the most
+    /// specific host they were set for, down to its eTLD+1.
+    pub fn site_switches(&self, site_host: &str) -> SiteSwitches {
+        if self.site_switches.is_empty() {
+            return SiteSwitches::empty();
+        }
+        walk_host_suffixes(site_host)
+            .find_map(|suffix| self.site_switches.get(suffix).copied())
+            .unwrap_or_default()
+    }
+
+    fn cosmetics_switched_off(&self, ctx: &RequestContext<'_>) -> bool {
+        self.site_switches(ctx.site_host).contains(SiteSwitches::NO_COSMETIC_FILTERING)
+    }
+
+    /// The switch that blocks this request outright, if any.
+    fn switch_block(&self, ctx: &RequestContext<'_>) -> Option<SiteSwitches> {
+        let switches = self.site_switches(ctx.site_host);
+        if switches.contains(SiteSwitches::NO_REMOTE_FONTS)
+            && ctx.request_type.intersects(RequestType::FONT)
+            && ctx.scheme != SchemeMask::DATA
+        {
+            return Some(SiteSwitches::NO_REMOTE_FONTS);
+        }
+        if switches.contains(SiteSwitches::NO_SCRIPTING) && ctx.request_type.intersects(RequestType::SCRIPT) {
+            return Some(SiteSwitches::NO_SCRIPTING);
+        }
+        None
+    }
+
+    /// Skip every rule from these lists, replacing any previous set.
+    ///
+    /// Lets a list be toggled off without recompiling the snapshot: network,
+    /// cosmetic, scriptlet and header rules from disabled lists never match.
+    pub fn set_disabled_lists(&mut self, list_ids: &[u16]) {
+        self.disabled_lists = id_bitset(list_ids.iter().map(|&id| id as usize));
+    }
+
+    /// The snapshot this matcher reads.
+    pub fn snapshot(&self) -> &'a Snapshot<'a> {
+        self.snapshot
+    }
+
+    /// Skip individual network rules, replacing any previous set.
+    ///
+    /// For neutralizing a rule the logger showed breaking a site without
+    /// recompiling. Rule ids are only meaningful for this snapshot.
+    pub fn set_disabled_rules(&mut self, rule_ids: &[u32]) {
+        self.disabled_rules = id_bitset(rule_ids.iter().map(|&id| id as usize));
+    }
+
+    /// Rule ids passed to [`Matcher::set_disabled_rules`], ascending.
+    pub fn disabled_rules(&self) -> Vec<u32> {
+        let mut rule_ids = Vec::new();
+        for (word_index, &word) in self.disabled_rules.iter().enumerate() {
+            let mut bits = word;
+            while bits != 0 {
+                rule_ids.push((word_index * 64) as u32 + bits.trailing_zeros());
+                bits &= bits - 1;
+            }
+        }
+        rule_ids
+    }
+
+    /// Tell the matcher the time, in unix seconds, so rules whose `$expires=`
+    /// date has passed stop matching. The matcher has no clock of its own:
+    /// until this is called no rule expires. Call it again once
+    /// [`Matcher::next_rule_expiry`] is reached.
+    pub fn set_current_time(&mut self, unix_secs: u64) {
+        let expiry = self.snapshot.rule_expiry();
+        self.expired_rules = id_bitset(
+            expiry
+                .filter(|&(_, expires_at)| expires_at as u64 <= unix_secs)
+                .map(|(rule_id, _)| rule_id as usize),
+        );
+        self.next_rule_expiry = self
+            .snapshot
+            .rule_expiry()
+            .map(|(_, expires_at)| expires_at as u64)
+            .filter(|&expires_at| expires_at > unix_secs)
+            .min();
+    }
+
+    /// When the next `$expires=` rule stops matching, if any rule is still
+    /// due to.
+    pub fn next_rule_expiry(&self) -> Option<u64> {
+        self.next_rule_expiry
+    }
+
+    /// Report `$csp` injections instead of enforcing them, so a policy's
+    /// breakage can be audited before it is switched on.
+    pub fn set_csp_report_only(&mut self, report_only: bool) {
+        self.csp_report_only = report_only;
+    }
+
+    /// Redirect to the resource's embedded `data:` URL instead of its
+    /// `/redirects/...` path, for callers that can't serve extension files
+    /// (MV3 DNR fallback, server-side use). Resources the snapshot has no
+    /// data for keep their path.
+    pub fn set_redirect_data_urls(&mut self, data_urls: bool) {
+        self.redirect_data_urls = data_urls;
+    }
+
+    /// Selectors per `{display:none}` rule in [`CosmeticMatchResult::css`]
+    /// (at least one). Browsers cap selectors per rule, and one selector
+    /// they can't parse drops its whole rule.
+    pub fn set_hide_css_chunk(&mut self, selectors: usize) {
+        self.hide_css_chunk = selectors.max(1);
+    }
+
+    pub fn hide_css_chunk(&self) -> usize {
+        self.hide_css_chunk
+    }
+
+    /// Largest `Content-Length`, in bytes, an image or media response may
+    /// have on sites with [`SiteSwitches::NO_LARGE_MEDIA`].
+    pub fn set_large_media_size(&mut self, bytes: u64) {
+        self.large_media_size = bytes;
+    }
+
+    pub fn large_media_size(&self) -> u64 {
+        self.large_media_size
+    }
+
+    /// Cap the work a single [`Matcher::match_request`] may do. Requests
+    /// that hit the cap are allowed, with [`MatchResult::budget_exceeded`]
+    /// set.
+    pub fn set_match_budget(&mut self, budget: MatchBudget) {
+        self.budget = budget;
+    }
+
+    pub fn match_budget(&self) -> MatchBudget {
+        self.budget
+    }
+
+    /// Whether any rule could match a request to `url`. Extension, internal
+    /// and unknown schemes are skipped unless a rule names them
+    /// (`$extension`, `$internal`).
+    pub fn is_filterable(&self, url: &str) -> bool {
+        extract_scheme(url).is_some_and(|scheme| self.filters_scheme(scheme))
+    }
+
+    fn filters_scheme(&self, scheme: SchemeMask) -> bool {
+        scheme.intersects(SchemeMask::WEB | self.targeted_schemes)
+    }
+
+    pub fn list_tier(&self, list_id: u16) -> ListTier {
+        self.list_tiers.get(list_id as usize).copied().unwrap_or_default()
+    }
+
+    /// Tier of a block or plain exception rule; `None` for rules that
+    /// never decide a request themselves, and when tiers are all equal.
+    pub(crate) fn deciding_tier(&self, rule_id: usize, action: RuleAction) -> Option<ListTier> {
+        if self.list_tiers.is_empty() {
+            return None;
+        }
+        let rules = self.snapshot.rules();
+        let modifier_exception = RuleFlags::REDIRECT_RULE_EXCEPTION
+            | RuleFlags::ELEMHIDE
+            | RuleFlags::GENERICHIDE
+            | RuleFlags::SPECIFICHIDE
+            | RuleFlags::GENERICBLOCK;
+        match action {
+            RuleAction::Block => {}
+            RuleAction::Allow if !RuleFlags::from_bits_truncate(rules.flags(rule_id)).intersects(modifier_exception) => {}
+            _ => return None,
+        }
+        Some(self.list_tier(rules.list_id(rule_id)))
+    }
+
+    pub fn is_list_enabled(&self, list_id: u16) -> bool {
+        !id_bitset_contains(&self.disabled_lists, list_id as usize)
+    }
+
+    #[inline]
+    pub fn is_rule_enabled(&self, rule_id: usize) -> bool {
+        !id_bitset_contains(&self.disabled_rules, rule_id)
     }
 
     /// Match a request and return the decision.
     pub fn match_request(&self, ctx: &RequestContext<'_>) -> MatchResult {
+        self.match_request_with_scratch(ctx, &mut MatchScratch::new())
+    }
+
+    /// [`Matcher::match_request`] using caller-owned buffers.
+    pub fn match_request_with_scratch(&self, ctx: &RequestContext<'_>, scratch: &mut MatchScratch) -> MatchResult {
+        let result = self.decide_request(ctx, scratch);
+        #[cfg(feature = "audit")]
+        self.audit.record(ctx, &result);
+        result
+    }
+
+    fn decide_request(&self, ctx: &RequestContext<'_>, scratch: &mut MatchScratch) -> MatchResult {
         // A0: Trusted site bypass
-        if self.trusted_sites.contains(ctx.site_etld1) {
+        if self.is_trusted_site(ctx.site_etld1) {
+            return MatchResult::default();
+        }
+        if !self.filters_scheme(ctx.scheme) {
             return MatchResult::default();
         }
+        if let Some(site_switch) = self.switch_block(ctx) {
+            return MatchResult {
+                decision: MatchDecision::Block,
+                site_switch,
+                ..MatchResult::default()
+            };
+        }
 
         // A1: Dynamic filtering would go here
 
-        if let Some(result) = self.match_removeparam(ctx) {
-            return result;
+        // Token and regex rules feed both `$removeparam` and static filtering,
+        // so they are matched, and charged to the budget, once per request.
+        scratch.work = MatchWork::new(self.budget);
+        scratch.candidates.clear();
+        self.match_token_rules(ctx, scratch);
+        scratch.dedup_candidates();
+
+        let result = match self.match_removeparam(ctx, &scratch.candidates) {
+            Some(result) => result,
+            // A3: Static network filtering
+            None => self.match_static_filters(ctx, scratch),
+        };
+        // The candidates cut by the budget may include the exception that
+        // covers this request, so a partial match never blocks.
+        if scratch.work.exceeded {
+            return MatchResult {
+                budget_exceeded: true,
+                ..MatchResult::default()
+            };
         }
+        result
+    }
 
-        // A3: Static network filtering
-        self.match_static_filters(ctx)
+    /// Match a request against this matcher and a previous one.
+    ///
+    /// Used after a snapshot reload to attribute decision changes to the
+    /// list update; each side reports its own responsible rule.
+    pub fn diff_decision(&self, previous: &Matcher<'_>, ctx: &RequestContext<'_>) -> DecisionDelta {
+        DecisionDelta {
+            previous: previous.match_request(ctx),
+            current: self.match_request(ctx),
+        }
     }
 
     pub fn match_response_headers(
@@ -106,11 +730,14 @@ impl<'a> Matcher<'a> {
         ctx: &RequestContext<'_>,
         headers: &[ResponseHeader<'_>],
     ) -> ResponseMatchResult {
-        let mut result = ResponseMatchResult::default();
+        let mut result = ResponseMatchResult {
+            csp_report_only: self.csp_report_only,
+            ..ResponseMatchResult::default()
+        };
 
-        let mut candidates = Vec::new();
-        self.match_domain_sets(ctx, &mut candidates);
-        self.match_token_rules(ctx, &mut candidates);
+        let mut scratch = MatchScratch::new();
+        self.collect_candidates(ctx, &mut scratch);
+        let candidates = scratch.candidates;
 
         let rules = self.snapshot.rules();
         let document_only = ctx.request_type.intersects(RequestType::DOCUMENT);
@@ -119,59 +746,22 @@ impl<'a> Matcher<'a> {
         let mut csp_exceptions: HashSet<&str> = HashSet::new();
         let mut csp_disabled = false;
 
-        let mut best_important_block: Option<&MatchCandidate> = None;
-        let mut best_allow: Option<&MatchCandidate> = None;
-        let mut best_block: Option<&MatchCandidate> = None;
-
         for candidate in &candidates {
             let option_id = rules.option_id(candidate.rule_id);
-            if option_id == NO_OPTION_ID {
+            if !document_only || candidate.action != RuleAction::CspInject || option_id == NO_OPTION_ID {
                 continue;
             }
-
-            match candidate.action {
-                RuleAction::CspInject => {
-                    if !document_only {
-                        continue;
-                    }
-                    let flags = RuleFlags::from_bits_truncate(rules.flags(candidate.rule_id));
-                    if let Some(spec) = self.get_csp_spec(option_id) {
-                        if flags.contains(RuleFlags::CSP_EXCEPTION) {
-                            if spec.is_empty() {
-                                csp_disabled = true;
-                            } else {
-                                csp_exceptions.insert(spec);
-                            }
-                        } else {
-                            csp_injection_set.insert(spec);
-                        }
-                    }
-                }
-                RuleAction::HeaderMatchBlock | RuleAction::HeaderMatchAllow => {
-                    let spec = match self.get_header_spec(option_id) {
-                        Some(spec) => spec,
-                        None => continue,
-                    };
-                    if !header_matches(&spec, headers) {
-                        continue;
-                    }
-                    if candidate.action == RuleAction::HeaderMatchAllow {
-                        if best_allow.map_or(true, |b| candidate.priority > b.priority) {
-                            best_allow = Some(candidate);
-                        }
-                        continue;
-                    }
-
-                    let flags = RuleFlags::from_bits_truncate(rules.flags(candidate.rule_id));
-                    if flags.contains(RuleFlags::IMPORTANT) {
-                        if best_important_block.map_or(true, |b| candidate.priority > b.priority) {
-                            best_important_block = Some(candidate);
-                        }
-                    } else if best_block.map_or(true, |b| candidate.priority > b.priority) {
-                        best_block = Some(candidate);
+            let flags = RuleFlags::from_bits_truncate(rules.flags(candidate.rule_id));
+            if let Some(spec) = self.get_csp_spec(option_id) {
+                if flags.contains(RuleFlags::CSP_EXCEPTION) {
+                    if spec.is_empty() {
+                        csp_disabled = true;
+                    } else {
+                        csp_exceptions.insert(spec);
                     }
+                } else {
+                    csp_injection_set.insert(spec);
                 }
-                _ => {}
             }
         }
 
@@ -182,6 +772,13 @@ impl<'a> Matcher<'a> {
                 }
             }
         }
+        // The user asked for it, so list exceptions don't apply.
+        if document_only
+            && self.site_switches(ctx.site_host).contains(SiteSwitches::NO_SCRIPTING)
+            && !self.is_trusted_site(ctx.site_etld1)
+        {
+            result.csp_injections.push(NO_SCRIPTING_CSP.to_string());
+        }
 
         if document_only {
             let section = self.snapshot.responseheader_rules();
@@ -195,6 +792,9 @@ impl<'a> Matcher<'a> {
                         break;
                     }
                     let constraint_offset = read_u32_le(section, entry_offset);
+                    if !self.is_list_enabled(read_u16_le(section, entry_offset + 14)) {
+                        continue;
+                    }
                     if !self.check_domain_constraints_offset(constraint_offset, ctx) {
                         continue;
                     }
@@ -226,251 +826,1004 @@ impl<'a> Matcher<'a> {
             }
         }
 
-        if let Some(c) = best_important_block {
+        for header in self.collect_removeheaders(&candidates, false) {
+            if !result.remove_headers.iter().any(|existing| existing.eq_ignore_ascii_case(header)) {
+                result.remove_headers.push(header.to_string());
+            }
+        }
+
+        if let Some(c) = self.header_rule_block(&candidates, headers, false) {
             result.cancel = true;
             result.rule_id = c.rule_id as i32;
             result.list_id = rules.list_id(c.rule_id);
-            return result;
+        } else if self.is_large_media(ctx, headers) {
+            result.cancel = true;
+            result.site_switch = SiteSwitches::NO_LARGE_MEDIA;
+        }
+
+        result
+    }
+
+    /// An image or media response over the large media size, on a site with
+    /// [`SiteSwitches::NO_LARGE_MEDIA`]. Responses without a usable
+    /// `Content-Length` (chunked, streamed) are let through.
+    fn is_large_media(&self, ctx: &RequestContext<'_>, headers: &[ResponseHeader<'_>]) -> bool {
+        if !ctx.request_type.intersects(RequestType::IMAGE | RequestType::MEDIA)
+            || !self.site_switches(ctx.site_host).contains(SiteSwitches::NO_LARGE_MEDIA)
+            || self.is_trusted_site(ctx.site_etld1)
+        {
+            return false;
         }
+        headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+            .and_then(|header| header.value.trim().parse::<u64>().ok())
+            .is_some_and(|length| length > self.large_media_size)
+    }
+
+    /// The response's header list after CSP injection and header removal.
+    ///
+    /// See [`ResponseMatchResult::rewrite`]; callers that also need the
+    /// cancel decision should use [`Matcher::match_response_headers`].
+    pub fn rewrite_headers(
+        &self,
+        ctx: &RequestContext<'_>,
+        headers: &[ResponseHeader<'_>],
+    ) -> Vec<(String, Option<String>)> {
+        self.match_response_headers(ctx, headers).rewrite(headers)
+    }
 
-        if best_allow.is_some() && best_block.is_some() {
+    /// Actions for a request's outgoing headers, for `onBeforeSendHeaders`:
+    /// `$header=request:` rules that cancel it (e.g. on a `Referer`) and
+    /// `$removeheader=request:` headers to strip.
+    ///
+    /// Only headers present in `headers` are listed for removal.
+    pub fn match_request_headers(
+        &self,
+        ctx: &RequestContext<'_>,
+        headers: &[RequestHeader<'_>],
+    ) -> RequestHeaderMatchResult {
+        let mut result = RequestHeaderMatchResult::default();
+        if self.is_trusted_site(ctx.site_etld1) {
             return result;
         }
 
-        if let Some(c) = best_block {
+        let mut scratch = MatchScratch::new();
+        self.collect_candidates(ctx, &mut scratch);
+        let candidates = scratch.candidates;
+
+        result.remove_headers = self
+            .collect_removeheaders(&candidates, true)
+            .into_iter()
+            .filter(|name| headers.iter().any(|header| header.name.eq_ignore_ascii_case(name)))
+            .map(str::to_string)
+            .collect();
+
+        if let Some(c) = self.header_rule_block(&candidates, headers, true) {
+            let rules = self.snapshot.rules();
             result.cancel = true;
             result.rule_id = c.rule_id as i32;
             result.list_id = rules.list_id(c.rule_id);
         }
-
         result
     }
 
-    pub fn match_cosmetics(&self, ctx: &RequestContext<'_>) -> CosmeticMatchResult {
-        let mut result = CosmeticMatchResult {
-            css: String::new(),
-            enable_generic: true,
-            scriptlets: Vec::new(),
-            procedural: Vec::new(),
-        };
+    /// Collect the `$replace=` rewrites that apply to a request's response body.
+    pub fn match_replace(&self, ctx: &RequestContext<'_>) -> Vec<ReplaceRule> {
+        if self.is_trusted_site(ctx.site_etld1) {
+            return Vec::new();
+        }
 
-        let mut candidates = Vec::new();
-        self.match_domain_sets(ctx, &mut candidates);
-        self.match_token_rules(ctx, &mut candidates);
+        let mut scratch = MatchScratch::new();
+        self.match_token_rules(ctx, &mut scratch);
+        scratch.dedup_candidates();
+        let candidates = scratch.candidates;
 
         let rules = self.snapshot.rules();
-        let mut elemhide_disabled = false;
-        let mut generichide_disabled = false;
+        let mut replace_candidates: Vec<(usize, ReplaceSpecRef<'a>)> = Vec::new();
+        let mut exceptions: HashSet<(&str, &str)> = HashSet::new();
 
         for candidate in &candidates {
-            if candidate.action != RuleAction::Allow {
+            if candidate.action != RuleAction::ResponseReplace {
                 continue;
             }
-            let flags = RuleFlags::from_bits_truncate(rules.flags(candidate.rule_id));
-            if flags.contains(RuleFlags::ELEMHIDE) {
-                elemhide_disabled = true;
+            let spec = match self.get_replace_spec(rules.option_id(candidate.rule_id)) {
+                Some(spec) => spec,
+                None => continue,
+            };
+            if spec.flags & replace_flags::EXCEPTION != 0 {
+                if spec.pattern.is_empty() {
+                    return Vec::new();
+                }
+                exceptions.insert((spec.pattern, spec.replacement));
+            } else {
+                replace_candidates.push((candidate.rule_id, spec));
             }
-            if flags.contains(RuleFlags::GENERICHIDE) {
-                generichide_disabled = true;
+        }
+
+        let mut seen: HashSet<(&str, &str)> = HashSet::new();
+        let mut result = Vec::new();
+        for (rule_id, spec) in replace_candidates {
+            let key = (spec.pattern, spec.replacement);
+            if exceptions.contains(&key) || !seen.insert(key) {
+                continue;
             }
+            result.push(ReplaceRule {
+                pattern: spec.pattern.to_string(),
+                replacement: spec.replacement.to_string(),
+                flags: replace_flags_string(spec.flags),
+                rule_id: rule_id as i32,
+                list_id: rules.list_id(rule_id),
+            });
         }
 
-        let mut specific_selectors: HashSet<&str> = HashSet::new();
-        let mut generic_selectors: HashSet<&str> = HashSet::new();
-        let mut exception_selectors: HashSet<&str> = HashSet::new();
+        result
+    }
 
-        let section = self.snapshot.cosmetic_rules();
-        if section.len() >= 4 {
-            let count = read_u32_le(section, 0) as usize;
-            for idx in 0..count {
-                let entry_offset = 4 + idx * 16;
-                if entry_offset + 16 > section.len() {
-                    break;
-                }
-                let constraint_offset = read_u32_le(section, entry_offset);
-                if !self.check_domain_constraints_offset(constraint_offset, ctx) {
-                    continue;
-                }
-                let selector_off = read_u32_le(section, entry_offset + 4) as usize;
-                let selector_len = read_u32_le(section, entry_offset + 8) as usize;
-                let flags = read_u16_le(section, entry_offset + 12);
+    pub fn match_cosmetics(&self, ctx: &RequestContext<'_>) -> CosmeticMatchResult {
+        self.match_cosmetics_with_switches(ctx, self.cosmetic_hide_switches(ctx))
+    }
 
-                let selector = match self.snapshot.get_string(selector_off, selector_len) {
-                    Some(value) => value,
+    /// [`Matcher::match_cosmetics`] for a page whose hide exceptions are
+    /// already known. Beyond `switches`, the result only depends on
+    /// `ctx.site_host`, so callers may cache it per site until the matcher
+    /// is reconfigured.
+    pub fn match_cosmetics_with_switches(
+        &self,
+        ctx: &RequestContext<'_>,
+        switches: CosmeticHideSwitches,
+    ) -> CosmeticMatchResult {
+        let mut result = CosmeticMatchResult {
+            css: Vec::new(),
+            style_css: String::new(),
+            enable_generic: true,
+            scriptlets: Vec::new(),
+            procedural: Vec::new(),
+            site_switch: SiteSwitches::empty(),
+        };
+        if self.cosmetics_switched_off(ctx) {
+            result.enable_generic = false;
+            result.site_switch = SiteSwitches::NO_COSMETIC_FILTERING;
+            return result;
+        }
+
+        let (elemhide_disabled, generichide_disabled) = (switches.elemhide, switches.generichide);
+        let specifichide_disabled = switches.specifichide;
+
+        if !elemhide_disabled {
+            let selectors: Vec<&str> = self
+                .applied_hide_rules(ctx, switches)
+                .into_iter()
+                .map(|(_, selector)| selector)
+                .collect();
+            result.css = hide_css(&selectors, self.hide_css_chunk);
+
+            result.style_css = self.match_cosmetic_styles(ctx, !specifichide_disabled, !generichide_disabled);
+        }
+
+        result.enable_generic = !generichide_disabled;
+
+        result.procedural = self
+            .applied_procedural_rules(ctx, switches)
+            .into_iter()
+            .filter_map(|(idx, _)| self.procedural_program(idx))
+            .collect();
+
+        let section = self.snapshot.scriptlet_rules();
+        if section.len() >= 4 {
+            let count = read_u32_le(section, 0) as usize;
+            let mut scriptlet_candidates: HashSet<&str> = HashSet::new();
+            let mut scriptlet_exceptions: HashSet<&str> = HashSet::new();
+            let mut scriptlet_name_exceptions: HashSet<&str> = HashSet::new();
+            let mut scriptlet_disable_all = false;
+
+            for idx in 0..count {
+                let entry_offset = 4 + idx * 16;
+                if entry_offset + 16 > section.len() {
+                    break;
+                }
+                let constraint_offset = read_u32_le(section, entry_offset);
+                if !self.is_list_enabled(read_u16_le(section, entry_offset + 14)) {
+                    continue;
+                }
+                if !self.check_domain_constraints_offset(constraint_offset, ctx) {
+                    continue;
+                }
+                let scriptlet_off = read_u32_le(section, entry_offset + 4) as usize;
+                let scriptlet_len = read_u32_le(section, entry_offset + 8) as usize;
+                let flags = read_u16_le(section, entry_offset + 12);
+
+                let scriptlet_raw = match self.snapshot.get_string(scriptlet_off, scriptlet_len) {
+                    Some(value) => value,
                     None => continue,
                 };
 
                 let is_exception = flags & 1 != 0;
                 let is_generic = flags & (1 << 1) != 0;
+                let is_name_only = flags & (1 << 2) != 0;
+
+                if is_exception && scriptlet_raw.is_empty() {
+                    scriptlet_disable_all = true;
+                    continue;
+                }
+
+                // Like `#@#+js()`, a name-only exception applies even without
+                // a domain.
+                if is_exception && is_name_only {
+                    scriptlet_name_exceptions.insert(scriptlet_raw);
+                    continue;
+                }
+
+                if is_generic {
+                    continue;
+                }
 
                 if is_exception {
-                    exception_selectors.insert(selector);
-                } else if is_generic {
-                    generic_selectors.insert(selector);
+                    scriptlet_exceptions.insert(scriptlet_raw);
                 } else {
-                    specific_selectors.insert(selector);
+                    scriptlet_candidates.insert(scriptlet_raw);
+                }
+            }
+
+            if !scriptlet_disable_all {
+                for scriptlet_raw in scriptlet_candidates {
+                    if scriptlet_exceptions.contains(scriptlet_raw) {
+                        continue;
+                    }
+                    if let Some(call) = parse_scriptlet_call(scriptlet_raw) {
+                        if scriptlet_name_exceptions.contains(call.name.as_str()) {
+                            continue;
+                        }
+                        result.scriptlets.push(call);
+                    }
                 }
             }
         }
 
-        if !elemhide_disabled {
-            let mut selectors: Vec<&str> = Vec::new();
-            for selector in specific_selectors {
-                if !exception_selectors.contains(selector) {
-                    selectors.push(selector);
+        result
+    }
+
+    /// Hide CSS for bucketed generic selectors whose leading `#id` / `.class`
+    /// key hash (`hash_token("#id")`, `hash_token(".class")`) is in `keys`,
+    /// i.e. those the page can actually match.
+    pub fn match_cosmetics_generic(&self, ctx: &RequestContext<'_>, keys: &[u32]) -> String {
+        self.match_cosmetics_generic_with_switches(ctx, keys, self.cosmetic_hide_switches(ctx))
+    }
+
+    /// [`Matcher::match_cosmetics_generic`] for a page whose hide exceptions
+    /// are already known, so repeated calls for one page (a content script
+    /// sends one per DOM mutation batch) skip the network rule lookup.
+    pub fn match_cosmetics_generic_with_switches(
+        &self,
+        ctx: &RequestContext<'_>,
+        keys: &[u32],
+        switches: CosmeticHideSwitches,
+    ) -> String {
+        if self.cosmetics_switched_off(ctx) {
+            return String::new();
+        }
+        let selectors: Vec<&str> = self
+            .generic_hide_rules(ctx, keys, switches)
+            .into_iter()
+            .map(|(_, selector)| selector)
+            .collect();
+        hide_css(&selectors, self.hide_css_chunk).join("\n")
+    }
+
+    /// Selector counts and sizes for the page, split the way the extension
+    /// injects them. Counts follow [`Matcher::match_cosmetics`], with the
+    /// bucketed generics counted as if every key were present.
+    pub fn cosmetics_stats(&self, ctx: &RequestContext<'_>) -> CosmeticStats {
+        let switches = self.cosmetic_hide_switches(ctx);
+        let mut stats = CosmeticStats {
+            enable_generic: !switches.generichide,
+            ..CosmeticStats::default()
+        };
+        if self.cosmetics_switched_off(ctx) {
+            stats.enable_generic = false;
+            return stats;
+        }
+        if switches.elemhide {
+            return stats;
+        }
+
+        let section = self.snapshot.cosmetic_rules();
+        for (idx, selector) in self.applied_hide_rules(ctx, switches) {
+            if read_u16_le(section, 4 + idx * 16 + 12) & (1 << 1) != 0 {
+                stats.generic.add(selector);
+            } else {
+                stats.specific.add(selector);
+            }
+        }
+        if !switches.generichide {
+            for selector in self.bucketed_generic_selectors(ctx) {
+                stats.generic_bucketed.add(selector);
+            }
+        }
+        for (_, selector) in self.applied_procedural_rules(ctx, switches) {
+            stats.procedural.add(selector);
+        }
+        stats
+    }
+
+    /// The rules behind [`Matcher::match_cosmetics`]'s hide CSS, one per
+    /// selector, in snapshot order.
+    pub fn match_cosmetics_detailed(&self, ctx: &RequestContext<'_>) -> Vec<CosmeticRuleMatch> {
+        self.match_cosmetics_detailed_with_switches(ctx, self.cosmetic_hide_switches(ctx))
+    }
+
+    /// [`Matcher::match_cosmetics_detailed`] with the page's switches
+    /// already known.
+    pub fn match_cosmetics_detailed_with_switches(
+        &self,
+        ctx: &RequestContext<'_>,
+        switches: CosmeticHideSwitches,
+    ) -> Vec<CosmeticRuleMatch> {
+        if self.cosmetics_switched_off(ctx) {
+            return Vec::new();
+        }
+        self.cosmetic_rule_matches(self.applied_hide_rules(ctx, switches))
+    }
+
+    /// The rules behind [`Matcher::match_cosmetics_generic_with_switches`]'s
+    /// hide CSS.
+    pub fn match_cosmetics_generic_detailed(
+        &self,
+        ctx: &RequestContext<'_>,
+        keys: &[u32],
+        switches: CosmeticHideSwitches,
+    ) -> Vec<CosmeticRuleMatch> {
+        if self.cosmetics_switched_off(ctx) {
+            return Vec::new();
+        }
+        self.cosmetic_rule_matches(self.generic_hide_rules(ctx, keys, switches))
+    }
+
+    fn cosmetic_rule_matches(&self, rules: Vec<(usize, &str)>) -> Vec<CosmeticRuleMatch> {
+        let section = self.snapshot.cosmetic_rules();
+        let lines = self.snapshot.cosmetic_rule_lines();
+        let line_count = if lines.len() >= 4 { read_u32_le(lines, 0) as usize } else { 0 };
+        rules
+            .into_iter()
+            .map(|(idx, selector)| {
+                let line_offset = 4 + idx * COSMETIC_RULE_LINE_ENTRY_SIZE;
+                CosmeticRuleMatch {
+                    selector: selector.to_string(),
+                    list_id: read_u16_le(section, 4 + idx * 16 + 14),
+                    rule_index: idx as u32,
+                    line: if idx < line_count && line_offset + 4 <= lines.len() {
+                        read_u32_le(lines, line_offset)
+                    } else {
+                        0
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// Plain `##` hides that apply to the page once `#@#` exceptions and
+    /// `$elemhide`-style switches are honoured, as (rule index, selector) in
+    /// snapshot order. When several rules hide the same selector the first
+    /// one is reported. Bucketed generics are left to `generic_hide_rules`.
+    fn applied_hide_rules(&self, ctx: &RequestContext<'_>, switches: CosmeticHideSwitches) -> Vec<(usize, &'a str)> {
+        let section = self.snapshot.cosmetic_rules();
+        if switches.elemhide || section.len() < 4 {
+            return Vec::new();
+        }
+
+        let mut hides: Vec<(usize, &'a str, u32)> = Vec::new();
+        let mut exception_hashes: HashSet<u32> = HashSet::new();
+
+        let count = read_u32_le(section, 0) as usize;
+        for idx in 0..count {
+            let entry_offset = 4 + idx * 16;
+            if entry_offset + 16 > section.len() {
+                break;
+            }
+            let constraint_offset = read_u32_le(section, entry_offset);
+            if !self.is_list_enabled(read_u16_le(section, entry_offset + 14)) {
+                continue;
+            }
+            if !self.check_domain_constraints_offset(constraint_offset, ctx) {
+                continue;
+            }
+            let selector_off = read_u32_le(section, entry_offset + 4) as usize;
+            let selector_len = read_u32_le(section, entry_offset + 8) as usize;
+            let flags = read_u16_le(section, entry_offset + 12);
+
+            let selector = match self.snapshot.get_string(selector_off, selector_len) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let is_exception = flags & 1 != 0;
+            let is_generic = flags & (1 << 1) != 0;
+            // Bucketed generics are served by `match_cosmetics_generic`
+            let is_bucketed = flags & (1 << 2) != 0;
+
+            let hash = self.cosmetic_selector_hash(idx, selector);
+            if is_exception {
+                exception_hashes.insert(hash);
+            } else if is_bucketed || (is_generic && switches.generichide) || (!is_generic && switches.specifichide) {
+                continue;
+            } else {
+                hides.push((idx, selector, hash));
+            }
+        }
+
+        let mut seen: HashSet<&str> = HashSet::new();
+        hides
+            .into_iter()
+            .filter(|&(_, selector, hash)| !exception_hashes.contains(&hash) && seen.insert(selector))
+            .map(|(idx, selector, _)| (idx, selector))
+            .collect()
+    }
+
+    /// Procedural rules that apply to the page once `#@#` exceptions and
+    /// `$elemhide`-style switches are honoured, as (entry index, selector)
+    /// in section order.
+    fn applied_procedural_rules(
+        &self,
+        ctx: &RequestContext<'_>,
+        switches: CosmeticHideSwitches,
+    ) -> Vec<(usize, &'a str)> {
+        if switches.elemhide {
+            return Vec::new();
+        }
+
+        let mut procedural_rules: Vec<(usize, &str)> = Vec::new();
+        let mut procedural_exceptions: HashSet<&str> = HashSet::new();
+
+        let section = self.snapshot.procedural_rules();
+        if section.len() >= 4 {
+            let count = read_u32_le(section, 0) as usize;
+            for idx in 0..count {
+                let entry_offset = 4 + idx * PROCEDURAL_RULE_ENTRY_SIZE;
+                if entry_offset + PROCEDURAL_RULE_ENTRY_SIZE > section.len() {
+                    break;
+                }
+                let constraint_offset = read_u32_le(section, entry_offset + procedural_rule_entry::CONSTRAINT_OFFSET);
+                if !self.is_list_enabled(read_u16_le(section, entry_offset + procedural_rule_entry::LIST_ID)) {
+                    continue;
+                }
+                if !self.check_domain_constraints_offset(constraint_offset, ctx) {
+                    continue;
+                }
+                let selector_off = read_u32_le(section, entry_offset + procedural_rule_entry::SELECTOR_OFF) as usize;
+                let selector_len = read_u32_le(section, entry_offset + procedural_rule_entry::SELECTOR_LEN) as usize;
+                let flags = read_u16_le(section, entry_offset + procedural_rule_entry::FLAGS);
+
+                let selector = match self.snapshot.get_string(selector_off, selector_len) {
+                    Some(value) => value,
+                    None => continue,
+                };
+
+                let is_exception = flags & 1 != 0;
+                let is_generic = flags & (1 << 1) != 0;
+                let switched_off = if is_generic { switches.generichide } else { switches.specifichide };
+
+                if is_exception {
+                    procedural_exceptions.insert(selector);
+                } else if !switched_off {
+                    procedural_rules.push((idx, selector));
                 }
             }
-            if !generichide_disabled {
-                for selector in generic_selectors {
-                    if !exception_selectors.contains(selector) {
-                        selectors.push(selector);
+        }
+
+        let mut seen: HashSet<&str> = HashSet::new();
+        procedural_rules.retain(|&(_, selector)| !procedural_exceptions.contains(selector) && seen.insert(selector));
+        procedural_rules
+    }
+
+    /// Decode the compiled program of the procedural rule at `idx`.
+    fn procedural_program(&self, idx: usize) -> Option<ProceduralProgram> {
+        let section = self.snapshot.procedural_rules();
+        // Snapshots before v5 store the selector text only
+        if self.snapshot.version < 5 || section.len() < 4 {
+            return None;
+        }
+        let count = read_u32_le(section, 0) as usize;
+        let programs_offset = count.checked_mul(PROCEDURAL_RULE_ENTRY_SIZE)?.checked_add(4)?;
+        let ops_offset = programs_offset.checked_add(count.checked_mul(PROCEDURAL_PROGRAM_ENTRY_SIZE)?)?;
+        if idx >= count || ops_offset > section.len() {
+            return None;
+        }
+        let entry_offset = programs_offset + idx * PROCEDURAL_PROGRAM_ENTRY_SIZE;
+
+        let base = self.snapshot.get_string(
+            read_u32_le(section, entry_offset + procedural_program_entry::BASE_OFF) as usize,
+            read_u16_le(section, entry_offset + procedural_program_entry::BASE_LEN) as usize,
+        )?;
+        let op_count = read_u16_le(section, entry_offset + procedural_program_entry::OP_COUNT) as usize;
+        let first_op = read_u32_le(section, entry_offset + procedural_program_entry::FIRST_OP) as usize;
+        let op_table_len = (section.len() - ops_offset) / PROCEDURAL_OP_ENTRY_SIZE;
+        if op_count == 0 || first_op.checked_add(op_count)? > op_table_len {
+            return None;
+        }
+
+        let mut ops = Vec::with_capacity(op_count);
+        for op_idx in first_op..first_op + op_count {
+            let op_offset = ops_offset + op_idx * PROCEDURAL_OP_ENTRY_SIZE;
+            let kind = ProceduralOpKind::try_from(section[op_offset + procedural_op_entry::KIND]).ok()?;
+            let arg = self.snapshot.get_string(
+                read_u32_le(section, op_offset + procedural_op_entry::ARG_OFF) as usize,
+                read_u16_le(section, op_offset + procedural_op_entry::ARG_LEN) as usize,
+            )?;
+            ops.push(ProceduralOp {
+                kind,
+                arg: arg.to_string(),
+            });
+        }
+
+        Some(ProceduralProgram {
+            base: base.to_string(),
+            ops,
+        })
+    }
+
+    /// Bucketed generic hides whose key is in `keys`, as (rule index, selector).
+    fn generic_hide_rules(
+        &self,
+        ctx: &RequestContext<'_>,
+        keys: &[u32],
+        switches: CosmeticHideSwitches,
+    ) -> Vec<(usize, &'a str)> {
+        let buckets = self.snapshot.generic_cosmetic_buckets();
+        let section = self.snapshot.cosmetic_rules();
+        if buckets.len() < 4 || section.len() < 4 || keys.is_empty() {
+            return Vec::new();
+        }
+
+        if switches.elemhide || switches.generichide {
+            return Vec::new();
+        }
+        let bucket_count =
+            (read_u32_le(buckets, 0) as usize).min((buckets.len() - 4) / GENERIC_COSMETIC_BUCKET_ENTRY_SIZE);
+        let rule_count = read_u32_le(section, 0) as usize;
+        let key_at = |idx: usize| {
+            read_u32_le(buckets, 4 + idx * GENERIC_COSMETIC_BUCKET_ENTRY_SIZE + generic_cosmetic_bucket_entry::KEY_HASH)
+        };
+
+        let mut selectors: Vec<(usize, &'a str, u32)> = Vec::new();
+        let mut seen: HashSet<&str> = HashSet::new();
+        for &key in keys {
+            // Lower bound: entries are sorted by key hash
+            let (mut idx, mut hi) = (0, bucket_count);
+            while idx < hi {
+                let mid = idx + (hi - idx) / 2;
+                if key_at(mid) < key {
+                    idx = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+            while idx < bucket_count && key_at(idx) == key {
+                let entry = 4 + idx * GENERIC_COSMETIC_BUCKET_ENTRY_SIZE;
+                idx += 1;
+                let rule_index = read_u32_le(buckets, entry + generic_cosmetic_bucket_entry::RULE_INDEX) as usize;
+                let entry_offset = 4 + rule_index * 16;
+                if rule_index >= rule_count || entry_offset + 16 > section.len() {
+                    continue;
+                }
+                if !self.is_list_enabled(read_u16_le(section, entry_offset + 14)) {
+                    continue;
+                }
+                if !self.check_domain_constraints_offset(read_u32_le(section, entry_offset), ctx) {
+                    continue;
+                }
+                let selector_off = read_u32_le(section, entry_offset + 4) as usize;
+                let selector_len = read_u32_le(section, entry_offset + 8) as usize;
+                if let Some(selector) = self.snapshot.get_string(selector_off, selector_len) {
+                    if seen.insert(selector) {
+                        selectors.push((rule_index, selector, self.cosmetic_selector_hash(rule_index, selector)));
                     }
                 }
             }
+        }
+        if selectors.is_empty() {
+            return Vec::new();
+        }
+
+        let exceptions = self.cosmetic_exception_hashes(ctx);
+        selectors
+            .into_iter()
+            .filter(|(_, _, hash)| !exceptions.contains(hash))
+            .map(|(idx, selector, _)| (idx, selector))
+            .collect()
+    }
 
-            if !selectors.is_empty() {
-                result.css = format!("{}{{display:none !important;}}", selectors.join(",\n"));
+    /// Every distinct bucketed generic hide that applies to the page,
+    /// regardless of key, minus `#@#` exceptions.
+    fn bucketed_generic_selectors(&self, ctx: &RequestContext<'_>) -> Vec<&'a str> {
+        let section = self.snapshot.cosmetic_rules();
+        if section.len() < 4 {
+            return Vec::new();
+        }
+
+        let exceptions = self.cosmetic_exception_hashes(ctx);
+        let mut seen: HashSet<&str> = HashSet::new();
+        let mut selectors = Vec::new();
+        let count = read_u32_le(section, 0) as usize;
+        for idx in 0..count {
+            let entry_offset = 4 + idx * 16;
+            if entry_offset + 16 > section.len() {
+                break;
+            }
+            let flags = read_u16_le(section, entry_offset + 12);
+            if flags & 1 != 0 || flags & (1 << 2) == 0 {
+                continue;
+            }
+            if !self.is_list_enabled(read_u16_le(section, entry_offset + 14)) {
+                continue;
+            }
+            if !self.check_domain_constraints_offset(read_u32_le(section, entry_offset), ctx) {
+                continue;
+            }
+            let selector_off = read_u32_le(section, entry_offset + 4) as usize;
+            let selector_len = read_u32_le(section, entry_offset + 8) as usize;
+            if let Some(selector) = self.snapshot.get_string(selector_off, selector_len) {
+                if !exceptions.contains(&self.cosmetic_selector_hash(idx, selector)) && seen.insert(selector) {
+                    selectors.push(selector);
+                }
             }
         }
+        selectors
+    }
 
-        result.enable_generic = !generichide_disabled;
+    /// The `$elemhide`, `$generichide` and `$specifichide` exceptions that
+    /// apply to the page `ctx` describes.
+    pub fn cosmetic_hide_switches(&self, ctx: &RequestContext<'_>) -> CosmeticHideSwitches {
+        let mut scratch = MatchScratch::new();
+        self.collect_candidates(ctx, &mut scratch);
 
-        if !elemhide_disabled {
-            let mut procedural_specific: HashSet<&str> = HashSet::new();
-            let mut procedural_generic: HashSet<&str> = HashSet::new();
-            let mut procedural_exceptions: HashSet<&str> = HashSet::new();
+        let rules = self.snapshot.rules();
+        let mut switches = CosmeticHideSwitches::default();
 
-            let section = self.snapshot.procedural_rules();
-            if section.len() >= 4 {
-                let count = read_u32_le(section, 0) as usize;
-                for idx in 0..count {
-                    let entry_offset = 4 + idx * 16;
-                    if entry_offset + 16 > section.len() {
-                        break;
-                    }
-                    let constraint_offset = read_u32_le(section, entry_offset);
-                    if !self.check_domain_constraints_offset(constraint_offset, ctx) {
-                        continue;
-                    }
-                    let selector_off = read_u32_le(section, entry_offset + 4) as usize;
-                    let selector_len = read_u32_le(section, entry_offset + 8) as usize;
-                    let flags = read_u16_le(section, entry_offset + 12);
+        for candidate in &scratch.candidates {
+            if candidate.action != RuleAction::Allow {
+                continue;
+            }
+            let flags = RuleFlags::from_bits_truncate(rules.flags(candidate.rule_id));
+            switches.elemhide |= flags.contains(RuleFlags::ELEMHIDE);
+            switches.generichide |= flags.contains(RuleFlags::GENERICHIDE);
+            switches.specifichide |= flags.contains(RuleFlags::SPECIFICHIDE);
+        }
+        switches
+    }
 
-                    let selector = match self.snapshot.get_string(selector_off, selector_len) {
-                        Some(value) => value,
-                        None => continue,
-                    };
+    /// Selector hash of cosmetic rule `idx`. Snapshots built before the
+    /// CosmeticSelectorHashes section existed fall back to hashing the text.
+    fn cosmetic_selector_hash(&self, idx: usize, selector: &str) -> u32 {
+        let section = self.snapshot.cosmetic_selector_hashes();
+        let offset = 4 + idx * COSMETIC_SELECTOR_HASH_ENTRY_SIZE;
+        if section.len() >= 4 && idx < read_u32_le(section, 0) as usize && offset + 4 <= section.len() {
+            read_u32_le(section, offset)
+        } else {
+            hash_token(selector)
+        }
+    }
 
-                    let is_exception = flags & 1 != 0;
-                    let is_generic = flags & (1 << 1) != 0;
+    /// `selectors` minus the ones a `#@#` exception on the page covers, for
+    /// hides that don't come from the snapshot, such as user styles.
+    pub fn unexcepted_selectors<'s>(&self, ctx: &RequestContext<'_>, selectors: &[&'s str]) -> Vec<&'s str> {
+        if selectors.is_empty() {
+            return Vec::new();
+        }
+        let exceptions = self.cosmetic_exception_hashes(ctx);
+        selectors
+            .iter()
+            .copied()
+            .filter(|selector| !exceptions.contains(&hash_token(selector)))
+            .collect()
+    }
 
-                    if is_exception {
-                        procedural_exceptions.insert(selector);
-                    } else if is_generic {
-                        procedural_generic.insert(selector);
-                    } else {
-                        procedural_specific.insert(selector);
-                    }
+    /// Selector hashes of the `#@#` exceptions that apply to the page.
+    fn cosmetic_exception_hashes(&self, ctx: &RequestContext<'_>) -> HashSet<u32> {
+        let mut exceptions = HashSet::new();
+        let section = self.snapshot.cosmetic_rules();
+        if section.len() < 4 {
+            return exceptions;
+        }
+        let count = read_u32_le(section, 0) as usize;
+        for idx in 0..count {
+            let entry_offset = 4 + idx * 16;
+            if entry_offset + 16 > section.len() {
+                break;
+            }
+            if read_u16_le(section, entry_offset + 12) & 1 == 0 {
+                continue;
+            }
+            if !self.is_list_enabled(read_u16_le(section, entry_offset + 14)) {
+                continue;
+            }
+            if !self.check_domain_constraints_offset(read_u32_le(section, entry_offset), ctx) {
+                continue;
+            }
+            let selector_off = read_u32_le(section, entry_offset + 4) as usize;
+            let selector_len = read_u32_le(section, entry_offset + 8) as usize;
+            if let Some(selector) = self.snapshot.get_string(selector_off, selector_len) {
+                exceptions.insert(self.cosmetic_selector_hash(idx, selector));
+            }
+        }
+        exceptions
+    }
+
+    /// Build the stylesheet for `:style()` rules, one block per distinct
+    /// declaration list. Exceptions must name both selector and style.
+    fn match_cosmetic_styles(&self, ctx: &RequestContext<'_>, include_specific: bool, include_generic: bool) -> String {
+        let section = self.snapshot.cosmetic_style_rules();
+        if section.len() < 4 {
+            return String::new();
+        }
+
+        let mut candidates: Vec<(&str, &str)> = Vec::new();
+        let mut exceptions: HashSet<(&str, &str)> = HashSet::new();
+
+        let count = read_u32_le(section, 0) as usize;
+        for idx in 0..count {
+            let entry_offset = 4 + idx * COSMETIC_STYLE_ENTRY_SIZE;
+            if entry_offset + COSMETIC_STYLE_ENTRY_SIZE > section.len() {
+                break;
+            }
+            let constraint_offset = read_u32_le(section, entry_offset + cosmetic_style_entry::CONSTRAINT_OFFSET);
+            if !self.is_list_enabled(read_u16_le(section, entry_offset + cosmetic_style_entry::LIST_ID)) {
+                continue;
+            }
+            if !self.check_domain_constraints_offset(constraint_offset, ctx) {
+                continue;
+            }
+            let flags = read_u16_le(section, entry_offset + cosmetic_style_entry::FLAGS);
+            let is_exception = flags & 1 != 0;
+            let is_generic = flags & (1 << 1) != 0;
+            if !is_exception && !(if is_generic { include_generic } else { include_specific }) {
+                continue;
+            }
+
+            let selector = self.snapshot.get_string(
+                read_u32_le(section, entry_offset + cosmetic_style_entry::SELECTOR_OFF) as usize,
+                read_u32_le(section, entry_offset + cosmetic_style_entry::SELECTOR_LEN) as usize,
+            );
+            let style = self.snapshot.get_string(
+                read_u32_le(section, entry_offset + cosmetic_style_entry::STYLE_OFF) as usize,
+                read_u32_le(section, entry_offset + cosmetic_style_entry::STYLE_LEN) as usize,
+            );
+            let (Some(selector), Some(style)) = (selector, style) else {
+                continue;
+            };
+
+            if is_exception {
+                exceptions.insert((selector, style));
+            } else {
+                candidates.push((selector, style));
+            }
+        }
+
+        let mut by_style: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (selector, style) in candidates {
+            if exceptions.contains(&(selector, style)) {
+                continue;
+            }
+            let selectors = by_style.entry(style).or_default();
+            if !selectors.contains(&selector) {
+                selectors.push(selector);
+            }
+        }
+
+        let mut css = String::new();
+        for (style, selectors) in by_style {
+            if !css.is_empty() {
+                css.push('\n');
+            }
+            css.push_str(&selectors.join(",\n"));
+            css.push('{');
+            css.push_str(style);
+            css.push('}');
+        }
+        css
+    }
+
+    /// The `$header=` block rule that cancels an exchange, checked against
+    /// one direction's headers. Precedence mirrors network rules: an
+    /// `$important` block beats exceptions, which beat plain blocks.
+    fn header_rule_block<'c>(
+        &self,
+        candidates: &'c [MatchCandidate],
+        headers: &[ResponseHeader<'_>],
+        request: bool,
+    ) -> Option<&'c MatchCandidate> {
+        let rules = self.snapshot.rules();
+        let mut best_important_block: Option<&MatchCandidate> = None;
+        let mut best_allow: Option<&MatchCandidate> = None;
+        let mut best_block: Option<&MatchCandidate> = None;
+
+        for candidate in candidates {
+            if !matches!(candidate.action, RuleAction::HeaderMatchBlock | RuleAction::HeaderMatchAllow) {
+                continue;
+            }
+            let spec = match self.get_header_spec(rules.option_id(candidate.rule_id)) {
+                Some(spec) => spec,
+                None => continue,
+            };
+            if spec.request != request || !header_matches(&spec, headers) {
+                continue;
+            }
+            if candidate.action == RuleAction::HeaderMatchAllow {
+                if best_allow.is_none_or(|b| candidate.priority > b.priority) {
+                    best_allow = Some(candidate);
                 }
+                continue;
             }
 
-            let mut selectors: Vec<&str> = Vec::new();
-            for selector in procedural_specific {
-                if !procedural_exceptions.contains(selector) {
-                    selectors.push(selector);
+            let flags = RuleFlags::from_bits_truncate(rules.flags(candidate.rule_id));
+            if flags.contains(RuleFlags::IMPORTANT) {
+                if best_important_block.is_none_or(|b| candidate.priority > b.priority) {
+                    best_important_block = Some(candidate);
                 }
+            } else if best_block.is_none_or(|b| candidate.priority > b.priority) {
+                best_block = Some(candidate);
             }
-            if !generichide_disabled {
-                for selector in procedural_generic {
-                    if !procedural_exceptions.contains(selector) {
-                        selectors.push(selector);
-                    }
+        }
+
+        if best_important_block.is_some() {
+            return best_important_block;
+        }
+        if best_allow.is_some() {
+            return None;
+        }
+        best_block
+    }
+
+    /// Header names from matched `$removeheader` rules for one direction,
+    /// minus exceptions. A bare `@@...$removeheader` disables all of them.
+    fn collect_removeheaders(&self, candidates: &[MatchCandidate], request: bool) -> Vec<&'a str> {
+        let rules = self.snapshot.rules();
+        let mut removals: Vec<&str> = Vec::new();
+        let mut exceptions: HashSet<&str> = HashSet::new();
+        let mut disabled = false;
+
+        for candidate in candidates {
+            if candidate.action != RuleAction::RemoveHeader {
+                continue;
+            }
+            let spec = match self.get_removeheader_spec(rules.option_id(candidate.rule_id)) {
+                Some(spec) => spec,
+                None => continue,
+            };
+            let flags = RuleFlags::from_bits_truncate(rules.flags(candidate.rule_id));
+            if flags.contains(RuleFlags::REMOVEHEADER_EXCEPTION) {
+                if spec.name.is_empty() {
+                    disabled = true;
+                } else if spec.request == request {
+                    exceptions.insert(spec.name);
                 }
+                continue;
             }
-
-            for selector in selectors {
-                result.procedural.push(selector.to_string());
+            if spec.request == request && is_removable_header(spec.name) && !removals.contains(&spec.name) {
+                removals.push(spec.name);
             }
         }
 
-        let section = self.snapshot.scriptlet_rules();
-        if section.len() >= 4 {
-            let count = read_u32_le(section, 0) as usize;
-            let mut scriptlet_candidates: HashSet<&str> = HashSet::new();
-            let mut scriptlet_exceptions: HashSet<&str> = HashSet::new();
-            let mut scriptlet_disable_all = false;
+        if disabled {
+            return Vec::new();
+        }
+        removals.retain(|name| !exceptions.contains(name));
+        removals
+    }
 
-            for idx in 0..count {
-                let entry_offset = 4 + idx * 16;
-                if entry_offset + 16 > section.len() {
-                    break;
-                }
-                let constraint_offset = read_u32_le(section, entry_offset);
-                if !self.check_domain_constraints_offset(constraint_offset, ctx) {
-                    continue;
-                }
-                let scriptlet_off = read_u32_le(section, entry_offset + 4) as usize;
-                let scriptlet_len = read_u32_le(section, entry_offset + 8) as usize;
-                let flags = read_u16_le(section, entry_offset + 12);
+    /// Match against static filters. `scratch` already holds the token and
+    /// regex candidates.
+    fn match_static_filters(&self, ctx: &RequestContext<'_>, scratch: &mut MatchScratch) -> MatchResult {
+        // Steps 1-2: Add the domain sets (host-only rules) to the
+        // token-indexed URL rules
+        self.match_domain_sets(ctx, scratch);
+        scratch.dedup_candidates();
+
+        // Step 3: `$genericblock` on the page turns off generic block rules
+        if self.has_genericblock
+            && scratch.candidates.iter().any(|c| self.is_generic_block(c))
+            && self.genericblock_applies(ctx, scratch)
+        {
+            scratch.candidates.retain(|c| !self.is_generic_block(c));
+        }
 
-                let scriptlet_raw = match self.snapshot.get_string(scriptlet_off, scriptlet_len) {
-                    Some(value) => value,
-                    None => continue,
-                };
+        // Step 4: Apply precedence logic
+        self.apply_precedence(&scratch.candidates)
+    }
 
-                let is_exception = flags & 1 != 0;
-                let is_generic = flags & (1 << 1) != 0;
+    fn is_generic_block(&self, candidate: &MatchCandidate) -> bool {
+        candidate.action == RuleAction::Block
+            && RuleFlags::from_bits_truncate(self.snapshot.rules().flags(candidate.rule_id)).contains(RuleFlags::IS_GENERIC)
+    }
 
-                if is_exception && scriptlet_raw.is_empty() {
-                    scriptlet_disable_all = true;
-                    continue;
-                }
+    /// Whether a `$genericblock` exception matches the page making the
+    /// request. Only the site host is known here, so the page is matched as
+    /// `https://<site_host>/`, against the exceptions filed under that host:
+    /// `||host^` domain-set entries and `||host/...` host-anchored rules.
+    /// The lookups are charged to the request's budget.
+    fn genericblock_applies(&self, ctx: &RequestContext<'_>, scratch: &mut MatchScratch) -> bool {
+        let mut url = core::mem::take(&mut scratch.page_url);
+        url.clear();
+        url.push_str("https://");
+        url.push_str(ctx.site_host);
+        url.push('/');
+        let page = RequestContext {
+            url: &url,
+            req_host: ctx.site_host,
+            req_etld1: ctx.site_etld1,
+            site_host: ctx.site_host,
+            site_etld1: ctx.site_etld1,
+            is_third_party: false,
+            request_type: RequestType::MAIN_FRAME,
+            scheme: SchemeMask::HTTPS,
+            ..*ctx
+        };
+        let applies = self.match_genericblock_exceptions(&page, scratch);
+        scratch.page_url = url;
+        applies
+    }
 
-                if is_generic {
-                    continue;
-                }
+    fn match_genericblock_exceptions(&self, page: &RequestContext<'_>, scratch: &mut MatchScratch) -> bool {
+        let allow_set = self.snapshot.domain_allow_set();
+        let domain_postings = self.snapshot.domain_postings();
+        let host_anchored = self.snapshot.host_anchored_rules();
+        let host_postings = self.snapshot.host_anchored_postings();
+        let bucket = posting_bucket_for(page.request_type.bits());
 
-                if is_exception {
-                    scriptlet_exceptions.insert(scriptlet_raw);
-                } else {
-                    scriptlet_candidates.insert(scriptlet_raw);
+        for suffix in walk_host_suffixes(page.req_host) {
+            let hash = hash_domain(suffix);
+            if let Some(value) = allow_set.lookup(hash) {
+                match domain_postings {
+                    Some(data) => decode_posting_list_with_count_into(data, value as usize, &mut scratch.postings),
+                    None => {
+                        scratch.postings.clear();
+                        scratch.postings.push(value);
+                    }
                 }
-            }
-
-            if !scriptlet_disable_all {
-                for scriptlet_raw in scriptlet_candidates {
-                    if scriptlet_exceptions.contains(scriptlet_raw) {
-                        continue;
+                for &rule_id in &scratch.postings {
+                    if self.is_genericblock_exception(rule_id as usize, page, &mut scratch.work) {
+                        return true;
                     }
-                    if let Some(call) = parse_scriptlet_call(scriptlet_raw) {
-                        result.scriptlets.push(call);
+                }
+            }
+            if host_anchored.capacity() != 0 {
+                if let Some(offset) = host_anchored.lookup(hash) {
+                    scratch.postings.clear();
+                    append_bucketed_postings(host_postings, offset as usize, bucket, &mut scratch.postings);
+                    for &rule_id in &scratch.postings {
+                        if self.is_genericblock_exception(rule_id as usize, page, &mut scratch.work) {
+                            return true;
+                        }
                     }
                 }
             }
         }
-
-        result
+        false
     }
 
-
-    /// Match against static filters.
-    fn match_static_filters(&self, ctx: &RequestContext<'_>) -> MatchResult {
-        let mut candidates = Vec::new();
-
-        // Step 1: Check domain sets (host-only rules)
-        self.match_domain_sets(ctx, &mut candidates);
-
-        // Step 2: Check token-indexed URL rules
-        self.match_token_rules(ctx, &mut candidates);
-
-        // Step 3: Apply precedence logic
-        self.apply_precedence(&candidates)
+    fn is_genericblock_exception(&self, rule_id: usize, page: &RequestContext<'_>, work: &mut MatchWork) -> bool {
+        if !work.charge_candidate() {
+            return false;
+        }
+        let rules = self.snapshot.rules();
+        if RuleAction::try_from(rules.action(rule_id)).ok() != Some(RuleAction::Allow)
+            || !RuleFlags::from_bits_truncate(rules.flags(rule_id)).contains(RuleFlags::GENERICBLOCK)
+            || !self.check_rule_options(rule_id, page)
+            || !self.check_domain_constraints(rule_id, page)
+        {
+            return false;
+        }
+        let pattern_id = rules.pattern_id(rule_id);
+        if pattern_id == NO_PATTERN {
+            return true;
+        }
+        let pattern_pool = self.snapshot.pattern_pool();
+        match pattern_pool.get_pattern(pattern_id as usize) {
+            Some(pattern) => self.verify_pattern(page.url, &pattern, pattern_pool.get_program(&pattern), work),
+            None => true,
+        }
     }
 
-    fn match_removeparam(&self, ctx: &RequestContext<'_>) -> Option<MatchResult> {
-        let mut candidates = Vec::new();
-        self.match_token_rules(ctx, &mut candidates);
-
+    fn match_removeparam(&self, ctx: &RequestContext<'_>, candidates: &[MatchCandidate]) -> Option<MatchResult> {
         if candidates.is_empty() {
             return None;
         }
@@ -527,10 +1880,7 @@ impl<'a> Matcher<'a> {
             return None;
         }
 
-        let new_url = match remove_params(ctx.url, &remove_keys) {
-            Some(url) => url,
-            None => return None,
-        };
+        let new_url = remove_params(ctx.url, &remove_keys)?;
 
         let rule_id = selected_rule?;
 
@@ -539,11 +1889,21 @@ impl<'a> Matcher<'a> {
             rule_id: rule_id as i32,
             list_id: rules.list_id(rule_id),
             redirect_url: Some(new_url),
+            budget_exceeded: false,
+            site_switch: SiteSwitches::empty(),
         })
     }
 
+    /// Every network rule matching the request, from both the domain sets
+    /// and the token index, each rule once.
+    fn collect_candidates(&self, ctx: &RequestContext<'_>, scratch: &mut MatchScratch) {
+        self.match_domain_sets(ctx, scratch);
+        self.match_token_rules(ctx, scratch);
+        scratch.dedup_candidates();
+    }
+
     /// Match against domain hash sets.
-    fn match_domain_sets(&self, ctx: &RequestContext<'_>, candidates: &mut Vec<MatchCandidate>) {
+    fn match_domain_sets(&self, ctx: &RequestContext<'_>, scratch: &mut MatchScratch) {
         let allow_set = self.snapshot.domain_allow_set();
         let block_set = self.snapshot.domain_block_set();
         let postings = self.snapshot.domain_postings();
@@ -553,7 +1913,7 @@ impl<'a> Matcher<'a> {
 
         // Walk suffixes from most specific to least
         for suffix in walk_host_suffixes(ctx.req_host) {
-            let hash = hash_domain(&suffix);
+            let hash = hash_domain(suffix);
 
             // Check allow set
             if let Some(value) = allow_set.lookup(hash) {
@@ -561,24 +1921,24 @@ impl<'a> Matcher<'a> {
                     let rule_id = value as usize;
                     if self.check_rule_options(rule_id, ctx) && self.check_domain_constraints(rule_id, ctx) {
                         let flags = RuleFlags::from_bits_truncate(rules.flags(rule_id));
-                        candidates.push(MatchCandidate {
+                        scratch.candidates.push(MatchCandidate {
                             rule_id,
                             action: RuleAction::Allow,
                             is_important: flags.contains(RuleFlags::IMPORTANT),
-                            priority: 0,
+                            priority: rules.priority(rule_id),
                         });
                     }
                 } else {
-                    let rule_ids = decode_posting_list_with_count(postings_data, value as usize);
-                    for rule_id in rule_ids {
+                    decode_posting_list_with_count_into(postings_data, value as usize, &mut scratch.postings);
+                    for &rule_id in &scratch.postings {
                         let rule_id = rule_id as usize;
                         if self.check_rule_options(rule_id, ctx) && self.check_domain_constraints(rule_id, ctx) {
                             let flags = RuleFlags::from_bits_truncate(rules.flags(rule_id));
-                            candidates.push(MatchCandidate {
+                            scratch.candidates.push(MatchCandidate {
                                 rule_id,
                                 action: RuleAction::Allow,
                                 is_important: flags.contains(RuleFlags::IMPORTANT),
-                                priority: 0,
+                                priority: rules.priority(rule_id),
                             });
                         }
                     }
@@ -591,24 +1951,24 @@ impl<'a> Matcher<'a> {
                     let rule_id = value as usize;
                     if self.check_rule_options(rule_id, ctx) && self.check_domain_constraints(rule_id, ctx) {
                         let flags = RuleFlags::from_bits_truncate(rules.flags(rule_id));
-                        candidates.push(MatchCandidate {
+                        scratch.candidates.push(MatchCandidate {
                             rule_id,
                             action: RuleAction::Block,
                             is_important: flags.contains(RuleFlags::IMPORTANT),
-                            priority: 0,
+                            priority: rules.priority(rule_id),
                         });
                     }
                 } else {
-                    let rule_ids = decode_posting_list_with_count(postings_data, value as usize);
-                    for rule_id in rule_ids {
+                    decode_posting_list_with_count_into(postings_data, value as usize, &mut scratch.postings);
+                    for &rule_id in &scratch.postings {
                         let rule_id = rule_id as usize;
                         if self.check_rule_options(rule_id, ctx) && self.check_domain_constraints(rule_id, ctx) {
                             let flags = RuleFlags::from_bits_truncate(rules.flags(rule_id));
-                            candidates.push(MatchCandidate {
+                            scratch.candidates.push(MatchCandidate {
                                 rule_id,
                                 action: RuleAction::Block,
                                 is_important: flags.contains(RuleFlags::IMPORTANT),
-                                priority: 0,
+                                priority: rules.priority(rule_id),
                             });
                         }
                     }
@@ -618,42 +1978,52 @@ impl<'a> Matcher<'a> {
     }
 
     /// Match against token-indexed URL pattern rules.
-    fn match_token_rules(&self, ctx: &RequestContext<'_>, candidates: &mut Vec<MatchCandidate>) {
+    fn match_token_rules(&self, ctx: &RequestContext<'_>, scratch: &mut MatchScratch) {
+        // Regex filters have no tokens, so they ride along with every lookup.
+        self.match_regex_rules(ctx, &mut scratch.candidates, &mut scratch.work);
+
         let token_dict = self.snapshot.token_dict();
-        let postings = self.snapshot.token_postings();
         let rules = self.snapshot.rules();
         let pattern_pool = self.snapshot.pattern_pool();
 
-        // Tokenize the URL
-        let token_hashes = tokenize_url(ctx.url);
-        if token_hashes.is_empty() {
-            return;
+        // Tokenize the URL. Each rule is posted under one of its tokens, so
+        // every distinct URL token has to be looked up.
+        tokenize_url_into(ctx.url, &mut scratch.tokens);
+        scratch.tokens.sort_unstable();
+        scratch.tokens.dedup();
+
+        // The bloom filter rejects most tokens that no rule uses without
+        // probing the dict; the type buckets skip postings for rules that
+        // cannot match this request type.
+        let bloom = self.snapshot.token_bloom();
+        let bucket = posting_bucket_for(ctx.request_type.bits());
+        scratch.postings.clear();
+        for &hash in &scratch.tokens {
+            if !bloom.may_contain(hash) {
+                continue;
+            }
+            if let Some(entry) = token_dict.lookup(hash) {
+                self.snapshot.append_token_postings(&entry, bucket, &mut scratch.postings);
+            }
         }
 
-        // Find the rarest token to minimize candidate set
-        let mut best_entry = None;
-        let mut best_count = usize::MAX;
-
-        for &hash in &token_hashes {
-            if let Some(entry) = token_dict.lookup(hash) {
-                if entry.rule_count < best_count {
-                    best_entry = Some(entry);
-                    best_count = entry.rule_count;
+        // `||host/path` rules are filed under their host rather than a token.
+        let host_anchored = self.snapshot.host_anchored_rules();
+        if host_anchored.capacity() != 0 {
+            let host_postings = self.snapshot.host_anchored_postings();
+            for suffix in walk_host_suffixes(ctx.req_host) {
+                if let Some(offset) = host_anchored.lookup(hash_domain(suffix)) {
+                    append_bucketed_postings(host_postings, offset as usize, bucket, &mut scratch.postings);
                 }
             }
         }
 
-        let entry = match best_entry {
-            Some(e) => e,
-            None => return,
-        };
-
-        // Decode the posting list
-        let rule_ids = decode_posting_list(postings, entry.postings_offset, entry.rule_count);
-
         // Verify each candidate
-        for rule_id in rule_ids {
+        for &rule_id in &scratch.postings {
             let rule_id = rule_id as usize;
+            if !scratch.work.charge_candidate() {
+                break;
+            }
 
             // Quick option checks first
             if !self.check_rule_options(rule_id, ctx) {
@@ -670,7 +2040,7 @@ impl<'a> Matcher<'a> {
             if pattern_id != NO_PATTERN {
                 if let Some(pattern) = pattern_pool.get_pattern(pattern_id as usize) {
                     let program = pattern_pool.get_program(&pattern);
-                    if !self.verify_pattern(ctx.url, &pattern, program) {
+                    if !self.verify_pattern(ctx.url, &pattern, program, &mut scratch.work) {
                         continue;
                     }
                 }
@@ -681,7 +2051,7 @@ impl<'a> Matcher<'a> {
             let flags = RuleFlags::from_bits_truncate(rules.flags(rule_id));
             let priority = rules.priority(rule_id);
 
-            candidates.push(MatchCandidate {
+            scratch.candidates.push(MatchCandidate {
                 rule_id,
                 action,
                 is_important: flags.contains(RuleFlags::IMPORTANT),
@@ -690,14 +2060,64 @@ impl<'a> Matcher<'a> {
         }
     }
 
+    /// Try every regex filter; the option and domain checks run first since
+    /// they are much cheaper than the regex.
+    #[cfg(feature = "regex")]
+    fn match_regex_rules(&self, ctx: &RequestContext<'_>, candidates: &mut Vec<MatchCandidate>, work: &mut MatchWork) {
+        let rules = self.snapshot.rules();
+        for regex_rule in &self.regex_rules {
+            let rule_id = regex_rule.rule_id;
+            if !work.charge_candidate() {
+                break;
+            }
+            if !self.check_rule_options(rule_id, ctx) || !self.check_domain_constraints(rule_id, ctx) {
+                continue;
+            }
+            if !regex_rule.regex.is_match(ctx.url) {
+                continue;
+            }
+
+            let flags = RuleFlags::from_bits_truncate(rules.flags(rule_id));
+            candidates.push(MatchCandidate {
+                rule_id,
+                action: RuleAction::try_from(rules.action(rule_id)).unwrap_or(RuleAction::Block),
+                is_important: flags.contains(RuleFlags::IMPORTANT),
+                priority: rules.priority(rule_id),
+            });
+        }
+    }
+
+    #[cfg(not(feature = "regex"))]
+    fn match_regex_rules(&self, _ctx: &RequestContext<'_>, _candidates: &mut Vec<MatchCandidate>, _work: &mut MatchWork) {}
+
     /// Check if a rule's options match the request context.
+    #[inline]
     fn check_rule_options(&self, rule_id: usize, ctx: &RequestContext<'_>) -> bool {
+        self.rule_option_rejection(rule_id, ctx).is_none()
+    }
+
+    /// The first option check a rule fails for this request, if any.
+    #[inline]
+    fn rule_option_rejection(&self, rule_id: usize, ctx: &RequestContext<'_>) -> Option<Rejection> {
         let rules = self.snapshot.rules();
 
-        // Type mask
-        let type_mask = rules.type_mask(rule_id);
-        if type_mask != 0 && (type_mask & ctx.request_type.bits()) == 0 {
-            return false;
+        if !self.is_rule_enabled(rule_id) {
+            return Some(Rejection::RuleDisabled);
+        }
+        if id_bitset_contains(&self.expired_rules, rule_id) {
+            return Some(Rejection::Expired);
+        }
+        if !self.is_list_enabled(rules.list_id(rule_id)) {
+            return Some(Rejection::ListDisabled);
+        }
+
+        // Type mask; rules without type options don't apply to popups
+        let type_mask = match rules.type_mask(rule_id) {
+            0 => RequestType::ALL.bits(),
+            type_mask => type_mask,
+        };
+        if (type_mask & ctx.request_type.bits()) == 0 {
+            return Some(Rejection::RequestType);
         }
 
         // Party mask
@@ -709,17 +2129,20 @@ impl<'a> Matcher<'a> {
                 PartyMask::FIRST_PARTY
             };
             if (party_mask & request_party.bits()) == 0 {
-                return false;
+                return Some(Rejection::Party);
             }
         }
 
-        // Scheme mask
-        let scheme_mask = rules.scheme_mask(rule_id);
-        if scheme_mask != 0 && (scheme_mask & ctx.scheme.bits()) == 0 {
-            return false;
+        // Scheme mask; rules without scheme options only see web schemes
+        let scheme_mask = match rules.scheme_mask(rule_id) {
+            0 => SchemeMask::WEB.bits(),
+            scheme_mask => scheme_mask,
+        };
+        if (scheme_mask & ctx.scheme.bits()) == 0 {
+            return Some(Rejection::Scheme);
         }
 
-        true
+        None
     }
 
     /// Check domain constraints ($domain=).
@@ -765,30 +2188,56 @@ impl<'a> Matcher<'a> {
             false
         };
 
-        if include_count > 0 {
-            let mut matched = false;
-            for suffix in walk_host_suffixes(ctx.site_host) {
-                let hash = hash_domain(&suffix);
-                if list_contains(include_slice, hash.lo, hash.hi) {
-                    matched = true;
-                    break;
-                }
-            }
-            if !matched {
-                return false;
+        // Length of the longest suffix of the site host the list names, so
+        // the most specific entry decides. `example.*` entries are matched
+        // against the site with its public suffix cut off, and count as the
+        // host suffix they stand for. Both walks go most specific first.
+        let entity = site_entity(ctx);
+        let longest_match = |list: &[u8]| -> Option<usize> {
+            if list.is_empty() {
+                return None;
             }
-        }
+            let host_match = walk_host_suffixes(ctx.site_host)
+                .find(|suffix| {
+                    let hash = hash_domain(suffix);
+                    list_contains(list, hash.lo, hash.hi)
+                })
+                .map(str::len);
+            let entity_match = core::iter::successors(entity, |name| name.split_once('.').map(|(_, parent)| parent))
+                .find(|name| {
+                    let hash = hash_wildcard_domain(name);
+                    list_contains(list, hash.lo, hash.hi)
+                })
+                .map(|name| ctx.site_host.len() - (entity.map_or(0, str::len) - name.len()));
+            host_match.max(entity_match)
+        };
 
-        if exclude_count > 0 {
-            for suffix in walk_host_suffixes(ctx.site_host) {
-                let hash = hash_domain(&suffix);
-                if list_contains(exclude_slice, hash.lo, hash.hi) {
-                    return false;
-                }
-            }
+        // `example.com,~sub.example.com,deep.sub.example.com` applies on
+        // example.com and deep.sub.example.com but not on the rest of
+        // sub.example.com. An exclusion beats an inclusion of the same name.
+        let included = longest_match(include_slice);
+        if include_count > 0 && included.is_none() {
+            return false;
         }
+        match longest_match(exclude_slice) {
+            Some(excluded) => included.is_some_and(|included| included > excluded),
+            None => true,
+        }
+    }
 
-        true
+    /// Whether `url` satisfies network rule `rule_id`'s URL pattern, ignoring
+    /// its options and `$domain=`. Rules without a pattern match any URL.
+    pub fn pattern_matches(&self, rule_id: usize, url: &str) -> bool {
+        let pattern_id = self.snapshot.rules().pattern_id(rule_id);
+        if pattern_id == NO_PATTERN {
+            return true;
+        }
+        let pattern_pool = self.snapshot.pattern_pool();
+        let Some(pattern) = pattern_pool.get_pattern(pattern_id as usize) else {
+            return true;
+        };
+        let program = pattern_pool.get_program(&pattern);
+        self.verify_pattern(url, &pattern, program, &mut MatchWork::new(MatchBudget::UNLIMITED))
     }
 
     /// Verify a URL against a compiled pattern program.
@@ -797,12 +2246,17 @@ impl<'a> Matcher<'a> {
         url: &str,
         pattern: &crate::snapshot::PatternEntry,
         program: &[u8],
+        work: &mut MatchWork,
     ) -> bool {
         let url_bytes = url.as_bytes();
         let mut url_pos: usize = 0;
         let mut prog_pos: usize = 0;
+        let match_case = pattern.flags & pattern_flags::MATCH_CASE != 0;
 
         while prog_pos < program.len() {
+            if !work.charge_pattern_op() {
+                return false;
+            }
             let op = match PatternOp::try_from(program[prog_pos]) {
                 Ok(op) => op,
                 Err(_) => return false,
@@ -823,7 +2277,12 @@ impl<'a> Matcher<'a> {
                         None => return false,
                     };
 
-                    match find_case_insensitive(&url_bytes[url_pos..], literal.as_bytes()) {
+                    let found = if match_case {
+                        find_case_sensitive(&url_bytes[url_pos..], literal.as_bytes())
+                    } else {
+                        find_case_insensitive(&url_bytes[url_pos..], literal.as_bytes())
+                    };
+                    match found {
                         Some(pos) => url_pos += pos + literal.len(),
                         None => return false,
                     }
@@ -866,7 +2325,7 @@ impl<'a> Matcher<'a> {
 
                         let mut host_matches = false;
                         for suffix in walk_host_suffixes(req_host) {
-                            let suffix_hash = hash_domain(&suffix);
+                            let suffix_hash = hash_domain(suffix);
                             if suffix_hash.lo == pattern.host_hash_lo
                                 && suffix_hash.hi == pattern.host_hash_hi
                             {
@@ -901,6 +2360,13 @@ impl<'a> Matcher<'a> {
         }
 
         let rules = self.snapshot.rules();
+        // Only the highest tier with a block or exception gets a say.
+        let tier = candidates
+            .iter()
+            .filter_map(|c| self.deciding_tier(c.rule_id, c.action))
+            .max()
+            .unwrap_or_default();
+        let outranked = |c: &MatchCandidate| self.list_tier(rules.list_id(c.rule_id)) < tier;
 
         let mut best_important_block: Option<&MatchCandidate> = None;
         let mut best_important_allow: Option<&MatchCandidate> = None;
@@ -911,13 +2377,14 @@ impl<'a> Matcher<'a> {
 
         for c in candidates {
             match c.action {
+                RuleAction::Block if outranked(c) => {}
                 RuleAction::Block => {
                     if c.is_important {
-                        if best_important_block.map_or(true, |b| c.priority > b.priority) {
+                        if best_important_block.is_none_or(|b| c.priority > b.priority) {
                             best_important_block = Some(c);
                         }
                     } else {
-                        if best_block.map_or(true, |b| c.priority > b.priority) {
+                        if best_block.is_none_or(|b| c.priority > b.priority) {
                             best_block = Some(c);
                         }
                     }
@@ -931,21 +2398,22 @@ impl<'a> Matcher<'a> {
                         }
                         continue;
                     }
-                    if flags.contains(RuleFlags::ELEMHIDE) || flags.contains(RuleFlags::GENERICHIDE) {
+                    if flags.intersects(
+                        RuleFlags::ELEMHIDE | RuleFlags::GENERICHIDE | RuleFlags::SPECIFICHIDE | RuleFlags::GENERICBLOCK,
+                    ) || outranked(c)
+                    {
                         continue;
                     }
                     if c.is_important {
-                        if best_important_allow.map_or(true, |b| c.priority > b.priority) {
+                        if best_important_allow.is_none_or(|b| c.priority > b.priority) {
                             best_important_allow = Some(c);
                         }
-                    } else if best_allow.map_or(true, |b| c.priority > b.priority) {
+                    } else if best_allow.is_none_or(|b| c.priority > b.priority) {
                         best_allow = Some(c);
                     }
                 }
-                RuleAction::RedirectDirective => {
-                    if best_redirect.map_or(true, |b| c.priority > b.priority) {
-                        best_redirect = Some(c);
-                    }
+                RuleAction::RedirectDirective if best_redirect.is_none_or(|b| c.priority > b.priority) => {
+                    best_redirect = Some(c);
                 }
                 _ => {}
             }
@@ -958,6 +2426,8 @@ impl<'a> Matcher<'a> {
                 rule_id: c.rule_id as i32,
                 list_id: rules.list_id(c.rule_id),
                 redirect_url: None,
+                budget_exceeded: false,
+                site_switch: SiteSwitches::empty(),
             };
         }
 
@@ -971,6 +2441,8 @@ impl<'a> Matcher<'a> {
                     rule_id: c.rule_id as i32,
                     list_id,
                     redirect_url: Some(url),
+                    budget_exceeded: false,
+                    site_switch: SiteSwitches::empty(),
                 };
             }
 
@@ -983,6 +2455,8 @@ impl<'a> Matcher<'a> {
                         rule_id: c.rule_id as i32,
                         list_id,
                         redirect_url: Some(url),
+                        budget_exceeded: false,
+                        site_switch: SiteSwitches::empty(),
                     };
                 }
             }
@@ -992,17 +2466,20 @@ impl<'a> Matcher<'a> {
                 rule_id: c.rule_id as i32,
                 list_id,
                 redirect_url: None,
+                budget_exceeded: false,
+                site_switch: SiteSwitches::empty(),
             };
         }
 
         // 2. ALLOW exception overrides normal block
-        if best_allow.is_some() && best_block.is_some() {
-            let c = best_allow.unwrap();
+        if let (Some(c), Some(_)) = (best_allow, best_block) {
             return MatchResult {
                 decision: MatchDecision::Allow,
                 rule_id: c.rule_id as i32,
                 list_id: rules.list_id(c.rule_id),
                 redirect_url: None,
+                budget_exceeded: false,
+                site_switch: SiteSwitches::empty(),
             };
         }
 
@@ -1016,6 +2493,8 @@ impl<'a> Matcher<'a> {
                     rule_id: c.rule_id as i32,
                     list_id,
                     redirect_url: Some(url),
+                    budget_exceeded: false,
+                    site_switch: SiteSwitches::empty(),
                 };
             }
 
@@ -1028,6 +2507,8 @@ impl<'a> Matcher<'a> {
                         rule_id: c.rule_id as i32,
                         list_id,
                         redirect_url: Some(url),
+                        budget_exceeded: false,
+                        site_switch: SiteSwitches::empty(),
                     };
                 }
             }
@@ -1037,6 +2518,8 @@ impl<'a> Matcher<'a> {
                 rule_id: c.rule_id as i32,
                 list_id,
                 redirect_url: None,
+                budget_exceeded: false,
+                site_switch: SiteSwitches::empty(),
             };
         }
 
@@ -1047,6 +2530,8 @@ impl<'a> Matcher<'a> {
                 rule_id: c.rule_id as i32,
                 list_id: rules.list_id(c.rule_id),
                 redirect_url: None,
+                budget_exceeded: false,
+                site_switch: SiteSwitches::empty(),
             };
         }
 
@@ -1075,13 +2560,26 @@ impl<'a> Matcher<'a> {
             return None;
         }
 
-        let entry_offset = 4 + option_id as usize * 20;
-        if entry_offset + 16 > section.len() {
+        let entry_offset = 4 + option_id as usize * REDIRECT_RESOURCE_ENTRY_SIZE;
+        if entry_offset + REDIRECT_RESOURCE_ENTRY_SIZE > section.len() {
             return None;
         }
 
-        let path_str_off = read_u32_le(section, entry_offset + 8) as usize;
-        let path_str_len = read_u32_le(section, entry_offset + 12) as usize;
+        let path_str_off = read_u32_le(section, entry_offset + redirect_resource_entry::PATH_OFF) as usize;
+        let path_str_len = read_u32_le(section, entry_offset + redirect_resource_entry::PATH_LEN) as usize;
+        let flags = read_u32_le(section, entry_offset + redirect_resource_entry::FLAGS);
+
+        if flags & redirect_resource_flags::HAS_DATA_URL != 0 && (self.redirect_data_urls || path_str_len == 0) {
+            let data_offset =
+                4 + resource_count * REDIRECT_RESOURCE_ENTRY_SIZE + option_id as usize * REDIRECT_DATA_ENTRY_SIZE;
+            if data_offset + REDIRECT_DATA_ENTRY_SIZE <= section.len() {
+                let data_off = read_u32_le(section, data_offset) as usize;
+                let data_len = read_u32_le(section, data_offset + 4) as usize;
+                if let Some(url) = self.snapshot.get_string(data_off, data_len) {
+                    return Some(url.to_string());
+                }
+            }
+        }
 
         self.snapshot.get_string(path_str_off, path_str_len).map(|s| s.to_string())
     }
@@ -1112,6 +2610,36 @@ impl<'a> Matcher<'a> {
         self.snapshot.get_string(param_off, param_len)
     }
 
+    fn get_removeheader_spec(&self, option_id: u32) -> Option<RemoveheaderSpecRef<'a>> {
+        if option_id == NO_OPTION_ID {
+            return None;
+        }
+
+        let section = self.snapshot.removeheader_specs();
+        if section.len() < 4 {
+            return None;
+        }
+
+        let spec_count = read_u32_le(section, 0) as usize;
+        if option_id as usize >= spec_count {
+            return None;
+        }
+
+        let entry_offset = 4 + option_id as usize * REMOVEHEADER_SPEC_ENTRY_SIZE;
+        if entry_offset + REMOVEHEADER_SPEC_ENTRY_SIZE > section.len() {
+            return None;
+        }
+
+        let name_off = read_u32_le(section, entry_offset + removeheader_spec_entry::NAME_OFF) as usize;
+        let name_len = read_u32_le(section, entry_offset + removeheader_spec_entry::NAME_LEN) as usize;
+        let flags = read_u32_le(section, entry_offset + removeheader_spec_entry::FLAGS);
+
+        Some(RemoveheaderSpecRef {
+            name: self.snapshot.get_string(name_off, name_len)?,
+            request: flags & removeheader_flags::REQUEST != 0,
+        })
+    }
+
     fn get_csp_spec(&self, option_id: u32) -> Option<&str> {
         if option_id == NO_OPTION_ID {
             return None;
@@ -1138,6 +2666,39 @@ impl<'a> Matcher<'a> {
         self.snapshot.get_string(spec_off, spec_len)
     }
 
+    fn get_replace_spec(&self, option_id: u32) -> Option<ReplaceSpecRef<'a>> {
+        if option_id == NO_OPTION_ID {
+            return None;
+        }
+
+        let section = self.snapshot.replace_specs();
+        if section.len() < 4 {
+            return None;
+        }
+
+        let spec_count = read_u32_le(section, 0) as usize;
+        if option_id as usize >= spec_count {
+            return None;
+        }
+
+        let entry_offset = 4 + option_id as usize * REPLACE_SPEC_ENTRY_SIZE;
+        if entry_offset + REPLACE_SPEC_ENTRY_SIZE > section.len() {
+            return None;
+        }
+
+        let pattern_off = read_u32_le(section, entry_offset) as usize;
+        let pattern_len = read_u32_le(section, entry_offset + 4) as usize;
+        let replacement_off = read_u32_le(section, entry_offset + 8) as usize;
+        let replacement_len = read_u32_le(section, entry_offset + 12) as usize;
+        let flags = read_u32_le(section, entry_offset + 16);
+
+        Some(ReplaceSpecRef {
+            pattern: self.snapshot.get_string(pattern_off, pattern_len)?,
+            replacement: self.snapshot.get_string(replacement_off, replacement_len)?,
+            flags,
+        })
+    }
+
     fn get_header_spec(&self, option_id: u32) -> Option<HeaderSpecRef<'a>> {
         if option_id == NO_OPTION_ID {
             return None;
@@ -1153,8 +2714,8 @@ impl<'a> Matcher<'a> {
             return None;
         }
 
-        let entry_offset = 4 + option_id as usize * 20;
-        if entry_offset + 20 > section.len() {
+        let entry_offset = 4 + option_id as usize * HEADER_SPEC_ENTRY_SIZE;
+        if entry_offset + HEADER_SPEC_ENTRY_SIZE > section.len() {
             return None;
         }
 
@@ -1174,11 +2735,71 @@ impl<'a> Matcher<'a> {
         Some(HeaderSpecRef {
             name,
             value,
-            negate: flags & 1 != 0,
+            negate: flags & header_spec_flags::NEGATE != 0,
+            request: flags & header_spec_flags::REQUEST != 0,
         })
     }
 }
 
+fn list_tiers(snapshot: &Snapshot<'_>) -> Vec<ListTier> {
+    let metadata = snapshot.all_list_metadata();
+    if metadata.iter().all(|meta| meta.tier == ListTier::BuiltIn) {
+        return Vec::new();
+    }
+    let mut tiers = Vec::new();
+    for meta in metadata {
+        let list_id = meta.list_id as usize;
+        if tiers.len() <= list_id {
+            tiers.resize(list_id + 1, ListTier::BuiltIn);
+        }
+        tiers[list_id] = meta.tier;
+    }
+    tiers
+}
+
+fn targeted_schemes(snapshot: &Snapshot<'_>) -> SchemeMask {
+    let rules = snapshot.rules();
+    let bits = (0..rules.count).fold(0, |bits, rule_id| bits | rules.scheme_mask(rule_id));
+    SchemeMask::from_bits_truncate(bits) - SchemeMask::WEB
+}
+
+fn has_genericblock(snapshot: &Snapshot<'_>) -> bool {
+    let rules = snapshot.rules();
+    (0..rules.count).any(|rule_id| RuleFlags::from_bits_truncate(rules.flags(rule_id)).contains(RuleFlags::GENERICBLOCK))
+}
+
+/// Compile the RegexPatterns section. Sources the engine rejects are skipped.
+#[cfg(feature = "regex")]
+fn compile_regex_rules(snapshot: &Snapshot<'_>) -> Vec<RegexRule> {
+    let section = snapshot.regex_patterns();
+    if section.len() < 4 {
+        return Vec::new();
+    }
+
+    let count = bounded_count(read_u32_le(section, 0) as usize, section.len(), 4, REGEX_PATTERN_ENTRY_SIZE);
+    let mut regex_rules = Vec::with_capacity(count);
+    for i in 0..count {
+        let base = 4 + i * REGEX_PATTERN_ENTRY_SIZE;
+        if base + REGEX_PATTERN_ENTRY_SIZE > section.len() {
+            break;
+        }
+        let source_off = read_u32_le(section, base + regex_pattern_entry::SOURCE_OFF) as usize;
+        let source_len = read_u32_le(section, base + regex_pattern_entry::SOURCE_LEN) as usize;
+        let flags = read_u32_le(section, base + regex_pattern_entry::FLAGS);
+        let Some(source) = snapshot.get_string(source_off, source_len) else {
+            continue;
+        };
+        let match_case = flags & pattern_flags::MATCH_CASE as u32 != 0;
+        if let Some(regex) = crate::regex::compile_filter_regex(source, match_case) {
+            regex_rules.push(RegexRule {
+                rule_id: read_u32_le(section, base + regex_pattern_entry::RULE_ID) as usize,
+                regex,
+            });
+        }
+    }
+    regex_rules
+}
+
 // =============================================================================
 // Match Candidate
 // =============================================================================
@@ -1195,6 +2816,72 @@ struct HeaderSpecRef<'a> {
     name: &'a str,
     value: Option<&'a str>,
     negate: bool,
+    request: bool,
+}
+
+struct RemoveheaderSpecRef<'a> {
+    name: &'a str,
+    request: bool,
+}
+
+struct ReplaceSpecRef<'a> {
+    pattern: &'a str,
+    replacement: &'a str,
+    flags: u32,
+}
+
+/// Bitset over small ids, one bit per id in 64-bit words.
+fn id_bitset(ids: impl Iterator<Item = usize>) -> Vec<u64> {
+    let mut words = Vec::new();
+    for id in ids {
+        let word = id / 64;
+        if word >= words.len() {
+            words.resize(word + 1, 0);
+        }
+        words[word] |= 1 << (id % 64);
+    }
+    words
+}
+
+#[inline]
+fn id_bitset_contains(words: &[u64], id: usize) -> bool {
+    words.get(id / 64).is_some_and(|word| word & (1 << (id % 64)) != 0)
+}
+
+/// The page's host without its public suffix, e.g. `www.example` for
+/// `www.example.co.uk`; `None` when the site has no registrable domain.
+fn site_entity<'c>(ctx: &RequestContext<'c>) -> Option<&'c str> {
+    let (_, public_suffix) = ctx.site_etld1.split_once('.')?;
+    ctx.site_host.strip_suffix(public_suffix)?.strip_suffix('.')
+}
+
+/// `display:none` rules for `selectors`, `chunk` selectors each.
+pub fn hide_css(selectors: &[&str], chunk: usize) -> Vec<String> {
+    selectors
+        .chunks(chunk.max(1))
+        .map(|chunk| {
+            let mut css = chunk.join(",\n");
+            css.push_str("{display:none !important;}");
+            css
+        })
+        .collect()
+}
+
+fn replace_flags_string(flags: u32) -> String {
+    let mut out = String::new();
+    if flags & replace_flags::GLOBAL != 0 {
+        out.push('g');
+    }
+    if flags & replace_flags::IGNORE_CASE != 0 {
+        out.push('i');
+    }
+    if flags & replace_flags::MULTILINE != 0 {
+        out.push('m');
+    }
+    if flags & replace_flags::DOT_ALL != 0 {
+        out.push('s');
+    }
+    out
 }
 
 fn find_case_insensitive(haystack: &[u8], needle: &[u8]) -> Option<usize> {
@@ -1215,6 +2902,17 @@ fn find_case_insensitive(haystack: &[u8], needle: &[u8]) -> Option<usize> {
     None
 }
 
+fn find_case_sensitive(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    if needle.len() > haystack.len() {
+        return None;
+    }
+
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
 fn header_matches(spec: &HeaderSpecRef<'_>, headers: &[ResponseHeader<'_>]) -> bool {
     let mut found = false;
     let mut any_value_match = false;
@@ -1267,8 +2965,49 @@ fn is_safe_response_header(name: &str) -> bool {
         || name.eq_ignore_ascii_case("set-cookie")
 }
 
+/// Headers `$removeheader` must never strip, since removing them weakens
+/// security or breaks the exchange (after AdGuard's restricted list).
+fn is_removable_header(name: &str) -> bool {
+    const RESTRICTED: &[&str] = &[
+        "accept",
+        "accept-encoding",
+        "allow",
+        "connection",
+        "content-length",
+        "content-security-policy",
+        "content-security-policy-report-only",
+        "content-type",
+        "expect-ct",
+        "feature-policy",
+        "host",
+        "origin",
+        "origin-isolation",
+        "p3p",
+        "permissions-policy",
+        "public-key-pins",
+        "public-key-pins-report-only",
+        "referrer-policy",
+        "strict-transport-security",
+        "timing-allow-origin",
+        "transfer-encoding",
+        "upgrade",
+        "upgrade-insecure-requests",
+        "x-content-type-options",
+        "x-download-options",
+        "x-frame-options",
+        "x-permitted-cross-domain-policies",
+        "x-powered-by",
+        "x-xss-protection",
+    ];
+    const RESTRICTED_PREFIXES: &[&str] = &["access-control-", "cross-origin-", "sec-fetch-", "sec-websocket-"];
+
+    !name.is_empty()
+        && !RESTRICTED.contains(&name)
+        && !RESTRICTED_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+}
+
 fn split_removeparam_spec(spec: &str) -> Vec<&str> {
-    spec.split(|ch| ch == '|' || ch == ',')
+    spec.split(['|', ','])
         .map(|part| part.trim())
         .filter(|part| !part.is_empty())
         .collect()
@@ -1279,7 +3018,7 @@ fn remove_params(url: &str, remove_keys: &[&str]) -> Option<String> {
     let fragment_start = url[query_start + 1..].find('#').map(|idx| idx + query_start + 1);
 
     let base = &url[..query_start];
-    let query_end = fragment_start.unwrap_or_else(|| url.len());
+    let query_end = fragment_start.unwrap_or(url.len());
     let query = &url[query_start + 1..query_end];
     let fragment = fragment_start.map(|idx| &url[idx..]).unwrap_or("");
 
@@ -0,0 +1,129 @@
+//! Decision audit log
+//!
+//! With the `audit` feature, [`Matcher::match_request`] records what it
+//! decided for each request, with the deciding rule and the time, in a
+//! fixed-size ring buffer. Embedded deployments can read it back with
+//! [`Matcher::recent_decisions`] after something went wrong. Unlike a request
+//! trace, which is captured before matching, this is the engine's own
+//! account of its decisions. Slots are reused once the buffer is full, so a
+//! warmed-up log adds no allocations to the request path.
+//!
+//! The log works without `std`: it is guarded by a spin lock there (a
+//! `std` mutex otherwise), and the time comes from
+//! [`Matcher::set_audit_clock`]. With `std` the clock defaults to the system
+//! clock; without it entries are stamped 0 until one is set.
+
+#[cfg(not(feature = "std"))]
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
+
+use crate::sync::Mutex;
+use crate::types::{MatchDecision, MatchResult, RequestContext, RequestType};
+
+use super::Matcher;
+
+/// The time for [`AuditEntry::timestamp_ms`], usually unix milliseconds.
+pub type AuditClock = fn() -> u64;
+
+#[cfg(feature = "std")]
+fn default_clock() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(not(feature = "std"))]
+fn default_clock() -> u64 {
+    0
+}
+
+/// Decisions kept by a new matcher.
+pub const DEFAULT_AUDIT_CAPACITY: usize = 256;
+
+/// One request the matcher decided.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// From the matcher's [`AuditClock`]; unix milliseconds by default
+    pub timestamp_ms: u64,
+    pub url: String,
+    pub site_host: String,
+    pub request_type: RequestType,
+    pub decision: MatchDecision,
+    /// Rule that decided the request, -1 if none did
+    pub rule_id: i32,
+    pub list_id: u16,
+}
+
+pub(super) struct AuditLog {
+    entries: Mutex<VecDeque<AuditEntry>>,
+    capacity: usize,
+    clock: AuditClock,
+}
+
+impl AuditLog {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            clock: default_clock,
+        }
+    }
+
+    pub(super) fn record(&self, ctx: &RequestContext<'_>, result: &MatchResult) {
+        if self.capacity == 0 {
+            return;
+        }
+        let timestamp_ms = (self.clock)();
+        let mut entries = self.entries.lock();
+        let mut entry = if entries.len() >= self.capacity {
+            entries.pop_front().unwrap()
+        } else {
+            AuditEntry {
+                timestamp_ms: 0,
+                url: String::new(),
+                site_host: String::new(),
+                request_type: RequestType::OTHER,
+                decision: MatchDecision::Allow,
+                rule_id: -1,
+                list_id: 0,
+            }
+        };
+        entry.timestamp_ms = timestamp_ms;
+        entry.url.clear();
+        entry.url.push_str(ctx.url);
+        entry.site_host.clear();
+        entry.site_host.push_str(ctx.site_host);
+        entry.request_type = ctx.request_type;
+        entry.decision = result.decision;
+        entry.rule_id = result.rule_id;
+        entry.list_id = result.list_id;
+        entries.push_back(entry);
+    }
+}
+
+impl Matcher<'_> {
+    /// The last decisions [`Matcher::match_request`] made, oldest first.
+    pub fn recent_decisions(&self) -> Vec<AuditEntry> {
+        self.audit.entries.lock().iter().cloned().collect()
+    }
+
+    /// Keep the last `capacity` decisions (0 turns recording off). Clears
+    /// the log.
+    pub fn set_audit_capacity(&mut self, capacity: usize) {
+        let clock = self.audit.clock;
+        self.audit = AuditLog::new(capacity);
+        self.audit.clock = clock;
+    }
+
+    /// Stamp recorded decisions with `clock` instead of the default one.
+    /// Needed without `std`, where the matcher has no clock of its own.
+    pub fn set_audit_clock(&mut self, clock: AuditClock) {
+        self.audit.clock = clock;
+    }
+
+    /// Forget the recorded decisions.
+    pub fn clear_recent_decisions(&self) {
+        self.audit.entries.lock().clear();
+    }
+}
@@ -0,0 +1,160 @@
+//! Per-site exception diagnostics
+//!
+//! [`Matcher::site_diagnostics`] counts the exception rules active on a page,
+//! by kind and by list. When a site still shows ads (or an anti-adblock wall
+//! got through), support can see whether an allowlisting rule from some list
+//! is responsible. Like [`Matcher::explain_request`] it scans whole sections,
+//! so it is for tooling, not the request path.
+
+#[cfg(not(feature = "std"))]
+use alloc::{collections::BTreeMap, vec::Vec};
+
+#[cfg(feature = "std")]
+use std::collections::BTreeMap;
+
+use crate::snapshot::{read_u16_le, read_u32_le, NO_CONSTRAINT};
+use crate::types::{RequestContext, RuleAction, RuleFlags};
+
+use super::{id_bitset_contains, CosmeticHideSwitches, MatchScratch, Matcher};
+
+/// What an exception rule turns off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ExceptionKind {
+    /// `@@` rule that matches the page or names the site in `$domain=`
+    Network,
+    /// `$elemhide`, `$generichide` or `$specifichide` exception for the page
+    HideSwitch,
+    /// `#@#` exception for a plain selector
+    Cosmetic,
+    /// `#@#` exception for a procedural selector
+    Procedural,
+    /// `#@#+js(...)` exception
+    Scriptlet,
+}
+
+impl ExceptionKind {
+    /// Stable identifier for JSON output.
+    pub fn code(self) -> &'static str {
+        match self {
+            ExceptionKind::Network => "network",
+            ExceptionKind::HideSwitch => "hide-switch",
+            ExceptionKind::Cosmetic => "cosmetic",
+            ExceptionKind::Procedural => "procedural",
+            ExceptionKind::Scriptlet => "scriptlet",
+        }
+    }
+}
+
+/// Active exceptions of one kind from one list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionCount {
+    pub kind: ExceptionKind,
+    pub list_id: u16,
+    pub count: u32,
+}
+
+/// Everything [`Matcher::site_diagnostics`] found for a page.
+#[derive(Debug, Clone, Default)]
+pub struct SiteDiagnostics {
+    /// The site is trusted, so nothing is filtered at all
+    pub trusted: bool,
+    pub switches: CosmeticHideSwitches,
+    /// Sorted by kind, then list
+    pub exceptions: Vec<ExceptionCount>,
+}
+
+impl SiteDiagnostics {
+    /// Active exceptions of `kind` across every list.
+    pub fn count(&self, kind: ExceptionKind) -> u32 {
+        self.exceptions.iter().filter(|entry| entry.kind == kind).map(|entry| entry.count).sum()
+    }
+}
+
+impl Matcher<'_> {
+    /// Count the exception rules active on the page `ctx` describes.
+    ///
+    /// Network exceptions are those matching the page request itself plus
+    /// those whose `$domain=` covers the site, so subresource allowlists
+    /// show up too. Cosmetic and scriptlet exceptions are those whose
+    /// domains cover the site, generic ones included.
+    pub fn site_diagnostics(&self, ctx: &RequestContext<'_>) -> SiteDiagnostics {
+        let mut counts: BTreeMap<(ExceptionKind, u16), u32> = BTreeMap::new();
+        let mut count = |kind: ExceptionKind, list_id: u16| *counts.entry((kind, list_id)).or_default() += 1;
+
+        let rules = self.snapshot.rules();
+        let network_kind = |rule_id: usize| {
+            let flags = RuleFlags::from_bits_truncate(rules.flags(rule_id));
+            if flags.intersects(RuleFlags::ELEMHIDE | RuleFlags::GENERICHIDE | RuleFlags::SPECIFICHIDE) {
+                ExceptionKind::HideSwitch
+            } else {
+                ExceptionKind::Network
+            }
+        };
+
+        let mut scratch = MatchScratch::new();
+        self.collect_candidates(ctx, &mut scratch);
+        // Sorted and deduplicated by collect_candidates
+        let page_rules: Vec<usize> = scratch
+            .candidates
+            .iter()
+            .filter(|candidate| candidate.action == RuleAction::Allow)
+            .map(|candidate| candidate.rule_id)
+            .collect();
+        for &rule_id in &page_rules {
+            count(network_kind(rule_id), rules.list_id(rule_id));
+        }
+
+        for rule_id in 0..rules.count {
+            if rules.action(rule_id) != RuleAction::Allow as u8
+                || rules.domain_constraint_offset(rule_id) == NO_CONSTRAINT
+                || page_rules.binary_search(&rule_id).is_ok()
+            {
+                continue;
+            }
+            if !self.is_rule_enabled(rule_id)
+                || id_bitset_contains(&self.expired_rules, rule_id)
+                || !self.is_list_enabled(rules.list_id(rule_id))
+            {
+                continue;
+            }
+            if self.check_domain_constraints(rule_id, ctx) {
+                count(network_kind(rule_id), rules.list_id(rule_id));
+            }
+        }
+
+        let sections = [
+            (self.snapshot.cosmetic_rules(), ExceptionKind::Cosmetic),
+            (self.snapshot.procedural_rules(), ExceptionKind::Procedural),
+            (self.snapshot.scriptlet_rules(), ExceptionKind::Scriptlet),
+        ];
+        for (section, kind) in sections {
+            if section.len() < 4 {
+                continue;
+            }
+            let entries = read_u32_le(section, 0) as usize;
+            for idx in 0..entries {
+                let entry_offset = 4 + idx * 16;
+                if entry_offset + 16 > section.len() {
+                    break;
+                }
+                if read_u16_le(section, entry_offset + 12) & 1 == 0 {
+                    continue;
+                }
+                let list_id = read_u16_le(section, entry_offset + 14);
+                let constraint_offset = read_u32_le(section, entry_offset);
+                if self.is_list_enabled(list_id) && self.check_domain_constraints_offset(constraint_offset, ctx) {
+                    count(kind, list_id);
+                }
+            }
+        }
+
+        SiteDiagnostics {
+            trusted: self.is_trusted_site(ctx.site_etld1),
+            switches: self.cosmetic_hide_switches(ctx),
+            exceptions: counts
+                .into_iter()
+                .map(|((kind, list_id), count)| ExceptionCount { kind, list_id, count })
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,430 @@
+//! Match explanations
+//!
+//! [`Matcher::explain_request`] replays static network matching for one
+//! request and keeps every candidate rule it looks at: which stage produced
+//! it, the first check that rejected it, and the precedence step behind the
+//! final decision. It allocates freely and ignores the match budget, so it
+//! is for debugging lists, not for the request path.
+
+#[cfg(not(feature = "std"))]
+use alloc::{string::{String, ToString}, vec::Vec};
+
+use core::fmt;
+
+use crate::hash::hash_domain;
+use crate::psl::walk_host_suffixes;
+use crate::snapshot::{append_bucketed_postings, decode_posting_list_with_count_into, posting_bucket_for, PostingBuf};
+use crate::types::{MatchDecision, MatchResult, RequestContext, RuleAction, RuleFlags};
+use crate::url::tokenize_url_with_positions;
+
+use super::{MatchScratch, Matcher};
+
+/// Where a candidate rule came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CandidateSource {
+    /// Host-only rule filed under this suffix of the request host
+    DomainSet { suffix: String },
+    /// Rule posted under this URL token
+    Token { token: String },
+    /// `||host/path` rule filed under this suffix of the request host
+    HostAnchor { suffix: String },
+    /// Regex filter, tried on every request
+    Regex,
+}
+
+impl fmt::Display for CandidateSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CandidateSource::DomainSet { suffix } => write!(f, "domain set '{}'", suffix),
+            CandidateSource::Token { token } => write!(f, "token '{}'", token),
+            CandidateSource::HostAnchor { suffix } => write!(f, "host anchor '{}'", suffix),
+            CandidateSource::Regex => f.write_str("regex"),
+        }
+    }
+}
+
+/// The first check a candidate failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    RuleDisabled,
+    /// The rule's `$expires=` date has passed
+    Expired,
+    ListDisabled,
+    RequestType,
+    Party,
+    Scheme,
+    DomainConstraint,
+    Pattern,
+    /// Generic block rule turned off by a `$genericblock` exception
+    GenericBlock,
+}
+
+impl Rejection {
+    /// Stable identifier for JSON output.
+    pub fn code(self) -> &'static str {
+        match self {
+            Rejection::RuleDisabled => "rule-disabled",
+            Rejection::Expired => "expired",
+            Rejection::ListDisabled => "list-disabled",
+            Rejection::RequestType => "type",
+            Rejection::Party => "party",
+            Rejection::Scheme => "scheme",
+            Rejection::DomainConstraint => "domain",
+            Rejection::Pattern => "pattern",
+            Rejection::GenericBlock => "genericblock",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rejection::RuleDisabled => "rule is disabled",
+            Rejection::Expired => "$expires date has passed",
+            Rejection::ListDisabled => "list is disabled",
+            Rejection::RequestType => "request type not in the rule's type mask",
+            Rejection::Party => "first/third-party mismatch",
+            Rejection::Scheme => "scheme not allowed by the rule",
+            Rejection::DomainConstraint => "$domain= does not match the site",
+            Rejection::Pattern => "URL does not match the pattern",
+            Rejection::GenericBlock => "generic rule disabled by $genericblock",
+        })
+    }
+}
+
+/// The precedence step that produced the decision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecisionReason {
+    /// The site is trusted; nothing was checked
+    TrustedSite,
+    /// No rule targets the URL's scheme; nothing was checked
+    UnfilterableScheme,
+    /// A per-site switch blocked the request; rules were not checked
+    SiteSwitch,
+    /// A `$removeparam` rule rewrote the URL
+    Removeparam,
+    /// No block or exception rule matched
+    NoMatch,
+    /// An `$important` exception beats every block
+    ImportantException,
+    /// An `$important` block ignores ordinary exceptions
+    ImportantBlock,
+    /// An exception overrides the matching block rules
+    Exception,
+    Block,
+    /// Blocked, and a `$redirect` resource replaces the response
+    Redirect,
+    /// Only exceptions matched, so there was nothing to override
+    ExceptionWithoutBlock,
+}
+
+impl DecisionReason {
+    /// Stable identifier for JSON output.
+    pub fn code(self) -> &'static str {
+        match self {
+            DecisionReason::TrustedSite => "trusted-site",
+            DecisionReason::UnfilterableScheme => "unfilterable-scheme",
+            DecisionReason::SiteSwitch => "site-switch",
+            DecisionReason::Removeparam => "removeparam",
+            DecisionReason::NoMatch => "no-match",
+            DecisionReason::ImportantException => "important-exception",
+            DecisionReason::ImportantBlock => "important-block",
+            DecisionReason::Exception => "exception",
+            DecisionReason::Block => "block",
+            DecisionReason::Redirect => "redirect",
+            DecisionReason::ExceptionWithoutBlock => "exception-without-block",
+        }
+    }
+}
+
+impl fmt::Display for DecisionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DecisionReason::TrustedSite => "site is trusted, filtering bypassed",
+            DecisionReason::UnfilterableScheme => "no rule targets this URL scheme",
+            DecisionReason::SiteSwitch => "blocked by a per-site switch",
+            DecisionReason::Removeparam => "$removeparam rewrote the URL",
+            DecisionReason::NoMatch => "no block or exception rule matched",
+            DecisionReason::ImportantException => "$important exception overrides all blocks",
+            DecisionReason::ImportantBlock => "$important block ignores ordinary exceptions",
+            DecisionReason::Exception => "exception overrides the block rules",
+            DecisionReason::Block => "block rule matched and no exception did",
+            DecisionReason::Redirect => "blocked and redirected to a resource",
+            DecisionReason::ExceptionWithoutBlock => "only exceptions matched",
+        })
+    }
+}
+
+/// One rule considered for the request.
+#[derive(Debug, Clone)]
+pub struct ExplainedCandidate {
+    pub rule_id: usize,
+    pub list_id: u16,
+    pub action: RuleAction,
+    pub important: bool,
+    pub priority: i16,
+    pub source: CandidateSource,
+    /// `None` when the rule matched
+    pub rejected: Option<Rejection>,
+}
+
+impl ExplainedCandidate {
+    pub fn matched(&self) -> bool {
+        self.rejected.is_none()
+    }
+}
+
+/// Everything [`Matcher::explain_request`] found out about a request.
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    /// What [`Matcher::match_request`] returns for the request
+    pub result: MatchResult,
+    pub reason: DecisionReason,
+    /// Candidates in the order they were produced: domain sets from the most
+    /// specific host suffix, then regex filters, then token postings
+    pub candidates: Vec<ExplainedCandidate>,
+    /// Distinct URL tokens that have postings
+    pub tokens: Vec<String>,
+    /// A `$genericblock` exception matched the page
+    pub genericblock: bool,
+}
+
+impl Matcher<'_> {
+    /// Explain how [`Matcher::match_request`] decides `ctx`.
+    pub fn explain_request(&self, ctx: &RequestContext<'_>) -> Explanation {
+        let mut explanation = Explanation {
+            result: self.match_request(ctx),
+            reason: DecisionReason::NoMatch,
+            candidates: Vec::new(),
+            tokens: Vec::new(),
+            genericblock: false,
+        };
+        if self.is_trusted_site(ctx.site_etld1) {
+            explanation.reason = DecisionReason::TrustedSite;
+            return explanation;
+        }
+        if !self.filters_scheme(ctx.scheme) {
+            explanation.reason = DecisionReason::UnfilterableScheme;
+            return explanation;
+        }
+        if !explanation.result.site_switch.is_empty() {
+            explanation.reason = DecisionReason::SiteSwitch;
+            return explanation;
+        }
+
+        self.explain_domain_sets(ctx, &mut explanation.candidates);
+        self.explain_regex_rules(ctx, &mut explanation.candidates);
+        self.explain_token_rules(ctx, &mut explanation);
+
+        let rules = self.snapshot.rules();
+        let is_generic_block = |c: &ExplainedCandidate| {
+            c.matched()
+                && c.action == RuleAction::Block
+                && RuleFlags::from_bits_truncate(rules.flags(c.rule_id)).contains(RuleFlags::IS_GENERIC)
+        };
+        if self.has_genericblock
+            && explanation.candidates.iter().any(is_generic_block)
+            && self.genericblock_applies(ctx, &mut MatchScratch::new())
+        {
+            explanation.genericblock = true;
+            for candidate in explanation.candidates.iter_mut().filter(|c| is_generic_block(c)) {
+                candidate.rejected = Some(Rejection::GenericBlock);
+            }
+        }
+
+        explanation.reason = if explanation.result.decision == MatchDecision::Removeparam {
+            DecisionReason::Removeparam
+        } else {
+            self.decision_reason(&explanation)
+        };
+        explanation
+    }
+
+    fn explain_candidate(
+        &self,
+        rule_id: usize,
+        action: RuleAction,
+        source: CandidateSource,
+        rejected: Option<Rejection>,
+    ) -> ExplainedCandidate {
+        let rules = self.snapshot.rules();
+        ExplainedCandidate {
+            rule_id,
+            list_id: rules.list_id(rule_id),
+            action,
+            important: RuleFlags::from_bits_truncate(rules.flags(rule_id)).contains(RuleFlags::IMPORTANT),
+            priority: rules.priority(rule_id),
+            source,
+            rejected,
+        }
+    }
+
+    fn option_and_domain_rejection(&self, rule_id: usize, ctx: &RequestContext<'_>) -> Option<Rejection> {
+        self.rule_option_rejection(rule_id, ctx).or_else(|| {
+            (!self.check_domain_constraints(rule_id, ctx)).then_some(Rejection::DomainConstraint)
+        })
+    }
+
+    fn explain_domain_sets(&self, ctx: &RequestContext<'_>, out: &mut Vec<ExplainedCandidate>) {
+        let postings = self.snapshot.domain_postings();
+        let mut rule_ids = PostingBuf::new();
+
+        for suffix in walk_host_suffixes(ctx.req_host) {
+            let hash = hash_domain(suffix);
+            for (set, action) in [
+                (self.snapshot.domain_allow_set(), RuleAction::Allow),
+                (self.snapshot.domain_block_set(), RuleAction::Block),
+            ] {
+                let Some(value) = set.lookup(hash) else {
+                    continue;
+                };
+                match postings {
+                    Some(data) => decode_posting_list_with_count_into(data, value as usize, &mut rule_ids),
+                    None => {
+                        rule_ids.clear();
+                        rule_ids.push(value);
+                    }
+                }
+                for &rule_id in &rule_ids {
+                    let rule_id = rule_id as usize;
+                    let source = CandidateSource::DomainSet { suffix: suffix.to_string() };
+                    let rejected = self.option_and_domain_rejection(rule_id, ctx);
+                    out.push(self.explain_candidate(rule_id, action, source, rejected));
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    fn explain_regex_rules(&self, ctx: &RequestContext<'_>, out: &mut Vec<ExplainedCandidate>) {
+        let rules = self.snapshot.rules();
+        for regex_rule in &self.regex_rules {
+            let rule_id = regex_rule.rule_id;
+            let rejected = self
+                .option_and_domain_rejection(rule_id, ctx)
+                .or_else(|| (!regex_rule.regex.is_match(ctx.url)).then_some(Rejection::Pattern));
+            let action = RuleAction::try_from(rules.action(rule_id)).unwrap_or(RuleAction::Block);
+            out.push(self.explain_candidate(rule_id, action, CandidateSource::Regex, rejected));
+        }
+    }
+
+    #[cfg(not(feature = "regex"))]
+    fn explain_regex_rules(&self, _ctx: &RequestContext<'_>, _out: &mut Vec<ExplainedCandidate>) {}
+
+    fn explain_token_rules(&self, ctx: &RequestContext<'_>, explanation: &mut Explanation) {
+        let token_dict = self.snapshot.token_dict();
+        let bucket = posting_bucket_for(ctx.request_type.bits());
+
+        let mut seen = Vec::new();
+        let mut rule_ids = PostingBuf::new();
+        for token in tokenize_url_with_positions(ctx.url) {
+            if seen.contains(&token.hash) {
+                continue;
+            }
+            seen.push(token.hash);
+            let Some(entry) = token_dict.lookup(token.hash) else {
+                continue;
+            };
+            let text = ctx.url[token.start..token.start + token.len].to_ascii_lowercase();
+            rule_ids.clear();
+            self.snapshot.append_token_postings(&entry, bucket, &mut rule_ids);
+
+            for &rule_id in &rule_ids {
+                let source = CandidateSource::Token { token: text.clone() };
+                explanation.candidates.push(self.explain_pattern_candidate(rule_id as usize, ctx, source));
+            }
+            explanation.tokens.push(text);
+        }
+
+        let host_anchored = self.snapshot.host_anchored_rules();
+        let host_postings = self.snapshot.host_anchored_postings();
+        for suffix in walk_host_suffixes(ctx.req_host) {
+            let Some(offset) = host_anchored.lookup(hash_domain(suffix)) else {
+                continue;
+            };
+            rule_ids.clear();
+            append_bucketed_postings(host_postings, offset as usize, bucket, &mut rule_ids);
+            for &rule_id in &rule_ids {
+                let source = CandidateSource::HostAnchor { suffix: suffix.to_string() };
+                explanation.candidates.push(self.explain_pattern_candidate(rule_id as usize, ctx, source));
+            }
+        }
+    }
+
+    fn explain_pattern_candidate(
+        &self,
+        rule_id: usize,
+        ctx: &RequestContext<'_>,
+        source: CandidateSource,
+    ) -> ExplainedCandidate {
+        let rejected = self
+            .option_and_domain_rejection(rule_id, ctx)
+            .or_else(|| (!self.pattern_matches(rule_id, ctx.url)).then_some(Rejection::Pattern));
+        let action = RuleAction::try_from(self.snapshot.rules().action(rule_id)).unwrap_or(RuleAction::Block);
+        self.explain_candidate(rule_id, action, source, rejected)
+    }
+
+    /// Mirror of `apply_precedence`, naming the step that decided.
+    fn decision_reason(&self, explanation: &Explanation) -> DecisionReason {
+        let rules = self.snapshot.rules();
+        let tier = explanation
+            .candidates
+            .iter()
+            .filter(|c| c.matched())
+            .filter_map(|c| self.deciding_tier(c.rule_id, c.action))
+            .max()
+            .unwrap_or_default();
+        let matched = explanation
+            .candidates
+            .iter()
+            .filter(|c| c.matched() && self.list_tier(rules.list_id(c.rule_id)) >= tier);
+        let mut any_block = false;
+        let mut important_block = false;
+        let mut allow = false;
+        let mut important_allow = false;
+        for candidate in matched {
+            match candidate.action {
+                RuleAction::Block => {
+                    any_block = true;
+                    important_block |= candidate.important;
+                }
+                RuleAction::Allow => {
+                    let flags = RuleFlags::from_bits_truncate(rules.flags(candidate.rule_id));
+                    if flags.intersects(
+                        RuleFlags::REDIRECT_RULE_EXCEPTION
+                            | RuleFlags::ELEMHIDE
+                            | RuleFlags::GENERICHIDE
+                            | RuleFlags::SPECIFICHIDE
+                            | RuleFlags::GENERICBLOCK,
+                    ) {
+                        continue;
+                    }
+                    allow = true;
+                    important_allow |= candidate.important;
+                }
+                _ => {}
+            }
+        }
+
+        let blocked = if explanation.result.decision == MatchDecision::Redirect {
+            DecisionReason::Redirect
+        } else if important_block {
+            DecisionReason::ImportantBlock
+        } else {
+            DecisionReason::Block
+        };
+        if important_allow {
+            DecisionReason::ImportantException
+        } else if important_block {
+            blocked
+        } else if allow && any_block {
+            DecisionReason::Exception
+        } else if any_block {
+            blocked
+        } else if allow {
+            DecisionReason::ExceptionWithoutBlock
+        } else {
+            DecisionReason::NoMatch
+        }
+    }
+}
@@ -3,20 +3,29 @@
 //! This module provides fast eTLD+1 extraction with LRU caching.
 //! The PSL data is loaded from the snapshot at runtime.
 //!
+//! [`get_etld1_ref`] and [`walk_host_suffixes`] work on slices of the host
+//! and never allocate, so they are the ones to use per request.
+//! [`get_etld1`] returns an owned, lowercased copy.
+//!
 //! # Examples
 //!
 //! ```
-//! use bb_core::psl::get_etld1;
+//! use bb_core::psl::{get_etld1, get_etld1_ref, walk_host_suffixes};
 //!
 //! assert_eq!(get_etld1("sub.example.com"), "example.com");
-//! assert_eq!(get_etld1("sub.example.co.uk"), "example.co.uk");
+//! assert_eq!(get_etld1_ref("sub.example.co.uk"), "example.co.uk");
+//!
+//! let suffixes: Vec<&str> = walk_host_suffixes("a.b.example.com").collect();
+//! assert_eq!(suffixes, ["a.b.example.com", "b.example.com", "example.com"]);
 //! ```
 
 #[cfg(not(feature = "std"))]
-use alloc::{string::String, vec::Vec, collections::BTreeSet};
+use alloc::{boxed::Box, string::{String, ToString}};
 
 #[cfg(feature = "std")]
 use std::collections::HashSet;
+#[cfg(not(feature = "std"))]
+use hashbrown::HashSet;
 
 use crate::hash::{Hash64, hash_domain};
 
@@ -25,53 +34,66 @@ use crate::hash::{Hash64, hash_domain};
 // =============================================================================
 
 /// Simple fixed-size cache for eTLD+1 lookups.
-/// Uses a basic LRU strategy with a hashmap + vec.
+///
+/// Approximates LRU with two generations: inserts go to `recent`, and once
+/// it holds half the capacity it becomes `older`, dropping the previous
+/// `older` wholesale. Hits in `older` are promoted back into `recent`, so
+/// every operation is O(1).
 #[cfg(feature = "std")]
 pub struct LruCache {
     capacity: usize,
-    entries: std::collections::HashMap<String, String>,
-    order: std::collections::VecDeque<String>,
+    recent: std::collections::HashMap<String, String>,
+    older: std::collections::HashMap<String, String>,
 }
 
 #[cfg(feature = "std")]
 impl LruCache {
     /// Create a new LRU cache with the given capacity.
     pub fn new(capacity: usize) -> Self {
+        let generation = (capacity / 2).max(1);
         Self {
             capacity,
-            entries: std::collections::HashMap::with_capacity(capacity),
-            order: std::collections::VecDeque::with_capacity(capacity),
+            recent: std::collections::HashMap::with_capacity(generation),
+            older: std::collections::HashMap::new(),
         }
     }
 
     /// Get a value from the cache.
     pub fn get(&mut self, key: &str) -> Option<&str> {
-        if self.entries.contains_key(key) {
-            // Move to back (most recently used)
-            self.order.retain(|k| k != key);
-            self.order.push_back(key.to_string());
-            self.entries.get(key).map(|s| s.as_str())
-        } else {
-            None
+        if !self.recent.contains_key(key) {
+            let (key, value) = self.older.remove_entry(key)?;
+            self.insert(key, value);
         }
+        self.recent.get(key).map(|s| s.as_str())
+    }
+
+    /// Get a value without touching recency, for read-only callers.
+    pub fn peek(&self, key: &str) -> Option<&str> {
+        self.recent.get(key).or_else(|| self.older.get(key)).map(|s| s.as_str())
     }
 
     /// Insert a value into the cache.
     pub fn insert(&mut self, key: String, value: String) {
-        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
-            // Evict oldest
-            if let Some(oldest) = self.order.pop_front() {
-                self.entries.remove(&oldest);
-            }
+        if self.recent.len() >= (self.capacity / 2).max(1) && !self.recent.contains_key(&key) {
+            self.older = std::mem::take(&mut self.recent);
         }
-        self.order.push_back(key.clone());
-        self.entries.insert(key, value);
+        self.older.remove(&key);
+        self.recent.insert(key, value);
+    }
+
+    /// Number of cached entries.
+    pub fn len(&self) -> usize {
+        self.recent.len() + self.older.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     /// Clear the cache.
     pub fn clear(&mut self) {
-        self.entries.clear();
-        self.order.clear();
+        self.recent.clear();
+        self.older.clear();
     }
 }
 
@@ -83,22 +105,13 @@ impl LruCache {
 #[derive(Debug, Default)]
 pub struct PslSets {
     /// Exact TLD rules (e.g., "com", "co.uk")
-    #[cfg(feature = "std")]
     pub exact: HashSet<u64>,
-    #[cfg(not(feature = "std"))]
-    pub exact: BTreeSet<u64>,
-    
+
     /// Wildcard rules (e.g., "*.ck" stored as "ck")
-    #[cfg(feature = "std")]
     pub wildcard: HashSet<u64>,
-    #[cfg(not(feature = "std"))]
-    pub wildcard: BTreeSet<u64>,
-    
+
     /// Exception rules (e.g., "!www.ck" stored as "www.ck")
-    #[cfg(feature = "std")]
     pub exception: HashSet<u64>,
-    #[cfg(not(feature = "std"))]
-    pub exception: BTreeSet<u64>,
 }
 
 impl PslSets {
@@ -155,6 +168,38 @@ pub fn is_psl_initialized() -> bool {
     PSL_SETS.read().unwrap().is_some()
 }
 
+#[cfg(feature = "std")]
+fn with_psl<R>(f: impl FnOnce(Option<&PslSets>) -> R) -> R {
+    f(PSL_SETS.read().unwrap().as_ref())
+}
+
+// Without std there is no lock to guard replacement, so the sets are leaked
+// and published through an atomic pointer. Reloading the PSL leaks the
+// previous sets, which is fine for the load-once embedded use case.
+#[cfg(not(feature = "std"))]
+static PSL_SETS: core::sync::atomic::AtomicPtr<PslSets> =
+    core::sync::atomic::AtomicPtr::new(core::ptr::null_mut());
+
+/// Initialize PSL from sets.
+#[cfg(not(feature = "std"))]
+pub fn init_psl(sets: PslSets) {
+    let sets = Box::into_raw(Box::new(sets));
+    PSL_SETS.store(sets, core::sync::atomic::Ordering::Release);
+}
+
+/// Check if PSL is initialized.
+#[cfg(not(feature = "std"))]
+pub fn is_psl_initialized() -> bool {
+    !PSL_SETS.load(core::sync::atomic::Ordering::Acquire).is_null()
+}
+
+#[cfg(not(feature = "std"))]
+fn with_psl<R>(f: impl FnOnce(Option<&PslSets>) -> R) -> R {
+    let sets = PSL_SETS.load(core::sync::atomic::Ordering::Acquire);
+    // SAFETY: published by `init_psl` from `Box::into_raw` and never freed.
+    f(unsafe { sets.as_ref() })
+}
+
 // =============================================================================
 // eTLD+1 Extraction
 // =============================================================================
@@ -175,103 +220,108 @@ const COMMON_TWO_PART_TLDS: &[&str] = &[
 /// If PSL is not loaded, falls back to simple heuristic.
 #[cfg(feature = "std")]
 pub fn get_etld1(host: &str) -> String {
-    let host = host.to_lowercase();
+    let lowered;
+    let host = if host.bytes().any(|b| b.is_ascii_uppercase() || !b.is_ascii()) {
+        lowered = host.to_lowercase();
+        lowered.as_str()
+    } else {
+        host
+    };
     let host = host.trim_end_matches('.');
 
-    // Check cache
+    // Check cache: hits in the recent generation only need the read lock,
+    // older ones take the write lock to be promoted.
+    if let Some(cache) = ETLD1_CACHE.read().unwrap().as_ref() {
+        if let Some(cached) = cache.recent.get(host) {
+            return cached.clone();
+        }
+    }
     if let Some(ref mut cache) = *ETLD1_CACHE.write().unwrap() {
         if let Some(cached) = cache.get(host) {
             return cached.to_string();
         }
-    }
-
-    let result = compute_etld1(host);
-
-    // Store in cache
-    if let Some(ref mut cache) = *ETLD1_CACHE.write().unwrap() {
+        let result = get_etld1_ref(host).to_string();
         cache.insert(host.to_string(), result.clone());
+        return result;
     }
 
-    result
+    get_etld1_ref(host).to_string()
 }
 
-/// Compute eTLD+1 without caching.
-#[cfg(feature = "std")]
-fn compute_etld1(host: &str) -> String {
-    let labels: Vec<&str> = host.split('.').collect();
-    let n = labels.len();
-
-    if n <= 1 {
-        return host.to_string();
-    }
+/// Get the eTLD+1 (registrable domain) for a hostname.
+///
+/// If PSL is not loaded, falls back to simple heuristic. Uncached without std.
+#[cfg(not(feature = "std"))]
+pub fn get_etld1(host: &str) -> String {
+    let lowered;
+    let host = if host.bytes().any(|b| b.is_ascii_uppercase() || !b.is_ascii()) {
+        lowered = host.to_lowercase();
+        lowered.as_str()
+    } else {
+        host
+    };
+    get_etld1_ref(host).to_string()
+}
 
-    // Check PSL if available
-    if let Some(ref psl) = *PSL_SETS.read().unwrap() {
-        for i in 0..n - 1 {
-            let suffix: String = labels[i..].join(".");
-            let parent_suffix: String = if i + 1 < n {
-                labels[i + 1..].join(".")
-            } else {
-                String::new()
-            };
-
-            // Exception rules override wildcards
-            if psl.is_exception(&suffix) {
-                if i > 0 {
-                    return labels[i - 1..].join(".");
-                }
-                return suffix;
-            }
+/// The eTLD+1 (registrable domain) of `host`, as a slice of it.
+///
+/// Unlike [`get_etld1`] this never allocates: ASCII case is ignored when
+/// looking up suffixes but kept in the result, and a trailing dot is
+/// dropped. If PSL is not loaded, falls back to a simple heuristic.
+pub fn get_etld1_ref(host: &str) -> &str {
+    let host = host.trim_end_matches('.');
+    let start = with_psl(|psl| psl.and_then(|psl| psl_etld1_start(psl, host)))
+        .unwrap_or_else(|| host.len() - fallback_etld1_len(host));
+    &host[start..]
+}
 
-            // Exact rule
-            if psl.is_exact(&suffix) {
-                if i > 0 {
-                    return labels[i - 1..].join(".");
-                }
-                return host.to_string();
-            }
+/// Length of the eTLD+1 of `host`.
+pub fn etld1_len(host: &str) -> usize {
+    get_etld1_ref(host).len()
+}
 
-            // Wildcard rule on parent
-            if !parent_suffix.is_empty() && psl.is_wildcard(&parent_suffix) {
-                if i > 0 {
-                    return labels[i - 1..].join(".");
-                }
-                return suffix;
-            }
+/// Byte offset in `host` where its eTLD+1 starts according to `psl`, or
+/// `None` if no rule covers it.
+fn psl_etld1_start(psl: &PslSets, host: &str) -> Option<usize> {
+    // Start of the label before the suffix being checked
+    let mut previous = 0;
+    let mut label = 0;
+    loop {
+        // Single-label suffixes are left to the fallback.
+        let next = label + host[label..].find('.')? + 1;
+        let suffix = &host[label..];
+        let parent = &host[next..];
+        if psl.is_exception(suffix) || psl.is_exact(suffix) || (!parent.is_empty() && psl.is_wildcard(parent)) {
+            return Some(previous);
         }
+        previous = label;
+        label = next;
     }
-
-    // Fallback heuristic
-    fallback_etld1(&labels)
 }
 
-/// Fallback eTLD+1 heuristic.
-fn fallback_etld1(labels: &[&str]) -> String {
-    let n = labels.len();
-    if n <= 2 {
-        return labels.join(".");
-    }
-
-    // Check for common two-part TLDs
-    let last_two = format!("{}.{}", labels[n - 2], labels[n - 1]);
-    if COMMON_TWO_PART_TLDS.contains(&last_two.as_str()) {
-        return labels[n - 3..].join(".");
+/// Length of the eTLD+1 the fallback heuristic picks, without splitting
+/// `host`: the last two labels, or three under a common two-part TLD.
+fn fallback_etld1_len(host: &str) -> usize {
+    let mut dots = host.rmatch_indices('.').map(|(idx, _)| idx);
+    let (Some(_), Some(second)) = (dots.next(), dots.next()) else {
+        return host.len();
+    };
+
+    let last_two = &host[second + 1..];
+    if COMMON_TWO_PART_TLDS.iter().any(|tld| tld.eq_ignore_ascii_case(last_two)) {
+        return dots.next().map_or(host.len(), |third| host.len() - third - 1);
     }
-
-    // Default: last 2 labels
-    labels[n - 2..].join(".")
+    host.len() - second - 1
 }
 
 /// Check if two hosts share the same eTLD+1.
-#[cfg(feature = "std")]
 pub fn is_same_site(host1: &str, host2: &str) -> bool {
-    get_etld1(host1) == get_etld1(host2)
+    get_etld1_ref(host1).eq_ignore_ascii_case(get_etld1_ref(host2))
 }
 
 /// Check if a request is third-party.
-#[cfg(feature = "std")]
 pub fn is_third_party(site_host: &str, req_host: &str) -> bool {
-    get_etld1(site_host) != get_etld1(req_host)
+    !is_same_site(site_host, req_host)
 }
 
 /// Get the parent domain (strip leftmost label).
@@ -283,18 +333,18 @@ pub fn get_parent_domain(host: &str) -> Option<&str> {
 }
 
 /// Iterator for suffix-walking a host from full to eTLD+1.
+///
+/// Yields slices of the host, so walking allocates nothing.
 pub struct HostSuffixIter<'a> {
     current: &'a str,
     etld1_len: usize,
 }
 
 impl<'a> HostSuffixIter<'a> {
-    #[cfg(feature = "std")]
     pub fn new(host: &'a str) -> Self {
-        let etld1 = get_etld1(host);
         Self {
             current: host,
-            etld1_len: etld1.len(),
+            etld1_len: etld1_len(host),
         }
     }
 }
@@ -325,7 +375,6 @@ impl<'a> Iterator for HostSuffixIter<'a> {
 }
 
 /// Walk host suffixes from most specific to least specific.
-#[cfg(feature = "std")]
 pub fn walk_host_suffixes(host: &str) -> HostSuffixIter<'_> {
     HostSuffixIter::new(host)
 }
@@ -373,16 +422,57 @@ pub fn load_psl_from_bytes(data: &[u8], offset: usize) -> PslSets {
 mod tests {
     use super::*;
 
+    fn fallback_etld1(host: &str) -> &str {
+        &host[host.len() - fallback_etld1_len(host)..]
+    }
+
     #[test]
     fn test_fallback_etld1_simple() {
-        assert_eq!(fallback_etld1(&["example", "com"]), "example.com");
-        assert_eq!(fallback_etld1(&["sub", "example", "com"]), "example.com");
+        assert_eq!(fallback_etld1("example.com"), "example.com");
+        assert_eq!(fallback_etld1("sub.example.com"), "example.com");
+        assert_eq!(fallback_etld1("localhost"), "localhost");
     }
 
     #[test]
     fn test_fallback_etld1_two_part() {
-        assert_eq!(fallback_etld1(&["sub", "example", "co", "uk"]), "example.co.uk");
-        assert_eq!(fallback_etld1(&["example", "co", "uk"]), "example.co.uk");
+        assert_eq!(fallback_etld1("sub.example.co.uk"), "example.co.uk");
+        assert_eq!(fallback_etld1("example.co.uk"), "example.co.uk");
+        assert_eq!(fallback_etld1("Sub.Example.CO.UK"), "Example.CO.UK");
+        assert_eq!(fallback_etld1("co.uk"), "co.uk");
+    }
+
+    #[test]
+    fn test_psl_etld1_start() {
+        let mut psl = PslSets::new();
+        psl.exact.insert(hash_domain("com").to_u64());
+        psl.exact.insert(hash_domain("github.io").to_u64());
+        psl.wildcard.insert(hash_domain("ck").to_u64());
+
+        let etld1 = |host: &'static str| psl_etld1_start(&psl, host).map(|start| &host[start..]);
+        assert_eq!(etld1("a.b.user.github.io"), Some("user.github.io"));
+        assert_eq!(etld1("User.GitHub.io"), Some("User.GitHub.io"));
+        assert_eq!(etld1("shop.example.ck"), Some("shop.example.ck"));
+        // Single-label suffixes are left to the fallback.
+        assert_eq!(etld1("sub.example.com"), None);
+        assert_eq!(etld1("localhost"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_lru_cache_keeps_recent_entries() {
+        let mut cache = LruCache::new(4);
+        cache.insert("a".to_string(), "1".to_string());
+        cache.insert("b".to_string(), "2".to_string());
+        cache.insert("c".to_string(), "3".to_string());
+        // "a" and "b" aged into the older generation; touching "a" keeps it.
+        assert_eq!(cache.get("a"), Some("1"));
+        cache.insert("d".to_string(), "4".to_string());
+        cache.insert("e".to_string(), "5".to_string());
+
+        assert_eq!(cache.peek("a"), Some("1"));
+        assert_eq!(cache.peek("b"), None);
+        assert_eq!(cache.peek("e"), Some("5"));
+        assert!(cache.len() <= 4);
     }
 
     #[test]
@@ -0,0 +1,16 @@
+//! Regex filter compilation
+//!
+//! `/.../` filters are stored as source text and compiled when a matcher is
+//! created. The compiler uses the same entry point to reject sources the
+//! engine would not accept.
+
+pub use regex_lite::Regex;
+
+/// Compile a regex filter body. Filters are case-insensitive unless
+/// `$match-case` is set, like literal patterns.
+pub fn compile_filter_regex(source: &str, match_case: bool) -> Option<Regex> {
+    regex_lite::RegexBuilder::new(source)
+        .case_insensitive(!match_case)
+        .build()
+        .ok()
+}
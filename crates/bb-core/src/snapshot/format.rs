@@ -1,18 +1,82 @@
-//! UBX Snapshot Format v1 Constants
+//! UBX Snapshot Format Constants
 //!
 //! All values are little-endian.
 
-/// Magic bytes: "UBX1"
-pub const UBX_MAGIC: [u8; 4] = [0x55, 0x42, 0x58, 0x31];
+use crate::types::RequestType;
+
+/// Magic bytes written by the compiler: "UBX2"
+pub const UBX_MAGIC: [u8; 4] = [0x55, 0x42, 0x58, 0x32];
+
+/// Magic bytes of the v1 layout: "UBX1"
+pub const UBX_MAGIC_V1: [u8; 4] = [0x55, 0x42, 0x58, 0x31];
 
 /// Current format version
-pub const UBX_VERSION: u16 = 1;
+pub const UBX_VERSION: u16 = 5;
+
+/// Oldest format version the loader still reads. Sections whose contents
+/// changed since are decoded according to the snapshot's version.
+pub const UBX_MIN_VERSION: u16 = 1;
+
+/// First version written with the `UBX2` layout
+pub const UBX_LAYOUT_V2_VERSION: u16 = 4;
 
 /// Header size in bytes
 pub const HEADER_SIZE: usize = 64;
 
 /// Section directory entry size
-pub const SECTION_ENTRY_SIZE: usize = 24;
+pub const SECTION_ENTRY_SIZE: usize = 32;
+
+/// Section directory entry size in v1 snapshots
+pub const SECTION_ENTRY_SIZE_V1: usize = 24;
+
+/// Alignment of every section's start in v2 snapshots, so typed-array views
+/// of 64-bit data (hash tables, future SoA columns) need no copy.
+pub const SECTION_ALIGNMENT: usize = 8;
+
+/// Layout of the header and section directory, chosen by the magic bytes.
+///
+/// Section contents are the same in both; `version` covers those.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SnapshotLayout {
+    /// `UBX1`: u32 section offsets and lengths, sections 4-byte aligned
+    V1,
+    /// `UBX2`: u64 section offsets and lengths, alignment stored per section
+    #[default]
+    V2,
+}
+
+impl SnapshotLayout {
+    /// The layout `data` starts with, if it's a UBX snapshot.
+    pub fn from_magic(data: &[u8]) -> Option<Self> {
+        match data.get(..4)? {
+            magic if magic == UBX_MAGIC => Some(Self::V2),
+            magic if magic == UBX_MAGIC_V1 => Some(Self::V1),
+            _ => None,
+        }
+    }
+
+    pub const fn magic(self) -> [u8; 4] {
+        match self {
+            Self::V1 => UBX_MAGIC_V1,
+            Self::V2 => UBX_MAGIC,
+        }
+    }
+
+    pub const fn section_entry_size(self) -> usize {
+        match self {
+            Self::V1 => SECTION_ENTRY_SIZE_V1,
+            Self::V2 => SECTION_ENTRY_SIZE,
+        }
+    }
+
+    /// Largest offset or length a section directory entry can store.
+    pub const fn max_section_end(self) -> u64 {
+        match self {
+            Self::V1 => u32::MAX as u64,
+            Self::V2 => u64::MAX,
+        }
+    }
+}
 
 // =============================================================================
 // Header Field Offsets
@@ -20,7 +84,7 @@ pub const SECTION_ENTRY_SIZE: usize = 24;
 
 /// Header field byte offsets.
 pub mod header {
-    /// u8[4] magic = "UBX1"
+    /// u8[4] magic = "UBX2" ("UBX1" for the v1 layout)
     pub const MAGIC: usize = 0;
     /// u16 version
     pub const VERSION: usize = 4;
@@ -51,6 +115,24 @@ pub mod header_flags {
 // =============================================================================
 
 pub mod section_entry {
+    /// u16 section id
+    pub const ID: usize = 0;
+    /// u16 flags
+    pub const FLAGS: usize = 2;
+    /// u32 alignment of the section's offset, a power of two
+    pub const ALIGNMENT: usize = 4;
+    /// u64 file offset
+    pub const OFFSET: usize = 8;
+    /// u64 byte length
+    pub const LENGTH: usize = 16;
+    /// u32 uncompressed length (0 if not compressed)
+    pub const UNCOMPRESSED_LENGTH: usize = 24;
+    /// u32 CRC32 (0 if unused)
+    pub const CRC32: usize = 28;
+}
+
+/// Section directory entry offsets in v1 snapshots.
+pub mod section_entry_v1 {
     /// u16 section id
     pub const ID: usize = 0;
     /// u16 flags
@@ -69,6 +151,11 @@ pub mod section_entry {
 // Section IDs
 // =============================================================================
 
+/// Section ids embedders may use for their own data; see
+/// `Snapshot::get_custom_section`. Every id below belongs to the format,
+/// including ones this version doesn't know yet.
+pub const CUSTOM_SECTION_IDS: core::ops::RangeInclusive<u16> = 0x8000..=0xFFFF;
+
 /// Section type identifiers.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u16)]
@@ -105,6 +192,34 @@ pub enum SectionId {
     ProceduralRules = 0x000F,
     /// Scriptlet injection rules
     ScriptletRules = 0x0010,
+    /// Response body replace specifications
+    ReplaceSpecs = 0x0011,
+    /// Filter list header metadata
+    ListMeta = 0x0012,
+    /// Cosmetic rules with `:style()` declarations
+    CosmeticStyleRules = 0x0013,
+    /// Bloom filter over every token in the token dictionary
+    TokenBloom = 0x0014,
+    /// Sources of `/.../` regex filters
+    RegexPatterns = 0x0015,
+    /// $removeheader specifications
+    RemoveheaderSpecs = 0x0016,
+    /// Generic cosmetic rules bucketed by leading `#id` / `.class`
+    GenericCosmeticBuckets = 0x0017,
+    /// Every redirect resource the compiler accepted, with MIME type and checksum
+    RedirectRegistry = 0x0018,
+    /// Hash of each cosmetic rule's canonical selector, parallel to CosmeticRules
+    CosmeticSelectorHashes = 0x0019,
+    /// Compile time, compiler version and the source and content hash of each list
+    BuildInfo = 0x001A,
+    /// Source line of each cosmetic rule, parallel to CosmeticRules
+    CosmeticRuleLines = 0x001B,
+    /// Expiry time of every network rule with `$expires=`
+    RuleExpiry = 0x001C,
+    /// Network rules by the signature a `$badfilter` of them would carry
+    RuleSignatures = 0x001D,
+    /// `||host/path` pattern rules filed under the hash of their host
+    HostAnchoredRules = 0x001E,
 }
 
 impl TryFrom<u16> for SectionId {
@@ -128,11 +243,324 @@ impl TryFrom<u16> for SectionId {
             0x000E => Ok(Self::CosmeticRules),
             0x000F => Ok(Self::ProceduralRules),
             0x0010 => Ok(Self::ScriptletRules),
+            0x0011 => Ok(Self::ReplaceSpecs),
+            0x0012 => Ok(Self::ListMeta),
+            0x0013 => Ok(Self::CosmeticStyleRules),
+            0x0014 => Ok(Self::TokenBloom),
+            0x0015 => Ok(Self::RegexPatterns),
+            0x0016 => Ok(Self::RemoveheaderSpecs),
+            0x0017 => Ok(Self::GenericCosmeticBuckets),
+            0x0018 => Ok(Self::RedirectRegistry),
+            0x0019 => Ok(Self::CosmeticSelectorHashes),
+            0x001A => Ok(Self::BuildInfo),
+            0x001B => Ok(Self::CosmeticRuleLines),
+            0x001C => Ok(Self::RuleExpiry),
+            0x001D => Ok(Self::RuleSignatures),
+            0x001E => Ok(Self::HostAnchoredRules),
             _ => Err(()),
         }
     }
 }
 
+// =============================================================================
+// Replace Spec Layout
+// =============================================================================
+
+/// Replace spec entry size (regex off/len, replacement off/len, flags)
+pub const REPLACE_SPEC_ENTRY_SIZE: usize = 20;
+
+/// Replace spec flags.
+pub mod replace_flags {
+    /// Exception ($replace on an @@ rule)
+    pub const EXCEPTION: u32 = 1 << 0;
+    /// Regex flag `i`
+    pub const IGNORE_CASE: u32 = 1 << 1;
+    /// Regex flag `g`
+    pub const GLOBAL: u32 = 1 << 2;
+    /// Regex flag `m`
+    pub const MULTILINE: u32 = 1 << 3;
+    /// Regex flag `s`
+    pub const DOT_ALL: u32 = 1 << 4;
+}
+
+// =============================================================================
+// Header Spec Layout
+// =============================================================================
+
+/// Header spec entry size (name off/len, value off/len, flags)
+pub const HEADER_SPEC_ENTRY_SIZE: usize = 20;
+
+/// Header spec flags.
+pub mod header_spec_flags {
+    /// Match when the header or value is absent (`~` prefix)
+    pub const NEGATE: u32 = 1 << 0;
+    /// Match the outgoing request headers (`request:` prefix) rather than the response
+    pub const REQUEST: u32 = 1 << 1;
+}
+
+// =============================================================================
+// Removeheader Spec Layout
+// =============================================================================
+
+/// Removeheader spec entry size (name off/len, flags)
+pub const REMOVEHEADER_SPEC_ENTRY_SIZE: usize = 12;
+
+pub mod removeheader_spec_entry {
+    pub const NAME_OFF: usize = 0;
+    pub const NAME_LEN: usize = 4;
+    pub const FLAGS: usize = 8;
+}
+
+/// Removeheader spec flags.
+pub mod removeheader_flags {
+    /// Strip the header from the request (`request:` prefix) rather than the response
+    pub const REQUEST: u32 = 1 << 0;
+}
+
+// =============================================================================
+// List Metadata Layout
+// =============================================================================
+
+/// List metadata entry size
+pub const LIST_META_ENTRY_SIZE: usize = 32;
+
+/// List metadata entry field offsets. Strings live in the string pool;
+/// a zero length means the header was absent.
+pub mod list_meta_entry {
+    pub const LIST_ID: usize = 0;
+    /// [`crate::types::ListTier`], u16; 0 (built-in) in older snapshots
+    pub const TIER: usize = 2;
+    pub const EXPIRES_SECS: usize = 4;
+    pub const TITLE_OFF: usize = 8;
+    pub const TITLE_LEN: usize = 12;
+    pub const VERSION_OFF: usize = 16;
+    pub const VERSION_LEN: usize = 20;
+    pub const HOMEPAGE_OFF: usize = 24;
+    pub const HOMEPAGE_LEN: usize = 28;
+}
+
+// =============================================================================
+// Build Info Layout
+// =============================================================================
+
+/// Build info header size (timestamp, compiler version, list count)
+pub const BUILD_INFO_HEADER_SIZE: usize = 20;
+
+/// Build info header field offsets.
+pub mod build_info_header {
+    /// Compile time, unix seconds (u64)
+    pub const COMPILED_AT: usize = 0;
+    pub const COMPILER_VERSION_OFF: usize = 8;
+    pub const COMPILER_VERSION_LEN: usize = 12;
+    pub const LIST_COUNT: usize = 16;
+}
+
+/// Build info list entry size
+pub const BUILD_INFO_ENTRY_SIZE: usize = 20;
+
+/// Build info list entry field offsets. The source (path or URL) lives in
+/// the string pool; the content hash is `hash64` of the list text as read.
+pub mod build_info_entry {
+    pub const LIST_ID: usize = 0;
+    pub const SOURCE_OFF: usize = 4;
+    pub const SOURCE_LEN: usize = 8;
+    pub const CONTENT_HASH_LO: usize = 12;
+    pub const CONTENT_HASH_HI: usize = 16;
+}
+
+// =============================================================================
+// Cosmetic Style Layout
+// =============================================================================
+
+/// Cosmetic style entry size
+pub const COSMETIC_STYLE_ENTRY_SIZE: usize = 24;
+
+/// Cosmetic style entry field offsets. Flags use the same bits as cosmetic
+/// rules (bit 0 exception, bit 1 generic).
+pub mod cosmetic_style_entry {
+    pub const CONSTRAINT_OFFSET: usize = 0;
+    pub const SELECTOR_OFF: usize = 4;
+    pub const SELECTOR_LEN: usize = 8;
+    pub const STYLE_OFF: usize = 12;
+    pub const STYLE_LEN: usize = 16;
+    pub const FLAGS: usize = 20;
+    pub const LIST_ID: usize = 22;
+}
+
+// =============================================================================
+// Procedural Rules Layout
+// =============================================================================
+
+/// Procedural rule entry size (constraint offset, selector off/len, flags,
+/// list id), the same shape as cosmetic and scriptlet entries. The raw
+/// selector is what `#@#` exceptions compare against.
+///
+/// The `count` entries are followed by `count` programs of
+/// `PROCEDURAL_PROGRAM_ENTRY_SIZE`, in the same order, and then the op table
+/// of `PROCEDURAL_OP_ENTRY_SIZE` entries the programs index into.
+pub const PROCEDURAL_RULE_ENTRY_SIZE: usize = 16;
+
+/// Program entry size (base selector off/len, op count, first op index).
+/// A program with no ops failed to compile and is never applied.
+pub const PROCEDURAL_PROGRAM_ENTRY_SIZE: usize = 12;
+
+/// Op table entry size (argument off/len, `ProceduralOpKind`, reserved byte)
+pub const PROCEDURAL_OP_ENTRY_SIZE: usize = 8;
+
+pub mod procedural_rule_entry {
+    pub const CONSTRAINT_OFFSET: usize = 0;
+    pub const SELECTOR_OFF: usize = 4;
+    pub const SELECTOR_LEN: usize = 8;
+    pub const FLAGS: usize = 12;
+    pub const LIST_ID: usize = 14;
+}
+
+pub mod procedural_program_entry {
+    /// u32 string pool offset of the base selector (`*` when the filter has none)
+    pub const BASE_OFF: usize = 0;
+    /// u16 base selector length
+    pub const BASE_LEN: usize = 4;
+    /// u16 number of ops
+    pub const OP_COUNT: usize = 6;
+    /// u32 index of the first op in the op table
+    pub const FIRST_OP: usize = 8;
+}
+
+pub mod procedural_op_entry {
+    /// u32 string pool offset of the trimmed argument
+    pub const ARG_OFF: usize = 0;
+    /// u16 argument length
+    pub const ARG_LEN: usize = 4;
+    /// u8 `ProceduralOpKind`
+    pub const KIND: usize = 6;
+}
+
+// =============================================================================
+// Redirect Resources Layout
+// =============================================================================
+
+/// Redirect resource entry size (name off/len, path off/len, flags). Entries
+/// are indexed by a rule's option id. When any entry has `HAS_DATA_URL`, a
+/// table of `REDIRECT_DATA_ENTRY_SIZE` (off, len) string refs to `data:` URLs
+/// follows the entries, one per entry.
+pub const REDIRECT_RESOURCE_ENTRY_SIZE: usize = 20;
+
+/// Entry size of the trailing `data:` URL table
+pub const REDIRECT_DATA_ENTRY_SIZE: usize = 8;
+
+pub mod redirect_resource_entry {
+    pub const NAME_OFF: usize = 0;
+    pub const NAME_LEN: usize = 4;
+    pub const PATH_OFF: usize = 8;
+    pub const PATH_LEN: usize = 12;
+    pub const FLAGS: usize = 16;
+}
+
+/// Redirect resource flags.
+pub mod redirect_resource_flags {
+    /// The resource's `data:` URL is in the trailing table
+    pub const HAS_DATA_URL: u32 = 1 << 0;
+}
+
+// =============================================================================
+// Redirect Registry Layout
+// =============================================================================
+
+/// Redirect registry entry size (name, path and MIME type off/len, checksum, flags)
+pub const REDIRECT_REGISTRY_ENTRY_SIZE: usize = 32;
+
+pub mod redirect_registry_entry {
+    pub const NAME_OFF: usize = 0;
+    pub const NAME_LEN: usize = 4;
+    pub const PATH_OFF: usize = 8;
+    pub const PATH_LEN: usize = 12;
+    pub const MIME_OFF: usize = 16;
+    pub const MIME_LEN: usize = 20;
+    pub const CHECKSUM: usize = 24;
+    pub const FLAGS: usize = 28;
+}
+
+/// Redirect registry flags.
+pub mod redirect_registry_flags {
+    /// `CHECKSUM` holds the CRC32 of the bundled file
+    pub const HAS_CHECKSUM: u32 = 1 << 0;
+}
+
+// =============================================================================
+// Generic Cosmetic Bucket Layout
+// =============================================================================
+
+/// Generic cosmetic bucket entry size (key hash, cosmetic rule index).
+///
+/// Entries are sorted by key hash, where the key is `hash_token("#id")` or
+/// `hash_token(".class")` for the selector's leading id or class. The
+/// referenced cosmetic rules carry flag bit 2 and are left out of
+/// `match_cosmetics`, so pages only receive them when they use the key.
+pub const GENERIC_COSMETIC_BUCKET_ENTRY_SIZE: usize = 8;
+
+pub mod generic_cosmetic_bucket_entry {
+    pub const KEY_HASH: usize = 0;
+    pub const RULE_INDEX: usize = 4;
+}
+
+// =============================================================================
+// Cosmetic Selector Hash Layout
+// =============================================================================
+
+/// Cosmetic selector hash entry size.
+///
+/// A u32 count followed by `hash_token(selector)` for every CosmeticRules
+/// entry, in the same order. Selectors are normalized by the compiler, so
+/// `#@#` exceptions cancel hides by comparing hashes rather than text.
+pub const COSMETIC_SELECTOR_HASH_ENTRY_SIZE: usize = 4;
+
+/// Cosmetic rule line entry size.
+///
+/// A u32 count followed by the 1-based source line (0 if unknown) of every
+/// CosmeticRules entry, in the same order. Together with the entry's list id
+/// it attributes a hidden element to the filter that hid it.
+pub const COSMETIC_RULE_LINE_ENTRY_SIZE: usize = 4;
+
+// =============================================================================
+// Rule Expiry Layout
+// =============================================================================
+
+/// Rule expiry entry size.
+///
+/// A u32 count followed by one entry per rule that has an `$expires=`
+/// date, sorted by rule id. The section is omitted when no rule expires.
+pub const RULE_EXPIRY_ENTRY_SIZE: usize = 8;
+
+/// Rule expiry entry field offsets.
+pub mod rule_expiry_entry {
+    /// u32 index into the Rules section
+    pub const RULE_ID: usize = 0;
+    /// u32 unix time (seconds) from which the rule stops matching
+    pub const EXPIRES_AT: usize = 4;
+}
+
+// =============================================================================
+// Rule Signature Layout
+// =============================================================================
+
+/// Rule signature entry size.
+///
+/// A u32 count followed by one entry per network rule, sorted by signature
+/// (as `hi << 32 | lo`) and then rule id. Rules from different lists can
+/// share a signature. Lets a `$badfilter` entered at runtime find the rules
+/// it cancels without the filter lists.
+pub const RULE_SIGNATURE_ENTRY_SIZE: usize = 12;
+
+/// Rule signature entry field offsets.
+pub mod rule_signature_entry {
+    /// u32 low half of the signature hash
+    pub const SIG_LO: usize = 0;
+    /// u32 high half of the signature hash
+    pub const SIG_HI: usize = 4;
+    /// u32 index into the Rules section
+    pub const RULE_ID: usize = 8;
+}
+
 // =============================================================================
 // HashSet64 / HashMap64 Layout
 // =============================================================================
@@ -165,6 +593,97 @@ pub mod token_dict_entry {
     pub const RULE_COUNT: usize = 8;
 }
 
+// =============================================================================
+// Token Postings Layout
+// =============================================================================
+
+/// Each token's posting list is split by the request types its rules can
+/// match, so the matcher only decodes the buckets a request can hit:
+///
+/// - bucketMask: u8, bit `b` set when bucket `b` is non-empty
+/// - per set bucket, in bucket order: count varint, byteLen varint, then
+///   `count` varint deltas (restarting from 0 in each bucket)
+pub mod posting_bucket {
+    /// Rules whose types span more than one class, or have no type options
+    pub const ANY: u8 = 0;
+    pub const SCRIPT: u8 = 1;
+    pub const IMAGE: u8 = 2;
+    /// Main frames and subdocuments
+    pub const FRAME: u8 = 3;
+    pub const OTHER: u8 = 4;
+}
+
+/// Number of posting buckets
+pub const POSTING_BUCKET_COUNT: u8 = 5;
+
+/// Bucket for a rule's type mask, or for a request's single type bit.
+#[inline]
+pub fn posting_bucket_for(type_mask: u32) -> u8 {
+    const SCRIPT: u32 = RequestType::SCRIPT.bits();
+    const IMAGE: u32 = RequestType::IMAGE.bits();
+    const FRAME: u32 = RequestType::DOCUMENT.bits();
+    const OTHER: u32 = RequestType::ALL.bits() & !(SCRIPT | IMAGE | FRAME);
+
+    let mut bucket = posting_bucket::ANY;
+    for (class_mask, class) in [
+        (SCRIPT, posting_bucket::SCRIPT),
+        (IMAGE, posting_bucket::IMAGE),
+        (FRAME, posting_bucket::FRAME),
+        (OTHER, posting_bucket::OTHER),
+    ] {
+        if type_mask & class_mask != 0 {
+            if bucket != posting_bucket::ANY {
+                return posting_bucket::ANY;
+            }
+            bucket = class;
+        }
+    }
+    bucket
+}
+
+// =============================================================================
+// Token Bloom Layout
+// =============================================================================
+
+/// Token bloom header size (word count, hash count)
+pub const TOKEN_BLOOM_HEADER_SIZE: usize = 8;
+
+/// Bits reserved per token when sizing the bloom filter
+pub const TOKEN_BLOOM_BITS_PER_TOKEN: usize = 16;
+
+/// Bits set per token; all of them land in one 64-bit word
+pub const TOKEN_BLOOM_HASH_COUNT: u32 = 5;
+
+/// Word index and bit mask for a token in a blocked bloom filter with
+/// `word_mask + 1` 64-bit words.
+#[inline]
+pub fn token_bloom_probe(token_hash: u32, word_mask: usize, hash_count: u32) -> (usize, u64) {
+    let mut mixed = (token_hash ^ (token_hash >> 16)).wrapping_mul(0x85EB_CA6B);
+    mixed ^= mixed >> 13;
+
+    let mut bits = 0u64;
+    for i in 0..hash_count.min(5) {
+        bits |= 1u64 << ((mixed >> (i * 6)) & 63);
+    }
+    (token_hash as usize & word_mask, bits)
+}
+
+// =============================================================================
+// Regex Pattern Layout
+// =============================================================================
+
+/// Regex pattern entry size
+pub const REGEX_PATTERN_ENTRY_SIZE: usize = 16;
+
+/// Regex pattern entry field offsets. Regex rules have no pattern program;
+/// the entry points back at the rule by id. Flags use `pattern_flags` bits.
+pub mod regex_pattern_entry {
+    pub const RULE_ID: usize = 0;
+    pub const SOURCE_OFF: usize = 4;
+    pub const SOURCE_LEN: usize = 8;
+    pub const FLAGS: usize = 12;
+}
+
 // =============================================================================
 // Pattern Pool Layout
 // =============================================================================
@@ -181,6 +700,12 @@ pub mod pattern_entry {
     pub const HOST_HASH_HI: usize = 12;
 }
 
+/// Pattern entry flags.
+pub mod pattern_flags {
+    /// Literals keep their original case ($match-case)
+    pub const MATCH_CASE: u8 = 1 << 0;
+}
+
 /// Pattern anchor types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -252,10 +777,18 @@ pub const fn align_offset(offset: usize, alignment: usize) -> usize {
     (offset + alignment - 1) & !(alignment - 1)
 }
 
-/// Validate magic bytes.
+/// Cap an entry count read from a snapshot at the number of `entry_size`
+/// entries that fit in `data_len` bytes after a `header_size` header, so a
+/// corrupt count cannot drive huge allocations or long loops.
+#[inline]
+pub fn bounded_count(count: usize, data_len: usize, header_size: usize, entry_size: usize) -> usize {
+    count.min(data_len.saturating_sub(header_size) / entry_size)
+}
+
+/// Validate magic bytes, accepting either layout.
 #[inline]
 pub fn validate_magic(data: &[u8]) -> bool {
-    data.len() >= 4 && data[..4] == UBX_MAGIC
+    SnapshotLayout::from_magic(data).is_some()
 }
 
 /// Read u16 little-endian.
@@ -270,6 +803,14 @@ pub fn read_u32_le(data: &[u8], offset: usize) -> u32 {
     u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
 }
 
+/// Read u64 little-endian.
+#[inline]
+pub fn read_u64_le(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
 /// Read i16 little-endian.
 #[inline]
 pub fn read_i16_le(data: &[u8], offset: usize) -> i16 {
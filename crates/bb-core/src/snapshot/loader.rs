@@ -1,8 +1,17 @@
 //! Zero-copy UBX Snapshot Loader
 
+#[cfg(not(feature = "std"))]
+use alloc::{format, string::{String, ToString}, vec::Vec};
+
+#[cfg(feature = "std")]
 use std::collections::HashMap;
+#[cfg(not(feature = "std"))]
+use hashbrown::HashMap;
+
+use smallvec::SmallVec;
 
-use crate::hash::{Hash64, crc32};
+use crate::hash::{Hash64, crc32_parts};
+use crate::types::ListTier;
 use crate::psl::{load_psl_from_bytes, init_psl};
 use super::format::*;
 
@@ -19,6 +28,9 @@ pub enum SnapshotError {
     InvalidSection(String),
     #[error("Data too short")]
     DataTooShort,
+    #[cfg(feature = "mmap")]
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
 /// Section metadata.
@@ -28,6 +40,8 @@ pub struct SectionInfo {
     pub flags: u16,
     pub offset: usize,
     pub length: usize,
+    /// Alignment of `offset`; 4 in v1 snapshots
+    pub alignment: usize,
     pub uncompressed_length: usize,
     pub crc32: u32,
 }
@@ -35,27 +49,61 @@ pub struct SectionInfo {
 /// Zero-copy snapshot view.
 pub struct Snapshot<'a> {
     data: &'a [u8],
+    pub layout: SnapshotLayout,
     pub version: u16,
     pub flags: u16,
     pub build_id: u32,
     sections: HashMap<SectionId, SectionInfo>,
+    /// Embedders' sections by id, as (offset, length)
+    custom_sections: HashMap<u16, (usize, usize)>,
 }
 
 impl<'a> Snapshot<'a> {
     /// Load a snapshot from bytes.
     pub fn load(data: &'a [u8]) -> Result<Self, SnapshotError> {
+        let snapshot = Self::load_without_psl(data)?;
+
+        // Initialize PSL if present
+        if let Some(psl_section) = snapshot.get_section(SectionId::PslSets) {
+            init_psl(load_psl_from_bytes(psl_section, 0));
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Load a snapshot without touching the global PSL state.
+    ///
+    /// Useful for secondary snapshots (e.g. the one being replaced by a list
+    /// update) that must not override the active snapshot's suffix list.
+    pub fn load_without_psl(data: &'a [u8]) -> Result<Self, SnapshotError> {
+        Self::parse(data, true, true)
+    }
+
+    /// Load a snapshot for inspection whatever its version, e.g. one written
+    /// by a newer build. Sections are decoded as the nearest version this
+    /// build reads, so the result is best effort outside the supported range.
+    /// Doesn't touch the global PSL state.
+    pub fn load_any_version(data: &'a [u8]) -> Result<Self, SnapshotError> {
+        Self::parse(data, true, false)
+    }
+
+    /// Parse the header and section directory, optionally skipping the CRC
+    /// pass and the version check.
+    pub(crate) fn parse(data: &'a [u8], verify_crc: bool, check_version: bool) -> Result<Self, SnapshotError> {
         if data.len() < HEADER_SIZE {
             return Err(SnapshotError::DataTooShort);
         }
 
-        // Validate magic
-        if !validate_magic(data) {
-            return Err(SnapshotError::InvalidMagic);
-        }
+        let layout = SnapshotLayout::from_magic(data).ok_or(SnapshotError::InvalidMagic)?;
+        let entry_size = layout.section_entry_size();
 
         // Read header
         let version = read_u16_le(data, header::VERSION);
-        if version != UBX_VERSION {
+        let min_version = match layout {
+            SnapshotLayout::V1 => UBX_MIN_VERSION,
+            SnapshotLayout::V2 => UBX_LAYOUT_V2_VERSION,
+        };
+        if check_version && !(min_version..=UBX_VERSION).contains(&version) {
             return Err(SnapshotError::UnsupportedVersion(version));
         }
 
@@ -65,7 +113,7 @@ impl<'a> Snapshot<'a> {
         let build_id = read_u32_le(data, header::BUILD_ID);
 
         let section_dir_bytes = section_count
-            .checked_mul(SECTION_ENTRY_SIZE)
+            .checked_mul(entry_size)
             .ok_or_else(|| SnapshotError::InvalidSection("section directory overflow".to_string()))?;
         let section_dir_end = section_dir_offset
             .checked_add(section_dir_bytes)
@@ -74,15 +122,16 @@ impl<'a> Snapshot<'a> {
             return Err(SnapshotError::InvalidSection("section directory out of bounds".to_string()));
         }
 
-        // Validate CRC32 if present
-        if flags & header_flags::HAS_CRC32 != 0 {
+        // Validate CRC32 if present. Fuzz builds skip it so mutated inputs
+        // reach the section views instead of all failing here.
+        if verify_crc && !cfg!(fuzzing) && flags & header_flags::HAS_CRC32 != 0 {
             let stored_crc = read_u32_le(data, header::SNAPSHOT_CRC32);
             
             // Compute CRC over everything except the CRC field
-            let mut crc_data = Vec::with_capacity(data.len() - 4);
-            crc_data.extend_from_slice(&data[..header::SNAPSHOT_CRC32]);
-            crc_data.extend_from_slice(&data[header::SNAPSHOT_CRC32 + 4..]);
-            let computed_crc = crc32(&crc_data);
+            let computed_crc = crc32_parts(&[
+                &data[..header::SNAPSHOT_CRC32],
+                &data[header::SNAPSHOT_CRC32 + 4..],
+            ]);
             
             if stored_crc != computed_crc {
                 return Err(SnapshotError::Crc32Mismatch {
@@ -94,25 +143,58 @@ impl<'a> Snapshot<'a> {
 
         // Parse section directory
         let mut sections = HashMap::new();
+        let mut custom_sections = HashMap::new();
         for i in 0..section_count {
-            let entry_offset = section_dir_offset + i * SECTION_ENTRY_SIZE;
-            if entry_offset + SECTION_ENTRY_SIZE > data.len() {
+            let entry_offset = section_dir_offset + i * entry_size;
+            if entry_offset + entry_size > data.len() {
                 return Err(SnapshotError::InvalidSection("section directory entry out of bounds".to_string()));
             }
 
+            // Both layouts start entries with the id and flags.
             let id_raw = read_u16_le(data, entry_offset + section_entry::ID);
             let id = match SectionId::try_from(id_raw) {
                 Ok(id) => id,
+                Err(_) if CUSTOM_SECTION_IDS.contains(&id_raw) => {
+                    let (offset, length) = custom_section_bounds(data, layout, entry_offset, id_raw)?;
+                    custom_sections.insert(id_raw, (offset, length));
+                    continue;
+                }
                 Err(_) => continue, // Skip unknown sections
             };
 
-            let info = SectionInfo {
-                id,
-                flags: read_u16_le(data, entry_offset + section_entry::FLAGS),
-                offset: read_u32_le(data, entry_offset + section_entry::OFFSET) as usize,
-                length: read_u32_le(data, entry_offset + section_entry::LENGTH) as usize,
-                uncompressed_length: read_u32_le(data, entry_offset + section_entry::UNCOMPRESSED_LENGTH) as usize,
-                crc32: read_u32_le(data, entry_offset + section_entry::CRC32),
+            let info = match layout {
+                SnapshotLayout::V1 => SectionInfo {
+                    id,
+                    flags: read_u16_le(data, entry_offset + section_entry_v1::FLAGS),
+                    offset: read_u32_le(data, entry_offset + section_entry_v1::OFFSET) as usize,
+                    length: read_u32_le(data, entry_offset + section_entry_v1::LENGTH) as usize,
+                    alignment: 4,
+                    uncompressed_length: read_u32_le(data, entry_offset + section_entry_v1::UNCOMPRESSED_LENGTH)
+                        as usize,
+                    crc32: read_u32_le(data, entry_offset + section_entry_v1::CRC32),
+                },
+                SnapshotLayout::V2 => {
+                    // Past usize on 32-bit targets is also past the end of `data`.
+                    let wide = |field: usize| {
+                        usize::try_from(read_u64_le(data, entry_offset + field)).map_err(|_| {
+                            SnapshotError::InvalidSection(format!("section {:?} out of bounds", id))
+                        })
+                    };
+                    let info = SectionInfo {
+                        id,
+                        flags: read_u16_le(data, entry_offset + section_entry::FLAGS),
+                        offset: wide(section_entry::OFFSET)?,
+                        length: wide(section_entry::LENGTH)?,
+                        alignment: read_u32_le(data, entry_offset + section_entry::ALIGNMENT) as usize,
+                        uncompressed_length: read_u32_le(data, entry_offset + section_entry::UNCOMPRESSED_LENGTH)
+                            as usize,
+                        crc32: read_u32_le(data, entry_offset + section_entry::CRC32),
+                    };
+                    if !info.alignment.is_power_of_two() || !info.offset.is_multiple_of(info.alignment) {
+                        return Err(SnapshotError::InvalidSection(format!("section {:?} misaligned", id)));
+                    }
+                    info
+                }
             };
 
             let section_end = info
@@ -131,19 +213,16 @@ impl<'a> Snapshot<'a> {
 
         let snapshot = Self {
             data,
+            layout,
             version,
             flags,
             build_id,
             sections,
+            custom_sections,
         };
 
         snapshot.validate_strpool()?;
-
-        // Initialize PSL if present
-        if let Some(psl_section) = snapshot.sections.get(&SectionId::PslSets) {
-            let psl_sets = load_psl_from_bytes(data, psl_section.offset);
-            init_psl(psl_sets);
-        }
+        snapshot.validate_rules()?;
 
         Ok(snapshot)
     }
@@ -166,12 +245,21 @@ impl<'a> Snapshot<'a> {
         if bytes_end > section.len() {
             return Err(SnapshotError::InvalidSection("strpool length out of bounds".to_string()));
         }
-        if std::str::from_utf8(&section[4..bytes_end]).is_err() {
+        if core::str::from_utf8(&section[4..bytes_end]).is_err() {
             return Err(SnapshotError::InvalidSection("strpool utf8 invalid".to_string()));
         }
         Ok(())
     }
 
+    fn validate_rules(&self) -> Result<(), SnapshotError> {
+        match self.get_section(SectionId::Rules) {
+            Some(section) if RulesView::checked(section, self.version).is_none() => {
+                Err(SnapshotError::InvalidSection("rules arrays truncated".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn get_section(&self, id: SectionId) -> Option<&'a [u8]> {
         let info = self.sections.get(&id)?;
         if info.offset + info.length > self.data.len() {
@@ -180,6 +268,20 @@ impl<'a> Snapshot<'a> {
         Some(&self.data[info.offset..info.offset + info.length])
     }
 
+    /// Bytes of an embedder's section, attached at build time with an id in
+    /// [`CUSTOM_SECTION_IDS`].
+    pub fn get_custom_section(&self, id: u16) -> Option<&'a [u8]> {
+        let &(offset, length) = self.custom_sections.get(&id)?;
+        self.data.get(offset..offset + length)
+    }
+
+    /// Ids of the custom sections present, ascending.
+    pub fn custom_section_ids(&self) -> Vec<u16> {
+        let mut ids: Vec<u16> = self.custom_sections.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
     /// Get section info.
     pub fn get_section_info(&self, id: SectionId) -> Option<&SectionInfo> {
         self.sections.get(&id)
@@ -194,18 +296,15 @@ impl<'a> Snapshot<'a> {
         
         // First 4 bytes are the pool length
         let pool_data = &section[4..];
-        if offset + length > pool_data.len() {
-            return None;
-        }
-        
-        std::str::from_utf8(&pool_data[offset..offset + length]).ok()
+        let end = offset.checked_add(length)?;
+        core::str::from_utf8(pool_data.get(offset..end)?).ok()
     }
 
     /// Get domain block set view.
     pub fn domain_block_set(&self) -> DomainHashSet<'a> {
         self.get_section(SectionId::DomainSets)
             .map(|data| DomainHashSet::new(data, 0))
-            .unwrap_or_else(|| DomainHashSet::empty())
+            .unwrap_or_else(DomainHashSet::empty)
     }
 
     /// Get domain allow set view.
@@ -213,29 +312,19 @@ impl<'a> Snapshot<'a> {
         self.get_section(SectionId::DomainSets)
             .map(|data| {
                 // Allow set is after block set
-                let block_capacity = read_u32_le(data, 0) as usize;
-                let block_size = HASHMAP64_HEADER_SIZE + block_capacity * HASHMAP64_ENTRY_SIZE;
-                if block_size < data.len() {
-                    DomainHashSet::new(data, block_size)
-                } else {
-                    DomainHashSet::empty()
+                match hashmap64_bytes(data, 0) {
+                    Some(block_size) if block_size < data.len() => DomainHashSet::new(data, block_size),
+                    _ => DomainHashSet::empty(),
                 }
             })
-            .unwrap_or_else(|| DomainHashSet::empty())
+            .unwrap_or_else(DomainHashSet::empty)
     }
 
     pub fn domain_postings(&self) -> Option<&'a [u8]> {
         let data = self.get_section(SectionId::DomainSets)?;
-        let block_capacity = read_u32_le(data, 0) as usize;
-        let block_size = HASHMAP64_HEADER_SIZE + block_capacity * HASHMAP64_ENTRY_SIZE;
-        if block_size + 4 > data.len() {
-            return None;
-        }
-
-        let allow_capacity = read_u32_le(data, block_size) as usize;
-        let allow_size = HASHMAP64_HEADER_SIZE + allow_capacity * HASHMAP64_ENTRY_SIZE;
-        let postings_offset = block_size + allow_size;
-        if postings_offset + 4 > data.len() {
+        let block_size = hashmap64_bytes(data, 0)?;
+        let postings_offset = block_size.checked_add(hashmap64_bytes(data, block_size)?)?;
+        if postings_offset.checked_add(4)? > data.len() {
             return None;
         }
 
@@ -245,11 +334,45 @@ impl<'a> Snapshot<'a> {
         Some(&data[start..start + len.min(available)])
     }
 
+    /// Host hash to bucketed postings of the `||host/path` rules filed
+    /// under it. Empty for snapshots built before the section existed,
+    /// which post those rules under a URL token instead.
+    pub fn host_anchored_rules(&self) -> DomainHashSet<'a> {
+        self.get_section(SectionId::HostAnchoredRules)
+            .map(|data| DomainHashSet::new(data, 0))
+            .unwrap_or_else(DomainHashSet::empty)
+    }
+
+    /// Postings the [`host_anchored_rules`](Self::host_anchored_rules)
+    /// values point into.
+    pub fn host_anchored_postings(&self) -> &'a [u8] {
+        let Some(data) = self.get_section(SectionId::HostAnchoredRules) else {
+            return &[];
+        };
+        let Some(postings_offset) = hashmap64_bytes(data, 0) else {
+            return &[];
+        };
+        if postings_offset.saturating_add(4) > data.len() {
+            return &[];
+        }
+        let len = read_u32_le(data, postings_offset) as usize;
+        let start = postings_offset + 4;
+        &data[start..start + len.min(data.len() - start)]
+    }
+
     /// Get token dictionary view.
     pub fn token_dict(&self) -> TokenDict<'a> {
         self.get_section(SectionId::TokenDict)
-            .map(|data| TokenDict::new(data))
-            .unwrap_or_else(|| TokenDict::empty())
+            .map(TokenDict::new)
+            .unwrap_or_else(TokenDict::empty)
+    }
+
+    /// Get token bloom filter view. Snapshots without one get a filter
+    /// that passes every token.
+    pub fn token_bloom(&self) -> TokenBloom<'a> {
+        self.get_section(SectionId::TokenBloom)
+            .map(TokenBloom::new)
+            .unwrap_or_else(TokenBloom::empty)
     }
 
     /// Get token postings data.
@@ -266,18 +389,30 @@ impl<'a> Snapshot<'a> {
             .unwrap_or(&[])
     }
 
+    /// Append the postings of a token dictionary entry that a request in
+    /// `bucket` can match (see [`append_bucketed_postings`]). Snapshots
+    /// before v3 store one unbucketed list per token, which is appended whole.
+    pub fn append_token_postings(&self, entry: &TokenEntry, bucket: u8, out: &mut PostingBuf) {
+        let postings = self.token_postings();
+        if self.version < 3 {
+            decode_posting_list_extend(postings, entry.postings_offset, entry.rule_count, out);
+        } else {
+            append_bucketed_postings(postings, entry.postings_offset, bucket, out);
+        }
+    }
+
     /// Get pattern pool view.
     pub fn pattern_pool(&self) -> PatternPool<'a> {
         self.get_section(SectionId::PatternPool)
-            .map(|data| PatternPool::new(data))
-            .unwrap_or_else(|| PatternPool::empty())
+            .map(PatternPool::new)
+            .unwrap_or_else(PatternPool::empty)
     }
 
     /// Get rules view.
     pub fn rules(&self) -> RulesView<'a> {
         self.get_section(SectionId::Rules)
-            .map(|data| RulesView::new(data))
-            .unwrap_or_else(|| RulesView::empty())
+            .map(|data| RulesView::new(data, self.version))
+            .unwrap_or_else(RulesView::empty)
     }
 
     /// Get domain constraints data.
@@ -298,6 +433,10 @@ impl<'a> Snapshot<'a> {
         self.get_section(SectionId::RemoveparamSpecs).unwrap_or(&[])
     }
 
+    pub fn removeheader_specs(&self) -> &'a [u8] {
+        self.get_section(SectionId::RemoveheaderSpecs).unwrap_or(&[])
+    }
+
     pub fn csp_specs(&self) -> &'a [u8] {
         self.get_section(SectionId::CspSpecs).unwrap_or(&[])
     }
@@ -306,6 +445,10 @@ impl<'a> Snapshot<'a> {
         self.get_section(SectionId::HeaderSpecs).unwrap_or(&[])
     }
 
+    pub fn replace_specs(&self) -> &'a [u8] {
+        self.get_section(SectionId::ReplaceSpecs).unwrap_or(&[])
+    }
+
     pub fn responseheader_rules(&self) -> &'a [u8] {
         self.get_section(SectionId::ResponseHeaderRules).unwrap_or(&[])
     }
@@ -314,6 +457,22 @@ impl<'a> Snapshot<'a> {
         self.get_section(SectionId::CosmeticRules).unwrap_or(&[])
     }
 
+    pub fn generic_cosmetic_buckets(&self) -> &'a [u8] {
+        self.get_section(SectionId::GenericCosmeticBuckets).unwrap_or(&[])
+    }
+
+    pub fn cosmetic_selector_hashes(&self) -> &'a [u8] {
+        self.get_section(SectionId::CosmeticSelectorHashes).unwrap_or(&[])
+    }
+
+    pub fn cosmetic_rule_lines(&self) -> &'a [u8] {
+        self.get_section(SectionId::CosmeticRuleLines).unwrap_or(&[])
+    }
+
+    pub fn cosmetic_style_rules(&self) -> &'a [u8] {
+        self.get_section(SectionId::CosmeticStyleRules).unwrap_or(&[])
+    }
+
     pub fn procedural_rules(&self) -> &'a [u8] {
         self.get_section(SectionId::ProceduralRules).unwrap_or(&[])
     }
@@ -321,6 +480,255 @@ impl<'a> Snapshot<'a> {
     pub fn scriptlet_rules(&self) -> &'a [u8] {
         self.get_section(SectionId::ScriptletRules).unwrap_or(&[])
     }
+
+    /// `(rule_id, expires_at)` for every rule with an `$expires=` date, by
+    /// rule id. `expires_at` is unix seconds.
+    pub fn rule_expiry(&self) -> impl Iterator<Item = (u32, u32)> + 'a {
+        let section = self.get_section(SectionId::RuleExpiry).unwrap_or(&[]);
+        let count = if section.len() >= 4 {
+            bounded_count(read_u32_le(section, 0) as usize, section.len(), 4, RULE_EXPIRY_ENTRY_SIZE)
+        } else {
+            0
+        };
+        (0..count).map(move |i| {
+            let base = 4 + i * RULE_EXPIRY_ENTRY_SIZE;
+            (
+                read_u32_le(section, base + rule_expiry_entry::RULE_ID),
+                read_u32_le(section, base + rule_expiry_entry::EXPIRES_AT),
+            )
+        })
+    }
+
+    /// Whether the snapshot has a RuleSignatures section.
+    pub fn has_rule_signatures(&self) -> bool {
+        self.get_section(SectionId::RuleSignatures).is_some()
+    }
+
+    /// Ids of the network rules with `signature`, ascending.
+    pub fn rules_with_signature(&self, signature: Hash64) -> Vec<u32> {
+        let section = self.get_section(SectionId::RuleSignatures).unwrap_or(&[]);
+        let count = if section.len() >= 4 {
+            bounded_count(read_u32_le(section, 0) as usize, section.len(), 4, RULE_SIGNATURE_ENTRY_SIZE)
+        } else {
+            0
+        };
+        let key = |i: usize| {
+            let base = 4 + i * RULE_SIGNATURE_ENTRY_SIZE;
+            Hash64::new(
+                read_u32_le(section, base + rule_signature_entry::SIG_LO),
+                read_u32_le(section, base + rule_signature_entry::SIG_HI),
+            )
+            .to_u64()
+        };
+        let target = signature.to_u64();
+
+        // Lower bound, then every entry sharing the signature.
+        let (mut lo, mut hi) = (0, count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if key(mid) < target {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        (lo..count)
+            .take_while(|&i| key(i) == target)
+            .map(|i| read_u32_le(section, 4 + i * RULE_SIGNATURE_ENTRY_SIZE + rule_signature_entry::RULE_ID))
+            .collect()
+    }
+
+    /// Get the regex filter sources section.
+    pub fn regex_patterns(&self) -> &'a [u8] {
+        self.get_section(SectionId::RegexPatterns).unwrap_or(&[])
+    }
+
+    /// Redirect resources the snapshot was compiled against, so the
+    /// extension can check its bundled files match.
+    pub fn redirect_resources(&self) -> impl Iterator<Item = RedirectResourceInfo<'a>> + '_ {
+        let section = self.get_section(SectionId::RedirectRegistry).unwrap_or(&[]);
+        let count = if section.len() >= 4 { read_u32_le(section, 0) as usize } else { 0 };
+
+        (0..count).map_while(move |i| {
+            let base = 4 + i * REDIRECT_REGISTRY_ENTRY_SIZE;
+            if base + REDIRECT_REGISTRY_ENTRY_SIZE > section.len() {
+                return None;
+            }
+            let string_at = |off: usize, len: usize| {
+                self.get_string(
+                    read_u32_le(section, base + off) as usize,
+                    read_u32_le(section, base + len) as usize,
+                )
+            };
+            let flags = read_u32_le(section, base + redirect_registry_entry::FLAGS);
+
+            Some(RedirectResourceInfo {
+                name: string_at(redirect_registry_entry::NAME_OFF, redirect_registry_entry::NAME_LEN)?,
+                path: string_at(redirect_registry_entry::PATH_OFF, redirect_registry_entry::PATH_LEN)?,
+                mime: string_at(redirect_registry_entry::MIME_OFF, redirect_registry_entry::MIME_LEN)?,
+                checksum: (flags & redirect_registry_flags::HAS_CHECKSUM != 0)
+                    .then(|| read_u32_le(section, base + redirect_registry_entry::CHECKSUM)),
+            })
+        })
+    }
+
+    /// Get header metadata (`! Title:`, `! Expires:`, ...) for a list.
+    pub fn list_metadata(&self, list_id: u16) -> Option<ListMeta<'a>> {
+        self.all_list_metadata().into_iter().find(|meta| meta.list_id == list_id)
+    }
+
+    /// Get header metadata for every list that has any.
+    pub fn all_list_metadata(&self) -> Vec<ListMeta<'a>> {
+        let Some(section) = self.get_section(SectionId::ListMeta) else {
+            return Vec::new();
+        };
+        if section.len() < 4 {
+            return Vec::new();
+        }
+        let count = bounded_count(read_u32_le(section, 0) as usize, section.len(), 4, LIST_META_ENTRY_SIZE);
+
+        let mut lists = Vec::with_capacity(count);
+        for i in 0..count {
+            let base = 4 + i * LIST_META_ENTRY_SIZE;
+            if base + LIST_META_ENTRY_SIZE > section.len() {
+                break;
+            }
+
+            let string_at = |off: usize, len: usize| {
+                let len = read_u32_le(section, base + len) as usize;
+                if len == 0 {
+                    return None;
+                }
+                self.get_string(read_u32_le(section, base + off) as usize, len)
+            };
+            let expires_secs = read_u32_le(section, base + list_meta_entry::EXPIRES_SECS);
+
+            lists.push(ListMeta {
+                list_id: read_u16_le(section, base + list_meta_entry::LIST_ID),
+                title: string_at(list_meta_entry::TITLE_OFF, list_meta_entry::TITLE_LEN),
+                version: string_at(list_meta_entry::VERSION_OFF, list_meta_entry::VERSION_LEN),
+                homepage: string_at(list_meta_entry::HOMEPAGE_OFF, list_meta_entry::HOMEPAGE_LEN),
+                expires_secs: (expires_secs != 0).then_some(expires_secs),
+                tier: u8::try_from(read_u16_le(section, base + list_meta_entry::TIER))
+                    .ok()
+                    .and_then(|tier| ListTier::try_from(tier).ok())
+                    .unwrap_or_default(),
+            });
+        }
+
+        lists
+    }
+
+    /// Get the compile time, compiler version and per-list provenance, if
+    /// the snapshot was built with them.
+    pub fn build_info(&self) -> Option<BuildMeta<'a>> {
+        let section = self.get_section(SectionId::BuildInfo)?;
+        if section.len() < BUILD_INFO_HEADER_SIZE {
+            return None;
+        }
+
+        let compiled_at = read_u32_le(section, build_info_header::COMPILED_AT) as u64
+            | (read_u32_le(section, build_info_header::COMPILED_AT + 4) as u64) << 32;
+        let compiler_version = self.get_string(
+            read_u32_le(section, build_info_header::COMPILER_VERSION_OFF) as usize,
+            read_u32_le(section, build_info_header::COMPILER_VERSION_LEN) as usize,
+        )?;
+        let count = bounded_count(
+            read_u32_le(section, build_info_header::LIST_COUNT) as usize,
+            section.len(),
+            BUILD_INFO_HEADER_SIZE,
+            BUILD_INFO_ENTRY_SIZE,
+        );
+
+        let mut lists = Vec::with_capacity(count);
+        for i in 0..count {
+            let base = BUILD_INFO_HEADER_SIZE + i * BUILD_INFO_ENTRY_SIZE;
+            if base + BUILD_INFO_ENTRY_SIZE > section.len() {
+                break;
+            }
+            let Some(source) = self.get_string(
+                read_u32_le(section, base + build_info_entry::SOURCE_OFF) as usize,
+                read_u32_le(section, base + build_info_entry::SOURCE_LEN) as usize,
+            ) else {
+                continue;
+            };
+            lists.push(ListSourceMeta {
+                list_id: read_u16_le(section, base + build_info_entry::LIST_ID),
+                source,
+                content_hash: Hash64::new(
+                    read_u32_le(section, base + build_info_entry::CONTENT_HASH_LO),
+                    read_u32_le(section, base + build_info_entry::CONTENT_HASH_HI),
+                ),
+            });
+        }
+
+        Some(BuildMeta {
+            compiled_at,
+            compiler_version,
+            lists,
+        })
+    }
+}
+
+// =============================================================================
+// Build Info
+// =============================================================================
+
+/// Provenance recorded by the compiler, borrowed from the string pool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildMeta<'a> {
+    /// Compile time, unix seconds
+    pub compiled_at: u64,
+    /// `bb-compiler` crate version
+    pub compiler_version: &'a str,
+    pub lists: Vec<ListSourceMeta<'a>>,
+}
+
+/// Where one list came from and a hash of the text it was compiled from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListSourceMeta<'a> {
+    pub list_id: u16,
+    /// Path or URL the list was read from
+    pub source: &'a str,
+    pub content_hash: Hash64,
+}
+
+impl ListSourceMeta<'_> {
+    /// The content hash as 16 hex digits, high half first.
+    pub fn content_hash_hex(&self) -> String {
+        format!("{:08x}{:08x}", self.content_hash.hi, self.content_hash.lo)
+    }
+}
+
+// =============================================================================
+// List Metadata
+// =============================================================================
+
+/// Filter list header metadata, borrowed from the string pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListMeta<'a> {
+    pub list_id: u16,
+    pub title: Option<&'a str>,
+    pub version: Option<&'a str>,
+    pub homepage: Option<&'a str>,
+    /// Refresh interval from `! Expires:`, in seconds
+    pub expires_secs: Option<u32>,
+    pub tier: ListTier,
+}
+
+// =============================================================================
+// Redirect Resources
+// =============================================================================
+
+/// A redirect resource from the RedirectRegistry section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectResourceInfo<'a> {
+    pub name: &'a str,
+    /// Path under the extension's `resources/` directory
+    pub path: &'a str,
+    pub mime: &'a str,
+    /// CRC32 of the bundled file
+    pub checksum: Option<u32>,
 }
 
 // =============================================================================
@@ -336,10 +744,11 @@ pub struct DomainHashSet<'a> {
 
 impl<'a> DomainHashSet<'a> {
     fn new(data: &'a [u8], offset: usize) -> Self {
-        let capacity = if offset + 4 <= data.len() {
-            read_u32_le(data, offset) as usize
-        } else {
-            0
+        // A table that is not a power of two or overruns the section is
+        // treated as empty rather than probed out of bounds.
+        let capacity = match hashmap64_bytes(data, offset) {
+            Some(size) if offset + size <= data.len() => read_u32_le(data, offset) as usize,
+            _ => 0,
         };
         Self { data, offset, capacity }
     }
@@ -388,6 +797,24 @@ impl<'a> DomainHashSet<'a> {
         self.lookup(hash).is_some()
     }
 
+    /// Iterate occupied slots as (hash, value) pairs, in slot order.
+    pub fn entries(&self) -> impl Iterator<Item = (Hash64, u32)> + 'a {
+        let data = self.data;
+        let entries_offset = self.offset + HASHMAP64_HEADER_SIZE;
+        (0..self.capacity).filter_map(move |idx| {
+            let entry_offset = entries_offset + idx * HASHMAP64_ENTRY_SIZE;
+            if entry_offset + HASHMAP64_ENTRY_SIZE > data.len() {
+                return None;
+            }
+            let lo = read_u32_le(data, entry_offset);
+            let hi = read_u32_le(data, entry_offset + 4);
+            if lo == 0 && hi == 0 {
+                return None;
+            }
+            Some((Hash64 { lo, hi }, read_u32_le(data, entry_offset + 8)))
+        })
+    }
+
     pub fn capacity(&self) -> usize {
         self.capacity
     }
@@ -400,6 +827,51 @@ impl<'a> DomainHashSet<'a> {
     }
 }
 
+/// (offset, length) of the custom section whose directory entry is at
+/// `entry_offset`, checked against `data` like the built-in sections.
+fn custom_section_bounds(
+    data: &[u8],
+    layout: SnapshotLayout,
+    entry_offset: usize,
+    id: u16,
+) -> Result<(usize, usize), SnapshotError> {
+    let out_of_bounds = || SnapshotError::InvalidSection(format!("custom section {:#06x} out of bounds", id));
+    let (offset, length) = match layout {
+        SnapshotLayout::V1 => (
+            read_u32_le(data, entry_offset + section_entry_v1::OFFSET) as usize,
+            read_u32_le(data, entry_offset + section_entry_v1::LENGTH) as usize,
+        ),
+        SnapshotLayout::V2 => {
+            let wide = |field: usize| usize::try_from(read_u64_le(data, entry_offset + field)).map_err(|_| out_of_bounds());
+            let (offset, length) = (wide(section_entry::OFFSET)?, wide(section_entry::LENGTH)?);
+            let alignment = read_u32_le(data, entry_offset + section_entry::ALIGNMENT) as usize;
+            if !alignment.is_power_of_two() || !offset.is_multiple_of(alignment) {
+                return Err(SnapshotError::InvalidSection(format!("custom section {:#06x} misaligned", id)));
+            }
+            (offset, length)
+        }
+    };
+    match offset.checked_add(length) {
+        Some(end) if end <= data.len() => Ok((offset, length)),
+        _ => Err(out_of_bounds()),
+    }
+}
+
+/// Size in bytes of the HashMap64 table at `offset`, header included.
+/// `None` if the capacity is unreadable, not a power of two or overflows.
+fn hashmap64_bytes(data: &[u8], offset: usize) -> Option<usize> {
+    if offset.checked_add(4)? > data.len() {
+        return None;
+    }
+    let capacity = read_u32_le(data, offset) as usize;
+    if capacity != 0 && !capacity.is_power_of_two() {
+        return None;
+    }
+    capacity
+        .checked_mul(HASHMAP64_ENTRY_SIZE)?
+        .checked_add(HASHMAP64_HEADER_SIZE)
+}
+
 // =============================================================================
 // Token Dictionary View
 // =============================================================================
@@ -425,6 +897,13 @@ impl<'a> TokenDict<'a> {
         } else {
             0
         };
+        let fits = capacity
+            .checked_mul(TOKEN_DICT_ENTRY_SIZE)
+            .and_then(|size| size.checked_add(TOKEN_DICT_HEADER_SIZE))
+            .is_some_and(|size| size <= data.len());
+        if !capacity.is_power_of_two() || !fits {
+            return Self::empty();
+        }
         Self { data, capacity }
     }
 
@@ -471,6 +950,57 @@ impl<'a> TokenDict<'a> {
     }
 }
 
+// =============================================================================
+// Token Bloom View
+// =============================================================================
+
+/// Zero-copy view into the token bloom filter.
+#[derive(Clone, Copy)]
+pub struct TokenBloom<'a> {
+    words: &'a [u8],
+    word_mask: usize,
+    hash_count: u32,
+}
+
+impl<'a> TokenBloom<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        if data.len() < TOKEN_BLOOM_HEADER_SIZE {
+            return Self::empty();
+        }
+        let word_count = read_u32_le(data, 0) as usize;
+        let hash_count = read_u32_le(data, 4);
+        let words = &data[TOKEN_BLOOM_HEADER_SIZE..];
+        if !word_count.is_power_of_two() || word_count > words.len() / 8 || hash_count == 0 {
+            return Self::empty();
+        }
+        Self {
+            words: &words[..word_count * 8],
+            word_mask: word_count - 1,
+            hash_count,
+        }
+    }
+
+    fn empty() -> Self {
+        Self { words: &[], word_mask: 0, hash_count: 0 }
+    }
+
+    /// False only if the token is definitely not in the token dictionary.
+    #[inline]
+    pub fn may_contain(&self, token_hash: u32) -> bool {
+        if self.words.is_empty() {
+            return true;
+        }
+        let (word, bits) = token_bloom_probe(token_hash, self.word_mask, self.hash_count);
+        let offset = word * 8;
+        let value = u64::from_le_bytes(self.words[offset..offset + 8].try_into().unwrap());
+        value & bits == bits
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+}
+
 // =============================================================================
 // Pattern Pool View
 // =============================================================================
@@ -499,7 +1029,7 @@ impl<'a> PatternPool<'a> {
             return Self::empty();
         }
 
-        let pattern_count = read_u32_le(data, 0) as usize;
+        let pattern_count = bounded_count(read_u32_le(data, 0) as usize, data.len(), 4, PATTERN_INDEX_ENTRY_SIZE);
         let index_size = pattern_count * PATTERN_INDEX_ENTRY_SIZE;
         let prog_bytes_offset = 4 + index_size + 4; // +4 for prog_bytes_len
 
@@ -533,13 +1063,10 @@ impl<'a> PatternPool<'a> {
 
     /// Get program bytes for a pattern.
     pub fn get_program(&self, entry: &PatternEntry) -> &'a [u8] {
-        let start = self.prog_bytes_offset + entry.prog_offset;
-        let end = start + entry.prog_len;
-        if end <= self.data.len() {
-            &self.data[start..end]
-        } else {
-            &[]
-        }
+        self.prog_bytes_offset
+            .checked_add(entry.prog_offset)
+            .and_then(|start| self.data.get(start..start.checked_add(entry.prog_len)?))
+            .unwrap_or(&[])
     }
 }
 
@@ -554,6 +1081,8 @@ pub struct RulesView<'a> {
     // Precomputed offsets for each array
     action_offset: usize,
     flags_offset: usize,
+    /// Flags are u32 since v4, u16 before
+    flags_size: usize,
     type_mask_offset: usize,
     party_mask_offset: usize,
     scheme_mask_offset: usize,
@@ -565,49 +1094,47 @@ pub struct RulesView<'a> {
 }
 
 impl<'a> RulesView<'a> {
-    fn new(data: &'a [u8]) -> Self {
+    fn new(data: &'a [u8], version: u16) -> Self {
+        Self::checked(data, version).unwrap_or_else(Self::empty)
+    }
+
+    /// The view, or `None` when the count is missing or the arrays it
+    /// implies run past the end of the section.
+    fn checked(data: &'a [u8], version: u16) -> Option<Self> {
         if data.len() < 4 {
-            return Self::empty();
+            return None;
         }
 
         let count = read_u32_le(data, 0) as usize;
+        // Each array starts after the previous one, aligned for its element size.
         let mut offset = 4;
+        let mut array = |elem_size: usize, align: usize| -> Option<usize> {
+            let start = align_offset(offset, align);
+            offset = start.checked_add(count.checked_mul(elem_size)?)?;
+            Some(start)
+        };
 
-        // Calculate offsets for each array
-        let action_offset = offset;
-        offset = align_offset(offset + count, 2);
-
-        let flags_offset = offset;
-        offset = align_offset(offset + count * 2, 4);
-
-        let type_mask_offset = offset;
-        offset += count * 4;
-
-        let party_mask_offset = offset;
-        offset = align_offset(offset + count, 1);
-
-        let scheme_mask_offset = offset;
-        offset = align_offset(offset + count, 4);
-
-        let pattern_id_offset = offset;
-        offset += count * 4;
-
-        let domain_constraint_offset = offset;
-        offset += count * 4;
-
-        let option_id_offset = offset;
-        offset += count * 4;
-
-        let priority_offset = offset;
-        offset = align_offset(offset + count * 2, 2);
-
-        let list_id_offset = offset;
+        let action_offset = array(1, 1)?;
+        let flags_size = if version >= 4 { 4 } else { 2 };
+        let flags_offset = array(flags_size, flags_size)?;
+        let type_mask_offset = array(4, 4)?;
+        let party_mask_offset = array(1, 1)?;
+        let scheme_mask_offset = array(1, 1)?;
+        let pattern_id_offset = array(4, 4)?;
+        let domain_constraint_offset = array(4, 4)?;
+        let option_id_offset = array(4, 4)?;
+        let priority_offset = array(2, 2)?;
+        let list_id_offset = array(2, 2)?;
+        if offset > data.len() {
+            return None;
+        }
 
-        Self {
+        Some(Self {
             data,
             count,
             action_offset,
             flags_offset,
+            flags_size,
             type_mask_offset,
             party_mask_offset,
             scheme_mask_offset,
@@ -616,7 +1143,7 @@ impl<'a> RulesView<'a> {
             option_id_offset,
             priority_offset,
             list_id_offset,
-        }
+        })
     }
 
     fn empty() -> Self {
@@ -625,6 +1152,7 @@ impl<'a> RulesView<'a> {
             count: 0,
             action_offset: 0,
             flags_offset: 0,
+            flags_size: 4,
             type_mask_offset: 0,
             party_mask_offset: 0,
             scheme_mask_offset: 0,
@@ -641,10 +1169,13 @@ impl<'a> RulesView<'a> {
         self.data.get(self.action_offset + rule_id).copied().unwrap_or(0)
     }
 
-    pub fn flags(&self, rule_id: usize) -> u16 {
+    pub fn flags(&self, rule_id: usize) -> u32 {
         if rule_id >= self.count { return 0; }
-        let offset = self.flags_offset + rule_id * 2;
-        read_u16_le(self.data, offset)
+        let offset = self.flags_offset + rule_id * self.flags_size;
+        if self.flags_size == 2 {
+            return read_u16_le(self.data, offset) as u32;
+        }
+        read_u32_le(self.data, offset)
     }
 
     pub fn type_mask(&self, rule_id: usize) -> u32 {
@@ -724,17 +1255,77 @@ pub fn decode_varint(data: &[u8], offset: usize) -> (u32, usize) {
         }
 
         shift += 7;
-        if shift > 35 {
-            break; // Overflow protection
+        if shift >= 32 {
+            break; // A u32 takes at most 5 bytes
         }
     }
 
     (result, bytes_read)
 }
 
+/// Inline buffer for decoded posting lists; most lists fit without touching the heap.
+pub type PostingBuf = SmallVec<[u32; 32]>;
+
 /// Decode a delta-encoded posting list.
 pub fn decode_posting_list(data: &[u8], offset: usize, count: usize) -> Vec<u32> {
     let mut result = Vec::with_capacity(count);
+    decode_posting_list_extend(data, offset, count, &mut result);
+    result
+}
+
+pub fn decode_posting_list_with_count(data: &[u8], offset: usize) -> Vec<u32> {
+    if offset + 4 > data.len() {
+        return Vec::new();
+    }
+    let count = read_u32_le(data, offset) as usize;
+    decode_posting_list(data, offset + 4, count)
+}
+
+/// Like [`decode_posting_list`], reusing `out` instead of allocating.
+pub fn decode_posting_list_into(data: &[u8], offset: usize, count: usize, out: &mut PostingBuf) {
+    out.clear();
+    decode_posting_list_extend(data, offset, count, out);
+}
+
+/// Append the posting buckets of one token list (see [`posting_bucket`])
+/// that a request in `bucket` can match: the `ANY` bucket plus `bucket`
+/// itself. Other buckets are skipped without decoding. A request bucket of
+/// `ANY` (types from several classes) decodes every bucket.
+pub fn append_bucketed_postings(data: &[u8], offset: usize, bucket: u8, out: &mut PostingBuf) {
+    let Some(&bucket_mask) = data.get(offset) else {
+        return;
+    };
+    let mut pos = offset + 1;
+    for current in 0..POSTING_BUCKET_COUNT {
+        if bucket_mask & (1 << current) == 0 {
+            continue;
+        }
+        let (count, read) = decode_varint(data, pos);
+        pos += read;
+        let (byte_len, read) = decode_varint(data, pos);
+        pos += read;
+        let end = pos.saturating_add(byte_len as usize).min(data.len());
+        if current == posting_bucket::ANY || bucket == posting_bucket::ANY || current == bucket {
+            decode_posting_list_extend(&data[..end], pos, count as usize, out);
+        }
+        pos = end;
+        if pos >= data.len() {
+            break;
+        }
+    }
+}
+
+/// Like [`decode_posting_list_with_count`], reusing `out` instead of allocating.
+pub fn decode_posting_list_with_count_into(data: &[u8], offset: usize, out: &mut PostingBuf) {
+    out.clear();
+    if offset + 4 > data.len() {
+        return;
+    }
+    let count = read_u32_le(data, offset) as usize;
+    decode_posting_list_extend(data, offset + 4, count, out);
+}
+
+fn decode_posting_list_extend(data: &[u8], offset: usize, count: usize, out: &mut impl Extend<u32>) {
     let mut pos = offset;
     let mut prev_id: u32 = 0;
 
@@ -745,16 +1336,6 @@ pub fn decode_posting_list(data: &[u8], offset: usize, count: usize) -> Vec<u32>
         let (delta, bytes_read) = decode_varint(data, pos);
         pos += bytes_read;
         prev_id = prev_id.wrapping_add(delta);
-        result.push(prev_id);
+        out.extend(Some(prev_id));
     }
-
-    result
-}
-
-pub fn decode_posting_list_with_count(data: &[u8], offset: usize) -> Vec<u32> {
-    if offset + 4 > data.len() {
-        return Vec::new();
-    }
-    let count = read_u32_le(data, offset) as usize;
-    decode_posting_list(data, offset + 4, count)
 }
@@ -0,0 +1,58 @@
+//! Memory-mapped Snapshot Loading
+//!
+//! Maps a UBX file directly instead of reading it into a heap buffer, so
+//! large research corpora are only resident once (in the page cache).
+
+use std::fs::File;
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use super::loader::{Snapshot, SnapshotError};
+
+/// A UBX snapshot file mapped into memory.
+///
+/// Owns the mapping; zero-copy views borrow from it via [`MappedSnapshot::snapshot`].
+pub struct MappedSnapshot {
+    mmap: Mmap,
+}
+
+impl MappedSnapshot {
+    /// Raw snapshot bytes.
+    pub fn bytes(&self) -> &[u8] {
+        &self.mmap
+    }
+
+    /// Size of the mapped file in bytes.
+    pub fn len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mmap.is_empty()
+    }
+
+    /// Borrow a zero-copy snapshot view over the mapping.
+    ///
+    /// The CRC was already checked by [`Snapshot::load_mmap`], so this only
+    /// re-reads the header and section directory.
+    pub fn snapshot(&self) -> Result<Snapshot<'_>, SnapshotError> {
+        Snapshot::parse(&self.mmap, false, true)
+    }
+}
+
+impl Snapshot<'_> {
+    /// Memory-map and validate a snapshot file.
+    ///
+    /// Initializes the global PSL like [`Snapshot::load`]. The file must not
+    /// be truncated or rewritten while the mapping is alive; write updated
+    /// snapshots to a new path and rename them into place instead.
+    pub fn load_mmap(path: impl AsRef<Path>) -> Result<MappedSnapshot, SnapshotError> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is read-only and callers are documented not to
+        // mutate the underlying file while it is mapped.
+        let mmap = unsafe { Mmap::map(&file)? };
+        Snapshot::load(&mmap)?;
+        Ok(MappedSnapshot { mmap })
+    }
+}
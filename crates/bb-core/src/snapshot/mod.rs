@@ -5,6 +5,10 @@
 
 mod format;
 mod loader;
+#[cfg(feature = "mmap")]
+mod mmap;
 
 pub use format::*;
 pub use loader::*;
+#[cfg(feature = "mmap")]
+pub use mmap::MappedSnapshot;
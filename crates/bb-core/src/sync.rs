@@ -0,0 +1,40 @@
+//! Locks for state a shared [`crate::Matcher`] changes through `&self`
+//!
+//! With `std` these are the `std::sync` locks, so waiting threads sleep
+//! instead of spinning; a panic while holding one doesn't poison it for
+//! later callers. Without `std` they are spin locks. Both have the same
+//! guard-returning API, so callers don't need their own cfgs.
+
+#[cfg(all(feature = "audit", not(feature = "std")))]
+pub(crate) use spin::Mutex;
+#[cfg(not(feature = "std"))]
+pub(crate) use spin::RwLock;
+
+#[cfg(all(feature = "audit", feature = "std"))]
+pub(crate) struct Mutex<T>(std::sync::Mutex<T>);
+
+#[cfg(all(feature = "audit", feature = "std"))]
+impl<T> Mutex<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        Self(std::sync::Mutex::new(value))
+    }
+
+    pub(crate) fn lock(&self) -> std::sync::MutexGuard<'_, T> {
+        self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Default)]
+pub(crate) struct RwLock<T>(std::sync::RwLock<T>);
+
+#[cfg(feature = "std")]
+impl<T> RwLock<T> {
+    pub(crate) fn read(&self) -> std::sync::RwLockReadGuard<'_, T> {
+        self.0.read().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    pub(crate) fn write(&self) -> std::sync::RwLockWriteGuard<'_, T> {
+        self.0.write().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
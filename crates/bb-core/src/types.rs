@@ -6,6 +6,9 @@
 #[cfg(not(feature = "std"))]
 use alloc::string::String;
 
+use crate::psl::get_etld1_ref;
+use crate::url::{extract_host, extract_scheme};
+
 // =============================================================================
 // Rule Actions (matches RULES section action field)
 // =============================================================================
@@ -30,6 +33,10 @@ pub enum RuleAction {
     HeaderMatchAllow = 6,
     /// Cancel at response phase (rare)
     ResponseCancel = 7,
+    /// Rewrite response body ($replace=)
+    ResponseReplace = 8,
+    /// Strip a request or response header ($removeheader=)
+    RemoveHeader = 9,
 }
 
 impl TryFrom<u8> for RuleAction {
@@ -45,6 +52,8 @@ impl TryFrom<u8> for RuleAction {
             5 => Ok(Self::HeaderMatchBlock),
             6 => Ok(Self::HeaderMatchAllow),
             7 => Ok(Self::ResponseCancel),
+            8 => Ok(Self::ResponseReplace),
+            9 => Ok(Self::RemoveHeader),
             _ => Err(()),
         }
     }
@@ -57,7 +66,7 @@ impl TryFrom<u8> for RuleAction {
 bitflags::bitflags! {
     /// Flags for rule behavior.
     #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-    pub struct RuleFlags: u16 {
+    pub struct RuleFlags: u32 {
         /// $important - ignores exception filters
         const IMPORTANT = 1 << 0;
         /// Pattern is a regex
@@ -80,6 +89,45 @@ bitflags::bitflags! {
         const REDIRECT_RULE_EXCEPTION = 1 << 11;
         const ELEMHIDE = 1 << 12;
         const GENERICHIDE = 1 << 13;
+        const REMOVEHEADER_EXCEPTION = 1 << 14;
+        /// $specifichide / $shide - disables domain-specific cosmetic rules only
+        const SPECIFICHIDE = 1 << 15;
+        /// $genericblock - page-level exception disabling generic block rules
+        const GENERICBLOCK = 1 << 16;
+        /// Block rule without a positive `$domain=`; set by the compiler
+        const IS_GENERIC = 1 << 17;
+    }
+}
+
+// =============================================================================
+// List Tiers (ListMeta section tier field)
+// =============================================================================
+
+/// Precedence tier of a filter list. A request is decided by the rules of
+/// the highest tier that has a matching block or exception, so a user's
+/// exception beats a built-in `$important` block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+pub enum ListTier {
+    /// Lists shipped with the extension
+    #[default]
+    BuiltIn = 0,
+    /// Lists the user subscribed to
+    Custom = 1,
+    /// The user's own rules
+    User = 2,
+}
+
+impl TryFrom<u8> for ListTier {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::BuiltIn),
+            1 => Ok(Self::Custom),
+            2 => Ok(Self::User),
+            _ => Err(()),
+        }
     }
 }
 
@@ -107,9 +155,16 @@ bitflags::bitflags! {
         const BEACON = 1 << 13;
         const FETCH = 1 << 14;
         const SPECULATIVE = 1 << 15;
+        const AUDIOWORKLET = 1 << 16;
+        const PAINTWORKLET = 1 << 17;
+        const MANIFEST = 1 << 18;    // web app manifest
+        const XSLT = 1 << 19;
+        /// A window or tab opened by a page. Outside [`RequestType::ALL`]:
+        /// only rules naming `$popup` apply to it.
+        const POPUP = 1 << 20;
         
         /// All request types
-        const ALL = 0xFFFF;
+        const ALL = 0xF_FFFF;
         /// Document types (main_frame + sub_frame)
         const DOCUMENT = Self::MAIN_FRAME.bits() | Self::SUBDOCUMENT.bits();
     }
@@ -117,6 +172,7 @@ bitflags::bitflags! {
 
 impl RequestType {
     /// Parse from browser request type string.
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Self {
         match s {
             "main_frame" | "document" => Self::MAIN_FRAME,
@@ -134,11 +190,37 @@ impl RequestType {
             "speculative" => Self::SPECULATIVE,
             "media" => Self::MEDIA,
             "websocket" | "ws" => Self::WEBSOCKET,
+            "audioworklet" => Self::AUDIOWORKLET,
+            "paintworklet" => Self::PAINTWORKLET,
+            "manifest" | "web_manifest" => Self::MANIFEST,
+            "xslt" => Self::XSLT,
+            "popup" => Self::POPUP,
             _ => Self::OTHER,
         }
     }
 }
 
+// =============================================================================
+// Site Switches
+// =============================================================================
+
+bitflags::bitflags! {
+    /// Per-site switches set at runtime, like uBO's: each turns something
+    /// off for pages on a site, whatever the filter lists say.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct SiteSwitches: u8 {
+        /// No hides, styles, procedural filters or scriptlets
+        const NO_COSMETIC_FILTERING = 1 << 0;
+        /// Block font requests, except `data:` URLs
+        const NO_REMOTE_FONTS = 1 << 1;
+        /// Block scripts and inject `script-src 'none'` into documents
+        const NO_SCRIPTING = 1 << 2;
+        /// Cancel images and media whose `Content-Length` exceeds the
+        /// matcher's large media size
+        const NO_LARGE_MEDIA = 1 << 3;
+    }
+}
+
 // =============================================================================
 // Party Masks
 // =============================================================================
@@ -170,7 +252,13 @@ bitflags::bitflags! {
         const WSS = 1 << 3;
         const DATA = 1 << 4;
         const FTP = 1 << 5;
-        /// All web schemes
+        /// `chrome-extension:`, `moz-extension:` and `safari-web-extension:`
+        const EXTENSION = 1 << 6;
+        /// Browser-internal URLs: `about:`, `blob:`, `chrome:` and the like
+        const INTERNAL = 1 << 7;
+        /// HTTP through FTP: what filters apply to unless they name another scheme
+        const WEB = 0x3F;
+        /// Every scheme
         const ALL = 0xFF;
     }
 }
@@ -216,11 +304,78 @@ impl TryFrom<u8> for PatternOp {
     }
 }
 
+// =============================================================================
+// Procedural Operators (PROCEDURAL_RULES op table)
+// =============================================================================
+
+/// Operator of a procedural cosmetic selector, as stored in the op table
+/// of the ProceduralRules section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum ProceduralOpKind {
+    /// `:has-text(text)` or `:has-text(/regex/)`
+    HasText = 0,
+    /// `:matches-css(property: value)`
+    MatchesCss = 1,
+    /// `:matches-path(path)` against the page URL's path and query
+    MatchesPath = 2,
+    /// `:min-text-length(n)`
+    MinTextLength = 3,
+    /// `:xpath(expression)`
+    Xpath = 4,
+    /// `:upward(n)` or `:upward(selector)`
+    Upward = 5,
+    /// `:remove()`: remove matches instead of hiding them
+    Remove = 6,
+    /// `:style(declarations)` on a procedural selector
+    Style = 7,
+}
+
+impl ProceduralOpKind {
+    pub const ALL: [Self; 8] = [
+        Self::HasText,
+        Self::MatchesCss,
+        Self::MatchesPath,
+        Self::MinTextLength,
+        Self::Xpath,
+        Self::Upward,
+        Self::Remove,
+        Self::Style,
+    ];
+
+    /// Operator name as written in filters, without the `:` and `(`. The
+    /// content script dispatches on it.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::HasText => "has-text",
+            Self::MatchesCss => "matches-css",
+            Self::MatchesPath => "matches-path",
+            Self::MinTextLength => "min-text-length",
+            Self::Xpath => "xpath",
+            Self::Upward => "upward",
+            Self::Remove => "remove",
+            Self::Style => "style",
+        }
+    }
+}
+
+impl TryFrom<u8> for ProceduralOpKind {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Self::ALL.get(value as usize).copied().ok_or(())
+    }
+}
+
 // =============================================================================
 // Request Context
 // =============================================================================
 
 /// Context for a request being matched.
+///
+/// The derived fields (hosts, eTLD+1s, party, scheme) must agree with `url`
+/// and each other; build one with [`RequestContext::builder`] rather than a
+/// struct literal.
 #[derive(Debug, Clone)]
 pub struct RequestContext<'a> {
     /// Full request URL
@@ -247,6 +402,196 @@ pub struct RequestContext<'a> {
     pub request_id: &'a str,
 }
 
+impl<'a> RequestContext<'a> {
+    /// Start building the context for a request to `url`.
+    ///
+    /// ```
+    /// use bb_core::types::{RequestContext, RequestType};
+    ///
+    /// let request = RequestContext::builder("https://ads.tracker.net/x.js", RequestType::SCRIPT)
+    ///     .initiator("https://www.example.com/")
+    ///     .build()
+    ///     .unwrap();
+    /// let ctx = request.context();
+    /// assert_eq!(ctx.site_etld1, "example.com");
+    /// assert!(ctx.is_third_party);
+    /// ```
+    pub fn builder(url: &'a str, request_type: RequestType) -> RequestContextBuilder<'a> {
+        RequestContextBuilder::new(url, request_type)
+    }
+}
+
+/// A request with its derived fields computed.
+///
+/// The eTLD+1s are slices of the hosts, so deriving them allocates nothing.
+#[derive(Debug, Clone)]
+pub struct RequestInfo<'u> {
+    pub url: &'u str,
+    pub req_host: &'u str,
+    pub req_etld1: &'u str,
+    pub site_host: &'u str,
+    pub site_etld1: &'u str,
+    pub scheme: SchemeMask,
+    pub request_type: RequestType,
+    pub is_third_party: bool,
+    pub tab_id: i32,
+    pub frame_id: i32,
+    pub request_id: &'u str,
+}
+
+impl<'u> RequestInfo<'u> {
+    /// `initiator` is the URL or origin of the document making the request.
+    /// Unlike [`RequestContextBuilder::build`] nothing is validated: an
+    /// unknown scheme counts as HTTP and a missing host as empty.
+    pub fn new(url: &'u str, request_type: RequestType, initiator: Option<&'u str>) -> Self {
+        Self::with_site_host(url, request_type, initiator.and_then(extract_host))
+    }
+
+    /// Like [`RequestInfo::new`], for callers that already know the site host
+    /// (e.g. from frame tracking). Main-frame requests are their own site, and
+    /// a missing or empty site host falls back to the request host.
+    pub fn with_site_host(url: &'u str, request_type: RequestType, site_host: Option<&'u str>) -> Self {
+        Self::derive(
+            url,
+            extract_scheme(url).unwrap_or(SchemeMask::HTTP),
+            extract_host(url).unwrap_or(""),
+            request_type,
+            site_host,
+        )
+    }
+
+    fn derive(
+        url: &'u str,
+        scheme: SchemeMask,
+        req_host: &'u str,
+        request_type: RequestType,
+        site_host: Option<&'u str>,
+    ) -> Self {
+        let site_host = if request_type == RequestType::MAIN_FRAME {
+            req_host
+        } else {
+            site_host.filter(|host| !host.is_empty()).unwrap_or(req_host)
+        };
+        let req_etld1 = get_etld1_ref(req_host);
+        let site_etld1 = if site_host == req_host { req_etld1 } else { get_etld1_ref(site_host) };
+        let is_third_party = !site_etld1.is_empty() && !req_etld1.eq_ignore_ascii_case(site_etld1);
+
+        Self {
+            url,
+            req_host,
+            req_etld1,
+            site_host,
+            site_etld1,
+            scheme,
+            request_type,
+            is_third_party,
+            tab_id: -1,
+            frame_id: -1,
+            request_id: "",
+        }
+    }
+
+    /// Attach the browser's tab, frame and request ids.
+    pub fn with_ids(mut self, tab_id: i32, frame_id: i32, request_id: &'u str) -> Self {
+        self.tab_id = tab_id;
+        self.frame_id = frame_id;
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn context(&self) -> RequestContext<'_> {
+        RequestContext {
+            url: self.url,
+            req_host: self.req_host,
+            req_etld1: self.req_etld1,
+            site_host: self.site_host,
+            site_etld1: self.site_etld1,
+            is_third_party: self.is_third_party,
+            request_type: self.request_type,
+            scheme: self.scheme,
+            tab_id: self.tab_id,
+            frame_id: self.frame_id,
+            request_id: self.request_id,
+        }
+    }
+}
+
+/// Why a [`RequestContextBuilder`] rejected a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum RequestContextError {
+    #[error("URL scheme is not http(s), ws(s), ftp or data")]
+    UnsupportedScheme,
+    #[error("URL has no host")]
+    MissingHost,
+}
+
+/// Builds a [`RequestInfo`] from a URL, request type and initiator, deriving
+/// hosts, eTLD+1s, scheme and party the same way the engine does.
+#[derive(Debug, Clone)]
+pub struct RequestContextBuilder<'u> {
+    url: &'u str,
+    request_type: RequestType,
+    site_host: Option<&'u str>,
+    tab_id: i32,
+    frame_id: i32,
+    request_id: &'u str,
+}
+
+impl<'u> RequestContextBuilder<'u> {
+    pub fn new(url: &'u str, request_type: RequestType) -> Self {
+        Self {
+            url,
+            request_type,
+            site_host: None,
+            tab_id: -1,
+            frame_id: -1,
+            request_id: "",
+        }
+    }
+
+    /// URL or origin of the document making the request. Opaque origins
+    /// (`null`) leave the request as its own site.
+    pub fn initiator(mut self, initiator: &'u str) -> Self {
+        self.site_host = extract_host(initiator);
+        self
+    }
+
+    /// Site host known up front, e.g. from frame tracking.
+    pub fn site_host(mut self, site_host: &'u str) -> Self {
+        self.site_host = Some(site_host);
+        self
+    }
+
+    pub fn tab_id(mut self, tab_id: i32) -> Self {
+        self.tab_id = tab_id;
+        self
+    }
+
+    pub fn frame_id(mut self, frame_id: i32) -> Self {
+        self.frame_id = frame_id;
+        self
+    }
+
+    pub fn request_id(mut self, request_id: &'u str) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    /// Validate the URL and compute the derived fields. Only web schemes are
+    /// accepted, and `data:` URLs are the only ones allowed without a host.
+    pub fn build(self) -> Result<RequestInfo<'u>, RequestContextError> {
+        let scheme = extract_scheme(self.url)
+            .filter(|scheme| scheme.intersects(SchemeMask::WEB))
+            .ok_or(RequestContextError::UnsupportedScheme)?;
+        let req_host = extract_host(self.url).unwrap_or("");
+        if req_host.is_empty() && scheme != SchemeMask::DATA {
+            return Err(RequestContextError::MissingHost);
+        }
+        Ok(RequestInfo::derive(self.url, scheme, req_host, self.request_type, self.site_host)
+            .with_ids(self.tab_id, self.frame_id, self.request_id))
+    }
+}
+
 // =============================================================================
 // Match Result
 // =============================================================================
@@ -275,6 +620,12 @@ pub struct MatchResult {
     pub list_id: u16,
     /// Redirect URL if decision is Redirect or Removeparam
     pub redirect_url: Option<String>,
+    /// Matching stopped at the matcher's work budget and the request was
+    /// allowed, since an unchecked exception may have covered it
+    pub budget_exceeded: bool,
+    /// The site switch that decided the request instead of a rule (with
+    /// `rule_id` -1); empty otherwise
+    pub site_switch: SiteSwitches,
 }
 
 impl Default for MatchResult {
@@ -284,6 +635,8 @@ impl Default for MatchResult {
             rule_id: -1,
             list_id: 0,
             redirect_url: None,
+            budget_exceeded: false,
+            site_switch: SiteSwitches::empty(),
         }
     }
 }
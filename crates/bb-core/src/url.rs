@@ -2,8 +2,17 @@
 //!
 //! These functions avoid allocations and work directly on string slices.
 
-use crate::types::SchemeMask;
+#[cfg(not(feature = "std"))]
+use alloc::{format, string::{String, ToString}, vec::Vec};
+
+#[cfg(feature = "std")]
+use std::collections::HashSet;
+#[cfg(not(feature = "std"))]
+use hashbrown::HashSet;
+
+use crate::types::{RequestType, SchemeMask};
 use crate::hash::hash_token;
+use smallvec::SmallVec;
 
 // =============================================================================
 // Scheme Extraction
@@ -54,10 +63,33 @@ pub fn extract_scheme(url: &str) -> Option<SchemeMask> {
                 None
             }
         }
-        _ => None,
+        _ => extract_browser_scheme(bytes),
+    }
+}
+
+/// Extension and browser-internal schemes, which the browser may report
+/// but filters only reach by naming them.
+fn extract_browser_scheme(bytes: &[u8]) -> Option<SchemeMask> {
+    const EXTENSION: &[&[u8]] = &[b"chrome-extension://", b"moz-extension://", b"safari-web-extension://"];
+    const INTERNAL: &[&[u8]] = &[b"about:", b"blob:", b"chrome://", b"edge://", b"resource://", b"view-source:"];
+
+    let has_prefix = |prefix: &&[u8]| bytes.len() >= prefix.len() && bytes[..prefix.len()].eq_ignore_ascii_case(prefix);
+    if EXTENSION.iter().any(has_prefix) {
+        Some(SchemeMask::EXTENSION)
+    } else if INTERNAL.iter().any(has_prefix) {
+        Some(SchemeMask::INTERNAL)
+    } else {
+        None
     }
 }
 
+/// Whether filters apply to a URL at all: true for the [`SchemeMask::WEB`]
+/// schemes, false for extension, browser-internal and unknown ones.
+#[inline]
+pub fn is_filterable_url(url: &str) -> bool {
+    extract_scheme(url).is_some_and(|scheme| scheme.intersects(SchemeMask::WEB))
+}
+
 /// Get the position after "://".
 #[inline]
 pub fn get_scheme_end(url: &str) -> Option<usize> {
@@ -162,6 +194,91 @@ pub fn extract_path(url: &str) -> &str {
     &url[path_start..path_end]
 }
 
+// =============================================================================
+// Request Type Inference
+// =============================================================================
+
+/// Guess the type of a request from its `Accept` header or path extension.
+/// Used for requests the browser reports as `other`; returns None when
+/// neither says anything specific.
+pub fn infer_request_type(url: &str, accept: Option<&str>) -> Option<RequestType> {
+    if matches!(extract_scheme(url), Some(scheme) if scheme.intersects(SchemeMask::WS | SchemeMask::WSS)) {
+        return Some(RequestType::WEBSOCKET);
+    }
+    accept.and_then(request_type_from_accept).or_else(|| request_type_from_extension(extract_path(url)))
+}
+
+/// The `ws://`/`wss://` form of an `http(s)://` URL. Firefox reports
+/// websocket handshakes with the HTTP scheme; matching them under the
+/// websocket one lets `|wss://` filters apply.
+pub fn websocket_url(url: &str) -> Option<String> {
+    let (scheme, rest) = url.split_once(':')?;
+    let websocket_scheme = if scheme.eq_ignore_ascii_case("https") {
+        "wss"
+    } else if scheme.eq_ignore_ascii_case("http") {
+        "ws"
+    } else {
+        return None;
+    };
+    Some(format!("{}:{}", websocket_scheme, rest))
+}
+
+/// `declared`, or the inferred type when the browser reported `other`.
+#[inline]
+pub fn refine_request_type(declared: RequestType, url: &str, accept: Option<&str>) -> RequestType {
+    if declared != RequestType::OTHER {
+        return declared;
+    }
+    infer_request_type(url, accept).unwrap_or(declared)
+}
+
+/// Only the first media range counts; `*/*` and HTML carry no signal.
+fn request_type_from_accept(accept: &str) -> Option<RequestType> {
+    let first = accept.split(',').next()?.split(';').next()?.trim();
+    let first = first.as_bytes();
+    let starts_with = |prefix: &str| {
+        first.len() >= prefix.len() && first[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+    };
+    if starts_with("text/css") {
+        Some(RequestType::STYLESHEET)
+    } else if starts_with("image/") {
+        Some(RequestType::IMAGE)
+    } else if starts_with("font/") || starts_with("application/font") {
+        Some(RequestType::FONT)
+    } else if starts_with("audio/") || starts_with("video/") {
+        Some(RequestType::MEDIA)
+    } else if starts_with("application/javascript") || starts_with("text/javascript") {
+        Some(RequestType::SCRIPT)
+    } else if starts_with("application/manifest+json") {
+        Some(RequestType::MANIFEST)
+    } else {
+        None
+    }
+}
+
+fn request_type_from_extension(path: &str) -> Option<RequestType> {
+    let file = &path[path.rfind('/').map_or(0, |i| i + 1)..];
+    let ext = &file[file.rfind('.')? + 1..];
+    if ext.is_empty() || ext.len() > 11 {
+        return None;
+    }
+    let mut buf = [0u8; 11];
+    let lower = &mut buf[..ext.len()];
+    lower.copy_from_slice(ext.as_bytes());
+    lower.make_ascii_lowercase();
+
+    Some(match &*lower {
+        b"js" | b"mjs" => RequestType::SCRIPT,
+        b"css" => RequestType::STYLESHEET,
+        b"png" | b"jpg" | b"jpeg" | b"gif" | b"webp" | b"avif" | b"svg" | b"ico" | b"bmp" => RequestType::IMAGE,
+        b"woff" | b"woff2" | b"ttf" | b"otf" | b"eot" => RequestType::FONT,
+        b"mp4" | b"webm" | b"mp3" | b"m4a" | b"ogg" | b"oga" | b"wav" | b"flac" => RequestType::MEDIA,
+        b"swf" => RequestType::OBJECT,
+        b"webmanifest" => RequestType::MANIFEST,
+        _ => return None,
+    })
+}
+
 // =============================================================================
 // URL Tokenization
 // =============================================================================
@@ -175,6 +292,13 @@ fn is_alnum(b: u8) -> bool {
     b.is_ascii_alphanumeric()
 }
 
+/// Whether `bytes[start..end]` is the URL scheme, which is a token even when
+/// shorter than [`MIN_TOKEN_LEN`] (`ws`).
+#[inline]
+pub fn is_scheme_token(bytes: &[u8], start: usize, end: usize) -> bool {
+    start == 0 && end > 0 && bytes.get(end) == Some(&b':')
+}
+
 /// Token extracted from a URL.
 #[derive(Debug, Clone, Copy)]
 pub struct UrlToken {
@@ -184,17 +308,23 @@ pub struct UrlToken {
 }
 
 /// Tokenize a URL for pattern matching.
-/// Returns hashed tokens.
+/// Returns hashed tokens. The scheme is tokenized like the rest of the URL,
+/// so scheme-anchored filters such as `|wss://` can be indexed on it.
 pub fn tokenize_url(url: &str) -> Vec<u32> {
     let mut tokens = Vec::with_capacity(MAX_TOKENS);
+    tokenize_url_into(url, &mut tokens);
+    tokens
+}
+
+/// Like [`tokenize_url`], reusing `tokens` instead of allocating.
+pub fn tokenize_url_into(url: &str, tokens: &mut Vec<u32>) {
+    tokens.clear();
     let bytes = url.as_bytes();
-    
-    // Start after scheme
-    let start = get_scheme_end(url).unwrap_or(0);
-    
+    // Lowercased token bytes; only tokens longer than 64 bytes spill to the heap.
+    let mut lowered: SmallVec<[u8; 64]> = SmallVec::new();
     let mut token_start = None;
     
-    for i in start..=bytes.len() {
+    for i in 0..=bytes.len() {
         let is_alpha = i < bytes.len() && is_alnum(bytes[i]);
         
         if is_alpha {
@@ -203,31 +333,25 @@ pub fn tokenize_url(url: &str) -> Vec<u32> {
             }
         } else if let Some(ts) = token_start {
             let len = i - ts;
-            if len >= MIN_TOKEN_LEN && tokens.len() < MAX_TOKENS {
+            if (len >= MIN_TOKEN_LEN || is_scheme_token(bytes, ts, i)) && tokens.len() < MAX_TOKENS {
                 // Hash the lowercased token
-                let token_bytes: Vec<u8> = bytes[ts..i]
-                    .iter()
-                    .map(|b| b.to_ascii_lowercase())
-                    .collect();
-                let token_str = unsafe { std::str::from_utf8_unchecked(&token_bytes) };
+                lowered.clear();
+                lowered.extend(bytes[ts..i].iter().map(|b| b.to_ascii_lowercase()));
+                let token_str = unsafe { core::str::from_utf8_unchecked(&lowered) };
                 tokens.push(hash_token(token_str));
             }
             token_start = None;
         }
     }
-    
-    tokens
 }
 
 /// Tokenize URL into token structs with position info.
 pub fn tokenize_url_with_positions(url: &str) -> Vec<UrlToken> {
     let mut tokens = Vec::with_capacity(MAX_TOKENS);
     let bytes = url.as_bytes();
-    
-    let start = get_scheme_end(url).unwrap_or(0);
     let mut token_start = None;
     
-    for i in start..=bytes.len() {
+    for i in 0..=bytes.len() {
         let is_alpha = i < bytes.len() && is_alnum(bytes[i]);
         
         if is_alpha {
@@ -236,12 +360,12 @@ pub fn tokenize_url_with_positions(url: &str) -> Vec<UrlToken> {
             }
         } else if let Some(ts) = token_start {
             let len = i - ts;
-            if len >= MIN_TOKEN_LEN && tokens.len() < MAX_TOKENS {
+            if (len >= MIN_TOKEN_LEN || is_scheme_token(bytes, ts, i)) && tokens.len() < MAX_TOKENS {
                 let token_bytes: Vec<u8> = bytes[ts..i]
                     .iter()
                     .map(|b| b.to_ascii_lowercase())
                     .collect();
-                let token_str = unsafe { std::str::from_utf8_unchecked(&token_bytes) };
+                let token_str = unsafe { core::str::from_utf8_unchecked(&token_bytes) };
                 tokens.push(UrlToken {
                     hash: hash_token(token_str),
                     start: ts,
@@ -291,8 +415,7 @@ pub fn is_at_boundary(s: &str, pos: usize) -> bool {
 
 /// Remove specified parameters from a URL.
 /// Returns the modified URL, or None if no changes.
-#[cfg(feature = "std")]
-pub fn remove_query_params(url: &str, keys_to_remove: &std::collections::HashSet<&str>) -> Option<String> {
+pub fn remove_query_params(url: &str, keys_to_remove: &HashSet<&str>) -> Option<String> {
     let q_pos = url.find('?')?;
     
     // Find fragment
@@ -354,20 +477,19 @@ pub fn get_host_position(url: &str) -> Option<(usize, usize)> {
 
     // Skip userinfo
     let mut host_start = scheme_end;
-    for i in scheme_end..bytes.len() {
-        if bytes[i] == b'@' {
+    for (i, &b) in bytes.iter().enumerate().skip(scheme_end) {
+        if b == b'@' {
             host_start = i + 1;
             break;
         }
-        if bytes[i] == b'/' {
+        if b == b'/' {
             break;
         }
     }
 
     // Find host end
     let mut host_end = bytes.len();
-    for i in host_start..bytes.len() {
-        let b = bytes[i];
+    for (i, &b) in bytes.iter().enumerate().skip(host_start) {
         if b == b'/' || b == b'?' || b == b'#' || b == b':' {
             host_end = i;
             break;
@@ -389,9 +511,23 @@ mod tests {
         assert_eq!(extract_scheme("ws://example.com"), Some(SchemeMask::WS));
         assert_eq!(extract_scheme("data:text/html"), Some(SchemeMask::DATA));
         assert_eq!(extract_scheme("ftp://example.com"), Some(SchemeMask::FTP));
+        assert_eq!(extract_scheme("chrome-extension://abc/popup.html"), Some(SchemeMask::EXTENSION));
+        assert_eq!(extract_scheme("moz-extension://abc/x.js"), Some(SchemeMask::EXTENSION));
+        assert_eq!(extract_scheme("about:blank"), Some(SchemeMask::INTERNAL));
+        assert_eq!(extract_scheme("blob:https://example.com/uuid"), Some(SchemeMask::INTERNAL));
         assert_eq!(extract_scheme("invalid"), None);
     }
 
+    #[test]
+    fn test_is_filterable_url() {
+        assert!(is_filterable_url("https://example.com/ad.js"));
+        assert!(is_filterable_url("data:image/png;base64,AAAA"));
+        assert!(!is_filterable_url("chrome-extension://abc/popup.html"));
+        assert!(!is_filterable_url("about:blank"));
+        assert!(!is_filterable_url("blob:https://example.com/uuid"));
+        assert!(!is_filterable_url("file:///tmp/x.html"));
+    }
+
     #[test]
     fn test_extract_host() {
         assert_eq!(extract_host("https://example.com/path"), Some("example.com"));
@@ -408,6 +544,22 @@ mod tests {
         assert_eq!(extract_path("https://example.com?query"), "/");
     }
 
+    #[test]
+    fn test_infer_request_type() {
+        assert_eq!(infer_request_type("https://cdn.example/a/tag.JS?v=1", None), Some(RequestType::SCRIPT));
+        assert_eq!(infer_request_type("https://cdn.example/px.gif#x", None), Some(RequestType::IMAGE));
+        assert_eq!(infer_request_type("https://cdn.example/v1.2/track", None), None);
+        let accept = Some("image/avif,image/webp,*/*");
+        assert_eq!(infer_request_type("https://cdn.example/collect", accept), Some(RequestType::IMAGE));
+        assert_eq!(infer_request_type("https://cdn.example/app.js", Some("*/*")), Some(RequestType::SCRIPT));
+
+        // Only `other` is refined.
+        assert_eq!(refine_request_type(RequestType::OTHER, "https://a.example/s.css", None), RequestType::STYLESHEET);
+        let xhr = RequestType::XMLHTTPREQUEST;
+        assert_eq!(refine_request_type(xhr, "https://a.example/s.css", None), xhr);
+        assert_eq!(refine_request_type(RequestType::OTHER, "https://a.example/data", None), RequestType::OTHER);
+    }
+
     #[test]
     fn test_tokenize_url() {
         let tokens = tokenize_url("https://example.com/path/analytics.js");
@@ -415,6 +567,25 @@ mod tests {
         // Should contain tokens for "example", "com", "path", "analytics"
     }
 
+    #[test]
+    fn test_websocket_urls() {
+        assert_eq!(infer_request_type("wss://chat.example.com/socket", None), Some(RequestType::WEBSOCKET));
+        assert_eq!(refine_request_type(RequestType::OTHER, "WS://chat.example.com/a.js", None), RequestType::WEBSOCKET);
+        assert_eq!(websocket_url("https://chat.example.com/socket").as_deref(), Some("wss://chat.example.com/socket"));
+        assert_eq!(websocket_url("HTTP://chat.example.com/").as_deref(), Some("ws://chat.example.com/"));
+        assert_eq!(websocket_url("wss://chat.example.com/"), None);
+    }
+
+    #[test]
+    fn test_tokenize_url_includes_scheme() {
+        let tokens = tokenize_url("wss://live.chat.net/socket");
+        for token in ["wss", "live", "chat", "net", "socket"] {
+            assert!(tokens.contains(&hash_token(token)), "missing token {}", token);
+        }
+        assert_eq!(tokenize_url_with_positions("WS://chat.net/")[0].hash, hash_token("ws"));
+        assert_eq!(tokenize_url("ws://chat.net/")[0], hash_token("ws"));
+    }
+
     #[test]
     fn test_is_boundary() {
         assert!(is_at_boundary("abc", 3)); // End of string
@@ -1,28 +1,162 @@
 //! WebAssembly bindings for BetterBlocker
 
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::sync::OnceLock;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::mem::ManuallyDrop;
+use std::rc::Rc;
 use wasm_bindgen::prelude::*;
-use bb_compiler::{build_snapshot, optimize_rules, parse_filter_list};
+use bb_compiler::{
+    normalize_selector, optimize_rules, parse_filter_list, parse_filter_list_into, parse_list_metadata,
+    preprocess_filter_list, render_scriptlet, rule_signature, selector_issue, try_build_snapshot_with_options,
+    BuildInfo, CompileDiagnostics, Error as CompileError, NetworkRuleChoices, NoIncludes, ParserConfig, PickerOptions,
+    PickerRuleKind, PreprocessEnv, SnapshotOptions,
+};
 use bb_core::{
-    Matcher,
+    Engine,
+    RequestInfo,
     Snapshot,
-    matcher::ResponseHeader,
-    types::{MatchDecision, RequestContext, RequestType, SchemeMask},
-    psl::get_etld1,
-    url::extract_host,
+    hash::{crc32, hash_token, Hash64},
+    matcher::{hide_css, CandidateSource, DEFAULT_HIDE_CSS_CHUNK, DEFAULT_LARGE_MEDIA_SIZE, CosmeticHideSwitches, CosmeticMatchResult, CosmeticStats, MatchBudget, Matcher, ResponseHeader, SelectorStats},
+    types::{ListTier, MatchDecision, MatchResult, RequestContext, RequestType, SiteSwitches},
+    psl::{self, get_etld1, get_etld1_ref},
+    url::{extract_host, refine_request_type, websocket_url},
 };
 
+/// An owned snapshot buffer together with the views that borrow it.
+///
+/// `engine` borrows `snapshot`, which borrows `data`; both are heap
+/// allocations owned by this struct and released in reverse order on drop,
+/// so swapping the state out frees the old buffer.
 struct MatcherState {
-    #[allow(dead_code)]
-    data: &'static [u8],
-    #[allow(dead_code)]
-    snapshot: &'static Snapshot<'static>,
-    matcher: &'static Matcher<'static>,
+    engine: ManuallyDrop<Engine<'static>>,
+    snapshot: *mut Snapshot<'static>,
+    data: *mut [u8],
+    fingerprint: u32,
+    epoch: u32,
+}
+
+impl MatcherState {
+    /// Take ownership of `snapshot_data` and load it in place.
+    fn load(snapshot_data: Box<[u8]>, epoch: u32, with_psl: bool) -> Result<Self, JsValue> {
+        let data = Box::into_raw(snapshot_data);
+        // SAFETY: `data` stays allocated until `Drop` runs, after the views
+        // borrowing it are gone.
+        let bytes: &'static [u8] = unsafe { &*data };
+
+        let loaded = if with_psl {
+            Snapshot::load(bytes)
+        } else {
+            Snapshot::load_without_psl(bytes)
+        };
+        let snapshot = match loaded {
+            Ok(snapshot) => Box::into_raw(Box::new(snapshot)),
+            Err(e) => {
+                // SAFETY: nothing borrows `data` yet.
+                drop(unsafe { Box::from_raw(data) });
+                return Err(JsValue::from_str(&format!("Failed to load snapshot: {}", e)));
+            }
+        };
+        // SAFETY: as above, `snapshot` outlives `engine`.
+        let mut engine = Engine::new(unsafe { &*snapshot });
+        with_runtime(|runtime| configure_matcher(engine.matcher_mut(), &runtime.settings));
+        track_snapshot_buffer(bytes.len() as isize);
+
+        Ok(Self {
+            engine: ManuallyDrop::new(engine),
+            snapshot,
+            data,
+            fingerprint: crc32(bytes),
+            epoch,
+        })
+    }
+
+    fn engine(&self) -> &Engine<'_> {
+        &self.engine
+    }
+
+    fn snapshot(&self) -> &Snapshot<'_> {
+        // SAFETY: `snapshot` is only freed in `Drop`.
+        unsafe { &*self.snapshot }
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn bytes(&self) -> &[u8] {
+        // SAFETY: `data` is only freed in `Drop`.
+        unsafe { &*self.data }
+    }
+}
+
+impl Drop for MatcherState {
+    fn drop(&mut self) {
+        // SAFETY: `snapshot` and `data` came from `Box::into_raw` in `load`
+        // and are dropped exactly once, after everything borrowing them.
+        unsafe {
+            ManuallyDrop::drop(&mut self.engine);
+            drop(Box::from_raw(self.snapshot));
+            drop(Box::from_raw(self.data));
+        }
+        track_snapshot_buffer(-(self.len() as isize));
+    }
+}
+
+thread_local! {
+    static MATCHER_STATE: RefCell<Option<Rc<MatcherState>>> = const { RefCell::new(None) };
+    static SNAPSHOT_EPOCH: Cell<u32> = const { Cell::new(0) };
+    /// Snapshot buffers currently allocated and their total size, whether
+    /// active, retained for diffing, or still held by an in-flight call
+    static SNAPSHOT_BUFFERS: Cell<(u32, usize)> = const { Cell::new((0, 0)) };
+}
+
+fn track_snapshot_buffer(delta_bytes: isize) {
+    SNAPSHOT_BUFFERS.with(|buffers| {
+        let (count, bytes) = buffers.get();
+        buffers.set(if delta_bytes >= 0 {
+            (count + 1, bytes + delta_bytes as usize)
+        } else {
+            (count.saturating_sub(1), bytes.saturating_sub(delta_bytes.unsigned_abs()))
+        });
+    });
+}
+
+/// Current matcher state. Callers hold the `Rc` for the duration of a match,
+/// so a `reload` never frees a buffer that a caller is still matching against.
+fn current_state() -> Option<Rc<MatcherState>> {
+    MATCHER_STATE.with(|state| {
+        if let Ok(mut current) = state.try_borrow_mut() {
+            if let Some(current) = current.as_mut() {
+                retire_expired_rules(current);
+            }
+        }
+        state.borrow().clone()
+    })
+}
+
+/// Stop `$expires=` rules whose time has come. Reads the clock only while a
+/// rule is still due to expire; if a call holds the state, the next one
+/// catches up.
+fn retire_expired_rules(state: &mut Rc<MatcherState>) {
+    let Some(next_expiry) = state.engine().matcher().next_rule_expiry() else {
+        return;
+    };
+    let now = now_ms() / 1000;
+    if now >= next_expiry {
+        if let Some(state) = Rc::get_mut(state) {
+            state.engine.matcher_mut().set_current_time(now);
+            invalidate_cosmetic_cache();
+        }
+    }
 }
 
-static MATCHER_STATE: OnceLock<MatcherState> = OnceLock::new();
+fn next_epoch() -> u32 {
+    SNAPSHOT_EPOCH.with(|epoch| {
+        let next = epoch.get().wrapping_add(1);
+        epoch.set(next);
+        next
+    })
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
@@ -48,11 +182,42 @@ struct DynamicRule {
     target: String,
     rule_type: String,
     action: DynamicAction,
+    /// Insertion order; the most recently set rule wins ties
+    seq: u64,
+}
+
+/// Dynamic filtering rules keyed by their (lowercased) site pattern, so a
+/// request only looks at rules for the suffixes of its site host plus `*`.
+/// At most one rule exists per site/target/type.
+#[derive(Default)]
+struct DynamicRuleSet {
+    by_site: HashMap<String, Vec<DynamicRule>>,
+    len: usize,
+    next_seq: u64,
 }
 
 struct RuntimeSettings {
     dynamic_filtering_enabled: bool,
     disabled_sites: Vec<String>,
+    /// Applied to every matcher, including ones loaded by later reloads
+    disabled_lists: Vec<u16>,
+    /// Inject `$csp` policies as `Content-Security-Policy-Report-Only`
+    csp_report_only: bool,
+    /// Redirect to embedded `data:` URLs instead of `/redirects/*` paths
+    redirect_data_urls: bool,
+    /// Refine requests reported as `other` from their URL
+    infer_request_types: bool,
+    /// Per-request matcher work cap
+    match_budget: MatchBudget,
+    /// Selectors per hide CSS rule
+    hide_css_chunk: usize,
+    /// Bytes above which the no-large-media switch cancels a response
+    large_media_size: u64,
+    /// Signatures of the filters passed to `suppress_filter`; their rules
+    /// stay disabled across reloads
+    suppressed_filters: Vec<Hash64>,
+    /// Per-site switches by host, from `set_site_switches`
+    site_switches: HashMap<String, SiteSwitches>,
 }
 
 impl Default for RuntimeSettings {
@@ -60,6 +225,15 @@ impl Default for RuntimeSettings {
         Self {
             dynamic_filtering_enabled: true,
             disabled_sites: Vec::new(),
+            disabled_lists: Vec::new(),
+            csp_report_only: false,
+            redirect_data_urls: false,
+            infer_request_types: false,
+            match_budget: MatchBudget::UNLIMITED,
+            hide_css_chunk: DEFAULT_HIDE_CSS_CHUNK,
+            large_media_size: DEFAULT_LARGE_MEDIA_SIZE,
+            suppressed_filters: Vec::new(),
+            site_switches: HashMap::new(),
         }
     }
 }
@@ -67,17 +241,48 @@ impl Default for RuntimeSettings {
 #[derive(Clone, Debug)]
 struct RemoveparamEntry {
     ts: u64,
+    #[allow(dead_code)]
     url: String,
 }
 
 #[derive(Clone, Debug)]
 struct TraceEntry {
+    /// Monotonic per-session sequence number; gaps mean dropped entries
+    seq: u64,
     url: String,
     request_type: String,
     initiator: Option<String>,
     tab_id: i32,
     frame_id: i32,
     request_id: String,
+    outcome: Option<TraceOutcome>,
+}
+
+/// Static-filter outcome captured with a trace entry when enabled.
+#[derive(Clone, Debug)]
+struct TraceOutcome {
+    decision: u8,
+    rule_id: i32,
+    list_id: u16,
+    redirect_url: Option<String>,
+    /// Cosmetic counts, only filled in for document requests
+    hidden_selectors: u32,
+    procedural: u32,
+    scriptlets: u32,
+}
+
+/// A frame in a tab, as reported by `frame_created`.
+#[derive(Clone, Debug)]
+struct FrameInfo {
+    parent_frame_id: i32,
+    /// Host of the frame's document; empty for about:blank, srcdoc and
+    /// data: frames, which inherit their parent's origin
+    host: String,
+}
+
+struct PreviousSnapshot {
+    state: Rc<MatcherState>,
+    expires_at: u64,
 }
 
 #[derive(Default)]
@@ -85,48 +290,460 @@ struct PerfBucket {
     values: Vec<f64>,
 }
 
+impl TraceEntry {
+    fn heap_bytes(&self) -> usize {
+        self.url.capacity()
+            + self.request_type.capacity()
+            + self.initiator.as_ref().map_or(0, String::capacity)
+            + self.request_id.capacity()
+            + self
+                .outcome
+                .as_ref()
+                .and_then(|outcome| outcome.redirect_url.as_ref())
+                .map_or(0, String::capacity)
+    }
+}
+
+impl DynamicRule {
+    fn heap_bytes(&self) -> usize {
+        self.site.capacity() + self.target.capacity() + self.rule_type.capacity()
+    }
+
+    /// How the rule ranks for `request`, or `None` if it doesn't apply.
+    ///
+    /// Follows uBO's evaluation order: rules for the request's host (the most
+    /// specific target first) beat every `*`-target cell; among cells,
+    /// `3p-script`/`3p-frame`/`1p-script` beat `3p`, which beats plain types
+    /// such as `image` or `inline-script`, which beat `*`. Within a tier the
+    /// most specific site wins, then a typed rule, then the newest.
+    fn precedence(&self, request: &DynamicRequest<'_>) -> Option<(u8, usize, usize, bool, u64)> {
+        let typed = self.rule_type != "*";
+        let (tier, target_depth) = match self.target.as_str() {
+            "*" => (dynamic_cell_tier(&self.rule_type, request)?, 0),
+            "3p" | "third-party" | "1p" | "first-party" => {
+                let third_party = matches!(self.target.as_str(), "3p" | "third-party");
+                if third_party != request.is_third_party || !type_matches(&self.rule_type, request.request_type) {
+                    return None;
+                }
+                (if typed { 4 } else { 3 }, 0)
+            }
+            target => {
+                if !host_matches(target, request.req_host) || !type_matches(&self.rule_type, request.request_type) {
+                    return None;
+                }
+                (5, label_count(target))
+            }
+        };
+        Some((tier, target_depth, label_count(&self.site), typed, self.seq))
+    }
+}
+
+/// What dynamic rules are matched against.
+struct DynamicRequest<'a> {
+    req_host: &'a str,
+    is_third_party: bool,
+    request_type: &'a str,
+}
+
+/// uBO's `*`-target cell types, in popup order. `inline-script` applies when
+/// the caller asks about a document's inline scripts with that request type.
+const DYNAMIC_CELL_TYPES: [&str; 7] = ["*", "image", "3p", "inline-script", "1p-script", "3p-script", "3p-frame"];
+
+/// Precedence tier of a `*`-target rule of `rule_type`, if it applies.
+fn dynamic_cell_tier(rule_type: &str, request: &DynamicRequest<'_>) -> Option<u8> {
+    let script = request.request_type == "script";
+    let frame = matches!(request.request_type, "sub_frame" | "object");
+    match rule_type {
+        "*" => Some(1),
+        "3p" => request.is_third_party.then_some(3),
+        "3p-script" => (request.is_third_party && script).then_some(4),
+        "3p-frame" => (request.is_third_party && frame).then_some(4),
+        "1p-script" => (!request.is_third_party && script).then_some(4),
+        _ => type_matches(rule_type, request.request_type).then_some(2),
+    }
+}
+
+/// Labels in a host pattern; `*` has none.
+fn label_count(pattern: &str) -> usize {
+    if pattern == "*" {
+        0
+    } else {
+        pattern.split('.').count()
+    }
+}
+
+impl DynamicRuleSet {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn clear(&mut self) {
+        self.by_site.clear();
+        self.len = 0;
+    }
+
+    /// Add `rule`, replacing any rule with the same site/target/type.
+    fn insert(&mut self, mut rule: DynamicRule) {
+        rule.seq = self.next_seq;
+        self.next_seq += 1;
+        let rules = self.by_site.entry(rule.site.clone()).or_default();
+        match rules
+            .iter_mut()
+            .find(|existing| existing.target == rule.target && existing.rule_type == rule.rule_type)
+        {
+            Some(existing) => *existing = rule,
+            None => {
+                rules.push(rule);
+                self.len += 1;
+            }
+        }
+    }
+
+    fn remove(&mut self, site: &str, target: &str, rule_type: &str) -> bool {
+        let Some(rules) = self.by_site.get_mut(site) else {
+            return false;
+        };
+        let before = rules.len();
+        rules.retain(|rule| !(rule.target == target && rule.rule_type == rule_type));
+        let removed = rules.len() < before;
+        if rules.is_empty() {
+            self.by_site.remove(site);
+        }
+        if removed {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    /// Rules whose site pattern matches `site_host`: `*`, the host itself
+    /// and each parent domain.
+    fn candidates<'s>(&'s self, site_host: &'s str) -> impl Iterator<Item = &'s DynamicRule> + 's {
+        let mut suffixes = vec!["*"];
+        let mut host = site_host;
+        while !host.is_empty() {
+            suffixes.push(host);
+            host = host.find('.').map_or("", |dot| &host[dot + 1..]);
+        }
+        suffixes
+            .into_iter()
+            .filter_map(|suffix| self.by_site.get(suffix))
+            .flatten()
+    }
+
+    /// The rule that decides `request` from `site_host`, if any.
+    fn evaluate<'s>(&'s self, site_host: &'s str, request: &DynamicRequest<'_>) -> Option<&'s DynamicRule> {
+        self.candidates(site_host)
+            .filter_map(|rule| Some((rule.precedence(request)?, rule)))
+            .max_by_key(|(precedence, _)| *precedence)
+            .map(|(_, rule)| rule)
+    }
+
+    /// The rule set for exactly `target`/`rule_type` on the most specific
+    /// suffix of `site_host` that has one, and the one set globally (`*`).
+    fn cell(&self, site_host: &str, target: &str, rule_type: &str) -> (Option<&DynamicRule>, Option<&DynamicRule>) {
+        let find = |site: &str| {
+            self.by_site
+                .get(site)?
+                .iter()
+                .find(|rule| rule.target == target && rule.rule_type == rule_type)
+        };
+        let mut local = None;
+        let mut host = site_host;
+        while !host.is_empty() && host != "*" && local.is_none() {
+            local = find(host);
+            host = host.find('.').map_or("", |dot| &host[dot + 1..]);
+        }
+        (local, find("*"))
+    }
+
+    /// All rules in the order they were set.
+    fn ordered(&self) -> Vec<&DynamicRule> {
+        let mut rules: Vec<&DynamicRule> = self.by_site.values().flatten().collect();
+        rules.sort_by_key(|rule| rule.seq);
+        rules
+    }
+
+    fn heap_bytes(&self) -> usize {
+        self.by_site
+            .iter()
+            .map(|(site, rules)| {
+                site.capacity()
+                    + rules.capacity() * std::mem::size_of::<DynamicRule>()
+                    + rules.iter().map(DynamicRule::heap_bytes).sum::<usize>()
+            })
+            .sum()
+    }
+}
+
+/// Cosmetic rules the user keeps outside the filter lists, for one site
+/// (`*` for every site). See `set_user_styles`.
+struct UserStyle {
+    site: String,
+    /// Selectors hidden as if by `site##selector`
+    hide: Vec<String>,
+    /// Stylesheet text, injected as is
+    css: String,
+}
+
+/// One subscribed filter list. `id` is the `list_id` the compiler assigns,
+/// i.e. the list's index in the `compile_filter_lists` input.
+struct ListEntry {
+    id: u16,
+    url: String,
+    title: Option<String>,
+    enabled: bool,
+    /// Refresh interval from the catalog; falls back to the snapshot's
+    /// `! Expires:` header, then to `DEFAULT_LIST_EXPIRY_SECS`
+    expires_secs: Option<u32>,
+    last_updated_ms: Option<u64>,
+    etag: Option<String>,
+}
+
+/// Lists the extension subscribes to and when each was last fetched.
+#[derive(Default)]
+struct ListCatalog {
+    lists: Vec<ListEntry>,
+}
+
+impl ListCatalog {
+    /// Replace the catalog. Update state carries over for lists whose id and
+    /// URL are unchanged, unless the new entry brings its own.
+    fn replace(&mut self, entries: Vec<ListEntry>) {
+        let mut previous = std::mem::take(&mut self.lists);
+        for mut entry in entries {
+            if let Some(pos) = previous.iter().position(|old| old.id == entry.id && old.url == entry.url) {
+                let old = previous.swap_remove(pos);
+                if entry.last_updated_ms.is_none() {
+                    entry.last_updated_ms = old.last_updated_ms;
+                    entry.etag = entry.etag.or(old.etag);
+                }
+            }
+            self.lists.push(entry);
+        }
+        self.lists.sort_by_key(|entry| entry.id);
+        self.lists.dedup_by_key(|entry| entry.id);
+    }
+
+    fn get_mut(&mut self, id: u16) -> Option<&mut ListEntry> {
+        self.lists.iter_mut().find(|entry| entry.id == id)
+    }
+}
+
+impl ListEntry {
+    fn expiry_ms(&self, snapshot_expiry: Option<u32>) -> u64 {
+        let secs = self
+            .expires_secs
+            .or(snapshot_expiry)
+            .unwrap_or(DEFAULT_LIST_EXPIRY_SECS)
+            .max(MIN_LIST_EXPIRY_SECS);
+        u64::from(secs) * 1000
+    }
+
+    /// When the list should next be fetched; `None` for disabled lists.
+    /// Lists never fetched are due immediately.
+    fn next_update_ms(&self, snapshot_expiry: Option<u32>) -> Option<u64> {
+        if !self.enabled {
+            return None;
+        }
+        Some(match self.last_updated_ms {
+            Some(last) => last.saturating_add(self.expiry_ms(snapshot_expiry)),
+            None => 0,
+        })
+    }
+}
+
 struct RuntimeState {
-    dynamic_rules: Vec<DynamicRule>,
+    list_catalog: ListCatalog,
+    dynamic_rules: DynamicRuleSet,
     settings: RuntimeSettings,
     removeparam_redirects: HashMap<String, RemoveparamEntry>,
     trace_enabled: bool,
+    trace_capture_outcome: bool,
     trace_max_entries: usize,
-    trace_entries: Vec<TraceEntry>,
+    trace_entries: VecDeque<TraceEntry>,
+    trace_next_seq: u64,
     perf_enabled: bool,
     perf_max_entries: usize,
     perf_before_request: PerfBucket,
     perf_headers_received: PerfBucket,
+    /// Requests cut short by the match budget while perf recording is on
+    perf_budget_exceeded: u32,
+    previous_snapshot: Option<PreviousSnapshot>,
+    user_styles: Vec<UserStyle>,
+    /// tab_id -> frame_id -> frame
+    frames: HashMap<i32, HashMap<i32, FrameInfo>>,
 }
 
 impl Default for RuntimeState {
     fn default() -> Self {
         Self {
-            dynamic_rules: Vec::new(),
+            list_catalog: ListCatalog::default(),
+            dynamic_rules: DynamicRuleSet::default(),
             settings: RuntimeSettings::default(),
             removeparam_redirects: HashMap::new(),
             trace_enabled: false,
+            trace_capture_outcome: false,
             trace_max_entries: MAX_TRACE_ENTRIES,
-            trace_entries: Vec::new(),
+            trace_entries: VecDeque::new(),
+            trace_next_seq: 0,
             perf_enabled: false,
             perf_max_entries: MAX_PERF_ENTRIES,
             perf_before_request: PerfBucket::default(),
             perf_headers_received: PerfBucket::default(),
+            perf_budget_exceeded: 0,
+            previous_snapshot: None,
+            user_styles: Vec::new(),
+            frames: HashMap::new(),
         }
     }
 }
 
 thread_local! {
     static RUNTIME_STATE: RefCell<RuntimeState> = RefCell::new(RuntimeState::default());
+    static COSMETIC_CACHE: RefCell<CosmeticCache> = RefCell::new(CosmeticCache::default());
+}
+
+/// Recent `match_cosmetics` results. A page's cosmetics only depend on its
+/// site host and the hide exceptions that match it, so revisiting a site
+/// skips the selector scan. The exceptions themselves are kept per page, so
+/// the generic and logger calls a content script makes for each DOM
+/// mutation batch don't look them up again. Entries belong to one snapshot
+/// epoch and are dropped whenever the matcher or the runtime settings change.
+#[derive(Default)]
+struct CosmeticCache {
+    epoch: u32,
+    /// Least recently used first
+    entries: VecDeque<(CosmeticCacheKey, Rc<CosmeticMatchResult>)>,
+    /// Least recently used first
+    switches: VecDeque<(PageKey, CosmeticHideSwitches)>,
+}
+
+type CosmeticCacheKey = (String, CosmeticHideSwitches);
+
+/// Page URL, site host and request type: what the hide exceptions match on.
+type PageKey = (String, String, RequestType);
+
+impl CosmeticCache {
+    fn get(&mut self, epoch: u32, key: &CosmeticCacheKey) -> Option<Rc<CosmeticMatchResult>> {
+        if epoch != self.epoch {
+            return None;
+        }
+        let index = self.entries.iter().position(|(entry_key, _)| entry_key == key)?;
+        let entry = self.entries.remove(index)?;
+        let result = Rc::clone(&entry.1);
+        self.entries.push_back(entry);
+        Some(result)
+    }
+
+    fn insert(&mut self, epoch: u32, key: CosmeticCacheKey, result: Rc<CosmeticMatchResult>) {
+        self.set_epoch(epoch);
+        if self.entries.len() >= COSMETIC_CACHE_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, result));
+    }
+
+    fn get_switches(&mut self, epoch: u32, ctx: &RequestContext<'_>) -> Option<CosmeticHideSwitches> {
+        if epoch != self.epoch {
+            return None;
+        }
+        let index = self.switches.iter().position(|((url, site_host, request_type), _)| {
+            url == ctx.url && site_host == ctx.site_host && *request_type == ctx.request_type
+        })?;
+        let entry = self.switches.remove(index)?;
+        let switches = entry.1;
+        self.switches.push_back(entry);
+        Some(switches)
+    }
+
+    fn insert_switches(&mut self, epoch: u32, ctx: &RequestContext<'_>, switches: CosmeticHideSwitches) {
+        self.set_epoch(epoch);
+        if self.switches.len() >= COSMETIC_CACHE_ENTRIES {
+            self.switches.pop_front();
+        }
+        let key = (ctx.url.to_string(), ctx.site_host.to_string(), ctx.request_type);
+        self.switches.push_back((key, switches));
+    }
+
+    fn set_epoch(&mut self, epoch: u32) {
+        if epoch != self.epoch {
+            self.clear();
+            self.epoch = epoch;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.switches.clear();
+    }
+
+    fn heap_bytes(&self) -> usize {
+        let result_bytes: usize = self
+            .entries
+            .iter()
+            .map(|((site, _), result)| {
+                site.capacity()
+                    + result.css.iter().map(String::capacity).sum::<usize>()
+                    + result.style_css.capacity()
+                    + result.procedural.iter().map(|program| program.base.capacity()).sum::<usize>()
+                    + result.procedural.iter().flat_map(|program| &program.ops).map(|op| op.arg.capacity()).sum::<usize>()
+                    + result.scriptlets.iter().map(|call| call.name.capacity()).sum::<usize>()
+            })
+            .sum();
+        let switch_bytes: usize = self
+            .switches
+            .iter()
+            .map(|((url, site_host, _), _)| url.capacity() + site_host.capacity())
+            .sum();
+        result_bytes + switch_bytes
+    }
+}
+
+fn invalidate_cosmetic_cache() {
+    COSMETIC_CACHE.with(|cache| cache.borrow_mut().clear());
 }
 
 const REMOVEPARAM_TTL_MS: u64 = 10_000;
 const MAX_SCRIPTLETS: usize = 32;
 const MAX_SCRIPTLET_ARGS: usize = 8;
 const MAX_PROCEDURAL_RULES: usize = 64;
+const MAX_GENERIC_COSMETIC_KEYS: usize = 4096;
+const COSMETIC_CACHE_ENTRIES: usize = 64;
 const MAX_TRACE_ENTRIES: usize = 50_000;
 const MAX_TRACE_ENTRIES_UPPER: usize = 500_000;
 const MAX_PERF_ENTRIES: usize = 100_000;
 const MAX_PERF_ENTRIES_UPPER: usize = 1_000_000;
+const RELOAD_GRACE_MS: u64 = 10 * 60 * 1000;
+const MAX_DIAGNOSTIC_ENTRIES: usize = 1_000;
+const MAX_FRAMES_PER_TAB: usize = 512;
+const MAX_FRAME_DEPTH: usize = 32;
+const DEFAULT_LIST_EXPIRY_SECS: u32 = 24 * 60 * 60;
+const MIN_LIST_EXPIRY_SECS: u32 = 60 * 60;
+const MIN_SNAPSHOT_CHUNK_BYTES: usize = 4 * 1024;
+/// Leads the header chunk from `snapshot_chunks`
+const SNAPSHOT_CHUNKS_MAGIC: &[u8; 4] = b"UBXC";
+/// Magic, total length, CRC-32 of the snapshot, data chunk count
+const SNAPSHOT_CHUNKS_HEADER_BYTES: usize = 16;
+
+impl RuntimeState {
+    fn trace_bytes(&self) -> usize {
+        self.trace_entries.capacity() * std::mem::size_of::<TraceEntry>()
+            + self.trace_entries.iter().map(TraceEntry::heap_bytes).sum::<usize>()
+    }
+
+    fn perf_bytes(&self) -> usize {
+        (self.perf_before_request.values.capacity() + self.perf_headers_received.values.capacity())
+            * std::mem::size_of::<f64>()
+    }
+
+    fn dynamic_rule_bytes(&self) -> usize {
+        self.dynamic_rules.heap_bytes()
+    }
+}
 
 fn with_runtime<R>(f: impl FnOnce(&mut RuntimeState) -> R) -> R {
     RUNTIME_STATE.with(|state| {
@@ -139,78 +756,457 @@ fn now_ms() -> u64 {
     js_sys::Date::now() as u64
 }
 
+/// Load the first snapshot. wasm-bindgen copies `snapshot_data` straight
+/// into the buffer the matcher keeps, so the bytes exist once in wasm memory.
 #[wasm_bindgen]
-pub fn init(snapshot_data: &[u8]) -> Result<(), JsValue> {
-    if MATCHER_STATE.get().is_some() {
-        return Err(JsValue::from_str("Already initialized. Reload the page to reinitialize."));
+pub fn init(snapshot_data: Vec<u8>) -> Result<(), JsValue> {
+    init_state(snapshot_data.into_boxed_slice())
+}
+
+/// Like [`init`], for a snapshot held in an `ArrayBuffer` (e.g. straight from
+/// `Response.arrayBuffer()` or IndexedDB) without wrapping it first.
+///
+/// Wasm can only read its own linear memory, so the buffer is copied once,
+/// directly into the allocation the matcher keeps. The buffer is only read
+/// during the call: the caller keeps ownership and may drop, reuse or
+/// transfer it as soon as this returns. Nothing refers to it afterwards.
+#[wasm_bindgen]
+pub fn init_from_shared(buffer: &js_sys::ArrayBuffer) -> Result<(), JsValue> {
+    init_state(copy_array_buffer(buffer))
+}
+
+fn init_state(snapshot_data: Box<[u8]>) -> Result<(), JsValue> {
+    if is_initialized() {
+        return Err(JsValue::from_str("Already initialized. Use reload() to swap snapshots."));
     }
 
-    let data: &'static [u8] = Box::leak(snapshot_data.to_vec().into_boxed_slice());
-    
-    let snapshot: &'static Snapshot<'static> = Box::leak(Box::new(
-        Snapshot::load(data)
-            .map_err(|e| JsValue::from_str(&format!("Failed to load snapshot: {}", e)))?
-    ));
-    
-    let matcher: &'static Matcher<'static> = Box::leak(Box::new(Matcher::new(snapshot)));
-    
-    MATCHER_STATE.set(MatcherState { data, snapshot, matcher })
-        .map_err(|_| JsValue::from_str("Failed to set matcher state"))?;
-    
+    let state = MatcherState::load(snapshot_data, next_epoch(), true)?;
+    MATCHER_STATE.with(|current| *current.borrow_mut() = Some(Rc::new(state)));
     Ok(())
 }
 
+/// Swap in a new snapshot without restarting the worker.
+///
+/// The old buffer is freed once no in-flight call holds it, unless
+/// `keep_previous` is set, in which case it is retained for the default
+/// grace period so `decision_changed_since_reload` can diff against it.
+/// Returns the new snapshot epoch.
+#[wasm_bindgen]
+pub fn reload(snapshot_data: Vec<u8>, keep_previous: bool) -> Result<u32, JsValue> {
+    reload_state(snapshot_data.into_boxed_slice(), keep_previous)
+}
+
+/// [`reload`] from an `ArrayBuffer`, with the same ownership contract as
+/// [`init_from_shared`].
+#[wasm_bindgen]
+pub fn reload_from_shared(buffer: &js_sys::ArrayBuffer, keep_previous: bool) -> Result<u32, JsValue> {
+    reload_state(copy_array_buffer(buffer), keep_previous)
+}
+
+fn reload_state(snapshot_data: Box<[u8]>, keep_previous: bool) -> Result<u32, JsValue> {
+    let state = Rc::new(MatcherState::load(snapshot_data, next_epoch(), true)?);
+    let epoch = state.epoch;
+    let old = MATCHER_STATE.with(|current| current.borrow_mut().replace(state));
+
+    with_runtime(|runtime| {
+        runtime.previous_snapshot = match old {
+            Some(old) if keep_previous => Some(PreviousSnapshot {
+                state: old,
+                expires_at: now_ms().saturating_add(RELOAD_GRACE_MS),
+            }),
+            _ => None,
+        };
+    });
+    Ok(epoch)
+}
+
+/// Copy an `ArrayBuffer` into a wasm allocation sized for it exactly.
+fn copy_array_buffer(buffer: &js_sys::ArrayBuffer) -> Box<[u8]> {
+    let view = js_sys::Uint8Array::new(buffer);
+    let mut data = vec![0u8; view.length() as usize].into_boxed_slice();
+    view.copy_to(&mut data);
+    data
+}
+
+/// The active snapshot split for storage: a 16-byte header chunk (magic,
+/// total length, CRC-32 of the whole snapshot and the data chunk count, all
+/// little-endian `u32`) followed by data chunks of at most `chunk_size`
+/// bytes (at least 4 KiB). Each chunk is its own `Uint8Array`, so IndexedDB
+/// can store them as separate records.
+#[wasm_bindgen]
+pub fn snapshot_chunks(chunk_size: u32) -> Result<js_sys::Array, JsValue> {
+    let state = current_state().ok_or_else(|| JsValue::from_str("No snapshot loaded"))?;
+    let bytes = state.bytes();
+    let chunk_size = (chunk_size as usize).max(MIN_SNAPSHOT_CHUNK_BYTES);
+    let chunk_count = bytes.len().div_ceil(chunk_size);
+
+    let mut header = [0u8; SNAPSHOT_CHUNKS_HEADER_BYTES];
+    header[..4].copy_from_slice(SNAPSHOT_CHUNKS_MAGIC);
+    header[4..8].copy_from_slice(&(bytes.len() as u32).to_le_bytes());
+    header[8..12].copy_from_slice(&state.fingerprint.to_le_bytes());
+    header[12..16].copy_from_slice(&(chunk_count as u32).to_le_bytes());
+
+    let chunks = js_sys::Array::new();
+    chunks.push(&js_sys::Uint8Array::from(&header[..]));
+    for chunk in bytes.chunks(chunk_size) {
+        chunks.push(&js_sys::Uint8Array::from(chunk));
+    }
+    Ok(chunks)
+}
+
+/// [`init`] from the chunks [`snapshot_chunks`] produced. They are copied
+/// straight into one wasm allocation, and the result must match the header's
+/// length and CRC-32 before it is loaded, so a partial or mixed-up set read
+/// back from storage is rejected rather than half-loaded.
+#[wasm_bindgen]
+pub fn init_from_chunks(chunks: js_sys::Array) -> Result<(), JsValue> {
+    init_state(reassemble_chunks(&chunks)?)
+}
+
+fn reassemble_chunks(chunks: &js_sys::Array) -> Result<Box<[u8]>, JsValue> {
+    let chunk_at = |index: u32| {
+        chunks
+            .get(index)
+            .dyn_into::<js_sys::Uint8Array>()
+            .map_err(|_| JsValue::from_str(&format!("Snapshot chunk {} is not a Uint8Array", index)))
+    };
+
+    let mut header = [0u8; SNAPSHOT_CHUNKS_HEADER_BYTES];
+    let header_chunk = chunk_at(0)?;
+    if header_chunk.length() as usize != header.len() {
+        return Err(JsValue::from_str("Snapshot chunk header has the wrong size"));
+    }
+    header_chunk.copy_to(&mut header);
+    if &header[..4] != SNAPSHOT_CHUNKS_MAGIC {
+        return Err(JsValue::from_str("Snapshot chunk header has the wrong magic"));
+    }
+    let field = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    let (total_len, expected_crc, chunk_count) = (field(4) as usize, field(8), field(12));
+    if chunks.length() != chunk_count.saturating_add(1) {
+        return Err(JsValue::from_str(&format!(
+            "Expected {} snapshot chunks, got {}",
+            chunk_count,
+            chunks.length().saturating_sub(1)
+        )));
+    }
+
+    // The header is unverified until the CRC passes, so its length is only
+    // trusted once the chunks actually add up to it.
+    let data_chunks = (1..=chunk_count).map(chunk_at).collect::<Result<Vec<_>, _>>()?;
+    let chunks_len: u64 = data_chunks.iter().map(|chunk| u64::from(chunk.length())).sum();
+    if chunks_len > total_len as u64 {
+        return Err(JsValue::from_str("Snapshot chunks are longer than the header says"));
+    }
+    if chunks_len < total_len as u64 {
+        return Err(JsValue::from_str("Snapshot chunks are shorter than the header says"));
+    }
+
+    let mut data = vec![0u8; total_len].into_boxed_slice();
+    let mut offset = 0;
+    for chunk in &data_chunks {
+        let end = offset + chunk.length() as usize;
+        chunk.copy_to(&mut data[offset..end]);
+        offset = end;
+    }
+    if crc32(&data) != expected_crc {
+        return Err(JsValue::from_str("Snapshot chunks failed the CRC check"));
+    }
+    Ok(data)
+}
+
 #[wasm_bindgen]
 pub fn is_initialized() -> bool {
-    MATCHER_STATE.get().is_some()
+    MATCHER_STATE.with(|state| state.borrow().is_some())
 }
 
 #[wasm_bindgen]
 pub fn get_snapshot_info() -> JsValue {
     let result = js_sys::Object::new();
-    if let Some(state) = MATCHER_STATE.get() {
-        let _ = js_sys::Reflect::set(&result, &"size".into(), &JsValue::from(state.data.len()));
+    if let Some(state) = current_state() {
+        let _ = js_sys::Reflect::set(&result, &"size".into(), &JsValue::from(state.len()));
         let _ = js_sys::Reflect::set(&result, &"initialized".into(), &JsValue::from(true));
+        let _ = js_sys::Reflect::set(&result, &"fingerprint".into(), &JsValue::from(state.fingerprint));
+        let _ = js_sys::Reflect::set(&result, &"epoch".into(), &JsValue::from(state.epoch));
     } else {
         let _ = js_sys::Reflect::set(&result, &"initialized".into(), &JsValue::from(false));
     }
     result.into()
 }
 
+/// Header metadata (`! Title:`, `! Expires:`, ...) embedded for `list_id`,
+/// or null if the active snapshot has none.
 #[wasm_bindgen]
-pub fn compile_filter_lists(list_texts: JsValue) -> Result<JsValue, JsValue> {
-    let list_array = js_sys::Array::from(&list_texts);
-    let list_count = list_array.length() as usize;
+pub fn get_list_metadata(list_id: u16) -> JsValue {
+    let Some(state) = current_state() else {
+        return JsValue::NULL;
+    };
+    let Some(meta) = state.snapshot().list_metadata(list_id) else {
+        return JsValue::NULL;
+    };
 
-    if list_count == 0 {
-        return Err(JsValue::from_str("No list texts provided"));
+    let result = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&result, &"listId".into(), &JsValue::from(meta.list_id));
+    if let Some(title) = meta.title {
+        let _ = js_sys::Reflect::set(&result, &"title".into(), &JsValue::from_str(title));
     }
+    if let Some(version) = meta.version {
+        let _ = js_sys::Reflect::set(&result, &"version".into(), &JsValue::from_str(version));
+    }
+    if let Some(homepage) = meta.homepage {
+        let _ = js_sys::Reflect::set(&result, &"homepage".into(), &JsValue::from_str(homepage));
+    }
+    if let Some(expires_secs) = meta.expires_secs {
+        let _ = js_sys::Reflect::set(&result, &"expiresSecs".into(), &JsValue::from(expires_secs));
+    }
+    let _ = js_sys::Reflect::set(&result, &"tier".into(), &JsValue::from(meta.tier as u8));
+    result.into()
+}
 
-    let mut all_rules = Vec::new();
-    let mut line_counts: Vec<usize> = Vec::with_capacity(list_count);
-    let mut rules_before_per_list: Vec<usize> = Vec::with_capacity(list_count);
-
-    for (idx, value) in list_array.iter().enumerate() {
-        let text = value
-            .as_string()
-            .ok_or_else(|| JsValue::from_str("List text must be a string"))?;
+/// Provenance of the active snapshot as `{ compiledAt, compilerVersion,
+/// lists: [{ listId, source, contentHash }] }`, or null if it has none.
+/// Include this in bug reports so the exact list versions are known.
+#[wasm_bindgen]
+pub fn get_build_info() -> JsValue {
+    let Some(state) = current_state() else {
+        return JsValue::NULL;
+    };
+    let Some(info) = state.snapshot().build_info() else {
+        return JsValue::NULL;
+    };
 
-        line_counts.push(text.lines().count());
+    let result = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&result, &"compiledAt".into(), &JsValue::from(info.compiled_at as f64));
+    let _ = js_sys::Reflect::set(
+        &result,
+        &"compilerVersion".into(),
+        &JsValue::from_str(info.compiler_version),
+    );
+    let lists = js_sys::Array::new();
+    for list in &info.lists {
+        let entry = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&entry, &"listId".into(), &JsValue::from(list.list_id));
+        let _ = js_sys::Reflect::set(&entry, &"source".into(), &JsValue::from_str(list.source));
+        let _ = js_sys::Reflect::set(
+            &entry,
+            &"contentHash".into(),
+            &JsValue::from_str(&list.content_hash_hex()),
+        );
+        lists.push(&entry);
+    }
+    let _ = js_sys::Reflect::set(&result, &"lists".into(), &lists);
+    result.into()
+}
 
-        let mut rules = parse_filter_list(&text);
-        for rule in &mut rules {
-            rule.list_id = idx as u16;
+/// Redirect resources registered in the active snapshot, as
+/// `{ name, path, mime, checksum? }`, so the extension can check its bundled
+/// files against what the compiler expected.
+#[wasm_bindgen]
+pub fn redirect_resources() -> JsValue {
+    let result = js_sys::Array::new();
+    let Some(state) = current_state() else {
+        return result.into();
+    };
+    for resource in state.snapshot().redirect_resources() {
+        let entry = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&entry, &"name".into(), &JsValue::from_str(resource.name));
+        let _ = js_sys::Reflect::set(&entry, &"path".into(), &JsValue::from_str(resource.path));
+        let _ = js_sys::Reflect::set(&entry, &"mime".into(), &JsValue::from_str(resource.mime));
+        if let Some(checksum) = resource.checksum {
+            let _ = js_sys::Reflect::set(&entry, &"checksum".into(), &JsValue::from(checksum));
         }
-
-        rules_before_per_list.push(rules.len());
-        all_rules.extend(rules);
+        result.push(&entry);
     }
+    result.into()
+}
 
-    let optimize_stats = optimize_rules(&mut all_rules);
-    let rules_before_total = optimize_stats.before;
-    let rules_after_total = optimize_stats.after;
+/// Ready-to-inject JS for one scriptlet call, with `args` interpolated as
+/// escaped string literals. `args` may hold the values `match_cosmetics`
+/// returns (numbers, booleans, null), which are turned back into their
+/// filter-list text. Fails for names or argument counts the compiler would
+/// reject.
+#[wasm_bindgen]
+pub fn get_scriptlet_source(name: &str, args: JsValue) -> Result<String, JsValue> {
+    let args: Vec<String> = js_sys::Array::from(&args)
+        .iter()
+        .take(MAX_SCRIPTLET_ARGS)
+        .map(|arg| {
+            if let Some(text) = arg.as_string() {
+                text
+            } else if let Some(flag) = arg.as_bool() {
+                flag.to_string()
+            } else if let Some(number) = arg.as_f64() {
+                number.to_string()
+            } else if arg.is_null() {
+                "null".to_string()
+            } else {
+                "undefined".to_string()
+            }
+        })
+        .collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    render_scriptlet(name, &args).map_err(|issue| JsValue::from_str(&issue.to_string()))
+}
 
-    let mut rules_after_per_list = vec![0usize; list_count];
+/// Filter line for the element picker's "create rule" flow. `options` is
+/// `{ stripNth?, stripIds? }`; the result is `{ filter, selector, kind,
+/// compiledSelector }` with `kind` either `"hide"` or `"procedural"`.
+#[wasm_bindgen]
+pub fn generate_cosmetic_rule(hostname: &str, selector: &str, options: JsValue) -> Result<JsValue, JsValue> {
+    let flag = |name: &str| {
+        js_sys::Reflect::get(&options, &JsValue::from_str(name))
+            .ok()
+            .and_then(|value| value.as_bool())
+            .unwrap_or(false)
+    };
+    let options = PickerOptions {
+        strip_nth: flag("stripNth"),
+        strip_ids: flag("stripIds"),
+    };
+    let rule = bb_compiler::generate_cosmetic_rule(hostname, selector, options)
+        .map_err(|issue| JsValue::from_str(&issue.to_string()))?;
+
+    let kind = match rule.kind {
+        PickerRuleKind::Hide => "hide",
+        PickerRuleKind::Procedural => "procedural",
+    };
+    let result = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&result, &"filter".into(), &JsValue::from_str(&rule.filter));
+    let _ = js_sys::Reflect::set(&result, &"selector".into(), &JsValue::from_str(&rule.selector));
+    let _ = js_sys::Reflect::set(&result, &"kind".into(), &JsValue::from_str(kind));
+    let _ = js_sys::Reflect::set(&result, &"compiledSelector".into(), &JsValue::from_str(&rule.compiled_selector));
+    Ok(result.into())
+}
+
+/// Candidate filters for a logged request, narrowest first, as
+/// `{ scope, filter }` with `scope` one of `"url"`, `"path"`, `"hostname"`,
+/// `"domain"`. `choices` is `{ allow?, thirdParty?, matchType?, siteOnly? }`;
+/// `site` is the page hostname.
+#[wasm_bindgen]
+pub fn generate_network_rule(
+    url: &str,
+    request_type: &str,
+    site: Option<String>,
+    choices: JsValue,
+) -> Result<JsValue, JsValue> {
+    let flag = |name: &str| {
+        js_sys::Reflect::get(&choices, &JsValue::from_str(name))
+            .ok()
+            .and_then(|value| value.as_bool())
+            .unwrap_or(false)
+    };
+    let choices = NetworkRuleChoices {
+        allow: flag("allow"),
+        third_party: flag("thirdParty"),
+        match_type: flag("matchType"),
+        site_only: flag("siteOnly"),
+    };
+    let candidates =
+        bb_compiler::generate_network_rule(url, parse_request_type(request_type), site.as_deref(), choices)
+            .map_err(|issue| JsValue::from_str(&issue.to_string()))?;
+
+    let result = js_sys::Array::new();
+    for candidate in candidates {
+        let entry = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&entry, &"scope".into(), &JsValue::from_str(candidate.scope.as_str()));
+        let _ = js_sys::Reflect::set(&entry, &"filter".into(), &JsValue::from_str(&candidate.filter));
+        result.push(&entry);
+    }
+    Ok(result.into())
+}
+
+/// CRC32 of a bundled resource, comparable with `redirect_resources()`.
+#[wasm_bindgen]
+pub fn resource_checksum(data: &[u8]) -> u32 {
+    crc32(data)
+}
+
+/// Compile list texts into a snapshot.
+///
+/// `include_resolver` is called synchronously with each `!#include` target
+/// and should return its text (or undefined); `env_flags` adds `!#if` flags
+/// such as `env_firefox`. `list_sources` (usually the list URLs, parallel to
+/// `list_texts`) is recorded in the snapshot's build info. `list_tiers`,
+/// also parallel, ranks each list: 0 built-in, 1 custom, 2 user; the top
+/// tier's rules decide a request before any lower tier's.
+#[wasm_bindgen]
+pub fn compile_filter_lists(
+    list_texts: JsValue,
+    include_resolver: Option<js_sys::Function>,
+    env_flags: Option<Vec<String>>,
+    list_sources: Option<Vec<String>>,
+    list_tiers: Option<Vec<u8>>,
+) -> Result<JsValue, JsValue> {
+    let list_array = js_sys::Array::from(&list_texts);
+    let list_count = list_array.length() as usize;
+
+    if list_count == 0 {
+        return Err(JsValue::from_str("No list texts provided"));
+    }
+
+    let env = env_flags
+        .unwrap_or_default()
+        .iter()
+        .fold(PreprocessEnv::default(), |env, flag| env.with_flag(flag));
+    let js_resolver = include_resolver.map(|resolver| {
+        move |name: &str| -> Option<String> {
+            resolver
+                .call1(&JsValue::NULL, &JsValue::from_str(name))
+                .ok()?
+                .as_string()
+        }
+    });
+    let mut unresolved_per_list: Vec<Vec<String>> = Vec::with_capacity(list_count);
+
+    let mut all_rules = Vec::new();
+    let mut options = SnapshotOptions::default();
+    let mut build_info = BuildInfo::new((js_sys::Date::now() / 1000.0) as u64);
+    let list_sources = list_sources.unwrap_or_default();
+    let list_tiers = list_tiers.unwrap_or_default();
+    let mut line_counts: Vec<usize> = Vec::with_capacity(list_count);
+    let mut rules_before_per_list: Vec<usize> = Vec::with_capacity(list_count);
+    let mut diagnostics = CompileDiagnostics::default();
+
+    for (idx, value) in list_array.iter().enumerate() {
+        let text = value
+            .as_string()
+            .ok_or_else(|| JsValue::from_str("List text must be a string"))?;
+
+        line_counts.push(text.lines().count());
+        let source = list_sources
+            .get(idx)
+            .cloned()
+            .unwrap_or_else(|| format!("list:{}", idx));
+        build_info.add_list(idx as u16, &source, &text);
+
+        let preprocessed = match &js_resolver {
+            Some(resolver) => preprocess_filter_list(&text, &env, resolver),
+            None => preprocess_filter_list(&text, &env, &NoIncludes),
+        };
+        unresolved_per_list.push(preprocessed.unresolved);
+
+        let first_rule = all_rules.len();
+        let list_diagnostics =
+            parse_filter_list_into(&preprocessed.text, &ParserConfig::default(), idx as u16, &mut all_rules);
+        diagnostics.merge(idx as u16, list_diagnostics);
+
+        let mut metadata = parse_list_metadata(&text);
+        metadata.list_id = idx as u16;
+        if let Some(&tier) = list_tiers.get(idx) {
+            metadata.tier =
+                ListTier::try_from(tier).map_err(|_| JsValue::from_str(&format!("Unknown list tier {}", tier)))?;
+        }
+        options.list_metadata.push(metadata);
+
+        rules_before_per_list.push(all_rules.len() - first_rule);
+    }
+
+    options.build_info = Some(build_info);
+    let optimize_stats = optimize_rules(&mut all_rules);
+    diagnostics.record_optimize(&optimize_stats);
+    let rules_before_total = optimize_stats.before;
+    let rules_after_total = optimize_stats.after;
+
+    let mut rules_after_per_list = vec![0usize; list_count];
     for rule in &all_rules {
         let list_id = rule.list_id as usize;
         if list_id < list_count {
@@ -218,7 +1214,7 @@ pub fn compile_filter_lists(list_texts: JsValue) -> Result<JsValue, JsValue> {
         }
     }
 
-    let snapshot = build_snapshot(&all_rules);
+    let snapshot = try_build_snapshot_with_options(&all_rules, &options).map_err(|e| compile_error_to_js(&e))?;
     let js_result = js_sys::Object::new();
     let snapshot_array = js_sys::Uint8Array::from(snapshot.as_slice());
 
@@ -228,6 +1224,7 @@ pub fn compile_filter_lists(list_texts: JsValue) -> Result<JsValue, JsValue> {
     let _ = js_sys::Reflect::set(&js_result, &"rulesDeduped".into(), &JsValue::from(optimize_stats.deduped as u32));
     let _ = js_sys::Reflect::set(&js_result, &"badfilterRules".into(), &JsValue::from(optimize_stats.badfilter_rules as u32));
     let _ = js_sys::Reflect::set(&js_result, &"badfilteredRules".into(), &JsValue::from(optimize_stats.badfiltered_rules as u32));
+    let _ = js_sys::Reflect::set(&js_result, &"shadowedRules".into(), &JsValue::from(optimize_stats.shadowed as u32));
 
     let list_stats = js_sys::Array::new_with_length(list_count as u32);
     for i in 0..list_count {
@@ -235,14 +1232,111 @@ pub fn compile_filter_lists(list_texts: JsValue) -> Result<JsValue, JsValue> {
         let _ = js_sys::Reflect::set(&stat, &"lines".into(), &JsValue::from(line_counts[i] as u32));
         let _ = js_sys::Reflect::set(&stat, &"rulesBefore".into(), &JsValue::from(rules_before_per_list[i] as u32));
         let _ = js_sys::Reflect::set(&stat, &"rulesAfter".into(), &JsValue::from(rules_after_per_list[i] as u32));
+        let metadata = &options.list_metadata[i];
+        if let Some(title) = &metadata.title {
+            let _ = js_sys::Reflect::set(&stat, &"title".into(), &JsValue::from_str(title));
+        }
+        if let Some(version) = &metadata.version {
+            let _ = js_sys::Reflect::set(&stat, &"version".into(), &JsValue::from_str(version));
+        }
+        if let Some(expires_secs) = metadata.expires_secs {
+            let _ = js_sys::Reflect::set(&stat, &"expiresSecs".into(), &JsValue::from(expires_secs));
+        }
+        if !unresolved_per_list[i].is_empty() {
+            let unresolved = js_sys::Array::new();
+            for name in &unresolved_per_list[i] {
+                unresolved.push(&JsValue::from_str(name));
+            }
+            let _ = js_sys::Reflect::set(&stat, &"unresolvedIncludes".into(), &unresolved);
+        }
         list_stats.set(i as u32, stat.into());
     }
 
     let _ = js_sys::Reflect::set(&js_result, &"listStats".into(), &list_stats);
+    let _ = js_sys::Reflect::set(&js_result, &"diagnostics".into(), &diagnostics_to_js(&diagnostics));
+
+    let errors = diagnostics.errors();
+    let errors_array = js_sys::Array::new();
+    for error in errors.iter().take(MAX_DIAGNOSTIC_ENTRIES) {
+        errors_array.push(&compile_error_entry(error));
+    }
+    let _ = js_sys::Reflect::set(&js_result, &"errors".into(), &errors_array);
+    let _ = js_sys::Reflect::set(&js_result, &"errorsTotal".into(), &JsValue::from(errors.len() as u32));
 
     Ok(js_result.into())
 }
 
+/// `{code, message, listId?, line?}` for one compile error.
+fn compile_error_entry(error: &CompileError) -> js_sys::Object {
+    let obj = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&obj, &"code".into(), &JsValue::from_str(error.code()));
+    let _ = js_sys::Reflect::set(&obj, &"message".into(), &JsValue::from_str(&error.to_string()));
+    if let Some((list_id, line)) = error.location() {
+        let _ = js_sys::Reflect::set(&obj, &"listId".into(), &JsValue::from(list_id));
+        let _ = js_sys::Reflect::set(&obj, &"line".into(), &JsValue::from(line));
+    }
+    obj
+}
+
+/// A thrown `Error` carrying the compile error's `code`.
+fn compile_error_to_js(error: &CompileError) -> JsValue {
+    let js_error = js_sys::Error::new(&error.to_string());
+    let _ = js_sys::Reflect::set(&js_error, &"code".into(), &JsValue::from_str(error.code()));
+    js_error.into()
+}
+
+/// Convert compile diagnostics to a JS object, keeping at most
+/// `MAX_DIAGNOSTIC_ENTRIES` dropped lines and duplicates.
+fn diagnostics_to_js(diagnostics: &CompileDiagnostics) -> JsValue {
+    let result = js_sys::Object::new();
+
+    let unsupported = js_sys::Object::new();
+    for (name, count) in &diagnostics.unsupported_options {
+        let _ = js_sys::Reflect::set(&unsupported, &name.as_str().into(), &JsValue::from(*count as u32));
+    }
+    let _ = js_sys::Reflect::set(&result, &"unsupportedOptions".into(), &unsupported);
+
+    let legacy = js_sys::Object::new();
+    for (name, count) in &diagnostics.legacy_options {
+        let _ = js_sys::Reflect::set(&legacy, &name.as_str().into(), &JsValue::from(*count as u32));
+    }
+    let _ = js_sys::Reflect::set(&result, &"legacyOptions".into(), &legacy);
+
+    let dropped = js_sys::Array::new();
+    for entry in diagnostics.dropped_lines.iter().take(MAX_DIAGNOSTIC_ENTRIES) {
+        let obj = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&obj, &"listId".into(), &JsValue::from(entry.list_id));
+        let _ = js_sys::Reflect::set(&obj, &"line".into(), &JsValue::from(entry.line));
+        let _ = js_sys::Reflect::set(&obj, &"text".into(), &JsValue::from_str(&entry.text));
+        let _ = js_sys::Reflect::set(&obj, &"reason".into(), &JsValue::from_str(&entry.reason.to_string()));
+        dropped.push(&obj);
+    }
+    let _ = js_sys::Reflect::set(&result, &"droppedLines".into(), &dropped);
+    let _ = js_sys::Reflect::set(
+        &result,
+        &"droppedTotal".into(),
+        &JsValue::from(diagnostics.dropped_lines.len() as u32),
+    );
+
+    let duplicates = js_sys::Array::new();
+    for dup in diagnostics.duplicates.iter().take(MAX_DIAGNOSTIC_ENTRIES) {
+        let obj = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&obj, &"listId".into(), &JsValue::from(dup.list_id));
+        let _ = js_sys::Reflect::set(&obj, &"line".into(), &JsValue::from(dup.line));
+        let _ = js_sys::Reflect::set(&obj, &"originalListId".into(), &JsValue::from(dup.original_list_id));
+        let _ = js_sys::Reflect::set(&obj, &"originalLine".into(), &JsValue::from(dup.original_line));
+        duplicates.push(&obj);
+    }
+    let _ = js_sys::Reflect::set(&result, &"duplicates".into(), &duplicates);
+    let _ = js_sys::Reflect::set(
+        &result,
+        &"duplicatesTotal".into(),
+        &JsValue::from(diagnostics.duplicates.len() as u32),
+    );
+
+    result.into()
+}
+
 #[wasm_bindgen]
 pub fn match_request(
     url: &str,
@@ -252,9 +1346,9 @@ pub fn match_request(
     frame_id: i32,
     request_id: &str,
 ) -> JsValue {
-    let matcher = match MATCHER_STATE.get() {
-        Some(state) => state.matcher,
-        None => {
+    let state = match current_state() {
+        Some(state) if state.engine().matcher().is_filterable(url) => state,
+        _ => {
             let result = js_sys::Object::new();
             let _ = js_sys::Reflect::set(&result, &"decision".into(), &JsValue::from(0));
             let _ = js_sys::Reflect::set(&result, &"ruleId".into(), &JsValue::from(-1));
@@ -262,46 +1356,33 @@ pub fn match_request(
             return result.into();
         }
     };
+    let engine = state.engine();
 
-    let req_host = extract_host(url).unwrap_or("");
-    let req_etld1 = get_etld1(req_host);
-
-    let is_main_frame = matches!(request_type, "main_frame" | "document");
-    let site_host = if is_main_frame {
-        req_host
-    } else {
-        initiator
-            .as_deref()
-            .and_then(extract_host)
-            .filter(|host| !host.is_empty())
-            .unwrap_or(req_host)
-    };
-    let site_etld1 = get_etld1(site_host);
-
-    let scheme = bb_core::url::extract_scheme(url).unwrap_or(SchemeMask::HTTP);
-    let is_third_party = !site_etld1.is_empty() && req_etld1 != site_etld1;
-    let request_type_mask = parse_request_type(request_type);
-    
-    let ctx = RequestContext {
-        url,
-        req_host,
-        req_etld1: &req_etld1,
-        site_host,
-        site_etld1: &site_etld1,
-        scheme,
-        request_type: request_type_mask,
-        is_third_party,
-        tab_id,
-        frame_id,
-        request_id,
-    };
+    let frame_site = frame_site_host(tab_id, frame_id, request_type);
+    let ws_url = websocket_request_url(url, request_type);
+    let url = ws_url.as_deref().unwrap_or(url);
+    let request = request_info(url, request_type, initiator.as_deref(), frame_site.as_deref())
+        .with_ids(tab_id, frame_id, request_id);
     
-    let result = matcher.match_request(&ctx);
+    let result = engine.check_request(&request);
+    if result.budget_exceeded {
+        with_runtime(|state| {
+            if state.perf_enabled {
+                state.perf_budget_exceeded = state.perf_budget_exceeded.saturating_add(1);
+            }
+        });
+    }
     
     let js_result = js_sys::Object::new();
     let _ = js_sys::Reflect::set(&js_result, &"decision".into(), &JsValue::from(result.decision as u8));
     let _ = js_sys::Reflect::set(&js_result, &"ruleId".into(), &JsValue::from(result.rule_id));
     let _ = js_sys::Reflect::set(&js_result, &"listId".into(), &JsValue::from(result.list_id));
+    if result.budget_exceeded {
+        let _ = js_sys::Reflect::set(&js_result, &"budgetExceeded".into(), &JsValue::from(true));
+    }
+    if !result.site_switch.is_empty() {
+        let _ = js_sys::Reflect::set(&js_result, &"siteSwitch".into(), &JsValue::from(result.site_switch.bits()));
+    }
     
     if let Some(redirect_url) = result.redirect_url {
         let _ = js_sys::Reflect::set(&js_result, &"redirectUrl".into(), &JsValue::from_str(&redirect_url));
@@ -310,61 +1391,99 @@ pub fn match_request(
     js_result.into()
 }
 
+/// Decide a tab or window `opener_url` opened for `url`: `{ decision, ruleId,
+/// listId }`. Only `$popup` rules apply, with the opener as the site, so the
+/// background script can close the new tab on a block.
 #[wasm_bindgen]
-pub fn match_response_headers(
+pub fn match_popup(url: &str, opener_url: &str) -> JsValue {
+    let js_result = js_sys::Object::new();
+    let result = match current_state() {
+        Some(state) => state.engine().check_popup(url, opener_url),
+        None => MatchResult::default(),
+    };
+    let _ = js_sys::Reflect::set(&js_result, &"decision".into(), &JsValue::from(result.decision as u8));
+    let _ = js_sys::Reflect::set(&js_result, &"ruleId".into(), &JsValue::from(result.rule_id));
+    let _ = js_sys::Reflect::set(&js_result, &"listId".into(), &JsValue::from(result.list_id));
+    js_result.into()
+}
+
+/// Every rule `match_request` considered for a request, for debugging false
+/// positives: `{ decision, ruleId, listId, redirectUrl?, reason, reasonText,
+/// genericblock, tokens, candidates }`, with each candidate a `{ ruleId,
+/// listId, action, important, priority, source, sourceKey?, matched,
+/// rejected?, rejectedText? }` object.
+#[wasm_bindgen]
+pub fn explain_request(
     url: &str,
     request_type: &str,
     initiator: Option<String>,
     tab_id: i32,
     frame_id: i32,
-    request_id: &str,
-    headers: JsValue,
 ) -> JsValue {
-    let matcher = match MATCHER_STATE.get() {
-        Some(state) => state.matcher,
-        None => {
-            let result = js_sys::Object::new();
-            let _ = js_sys::Reflect::set(&result, &"cancel".into(), &JsValue::from(false));
-            return result.into();
-        }
+    let Some(state) = current_state() else {
+        return JsValue::NULL;
     };
+    let engine = state.engine();
 
-    let req_host = extract_host(url).unwrap_or("");
-    let req_etld1 = get_etld1(req_host);
+    let frame_site = frame_site_host(tab_id, frame_id, request_type);
+    let ws_url = websocket_request_url(url, request_type);
+    let url = ws_url.as_deref().unwrap_or(url);
+    let request = request_info(url, request_type, initiator.as_deref(), frame_site.as_deref());
+    let explanation = engine.explain_request(&request);
 
-    let is_main_frame = matches!(request_type, "main_frame" | "document");
-    let site_host = if is_main_frame {
-        req_host
-    } else {
-        initiator
-            .as_deref()
-            .and_then(extract_host)
-            .filter(|host| !host.is_empty())
-            .unwrap_or(req_host)
-    };
-    let site_etld1 = get_etld1(site_host);
-
-    let scheme = bb_core::url::extract_scheme(url).unwrap_or(SchemeMask::HTTP);
-    let is_third_party = !site_etld1.is_empty() && req_etld1 != site_etld1;
-    let request_type_mask = parse_request_type(request_type);
-
-    let ctx = RequestContext {
-        url,
-        req_host,
-        req_etld1: &req_etld1,
-        site_host,
-        site_etld1: &site_etld1,
-        scheme,
-        request_type: request_type_mask,
-        is_third_party,
-        tab_id,
-        frame_id,
-        request_id,
-    };
+    let candidates = js_sys::Array::new();
+    for candidate in &explanation.candidates {
+        let obj = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&obj, &"ruleId".into(), &JsValue::from(candidate.rule_id as u32));
+        let _ = js_sys::Reflect::set(&obj, &"listId".into(), &JsValue::from(candidate.list_id));
+        let _ = js_sys::Reflect::set(&obj, &"action".into(), &JsValue::from(candidate.action as u8));
+        let _ = js_sys::Reflect::set(&obj, &"important".into(), &JsValue::from(candidate.important));
+        let _ = js_sys::Reflect::set(&obj, &"priority".into(), &JsValue::from(candidate.priority));
+        let (source, key) = match &candidate.source {
+            CandidateSource::DomainSet { suffix } => ("domain", Some(suffix)),
+            CandidateSource::Token { token } => ("token", Some(token)),
+            CandidateSource::HostAnchor { suffix } => ("host", Some(suffix)),
+            CandidateSource::Regex => ("regex", None),
+        };
+        let _ = js_sys::Reflect::set(&obj, &"source".into(), &JsValue::from_str(source));
+        if let Some(key) = key {
+            let _ = js_sys::Reflect::set(&obj, &"sourceKey".into(), &JsValue::from_str(key));
+        }
+        let _ = js_sys::Reflect::set(&obj, &"matched".into(), &JsValue::from(candidate.matched()));
+        if let Some(rejection) = candidate.rejected {
+            let _ = js_sys::Reflect::set(&obj, &"rejected".into(), &JsValue::from_str(rejection.code()));
+            let _ = js_sys::Reflect::set(&obj, &"rejectedText".into(), &JsValue::from_str(&rejection.to_string()));
+        }
+        candidates.push(&obj);
+    }
+    let tokens = js_sys::Array::new();
+    for token in &explanation.tokens {
+        tokens.push(&JsValue::from_str(token));
+    }
 
-    let headers_array = js_sys::Array::from(&headers);
-    let mut header_storage: Vec<(String, String)> = Vec::new();
-    header_storage.reserve(headers_array.length() as usize);
+    let result = &explanation.result;
+    let obj = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&obj, &"decision".into(), &JsValue::from(result.decision as u8));
+    let _ = js_sys::Reflect::set(&obj, &"ruleId".into(), &JsValue::from(result.rule_id));
+    let _ = js_sys::Reflect::set(&obj, &"listId".into(), &JsValue::from(result.list_id));
+    if let Some(redirect_url) = &result.redirect_url {
+        let _ = js_sys::Reflect::set(&obj, &"redirectUrl".into(), &JsValue::from_str(redirect_url));
+    }
+    let _ = js_sys::Reflect::set(&obj, &"reason".into(), &JsValue::from_str(explanation.reason.code()));
+    let _ = js_sys::Reflect::set(&obj, &"reasonText".into(), &JsValue::from_str(&explanation.reason.to_string()));
+    let _ = js_sys::Reflect::set(&obj, &"genericblock".into(), &JsValue::from(explanation.genericblock));
+    let _ = js_sys::Reflect::set(&obj, &"tokens".into(), &tokens);
+    let _ = js_sys::Reflect::set(&obj, &"candidates".into(), &candidates);
+    obj.into()
+}
+
+/// Read `chrome.webRequest.HttpHeader[]` into `(name, value)` pairs, keeping
+/// the original entries alongside so untouched headers can be passed back
+/// as-is (including `binaryValue` ones).
+fn read_headers(headers: &JsValue) -> (Vec<JsValue>, Vec<(String, String)>) {
+    let headers_array = js_sys::Array::from(headers);
+    let mut entries = Vec::with_capacity(headers_array.length() as usize);
+    let mut header_storage: Vec<(String, String)> = Vec::with_capacity(headers_array.length() as usize);
 
     for entry in headers_array.iter() {
         let name = js_sys::Reflect::get(&entry, &"name".into())
@@ -379,19 +1498,55 @@ pub fn match_response_headers(
             .and_then(|value| value.as_string())
             .unwrap_or_default();
         header_storage.push((name, value));
+        entries.push(entry);
     }
 
-    let mut header_views: Vec<ResponseHeader<'_>> = Vec::with_capacity(header_storage.len());
-    for (name, value) in &header_storage {
-        header_views.push(ResponseHeader { name, value });
-    }
+    (entries, header_storage)
+}
+
+fn header_views(header_storage: &[(String, String)]) -> Vec<ResponseHeader<'_>> {
+    header_storage
+        .iter()
+        .map(|(name, value)| ResponseHeader { name, value })
+        .collect()
+}
+
+#[wasm_bindgen]
+pub fn match_response_headers(
+    url: &str,
+    request_type: &str,
+    initiator: Option<String>,
+    tab_id: i32,
+    frame_id: i32,
+    request_id: &str,
+    headers: JsValue,
+) -> JsValue {
+    let state = match current_state() {
+        Some(state) => state,
+        None => {
+            let result = js_sys::Object::new();
+            let _ = js_sys::Reflect::set(&result, &"cancel".into(), &JsValue::from(false));
+            return result.into();
+        }
+    };
+    let engine = state.engine();
+
+    let frame_site = frame_site_host(tab_id, frame_id, request_type);
+    let request = request_info(url, request_type, initiator.as_deref(), frame_site.as_deref())
+        .with_ids(tab_id, frame_id, request_id);
+
+    let (_, header_storage) = read_headers(&headers);
+    let header_views = header_views(&header_storage);
 
-    let result = matcher.match_response_headers(&ctx, &header_views);
+    let result = engine.headers_for_request(&request, &header_views);
 
     let js_result = js_sys::Object::new();
     let _ = js_sys::Reflect::set(&js_result, &"cancel".into(), &JsValue::from(result.cancel));
     let _ = js_sys::Reflect::set(&js_result, &"ruleId".into(), &JsValue::from(result.rule_id));
     let _ = js_sys::Reflect::set(&js_result, &"listId".into(), &JsValue::from(result.list_id));
+    if !result.site_switch.is_empty() {
+        let _ = js_sys::Reflect::set(&js_result, &"siteSwitch".into(), &JsValue::from(result.site_switch.bits()));
+    }
 
     if !result.csp_injections.is_empty() {
         let csp_array = js_sys::Array::new();
@@ -399,6 +1554,7 @@ pub fn match_response_headers(
             csp_array.push(&JsValue::from_str(&value));
         }
         let _ = js_sys::Reflect::set(&js_result, &"csp".into(), &csp_array);
+        let _ = js_sys::Reflect::set(&js_result, &"cspReportOnly".into(), &JsValue::from(result.csp_report_only));
     }
 
     if !result.remove_headers.is_empty() {
@@ -412,6 +1568,172 @@ pub fn match_response_headers(
     js_result.into()
 }
 
+/// Match response headers and return the rewritten header list.
+///
+/// `responseHeaders` is only set when the list changed; untouched entries are
+/// the caller's original objects. `cspEnabled` / `removeEnabled` mirror the
+/// user settings and drop the corresponding actions before rewriting.
+#[wasm_bindgen]
+#[allow(clippy::too_many_arguments)]
+pub fn rewrite_response_headers(
+    url: &str,
+    request_type: &str,
+    initiator: Option<String>,
+    tab_id: i32,
+    frame_id: i32,
+    request_id: &str,
+    headers: JsValue,
+    csp_enabled: bool,
+    remove_enabled: bool,
+) -> JsValue {
+    let js_result = js_sys::Object::new();
+    let state = match current_state() {
+        Some(state) => state,
+        None => {
+            let _ = js_sys::Reflect::set(&js_result, &"cancel".into(), &JsValue::from(false));
+            return js_result.into();
+        }
+    };
+    let engine = state.engine();
+
+    let frame_site = frame_site_host(tab_id, frame_id, request_type);
+    let request = request_info(url, request_type, initiator.as_deref(), frame_site.as_deref())
+        .with_ids(tab_id, frame_id, request_id);
+
+    let (entries, header_storage) = read_headers(&headers);
+    let header_views = header_views(&header_storage);
+
+    let mut result = engine.headers_for_request(&request, &header_views);
+    if !csp_enabled {
+        result.csp_injections.clear();
+    }
+    if !remove_enabled {
+        result.remove_headers.clear();
+    }
+
+    let _ = js_sys::Reflect::set(&js_result, &"cancel".into(), &JsValue::from(result.cancel));
+    let _ = js_sys::Reflect::set(&js_result, &"ruleId".into(), &JsValue::from(result.rule_id));
+    let _ = js_sys::Reflect::set(&js_result, &"listId".into(), &JsValue::from(result.list_id));
+    if !result.site_switch.is_empty() {
+        let _ = js_sys::Reflect::set(&js_result, &"siteSwitch".into(), &JsValue::from(result.site_switch.bits()));
+    }
+
+    if result.cancel || (result.csp_injections.is_empty() && result.remove_headers.is_empty()) {
+        return js_result.into();
+    }
+
+    // Kept headers come back in input order, so walk the originals alongside.
+    let rewritten = result.rewrite(&header_views);
+    let headers_out = js_sys::Array::new();
+    let mut next_original = 0;
+    for (name, value) in rewritten {
+        match value {
+            Some(value) => {
+                let header = js_sys::Object::new();
+                let _ = js_sys::Reflect::set(&header, &"name".into(), &JsValue::from_str(&name));
+                let _ = js_sys::Reflect::set(&header, &"value".into(), &JsValue::from_str(&value));
+                headers_out.push(&header);
+            }
+            None => {
+                while next_original < header_storage.len() && header_storage[next_original].0 != name {
+                    next_original += 1;
+                }
+                if let Some(entry) = entries.get(next_original) {
+                    headers_out.push(entry);
+                }
+                next_original += 1;
+            }
+        }
+    }
+    let _ = js_sys::Reflect::set(&js_result, &"responseHeaders".into(), &headers_out);
+
+    js_result.into()
+}
+
+/// Match outgoing request headers, for `onBeforeSendHeaders`.
+///
+/// `requestHeaders` is only set when headers were stripped; the kept entries
+/// are the caller's original objects.
+#[wasm_bindgen]
+pub fn match_request_headers(
+    url: &str,
+    request_type: &str,
+    initiator: Option<String>,
+    tab_id: i32,
+    frame_id: i32,
+    request_id: &str,
+    headers: JsValue,
+) -> JsValue {
+    let js_result = js_sys::Object::new();
+    let state = match current_state() {
+        Some(state) => state,
+        None => {
+            let _ = js_sys::Reflect::set(&js_result, &"cancel".into(), &JsValue::from(false));
+            return js_result.into();
+        }
+    };
+    let engine = state.engine();
+
+    let frame_site = frame_site_host(tab_id, frame_id, request_type);
+    let request = request_info(url, request_type, initiator.as_deref(), frame_site.as_deref())
+        .with_ids(tab_id, frame_id, request_id);
+
+    let (entries, header_storage) = read_headers(&headers);
+    let header_views = header_views(&header_storage);
+
+    let result = engine.request_headers_for_request(&request, &header_views);
+
+    let _ = js_sys::Reflect::set(&js_result, &"cancel".into(), &JsValue::from(result.cancel));
+    let _ = js_sys::Reflect::set(&js_result, &"ruleId".into(), &JsValue::from(result.rule_id));
+    let _ = js_sys::Reflect::set(&js_result, &"listId".into(), &JsValue::from(result.list_id));
+
+    if result.cancel || result.remove_headers.is_empty() {
+        return js_result.into();
+    }
+
+    let headers_out = js_sys::Array::new();
+    for (entry, (name, _)) in entries.iter().zip(&header_storage) {
+        if !result.remove_headers.iter().any(|removed| removed.eq_ignore_ascii_case(name)) {
+            headers_out.push(entry);
+        }
+    }
+    let _ = js_sys::Reflect::set(&js_result, &"requestHeaders".into(), &headers_out);
+
+    js_result.into()
+}
+
+#[wasm_bindgen]
+pub fn match_replace(
+    url: &str,
+    request_type: &str,
+    initiator: Option<String>,
+    tab_id: i32,
+    frame_id: i32,
+    request_id: &str,
+) -> JsValue {
+    let state = match current_state() {
+        Some(state) => state,
+        None => return js_sys::Array::new().into(),
+    };
+    let engine = state.engine();
+
+    let frame_site = frame_site_host(tab_id, frame_id, request_type);
+    let request = request_info(url, request_type, initiator.as_deref(), frame_site.as_deref())
+        .with_ids(tab_id, frame_id, request_id);
+
+    let replacements = js_sys::Array::new();
+    for rule in engine.replace_for_request(&request) {
+        let obj = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&obj, &"regex".into(), &JsValue::from_str(&rule.pattern));
+        let _ = js_sys::Reflect::set(&obj, &"replacement".into(), &JsValue::from_str(&rule.replacement));
+        let _ = js_sys::Reflect::set(&obj, &"flags".into(), &JsValue::from_str(&rule.flags));
+        let _ = js_sys::Reflect::set(&obj, &"ruleId".into(), &JsValue::from(rule.rule_id));
+        let _ = js_sys::Reflect::set(&obj, &"listId".into(), &JsValue::from(rule.list_id));
+        replacements.push(&obj);
+    }
+    replacements.into()
+}
+
 #[wasm_bindgen]
 pub fn match_cosmetics(
     url: &str,
@@ -421,81 +1743,55 @@ pub fn match_cosmetics(
     frame_id: i32,
     request_id: &str,
 ) -> JsValue {
-    let matcher = match MATCHER_STATE.get() {
-        Some(state) => state.matcher,
+    let state = match current_state() {
+        Some(state) => state,
         None => {
             let result = js_sys::Object::new();
-            let _ = js_sys::Reflect::set(&result, &"css".into(), &JsValue::from(""));
+            let _ = js_sys::Reflect::set(&result, &"css".into(), &js_sys::Array::new());
+            let _ = js_sys::Reflect::set(&result, &"styleCss".into(), &JsValue::from(""));
             let _ = js_sys::Reflect::set(&result, &"enableGeneric".into(), &JsValue::from(true));
             let _ = js_sys::Reflect::set(&result, &"procedural".into(), &js_sys::Array::new());
             let _ = js_sys::Reflect::set(&result, &"scriptlets".into(), &js_sys::Array::new());
             return result.into();
         }
     };
+    let frame_site = frame_site_host(tab_id, frame_id, request_type);
+    let request = request_info(url, request_type, initiator.as_deref(), frame_site.as_deref())
+        .with_ids(tab_id, frame_id, request_id);
 
-    let req_host = extract_host(url).unwrap_or("");
-    let req_etld1 = get_etld1(req_host);
-
-    let is_main_frame = matches!(request_type, "main_frame" | "document");
-    let site_host = if is_main_frame {
-        req_host
-    } else {
-        initiator
-            .as_deref()
-            .and_then(extract_host)
-            .filter(|host| !host.is_empty())
-            .unwrap_or(req_host)
-    };
-    let site_etld1 = get_etld1(site_host);
-
-    let scheme = bb_core::url::extract_scheme(url).unwrap_or(SchemeMask::HTTP);
-    let is_third_party = !site_etld1.is_empty() && req_etld1 != site_etld1;
-    let request_type_mask = parse_request_type(request_type);
-
-    let ctx = RequestContext {
-        url,
-        req_host,
-        req_etld1: &req_etld1,
-        site_host,
-        site_etld1: &site_etld1,
-        scheme,
-        request_type: request_type_mask,
-        is_third_party,
-        tab_id,
-        frame_id,
-        request_id,
-    };
-
-    let result = matcher.match_cosmetics(&ctx);
+    let result = cached_cosmetics(&state, &request);
     let js_result = js_sys::Object::new();
-    let _ = js_sys::Reflect::set(&js_result, &"css".into(), &JsValue::from_str(&result.css));
+    let css: js_sys::Array = result.css.iter().map(|rule| JsValue::from_str(rule)).collect();
+    let _ = js_sys::Reflect::set(&js_result, &"css".into(), &css);
+    let _ = js_sys::Reflect::set(&js_result, &"styleCss".into(), &JsValue::from_str(&result.style_css));
     let _ = js_sys::Reflect::set(&js_result, &"enableGeneric".into(), &JsValue::from(result.enable_generic));
+    if !result.site_switch.is_empty() {
+        let _ = js_sys::Reflect::set(&js_result, &"siteSwitch".into(), &JsValue::from(result.site_switch.bits()));
+    }
 
     let procedural = js_sys::Array::new();
-    for selector in result.procedural.into_iter().take(MAX_PROCEDURAL_RULES) {
-        if let Some((base, ops)) = parse_procedural_rule(&selector) {
-            let ops_array = js_sys::Array::new();
-            for op in ops {
-                let op_obj = js_sys::Object::new();
-                let _ = js_sys::Reflect::set(&op_obj, &"type".into(), &JsValue::from_str(&op.op_type));
-                let _ = js_sys::Reflect::set(&op_obj, &"args".into(), &JsValue::from_str(&op.args));
-                ops_array.push(&op_obj);
-            }
-            let rule_obj = js_sys::Object::new();
-            let _ = js_sys::Reflect::set(&rule_obj, &"base".into(), &JsValue::from_str(&base));
-            let _ = js_sys::Reflect::set(&rule_obj, &"ops".into(), &ops_array);
-            procedural.push(&rule_obj);
+    for program in result.procedural.iter().take(MAX_PROCEDURAL_RULES) {
+        let ops_array = js_sys::Array::new();
+        for op in &program.ops {
+            let op_obj = js_sys::Object::new();
+            let _ = js_sys::Reflect::set(&op_obj, &"type".into(), &JsValue::from_str(op.kind.name()));
+            let _ = js_sys::Reflect::set(&op_obj, &"args".into(), &JsValue::from_str(&op.arg));
+            ops_array.push(&op_obj);
         }
+        let rule_obj = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&rule_obj, &"base".into(), &JsValue::from_str(&program.base));
+        let _ = js_sys::Reflect::set(&rule_obj, &"ops".into(), &ops_array);
+        procedural.push(&rule_obj);
     }
     let _ = js_sys::Reflect::set(&js_result, &"procedural".into(), &procedural);
 
     let scriptlets = js_sys::Array::new();
-    for call in result.scriptlets.into_iter().take(MAX_SCRIPTLETS) {
+    for call in result.scriptlets.iter().take(MAX_SCRIPTLETS) {
         let call_obj = js_sys::Object::new();
         let _ = js_sys::Reflect::set(&call_obj, &"name".into(), &JsValue::from_str(&call.name));
         let args_array = js_sys::Array::new();
-        for arg in call.args.into_iter().take(MAX_SCRIPTLET_ARGS) {
-            args_array.push(&parse_scriptlet_arg(&arg));
+        for arg in call.args.iter().take(MAX_SCRIPTLET_ARGS) {
+            args_array.push(&parse_scriptlet_arg(arg));
         }
         let _ = js_sys::Reflect::set(&call_obj, &"args".into(), &args_array);
         scriptlets.push(&call_obj);
@@ -505,51 +1801,351 @@ pub fn match_cosmetics(
     js_result.into()
 }
 
+/// `match_cosmetics` results through the per-site cache.
+fn cached_cosmetics(state: &MatcherState, request: &RequestInfo<'_>) -> Rc<CosmeticMatchResult> {
+    let engine = state.engine();
+    let switches = cached_hide_switches(state, request);
+    let key = (request.context().site_host.to_string(), switches);
+    if let Some(result) = COSMETIC_CACHE.with(|cache| cache.borrow_mut().get(state.epoch, &key)) {
+        return result;
+    }
+    let mut result = engine.cosmetics_with_switches_for_request(request, switches);
+    if result.site_switch.is_empty() {
+        with_runtime(|runtime| merge_user_styles(&runtime.user_styles, engine, request, switches, &mut result));
+    }
+    let result = Rc::new(result);
+    COSMETIC_CACHE.with(|cache| cache.borrow_mut().insert(state.epoch, key, Rc::clone(&result)));
+    result
+}
+
+/// The page's `$elemhide`-style switches through the per-page cache.
+fn cached_hide_switches(state: &MatcherState, request: &RequestInfo<'_>) -> CosmeticHideSwitches {
+    let ctx = request.context();
+    if let Some(switches) = COSMETIC_CACHE.with(|cache| cache.borrow_mut().get_switches(state.epoch, &ctx)) {
+        return switches;
+    }
+    let switches = state.engine().cosmetic_hide_switches_for_request(request);
+    COSMETIC_CACHE.with(|cache| cache.borrow_mut().insert_switches(state.epoch, &ctx, switches));
+    switches
+}
+
+/// Add the user styles for the page to `result`. `$elemhide` drops them all,
+/// `$generichide` the `*` ones and `$specifichide` the per-site ones, and
+/// the page's `#@#` exceptions cover user hides as they do list hides.
+fn merge_user_styles(
+    styles: &[UserStyle],
+    engine: &Engine<'_>,
+    request: &RequestInfo<'_>,
+    switches: CosmeticHideSwitches,
+    result: &mut CosmeticMatchResult,
+) {
+    if styles.is_empty() || switches.elemhide {
+        return;
+    }
+    let site_host = request.context().site_host;
+    let mut hides: Vec<&str> = Vec::new();
+    for style in styles {
+        let generic = style.site == "*";
+        if (generic && switches.generichide) || (!generic && switches.specifichide) || !host_matches(&style.site, site_host) {
+            continue;
+        }
+        hides.extend(style.hide.iter().map(String::as_str));
+        if !style.css.is_empty() {
+            if !result.style_css.is_empty() {
+                result.style_css.push('\n');
+            }
+            result.style_css.push_str(&style.css);
+        }
+    }
+    let hides = engine.unexcepted_selectors_for_request(request, &hides);
+    result.css.extend(hide_css(&hides, engine.matcher().hide_css_chunk()));
+}
+
+/// Hide CSS for generic selectors keyed by the page's ids and classes.
+/// `selectors` is an array of `"#id"` / `".class"` strings.
+#[wasm_bindgen]
+pub fn match_cosmetics_generic(
+    url: &str,
+    request_type: &str,
+    initiator: Option<String>,
+    tab_id: i32,
+    frame_id: i32,
+    request_id: &str,
+    selectors: JsValue,
+) -> String {
+    let state = match current_state() {
+        Some(state) => state,
+        None => return String::new(),
+    };
+    let engine = state.engine();
+
+    let keys: Vec<u32> = parse_string_array(selectors)
+        .iter()
+        .take(MAX_GENERIC_COSMETIC_KEYS)
+        .map(|selector| hash_token(selector))
+        .collect();
+
+    let frame_site = frame_site_host(tab_id, frame_id, request_type);
+    let request = request_info(url, request_type, initiator.as_deref(), frame_site.as_deref())
+        .with_ids(tab_id, frame_id, request_id);
+
+    let switches = cached_hide_switches(&state, &request);
+    engine.generic_cosmetics_with_switches_for_request(&request, &keys, switches)
+}
+
+/// The `##` rules behind `match_cosmetics` and `match_cosmetics_generic`, as
+/// `{ selector, listId, ruleIndex, line }` objects for the logger.
+#[wasm_bindgen]
+pub fn match_cosmetics_detailed(
+    url: &str,
+    request_type: &str,
+    initiator: Option<String>,
+    tab_id: i32,
+    frame_id: i32,
+    request_id: &str,
+    selectors: JsValue,
+) -> JsValue {
+    let state = match current_state() {
+        Some(state) => state,
+        None => return js_sys::Array::new().into(),
+    };
+    let engine = state.engine();
+
+    let keys: Vec<u32> = parse_string_array(selectors)
+        .iter()
+        .take(MAX_GENERIC_COSMETIC_KEYS)
+        .map(|selector| hash_token(selector))
+        .collect();
+
+    let frame_site = frame_site_host(tab_id, frame_id, request_type);
+    let request = request_info(url, request_type, initiator.as_deref(), frame_site.as_deref())
+        .with_ids(tab_id, frame_id, request_id);
+
+    let rules = js_sys::Array::new();
+    let switches = cached_hide_switches(&state, &request);
+    for rule in engine.cosmetic_rules_with_switches_for_request(&request, &keys, switches) {
+        let obj = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&obj, &"selector".into(), &JsValue::from_str(&rule.selector));
+        let _ = js_sys::Reflect::set(&obj, &"listId".into(), &JsValue::from(rule.list_id));
+        let _ = js_sys::Reflect::set(&obj, &"ruleIndex".into(), &JsValue::from(rule.rule_index));
+        let _ = js_sys::Reflect::set(&obj, &"line".into(), &JsValue::from(rule.line));
+        rules.push(&obj);
+    }
+    rules.into()
+}
+
+/// Selector counts and byte sizes for a page without building its CSS, as
+/// `{ specific, generic, genericBucketed, procedural, enableGeneric }` with
+/// each group a `{ count, bytes }` object.
+#[wasm_bindgen]
+pub fn cosmetics_stats(
+    url: &str,
+    request_type: &str,
+    initiator: Option<String>,
+    tab_id: i32,
+    frame_id: i32,
+    request_id: &str,
+) -> JsValue {
+    let stats = match current_state() {
+        Some(state) => {
+            let frame_site = frame_site_host(tab_id, frame_id, request_type);
+            let request = request_info(url, request_type, initiator.as_deref(), frame_site.as_deref())
+                .with_ids(tab_id, frame_id, request_id);
+            state.engine().cosmetics_stats_for_request(&request)
+        }
+        None => CosmeticStats { enable_generic: true, ..CosmeticStats::default() },
+    };
+
+    let group = |selectors: SelectorStats| {
+        let obj = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&obj, &"count".into(), &JsValue::from(selectors.count));
+        let _ = js_sys::Reflect::set(&obj, &"bytes".into(), &JsValue::from(selectors.bytes));
+        obj
+    };
+    let result = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&result, &"specific".into(), &group(stats.specific));
+    let _ = js_sys::Reflect::set(&result, &"generic".into(), &group(stats.generic));
+    let _ = js_sys::Reflect::set(&result, &"genericBucketed".into(), &group(stats.generic_bucketed));
+    let _ = js_sys::Reflect::set(&result, &"procedural".into(), &group(stats.procedural));
+    let _ = js_sys::Reflect::set(&result, &"enableGeneric".into(), &JsValue::from(stats.enable_generic));
+    result.into()
+}
+
+/// Exception rules active on the page at `url`, for working out why a site
+/// still shows ads: `{ trusted, elemhide, generichide, specifichide,
+/// exceptions }` with each exception a `{ kind, listId, count }` object and
+/// `kind` one of `network`, `hide-switch`, `cosmetic`, `procedural` or
+/// `scriptlet`.
+#[wasm_bindgen]
+pub fn site_diagnostics(url: &str) -> JsValue {
+    let Some(state) = current_state() else {
+        return JsValue::NULL;
+    };
+    let diagnostics = state.engine().site_diagnostics(url);
+
+    let exceptions = js_sys::Array::new();
+    for entry in &diagnostics.exceptions {
+        let obj = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&obj, &"kind".into(), &JsValue::from_str(entry.kind.code()));
+        let _ = js_sys::Reflect::set(&obj, &"listId".into(), &JsValue::from(entry.list_id));
+        let _ = js_sys::Reflect::set(&obj, &"count".into(), &JsValue::from(entry.count));
+        exceptions.push(&obj);
+    }
+    let result = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&result, &"trusted".into(), &JsValue::from(diagnostics.trusted));
+    let _ = js_sys::Reflect::set(&result, &"elemhide".into(), &JsValue::from(diagnostics.switches.elemhide));
+    let _ = js_sys::Reflect::set(&result, &"generichide".into(), &JsValue::from(diagnostics.switches.generichide));
+    let _ = js_sys::Reflect::set(&result, &"specifichide".into(), &JsValue::from(diagnostics.switches.specifichide));
+    let _ = js_sys::Reflect::set(&result, &"exceptions".into(), &exceptions);
+    result.into()
+}
+
 #[wasm_bindgen]
 pub fn should_block(
     url: &str,
     request_type: &str,
     initiator: Option<String>,
 ) -> bool {
-    let matcher = match MATCHER_STATE.get() {
-        Some(state) => state.matcher,
+    let state = match current_state() {
+        Some(state) => state,
         None => return false,
     };
+    let engine = state.engine();
+    if !engine.matcher().is_filterable(url) {
+        return false;
+    }
+
+    let request = RequestInfo::new(url, parse_request_type(request_type), initiator.as_deref());
+    
+    engine.check_request(&request).decision == MatchDecision::Block
+}
 
-    let req_host = extract_host(url).unwrap_or("");
-    let req_etld1 = get_etld1(req_host);
+/// Keep the snapshot that was active before a list update around for
+/// `grace_ms` (0 = default) so the logger can attribute decision changes.
+#[wasm_bindgen]
+pub fn retain_previous_snapshot(snapshot_data: Vec<u8>, grace_ms: u32) -> Result<(), JsValue> {
+    let previous = MatcherState::load(snapshot_data.into_boxed_slice(), 0, false)?;
+    let grace = if grace_ms == 0 { RELOAD_GRACE_MS } else { grace_ms as u64 };
 
-    let is_main_frame = matches!(request_type, "main_frame" | "document");
-    let site_host = if is_main_frame {
-        req_host
-    } else {
-        initiator
-            .as_deref()
-            .and_then(extract_host)
-            .filter(|host| !host.is_empty())
-            .unwrap_or(req_host)
+    with_runtime(|state| {
+        state.previous_snapshot = Some(PreviousSnapshot {
+            state: Rc::new(previous),
+            expires_at: now_ms().saturating_add(grace),
+        });
+    });
+    Ok(())
+}
+
+#[wasm_bindgen]
+pub fn decision_changed_since_reload(
+    url: &str,
+    request_type: &str,
+    initiator: Option<String>,
+) -> JsValue {
+    let current = match current_state() {
+        Some(state) => state,
+        None => return JsValue::NULL,
     };
-    let site_etld1 = get_etld1(site_host);
 
-    let scheme = bb_core::url::extract_scheme(url).unwrap_or(SchemeMask::HTTP);
-    let is_third_party = !site_etld1.is_empty() && req_etld1 != site_etld1;
-    let request_type_mask = parse_request_type(request_type);
-    
-    let ctx = RequestContext {
-        url,
-        req_host,
-        req_etld1: &req_etld1,
-        site_host,
-        site_etld1: &site_etld1,
-        scheme,
-        request_type: request_type_mask,
-        is_third_party,
-        tab_id: -1,
-        frame_id: -1,
-        request_id: "",
+    let now = now_ms();
+    let previous = with_runtime(|state| {
+        if state.previous_snapshot.as_ref().is_some_and(|prev| now >= prev.expires_at) {
+            state.previous_snapshot = None;
+        }
+        state.previous_snapshot.as_ref().map(|prev| Rc::clone(&prev.state))
+    });
+    let Some(previous) = previous else {
+        return JsValue::NULL;
     };
-    
-    matcher.match_request(&ctx).decision == MatchDecision::Block
+
+    let request = RequestInfo::new(url, parse_request_type(request_type), initiator.as_deref());
+
+    let delta = current.engine().diff_decision(previous.engine(), &request);
+
+    let js_result = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&js_result, &"changed".into(), &JsValue::from(delta.changed()));
+    let _ = js_sys::Reflect::set(&js_result, &"previous".into(), &match_result_to_js(&delta.previous));
+    let _ = js_sys::Reflect::set(&js_result, &"current".into(), &match_result_to_js(&delta.current));
+    let _ = js_sys::Reflect::set(&js_result, &"previousFingerprint".into(), &JsValue::from(previous.fingerprint));
+    let _ = js_sys::Reflect::set(&js_result, &"currentFingerprint".into(), &JsValue::from(current.fingerprint));
+    js_result.into()
+}
+
+fn match_result_to_js(result: &MatchResult) -> js_sys::Object {
+    let obj = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&obj, &"decision".into(), &JsValue::from(result.decision as u8));
+    let _ = js_sys::Reflect::set(&obj, &"ruleId".into(), &JsValue::from(result.rule_id));
+    let _ = js_sys::Reflect::set(&obj, &"listId".into(), &JsValue::from(result.list_id));
+    if let Some(redirect_url) = &result.redirect_url {
+        let _ = js_sys::Reflect::set(&obj, &"redirectUrl".into(), &JsValue::from_str(redirect_url));
+    }
+    obj
+}
+
+/// Memory held by the matcher and runtime buffers, in bytes unless noted.
+///
+/// `leakedBuffers` counts snapshot buffers that are neither active nor
+/// retained for `decision_changed_since_reload`; it should be 0 between
+/// calls.
+#[wasm_bindgen]
+pub fn memory_stats() -> JsValue {
+    let current = current_state();
+    let now = now_ms();
+    let (previous_bytes, dynamic_rules, dynamic_rule_bytes, trace_entries, trace_bytes, perf_entries, perf_bytes) =
+        with_runtime(|state| {
+            if state.previous_snapshot.as_ref().is_some_and(|prev| now >= prev.expires_at) {
+                state.previous_snapshot = None;
+            }
+            (
+                state.previous_snapshot.as_ref().map(|prev| prev.state.len()),
+                state.dynamic_rules.len(),
+                state.dynamic_rule_bytes(),
+                state.trace_entries.len(),
+                state.trace_bytes(),
+                state.perf_before_request.values.len() + state.perf_headers_received.values.len(),
+                state.perf_bytes(),
+            )
+        });
+    let (live_buffers, live_bytes) = SNAPSHOT_BUFFERS.with(Cell::get);
+    let accounted = current.is_some() as u32 + previous_bytes.is_some() as u32;
+
+    let result = js_sys::Object::new();
+    let set = |key: &str, value: JsValue| {
+        let _ = js_sys::Reflect::set(&result, &JsValue::from_str(key), &value);
+    };
+    set("snapshotBytes", JsValue::from(current.as_ref().map_or(0, |state| state.len()) as f64));
+    set("previousSnapshotBytes", JsValue::from(previous_bytes.unwrap_or(0) as f64));
+    set("snapshotBuffers", JsValue::from(live_buffers));
+    set("snapshotBuffersBytes", JsValue::from(live_bytes as f64));
+    set("leakedBuffers", JsValue::from(live_buffers.saturating_sub(accounted)));
+    set("dynamicRules", JsValue::from(dynamic_rules as u32));
+    set("dynamicRuleBytes", JsValue::from(dynamic_rule_bytes as f64));
+    set("traceEntries", JsValue::from(trace_entries as u32));
+    set("traceBytes", JsValue::from(trace_bytes as f64));
+    set("perfEntries", JsValue::from(perf_entries as u32));
+    set("perfBytes", JsValue::from(perf_bytes as f64));
+    let (cosmetic_cache_entries, cosmetic_cache_bytes) = COSMETIC_CACHE.with(|cache| {
+        let cache = cache.borrow();
+        (cache.entries.len(), cache.heap_bytes())
+    });
+    set("cosmeticCacheEntries", JsValue::from(cosmetic_cache_entries as u32));
+    set("cosmeticCacheBytes", JsValue::from(cosmetic_cache_bytes as f64));
+    let pages = wasm_memory_pages();
+    set("wasmMemoryPages", JsValue::from(pages as u32));
+    set("wasmMemoryBytes", JsValue::from((pages * WASM_PAGE_SIZE) as f64));
+    result.into()
+}
+
+const WASM_PAGE_SIZE: usize = 64 * 1024;
+
+#[cfg(target_arch = "wasm32")]
+fn wasm_memory_pages() -> usize {
+    core::arch::wasm32::memory_size::<0>()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn wasm_memory_pages() -> usize {
+    0
 }
 
 #[wasm_bindgen]
@@ -559,155 +2155,580 @@ pub fn get_etld1_js(host: &str) -> String {
 
 #[wasm_bindgen]
 pub fn is_same_site(host1: &str, host2: &str) -> bool {
-    get_etld1(host1) == get_etld1(host2)
+    psl::is_same_site(host1, host2)
+}
+
+#[wasm_bindgen]
+pub fn is_third_party_js(site_host: &str, req_host: &str) -> bool {
+    psl::is_third_party(site_host, req_host)
+}
+
+#[wasm_bindgen]
+pub fn extract_host_js(url: &str) -> Option<String> {
+    extract_host(url).map(|h| h.to_string())
+}
+
+fn get_string_field(value: &JsValue, key: &str) -> Option<String> {
+    js_sys::Reflect::get(value, &JsValue::from_str(key))
+        .ok()
+        .and_then(|v| v.as_string())
+}
+
+fn normalize_pattern(value: Option<String>) -> String {
+    let trimmed = value.unwrap_or_default().trim().to_lowercase();
+    if trimmed.is_empty() {
+        "*".to_string()
+    } else {
+        trimmed
+    }
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    if pattern.is_empty() || pattern == "*" {
+        return true;
+    }
+    if host.is_empty() {
+        return false;
+    }
+    if host == pattern {
+        return true;
+    }
+    host.ends_with(&format!(".{pattern}"))
+}
+
+fn type_matches(rule_type: &str, request_type: &str) -> bool {
+    if rule_type.is_empty() || rule_type == "*" {
+        return true;
+    }
+    let normalized = rule_type.to_lowercase();
+    match normalized.as_str() {
+        "document" => request_type == "main_frame" || request_type == "sub_frame",
+        "subdocument" | "sub_frame" => request_type == "sub_frame",
+        "main_frame" => request_type == "main_frame",
+        "xhr" => request_type == "xmlhttprequest",
+        _ => normalized == request_type,
+    }
+}
+
+fn is_overly_broad_dynamic_rule(rule: &DynamicRule) -> bool {
+    let is_global_site = rule.site == "*";
+    let is_global_target = rule.target == "*";
+    let type_pattern = rule.rule_type.as_str();
+    let is_main_frame_type = type_pattern == "*" || type_pattern == "main_frame" || type_pattern == "document";
+    is_global_site && is_global_target && is_main_frame_type
+}
+
+fn parse_dynamic_rules(value: JsValue) -> Result<Vec<DynamicRule>, JsValue> {
+    let array = js_sys::Array::from(&value);
+    Ok(array.iter().map(|entry| parse_dynamic_rule(&entry)).collect())
+}
+
+fn parse_dynamic_rule(entry: &JsValue) -> DynamicRule {
+    let action_val = js_sys::Reflect::get(entry, &JsValue::from_str("action"))
+        .ok()
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.0) as u8;
+    DynamicRule {
+        site: normalize_pattern(get_string_field(entry, "site")),
+        target: normalize_pattern(get_string_field(entry, "target")),
+        rule_type: normalize_pattern(get_string_field(entry, "type")),
+        action: DynamicAction::from_u8(action_val),
+        seq: 0,
+    }
+}
+
+fn parse_string_array(value: JsValue) -> Vec<String> {
+    let array = js_sys::Array::from(&value);
+    array
+        .iter()
+        .filter_map(|entry| entry.as_string())
+        .map(|entry| entry.trim().to_string())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+#[wasm_bindgen]
+pub fn set_dynamic_rules(value: JsValue) -> Result<(), JsValue> {
+    let rules = parse_dynamic_rules(value)?;
+    with_runtime(|state| {
+        state.dynamic_rules.clear();
+        for rule in rules {
+            state.dynamic_rules.insert(rule);
+        }
+    });
+    invalidate_cosmetic_cache();
+    Ok(())
+}
+
+/// Add one `{ site, target, type, action }` rule, replacing the rule with
+/// the same site/target/type if there is one.
+#[wasm_bindgen]
+pub fn add_dynamic_rule(value: JsValue) {
+    let rule = parse_dynamic_rule(&value);
+    with_runtime(|state| state.dynamic_rules.insert(rule));
+    invalidate_cosmetic_cache();
+}
+
+/// Remove the rule for `site`/`target`/`type`; false if there was none.
+#[wasm_bindgen]
+pub fn remove_dynamic_rule(site: &str, target: &str, rule_type: &str) -> bool {
+    let site = normalize_pattern(Some(site.to_string()));
+    let target = normalize_pattern(Some(target.to_string()));
+    let rule_type = normalize_pattern(Some(rule_type.to_string()));
+    invalidate_cosmetic_cache();
+    with_runtime(|state| state.dynamic_rules.remove(&site, &target, &rule_type))
+}
+
+/// Current rules as `{ site, target, type, action }`, oldest first.
+#[wasm_bindgen]
+pub fn get_dynamic_rules() -> JsValue {
+    let result = js_sys::Array::new();
+    with_runtime(|state| {
+        for rule in state.dynamic_rules.ordered() {
+            let entry = js_sys::Object::new();
+            let _ = js_sys::Reflect::set(&entry, &"site".into(), &JsValue::from_str(&rule.site));
+            let _ = js_sys::Reflect::set(&entry, &"target".into(), &JsValue::from_str(&rule.target));
+            let _ = js_sys::Reflect::set(&entry, &"type".into(), &JsValue::from_str(&rule.rule_type));
+            let _ = js_sys::Reflect::set(&entry, &"action".into(), &JsValue::from(rule.action as u8));
+            result.push(&entry);
+        }
+    });
+    result.into()
+}
+
+#[wasm_bindgen]
+pub fn set_runtime_settings(value: JsValue) -> Result<(), JsValue> {
+    with_runtime(|state| {
+        if let Ok(val) = js_sys::Reflect::get(&value, &JsValue::from_str("dynamicFilteringEnabled")) {
+            if let Some(enabled) = val.as_bool() {
+                state.settings.dynamic_filtering_enabled = enabled;
+            }
+        }
+        if let Ok(val) = js_sys::Reflect::get(&value, &JsValue::from_str("disabledSites")) {
+            if !val.is_undefined() && !val.is_null() {
+                state.settings.disabled_sites = parse_string_array(val);
+            }
+        }
+        if let Ok(val) = js_sys::Reflect::get(&value, &JsValue::from_str("cspReportOnly")) {
+            if let Some(report_only) = val.as_bool() {
+                state.settings.csp_report_only = report_only;
+            }
+        }
+        if let Ok(val) = js_sys::Reflect::get(&value, &JsValue::from_str("redirectDataUrls")) {
+            if let Some(data_urls) = val.as_bool() {
+                state.settings.redirect_data_urls = data_urls;
+            }
+        }
+        if let Ok(val) = js_sys::Reflect::get(&value, &JsValue::from_str("inferRequestTypes")) {
+            if let Some(infer) = val.as_bool() {
+                state.settings.infer_request_types = infer;
+            }
+        }
+        if let Ok(val) = js_sys::Reflect::get(&value, &JsValue::from_str("matchBudget")) {
+            if val.is_object() {
+                let limit = |key: &str| {
+                    js_sys::Reflect::get(&val, &JsValue::from_str(key))
+                        .ok()
+                        .and_then(|value| value.as_f64())
+                        .map_or(0, |value| value.clamp(0.0, u32::MAX as f64) as u32)
+                };
+                state.settings.match_budget = MatchBudget {
+                    max_candidates: limit("maxCandidates"),
+                    max_pattern_ops: limit("maxPatternOps"),
+                };
+            } else if val.is_null() {
+                state.settings.match_budget = MatchBudget::UNLIMITED;
+            }
+        }
+        if let Ok(val) = js_sys::Reflect::get(&value, &JsValue::from_str("hideCssChunk")) {
+            if let Some(selectors) = val.as_f64() {
+                state.settings.hide_css_chunk = selectors.clamp(1.0, u32::MAX as f64) as usize;
+            } else if val.is_null() {
+                state.settings.hide_css_chunk = DEFAULT_HIDE_CSS_CHUNK;
+            }
+        }
+        if let Ok(val) = js_sys::Reflect::get(&value, &JsValue::from_str("largeMediaKb")) {
+            if let Some(kb) = val.as_f64() {
+                state.settings.large_media_size = kb.clamp(0.0, u32::MAX as f64) as u64 * 1024;
+            } else if val.is_null() {
+                state.settings.large_media_size = DEFAULT_LARGE_MEDIA_SIZE;
+            }
+        }
+        if let Some(previous) = state.previous_snapshot.as_mut() {
+            reconfigure_matcher(&mut previous.state, &state.settings);
+        }
+    });
+    // Nothing holds the state between calls, so this only fails re-entrantly;
+    // the next reload() picks the settings up either way.
+    MATCHER_STATE.with(|current| {
+        if let Some(current) = current.borrow_mut().as_mut() {
+            with_runtime(|runtime| reconfigure_matcher(current, &runtime.settings));
+        }
+    });
+    // The hide CSS chunk size is baked into cached results.
+    invalidate_cosmetic_cache();
+    Ok(())
+}
+
+/// Turn lists off (or back on) at match time without recompiling: rules
+/// whose `list_id` is in `list_ids` stop matching. Replaces the previous set
+/// and carries over to snapshots loaded by `reload()`.
+#[wasm_bindgen]
+pub fn set_disabled_lists(list_ids: Vec<u16>) -> Result<(), JsValue> {
+    with_runtime(|runtime| {
+        runtime.settings.disabled_lists = list_ids;
+        if let Some(previous) = runtime.previous_snapshot.as_mut() {
+            reconfigure_matcher(&mut previous.state, &runtime.settings);
+        }
+    });
+    let applied = MATCHER_STATE.with(|current| match current.borrow_mut().as_mut() {
+        Some(state) => with_runtime(|runtime| reconfigure_matcher(state, &runtime.settings)),
+        None => true,
+    });
+
+    if applied {
+        Ok(())
+    } else {
+        Err(JsValue::from_str("Snapshot is in use; list toggles apply from the next reload()"))
+    }
 }
 
 #[wasm_bindgen]
-pub fn is_third_party_js(site_host: &str, req_host: &str) -> bool {
-    get_etld1(site_host) != get_etld1(req_host)
+pub fn get_disabled_lists() -> Vec<u16> {
+    with_runtime(|runtime| runtime.settings.disabled_lists.clone())
 }
 
+/// Set uBO-style switches for pages on `site` (a host; subdomains are
+/// covered too) as a bitmask: 1 no cosmetic filtering, 2 no remote fonts,
+/// 4 no scripting, 8 no large media. 0 clears the site. Replaces the site's previous switches
+/// and carries over to snapshots loaded by `reload()`.
 #[wasm_bindgen]
-pub fn extract_host_js(url: &str) -> Option<String> {
-    extract_host(url).map(|h| h.to_string())
-}
-
-fn get_string_field(value: &JsValue, key: &str) -> Option<String> {
-    js_sys::Reflect::get(value, &JsValue::from_str(key))
-        .ok()
-        .and_then(|v| v.as_string())
-}
+pub fn set_site_switches(site: &str, switches: u8) -> Result<(), JsValue> {
+    let site = site.trim_end_matches('.').to_lowercase();
+    if site.is_empty() {
+        return Err(JsValue::from_str("Site switches need a host"));
+    }
+    let switches = SiteSwitches::from_bits_truncate(switches);
+    with_runtime(|runtime| {
+        if switches.is_empty() {
+            runtime.settings.site_switches.remove(&site);
+        } else {
+            runtime.settings.site_switches.insert(site, switches);
+        }
+        if let Some(previous) = runtime.previous_snapshot.as_mut() {
+            reconfigure_matcher(&mut previous.state, &runtime.settings);
+        }
+    });
+    let applied = MATCHER_STATE.with(|current| match current.borrow_mut().as_mut() {
+        Some(state) => with_runtime(|runtime| reconfigure_matcher(state, &runtime.settings)),
+        None => true,
+    });
 
-fn normalize_pattern(value: Option<String>) -> String {
-    let trimmed = value.unwrap_or_default().trim().to_string();
-    if trimmed.is_empty() {
-        "*".to_string()
+    if applied {
+        Ok(())
     } else {
-        trimmed
+        Err(JsValue::from_str("Snapshot is in use; site switches apply from the next reload()"))
     }
 }
 
-fn host_matches(pattern: &str, host: &str) -> bool {
-    if pattern.is_empty() || pattern == "*" {
-        return true;
-    }
-    if host.is_empty() {
-        return false;
-    }
-    if host == pattern {
-        return true;
+/// Switches in effect for pages on `site_host`, as the `set_site_switches`
+/// bitmask.
+#[wasm_bindgen]
+pub fn get_site_switches(site_host: &str) -> u8 {
+    match current_state() {
+        Some(state) => state.engine().matcher().site_switches(site_host).bits(),
+        None => 0,
     }
-    host.ends_with(&format!(".{pattern}"))
 }
 
-fn target_matches(pattern: &str, req_host: &str, req_etld1: &str, is_third_party: bool) -> bool {
-    if pattern.is_empty() || pattern == "*" {
-        return true;
-    }
-    if pattern == "3p" || pattern == "third-party" {
-        return is_third_party;
-    }
-    if pattern == "1p" || pattern == "first-party" {
-        return !is_third_party;
+/// Set the subscribed lists from a JSON array of `{ id, url, title?,
+/// enabled?, expiresSecs?, lastUpdated?, etag? }`, where `id` is the list's
+/// `list_id` and `lastUpdated` is in epoch ms. Lists keep their update state
+/// across calls as long as their id and URL stay the same.
+#[wasm_bindgen]
+pub fn set_list_catalog(json: &str) -> Result<(), JsValue> {
+    let value = js_sys::JSON::parse(json)?;
+    if !js_sys::Array::is_array(&value) {
+        return Err(JsValue::from_str("List catalog must be a JSON array"));
     }
-    if !req_etld1.is_empty() && req_etld1 == pattern {
-        return true;
+    let mut entries = Vec::new();
+    for entry in js_sys::Array::from(&value).iter() {
+        let number = |key: &str| {
+            js_sys::Reflect::get(&entry, &JsValue::from_str(key))
+                .ok()
+                .and_then(|value| value.as_f64())
+                .filter(|value| value.is_finite() && *value >= 0.0)
+        };
+        let id = number("id")
+            .filter(|id| *id <= u16::MAX as f64 && id.fract() == 0.0)
+            .ok_or_else(|| JsValue::from_str("List catalog entry needs an integer id"))?;
+        let url = get_string_field(&entry, "url")
+            .filter(|url| !url.trim().is_empty())
+            .ok_or_else(|| JsValue::from_str("List catalog entry needs a url"))?;
+        let enabled = js_sys::Reflect::get(&entry, &JsValue::from_str("enabled"))
+            .ok()
+            .and_then(|value| value.as_bool())
+            .unwrap_or(true);
+        entries.push(ListEntry {
+            id: id as u16,
+            url: url.trim().to_string(),
+            title: get_string_field(&entry, "title"),
+            enabled,
+            expires_secs: number("expiresSecs").map(|secs| secs.min(u32::MAX as f64) as u32).filter(|&secs| secs > 0),
+            last_updated_ms: number("lastUpdated").map(|ms| ms as u64),
+            etag: get_string_field(&entry, "etag"),
+        });
     }
-    host_matches(pattern, req_host)
+    with_runtime(|runtime| runtime.list_catalog.replace(entries));
+    Ok(())
 }
 
-fn type_matches(rule_type: &str, request_type: &str) -> bool {
-    if rule_type.is_empty() || rule_type == "*" {
-        return true;
+/// Status of every catalog list plus a refresh plan:
+/// `{ lists: [{ id, url, title?, enabled, lastUpdated?, expiresSecs, etag?,
+/// nextUpdate?, due }], refresh: [{ id, url, etag? }], nextCheck? }`.
+/// `refresh` holds the enabled lists due now, most overdue first; pass
+/// `etag` as `If-None-Match`. `nextCheck` is the epoch ms at which the next
+/// list falls due.
+#[wasm_bindgen]
+pub fn get_lists_status() -> JsValue {
+    let now = now_ms();
+    let state = current_state();
+    let snapshot_expiry =
+        |id: u16| state.as_ref().and_then(|state| state.snapshot().list_metadata(id)).and_then(|meta| meta.expires_secs);
+
+    let lists = js_sys::Array::new();
+    let mut due = Vec::new();
+    let mut next_check: Option<u64> = None;
+    with_runtime(|runtime| {
+        for entry in &runtime.list_catalog.lists {
+            let expiry = snapshot_expiry(entry.id);
+            let next_update = entry.next_update_ms(expiry);
+            let is_due = next_update.is_some_and(|at| at <= now);
+
+            let item = js_sys::Object::new();
+            let _ = js_sys::Reflect::set(&item, &"id".into(), &JsValue::from(entry.id));
+            let _ = js_sys::Reflect::set(&item, &"url".into(), &JsValue::from_str(&entry.url));
+            if let Some(title) = &entry.title {
+                let _ = js_sys::Reflect::set(&item, &"title".into(), &JsValue::from_str(title));
+            }
+            let _ = js_sys::Reflect::set(&item, &"enabled".into(), &JsValue::from(entry.enabled));
+            if let Some(last) = entry.last_updated_ms {
+                let _ = js_sys::Reflect::set(&item, &"lastUpdated".into(), &JsValue::from(last as f64));
+            }
+            let expires_secs = (entry.expiry_ms(expiry) / 1000) as u32;
+            let _ = js_sys::Reflect::set(&item, &"expiresSecs".into(), &JsValue::from(expires_secs));
+            if let Some(etag) = &entry.etag {
+                let _ = js_sys::Reflect::set(&item, &"etag".into(), &JsValue::from_str(etag));
+            }
+            if let Some(at) = next_update {
+                let _ = js_sys::Reflect::set(&item, &"nextUpdate".into(), &JsValue::from(at as f64));
+            }
+            let _ = js_sys::Reflect::set(&item, &"due".into(), &JsValue::from(is_due));
+            lists.push(&item);
+
+            match next_update {
+                Some(at) if is_due => due.push((at, entry.id, entry.url.clone(), entry.etag.clone())),
+                Some(at) => next_check = Some(next_check.map_or(at, |next| next.min(at))),
+                None => {}
+            }
+        }
+    });
+
+    due.sort_by_key(|&(at, id, _, _)| (at, id));
+    let refresh = js_sys::Array::new();
+    for (_, id, url, etag) in due {
+        let item = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&item, &"id".into(), &JsValue::from(id));
+        let _ = js_sys::Reflect::set(&item, &"url".into(), &JsValue::from_str(&url));
+        if let Some(etag) = etag {
+            let _ = js_sys::Reflect::set(&item, &"etag".into(), &JsValue::from_str(&etag));
+        }
+        refresh.push(&item);
     }
-    let normalized = rule_type.to_lowercase();
-    match normalized.as_str() {
-        "document" => request_type == "main_frame" || request_type == "sub_frame",
-        "subdocument" | "sub_frame" => request_type == "sub_frame",
-        "main_frame" => request_type == "main_frame",
-        "xhr" => request_type == "xmlhttprequest",
-        _ => normalized == request_type,
+
+    let result = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&result, &"lists".into(), &lists);
+    let _ = js_sys::Reflect::set(&result, &"refresh".into(), &refresh);
+    if let Some(next_check) = next_check {
+        let _ = js_sys::Reflect::set(&result, &"nextCheck".into(), &JsValue::from(next_check as f64));
     }
+    result.into()
 }
 
-fn is_overly_broad_dynamic_rule(rule: &DynamicRule) -> bool {
-    let site_pattern = rule.site.to_lowercase();
-    let target_pattern = rule.target.to_lowercase();
-    let type_pattern = rule.rule_type.to_lowercase();
-    let is_global_site = site_pattern == "*";
-    let is_global_target = target_pattern == "*";
-    let is_main_frame_type = type_pattern == "*" || type_pattern == "main_frame" || type_pattern == "document";
-    is_global_site && is_global_target && is_main_frame_type
+/// Record a successful fetch (or a `304 Not Modified`) of list `id` at
+/// `timestamp` epoch ms, or now if `timestamp` isn't positive. Returns false
+/// if the catalog has no such list.
+#[wasm_bindgen]
+pub fn mark_list_updated(id: u16, etag: Option<String>, timestamp: f64) -> bool {
+    let at = if timestamp.is_finite() && timestamp > 0.0 { timestamp as u64 } else { now_ms() };
+    with_runtime(|runtime| match runtime.list_catalog.get_mut(id) {
+        Some(entry) => {
+            entry.last_updated_ms = Some(at);
+            if etag.is_some() {
+                entry.etag = etag;
+            }
+            true
+        }
+        None => false,
+    })
 }
 
-fn parse_dynamic_rules(value: JsValue) -> Result<Vec<DynamicRule>, JsValue> {
-    let array = js_sys::Array::from(&value);
-    let mut rules = Vec::with_capacity(array.length() as usize);
+/// Stop individual network rules (the `ruleId`s match results report) from
+/// matching, replacing the previous set. Rule ids belong to the loaded
+/// snapshot, so the set is dropped when `reload()` swaps in a new one.
+#[wasm_bindgen]
+pub fn set_disabled_rules(rule_ids: Vec<u32>) -> Result<(), JsValue> {
+    MATCHER_STATE.with(|current| match current.borrow_mut().as_mut() {
+        Some(state) => match Rc::get_mut(state) {
+            Some(state) => {
+                let matcher = state.engine.matcher_mut();
+                matcher.set_disabled_rules(&rule_ids);
+                with_runtime(|runtime| apply_suppressed_filters(matcher, &runtime.settings.suppressed_filters));
+                invalidate_cosmetic_cache();
+                Ok(())
+            }
+            None => Err(JsValue::from_str("Snapshot is in use; try again")),
+        },
+        None => Err(JsValue::from_str("No snapshot loaded")),
+    })
+}
 
-    for entry in array.iter() {
-        let site = normalize_pattern(get_string_field(&entry, "site"));
-        let target = normalize_pattern(get_string_field(&entry, "target"));
-        let rule_type = normalize_pattern(get_string_field(&entry, "type"));
-        let action_val = js_sys::Reflect::get(&entry, &JsValue::from_str("action"))
+/// Cancel the snapshot's copies of `filter` at runtime, as a `$badfilter`
+/// of it in a list would at compile time. `filter` may be given with or
+/// without `$badfilter`. Returns the ids of the rules it disabled; they stay
+/// disabled across `set_disabled_rules()` and, while the new snapshot has
+/// the same rules, across `reload()`.
+#[wasm_bindgen]
+pub fn suppress_filter(filter: &str) -> Result<Vec<u32>, JsValue> {
+    let signatures: Vec<Hash64> = parse_filter_list(filter.trim()).iter().filter_map(rule_signature).collect();
+    if signatures.is_empty() {
+        return Err(JsValue::from_str("Not a network filter"));
+    }
+
+    MATCHER_STATE.with(|current| match current.borrow_mut().as_mut() {
+        Some(state) => match Rc::get_mut(state) {
+            Some(state) => {
+                let snapshot = state.snapshot();
+                if !snapshot.has_rule_signatures() {
+                    return Err(JsValue::from_str("Snapshot has no rule signatures; recompile it to suppress filters"));
+                }
+                let mut rule_ids: Vec<u32> =
+                    signatures.iter().flat_map(|&signature| snapshot.rules_with_signature(signature)).collect();
+                rule_ids.sort_unstable();
+                rule_ids.dedup();
+
+                with_runtime(|runtime| {
+                    for signature in signatures {
+                        if !runtime.settings.suppressed_filters.contains(&signature) {
+                            runtime.settings.suppressed_filters.push(signature);
+                        }
+                    }
+                });
+                let matcher = state.engine.matcher_mut();
+                let mut disabled = matcher.disabled_rules();
+                disabled.extend_from_slice(&rule_ids);
+                matcher.set_disabled_rules(&disabled);
+                invalidate_cosmetic_cache();
+                Ok(rule_ids)
+            }
+            None => Err(JsValue::from_str("Snapshot is in use; try again")),
+        },
+        None => Err(JsValue::from_str("No snapshot loaded")),
+    })
+}
+
+/// Replace the user styles from a JSON array of `{ site, hide?, css? }`:
+/// `hide` is a list of selectors to hide and `css` a stylesheet, both applied
+/// on `site` and its subdomains (`*` or no site for every page).
+/// `match_cosmetics` merges them with the snapshot's cosmetics from the next
+/// call on, so they take effect without recompiling.
+#[wasm_bindgen]
+pub fn set_user_styles(json: &str) -> Result<(), JsValue> {
+    let value = js_sys::JSON::parse(json)?;
+    if !js_sys::Array::is_array(&value) {
+        return Err(JsValue::from_str("User styles must be a JSON array"));
+    }
+    let mut styles = Vec::new();
+    for entry in js_sys::Array::from(&value).iter() {
+        let hide = js_sys::Reflect::get(&entry, &JsValue::from_str("hide"))
             .ok()
-            .and_then(|v| v.as_f64())
-            .unwrap_or(0.0) as u8;
-        let action = DynamicAction::from_u8(action_val);
-        rules.push(DynamicRule {
-            site,
-            target,
-            rule_type,
-            action,
+            .filter(js_sys::Array::is_array)
+            .map_or_else(Vec::new, parse_string_array);
+        for selector in &hide {
+            if let Some(issue) = selector_issue(selector) {
+                return Err(JsValue::from_str(&format!("Invalid user style selector '{}': {}", selector, issue)));
+            }
+        }
+        // Normalized like list selectors, so `#@#` exceptions recognize them.
+        let hide: Vec<String> = hide.iter().map(|selector| normalize_selector(selector)).collect();
+        let css = get_string_field(&entry, "css").unwrap_or_default().trim().to_string();
+        if hide.is_empty() && css.is_empty() {
+            continue;
+        }
+        styles.push(UserStyle {
+            site: normalize_pattern(get_string_field(&entry, "site")),
+            hide,
+            css,
         });
     }
+    with_runtime(|runtime| runtime.user_styles = styles);
+    invalidate_cosmetic_cache();
+    Ok(())
+}
 
-    Ok(rules)
+/// Rule ids disabled by `set_disabled_rules()` or `suppress_filter()`.
+#[wasm_bindgen]
+pub fn get_disabled_rules() -> Vec<u32> {
+    current_state().map_or_else(Vec::new, |state| state.engine().matcher().disabled_rules())
 }
 
-fn parse_string_array(value: JsValue) -> Vec<String> {
-    let array = js_sys::Array::from(&value);
-    array
-        .iter()
-        .filter_map(|entry| entry.as_string())
-        .map(|entry| entry.trim().to_string())
-        .filter(|entry| !entry.is_empty())
-        .collect()
+/// Push the matcher-level runtime settings into a freshly loaded matcher.
+fn configure_matcher(matcher: &mut Matcher<'_>, settings: &RuntimeSettings) {
+    matcher.set_disabled_lists(&settings.disabled_lists);
+    matcher.set_csp_report_only(settings.csp_report_only);
+    matcher.set_redirect_data_urls(settings.redirect_data_urls);
+    matcher.set_match_budget(settings.match_budget);
+    matcher.set_hide_css_chunk(settings.hide_css_chunk);
+    matcher.set_large_media_size(settings.large_media_size);
+    matcher.clear_site_switches();
+    for (site, &switches) in &settings.site_switches {
+        matcher.set_site_switches(site, switches);
+    }
+    apply_suppressed_filters(matcher, &settings.suppressed_filters);
+    if matcher.next_rule_expiry().is_some() {
+        matcher.set_current_time(now_ms() / 1000);
+    }
 }
 
-#[wasm_bindgen]
-pub fn set_dynamic_rules(value: JsValue) -> Result<(), JsValue> {
-    let rules = parse_dynamic_rules(value)?;
-    with_runtime(|state| {
-        state.dynamic_rules = rules;
-    });
-    Ok(())
+/// Add the rules of every suppressed filter to the matcher's disabled set.
+fn apply_suppressed_filters(matcher: &mut Matcher<'_>, signatures: &[Hash64]) {
+    if signatures.is_empty() {
+        return;
+    }
+    let snapshot = matcher.snapshot();
+    let mut disabled = matcher.disabled_rules();
+    disabled.extend(signatures.iter().flat_map(|&signature| snapshot.rules_with_signature(signature)));
+    matcher.set_disabled_rules(&disabled);
 }
 
-#[wasm_bindgen]
-pub fn set_runtime_settings(value: JsValue) -> Result<(), JsValue> {
-    with_runtime(|state| {
-        if let Ok(val) = js_sys::Reflect::get(&value, &JsValue::from_str("dynamicFilteringEnabled")) {
-            if let Some(enabled) = val.as_bool() {
-                state.settings.dynamic_filtering_enabled = enabled;
-            }
-        }
-        if let Ok(val) = js_sys::Reflect::get(&value, &JsValue::from_str("disabledSites")) {
-            if !val.is_undefined() && !val.is_null() {
-                state.settings.disabled_sites = parse_string_array(val);
-            }
+/// Re-apply settings to a loaded matcher. Only possible while no in-flight
+/// call holds the state.
+fn reconfigure_matcher(state: &mut Rc<MatcherState>, settings: &RuntimeSettings) -> bool {
+    match Rc::get_mut(state) {
+        Some(state) => {
+            configure_matcher(state.engine.matcher_mut(), settings);
+            invalidate_cosmetic_cache();
+            true
         }
-    });
-    Ok(())
+        None => false,
+    }
 }
 
 #[wasm_bindgen]
 pub fn get_site_pattern_js(url: &str) -> Option<String> {
     let host = extract_host(url)?;
-    let etld1 = get_etld1(host);
-    if !etld1.is_empty() {
-        return Some(etld1);
-    }
-    Some(host.to_string())
+    let etld1 = get_etld1_ref(host);
+    Some(if etld1.is_empty() { host } else { etld1 }.to_string())
 }
 
 #[wasm_bindgen]
@@ -719,6 +2740,9 @@ pub fn is_site_disabled_js(url: &str) -> bool {
     with_runtime(|state| state.settings.disabled_sites.iter().any(|pattern| host_matches(pattern, host)))
 }
 
+/// The dynamic filtering action for a request, as `{ action, isOverlyBroad }`.
+/// Pass `"inline-script"` as the request type, with the document as `url`, to
+/// ask whether the document's inline scripts are blocked.
 #[wasm_bindgen]
 pub fn match_dynamic(url: &str, request_type: &str, initiator: Option<String>) -> JsValue {
     let (action, is_overly_broad) = with_runtime(|state| {
@@ -729,48 +2753,16 @@ pub fn match_dynamic(url: &str, request_type: &str, initiator: Option<String>) -
         let req_host = extract_host(url).unwrap_or("");
         let site_url = initiator.as_deref().unwrap_or(url);
         let site_host = extract_host(site_url).unwrap_or("");
-        let site_etld1 = get_etld1(site_host);
-        let req_etld1 = get_etld1(req_host);
-        let is_third_party = !site_etld1.is_empty() && !req_etld1.is_empty() && site_etld1 != req_etld1;
-
-        let mut best_action = DynamicAction::Noop;
-        let mut best_rule: Option<&DynamicRule> = None;
-        let mut best_score = -1i32;
-        let mut best_index = -1i32;
-
-        for (idx, rule) in state.dynamic_rules.iter().enumerate() {
-            let site_pattern = rule.site.to_lowercase();
-            let target_pattern = rule.target.to_lowercase();
-            let type_pattern = rule.rule_type.to_lowercase();
-
-            if !host_matches(&site_pattern, site_host) {
-                continue;
-            }
-            if !target_matches(&target_pattern, req_host, &req_etld1, is_third_party) {
-                continue;
-            }
-            if !type_matches(&type_pattern, request_type) {
-                continue;
-            }
-
-            let mut score = 0i32;
-            if site_pattern != "*" {
-                score += 1;
-            }
-            if target_pattern != "*" {
-                score += 1;
-            }
-            if type_pattern != "*" {
-                score += 1;
-            }
+        let site_etld1 = get_etld1_ref(site_host);
+        let req_etld1 = get_etld1_ref(req_host);
+        let request = DynamicRequest {
+            req_host,
+            is_third_party: !site_etld1.is_empty() && !req_etld1.is_empty() && !site_etld1.eq_ignore_ascii_case(req_etld1),
+            request_type,
+        };
 
-            if score > best_score || (score == best_score && idx as i32 > best_index) {
-                best_score = score;
-                best_index = idx as i32;
-                best_action = rule.action;
-                best_rule = Some(rule);
-            }
-        }
+        let best_rule = state.dynamic_rules.evaluate(site_host, &request);
+        let best_action = best_rule.map_or(DynamicAction::Noop, |rule| rule.action);
 
         let is_main_frame = request_type == "main_frame" || request_type == "document";
         if best_action == DynamicAction::Block && is_main_frame {
@@ -794,6 +2786,157 @@ pub fn match_dynamic(url: &str, request_type: &str, initiator: Option<String>) -
     result.into()
 }
 
+/// The dynamic filtering matrix as the popup shows it for `site`: one row per
+/// cell type (target `*`), then one per host in `targets` (type `*`). Each
+/// row is `{ target, type, action, local, global }`, where `local` is the
+/// action set for `site` or a parent domain, `global` the one set for `*`
+/// (`null` when unset), and `action` the one that applies.
+#[wasm_bindgen]
+pub fn evaluate_switchboard(site: &str, targets: JsValue) -> JsValue {
+    let site = site.trim().to_lowercase();
+    let hosts = if targets.is_undefined() || targets.is_null() {
+        Vec::new()
+    } else {
+        parse_string_array(targets)
+    };
+    let cells = DYNAMIC_CELL_TYPES
+        .iter()
+        .map(|&rule_type| ("*".to_string(), rule_type))
+        .chain(hosts.into_iter().map(|host| (host.to_lowercase(), "*")));
+
+    let rows = js_sys::Array::new();
+    with_runtime(|state| {
+        let action = |rule: Option<&DynamicRule>| rule.map_or(JsValue::NULL, |rule| JsValue::from(rule.action as u8));
+        for (target, rule_type) in cells {
+            let (local, global) = state.dynamic_rules.cell(&site, &target, rule_type);
+            let effective = local.or(global).map_or(DynamicAction::Noop, |rule| rule.action);
+            let row = js_sys::Object::new();
+            let _ = js_sys::Reflect::set(&row, &"target".into(), &JsValue::from_str(&target));
+            let _ = js_sys::Reflect::set(&row, &"type".into(), &JsValue::from_str(rule_type));
+            let _ = js_sys::Reflect::set(&row, &"action".into(), &JsValue::from(effective as u8));
+            let _ = js_sys::Reflect::set(&row, &"local".into(), &action(local));
+            let _ = js_sys::Reflect::set(&row, &"global".into(), &action(global));
+            rows.push(&row);
+        }
+    });
+    rows.into()
+}
+
+/// Record a frame navigation. Frame 0 is the top frame; creating it again
+/// (a main-frame navigation) discards the tab's previous frame tree.
+#[wasm_bindgen]
+pub fn frame_created(tab_id: i32, frame_id: i32, parent_frame_id: i32, url: &str) {
+    let host = extract_host(url).unwrap_or("").to_string();
+    with_runtime(|state| {
+        let frames = state.frames.entry(tab_id).or_default();
+        if frame_id == 0 {
+            frames.clear();
+        } else if frames.len() >= MAX_FRAMES_PER_TAB && !frames.contains_key(&frame_id) {
+            return;
+        }
+        frames.insert(frame_id, FrameInfo { parent_frame_id, host });
+    });
+}
+
+/// Forget a frame and every frame nested inside it.
+#[wasm_bindgen]
+pub fn frame_removed(tab_id: i32, frame_id: i32) {
+    with_runtime(|state| {
+        let Some(frames) = state.frames.get_mut(&tab_id) else {
+            return;
+        };
+        let mut doomed = vec![frame_id];
+        while let Some(id) = doomed.pop() {
+            frames.remove(&id);
+            doomed.extend(
+                frames
+                    .iter()
+                    .filter(|(_, frame)| frame.parent_frame_id == id)
+                    .map(|(&child, _)| child),
+            );
+        }
+    });
+}
+
+#[wasm_bindgen]
+pub fn tab_removed(tab_id: i32) {
+    with_runtime(|state| {
+        state.frames.remove(&tab_id);
+    });
+}
+
+/// Host of the document a request is made from, from the frame tree.
+///
+/// A `sub_frame` request is made by the frame's parent; anything else by the
+/// frame itself. Frames without a host of their own (about:blank, srcdoc)
+/// inherit the nearest ancestor's. `None` if the frame is not tracked.
+fn frame_site_host(tab_id: i32, frame_id: i32, request_type: &str) -> Option<String> {
+    if tab_id < 0 || frame_id < 0 {
+        return None;
+    }
+    with_runtime(|state| {
+        let frames = state.frames.get(&tab_id)?;
+        let mut current = frames.get(&frame_id)?;
+        if request_type == "sub_frame" {
+            current = frames.get(&current.parent_frame_id)?;
+        }
+        for _ in 0..MAX_FRAME_DEPTH {
+            if !current.host.is_empty() {
+                return Some(current.host.clone());
+            }
+            current = frames.get(&current.parent_frame_id)?;
+        }
+        None
+    })
+}
+
+/// Hosts, eTLD+1s and scheme for a webRequest event, derived once and shared
+/// by every lookup made for it. The tracked frame's host wins over the
+/// initiator, which Chrome reports as an origin only.
+fn request_info<'u>(
+    url: &'u str,
+    request_type: &str,
+    initiator: Option<&'u str>,
+    frame_site: Option<&'u str>,
+) -> RequestInfo<'u> {
+    let site_host = frame_site.or_else(|| initiator.and_then(extract_host));
+    let mut request_type = parse_request_type(request_type);
+    if request_type == RequestType::OTHER && with_runtime(|state| state.settings.infer_request_types) {
+        request_type = refine_request_type(request_type, url, None);
+    }
+    RequestInfo::with_site_host(url, request_type, site_host)
+}
+
+/// Websocket requests reported with an `http(s)://` URL (Firefox), rewritten
+/// to `ws(s)://` so scheme-anchored websocket filters see what they expect.
+fn websocket_request_url(url: &str, request_type: &str) -> Option<String> {
+    if parse_request_type(request_type) == RequestType::WEBSOCKET {
+        websocket_url(url)
+    } else {
+        None
+    }
+}
+
+/// The browser type string for `url` given its `Accept` header, or
+/// `undefined` when neither the header nor the path extension is telling.
+/// Lets callers that see request headers refine `other` themselves.
+#[wasm_bindgen]
+pub fn infer_request_type(url: &str, accept: Option<String>) -> Option<String> {
+    let inferred = bb_core::url::infer_request_type(url, accept.as_deref())?;
+    let name = match inferred {
+        RequestType::SCRIPT => "script",
+        RequestType::IMAGE => "image",
+        RequestType::STYLESHEET => "stylesheet",
+        RequestType::FONT => "font",
+        RequestType::MEDIA => "media",
+        RequestType::OBJECT => "object",
+        RequestType::MANIFEST => "manifest",
+        RequestType::WEBSOCKET => "websocket",
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
 #[wasm_bindgen]
 pub fn removeparam_should_skip(tab_id: i32, frame_id: i32, url: &str, redirect_url: &str) -> bool {
     let key = format!("{tab_id}:{frame_id}:{url}");
@@ -828,17 +2971,21 @@ pub fn removeparam_clear_tab(tab_id: i32) {
     });
 }
 
+/// Enable or disable request tracing. With `capture_outcome`, each recorded
+/// request is also matched so entries carry the decision and cosmetic counts.
 #[wasm_bindgen]
-pub fn trace_configure(enabled: bool, max_entries: u32) {
+pub fn trace_configure(enabled: bool, max_entries: u32, capture_outcome: Option<bool>) {
     with_runtime(|state| {
         state.trace_enabled = enabled;
+        state.trace_capture_outcome = enabled && capture_outcome.unwrap_or(false);
         let max = if max_entries == 0 { MAX_TRACE_ENTRIES as u32 } else { max_entries };
         let clamped = max
             .max(1_000)
             .min(MAX_TRACE_ENTRIES_UPPER as u32) as usize;
         state.trace_max_entries = clamped;
         if !enabled {
-            state.trace_entries.clear();
+            state.trace_entries = VecDeque::new();
+            state.trace_next_seq = 0;
         }
     });
 }
@@ -855,24 +3002,78 @@ pub fn trace_record(
     if url.is_empty() {
         return;
     }
-    with_runtime(|state| {
-        if !state.trace_enabled {
-            return;
-        }
-        if state.trace_entries.len() >= state.trace_max_entries {
-            return;
+    let (record, capture_outcome) = with_runtime(|state| {
+        let record = state.trace_enabled && state.trace_entries.len() < state.trace_max_entries;
+        if state.trace_enabled && !record {
+            // Keep the sequence moving so drained feeds can see the gap.
+            state.trace_next_seq += 1;
         }
-        state.trace_entries.push(TraceEntry {
+        (record, state.trace_capture_outcome)
+    });
+    if !record {
+        return;
+    }
+
+    let outcome = if capture_outcome {
+        trace_outcome(url, request_type, initiator.as_deref(), tab_id, frame_id, request_id)
+    } else {
+        None
+    };
+
+    with_runtime(|state| {
+        let seq = state.trace_next_seq;
+        state.trace_next_seq += 1;
+        state.trace_entries.push_back(TraceEntry {
+            seq,
             url: url.to_string(),
             request_type: request_type.to_string(),
             initiator,
             tab_id,
             frame_id,
             request_id: request_id.to_string(),
+            outcome,
         });
     });
 }
 
+fn trace_outcome(
+    url: &str,
+    request_type: &str,
+    initiator: Option<&str>,
+    tab_id: i32,
+    frame_id: i32,
+    request_id: &str,
+) -> Option<TraceOutcome> {
+    let state = current_state()?;
+    let engine = state.engine();
+    let frame_site = frame_site_host(tab_id, frame_id, request_type);
+    let ws_url = websocket_request_url(url, request_type);
+    let url = ws_url.as_deref().unwrap_or(url);
+    let request = request_info(url, request_type, initiator, frame_site.as_deref())
+        .with_ids(tab_id, frame_id, request_id);
+
+    let result = engine.check_request(&request);
+    let mut outcome = TraceOutcome {
+        decision: result.decision as u8,
+        rule_id: result.rule_id,
+        list_id: result.list_id,
+        redirect_url: result.redirect_url,
+        hidden_selectors: 0,
+        procedural: 0,
+        scriptlets: 0,
+    };
+
+    if parse_request_type(request_type).intersects(RequestType::DOCUMENT) {
+        let cosmetics = engine.cosmetics_for_request(&request);
+        // Each hide rule has `,\n`-joined selectors.
+        outcome.hidden_selectors = cosmetics.css.iter().map(|rule| rule.matches(",\n").count() as u32 + 1).sum();
+        outcome.procedural = cosmetics.procedural.len() as u32;
+        outcome.scriptlets = cosmetics.scriptlets.len() as u32;
+    }
+
+    Some(outcome)
+}
+
 #[wasm_bindgen]
 pub fn trace_stats() -> JsValue {
     let (enabled, count, max) = with_runtime(|state| {
@@ -885,37 +3086,61 @@ pub fn trace_stats() -> JsValue {
     result.into()
 }
 
+fn trace_entry_object(entry: &TraceEntry) -> js_sys::Object {
+    let obj = js_sys::Object::new();
+    let set = |key: &str, value: &JsValue| {
+        let _ = js_sys::Reflect::set(&obj, &JsValue::from_str(key), value);
+    };
+    set("seq", &JsValue::from(entry.seq as f64));
+    set("url", &JsValue::from_str(&entry.url));
+    set("type", &JsValue::from_str(&entry.request_type));
+    if let Some(initiator) = &entry.initiator {
+        set("initiator", &JsValue::from_str(initiator));
+    }
+    set("tabId", &JsValue::from(entry.tab_id));
+    set("frameId", &JsValue::from(entry.frame_id));
+    set("requestId", &JsValue::from_str(&entry.request_id));
+    if let Some(outcome) = &entry.outcome {
+        set("decision", &JsValue::from(outcome.decision));
+        set("ruleId", &JsValue::from(outcome.rule_id));
+        set("listId", &JsValue::from(outcome.list_id));
+        if let Some(redirect_url) = &outcome.redirect_url {
+            set("redirectUrl", &JsValue::from_str(redirect_url));
+        }
+        if parse_request_type(&entry.request_type).intersects(RequestType::DOCUMENT) {
+            set("hiddenSelectors", &JsValue::from(outcome.hidden_selectors));
+            set("procedural", &JsValue::from(outcome.procedural));
+            set("scriptlets", &JsValue::from(outcome.scriptlets));
+        }
+    }
+    obj
+}
+
+/// Remove and return up to `max` of the oldest trace entries (all of them
+/// if `max` is 0), for an incremental logger feed.
+#[wasm_bindgen]
+pub fn trace_drain(max: u32) -> JsValue {
+    let entries: Vec<TraceEntry> = with_runtime(|state| {
+        let count = if max == 0 {
+            state.trace_entries.len()
+        } else {
+            (max as usize).min(state.trace_entries.len())
+        };
+        state.trace_entries.drain(..count).collect()
+    });
+    let result = js_sys::Array::new();
+    for entry in &entries {
+        result.push(&trace_entry_object(entry));
+    }
+    result.into()
+}
+
 #[wasm_bindgen]
 pub fn trace_export_jsonl() -> String {
     let entries = with_runtime(|state| state.trace_entries.clone());
     let mut out = String::new();
-    for entry in entries {
-        let obj = js_sys::Object::new();
-        let _ = js_sys::Reflect::set(&obj, &JsValue::from_str("url"), &JsValue::from_str(&entry.url));
-        let _ = js_sys::Reflect::set(
-            &obj,
-            &JsValue::from_str("type"),
-            &JsValue::from_str(&entry.request_type),
-        );
-        if let Some(initiator) = entry.initiator {
-            let _ = js_sys::Reflect::set(
-                &obj,
-                &JsValue::from_str("initiator"),
-                &JsValue::from_str(&initiator),
-            );
-        }
-        let _ = js_sys::Reflect::set(&obj, &JsValue::from_str("tabId"), &JsValue::from(entry.tab_id));
-        let _ = js_sys::Reflect::set(
-            &obj,
-            &JsValue::from_str("frameId"),
-            &JsValue::from(entry.frame_id),
-        );
-        let _ = js_sys::Reflect::set(
-            &obj,
-            &JsValue::from_str("requestId"),
-            &JsValue::from_str(&entry.request_id),
-        );
-        if let Ok(json) = js_sys::JSON::stringify(&obj) {
+    for entry in &entries {
+        if let Ok(json) = js_sys::JSON::stringify(&trace_entry_object(entry)) {
             if let Some(line) = json.as_string() {
                 out.push_str(&line);
                 out.push('\n');
@@ -925,7 +3150,7 @@ pub fn trace_export_jsonl() -> String {
     out
 }
 
-fn perf_summary(values: &mut Vec<f64>) -> (u32, f64, f64, f64, f64, f64) {
+fn perf_summary(values: &mut [f64]) -> (u32, f64, f64, f64, f64, f64) {
     if values.is_empty() {
         return (0, 0.0, 0.0, 0.0, 0.0, 0.0);
     }
@@ -958,8 +3183,9 @@ pub fn perf_configure(enabled: bool, max_entries: u32) {
             .min(MAX_PERF_ENTRIES_UPPER as u32) as usize;
         state.perf_max_entries = clamped;
         if !enabled {
-            state.perf_before_request.values.clear();
-            state.perf_headers_received.values.clear();
+            state.perf_before_request.values = Vec::new();
+            state.perf_headers_received.values = Vec::new();
+            state.perf_budget_exceeded = 0;
         }
     });
 }
@@ -984,11 +3210,12 @@ pub fn perf_record(phase: u8, duration_ms: f64) {
 
 #[wasm_bindgen]
 pub fn perf_stats() -> JsValue {
-    let (before, headers, enabled) = with_runtime(|state| {
+    let (before, headers, enabled, budget_exceeded) = with_runtime(|state| {
         (
             state.perf_before_request.values.clone(),
             state.perf_headers_received.values.clone(),
             state.perf_enabled,
+            state.perf_budget_exceeded,
         )
     });
     let mut before_vals = before;
@@ -1016,6 +3243,7 @@ pub fn perf_stats() -> JsValue {
     let _ = js_sys::Reflect::set(&result, &JsValue::from_str("enabled"), &JsValue::from(enabled));
     let _ = js_sys::Reflect::set(&result, &JsValue::from_str("beforeRequest"), &before_obj);
     let _ = js_sys::Reflect::set(&result, &JsValue::from_str("headersReceived"), &headers_obj);
+    let _ = js_sys::Reflect::set(&result, &JsValue::from_str("budgetExceeded"), &JsValue::from(budget_exceeded));
     result.into()
 }
 
@@ -1115,120 +3343,6 @@ fn parse_scriptlet_arg(raw: &str) -> JsValue {
     JsValue::from_str(raw)
 }
 
-struct ProceduralOp {
-    op_type: String,
-    args: String,
-}
-
-struct ProceduralToken {
-    op_type: &'static str,
-    token: &'static str,
-}
-
-const PROCEDURAL_TOKENS: [ProceduralToken; 6] = [
-    ProceduralToken {
-        op_type: "has-text",
-        token: ":has-text(",
-    },
-    ProceduralToken {
-        op_type: "matches-css",
-        token: ":matches-css(",
-    },
-    ProceduralToken {
-        op_type: "xpath",
-        token: ":xpath(",
-    },
-    ProceduralToken {
-        op_type: "upward",
-        token: ":upward(",
-    },
-    ProceduralToken {
-        op_type: "remove",
-        token: ":remove(",
-    },
-    ProceduralToken {
-        op_type: "style",
-        token: ":style(",
-    },
-];
-
-fn find_next_procedural_op(raw: &str, start: usize) -> Option<(usize, &'static ProceduralToken)> {
-    let mut best: Option<(usize, &'static ProceduralToken)> = None;
-    for token in PROCEDURAL_TOKENS.iter() {
-        if let Some(idx) = raw[start..].find(token.token) {
-            let index = start + idx;
-            if best.map_or(true, |(best_idx, _)| index < best_idx) {
-                best = Some((index, token));
-            }
-        }
-    }
-    best
-}
-
-fn read_paren_content(raw: &str, start: usize) -> Option<(String, usize)> {
-    let bytes = raw.as_bytes();
-    if bytes.get(start) != Some(&b'(') {
-        return None;
-    }
-    let mut depth = 0i32;
-    let mut i = start;
-    while i < bytes.len() {
-        match bytes[i] {
-            b'(' => depth += 1,
-            b')' => {
-                depth -= 1;
-                if depth == 0 {
-                    return Some((raw[start + 1..i].to_string(), i));
-                }
-            }
-            _ => {}
-        }
-        i += 1;
-    }
-    None
-}
-
-fn parse_procedural_rule(raw: &str) -> Option<(String, Vec<ProceduralOp>)> {
-    let first = find_next_procedural_op(raw, 0)?;
-    let base = raw[..first.0].trim();
-    let mut ops = Vec::new();
-    let mut cursor = first.0;
-    while cursor < raw.len() {
-        let next = find_next_procedural_op(raw, cursor);
-        let Some((index, token)) = next else { break };
-        let paren_start = index + token.token.len() - 1;
-        let parsed = read_paren_content(raw, paren_start)?;
-        ops.push(ProceduralOp {
-            op_type: token.op_type.to_string(),
-            args: parsed.0.trim().to_string(),
-        });
-        cursor = parsed.1 + 1;
-    }
-    if ops.is_empty() {
-        return None;
-    }
-    let base_selector = if base.is_empty() { "*" } else { base };
-    Some((base_selector.to_string(), ops))
-}
-
 fn parse_request_type(request_type: &str) -> RequestType {
-    match request_type {
-        "main_frame" | "document" => RequestType::MAIN_FRAME,
-        "sub_frame" | "subdocument" => RequestType::SUBDOCUMENT,
-        "stylesheet" | "css" => RequestType::STYLESHEET,
-        "script" | "js" => RequestType::SCRIPT,
-        "image" | "img" => RequestType::IMAGE,
-        "font" => RequestType::FONT,
-        "object" => RequestType::OBJECT,
-        "xmlhttprequest" | "xhr" => RequestType::XMLHTTPREQUEST,
-        "ping" => RequestType::PING,
-        "beacon" => RequestType::BEACON,
-        "fetch" => RequestType::FETCH,
-        "csp_report" => RequestType::CSP_REPORT,
-        "speculative" => RequestType::SPECULATIVE,
-        "media" => RequestType::MEDIA,
-        "websocket" | "ws" => RequestType::WEBSOCKET,
-        "other" => RequestType::OTHER,
-        _ => RequestType::OTHER,
-    }
+    RequestType::from_str(request_type)
 }
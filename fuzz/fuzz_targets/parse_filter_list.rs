@@ -0,0 +1,18 @@
+//! `parse_filter_list` over arbitrary text. Whatever the parser accepts must
+//! optimize, build and load back without panicking.
+
+#![no_main]
+
+use bb_compiler::{build_snapshot, optimize_rules, parse_filter_list};
+use bb_core::Snapshot;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    let mut rules = parse_filter_list(text);
+    optimize_rules(&mut rules);
+    let bytes = build_snapshot(&rules);
+    Snapshot::load_without_psl(&bytes).expect("compiled snapshot should load");
+});
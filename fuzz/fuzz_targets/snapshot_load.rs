@@ -0,0 +1,42 @@
+//! `Snapshot::load` and the matcher over arbitrary bytes. Loading may fail;
+//! neither loading nor matching against whatever loads may panic.
+
+#![no_main]
+
+use bb_core::matcher::ResponseHeader;
+use bb_core::types::RequestType;
+use bb_core::{Engine, Snapshot};
+use libfuzzer_sys::fuzz_target;
+
+const URLS: &[&str] = &[
+    "https://ads.example.com/banner/ad.js?utm_source=feed",
+    "https://example.com/",
+    "wss://tracker.example.net/socket",
+    "http://sub.example.co.uk/pixel.gif",
+];
+
+const TYPES: &[RequestType] = &[
+    RequestType::MAIN_FRAME,
+    RequestType::SUBDOCUMENT,
+    RequestType::SCRIPT,
+    RequestType::IMAGE,
+];
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(snapshot) = Snapshot::load(data) else {
+        return;
+    };
+    snapshot.all_list_metadata();
+    snapshot.build_info();
+    snapshot.redirect_resources().count();
+
+    let engine = Engine::new(&snapshot);
+    let headers = [ResponseHeader { name: "content-type", value: "text/html" }];
+    for url in URLS {
+        for &request_type in TYPES {
+            engine.check(url, request_type, Some("https://news.example.org/"));
+        }
+        engine.cosmetics_for(url);
+        engine.headers_for(url, &headers);
+    }
+});
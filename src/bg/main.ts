@@ -11,6 +11,7 @@ import {
   clearStoredSnapshot,
   loadStoredSnapshot,
   saveStoredSnapshot,
+  type CompileError,
   type SnapshotStats,
 } from './snapshot-store.js';
 
@@ -54,11 +55,14 @@ const DEFAULT_LISTS: FilterList[] = [
 const DEFAULT_LIST_BY_ID = new Map(DEFAULT_LISTS.map((list) => [list.id, list]));
 const UPDATE_ALARM_NAME = 'listUpdate';
 const UPDATE_INTERVAL_MINUTES = 24 * 60;
+const MIN_UPDATE_INTERVAL_MINUTES = 60;
 const LIST_FETCH_TIMEOUT_MS = 30_000;
 const LIST_MAX_BYTES = 25 * 1024 * 1024;
 const topFrameByTab = new Map<number, string>();
 const blockedByTab = new Map<number, number>();
 const mainFrameRequestIdByTab = new Map<number, string>();
+// Tabs opened by another tab and not yet navigated, with the opener's URL.
+const popupOpenerByTab = new Map<number, string>();
 let dynamicRules: DynamicRule[] = [];
 let settings: UserSettings = { ...DEFAULT_SETTINGS };
 const BADGE_COLOR = '#d94848';
@@ -102,6 +106,8 @@ function syncRuntimeSettings(): void {
     wasm.set_runtime_settings({
       dynamicFilteringEnabled: settings.dynamicFilteringEnabled,
       disabledSites: settings.disabledSites,
+      cspReportOnly: settings.cspReportOnly,
+      inferRequestTypes: settings.inferRequestTypes,
     });
   } catch (e) {
     console.warn('[BetterBlocker] Failed to sync runtime settings:', e);
@@ -125,6 +131,8 @@ function normalizeSettings(value?: Partial<UserSettings>): UserSettings {
     dynamicFilteringEnabled: merged.dynamicFilteringEnabled !== false,
     removeparamEnabled: merged.removeparamEnabled !== false,
     cspEnabled: merged.cspEnabled !== false,
+    cspReportOnly: merged.cspReportOnly === true,
+    inferRequestTypes: merged.inferRequestTypes === true,
     responseHeaderEnabled: merged.responseHeaderEnabled !== false,
     disabledSites,
   };
@@ -249,6 +257,9 @@ function getEtld1(host: string): string {
 
 type DynamicMatch = { action: number; isOverlyBroad: boolean };
 
+/** Number and total byte length of a group of cosmetic selectors. */
+type SelectorStats = { count: number; bytes: number };
+
 function matchDynamic(details: RequestDetails, initiator?: string): DynamicMatch {
   if (!wasm?.match_dynamic) {
     return { action: 0, isOverlyBroad: false };
@@ -271,6 +282,9 @@ function updateTopFrame(details: RequestDetails): void {
     topFrameByTab.set(details.tabId, details.url);
     resetTabBlockCount(details.tabId, details.requestId);
   }
+  if ((details.type === 'main_frame' || details.type === 'sub_frame') && wasm?.frame_created) {
+    wasm.frame_created(details.tabId, details.frameId, details.parentFrameId ?? -1, details.url);
+  }
 }
 
 function getContextUrl(details: RequestDetails): string | undefined {
@@ -298,14 +312,37 @@ interface FilterList {
   ruleCount: number;
   lastUpdated: string | null;
   pinned?: boolean;
+  expiresSecs?: number;
   version?: string;
   homepage?: string;
   license?: string;
   source?: string;
 }
 
+interface TraceEvent {
+  seq: number;
+  url: string;
+  type: string;
+  initiator?: string;
+  tabId: number;
+  frameId: number;
+  requestId: string;
+  decision?: number;
+  ruleId?: number;
+  listId?: number;
+  redirectUrl?: string;
+  hiddenSelectors?: number;
+  procedural?: number;
+  scriptlets?: number;
+}
+
 interface WasmExports {
   init(data: Uint8Array): void;
+  init_from_shared?(buffer: ArrayBuffer): void;
+  snapshot_chunks?(chunkSize: number): Uint8Array[];
+  init_from_chunks?(chunks: Uint8Array[]): void;
+  reload?(data: Uint8Array, keepPrevious: boolean): number;
+  reload_from_shared?(buffer: ArrayBuffer, keepPrevious: boolean): number;
   is_initialized(): boolean;
   match_request(
     url: string,
@@ -314,7 +351,43 @@ interface WasmExports {
     tabId: number,
     frameId: number,
     requestId: string
-  ): { decision: number; ruleId: number; listId: number; redirectUrl?: string };
+  ): {
+    decision: number;
+    ruleId: number;
+    listId: number;
+    redirectUrl?: string;
+    budgetExceeded?: boolean;
+    siteSwitch?: number;
+  };
+  match_popup?(url: string, openerUrl: string): { decision: number; ruleId: number; listId: number };
+  explain_request?(
+    url: string,
+    requestType: string,
+    initiator: string | undefined,
+    tabId: number,
+    frameId: number
+  ): {
+    decision: number;
+    ruleId: number;
+    listId: number;
+    redirectUrl?: string;
+    reason: string;
+    reasonText: string;
+    genericblock: boolean;
+    tokens: string[];
+    candidates: {
+      ruleId: number;
+      listId: number;
+      action: number;
+      important: boolean;
+      priority: number;
+      source: 'domain' | 'token' | 'host' | 'regex';
+      sourceKey?: string;
+      matched: boolean;
+      rejected?: string;
+      rejectedText?: string;
+    }[];
+  } | null;
   match_response_headers(
     url: string,
     requestType: string,
@@ -323,7 +396,46 @@ interface WasmExports {
     frameId: number,
     requestId: string,
     headers: chrome.webRequest.HttpHeader[]
-  ): { cancel: boolean; ruleId: number; listId: number; csp?: string[]; removeHeaders?: string[] };
+  ): {
+    cancel: boolean;
+    ruleId: number;
+    listId: number;
+    siteSwitch?: number;
+    csp?: string[];
+    cspReportOnly?: boolean;
+    removeHeaders?: string[];
+  };
+  rewrite_response_headers(
+    url: string,
+    requestType: string,
+    initiator: string | undefined,
+    tabId: number,
+    frameId: number,
+    requestId: string,
+    headers: chrome.webRequest.HttpHeader[],
+    cspEnabled: boolean,
+    removeEnabled: boolean
+  ): {
+    cancel: boolean;
+    ruleId: number;
+    listId: number;
+    siteSwitch?: number;
+    responseHeaders?: chrome.webRequest.HttpHeader[];
+  };
+  match_request_headers?(
+    url: string,
+    requestType: string,
+    initiator: string | undefined,
+    tabId: number,
+    frameId: number,
+    requestId: string,
+    headers: chrome.webRequest.HttpHeader[]
+  ): {
+    cancel: boolean;
+    ruleId: number;
+    listId: number;
+    requestHeaders?: chrome.webRequest.HttpHeader[];
+  };
   match_cosmetics(
     url: string,
     requestType: string,
@@ -331,19 +443,126 @@ interface WasmExports {
     tabId: number,
     frameId: number,
     requestId: string
-  ): CosmeticPayload;
+  ): CosmeticPayload & { siteSwitch?: number };
+  match_cosmetics_generic?(
+    url: string,
+    requestType: string,
+    initiator: string | undefined,
+    tabId: number,
+    frameId: number,
+    requestId: string,
+    selectors: string[]
+  ): string;
+  match_cosmetics_detailed?(
+    url: string,
+    requestType: string,
+    initiator: string | undefined,
+    tabId: number,
+    frameId: number,
+    requestId: string,
+    selectors: string[]
+  ): { selector: string; listId: number; ruleIndex: number; line: number }[];
+  cosmetics_stats?(
+    url: string,
+    requestType: string,
+    initiator: string | undefined,
+    tabId: number,
+    frameId: number,
+    requestId: string
+  ): {
+    specific: SelectorStats;
+    generic: SelectorStats;
+    genericBucketed: SelectorStats;
+    procedural: SelectorStats;
+    enableGeneric: boolean;
+  };
+  site_diagnostics?(url: string): {
+    trusted: boolean;
+    elemhide: boolean;
+    generichide: boolean;
+    specifichide: boolean;
+    exceptions: {
+      kind: 'network' | 'hide-switch' | 'cosmetic' | 'procedural' | 'scriptlet';
+      listId: number;
+      count: number;
+    }[];
+  } | null;
   match_dynamic(
     url: string,
     requestType: string,
     initiator: string | undefined
   ): { action: number; isOverlyBroad: boolean };
+  redirect_resources?(): { name: string; path: string; mime: string; checksum?: number }[];
+  get_build_info?(): {
+    compiledAt: number;
+    compilerVersion: string;
+    lists: { listId: number; source: string; contentHash: string }[];
+  } | null;
+  resource_checksum?(data: Uint8Array): number;
+  get_scriptlet_source?(name: string, args: unknown[]): string;
+  generate_cosmetic_rule?(
+    hostname: string,
+    selector: string,
+    options: { stripNth?: boolean; stripIds?: boolean }
+  ): { filter: string; selector: string; kind: 'hide' | 'procedural'; compiledSelector: string };
+  generate_network_rule?(
+    url: string,
+    requestType: string,
+    site: string | undefined,
+    choices: { allow?: boolean; thirdParty?: boolean; matchType?: boolean; siteOnly?: boolean }
+  ): { scope: 'url' | 'path' | 'hostname' | 'domain'; filter: string }[];
   set_dynamic_rules?(rules: DynamicRule[]): void;
-  set_runtime_settings?(settings: { dynamicFilteringEnabled?: boolean; disabledSites?: string[] }): void;
+  add_dynamic_rule?(rule: DynamicRule): void;
+  remove_dynamic_rule?(site: string, target: string, type: string): boolean;
+  get_dynamic_rules?(): DynamicRule[];
+  evaluate_switchboard?(
+    site: string,
+    targets?: string[]
+  ): { target: string; type: string; action: number; local: number | null; global: number | null }[];
+  set_runtime_settings?(settings: {
+    dynamicFilteringEnabled?: boolean;
+    disabledSites?: string[];
+    cspReportOnly?: boolean;
+    redirectDataUrls?: boolean;
+    inferRequestTypes?: boolean;
+    matchBudget?: { maxCandidates?: number; maxPatternOps?: number } | null;
+    hideCssChunk?: number | null;
+    largeMediaKb?: number | null;
+  }): void;
+  infer_request_type?(url: string, accept?: string): string | undefined;
   is_site_disabled_js?(url: string): boolean;
+  set_site_switches?(site: string, switches: number): void;
+  get_site_switches?(siteHost: string): number;
+  set_disabled_lists?(listIds: Uint16Array | number[]): void;
+  get_disabled_lists?(): Uint16Array;
+  set_list_catalog?(json: string): void;
+  get_lists_status?(): {
+    lists: {
+      id: number;
+      url: string;
+      title?: string;
+      enabled: boolean;
+      lastUpdated?: number;
+      expiresSecs: number;
+      etag?: string;
+      nextUpdate?: number;
+      due: boolean;
+    }[];
+    refresh: { id: number; url: string; etag?: string }[];
+    nextCheck?: number;
+  };
+  mark_list_updated?(id: number, etag: string | undefined, timestamp: number): boolean;
+  set_disabled_rules?(ruleIds: Uint32Array | number[]): void;
+  get_disabled_rules?(): Uint32Array;
+  suppress_filter?(filter: string): Uint32Array;
+  set_user_styles?(json: string): void;
   get_site_pattern_js?(url: string): string | undefined;
   removeparam_should_skip?(tabId: number, frameId: number, url: string, redirectUrl: string): boolean;
   removeparam_clear_tab?(tabId: number): void;
-  trace_configure?(enabled: boolean, maxEntries: number): void;
+  frame_created?(tabId: number, frameId: number, parentFrameId: number, url: string): void;
+  frame_removed?(tabId: number, frameId: number): void;
+  tab_removed?(tabId: number): void;
+  trace_configure?(enabled: boolean, maxEntries: number, captureOutcome?: boolean): void;
   trace_record?(
     url: string,
     requestType: string,
@@ -354,25 +573,69 @@ interface WasmExports {
   ): void;
   trace_stats?(): { enabled: boolean; count: number; max: number };
   trace_export_jsonl?(): string;
+  trace_drain?(max: number): TraceEvent[];
   perf_configure?(enabled: boolean, maxEntries: number): void;
   perf_record?(phase: number, durationMs: number): void;
   perf_stats?(): {
     enabled: boolean;
     beforeRequest: { count: number; min: number; max: number; p50: number; p95: number; p99: number };
     headersReceived: { count: number; min: number; max: number; p50: number; p95: number; p99: number };
+    budgetExceeded?: number;
   };
   perf_export_json?(): string;
+  memory_stats?(): {
+    snapshotBytes: number;
+    previousSnapshotBytes: number;
+    snapshotBuffers: number;
+    snapshotBuffersBytes: number;
+    leakedBuffers: number;
+    dynamicRules: number;
+    dynamicRuleBytes: number;
+    traceEntries: number;
+    traceBytes: number;
+    perfEntries: number;
+    perfBytes: number;
+    cosmeticCacheEntries: number;
+    cosmeticCacheBytes: number;
+    wasmMemoryPages: number;
+    wasmMemoryBytes: number;
+  };
   should_block(url: string, requestType: string, initiator: string | undefined): boolean;
   get_snapshot_info(): { size: number; initialized: boolean };
   get_etld1_js?(host: string): string;
-  compile_filter_lists(list_texts: string[]): {
+  compile_filter_lists(
+    list_texts: string[],
+    includeResolver?: (name: string) => string | undefined,
+    envFlags?: string[],
+    listSources?: string[],
+    listTiers?: number[]
+  ): {
     snapshot: Uint8Array;
     rulesBefore: number;
     rulesAfter: number;
     rulesDeduped?: number;
     badfilterRules?: number;
     badfilteredRules?: number;
-    listStats: { lines: number; rulesBefore: number; rulesAfter: number }[];
+    shadowedRules?: number;
+    listStats: {
+      lines: number;
+      rulesBefore: number;
+      rulesAfter: number;
+      title?: string;
+      version?: string;
+      expiresSecs?: number;
+      unresolvedIncludes?: string[];
+    }[];
+    diagnostics?: {
+      unsupportedOptions: Record<string, number>;
+      legacyOptions?: Record<string, number>;
+      droppedLines: { listId: number; line: number; text: string; reason: string }[];
+      droppedTotal: number;
+      duplicates: { listId: number; line: number; originalListId: number; originalLine: number }[];
+      duplicatesTotal: number;
+    };
+    errors?: CompileError[];
+    errorsTotal?: number;
   };
 }
 
@@ -429,7 +692,42 @@ async function loadSnapshot(): Promise<Uint8Array> {
   return loadBundledSnapshot();
 }
 
+async function verifyRedirectResources(): Promise<void> {
+  if (!wasm?.redirect_resources || !wasm.resource_checksum || !wasm.is_initialized()) {
+    return;
+  }
+
+  for (const resource of wasm.redirect_resources()) {
+    try {
+      const response = await fetch(api.runtime.getURL(`resources${resource.path}`));
+      if (!response.ok) {
+        console.warn(`[BetterBlocker] Redirect resource missing: ${resource.name}`);
+        continue;
+      }
+      if (resource.checksum === undefined) {
+        continue;
+      }
+      const data = new Uint8Array(await response.arrayBuffer());
+      if (wasm.resource_checksum(data) !== resource.checksum) {
+        console.warn(`[BetterBlocker] Redirect resource does not match snapshot: ${resource.name}`);
+      }
+    } catch {
+      console.warn(`[BetterBlocker] Redirect resource missing: ${resource.name}`);
+    }
+  }
+}
+
 async function swapMatcher(snapshot: Uint8Array | null): Promise<boolean> {
+  if (snapshot && snapshot.length > 0 && wasm?.reload && wasm.is_initialized()) {
+    try {
+      wasm.reload(snapshot, false);
+      return true;
+    } catch (e) {
+      console.warn('[BetterBlocker] Snapshot validation failed during swap:', e);
+      return false;
+    }
+  }
+
   const cacheBust = Date.now().toString(36);
   const nextWasm = await loadWasm(cacheBust);
 
@@ -502,6 +800,7 @@ async function initialize(): Promise<void> {
     setupUpdateSchedule();
     updateAllBadges();
     initializationComplete = true;
+    void verifyRedirectResources();
 
     console.log('[BetterBlocker] Ready');
   } catch (e) {
@@ -511,6 +810,15 @@ async function initialize(): Promise<void> {
   }
 }
 
+// Precedence tier for compile_filter_lists: bundled lists rank lowest and the
+// user's own rules highest, so a user exception beats a bundled $important block.
+function listTier(list: FilterList): number {
+  if (list.source === 'bundled') {
+    return 0;
+  }
+  return list.source === 'user' ? 2 : 1;
+}
+
 async function getLists(): Promise<FilterList[]> {
   return new Promise((resolve) => {
     api.storage.sync.get([STORAGE_KEY], (result) => {
@@ -610,6 +918,21 @@ function setupUpdateSchedule(): void {
   });
 }
 
+// Refresh as often as the most demanding enabled list's `! Expires:` header asks.
+function rescheduleUpdates(lists: FilterList[]): void {
+  if (!api.alarms) {
+    return;
+  }
+  const expiries = lists
+    .filter((list) => list.enabled && typeof list.expiresSecs === 'number' && list.expiresSecs > 0)
+    .map((list) => Math.ceil((list.expiresSecs as number) / 60));
+  const period =
+    expiries.length > 0
+      ? Math.max(MIN_UPDATE_INTERVAL_MINUTES, Math.min(...expiries))
+      : UPDATE_INTERVAL_MINUTES;
+  api.alarms.create(UPDATE_ALARM_NAME, { periodInMinutes: period });
+}
+
 async function readResponseTextWithLimit(
   response: Response,
   controller: AbortController,
@@ -682,6 +1005,39 @@ async function fetchListText(url: string): Promise<string> {
   }
 }
 
+const INCLUDE_DIRECTIVE = /^!#include\s+(\S+)/gm;
+
+/**
+ * Fetch `!#include` targets up front, since the compiler resolves includes
+ * synchronously. Only same-origin includes are allowed, as in uBO; nested
+ * includes are not prefetched.
+ */
+async function prefetchIncludes(listUrls: string[], listTexts: string[]): Promise<Map<string, string>> {
+  const includes = new Map<string, string>();
+  await Promise.all(
+    listTexts.flatMap((text, idx) =>
+      Array.from(text.matchAll(INCLUDE_DIRECTIVE), async (match) => {
+        const name = match[1];
+        try {
+          const base = new URL(listUrls[idx]);
+          const target = new URL(name, base);
+          if (target.origin !== base.origin || includes.has(name)) {
+            return;
+          }
+          includes.set(name, await fetchListText(target.href));
+        } catch (e) {
+          console.warn('[BetterBlocker] Failed to fetch include', name, e);
+        }
+      })
+    )
+  );
+  return includes;
+}
+
+function preprocessEnvFlags(): string[] {
+  return [typeof browser !== 'undefined' ? 'env_firefox' : 'env_chromium'];
+}
+
 async function compileAndStoreLists(): Promise<{ stats: SnapshotStats | null; snapshot: Uint8Array | null }> {
   if (initPromise) {
     await initPromise;
@@ -720,7 +1076,17 @@ async function compileAndStoreLists(): Promise<{ stats: SnapshotStats | null; sn
       return cached;
     })
   );
-  const compileResult = wasm.compile_filter_lists(listTexts);
+  const includes = await prefetchIncludes(
+    enabledLists.map((list) => list.url.trim()),
+    listTexts
+  );
+  const compileResult = wasm.compile_filter_lists(
+    listTexts,
+    (name) => includes.get(name),
+    preprocessEnvFlags(),
+    enabledLists.map((list) => list.url.trim()),
+    enabledLists.map(listTier)
+  );
   const now = new Date().toISOString();
 
   const listStats = compileResult.listStats ?? [];
@@ -734,6 +1100,8 @@ async function compileAndStoreLists(): Promise<{ stats: SnapshotStats | null; sn
       ...list,
       ruleCount: stats ? stats.rulesAfter : 0,
       lastUpdated: now,
+      expiresSecs: stats?.expiresSecs,
+      version: stats?.version ?? list.version,
     };
   });
 
@@ -762,10 +1130,18 @@ async function compileAndStoreLists(): Promise<{ stats: SnapshotStats | null; sn
   if (typeof compileResult.badfilteredRules === 'number') {
     stats.badfilteredRules = compileResult.badfilteredRules;
   }
+  if (typeof compileResult.shadowedRules === 'number') {
+    stats.shadowedRules = compileResult.shadowedRules;
+  }
+  if (compileResult.errors) {
+    stats.errors = compileResult.errors;
+    stats.errorsTotal = compileResult.errorsTotal ?? compileResult.errors.length;
+  }
 
   snapshotStats = stats;
 
   await saveLists(updatedLists);
+  rescheduleUpdates(updatedLists);
 
   await saveStoredSnapshot({
     data: snapshotBytes,
@@ -783,6 +1159,7 @@ interface RequestDetails {
   type: string;
   tabId: number;
   frameId: number;
+  parentFrameId?: number;
   initiator?: string;
   originUrl?: string;
   documentUrl?: string;
@@ -792,6 +1169,10 @@ interface ResponseDetails extends RequestDetails {
   responseHeaders?: chrome.webRequest.HttpHeader[];
 }
 
+interface SendHeadersDetails extends RequestDetails {
+  requestHeaders?: chrome.webRequest.HttpHeader[];
+}
+
 function onBeforeRequest(
   details: RequestDetails
 ): chrome.webRequest.BlockingResponse | undefined {
@@ -823,6 +1204,10 @@ function onBeforeRequest(
     return finalize(undefined);
   }
 
+  if (details.type === 'main_frame' && details.frameId === 0 && isBlockedPopup(details)) {
+    return finalize({ cancel: true });
+  }
+
   const initiator = getContextUrl(details);
   if (wasm?.trace_record) {
     wasm.trace_record(details.url, details.type, initiator, details.tabId, details.frameId, details.requestId);
@@ -900,6 +1285,30 @@ function onBeforeRequest(
   }
 }
 
+// The first navigation of a tab another page opened, checked against
+// `$popup` rules; a blocked popup's tab is closed.
+function isBlockedPopup(details: RequestDetails): boolean {
+  const openerUrl = popupOpenerByTab.get(details.tabId);
+  if (openerUrl === undefined) {
+    return false;
+  }
+  popupOpenerByTab.delete(details.tabId);
+  if (!wasm?.match_popup || isSiteDisabled(openerUrl)) {
+    return false;
+  }
+  try {
+    const result = wasm.match_popup(details.url, openerUrl);
+    if (result.decision !== MatchDecision.BLOCK) {
+      return false;
+    }
+  } catch (e) {
+    console.error('[BetterBlocker] Popup match error:', e);
+    return false;
+  }
+  api.tabs.remove(details.tabId);
+  return true;
+}
+
 function onHeadersReceived(
   details: ResponseDetails
 ): chrome.webRequest.BlockingResponse | undefined {
@@ -934,14 +1343,16 @@ function onHeadersReceived(
   }
 
   try {
-    const result = wasm.match_response_headers(
+    const result = wasm.rewrite_response_headers(
       details.url,
       details.type,
       initiator,
       details.tabId,
       details.frameId,
       details.requestId,
-      headers
+      headers,
+      settings.cspEnabled,
+      settings.responseHeaderEnabled
     );
 
     if (result.cancel) {
@@ -949,24 +1360,8 @@ function onHeadersReceived(
       return finalize({ cancel: true });
     }
 
-    const removeHeaders = settings.responseHeaderEnabled ? (result.removeHeaders ?? []) : [];
-    let responseHeaders = headers;
-
-    if (removeHeaders.length > 0) {
-      const removeSet = new Set(removeHeaders.map((name) => name.toLowerCase()));
-      responseHeaders = headers.filter((header) => !removeSet.has(header.name.toLowerCase()));
-    }
-
-    if (settings.cspEnabled && result.csp && result.csp.length > 0) {
-      responseHeaders = [...responseHeaders];
-      for (const value of result.csp) {
-        responseHeaders.push({ name: 'Content-Security-Policy', value });
-      }
-      return finalize({ responseHeaders });
-    }
-
-    if (removeHeaders.length > 0) {
-      return finalize({ responseHeaders });
+    if (result.responseHeaders) {
+      return finalize({ responseHeaders: result.responseHeaders });
     }
 
     return finalize(undefined);
@@ -976,6 +1371,50 @@ function onHeadersReceived(
   }
 }
 
+function onBeforeSendHeaders(
+  details: SendHeadersDetails
+): chrome.webRequest.BlockingResponse | undefined {
+  if (!initializationComplete || details.tabId < 0) {
+    return undefined;
+  }
+
+  if (!settings.enabled || !settings.responseHeaderEnabled || !wasm?.match_request_headers) {
+    return undefined;
+  }
+
+  const headers = details.requestHeaders;
+  if (!headers || headers.length === 0) {
+    return undefined;
+  }
+
+  const initiator = getContextUrl(details);
+  if (isSiteDisabled(initiator ?? details.url)) {
+    return undefined;
+  }
+
+  try {
+    const result = wasm.match_request_headers(
+      details.url,
+      details.type,
+      initiator,
+      details.tabId,
+      details.frameId,
+      details.requestId,
+      headers
+    );
+    if (result.cancel) {
+      return { cancel: true };
+    }
+    if (result.requestHeaders) {
+      return { requestHeaders: result.requestHeaders };
+    }
+    return undefined;
+  } catch (e) {
+    console.error('[BetterBlocker] Request header match error:', e);
+    return undefined;
+  }
+}
+
 function setupWebRequest(): void {
   const filter: chrome.webRequest.RequestFilter = {
     urls: ['http://*/*', 'https://*/*', 'ws://*/*', 'wss://*/*'],
@@ -993,17 +1432,41 @@ function setupWebRequest(): void {
     ['blocking', 'responseHeaders']
   );
 
+  // Chrome hides Cookie/Referer from this event unless extraHeaders is requested.
+  const sendHeadersSpec = api.webRequest.OnBeforeSendHeadersOptions?.EXTRA_HEADERS
+    ? ['blocking', 'requestHeaders', 'extraHeaders']
+    : ['blocking', 'requestHeaders'];
+  api.webRequest.onBeforeSendHeaders.addListener(
+    onBeforeSendHeaders as Parameters<typeof api.webRequest.onBeforeSendHeaders.addListener>[0],
+    filter,
+    sendHeadersSpec as Parameters<typeof api.webRequest.onBeforeSendHeaders.addListener>[2]
+  );
+
   console.log('[BetterBlocker] webRequest listener registered');
 }
 
 function setupTabTracking(): void {
+  api.tabs.onCreated.addListener((tab) => {
+    if (tab.id === undefined || tab.openerTabId === undefined) {
+      return;
+    }
+    const openerUrl = topFrameByTab.get(tab.openerTabId);
+    if (openerUrl) {
+      popupOpenerByTab.set(tab.id, openerUrl);
+    }
+  });
+
   api.tabs.onRemoved.addListener((tabId) => {
     topFrameByTab.delete(tabId);
+    popupOpenerByTab.delete(tabId);
     blockedByTab.delete(tabId);
     mainFrameRequestIdByTab.delete(tabId);
     if (wasm?.removeparam_clear_tab) {
       wasm.removeparam_clear_tab(tabId);
     }
+    if (wasm?.tab_removed) {
+      wasm.tab_removed(tabId);
+    }
   });
 }
 
@@ -1057,6 +1520,12 @@ function setupMessageHandlers(): void {
           return true;
         }
 
+        case 'snapshot.buildInfo': {
+          const buildInfo = wasm?.is_initialized() ? (wasm.get_build_info?.() ?? null) : null;
+          sendResponse({ buildInfo });
+          return true;
+        }
+
         case 'dynamic.get':
           sendResponse({ rules: dynamicRules });
           return true;
@@ -1082,7 +1551,7 @@ function setupMessageHandlers(): void {
             isSiteDisabled(url) ||
             (!settings.cosmeticsEnabled && !settings.scriptletsEnabled)
           ) {
-            sendResponse({ css: '', enableGeneric: true, procedural: [], scriptlets: [] });
+            sendResponse({ css: [], styleCss: '', enableGeneric: false, procedural: [], scriptlets: [] });
             return true;
           }
           const tabId = sender.tab?.id ?? -1;
@@ -1093,11 +1562,12 @@ function setupMessageHandlers(): void {
             result = wasm.match_cosmetics(url, 'main_frame', undefined, tabId, frameId, requestId);
           } catch (e) {
             console.warn('[BetterBlocker] Cosmetic match error:', e);
-            sendResponse({ css: '', enableGeneric: true, procedural: [], scriptlets: [] });
+            sendResponse({ css: [], styleCss: '', enableGeneric: false, procedural: [], scriptlets: [] });
             return true;
           }
           if (!settings.cosmeticsEnabled) {
-            result.css = '';
+            result.css = [];
+            result.styleCss = '';
             result.enableGeneric = false;
             result.procedural = [];
           }
@@ -1108,6 +1578,36 @@ function setupMessageHandlers(): void {
           return true;
         }
 
+        case 'cosmetic.generic': {
+          const url =
+            typeof message.url === 'string'
+              ? message.url
+              : (sender.url ?? sender.tab?.url);
+          const selectors = Array.isArray(message.selectors) ? message.selectors : [];
+          if (
+            !url ||
+            selectors.length === 0 ||
+            !wasm?.match_cosmetics_generic ||
+            !wasm.is_initialized() ||
+            !settings.enabled ||
+            !settings.cosmeticsEnabled ||
+            isSiteDisabled(url)
+          ) {
+            sendResponse({ css: '' });
+            return true;
+          }
+          const tabId = sender.tab?.id ?? -1;
+          const frameId = sender.frameId ?? 0;
+          try {
+            const css = wasm.match_cosmetics_generic(url, 'main_frame', undefined, tabId, frameId, 'cosmetic', selectors);
+            sendResponse({ css });
+          } catch (e) {
+            console.warn('[BetterBlocker] Generic cosmetic match error:', e);
+            sendResponse({ css: '' });
+          }
+          return true;
+        }
+
         case 'settings.get':
           sendResponse({ settings });
           return true;
@@ -1196,9 +1696,78 @@ function setupMessageHandlers(): void {
             });
           return true;
 
+        case 'lists.setDisabled': {
+          if (!wasm?.set_disabled_lists) {
+            sendResponse({ ok: false, error: 'list toggles not supported' });
+            return true;
+          }
+          try {
+            wasm.set_disabled_lists(Uint16Array.from(message.listIds ?? []));
+            sendResponse({ ok: true, disabled: Array.from(wasm.get_disabled_lists?.() ?? []) });
+          } catch (e) {
+            sendResponse({ ok: false, error: String(e) });
+          }
+          return true;
+        }
+
+        case 'site.setSwitches': {
+          if (!wasm?.set_site_switches) {
+            sendResponse({ ok: false, error: 'site switches not supported' });
+            return true;
+          }
+          try {
+            wasm.set_site_switches(String(message.site ?? ''), Number(message.switches ?? 0));
+            sendResponse({ ok: true, switches: wasm.get_site_switches?.(String(message.site ?? '')) ?? 0 });
+          } catch (e) {
+            sendResponse({ ok: false, error: String(e) });
+          }
+          return true;
+        }
+
+        case 'rules.setDisabled': {
+          if (!wasm?.set_disabled_rules) {
+            sendResponse({ ok: false, error: 'rule toggles not supported' });
+            return true;
+          }
+          try {
+            wasm.set_disabled_rules(Uint32Array.from(message.ruleIds ?? []));
+            sendResponse({ ok: true, disabled: Array.from(wasm.get_disabled_rules?.() ?? []) });
+          } catch (e) {
+            sendResponse({ ok: false, error: String(e) });
+          }
+          return true;
+        }
+
+        case 'userStyles.set': {
+          if (!wasm?.set_user_styles) {
+            sendResponse({ ok: false, error: 'user styles not supported' });
+            return true;
+          }
+          try {
+            wasm.set_user_styles(JSON.stringify(message.styles ?? []));
+            sendResponse({ ok: true });
+          } catch (e) {
+            sendResponse({ ok: false, error: String(e) });
+          }
+          return true;
+        }
+
+        case 'memory.stats': {
+          const stats = wasm?.memory_stats ? wasm.memory_stats() : null;
+          sendResponse({ ok: stats !== null, stats });
+          return true;
+        }
+
+        case 'site.diagnostics': {
+          const url = typeof message.url === 'string' ? message.url : '';
+          const diagnostics = url && wasm?.is_initialized() ? (wasm.site_diagnostics?.(url) ?? null) : null;
+          sendResponse({ ok: diagnostics !== null, diagnostics });
+          return true;
+        }
+
         case 'trace.start': {
           if (wasm?.trace_configure) {
-            wasm.trace_configure(true, message.maxEntries ?? 50_000);
+            wasm.trace_configure(true, message.maxEntries ?? 50_000, message.captureOutcome === true);
           }
           const stats = wasm?.trace_stats ? wasm.trace_stats() : { enabled: false, count: 0, max: 0 };
           sendResponse({ ok: true, stats });
@@ -1220,6 +1789,12 @@ function setupMessageHandlers(): void {
           return true;
         }
 
+        case 'trace.drain': {
+          const events = wasm?.trace_drain ? wasm.trace_drain(message.max ?? 500) : [];
+          sendResponse({ ok: true, events });
+          return true;
+        }
+
         case 'trace.export': {
           const jsonl = wasm?.trace_export_jsonl ? wasm.trace_export_jsonl() : '';
           const stats = wasm?.trace_stats ? wasm.trace_stats() : { enabled: false, count: 0, max: 0 };
@@ -4,13 +4,24 @@ export interface ListStats {
   rulesAfter: number;
 }
 
+/** A line the compiler dropped, with a stable `code` for UI messages. */
+export interface CompileError {
+  code: string;
+  message: string;
+  listId?: number;
+  line?: number;
+}
+
 export interface SnapshotStats {
   rulesBefore: number;
   rulesAfter: number;
   rulesDeduped?: number;
   badfilterRules?: number;
   badfilteredRules?: number;
+  shadowedRules?: number;
   listStats: ListStats[];
+  errors?: CompileError[];
+  errorsTotal?: number;
 }
 
 export interface StoredSnapshot {
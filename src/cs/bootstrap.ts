@@ -3,6 +3,8 @@ import type { CosmeticPayload } from '../shared/types.js';
 import { pageContextScriptlets, scriptlets } from './scriptlets.js';
 
 const MAX_PROCEDURAL_NODES = 200;
+const MAX_GENERIC_KEYS = 8192;
+const GENERIC_FLUSH_DELAY_MS = 50;
 
 function isTopFrame(): boolean {
   try {
@@ -77,19 +79,34 @@ function applyProceduralRules(rules: ProceduralRule[]): void {
         continue;
       }
       if (op.type === 'matches-css') {
-        const parts = op.args.split(',');
-        const prop = parts[0] ? parts[0].trim() : '';
-        const value = parts[1] ? stripQuotes(parts.slice(1).join(',')).trim() : '';
+        // `property: value`, or the older `property, value`
+        const split = op.args.search(/[:,]/);
+        const prop = split > 0 ? op.args.slice(0, split).trim() : '';
+        const value = split > 0 ? stripQuotes(op.args.slice(split + 1)).trim() : '';
         if (!prop || !value) {
           continue;
         }
+        const matches = textMatcher(value);
         nodes = nodes.filter((node) => {
           const style = window.getComputedStyle(node);
-          const current = style.getPropertyValue(prop).trim();
-          return current === value || current.includes(value);
+          return matches(style.getPropertyValue(prop).trim());
         });
         continue;
       }
+      if (op.type === 'matches-path') {
+        const arg = stripQuotes(op.args);
+        if (arg && !textMatcher(arg)(window.location.pathname + window.location.search)) {
+          nodes = [];
+        }
+        continue;
+      }
+      if (op.type === 'min-text-length') {
+        const min = Number(op.args.trim());
+        if (Number.isFinite(min)) {
+          nodes = nodes.filter((node) => (node.textContent || '').length >= min);
+        }
+        continue;
+      }
       if (op.type === 'xpath') {
         const expr = stripQuotes(op.args);
         if (!expr) {
@@ -185,6 +202,107 @@ function applyProceduralRules(rules: ProceduralRule[]): void {
   }
 }
 
+/**
+ * Generic cosmetic rules keyed by a leading `#id` / `.class` are only sent
+ * for keys the page actually uses: report ids and classes as they appear
+ * and append the returned CSS.
+ */
+function watchGenericSelectors(): void {
+  const seen = new Set<string>();
+  let pending: string[] = [];
+  let scheduled = false;
+  let style: HTMLStyleElement | null = null;
+
+  const flush = () => {
+    scheduled = false;
+    const selectors = pending;
+    pending = [];
+    if (selectors.length === 0) {
+      return;
+    }
+    sendMessage<{ css?: string }>({
+      type: 'cosmetic.generic',
+      url: window.location.href,
+      selectors,
+    }).then((response) => {
+      if (!response?.css) {
+        return;
+      }
+      if (!style) {
+        style = document.createElement('style');
+        style.id = 'bb-injected-generic-style';
+        (document.head || document.documentElement).appendChild(style);
+      }
+      style.appendChild(document.createTextNode(`${response.css}\n`));
+    });
+  };
+
+  const queue = (key: string) => {
+    if (seen.has(key) || seen.size >= MAX_GENERIC_KEYS) {
+      return;
+    }
+    seen.add(key);
+    pending.push(key);
+    if (!scheduled) {
+      scheduled = true;
+      setTimeout(flush, GENERIC_FLUSH_DELAY_MS);
+    }
+  };
+
+  const collect = (element: Element) => {
+    if (element.id) {
+      queue(`#${element.id}`);
+    }
+    element.classList.forEach((name) => queue(`.${name}`));
+  };
+
+  const collectTree = (root: Element) => {
+    collect(root);
+    root.querySelectorAll('[id],[class]').forEach(collect);
+  };
+
+  collectTree(document.documentElement);
+
+  const observer = new MutationObserver((mutations) => {
+    for (const mutation of mutations) {
+      if (mutation.type === 'attributes') {
+        if (mutation.target instanceof Element) {
+          collect(mutation.target);
+        }
+        continue;
+      }
+      mutation.addedNodes.forEach((node) => {
+        if (node instanceof Element) {
+          collectTree(node);
+        }
+      });
+    }
+    if (seen.size >= MAX_GENERIC_KEYS) {
+      observer.disconnect();
+    }
+  });
+  observer.observe(document.documentElement, {
+    childList: true,
+    subtree: true,
+    attributes: true,
+    attributeFilter: ['id', 'class'],
+  });
+}
+
+/** `/regex/` arguments test as a regex, anything else as a substring. */
+function textMatcher(arg: string): (text: string) => boolean {
+  if (arg.length > 2 && arg.startsWith('/') && arg.endsWith('/')) {
+    try {
+      const re = new RegExp(arg.slice(1, -1));
+      return (text) => re.test(text);
+    } catch (e) {
+      void e;
+      return () => false;
+    }
+  }
+  return (text) => text === arg || text.includes(arg);
+}
+
 function stripQuotes(value: string): string {
   const trimmed = value.trim();
   if (
@@ -223,10 +341,21 @@ function stripQuotes(value: string): string {
     if (response.css && response.css.length > 0) {
       const style = document.createElement('style');
       style.id = 'bb-injected-style';
-      style.textContent = response.css;
+      style.textContent = response.css.join('\n');
+      (document.head || document.documentElement).appendChild(style);
+    }
+
+    if (response.styleCss && response.styleCss.length > 0) {
+      const style = document.createElement('style');
+      style.id = 'bb-injected-style-rules';
+      style.textContent = response.styleCss;
       (document.head || document.documentElement).appendChild(style);
     }
 
+    if (response.enableGeneric) {
+      watchGenericSelectors();
+    }
+
     if (response.procedural && response.procedural.length > 0) {
       applyProceduralRules(response.procedural);
     }
@@ -55,6 +55,8 @@ interface PerfStats {
   enabled: boolean;
   beforeRequest: PerfBucket;
   headersReceived: PerfBucket;
+  /** Requests the match budget cut short */
+  budgetExceeded?: number;
 }
 
 interface PerfResponse {
@@ -76,6 +78,8 @@ interface UserSettings {
   dynamicFilteringEnabled: boolean;
   removeparamEnabled: boolean;
   cspEnabled: boolean;
+  cspReportOnly: boolean;
+  inferRequestTypes: boolean;
   responseHeaderEnabled: boolean;
   disabledSites: string[];
 }
@@ -121,6 +125,8 @@ const pageElements = {
     dynamicFilteringEnabled: document.getElementById('toggle-dynamicFilteringEnabled') as HTMLInputElement,
     removeparamEnabled: document.getElementById('toggle-removeparamEnabled') as HTMLInputElement,
     cspEnabled: document.getElementById('toggle-cspEnabled') as HTMLInputElement,
+    cspReportOnly: document.getElementById('toggle-cspReportOnly') as HTMLInputElement,
+    inferRequestTypes: document.getElementById('toggle-inferRequestTypes') as HTMLInputElement,
     responseHeaderEnabled: document.getElementById('toggle-responseHeaderEnabled') as HTMLInputElement,
   }
 };
@@ -8,8 +8,8 @@ export type ProceduralRule = { base: string, ops: Array<ProceduralOperator>, };
 
 export type ScriptletCall = { name: string, args: unknown[], };
 
-export type CosmeticPayload = { css: string, enableGeneric: boolean, procedural: Array<ProceduralRule>, scriptlets: Array<ScriptletCall>, };
+export type CosmeticPayload = { css: Array<string>, styleCss: string, enableGeneric: boolean, procedural: Array<ProceduralRule>, scriptlets: Array<ScriptletCall>, };
 
 export type DynamicRule = { site: string, target: string, type: string, action: DynamicAction, };
 
-export type UserSettings = { enabled: boolean, cosmeticsEnabled: boolean, scriptletsEnabled: boolean, dynamicFilteringEnabled: boolean, removeparamEnabled: boolean, cspEnabled: boolean, responseHeaderEnabled: boolean, disabledSites: Array<string>, };
+export type UserSettings = { enabled: boolean, cosmeticsEnabled: boolean, scriptletsEnabled: boolean, dynamicFilteringEnabled: boolean, removeparamEnabled: boolean, cspEnabled: boolean, cspReportOnly: boolean, inferRequestTypes: boolean, responseHeaderEnabled: boolean, disabledSites: Array<string>, };
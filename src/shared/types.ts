@@ -67,6 +67,13 @@ export const enum RuleFlags {
   REDIRECT_RULE_EXCEPTION = 1 << 11,
   ELEMHIDE = 1 << 12,
   GENERICHIDE = 1 << 13,
+  REMOVEHEADER_EXCEPTION = 1 << 14,
+  /** $specifichide / $shide - disables domain-specific cosmetic rules only */
+  SPECIFICHIDE = 1 << 15,
+  /** $genericblock - page-level exception disabling generic block rules */
+  GENERICBLOCK = 1 << 16,
+  /** Block rule without a positive $domain=; set by the compiler */
+  IS_GENERIC = 1 << 17,
 }
 
 // =============================================================================
@@ -279,6 +286,8 @@ export const DEFAULT_SETTINGS: UserSettings = {
   dynamicFilteringEnabled: true,
   removeparamEnabled: true,
   cspEnabled: false,
+  cspReportOnly: false,
+  inferRequestTypes: false,
   responseHeaderEnabled: true,
   disabledSites: [],
 };